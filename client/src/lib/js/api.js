@@ -7,7 +7,9 @@ export class Api extends EventTarget {
     }
 
     connect() {
-        this.socket = new WebSocket(`ws://${this.host}:${this.port}/ws`);
+        // a page served over https can only open secure websockets
+        const scheme = window.location.protocol === 'https:' ? 'wss' : 'ws';
+        this.socket = new WebSocket(`${scheme}://${this.host}:${this.port}/ws`);
         this.idCounter = 0;
         this.requestCallbacks = {};
 
@@ -57,6 +59,21 @@ export class Api extends EventTarget {
         })
     }
 
+    // Switches to state deltas, answered with a Snapshot or the Deltas after `seq`
+    async sync(seq = null) {
+        return await this.request({
+            'Sync': seq
+        });
+    }
+
+    // settings: { enabled, channels, kinds, max_per_second }, null channels or kinds let
+    // everything through
+    async setMidiMonitor(settings) {
+        return await this.request({
+            'SetMidiMonitor': settings
+        })
+    }
+
     async connectMidiInput(slot, inputName) {
         return await this.request({
             'ConnectMidiInput': [slot, inputName]
@@ -69,6 +86,15 @@ export class Api extends EventTarget {
         })
     }
 
+    // settings: { channel_map: [the channel of every channel], dropped: { aftertouch,
+    // program_change, pitch_wheel, system_exclusive, control_changes: [numbers] },
+    // velocity_curve: { exponent, min, max } }
+    async setMidiInputSettings(slot, settings) {
+        return await this.request({
+            'SetMidiInputSettings': [slot, settings]
+        })
+    }
+
     async rendererRequest(req, timeout) {
         return await this.request({
             'RendererRequest': req
@@ -93,6 +119,102 @@ export class Api extends EventTarget {
         });
     }
 
+    async panic() {
+        return await this.rendererRequest('Panic');
+    }
+
+    // zone: { name, low_key, high_key, channel, node, transpose, velocity_scale }
+    async setZones(zones) {
+        return await this.rendererRequest({
+            'SetZones': zones
+        });
+    }
+
+    async addZone(zone) {
+        return await this.rendererRequest({
+            'AddZone': zone
+        });
+    }
+
+    async setZone(index, zone) {
+        return await this.rendererRequest({
+            'SetZone': { index, zone }
+        });
+    }
+
+    async removeZone(index) {
+        return await this.rendererRequest({
+            'RemoveZone': { index }
+        });
+    }
+
+    // instrument: { name, layers: [{ node, volume, detune, min_velocity, max_velocity }] }
+    async setLayeredInstruments(instruments) {
+        return await this.rendererRequest({
+            'SetLayeredInstruments': instruments
+        });
+    }
+
+    async addLayeredInstrument(instrument) {
+        return await this.rendererRequest({
+            'AddLayeredInstrument': instrument
+        });
+    }
+
+    async setLayeredInstrument(index, instrument) {
+        return await this.rendererRequest({
+            'SetLayeredInstrument': { index, instrument }
+        });
+    }
+
+    async removeLayeredInstrument(index) {
+        return await this.rendererRequest({
+            'RemoveLayeredInstrument': { index }
+        });
+    }
+
+    // null for none
+    async selectLayeredInstrument(index) {
+        return await this.rendererRequest({
+            'SelectLayeredInstrument': index
+        });
+    }
+
+    // settings: { controller, curve, scope }, scope is 'Master' or { 'Nodes': [ids] }, null
+    // leaves the pedal to the nodes
+    async setExpression(settings) {
+        return await this.rendererRequest({
+            'SetExpression': settings
+        });
+    }
+
+    // route: { source, channel, node, target, depth, curve }, source is { 'ControlChange': kind },
+    // 'ChannelAftertouch' or 'PitchWheel', target 'Gain', 'Filter', 'Reverb' or 'Chorus', curve
+    // 'Linear', 'Exponential' or 'Logarithmic'
+    async setModulationRoutes(routes) {
+        return await this.rendererRequest({
+            'SetModulationRoutes': routes
+        });
+    }
+
+    async addModulationRoute(route) {
+        return await this.rendererRequest({
+            'AddModulationRoute': route
+        });
+    }
+
+    async setModulationRoute(index, route) {
+        return await this.rendererRequest({
+            'SetModulationRoute': { index, route }
+        });
+    }
+
+    async removeModulationRoute(index) {
+        return await this.rendererRequest({
+            'RemoveModulationRoute': { index }
+        });
+    }
+
     async nodeRequest(id, kind, timeout) {
         return await this.rendererRequest({
             'NodeRequest': { id, kind }
@@ -239,6 +361,37 @@ export class Api extends EventTarget {
         });
     }
 
+    // Frames of every sample kept in memory, null for whole samples
+    async nodeSetPreloadSize(id, frames) {
+        return await this.nodeRequest(id, {
+            'SetPreloadSize': frames
+        });
+    }
+
+    // scale is the virtual path of an .scl file, null goes back to 12-TET
+    async nodeSetTuning(id, scale, keyboardMapping = null) {
+        return await this.nodeRequest(id, {
+            'SetTuning': scale === null ? null : {
+                'scale': scale,
+                'keyboard_mapping': keyboardMapping
+            }
+        });
+    }
+
+    // limit is the number of notes sounding at once, null for no limit
+    async nodeSetPolyphony(id, limit) {
+        return await this.nodeRequest(id, {
+            'SetPolyphony': limit
+        });
+    }
+
+    // stealing is 'Oldest' or 'Quietest'
+    async nodeSetVoiceStealing(id, stealing) {
+        return await this.nodeRequest(id, {
+            'SetVoiceStealing': stealing
+        });
+    }
+
     async readDir(path) {
         const res = (await this.request({
             'ReadDir': path
@@ -260,6 +413,24 @@ export class Api extends EventTarget {
         return [dirs.sort(), files.sort()]
     }
 
+    // [{ bank, preset, name }] or null, parsing a big soundfont takes a while
+    async soundfontPresets(path) {
+        const res = (await this.request({
+            'ListSoundfontPresets': path
+        }, 10000)).SoundfontPresets;
+
+        return res?.Ok ?? null;
+    }
+
+    // { regions, key_range, velocity_layers, samples: [{ path, missing }] } or null
+    async inspectSfz(path) {
+        const res = (await this.request({
+            'InspectSfz': path
+        }, 10000)).SfzInspection;
+
+        return res?.Ok ?? null;
+    }
+
     async drumMachineRequest(kind, timeout) {
         return await this.request({
             'DrumMachineRequest': kind
@@ -384,10 +555,22 @@ export class Api extends EventTarget {
             this.dispatchEvent(new CustomEvent('connected-midi-inputs', {
                 detail: msg.ConnectedMidiInputs
             }));
+        } else if ('MidiInputSettings' in msg) {
+            this.dispatchEvent(new CustomEvent('midi-input-settings', {
+                detail: msg.MidiInputSettings
+            }));
         } else if ('Cache' in msg) {
             this.dispatchEvent(new CustomEvent('cache', {
                 detail: msg.Cache
             }));
+        } else if ('StateDelta' in msg) {
+            this.dispatchEvent(new CustomEvent('state-delta', {
+                detail: msg.StateDelta
+            }));
+        } else if ('Snapshot' in msg) {
+            this.dispatchEvent(new CustomEvent('snapshot', {
+                detail: msg.Snapshot
+            }));
         } else if ('RendererResponse' in msg) {
             this.dispatchEvent(new CustomEvent('renderer-update', {
                 detail: msg.RendererResponse
@@ -396,6 +579,12 @@ export class Api extends EventTarget {
             this.dispatchEvent(new CustomEvent('drum-machine-update', {
                 detail: msg.DrumMachineUpdate
             }));
+        } else if ('InstrumentFileChanged' in msg) {
+            // reload with nodeLoadFile
+            const [id, path] = msg.InstrumentFileChanged;
+            this.dispatchEvent(new CustomEvent('instrument-file-changed', {
+                detail: { id, path }
+            }));
         }
     }
 }
\ No newline at end of file
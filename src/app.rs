@@ -0,0 +1,1037 @@
+use crate::{
+    audio,
+    control::{
+        self,
+        drum_machine::{self, DrumMachine},
+        node::{chord, euclidean, metronome, midi_file_player},
+        Controller,
+    },
+    files,
+    json::{self, JsonUpdateKind, JsonUpdater},
+    midi::{self, recorder::Recorder, MidiReader},
+    pads::{self, Action, Pads},
+    path::VirtualPaths,
+    performances::{self, Performances},
+    render::{
+        capture::{self, Capture},
+        command,
+        node::{audio_input, fluidlite_synth, looper, oxi_synth, rusty_synth, sfizz_synth},
+        Renderer,
+    },
+    session,
+    setlist::{self, Setlist},
+    sfz,
+    snapshots::Snapshots,
+    webserver::{self, Cache, ClientMessageKind, Clients, ServerMessageKind},
+};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, watch, Mutex};
+use tracing::{info, warn};
+
+const FADE_OUT_TIME: Duration = Duration::from_millis(200);
+// How long the clients get to close their connections when AMI stops
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct Requesters {
+    pub renderer: command::Requester,
+    pub drum_machine: drum_machine::Requester,
+    pub controller: control::command::Requester,
+    // Changed under the lock, they have no task of their own
+    pub pads: Arc<Mutex<Pads>>,
+    // Set by whoever owns the audio output
+    pub audio: Option<audio::output::Requester>,
+}
+
+// When a loop last went around, a wedged one stops beating
+#[derive(Clone)]
+pub struct Heartbeat(Arc<std::sync::Mutex<Instant>>);
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self(Arc::new(std::sync::Mutex::new(Instant::now())))
+    }
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        if let Ok(mut last) = self.0.lock() {
+            *last = Instant::now();
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        self.0.lock().map_or(Duration::MAX, |last| last.elapsed())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Heartbeats {
+    pub drum_machine: Heartbeat,
+    pub controller: Heartbeat,
+}
+
+// Everything behind the webserver: the drum machine, the controller and the renderer
+// (which is driven by whoever owns the audio output), wired together and ready to serve
+#[derive(Clone)]
+pub struct App {
+    pub clients: Clients,
+    pub midi_tx: midi::Sender,
+    pub midi_reader: Arc<Mutex<MidiReader>>,
+    pub renderer: Arc<Mutex<Renderer>>,
+    pub cache: Arc<Mutex<Cache>>,
+    pub pads: Arc<Mutex<Pads>>,
+    pub recorder: Arc<Mutex<Recorder>>,
+    // The audio of a session being recorded
+    pub capture: Arc<Mutex<Option<Capture>>>,
+    pub setlist: Arc<Mutex<Setlist>>,
+    // In memory only, to compare sounds and mixes
+    pub snapshots: Arc<Mutex<Snapshots>>,
+    pub performances: Arc<Mutex<Performances>>,
+    pub requesters: Requesters,
+    pub virtual_paths: VirtualPaths,
+    pub heartbeats: Heartbeats,
+}
+
+impl App {
+    // Spawns the background tasks, so it must be called from within a tokio runtime
+    pub fn new(
+        midi_tx: midi::Sender,
+        midi_reader: MidiReader,
+        virtual_paths: VirtualPaths,
+    ) -> Self {
+        let clients = Clients::new(256);
+        let midi_reader = Arc::new(Mutex::new(midi_reader));
+
+        tokio::spawn(run_midi_logger(midi_tx.subscribe(), clients.clone()));
+
+        let (dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(256);
+        let (dm_req_tx, dm_req_rx) = drum_machine::create_request_channel(32);
+        let mut drum_machine =
+            DrumMachine::new(dm_ctr_tx.clone(), dm_req_rx, virtual_paths.clone());
+        let (dm_update_tx, dm_update_rx) = json::create_json_update_channel(32);
+        drum_machine.set_json_updater(JsonUpdater::new(0, dm_update_tx));
+        drum_machine.set_midi_receiver(midi_tx.subscribe());
+        let drum_machine_json = drum_machine
+            .serialize()
+            .expect("Failed to serialize Drum Machine");
+
+        let heartbeats = Heartbeats::default();
+
+        let (ctr_req_tx, ctr_req_rx) = control::command::create_request_channel(32);
+        let pads = Pads::new();
+        let pad_triggers_rx = pads.subscribe_triggers();
+        let pads = Arc::new(Mutex::new(pads));
+
+        let mut controller = Controller::new(
+            midi_tx.subscribe(),
+            ctr_req_rx,
+            dm_ctr_tx.clone(),
+            virtual_paths.clone(),
+        );
+        controller.register_node_kind("Euclidean", || Box::<euclidean::Node>::default());
+        controller.register_node_kind("ChordGenerator", || Box::<chord::Node>::default());
+        controller.register_node_kind("Metronome", || Box::<metronome::Node>::default());
+        controller.register_node_kind("MidiFilePlayer", || {
+            Box::<midi_file_player::Node>::default()
+        });
+        let (ctr_update_tx, ctr_update_rx) = json::create_json_update_channel(32);
+        controller.set_json_update_sender(ctr_update_tx);
+        let (ctr_res_tx, ctr_res_rx) = mpsc::channel(8);
+        controller.set_response_sender(ctr_res_tx);
+        let tempo_rx = controller.subscribe_tempo();
+        let transport_rx = controller.subscribe_transport();
+        controller.set_pad_triggers_receiver(pad_triggers_rx);
+        let consumed_rx = controller.subscribe_consumed_inputs();
+        drum_machine.set_transport_receiver(controller.subscribe_transport());
+        let (dm_tempo_tx, dm_tempo_rx) = mpsc::channel(8);
+        drum_machine.set_tempo_request_sender(dm_tempo_tx);
+        let controller_json = controller
+            .serialize()
+            .expect("Failed to serialize Controller");
+
+        let heartbeat = heartbeats.drum_machine.clone();
+        tokio::spawn(async move {
+            loop {
+                drum_machine.tick().await;
+                heartbeat.beat();
+                tokio::time::sleep(Duration::from_secs_f32(drum_machine.period().min(0.01))).await;
+            }
+        });
+
+        let heartbeat = heartbeats.controller.clone();
+        tokio::spawn(async move {
+            loop {
+                controller.tick().await;
+                heartbeat.beat();
+                tokio::time::sleep(Duration::from_secs_f32(controller.period().min(0.01))).await;
+            }
+        });
+
+        let (req_tx, req_rx) = command::create_request_channel(32);
+        let mut renderer = Renderer::new(
+            midi_tx.subscribe(),
+            req_rx,
+            dm_ctr_rx,
+            virtual_paths.clone(),
+        );
+        renderer.register_node_kind("RustySynth", || Box::<rusty_synth::Node>::default());
+        renderer.register_node_kind("OxiSynth", || Box::<oxi_synth::Node>::default());
+        renderer.register_node_kind("FluidliteSynth", || Box::<fluidlite_synth::Node>::default());
+        renderer.register_node_kind("SfizzSynth", || Box::<sfizz_synth::Node>::default());
+        renderer.register_node_kind("AudioInput", || Box::<audio_input::Node>::default());
+        renderer.register_node_kind("Looper", || Box::<looper::Node>::default());
+        renderer.set_tempo_receiver(tempo_rx);
+        renderer.set_consumed_inputs_receiver(consumed_rx);
+        let (render_update_tx, render_update_rx) = json::create_json_update_channel(32);
+        renderer.set_json_update_sender(render_update_tx);
+        tokio::spawn(run_watch_broadcasts(
+            renderer.subscribe_load(),
+            clients.clone(),
+            ServerMessageKind::RenderLoad,
+        ));
+        tokio::spawn(run_watch_broadcasts(
+            renderer.subscribe_levels(),
+            clients.clone(),
+            ServerMessageKind::Levels,
+        ));
+        let transposition_rx = renderer.subscribe_transposition();
+        let octaves_rx = renderer.subscribe_octave_shift();
+        let renderer = Arc::new(Mutex::new(renderer));
+
+        let cache = Arc::new(Mutex::new(Cache::new(drum_machine_json, controller_json)));
+        tokio::spawn(run_renderer_watch_updates(
+            transposition_rx,
+            Arc::clone(&cache),
+            clients.clone(),
+            command::ResponseKind::GlobalTransposition,
+        ));
+        tokio::spawn(run_renderer_watch_updates(
+            octaves_rx,
+            Arc::clone(&cache),
+            clients.clone(),
+            command::ResponseKind::OctaveShift,
+        ));
+
+        tokio::spawn(run_drum_machine_updates(
+            dm_update_rx,
+            Arc::clone(&cache),
+            clients.clone(),
+        ));
+        tokio::spawn(run_controller_updates(
+            ctr_update_rx,
+            Arc::clone(&cache),
+            clients.clone(),
+        ));
+        tokio::spawn(run_drum_machine_tempo_requests(
+            dm_tempo_rx,
+            ctr_req_tx.clone(),
+            Arc::clone(&cache),
+            clients.clone(),
+        ));
+        tokio::spawn(run_controller_responses(
+            ctr_res_rx,
+            Arc::clone(&cache),
+            clients.clone(),
+        ));
+        tokio::spawn(run_transport_updates(
+            transport_rx,
+            Arc::clone(&cache),
+            clients.clone(),
+        ));
+        tokio::spawn(run_renderer_updates(
+            render_update_rx,
+            Arc::clone(&cache),
+            clients.clone(),
+        ));
+
+        let requesters = Requesters {
+            renderer: req_tx,
+            drum_machine: dm_req_tx,
+            controller: ctr_req_tx,
+            pads: Arc::clone(&pads),
+            audio: None,
+        };
+        tokio::spawn(run_pad_midi_triggers(
+            midi_tx.subscribe(),
+            Arc::clone(&pads),
+            requesters.clone(),
+            Arc::clone(&cache),
+            clients.clone(),
+        ));
+
+        let setlist = Arc::new(Mutex::new(Setlist::new(virtual_paths.clone())));
+        tokio::spawn(run_setlist_midi_triggers(
+            midi_tx.subscribe(),
+            Arc::clone(&setlist),
+            requesters.clone(),
+            Arc::clone(&cache),
+            clients.clone(),
+        ));
+
+        let performances = Arc::new(Mutex::new(Performances::new(virtual_paths.clone())));
+        tokio::spawn(run_performance_midi_triggers(
+            midi_tx.subscribe(),
+            Arc::clone(&performances),
+            requesters.clone(),
+            Arc::clone(&cache),
+            clients.clone(),
+        ));
+
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        tokio::spawn(run_midi_recorder(
+            midi_tx.subscribe(),
+            Arc::clone(&recorder),
+        ));
+
+        Self {
+            clients,
+            midi_tx,
+            midi_reader,
+            renderer,
+            cache,
+            pads,
+            recorder,
+            capture: Arc::new(Mutex::new(None)),
+            setlist,
+            snapshots: Default::default(),
+            performances,
+            requesters,
+            virtual_paths,
+            heartbeats,
+        }
+    }
+
+    // Must be called from within a tokio runtime
+    pub fn set_audio_output(
+        &mut self,
+        requester: audio::output::Requester,
+        status_rx: watch::Receiver<audio::output::DeviceStatus>,
+    ) {
+        self.requesters.audio = Some(requester);
+        tokio::spawn(run_watch_broadcasts(
+            status_rx,
+            self.clients.clone(),
+            ServerMessageKind::AudioDevice,
+        ));
+    }
+
+    // Before AMI stops: the output fades out and every node gets its notes off, so it doesn't stop
+    // in the middle of a buffer, then the session gets saved and the clients are told
+    pub async fn shutdown(&self, autosave: Option<&Path>) {
+        self.renderer.lock().await.fade_out(FADE_OUT_TIME);
+        // rendered by the audio output, a buffer more makes sure the last of it got played
+        tokio::time::sleep(FADE_OUT_TIME * 2).await;
+        self.renderer.lock().await.panic();
+
+        if let Some(path) = autosave {
+            let state = self.cache.lock().await.get().clone();
+            match session::save(&self.virtual_paths, path, &state).await {
+                Ok(()) => info!("Session saved to {path:?}"),
+                Err(e) => warn!("Failed to save the session to {path:?}: {e:?}"),
+            }
+        }
+
+        self.clients.clone().broadcast(ServerMessageKind::Shutdown);
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while self.clients.len().await > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    // The files the slots play get preloaded once they're set, so switching doesn't wait for them
+    pub async fn handle_performance_request(&self, req: performances::RequestKind) -> bool {
+        let preload = matches!(req, performances::RequestKind::SetSlots(_));
+        let mut clients = self.clients.clone();
+        let (performances, requesters) = (&self.performances, &self.requesters);
+        let ok =
+            process_performance_request(req, performances, requesters, &self.cache, &mut clients)
+                .await;
+        if ok && preload {
+            let files = performances.lock().await.files();
+            let paths = files
+                .iter()
+                .filter_map(|path| self.virtual_paths.translate(path))
+                .collect();
+            self.renderer.lock().await.soundfont_cache().preload(paths);
+        }
+        ok
+    }
+
+    pub fn shared_state(&self) -> webserver::SharedState {
+        webserver::SharedState {
+            clients: self.clients.clone(),
+            midi_reader: Arc::clone(&self.midi_reader),
+            cache: Arc::clone(&self.cache),
+            virtual_paths: self.virtual_paths.clone(),
+            password: None,
+            web_root: None,
+        }
+    }
+
+    pub async fn handle_client_message(
+        &self,
+        addr: SocketAddr,
+        req: ClientMessageKind,
+    ) -> ServerMessageKind {
+        let mut clients = self.clients.clone();
+        match req {
+            ClientMessageKind::Ping => ServerMessageKind::Pong,
+            // answered by the webserver, which knows the connection
+            ClientMessageKind::Authenticate(_)
+            | ClientMessageKind::Sync(_)
+            | ClientMessageKind::SetMidiMonitor(_) => ServerMessageKind::Nak,
+            ClientMessageKind::ListClients => ServerMessageKind::ClientList(clients.list().await),
+            ClientMessageKind::SetClientRole(client, role) => {
+                if clients.set_role(client, role).await {
+                    info!("Client [{client}] is now {role:?}, changed by [{addr}]");
+                    clients.broadcast(ServerMessageKind::ClientList(clients.list().await));
+                    ServerMessageKind::Ack
+                } else {
+                    ServerMessageKind::Nak
+                }
+            }
+            ClientMessageKind::Report(report) => {
+                info!("Report from [{addr}]: {report}");
+                ServerMessageKind::Ack
+            }
+            ClientMessageKind::ConnectMidiInput(slot, name) => {
+                let mut midi_reader = self.midi_reader.lock().await;
+                if let Ok(()) = midi_reader.connect_input(slot, &name) {
+                    clients.broadcast(ServerMessageKind::ConnectedMidiInputs(
+                        midi_reader.connected_input_names(),
+                    ));
+                    ServerMessageKind::Ack
+                } else {
+                    ServerMessageKind::Nak
+                }
+            }
+            ClientMessageKind::DisconnectMidiInput(slot) => {
+                let mut midi_reader = self.midi_reader.lock().await;
+                if let Ok(()) = midi_reader.disconnect_input(slot) {
+                    clients.broadcast(ServerMessageKind::ConnectedMidiInputs(
+                        midi_reader.connected_input_names(),
+                    ));
+                    ServerMessageKind::Ack
+                } else {
+                    ServerMessageKind::Nak
+                }
+            }
+            ClientMessageKind::SetMidiInputSettings(slot, settings) => {
+                let mut midi_reader = self.midi_reader.lock().await;
+                if let Ok(()) = midi_reader.set_input_settings(slot, settings) {
+                    clients.broadcast(ServerMessageKind::MidiInputSettings(
+                        midi_reader.input_settings(),
+                    ));
+                    ServerMessageKind::Ack
+                } else {
+                    ServerMessageKind::Nak
+                }
+            }
+            ClientMessageKind::RendererRequest(req) => {
+                let Some(res) = send_renderer_request(&self.requesters.renderer, req).await else {
+                    return ServerMessageKind::Nak;
+                };
+                self.cache.lock().await.cache_renderer_response(&res);
+                let moved = match res {
+                    command::ResponseKind::MoveNode { id, new_id } => Some((id, new_id)),
+                    _ => None,
+                };
+                clients.broadcast(ServerMessageKind::RendererResponse(res));
+                if let Some((id, new_id)) = moved {
+                    self.move_instrument(id, new_id, &mut clients).await;
+                }
+                ServerMessageKind::Ack
+            }
+            ClientMessageKind::ReadDir(path) => {
+                if let Some(path) = self.virtual_paths.translate(&path) {
+                    if let Ok(dir) = std::fs::read_dir(&path) {
+                        let entries = dir
+                            .into_iter()
+                            .flatten()
+                            .map(|x| {
+                                (
+                                    x.path().is_dir(),
+                                    crate::path::remove_prefix(x.path().as_path(), &path),
+                                )
+                            })
+                            .collect();
+                        return ServerMessageKind::DirInfo(Some(entries));
+                    }
+                }
+                ServerMessageKind::DirInfo(None)
+            }
+            ClientMessageKind::ReadDirDeep(path, recursive) => ServerMessageKind::DirTree(
+                files::read_dir(&self.virtual_paths, &path, recursive).await,
+            ),
+            ClientMessageKind::DiskUsage => {
+                ServerMessageKind::DiskUsage(files::disk_usage(&self.virtual_paths).await)
+            }
+            ClientMessageKind::ListSoundfontPresets(path) => ServerMessageKind::SoundfontPresets(
+                files::soundfont_presets(&self.virtual_paths, &path).await,
+            ),
+            ClientMessageKind::InspectSfz(path) => {
+                ServerMessageKind::SfzInspection(sfz::inspect(&self.virtual_paths, &path).await)
+            }
+            ClientMessageKind::CopyFile(from, to) => {
+                match files::copy(&self.virtual_paths, &from, &to).await {
+                    Ok(()) => ServerMessageKind::Ack,
+                    Err(e) => ServerMessageKind::FileError(e),
+                }
+            }
+            ClientMessageKind::DrumMachineRequest(req) => {
+                let res = send_drum_machine_request(&self.requesters.drum_machine, req).await;
+                let mut cache = self.cache.lock().await;
+                if let Some(res) = res {
+                    cache.chache_drum_machine_update(&res);
+                    clients.broadcast(ServerMessageKind::DrumMachineUpdate(res));
+                    ServerMessageKind::Ack
+                } else {
+                    ServerMessageKind::Nak
+                }
+            }
+            ClientMessageKind::PadRequest(pads::RequestKind::TriggerPad(index)) => {
+                let pads = &self.pads;
+                if trigger_pad(index, pads, &self.requesters, &self.cache, &mut clients).await {
+                    ServerMessageKind::Ack
+                } else {
+                    ServerMessageKind::Nak
+                }
+            }
+            ClientMessageKind::PadRequest(req) => {
+                let res = self.pads.lock().await.process_request(req);
+                self.cache.lock().await.cache_pads_update(&res);
+                clients.broadcast(ServerMessageKind::PadUpdate(res));
+                ServerMessageKind::Ack
+            }
+            ClientMessageKind::SetlistRequest(req) => {
+                let setlist = &self.setlist;
+                let requesters = &self.requesters;
+                if process_setlist_request(req, setlist, requesters, &self.cache, &mut clients)
+                    .await
+                {
+                    ServerMessageKind::Ack
+                } else {
+                    ServerMessageKind::Nak
+                }
+            }
+            ClientMessageKind::SnapshotRequest(req) => {
+                // locked while recalling, like the setlist
+                let mut snapshots = self.snapshots.lock().await;
+                let state = self.cache.lock().await.get().clone();
+                let (res, actions) = snapshots.process_request(req, &state);
+                let mut ok = !matches!(res, JsonUpdateKind::InvalidId);
+                self.cache.lock().await.cache_snapshots_update(&res);
+                clients.broadcast(ServerMessageKind::SnapshotUpdate(res));
+                for action in actions {
+                    ok &= run_action(action, &self.requesters, &self.cache, &mut clients).await;
+                }
+                if ok {
+                    ServerMessageKind::Ack
+                } else {
+                    ServerMessageKind::Nak
+                }
+            }
+            ClientMessageKind::PerformanceRequest(req) => {
+                if self.handle_performance_request(req).await {
+                    ServerMessageKind::Ack
+                } else {
+                    ServerMessageKind::Nak
+                }
+            }
+            ClientMessageKind::AudioRequest(req) => {
+                let Some(audio) = &self.requesters.audio else {
+                    return ServerMessageKind::Nak;
+                };
+                let changes = matches!(req, audio::output::RequestKind::SetBufferSize(_));
+                let Some(res) = send_audio_request(audio, req).await else {
+                    return ServerMessageKind::Nak;
+                };
+                if changes && matches!(res, audio::output::ResponseKind::Latency(_)) {
+                    clients.broadcast(ServerMessageKind::AudioResponse(res.clone()));
+                }
+                ServerMessageKind::AudioResponse(res)
+            }
+            ClientMessageKind::RecorderRequest(req) => self.process_recorder_request(req).await,
+            ClientMessageKind::ControllerRequest(req) => {
+                let res = send_controller_request(&self.requesters.controller, req).await;
+                let mut cache = self.cache.lock().await;
+                if let Some(res) = res {
+                    cache.cache_controller_response(&res);
+                    clients.broadcast(ServerMessageKind::ControllerResponse(res));
+                    ServerMessageKind::Ack
+                } else {
+                    ServerMessageKind::Nak
+                }
+            }
+        }
+    }
+
+    // The drum machine and the controller nodes play the renderer's nodes by index, they follow
+    // the one that moved
+    async fn move_instrument(&self, id: usize, new_id: usize, clients: &mut Clients) {
+        let req = drum_machine::RequestKind::MoveInstrument(id, new_id);
+        if let Some(res) = send_drum_machine_request(&self.requesters.drum_machine, req).await {
+            self.cache.lock().await.chache_drum_machine_update(&res);
+            clients.broadcast(ServerMessageKind::DrumMachineUpdate(res));
+        }
+        let req = control::command::RequestKind::MoveInstrument { id, new_id };
+        if let Some(res) = send_controller_request(&self.requesters.controller, req).await {
+            self.cache.lock().await.cache_controller_response(&res);
+            clients.broadcast(ServerMessageKind::ControllerResponse(res));
+        }
+    }
+
+    async fn process_recorder_request(
+        &self,
+        req: midi::recorder::RequestKind,
+    ) -> ServerMessageKind {
+        type RK = midi::recorder::RequestKind;
+        let mut clients = self.clients.clone();
+        let mut recorder = self.recorder.lock().await;
+        match req {
+            RK::Start(path, filter) => {
+                let tempo_bpm = self.drum_machine_tempo().await;
+                match recorder.start(&self.virtual_paths, &path, filter, tempo_bpm, None) {
+                    Ok(()) => {
+                        info!("Recording MIDI to {path:?}");
+                        clients.broadcast(ServerMessageKind::RecorderUpdate(recorder.status()));
+                        ServerMessageKind::Ack
+                    }
+                    Err(e) => ServerMessageKind::FileError(e),
+                }
+            }
+            RK::StartSession(path, filter) => {
+                if recorder.is_recording() {
+                    return ServerMessageKind::Nak;
+                }
+                let midi_path = path.with_extension("mid");
+                let audio_path = path.with_extension("wav");
+                let real_audio_path = match files::translate(&self.virtual_paths, &audio_path) {
+                    Ok(real_audio_path) => real_audio_path,
+                    Err(e) => return ServerMessageKind::FileError(e),
+                };
+                let tempo_bpm = self.drum_machine_tempo().await;
+                let mut renderer = self.renderer.lock().await;
+                // nothing to record without a running output
+                let Some(sample_rate) = renderer.sample_rate() else {
+                    return ServerMessageKind::Nak;
+                };
+                let start = Instant::now();
+                let res = recorder.start(
+                    &self.virtual_paths,
+                    &midi_path,
+                    filter,
+                    tempo_bpm,
+                    Some(start),
+                );
+                if let Err(e) = res {
+                    return ServerMessageKind::FileError(e);
+                }
+                let file = match std::fs::File::create_new(real_audio_path) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        recorder.stop();
+                        return ServerMessageKind::FileError(e.into());
+                    }
+                };
+                let (tap, capture) = capture::start(audio_path.clone(), file, sample_rate, start);
+                renderer.set_capture(Some(tap));
+                *self.capture.lock().await = Some(capture);
+                recorder.set_audio_path(audio_path);
+                info!("Recording a session to {path:?}");
+                clients.broadcast(ServerMessageKind::RecorderUpdate(recorder.status()));
+                ServerMessageKind::Ack
+            }
+            RK::Stop => {
+                let Some((path, bytes)) = recorder.stop() else {
+                    return ServerMessageKind::Nak;
+                };
+                let capture = self.capture.lock().await.take();
+                if capture.is_some() {
+                    // the writer finishes the file once the tap is gone
+                    self.renderer.lock().await.set_capture(None);
+                }
+                clients.broadcast(ServerMessageKind::RecorderUpdate(recorder.status()));
+                drop(recorder);
+                if let Err(e) = files::write_new(&path, &bytes).await {
+                    return ServerMessageKind::FileError(e);
+                }
+                let Some(capture) = capture else {
+                    return ServerMessageKind::Ack;
+                };
+                match tokio::task::spawn_blocking(move || capture.finish()).await {
+                    Ok(Ok(_)) => ServerMessageKind::Ack,
+                    Ok(Err(e)) => ServerMessageKind::FileError(e.into()),
+                    Err(_) => ServerMessageKind::Nak,
+                }
+            }
+            RK::GetStatus => ServerMessageKind::RecorderUpdate(recorder.status()),
+        }
+    }
+
+    // Recordings are in the tempo of the drum machine, so they line up with the beat
+    async fn drum_machine_tempo(&self) -> f32 {
+        self.cache.lock().await.get()["drum_machine"]["tempo_bpm"]
+            .as_f64()
+            .unwrap_or(120.0) as f32
+    }
+}
+
+async fn run_midi_recorder(mut midi_rx: midi::Receiver, recorder: Arc<Mutex<Recorder>>) {
+    loop {
+        match midi_rx.recv().await {
+            Ok(message) => recorder.lock().await.record(&message),
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn run_midi_logger(mut midi_rx: midi::Receiver, mut clients: Clients) {
+    loop {
+        match midi_rx.recv().await {
+            Ok(message) => clients.broadcast(ServerMessageKind::MidiEvent(message)),
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+// Meters and statuses change at their own pace, every change is broadcast
+async fn run_watch_broadcasts<T: Clone>(
+    mut value_rx: watch::Receiver<T>,
+    mut clients: Clients,
+    message: fn(T) -> ServerMessageKind,
+) {
+    while let Ok(()) = value_rx.changed().await {
+        let value = value_rx.borrow_and_update().clone();
+        clients.broadcast(message(value));
+    }
+}
+
+// Updates the drum machine makes on its own, like moving through the song chain
+async fn run_drum_machine_updates(
+    mut update_rx: json::JsonUpdateListener,
+    cache: Arc<Mutex<Cache>>,
+    mut clients: Clients,
+) {
+    while let Some((_, update)) = update_rx.recv().await {
+        cache.lock().await.chache_drum_machine_update(&update);
+        clients.broadcast(ServerMessageKind::DrumMachineUpdate(update));
+    }
+}
+
+// Changes controller nodes make on their own, they look like responses to the clients
+async fn run_controller_updates(
+    mut update_rx: json::JsonUpdateListener,
+    cache: Arc<Mutex<Cache>>,
+    mut clients: Clients,
+) {
+    while let Some((id, kind)) = update_rx.recv().await {
+        let res = control::command::ResponseKind::NodeResponse { id, kind };
+        cache.lock().await.cache_controller_response(&res);
+        clients.broadcast(ServerMessageKind::ControllerResponse(res));
+    }
+}
+
+// Tempos the drum machine asks for are set for everything, the drum machine takes them from
+// the transport
+async fn run_drum_machine_tempo_requests(
+    mut req_rx: mpsc::Receiver<control::command::RequestKind>,
+    ctr_req_tx: control::command::Requester,
+    cache: Arc<Mutex<Cache>>,
+    mut clients: Clients,
+) {
+    while let Some(kind) = req_rx.recv().await {
+        let (res_tx, res_rx) = control::command::create_response_channel();
+        if ctr_req_tx.send((kind, res_tx)).await.is_err() {
+            return;
+        }
+        if let Ok(res) = res_rx.await {
+            cache.lock().await.cache_controller_response(&res);
+            clients.broadcast(ServerMessageKind::ControllerResponse(res));
+        }
+    }
+}
+
+// Responses the controller makes on its own, like for a setup taken at the bar line
+async fn run_controller_responses(
+    mut res_rx: mpsc::Receiver<control::command::ResponseKind>,
+    cache: Arc<Mutex<Cache>>,
+    mut clients: Clients,
+) {
+    while let Some(res) = res_rx.recv().await {
+        cache.lock().await.cache_controller_response(&res);
+        clients.broadcast(ServerMessageKind::ControllerResponse(res));
+    }
+}
+
+// The state and position of the transport, they look like responses to the clients
+async fn run_transport_updates(
+    mut transport_rx: watch::Receiver<control::transport::Transport>,
+    cache: Arc<Mutex<Cache>>,
+    mut clients: Clients,
+) {
+    let mut status = transport_rx.borrow_and_update().status();
+    while let Ok(()) = transport_rx.changed().await {
+        // tempo changes don't move it
+        let changed = transport_rx.borrow_and_update().status();
+        if changed == status {
+            continue;
+        }
+        status = changed;
+        let res = control::command::ResponseKind::Transport(status);
+        cache.lock().await.cache_controller_response(&res);
+        clients.broadcast(ServerMessageKind::ControllerResponse(res));
+    }
+}
+
+// Changes render nodes make on their own, like a looper closing its loop
+async fn run_renderer_updates(
+    mut update_rx: json::JsonUpdateListener,
+    cache: Arc<Mutex<Cache>>,
+    mut clients: Clients,
+) {
+    while let Some((id, kind)) = update_rx.recv().await {
+        let res = command::ResponseKind::NodeResponse { id, kind };
+        cache.lock().await.cache_renderer_response(&res);
+        clients.broadcast(ServerMessageKind::RendererResponse(res));
+    }
+}
+
+// Changes the renderer makes on the audio thread, like the transposition CC does
+async fn run_renderer_watch_updates<T: Clone>(
+    mut value_rx: watch::Receiver<T>,
+    cache: Arc<Mutex<Cache>>,
+    mut clients: Clients,
+    response: fn(T) -> command::ResponseKind,
+) {
+    while let Ok(()) = value_rx.changed().await {
+        let res = response(value_rx.borrow_and_update().clone());
+        cache.lock().await.cache_renderer_response(&res);
+        clients.broadcast(ServerMessageKind::RendererResponse(res));
+    }
+}
+
+async fn run_pad_midi_triggers(
+    mut midi_rx: midi::Receiver,
+    pads: Arc<Mutex<Pads>>,
+    requesters: Requesters,
+    cache: Arc<Mutex<Cache>>,
+    mut clients: Clients,
+) {
+    loop {
+        let message = match midi_rx.recv().await {
+            Ok(message) => message,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        let triggered = pads.lock().await.triggered_by(&message);
+        for index in triggered {
+            trigger_pad(index, &pads, &requesters, &cache, &mut clients).await;
+        }
+    }
+}
+
+// The pads stay locked while the actions run, so actions of two pads never interleave
+pub async fn trigger_pad(
+    index: usize,
+    pads: &Mutex<Pads>,
+    requesters: &Requesters,
+    cache: &Mutex<Cache>,
+    clients: &mut Clients,
+) -> bool {
+    let pads = pads.lock().await;
+    let Some(actions) = pads.actions(index) else {
+        return false;
+    };
+    let mut ok = true;
+    for action in actions {
+        ok &= run_action(action, requesters, cache, clients).await;
+    }
+    ok
+}
+
+// A footswitch moves through the setlist like the next and previous requests do
+async fn run_setlist_midi_triggers(
+    mut midi_rx: midi::Receiver,
+    setlist: Arc<Mutex<Setlist>>,
+    requesters: Requesters,
+    cache: Arc<Mutex<Cache>>,
+    mut clients: Clients,
+) {
+    loop {
+        let message = match midi_rx.recv().await {
+            Ok(message) => message,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        let Some(req) = setlist.lock().await.navigation_by(&message) else {
+            continue;
+        };
+        process_setlist_request(req, &setlist, &requesters, &cache, &mut clients).await;
+    }
+}
+
+// The setlist stays locked while the actions of an entry run, so two songs never get mixed up
+pub async fn process_setlist_request(
+    req: setlist::RequestKind,
+    setlist: &Mutex<Setlist>,
+    requesters: &Requesters,
+    cache: &Mutex<Cache>,
+    clients: &mut Clients,
+) -> bool {
+    let mut setlist = setlist.lock().await;
+    let (res, actions) = setlist.process_request(req);
+    cache.lock().await.cache_setlist_update(&res);
+    clients.broadcast(ServerMessageKind::SetlistUpdate(res));
+    let mut ok = true;
+    for action in actions {
+        ok &= run_action(action, requesters, cache, clients).await;
+    }
+    ok
+}
+
+// A program change switches the performance like the switch request does
+async fn run_performance_midi_triggers(
+    mut midi_rx: midi::Receiver,
+    performances: Arc<Mutex<Performances>>,
+    requesters: Requesters,
+    cache: Arc<Mutex<Cache>>,
+    mut clients: Clients,
+) {
+    loop {
+        let message = match midi_rx.recv().await {
+            Ok(message) => message,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        let Some(req) = performances.lock().await.switch_by(&message) else {
+            continue;
+        };
+        process_performance_request(req, &performances, &requesters, &cache, &mut clients).await;
+    }
+}
+
+// Locked while switching like the setlist, a failed request or switch answers false
+pub async fn process_performance_request(
+    req: performances::RequestKind,
+    performances: &Mutex<Performances>,
+    requesters: &Requesters,
+    cache: &Mutex<Cache>,
+    clients: &mut Clients,
+) -> bool {
+    let mut performances = performances.lock().await;
+    let (res, actions) = performances.process_request(req);
+    let mut ok = matches!(res, JsonUpdateKind::UpdateFields(_));
+    cache.lock().await.cache_performances_update(&res);
+    clients.broadcast(ServerMessageKind::PerformanceUpdate(res));
+    for action in actions {
+        ok &= run_action(action, requesters, cache, clients).await;
+    }
+    ok
+}
+
+pub async fn run_action(
+    action: Action,
+    requesters: &Requesters,
+    cache: &Mutex<Cache>,
+    clients: &mut Clients,
+) -> bool {
+    match action {
+        Action::Renderer(req) => {
+            let Some(res) = send_renderer_request(&requesters.renderer, *req).await else {
+                return false;
+            };
+            cache.lock().await.cache_renderer_response(&res);
+            clients.broadcast(ServerMessageKind::RendererResponse(res));
+        }
+        Action::DrumMachine(req) => {
+            let Some(res) = send_drum_machine_request(&requesters.drum_machine, req).await else {
+                return false;
+            };
+            cache.lock().await.chache_drum_machine_update(&res);
+            clients.broadcast(ServerMessageKind::DrumMachineUpdate(res));
+        }
+        Action::Controller(req) => {
+            let Some(res) = send_controller_request(&requesters.controller, req).await else {
+                return false;
+            };
+            cache.lock().await.cache_controller_response(&res);
+            clients.broadcast(ServerMessageKind::ControllerResponse(res));
+        }
+        Action::Pads(pads) => {
+            let req = pads::RequestKind::SetPads(pads);
+            let res = requesters.pads.lock().await.process_request(req);
+            cache.lock().await.cache_pads_update(&res);
+            clients.broadcast(ServerMessageKind::PadUpdate(res));
+        }
+    }
+    true
+}
+
+pub async fn send_renderer_request(
+    req_tx: &command::Requester,
+    req: command::RequestKind,
+) -> Option<command::ResponseKind> {
+    let (res_tx, res_rx) = command::create_response_channel();
+
+    if let Ok(()) = req_tx.send((req, res_tx)).await {
+        if let Ok(response_kind) = res_rx.await {
+            Some(response_kind)
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+pub async fn send_drum_machine_request(
+    req_tx: &drum_machine::Requester,
+    req: drum_machine::RequestKind,
+) -> Option<JsonUpdateKind> {
+    let (res_tx, res_rx) = drum_machine::create_response_channel();
+
+    if let Ok(()) = req_tx.send((req, res_tx)).await {
+        if let Ok(response_kind) = res_rx.await {
+            Some(response_kind)
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+pub async fn send_controller_request(
+    req_tx: &control::command::Requester,
+    req: control::command::RequestKind,
+) -> Option<control::command::ResponseKind> {
+    let (res_tx, res_rx) = control::command::create_response_channel();
+
+    if let Ok(()) = req_tx.send((req, res_tx)).await {
+        res_rx.await.ok()
+    } else {
+        None
+    }
+}
+
+pub async fn send_audio_request(
+    req_tx: &audio::output::Requester,
+    req: audio::output::RequestKind,
+) -> Option<audio::output::ResponseKind> {
+    let (res_tx, res_rx) = audio::output::create_response_channel();
+
+    if let Ok(()) = req_tx.send((req, res_tx)).await {
+        res_rx.await.ok()
+    } else {
+        None
+    }
+}
@@ -85,6 +85,21 @@ pub fn print_info() {
     }
 }
 
+// (host name, names of its input devices) of every host
+pub fn get_available_inputs() -> Vec<(String, Vec<String>)> {
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|host_id| cpal::host_from_id(host_id).ok())
+        .map(|host| {
+            let names = match host.input_devices() {
+                Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+                Err(_) => vec![],
+            };
+            (host.id().name().to_owned(), names)
+        })
+        .collect()
+}
+
 fn get_available_hosts_struct(available_hosts: Vec<cpal::HostId>) -> HashMap<String, OutDevices> {
     available_hosts.iter().fold(
         HashMap::with_capacity(available_hosts.len()),
@@ -0,0 +1,251 @@
+// Live audio from an input device, like a mic or a guitar, for the render nodes playing it. The
+// input callback hands every listening node buffers of its own, like the capture tap hands them
+// to its writer, so it never waits on the renderer. The device runs at the sample rate of the
+// output and what it captures gets to the nodes a buffer or two later.
+
+use super::{
+    output::{find_host, Error},
+    realtime,
+};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    Device, FromSample, Host, Sample, SampleRate, SizedSample, Stream, StreamConfig,
+};
+use std::sync::{mpsc, Arc, Mutex};
+use tracing::error;
+
+// Buffers waiting for a node, when it falls this far behind the input gets dropped instead of
+// adding to the latency
+const MAX_PENDING_BUFFERS: usize = 4;
+
+struct Block {
+    // Interleaved, as many channels as the device has
+    samples: Vec<f32>,
+    channels: usize,
+}
+
+struct Listener {
+    block_tx: mpsc::SyncSender<Block>,
+    spare_rx: mpsc::Receiver<Vec<f32>>,
+}
+
+// Shared by the input stream and the nodes, a node listens as long as its feed lives
+#[derive(Clone, Default)]
+pub struct Input {
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
+
+impl Input {
+    pub fn listen(&self) -> Feed {
+        let (block_tx, block_rx) = mpsc::sync_channel(MAX_PENDING_BUFFERS);
+        let (spare_tx, spare_rx) = mpsc::channel();
+        if let Ok(mut listeners) = self.listeners.lock() {
+            listeners.push(Listener { block_tx, spare_rx });
+        }
+        Feed {
+            block_rx: Mutex::new(block_rx),
+            spare_tx,
+            block: None,
+            frame: 0,
+        }
+    }
+
+    // From the input callback, which allocates only until enough buffers are in circulation.
+    // The buffer is skipped rather than waiting while a node starts listening.
+    pub fn push<T>(&self, data: &[T], channels: usize)
+    where
+        T: Copy,
+        f32: FromSample<T>,
+    {
+        let Ok(mut listeners) = self.listeners.try_lock() else {
+            return;
+        };
+        listeners.retain(|listener| {
+            let mut samples = listener.spare_rx.try_recv().unwrap_or_default();
+            samples.clear();
+            samples.extend(data.iter().map(|&sample| f32::from_sample(sample)));
+            let res = listener.block_tx.try_send(Block { samples, channels });
+            !matches!(res, Err(mpsc::TrySendError::Disconnected(_)))
+        });
+    }
+}
+
+// A node's end
+pub struct Feed {
+    // Never locked, render nodes have to be Sync
+    block_rx: Mutex<mpsc::Receiver<Block>>,
+    spare_tx: mpsc::Sender<Vec<f32>>,
+    // The block being played and the frame of it that's next
+    block: Option<Block>,
+    frame: usize,
+}
+
+impl Feed {
+    // Adds the input to the buffers, the channel on both sides or else channels 1/2 as a pair,
+    // a mono device on both sides either way. Where the input is late the buffers stay as they
+    // are.
+    pub fn add_to(
+        &mut self,
+        lbuf: &mut [f32],
+        rbuf: &mut [f32],
+        channel: Option<usize>,
+        gain: f32,
+    ) {
+        let len = lbuf.len().min(rbuf.len());
+        let mut n = 0;
+        while n < len {
+            if self.block.is_none() {
+                let Ok(block_rx) = self.block_rx.get_mut() else {
+                    return;
+                };
+                let Ok(block) = block_rx.try_recv() else {
+                    return;
+                };
+                self.block = Some(block);
+                self.frame = 0;
+            }
+            let Some(block) = &self.block else {
+                return;
+            };
+            let channels = block.channels.max(1);
+            for frame in block.samples.chunks_exact(channels).skip(self.frame) {
+                if n == len {
+                    break;
+                }
+                let (l, r) = match channel {
+                    Some(channel) => {
+                        let sample = frame.get(channel).copied().unwrap_or(0.0);
+                        (sample, sample)
+                    }
+                    None => (frame[0], frame.get(1).copied().unwrap_or(frame[0])),
+                };
+                lbuf[n] += l * gain;
+                rbuf[n] += r * gain;
+                n += 1;
+                self.frame += 1;
+            }
+            if self.frame * channels >= block.samples.len() {
+                if let Some(block) = self.block.take() {
+                    _ = self.spare_tx.send(block.samples);
+                }
+            }
+        }
+    }
+}
+
+// Streams can't move between threads on every platform, so the stream gets built on a thread
+// of its own, which keeps it going for as long as AMI runs, fails if building the stream does
+pub fn spawn(
+    host_name: String,
+    device_name: String,
+    sample_rate: u32,
+    input: Input,
+) -> Result<(), Error> {
+    let (built_tx, built_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        match init_input_device(&host_name, &device_name, sample_rate, input) {
+            Ok(_stream) => {
+                let _ = built_tx.send(Ok(()));
+                loop {
+                    std::thread::park();
+                }
+            }
+            Err(e) => {
+                let _ = built_tx.send(Err(e));
+            }
+        }
+    });
+    built_rx
+        .recv()
+        .expect("The audio input thread stopped unexpectedly")
+}
+
+fn find_input_device(host: Host, device_name: &str) -> Option<Device> {
+    host.input_devices()
+        .ok()?
+        .find(|device| device.name().is_ok_and(|name| name == device_name))
+}
+
+fn init_input_device(
+    host_name: &str,
+    device_name: &str,
+    sample_rate: u32,
+    input: Input,
+) -> Result<Stream, Error> {
+    let host = find_host(host_name).ok_or(Error::HostNotFound)?;
+    let device = find_input_device(host, device_name).ok_or(Error::DeviceNotFound)?;
+    let config = device
+        .default_input_config()
+        .map_err(|_| Error::NoDefaultConfig)?;
+    let sample_format = config.sample_format();
+    let mut cfg: StreamConfig = config.into();
+    cfg.sample_rate = SampleRate(sample_rate);
+    match sample_format {
+        cpal::SampleFormat::I8 => create_stream::<i8>(&device, &cfg, input),
+        cpal::SampleFormat::I16 => create_stream::<i16>(&device, &cfg, input),
+        cpal::SampleFormat::I32 => create_stream::<i32>(&device, &cfg, input),
+        cpal::SampleFormat::I64 => create_stream::<i64>(&device, &cfg, input),
+        cpal::SampleFormat::U8 => create_stream::<u8>(&device, &cfg, input),
+        cpal::SampleFormat::U16 => create_stream::<u16>(&device, &cfg, input),
+        cpal::SampleFormat::U32 => create_stream::<u32>(&device, &cfg, input),
+        cpal::SampleFormat::U64 => create_stream::<u64>(&device, &cfg, input),
+        cpal::SampleFormat::F32 => create_stream::<f32>(&device, &cfg, input),
+        cpal::SampleFormat::F64 => create_stream::<f64>(&device, &cfg, input),
+        f => Err(Error::UnsupportedSampleFormat(f)),
+    }
+}
+
+fn create_stream<T>(device: &Device, config: &StreamConfig, input: Input) -> Result<Stream, Error>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let err_fn = move |err| error!("An error occurred on the input stream: {}", err);
+    let mut promoted = false;
+    let stream = device
+        .build_input_stream(
+            config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| {
+                realtime::promote_current_thread_once(&mut promoted);
+                input.push(data, channels);
+            },
+            err_fn,
+            None,
+        )
+        .map_err(Error::BuildStream)?;
+    stream.play().map_err(Error::PlayStream)?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Input;
+
+    #[test]
+    fn feeds_the_listening_nodes() {
+        let input = Input::default();
+        let mut feed = input.listen();
+        let mut other = input.listen();
+        input.push(&[1.0f32, 2.0, 3.0, 4.0], 2);
+        input.push(&[5.0f32, 6.0], 2);
+
+        let (mut lbuf, mut rbuf) = (vec![0.0; 2], vec![0.0; 2]);
+        feed.add_to(&mut lbuf, &mut rbuf, None, 1.0);
+        assert_eq!(lbuf, [1.0, 3.0]);
+        assert_eq!(rbuf, [2.0, 4.0]);
+        // the rest of the input, then it's late
+        let (mut lbuf, mut rbuf) = (vec![0.0; 2], vec![0.0; 2]);
+        feed.add_to(&mut lbuf, &mut rbuf, Some(1), 0.5);
+        assert_eq!(lbuf, [3.0, 0.0]);
+        assert_eq!(rbuf, [3.0, 0.0]);
+
+        let (mut lbuf, mut rbuf) = (vec![0.0; 3], vec![0.0; 3]);
+        other.add_to(&mut lbuf, &mut rbuf, Some(0), 1.0);
+        assert_eq!(lbuf, [1.0, 3.0, 5.0]);
+
+        drop(other);
+        input.push(&[7i16], 1);
+        assert_eq!(input.listeners.lock().unwrap().len(), 1);
+    }
+}
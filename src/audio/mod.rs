@@ -1,3 +1,5 @@
 
 pub mod info;
-pub mod output;
\ No newline at end of file
+pub mod input;
+pub mod output;
+pub mod realtime;
@@ -1,12 +1,103 @@
-use super::info;
-use crate::render::Renderer;
+use super::{info, realtime};
+use crate::render::{Renderer, MAX_BUFFER_SIZE};
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
     BufferSize, Device, FromSample, Host, SampleRate, SizedSample, Stream, StreamConfig,
 };
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot, watch, Mutex, Notify};
+use tracing::{error, info, warn};
+
+pub const MIN_BUFFER_SIZE: usize = 16;
+
+// How often the device is looked for, while connected and while it's gone
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// What the status shows while rendering without a device
+pub const NULL_OUTPUT_NAME: &str = "Null output";
+
+pub type Requester = mpsc::Sender<(RequestKind, Responder)>;
+pub type RequestListener = mpsc::Receiver<(RequestKind, Responder)>;
+pub type Responder = oneshot::Sender<ResponseKind>;
+pub type ResponseListener = oneshot::Receiver<ResponseKind>;
+
+pub fn create_request_channel(buffer: usize) -> (Requester, RequestListener) {
+    mpsc::channel(buffer)
+}
+
+pub fn create_response_channel() -> (Responder, ResponseListener) {
+    oneshot::channel()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestKind {
+    GetLatency,
+    GetDeviceStatus,
+    // How many channels the stream has, the render nodes can be routed to pairs of them
+    GetChannels,
+    // Rebuilds the stream, the renderer keeps running
+    SetBufferSize(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ResponseKind {
+    Latency(Latency),
+    DeviceStatus(DeviceStatus),
+    Channels(usize),
+    UnsupportedBufferSize,
+    Failed,
+}
+
+// A note played right after a callback waits for the next one to be rendered, then for the
+// device to play that buffer, so the worst case is the sum of both
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Latency {
+    // The frames per callback the device actually asks for
+    pub buffer_size: usize,
+    pub sample_rate: u32,
+    pub buffer_ms: f32,
+    // From a callback until its first frame is played, `None` if the host doesn't tell
+    pub device_ms: Option<f32>,
+    pub total_ms: f32,
+}
+
+// With the name of the device
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum DeviceStatus {
+    #[default]
+    Disconnected,
+    Connected(String),
+    // Unplugged or failed, reconnecting to it or another device is tried until it works
+    Lost(String),
+}
+
+// Written by the stream callbacks
+#[derive(Debug)]
+struct StreamStats {
+    period: AtomicUsize,
+    device_latency_us: AtomicU64,
+    lost: AtomicBool,
+    // Wakes the output thread up to reconnect right away
+    lost_notify: Notify,
+}
+
+impl Default for StreamStats {
+    fn default() -> Self {
+        Self {
+            period: AtomicUsize::new(0),
+            device_latency_us: AtomicU64::new(u64::MAX),
+            lost: AtomicBool::new(false),
+            lost_notify: Notify::new(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
@@ -25,7 +116,16 @@ pub struct Controller {
     stream: Option<Stream>,
     pub sample_rate: u32,
     pub buffer_size: usize,
+    // Asked of the device, `--list-devices` shows how many it has
+    pub channels: u16,
     num_channels: usize,
+    // (host name, device name) of the stream
+    device: Option<(String, String)>,
+    // Renders at the pace of a device without having one
+    null_output: bool,
+    stats: Arc<StreamStats>,
+    status_tx: watch::Sender<DeviceStatus>,
+    next_device_check: Instant,
 }
 
 impl Controller {
@@ -35,7 +135,13 @@ impl Controller {
             stream: Default::default(),
             sample_rate: 44100,
             buffer_size: 128,
+            channels: 2,
             num_channels: 0,
+            device: None,
+            null_output: false,
+            stats: Default::default(),
+            status_tx: watch::Sender::new(DeviceStatus::Disconnected),
+            next_device_check: Instant::now(),
         }
     }
 
@@ -44,17 +150,27 @@ impl Controller {
         host_name: &str,
         device_name: &str,
     ) -> Result<(), Error> {
+        let stats = Arc::new(StreamStats::default());
         let (stream, num_channels) = init_output_device(
             host_name,
             device_name,
             self.sample_rate,
             self.buffer_size as u32,
+            self.channels,
             Arc::clone(&self.renderer),
+            Arc::clone(&stats),
         )?;
         self.stream = Some(stream);
         self.num_channels = num_channels;
+        self.device = Some((host_name.to_owned(), device_name.to_owned()));
+        self.null_output = false;
+        self.stats = stats;
+        self.status_tx
+            .send_replace(DeviceStatus::Connected(device_name.to_owned()));
         futures::executor::block_on(async {
-            self.renderer.lock().await.set_sample_rate(self.sample_rate);
+            let mut renderer = self.renderer.lock().await;
+            renderer.set_sample_rate(self.sample_rate);
+            renderer.set_output_pairs(num_channels.div_ceil(2));
         });
         Ok(())
     }
@@ -66,9 +182,222 @@ impl Controller {
         let host_name = info::get_default_host_name();
         self.connect_to_output_device(&host_name, &device_name)
     }
+
+    // For CI, servers and using AMI only for its MIDI, the output thread renders the buffers
+    // and throws them away
+    pub fn connect_to_null_output(&mut self) {
+        self.stream = None;
+        self.num_channels = 2;
+        self.device = None;
+        self.null_output = true;
+        self.stats = Default::default();
+        self.status_tx
+            .send_replace(DeviceStatus::Connected(NULL_OUTPUT_NAME.to_owned()));
+        futures::executor::block_on(async {
+            let mut renderer = self.renderer.lock().await;
+            renderer.set_sample_rate(self.sample_rate);
+            renderer.set_output_pairs(1);
+        });
+    }
+
+    pub fn subscribe_status(&self) -> watch::Receiver<DeviceStatus> {
+        self.status_tx.subscribe()
+    }
+
+    pub fn is_lost(&self) -> bool {
+        matches!(*self.status_tx.borrow(), DeviceStatus::Lost(_))
+    }
+
+    // Lost or null, either way nothing pulls the audio
+    fn renders_without_device(&self) -> bool {
+        self.null_output || self.is_lost()
+    }
+
+    // Notices a lost device and tries to get a stream going again, first on the same device,
+    // then on the default one, does nothing until DEVICE_CHECK_INTERVAL passed since last time
+    pub fn check_device(&mut self) {
+        let now = Instant::now();
+        if now < self.next_device_check {
+            return;
+        }
+        self.next_device_check = now + DEVICE_CHECK_INTERVAL;
+        let Some((host_name, device_name)) = self.device.clone() else {
+            return;
+        };
+
+        if !self.is_lost() {
+            let gone = self.stats.lost.load(Ordering::Relaxed)
+                || find_host(&host_name)
+                    .and_then(|host| find_output_device(host, &device_name))
+                    .is_none();
+            if !gone {
+                return;
+            }
+            warn!("Audio output device {device_name} is gone");
+            self.stream = None;
+            self.status_tx
+                .send_replace(DeviceStatus::Lost(device_name.clone()));
+        }
+
+        if self
+            .connect_to_output_device(&host_name, &device_name)
+            .is_ok()
+        {
+            info!("Reconnected to audio output device {device_name}");
+            return;
+        }
+        let host = info::get_default_host();
+        let Some(default_name) = info::get_default_output_device_name(&host) else {
+            return;
+        };
+        let default_host_name = info::get_default_host_name();
+        if (&default_host_name, &default_name) != (&host_name, &device_name)
+            && self
+                .connect_to_output_device(&default_host_name, &default_name)
+                .is_ok()
+        {
+            info!("Switched to audio output device {default_name}");
+        }
+    }
+
+    // Without a device nothing pulls audio from the renderer, so this keeps it going for a
+    // buffer, returns how long the buffer would play
+    pub fn render_without_device(&mut self, lbuf: &mut Vec<f32>, rbuf: &mut Vec<f32>) -> Duration {
+        lbuf.resize(self.buffer_size, 0.0);
+        rbuf.resize(self.buffer_size, 0.0);
+        // called from within the runtime of the output thread, which can't block on its own
+        futures::executor::block_on(async {
+            self.renderer.lock().await.render(lbuf, rbuf);
+        });
+        Duration::from_secs_f32(self.buffer_size as f32 / self.sample_rate as f32)
+    }
+
+    pub fn latency(&self) -> Latency {
+        let buffer_size = match self.stats.period.load(Ordering::Relaxed) {
+            0 => self.buffer_size,
+            period => period,
+        };
+        let buffer_ms = buffer_size as f32 / self.sample_rate as f32 * 1000.0;
+        let device_ms = match self.stats.device_latency_us.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            us => Some(us as f32 / 1000.0),
+        };
+        Latency {
+            buffer_size,
+            sample_rate: self.sample_rate,
+            buffer_ms,
+            device_ms,
+            total_ms: buffer_ms + device_ms.unwrap_or(0.0),
+        }
+    }
+
+    // Reconnects to the device with the new size, on failure the previous size is restored
+    pub fn set_buffer_size(&mut self, buffer_size: usize) -> Result<(), Error> {
+        if !(MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE).contains(&buffer_size) {
+            return Err(Error::UnsupportedBufferSize);
+        }
+        let previous = std::mem::replace(&mut self.buffer_size, buffer_size);
+        let Some((host_name, device_name)) = self.device.clone() else {
+            return Ok(());
+        };
+        // some hosts open devices exclusively, so the old stream has to go first
+        self.stream = None;
+        let result = self.connect_to_output_device(&host_name, &device_name);
+        if result.is_err() {
+            self.buffer_size = previous;
+            if let Err(e) = self.connect_to_output_device(&host_name, &device_name) {
+                error!("Failed to reconnect to {device_name} after resizing failed: {e:?}");
+            }
+        }
+        result
+    }
+
+    pub fn process_request(&mut self, kind: RequestKind) -> ResponseKind {
+        match kind {
+            RequestKind::GetLatency => ResponseKind::Latency(self.latency()),
+            RequestKind::GetDeviceStatus => {
+                ResponseKind::DeviceStatus(self.status_tx.borrow().clone())
+            }
+            RequestKind::GetChannels => ResponseKind::Channels(self.num_channels),
+            RequestKind::SetBufferSize(buffer_size) => match self.set_buffer_size(buffer_size) {
+                Ok(()) => ResponseKind::Latency(self.latency()),
+                Err(Error::UnsupportedBufferSize) => ResponseKind::UnsupportedBufferSize,
+                Err(e) => {
+                    error!("Failed to change the buffer size: {e:?}");
+                    ResponseKind::Failed
+                }
+            },
+        }
+    }
 }
 
-fn find_host(host_name: &str) -> Option<Host> {
+// Streams can't move between threads on every platform, so the controller gets built and
+// served on a thread of its own, which also looks after the device, fails if building the
+// controller does. The thread sleeps until a request comes in, the stream reports the device
+// lost, the next device check is due or, without a device, the next buffer has to be rendered.
+pub fn spawn<F>(build: F) -> Result<(Requester, watch::Receiver<DeviceStatus>), Error>
+where
+    F: FnOnce() -> Result<Controller, Error> + Send + 'static,
+{
+    let (req_tx, mut req_rx) = create_request_channel(8);
+    let (built_tx, built_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut controller = match build() {
+            Ok(controller) => {
+                let _ = built_tx.send(Ok(controller.subscribe_status()));
+                controller
+            }
+            Err(e) => {
+                let _ = built_tx.send(Err(e));
+                return;
+            }
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("Failed to build the audio output runtime");
+        runtime.block_on(async move {
+            let (mut lbuf, mut rbuf) = (Vec::new(), Vec::new());
+            let mut promoted = false;
+            let mut next_render = Instant::now();
+            loop {
+                let stats = Arc::clone(&controller.stats);
+                let wake_at = if controller.renders_without_device() {
+                    next_render.min(controller.next_device_check)
+                } else {
+                    controller.next_device_check
+                };
+                tokio::select! {
+                    req = req_rx.recv() => {
+                        let Some((kind, responder)) = req else {
+                            break;
+                        };
+                        let _ = responder.send(controller.process_request(kind));
+                        continue;
+                    }
+                    _ = stats.lost_notify.notified() => {
+                        controller.next_device_check = Instant::now();
+                    }
+                    _ = tokio::time::sleep_until(wake_at.into()) => {}
+                }
+                controller.check_device();
+                let now = Instant::now();
+                if controller.renders_without_device() && now >= next_render {
+                    realtime::promote_current_thread_once(&mut promoted);
+                    let period = controller.render_without_device(&mut lbuf, &mut rbuf);
+                    // after falling behind, the pace starts over instead of catching up
+                    next_render = (next_render + period).max(now);
+                }
+            }
+        });
+    });
+    let status_rx = built_rx
+        .recv()
+        .expect("The audio output thread stopped unexpectedly")?;
+    Ok((req_tx, status_rx))
+}
+
+pub(super) fn find_host(host_name: &str) -> Option<Host> {
     let host_id = cpal::available_hosts()
         .into_iter()
         .find(|host| host.name() == host_name)?;
@@ -90,7 +419,9 @@ fn init_output_device(
     device_name: &str,
     sample_rate: u32,
     buffer_size: u32,
+    channels: u16,
     renderer: Arc<Mutex<Renderer>>,
+    stats: Arc<StreamStats>,
 ) -> Result<(Stream, usize), Error> {
     let host = find_host(host_name).ok_or(Error::HostNotFound)?;
     let device = find_output_device(host, device_name).ok_or(Error::DeviceNotFound)?;
@@ -101,8 +432,8 @@ fn init_output_device(
     let mut cfg: StreamConfig = config.into();
     cfg.buffer_size = BufferSize::Fixed(buffer_size);
     cfg.sample_rate = SampleRate(sample_rate);
-    cfg.channels = 2;
-    let stream = create_stream_dispatched(sample_format, device, &cfg, renderer)?;
+    cfg.channels = channels;
+    let stream = create_stream_dispatched(sample_format, device, &cfg, renderer, stats)?;
     Ok((stream, cfg.channels as usize))
 }
 
@@ -111,18 +442,19 @@ fn create_stream_dispatched(
     device: Device,
     cfg: &StreamConfig,
     renderer: Arc<Mutex<Renderer>>,
+    stats: Arc<StreamStats>,
 ) -> Result<Stream, Error> {
     match sample_format {
-        cpal::SampleFormat::I8 => create_stream::<i8>(&device, cfg, renderer),
-        cpal::SampleFormat::I16 => create_stream::<i16>(&device, cfg, renderer),
-        cpal::SampleFormat::I32 => create_stream::<i32>(&device, cfg, renderer),
-        cpal::SampleFormat::I64 => create_stream::<i64>(&device, cfg, renderer),
-        cpal::SampleFormat::U8 => create_stream::<u8>(&device, cfg, renderer),
-        cpal::SampleFormat::U16 => create_stream::<u16>(&device, cfg, renderer),
-        cpal::SampleFormat::U32 => create_stream::<u32>(&device, cfg, renderer),
-        cpal::SampleFormat::U64 => create_stream::<u64>(&device, cfg, renderer),
-        cpal::SampleFormat::F32 => create_stream::<f32>(&device, cfg, renderer),
-        cpal::SampleFormat::F64 => create_stream::<f64>(&device, cfg, renderer),
+        cpal::SampleFormat::I8 => create_stream::<i8>(&device, cfg, renderer, stats),
+        cpal::SampleFormat::I16 => create_stream::<i16>(&device, cfg, renderer, stats),
+        cpal::SampleFormat::I32 => create_stream::<i32>(&device, cfg, renderer, stats),
+        cpal::SampleFormat::I64 => create_stream::<i64>(&device, cfg, renderer, stats),
+        cpal::SampleFormat::U8 => create_stream::<u8>(&device, cfg, renderer, stats),
+        cpal::SampleFormat::U16 => create_stream::<u16>(&device, cfg, renderer, stats),
+        cpal::SampleFormat::U32 => create_stream::<u32>(&device, cfg, renderer, stats),
+        cpal::SampleFormat::U64 => create_stream::<u64>(&device, cfg, renderer, stats),
+        cpal::SampleFormat::F32 => create_stream::<f32>(&device, cfg, renderer, stats),
+        cpal::SampleFormat::F64 => create_stream::<f64>(&device, cfg, renderer, stats),
         f => Err(Error::UnsupportedSampleFormat(f)),
     }
 }
@@ -131,21 +463,37 @@ fn create_stream<T>(
     device: &Device,
     config: &StreamConfig,
     renderer: Arc<Mutex<Renderer>>,
+    stats: Arc<StreamStats>,
 ) -> Result<Stream, Error>
 where
     T: SizedSample + FromSample<f32>,
 {
     let channels = config.channels as usize;
     // let mut next_value = move || 0.0;
-    let err_fn = |err| error!("An error occurred on stream: {}", err); //TODO: handle this case
+    let err_stats = Arc::clone(&stats);
+    let err_fn = move |err| {
+        error!("An error occurred on stream: {}", err);
+        if let cpal::StreamError::DeviceNotAvailable = err {
+            err_stats.lost.store(true, Ordering::Relaxed);
+            err_stats.lost_notify.notify_one();
+        }
+    };
     let mut lbuf = vec![];
     let mut rbuf = vec![];
+    let mut promoted = false;
 
     let stream = device
         .build_output_stream(
             config,
-            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+                realtime::promote_current_thread_once(&mut promoted);
                 let curr_buf_size = data.len() / channels;
+                let timestamp = info.timestamp();
+                if let Some(latency) = timestamp.playback.duration_since(&timestamp.callback) {
+                    let us = latency.as_micros() as u64;
+                    stats.device_latency_us.store(us, Ordering::Relaxed);
+                }
+                stats.period.store(curr_buf_size, Ordering::Relaxed);
                 if lbuf.len() < curr_buf_size {
                     lbuf.resize(curr_buf_size, 0.0);
                     rbuf.resize(curr_buf_size, 0.0);
@@ -153,14 +501,31 @@ where
                 let lbuf_slice = &mut lbuf[..curr_buf_size];
                 let rbuf_slice = &mut rbuf[..curr_buf_size];
 
-                futures::executor::block_on(async {
-                    let mut renderer = renderer.lock().await;
-                    renderer.render(lbuf_slice, rbuf_slice);
-                });
-                for (n, frame) in data.chunks_mut(channels).enumerate() {
-                    let values = [T::from_sample(lbuf_slice[n]), T::from_sample(rbuf_slice[n])];
-                    for (k, sample) in frame.iter_mut().enumerate() {
-                        *sample = values[k & 1];
+                let mut renderer = renderer.blocking_lock();
+                renderer.render(lbuf_slice, rbuf_slice);
+                if channels == 2 {
+                    // the common case gets a loop without any indexing
+                    let frames = data
+                        .chunks_exact_mut(2)
+                        .zip(lbuf_slice.iter().zip(&*rbuf_slice));
+                    for (frame, (&l, &r)) in frames {
+                        frame[0] = T::from_sample(l);
+                        frame[1] = T::from_sample(r);
+                    }
+                } else {
+                    // a pair at a time, the channels of pairs the renderer doesn't have are silent
+                    for pair in 0..channels.div_ceil(2) {
+                        let (pair_lbuf, pair_rbuf) = match pair {
+                            0 => (&*lbuf_slice, &*rbuf_slice),
+                            _ => renderer.output_pair(pair).unwrap_or((&[], &[])),
+                        };
+                        for (n, frame) in data.chunks_mut(channels).enumerate() {
+                            let values = [pair_lbuf.get(n), pair_rbuf.get(n)];
+                            for (k, sample) in frame.iter_mut().enumerate().skip(pair * 2).take(2) {
+                                let value = values[k & 1].copied().unwrap_or(0.0);
+                                *sample = T::from_sample(value);
+                            }
+                        }
                     }
                 }
             },
@@ -174,10 +539,100 @@ where
 
 #[cfg(test)]
 mod tests {
+    use super::{DeviceStatus, RequestKind, ResponseKind};
     use crate::{audio::info, control, path::VirtualPaths, render};
     use std::sync::Arc;
     use tokio::sync::Mutex;
 
+    #[test]
+    fn buffer_size_without_device() {
+        let (_midi_tx, midi_rx) = crate::midi::create_channel(1);
+        let (_req_tx, req_rx) = render::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let renderer = Arc::new(Mutex::new(super::Renderer::new(
+            midi_rx,
+            req_rx,
+            dm_ctr_rx,
+            VirtualPaths::default(),
+        )));
+        let mut audio_ctr = super::Controller::new(renderer);
+        audio_ctr.sample_rate = 48000;
+
+        let res = audio_ctr.process_request(RequestKind::SetBufferSize(1));
+        assert_eq!(res, ResponseKind::UnsupportedBufferSize);
+        let ResponseKind::Latency(latency) =
+            audio_ctr.process_request(RequestKind::SetBufferSize(480))
+        else {
+            panic!("Expected the latency");
+        };
+        assert_eq!(latency.buffer_size, 480);
+        assert_eq!(latency.buffer_ms, 10.0);
+        assert_eq!(latency.device_ms, None);
+        assert_eq!(latency.total_ms, 10.0);
+
+        let res = audio_ctr.process_request(RequestKind::GetDeviceStatus);
+        assert_eq!(res, ResponseKind::DeviceStatus(DeviceStatus::Disconnected));
+        assert!(!audio_ctr.is_lost());
+    }
+
+    #[test]
+    fn spawned_controller() {
+        let (_midi_tx, midi_rx) = crate::midi::create_channel(1);
+        let (_req_tx, req_rx) = render::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let renderer = Arc::new(Mutex::new(super::Renderer::new(
+            midi_rx,
+            req_rx,
+            dm_ctr_rx,
+            VirtualPaths::default(),
+        )));
+        let (audio_req_tx, status_rx) =
+            super::spawn(move || Ok(super::Controller::new(renderer))).unwrap();
+        assert_eq!(*status_rx.borrow(), DeviceStatus::Disconnected);
+
+        let (res_tx, res_rx) = super::create_response_channel();
+        audio_req_tx
+            .blocking_send((RequestKind::GetDeviceStatus, res_tx))
+            .unwrap();
+        assert_eq!(
+            res_rx.blocking_recv().unwrap(),
+            ResponseKind::DeviceStatus(DeviceStatus::Disconnected)
+        );
+    }
+
+    #[test]
+    fn null_output() {
+        let (_midi_tx, midi_rx) = crate::midi::create_channel(1);
+        let (_req_tx, req_rx) = render::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let renderer = Arc::new(Mutex::new(super::Renderer::new(
+            midi_rx,
+            req_rx,
+            dm_ctr_rx,
+            VirtualPaths::default(),
+        )));
+        let renderer2 = Arc::clone(&renderer);
+        let (audio_req_tx, status_rx) = super::spawn(move || {
+            let mut audio_ctr = super::Controller::new(renderer2);
+            audio_ctr.sample_rate = 48000;
+            audio_ctr.connect_to_null_output();
+            Ok(audio_ctr)
+        })
+        .unwrap();
+        let name = super::NULL_OUTPUT_NAME.to_owned();
+        assert_eq!(*status_rx.borrow(), DeviceStatus::Connected(name));
+        assert_eq!(renderer.blocking_lock().sample_rate(), Some(48000));
+
+        let (res_tx, res_rx) = super::create_response_channel();
+        audio_req_tx
+            .blocking_send((RequestKind::SetBufferSize(480), res_tx))
+            .unwrap();
+        let ResponseKind::Latency(latency) = res_rx.blocking_recv().unwrap() else {
+            panic!("Expected the latency");
+        };
+        assert_eq!(latency.buffer_ms, 10.0);
+    }
+
     #[test]
     fn controller() {
         let (_midi_tx, midi_rx) = crate::midi::create_channel(1);
@@ -0,0 +1,38 @@
+// Audio threads get a realtime scheduling policy where the system allows it, so rendering isn't
+// preempted by the web server or file transfers
+
+// Below the kernel's interrupt threads (50) is too low to matter, above 90 competes with them
+// for no benefit, JACK and PipeWire use about the same
+pub const PRIORITY: i32 = 70;
+
+// Usually fails without CAP_SYS_NICE or an rtprio limit in /etc/security/limits.conf
+#[cfg(target_os = "linux")]
+pub fn promote_current_thread() -> std::io::Result<()> {
+    // SAFETY: only reads a constant of the scheduler
+    let max = unsafe { libc::sched_get_priority_max(libc::SCHED_FIFO) };
+    let param = libc::sched_param {
+        sched_priority: PRIORITY.min(max),
+    };
+    // SAFETY: `param` is valid for reads and the thread is the calling one
+    let res =
+        unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    match res {
+        0 => Ok(()),
+        errno => Err(std::io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn promote_current_thread() -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+// For threads which only find out they're rendering once they're running, warns on failure
+pub fn promote_current_thread_once(promoted: &mut bool) {
+    if std::mem::replace(promoted, true) {
+        return;
+    }
+    if let Err(e) = promote_current_thread() {
+        tracing::warn!("Audio thread runs without realtime priority: {e}");
+    }
+}
@@ -0,0 +1,397 @@
+// Settings of a deployment, read from a TOML file given with --config. Everything is optional,
+// arguments on the command line win over the file.
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub samples: Option<PathBuf>,
+    pub beats: Option<PathBuf>,
+    pub audio: Audio,
+    pub webserver: Webserver,
+    pub midi: Midi,
+    // Virtual path of a setlist loaded at the start, its first entry gets selected
+    pub session: Option<PathBuf>,
+    // Virtual path the sounds and the mix get saved to when AMI stops
+    pub autosave: Option<PathBuf>,
+    // Virtual paths of soundfonts and .sfz files loaded in the background at the start
+    pub preload: Vec<PathBuf>,
+    pub performances: Performances,
+    pub hot_reload: HotReload,
+    // Only read when built with the `gpio` feature
+    pub footswitches: Vec<Footswitch>,
+    // Only read when built with the `panel` feature
+    pub panel: Option<Panel>,
+    pub surface: Option<Surface>,
+}
+
+// What happens when the file of a render node changes on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HotReload {
+    Off,
+    // The clients get told and can reload it
+    #[default]
+    Ask,
+    // Reloaded right away, the other settings of the node stay
+    Auto,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Audio {
+    // The default host and device of the system without them
+    pub host: Option<String>,
+    pub device: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<usize>,
+    // Stereo if not set, the render nodes can be routed to the pairs after the first one
+    pub channels: Option<u16>,
+    // Of the same host, played by the `AudioInput` render nodes
+    pub input: Option<String>,
+    // Renders without a device, the host and device are ignored then
+    pub null_output: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Webserver {
+    pub port: u16,
+}
+
+impl Default for Webserver {
+    fn default() -> Self {
+        Self { port: 3000 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Midi {
+    pub auto_connect: Vec<AutoConnect>,
+}
+
+// The keyboards of the original setup, for when there's no config
+impl Default for Midi {
+    fn default() -> Self {
+        Self {
+            auto_connect: vec![
+                AutoConnect {
+                    port: "VMPK Output:out 130:0".into(),
+                    slot: 0,
+                },
+                AutoConnect {
+                    port: "Hammer 88 Pro:Hammer 88 Pro USB MIDI 20:0".into(),
+                    slot: 0,
+                },
+            ],
+        }
+    }
+}
+
+// Sessions to switch between in one go, by a request, a footswitch or a program change
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Performances {
+    // Virtual paths of the session files, the files they play get preloaded
+    pub slots: Vec<PathBuf>,
+    // 0 to 15, program change n switches to slot n
+    pub program_change_channel: Option<u8>,
+}
+
+// A port whose name contains `port` gets connected to the slot when it shows up and the slot
+// is free
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AutoConnect {
+    pub port: String,
+    pub slot: usize,
+}
+
+impl AutoConnect {
+    pub fn matches(&self, port_name: &str) -> bool {
+        port_name.contains(&self.port)
+    }
+}
+
+// A switch between a GPIO pin and ground, the pin needs its pull-up turned on
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Footswitch {
+    // BCM number of the pin
+    pub pin: u32,
+    pub action: FootswitchAction,
+    // A switch that pulls the pin up when pressed instead
+    #[serde(default)]
+    pub active_high: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum FootswitchAction {
+    NextEntry,
+    PreviousEntry,
+    // Starts the drum machine when it's stopped and stops it when it's playing
+    ToggleDrumMachine,
+    TriggerFill,
+    // The fill plays for as long as the switch is held down
+    HoldFill,
+    Pad(usize),
+    // Switches to the performance slot
+    Performance(usize),
+    // 127 while held down and 0 on release, like a sustain pedal
+    ControlChange { channel: u8, controller: u8 },
+    // Note on while held down, note off on release
+    Note { channel: u8, note: u8, velocity: u8 },
+}
+
+// A front panel with a knob and a small display, either can be left out
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Panel {
+    pub encoder: Option<Encoder>,
+    pub display: Option<Display>,
+    // What the knob can change, its button moves on to the next one
+    pub parameters: Vec<PanelParameter>,
+}
+
+impl Default for Panel {
+    fn default() -> Self {
+        Self {
+            encoder: None,
+            display: None,
+            parameters: vec![
+                PanelParameter::Entry,
+                PanelParameter::Tempo,
+                PanelParameter::DrumMachine,
+            ],
+        }
+    }
+}
+
+// A rotary encoder between two GPIO pins and ground, with a push button on a third pin
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Encoder {
+    pub a: u32,
+    pub b: u32,
+    pub button: Option<u32>,
+}
+
+// A character LCD or OLED with an HD44780 compatible controller behind a PCF8574 I2C backpack
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Display {
+    pub bus: PathBuf,
+    pub address: u16,
+    pub columns: usize,
+    pub rows: usize,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self {
+            bus: "/dev/i2c-1".into(),
+            address: 0x27,
+            columns: 16,
+            rows: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PanelParameter {
+    // The setlist entry
+    Entry,
+    Tempo,
+    // Whether the drum machine plays
+    DrumMachine,
+}
+
+// A Mackie Control or HUI surface as a mixer of the render nodes, its ports are found like the
+// ones to auto connect
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Surface {
+    #[serde(default)]
+    pub protocol: SurfaceProtocol,
+    pub input: String,
+    pub output: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SurfaceProtocol {
+    #[default]
+    Mcu,
+    Hui,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(std::io::Error),
+    Parse(toml::de::Error),
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(e) => write!(f, "Could not read the config file: {e}"),
+            ConfigError::Parse(e) => write!(f, "Invalid config file: {e}"),
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    let text = std::fs::read_to_string(path).map_err(ConfigError::Read)?;
+    parse(&text)
+}
+
+pub fn parse(text: &str) -> Result<Config, ConfigError> {
+    toml::from_str(text).map_err(ConfigError::Parse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse, AutoConnect, Config, ConfigError, Encoder, Footswitch, FootswitchAction, HotReload,
+        PanelParameter, SurfaceProtocol,
+    };
+    use std::path::PathBuf;
+
+    #[test]
+    fn full_config() {
+        let config = parse(
+            r#"
+            samples = "/srv/ami/samples"
+            beats = "/srv/ami/beats"
+            session = "beats:/gig.setlist"
+            autosave = "beats:/autosave.session"
+            preload = ["samples:/piano.sf2"]
+            hot_reload = "auto"
+
+            [performances]
+            slots = ["beats:/ballad.session", "beats:/rocker.session"]
+            program_change_channel = 15
+
+            [audio]
+            device = "USB Audio"
+            sample_rate = 48000
+            buffer_size = 256
+            channels = 4
+            input = "USB Audio"
+            null_output = true
+
+            [webserver]
+            port = 8080
+
+            [[midi.auto_connect]]
+            port = "Keystation"
+            slot = 1
+
+            [[footswitches]]
+            pin = 17
+            action = "next_entry"
+
+            [[footswitches]]
+            pin = 22
+            action = { performance = 1 }
+
+            [[footswitches]]
+            pin = 27
+            action = { control_change = { channel = 0, controller = 64 } }
+            active_high = true
+
+            [panel]
+            encoder = { a = 5, b = 6, button = 13 }
+            display = { columns = 20, rows = 4 }
+            parameters = ["tempo", "entry"]
+
+            [surface]
+            protocol = "hui"
+            input = "nanoKONTROL"
+            output = "nanoKONTROL"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.samples, Some(PathBuf::from("/srv/ami/samples")));
+        assert_eq!(config.session, Some(PathBuf::from("beats:/gig.setlist")));
+        let autosave = PathBuf::from("beats:/autosave.session");
+        assert_eq!(config.autosave, Some(autosave));
+        assert_eq!(config.preload, [PathBuf::from("samples:/piano.sf2")]);
+        assert_eq!(config.hot_reload, HotReload::Auto);
+        let slots = config.performances.slots;
+        assert_eq!(slots[1], PathBuf::from("beats:/rocker.session"));
+        assert_eq!(config.performances.program_change_channel, Some(15));
+        assert_eq!(config.audio.host, None);
+        assert_eq!(config.audio.device.as_deref(), Some("USB Audio"));
+        assert_eq!(config.audio.sample_rate, Some(48000));
+        assert_eq!(config.audio.buffer_size, Some(256));
+        assert_eq!(config.audio.channels, Some(4));
+        assert_eq!(config.audio.input.as_deref(), Some("USB Audio"));
+        assert!(config.audio.null_output);
+        assert_eq!(config.webserver.port, 8080);
+        let rule = AutoConnect {
+            port: "Keystation".into(),
+            slot: 1,
+        };
+        assert!(rule.matches("Keystation 49:Keystation 49 MIDI 1 24:0"));
+        assert!(!rule.matches("VMPK Output:out 130:0"));
+        assert_eq!(config.midi.auto_connect, [rule]);
+        assert_eq!(
+            config.footswitches,
+            [
+                Footswitch {
+                    pin: 17,
+                    action: FootswitchAction::NextEntry,
+                    active_high: false,
+                },
+                Footswitch {
+                    pin: 22,
+                    action: FootswitchAction::Performance(1),
+                    active_high: false,
+                },
+                Footswitch {
+                    pin: 27,
+                    action: FootswitchAction::ControlChange {
+                        channel: 0,
+                        controller: 64,
+                    },
+                    active_high: true,
+                },
+            ]
+        );
+        let panel = config.panel.unwrap();
+        let encoder = Encoder {
+            a: 5,
+            b: 6,
+            button: Some(13),
+        };
+        assert_eq!(panel.encoder, Some(encoder));
+        let display = panel.display.unwrap();
+        assert_eq!(
+            (display.address, display.columns, display.rows),
+            (0x27, 20, 4)
+        );
+        assert_eq!(
+            panel.parameters,
+            [PanelParameter::Tempo, PanelParameter::Entry]
+        );
+        let surface = config.surface.unwrap();
+        assert_eq!(surface.protocol, SurfaceProtocol::Hui);
+        assert_eq!(surface.input, "nanoKONTROL");
+    }
+
+    #[test]
+    fn defaults_and_typos() {
+        assert_eq!(parse("").unwrap(), Config::default());
+        assert_eq!(parse("[audio]\n").unwrap().webserver.port, 3000);
+        let res = parse("[webserver]\nprot = 8080\n");
+        assert!(matches!(res, Err(ConfigError::Parse(_))));
+    }
+}
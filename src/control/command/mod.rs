@@ -1,9 +1,9 @@
-use crate::json::JsonUpdateKind;
+use crate::{deser::NodeState, json::JsonUpdateKind, rhythm::Rhythm};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 
-use super::node;
+use super::{node, transport};
 
 pub type Requester = mpsc::Sender<(RequestKind, Responder)>;
 pub type RequestListener = mpsc::Receiver<(RequestKind, Responder)>;
@@ -26,6 +26,24 @@ pub enum RequestKind {
     RemoveNode { id: usize },
     CloneNode { id: usize },
     MoveNode { id: usize, new_id: usize },
+    // The renderer moved its node at `id` to `new_id`, the nodes playing it follow
+    MoveInstrument { id: usize, new_id: usize },
+    SetRhythm(Rhythm),
+    SetTempoBpm(f32),
+    Transport(transport::Action),
+    // Setups wait for the start of the next bar while the transport plays
+    SetQuantizedSetups(bool),
+    // Everything at once, like from a snapshot
+    SetSetup(Setup),
+}
+
+// Named like in the state the clients get. Nodes of the same kind as the one in their place
+// keep running and only take the settings, the others get replaced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Setup {
+    pub nodes: Vec<NodeState>,
+    pub rhythm: Rhythm,
+    pub tempo_bpm: f32,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -53,4 +71,14 @@ pub enum ResponseKind {
         id: usize,
         new_id: usize,
     },
+    // The updates of the nodes that followed the instrument, by node id
+    MoveInstrument(Vec<(usize, JsonUpdateKind)>),
+    SetRhythm(Rhythm),
+    SetTempoBpm(f32),
+    Transport(transport::Status),
+    QuantizedSetups(bool),
+    // Whether a setup waits for the next bar, one taken there comes as `Setup` on its own
+    QueuedSetup(bool),
+    // With the nodes as they serialize after taking the settings
+    Setup(Setup),
 }
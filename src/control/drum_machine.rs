@@ -1,7 +1,12 @@
 use crate::{
-    deser::{deser_field, deser_field_opt, serialize, DeserializationResult, SerializationResult},
-    json::{update_fields_or_fail, JsonUpdateKind},
+    deser::{
+        deser_field_opt, deser_value, serialize, DeserializationError, DeserializationResult,
+        PresetError, SerializationResult,
+    },
+    json::{self, update_fields_or_fail, JsonUpdateKind, JsonUpdater, Migration},
+    midi,
     path::VirtualPaths,
+    render::moved_node,
     rhythm::Rhythm,
 };
 use serde::{Deserialize, Serialize};
@@ -9,17 +14,164 @@ use serde_json::json;
 use std::{
     fs,
     path::{Path, PathBuf},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant},
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
 
-use super::{ControlMessage, CtrSender};
+use super::{
+    command,
+    node::field_update,
+    transport::{Change, Transport},
+    ControlMessage, CtrSender, Tempo,
+};
+use accent::Accent;
+use alternation::{Alternation, Alternative};
+use chance::Chance;
+use count_in::{CountIn, CountInPlayer};
+use dynamics::Dynamics;
+use edit::Edit;
+use fill::{Fill, FillPlayer, FillTrigger};
+use humanize::Humanize;
+use schedule::Schedule;
+use song::{ChainEntry, Song, SongPosition};
+
+pub mod accent;
+pub mod alternation;
+pub mod chance;
+pub mod count_in;
+pub mod dynamics;
+pub mod edit;
+pub mod fill;
+pub mod humanize;
+pub mod schedule;
+pub mod song;
 
 pub type Requester = mpsc::Sender<(RequestKind, Responder)>;
 pub type RequestListener = mpsc::Receiver<(RequestKind, Responder)>;
 pub type Responder = oneshot::Sender<JsonUpdateKind>;
 pub type ResponseListener = oneshot::Receiver<JsonUpdateKind>;
 
+// Index `n` upgrades a preset from version `n` to `n + 1`
+const PRESET_MIGRATIONS: &[Migration] = &[
+    // v0 -> v1: the version field was introduced, the layout stayed the same
+    |_| Ok(()),
+    // v1 -> v2: dynamics lane
+    |preset| {
+        preset["dynamics"] =
+            serde_json::to_value(Dynamics::default()).map_err(|e| e.to_string())?;
+        Ok(())
+    },
+    // v2 -> v3: several patterns and a song chain
+    |preset| {
+        preset["patterns"] = json!([preset["voices"].take()]);
+        preset["active_pattern"] = json!(0);
+        preset["song"] = serde_json::to_value(Song::default()).map_err(|e| e.to_string())?;
+        if let Some(preset) = preset.as_object_mut() {
+            preset.remove("voices");
+        }
+        Ok(())
+    },
+    // v3 -> v4: slots hold velocities instead of flags
+    |preset| {
+        let patterns = preset["patterns"].as_array_mut().ok_or("no patterns")?;
+        for pattern in patterns {
+            let voices = pattern["voices"].as_array_mut().ok_or("no voices")?;
+            for voice in voices {
+                let velocity = voice["velocity"].as_u64().ok_or("no voice velocity")?;
+                let slots = voice["slots"].as_array_mut().ok_or("no slots")?;
+                for slot in slots {
+                    let hit = slot.as_bool().ok_or("slot is not a flag")?;
+                    *slot = json!(if hit { velocity } else { 0 });
+                }
+            }
+        }
+        Ok(())
+    },
+    // v4 -> v5: slot probabilities and the random seed
+    |preset| {
+        let patterns = preset["patterns"].as_array_mut().ok_or("no patterns")?;
+        for pattern in patterns {
+            let voices = pattern["voices"].as_array_mut().ok_or("no voices")?;
+            for voice in voices {
+                let slots = voice["slots"].as_array_mut().ok_or("no slots")?;
+                for slot in slots {
+                    let velocity = slot.take();
+                    *slot = json!({ "velocity": velocity, "probability": 100 });
+                }
+            }
+        }
+        preset["seed"] = serde_json::Value::Null;
+        Ok(())
+    },
+    // v5 -> v6: swing
+    |preset| {
+        preset["swing"] = json!(0);
+        Ok(())
+    },
+    // v6 -> v7: humanize
+    |preset| {
+        preset["humanize"] =
+            serde_json::to_value(Humanize::default()).map_err(|e| e.to_string())?;
+        Ok(())
+    },
+    // v7 -> v8: slot ratchets and flams
+    |preset| {
+        let patterns = preset["patterns"].as_array_mut().ok_or("no patterns")?;
+        for pattern in patterns {
+            let voices = pattern["voices"].as_array_mut().ok_or("no voices")?;
+            for voice in voices {
+                let slots = voice["slots"].as_array_mut().ok_or("no slots")?;
+                for slot in slots {
+                    slot["ratchet"] = json!(1);
+                    slot["flam"] = json!(0);
+                }
+            }
+        }
+        Ok(())
+    },
+    // v8 -> v9: fill pattern
+    |preset| {
+        preset["fill"] = serde_json::to_value(Fill::default()).map_err(|e| e.to_string())?;
+        Ok(())
+    },
+    // v9 -> v10: count-in
+    |preset| {
+        preset["count_in"] = serde_json::to_value(CountIn::default()).map_err(|e| e.to_string())?;
+        Ok(())
+    },
+    // v10 -> v11: accent rows
+    |preset| {
+        let patterns = preset["patterns"].as_array_mut().ok_or("no patterns")?;
+        for pattern in patterns {
+            let num_slots = pattern["num_slots"].as_u64().ok_or("no number of slots")?;
+            let accent = Accent::new(num_slots as usize);
+            pattern["accent"] = serde_json::to_value(accent).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    },
+    // v11 -> v12: every pattern has its own dynamics lane
+    |preset| {
+        let dynamics = preset
+            .as_object_mut()
+            .and_then(|preset| preset.remove("dynamics"))
+            .ok_or("no dynamics")?;
+        let patterns = preset["patterns"].as_array_mut().ok_or("no patterns")?;
+        for pattern in patterns {
+            pattern["dynamics"] = dynamics.clone();
+        }
+        Ok(())
+    },
+];
+
+// In percent of a division
+pub const MAX_SWING: u8 = 75;
+pub const MAX_RATCHET: u8 = 8;
+pub const MAX_VOICE_SLOTS: usize = 64;
+// In percent of the step
+pub const MAX_FLAM: u8 = 50;
+// Velocity of the grace note relative to the main hit of a flam
+const FLAM_GRACE_SCALE: f32 = 0.6;
+
 pub fn create_request_channel(buffer: usize) -> (Requester, RequestListener) {
     mpsc::channel(buffer)
 }
@@ -38,9 +190,68 @@ pub enum RequestKind {
     SetVoiceInstrument(usize, Option<usize>),
     SetVoiceNote(usize, u8),
     SetVoiceVelocity(usize, u8),
-    SetSlot(usize, usize, bool),
+    // A voice with its own number of slots spreads them evenly over the bar, `None` makes it
+    // follow the rhythm again
+    SetVoiceSlots(usize, Option<usize>),
+    // How long the hits of the voice are held
+    SetVoiceGate(usize, Gate),
+    SetVoiceMuted(usize, bool),
+    // While any voice is soloed only the soloed voices are heard
+    SetVoiceSoloed(usize, bool),
+    // Notes the voice takes turns with on every hit
+    SetVoiceAlternatives(usize, Vec<Alternative>),
+    SetVoiceAlternationOrder(usize, alternation::Order),
+    // (voice, slot, velocity), velocity 0 turns the slot off
+    SetSlot(usize, usize, u8),
+    // (voice, slot, probability in percent)
+    SetSlotProbability(usize, usize, u8),
+    // (voice, slot, hits), the hits are spread evenly over the step
+    SetSlotRatchet(usize, usize, u8),
+    // (voice, slot, offset of the main hit after the grace note in percent of the step),
+    // 0 turns the flam off, a ratchet takes precedence over it
+    SetSlotFlam(usize, usize, u8),
+    // Edits the row of a voice of the active pattern, or the rows of all its voices with `None`
+    EditSlots(Option<usize>, Edit),
+    // (step, flag) of the accent row of the active pattern
+    SetAccentStep(usize, bool),
+    // Added to the velocities on the accented steps
+    SetAccentAmount(u8),
+    // With a seed the random hits repeat every time the drum machine starts over
+    SetRandomSeed(Option<u64>),
     SetRhythm(Rhythm),
     SetTempoBpm(f32),
+    // Delays every second division by a percentage of the division length
+    SetSwing(u8),
+    // Maximum random delay of the hits in milliseconds
+    SetHumanizeTiming(f32),
+    // Maximum random change of the hit velocities
+    SetHumanizeVelocity(u8),
+    SetDynamicsEnabled(bool),
+    SetDynamicsPoints(Vec<f32>),
+    // Adds a copy of the active pattern
+    AddPattern,
+    RemovePattern(usize),
+    SetPatternName(usize, String),
+    // Switches to the pattern right away
+    SelectPattern(usize),
+    // Switches to the pattern at the start of the next bar
+    QueuePattern(usize),
+    // Pattern selections and presets wait for the start of the next bar while it plays
+    SetQuantizedChanges(bool),
+    SetSongEnabled(bool),
+    SetSongLooped(bool),
+    SetSongChain(Vec<ChainEntry>),
+    // The song pauses while the fill plays
+    SetFillPattern(Option<usize>),
+    SetFillTrigger(Option<FillTrigger>),
+    // Plays the fill for the next bar
+    TriggerFill,
+    // Plays the fill from the next bar on until it's released
+    HoldFill(bool),
+    // Bars played before the groove whenever the drum machine gets enabled, 0 for none
+    SetCountIn(CountIn),
+    // (id, new_id) of a node the renderer moved, the voices and the clicks playing it follow
+    MoveInstrument(usize, usize),
     Reset,
     LoadPreset(PathBuf),
     SavePreset(PathBuf),
@@ -48,60 +259,201 @@ pub enum RequestKind {
 
 pub struct DrumMachine {
     enabled: bool,
-    voices: Voices,
-    rhythm: Rhythm,
-    tempo_bpm: f32,
+    patterns: Vec<Voices>,
+    active_pattern: usize,
+    queued_pattern: Option<usize>,
+    quantized_changes: bool,
+    queued_preset: Option<(PathBuf, Box<Preset>)>,
+    swing: u8,
+    humanize: Humanize,
+    schedule: Schedule,
     sender: CtrSender,
     req_rx: RequestListener,
-    last_time: f32,
-    start: SystemTime,
+    // The times of the schedule count from it
+    start: Instant,
     current_beat: u8,
     current_div: u8,
+    // the bars of the dynamics, `None` until the first bar starts, which counts as bar 0 too
+    current_bar: Option<usize>,
+    chance: Chance,
+    // rows of slots copied by the last edit
+    clipboard: Vec<Vec<Slot>>,
+    song: Song,
+    song_position: Option<SongPosition>,
+    fill: Fill,
+    fill_player: FillPlayer,
+    count_in: CountIn,
+    count_in_player: CountInPlayer,
+    midi_rx: Option<midi::Receiver>,
+    // A copy of the controller's, stepped here. It has the tempo and the rhythm, without the
+    // controller's the drum machine plays whenever it's enabled, in the tempo of its own.
+    transport_rx: Option<watch::Receiver<Transport>>,
+    transport: Transport,
+    // New tempos go to the controller through it, to take effect for everything at once
+    tempo_tx: Option<mpsc::Sender<command::RequestKind>>,
     virtual_paths: VirtualPaths,
+    json_updater: Option<JsonUpdater>,
 }
 
 impl DrumMachine {
     pub fn new(sender: CtrSender, req_rx: RequestListener, virtual_paths: VirtualPaths) -> Self {
         let mut res = Self {
             enabled: true,
-            voices: Default::default(),
-            rhythm: Default::default(),
-            tempo_bpm: 90.0,
+            patterns: vec![Voices::default()],
+            active_pattern: 0,
+            queued_pattern: None,
+            quantized_changes: false,
+            queued_preset: None,
+            swing: 0,
+            humanize: Default::default(),
+            schedule: Default::default(),
             sender,
             req_rx,
-            last_time: 0.0,
-            start: SystemTime::now(),
+            start: Instant::now(),
             current_beat: 0,
             current_div: 0,
+            current_bar: None,
+            chance: Default::default(),
+            clipboard: Vec::new(),
+            song: Default::default(),
+            song_position: None,
+            fill: Default::default(),
+            fill_player: Default::default(),
+            count_in: Default::default(),
+            count_in_player: Default::default(),
+            midi_rx: None,
+            transport_rx: None,
+            transport: Transport::new(Tempo::default(), Instant::now()),
+            tempo_tx: None,
             virtual_paths,
+            json_updater: None,
         };
-        res.voices.set_num_slots(res.rhythm.num_slots());
+        let num_slots = res.tempo().rhythm.num_slots();
+        res.voices_mut().set_num_slots(num_slots);
         res
     }
 
+    // Position changes of the song are broadcast through it, they don't answer any request
+    pub fn set_json_updater(&mut self, updater: JsonUpdater) {
+        self.json_updater = Some(updater);
+    }
+
+    // For the fill trigger
+    pub fn set_midi_receiver(&mut self, midi_rx: midi::Receiver) {
+        self.midi_rx = Some(midi_rx);
+    }
+
+    // Playing from the top starts it over and enables it, stopping holds it where it is
+    pub fn set_transport_receiver(&mut self, mut transport_rx: watch::Receiver<Transport>) {
+        self.transport = *transport_rx.borrow_and_update();
+        self.transport_rx = Some(transport_rx);
+    }
+
+    // For the tempo and the rhythm of the controller
+    pub fn set_tempo_request_sender(&mut self, tx: mpsc::Sender<command::RequestKind>) {
+        self.tempo_tx = Some(tx);
+    }
+
+    // Its tempo, stopping and continuing come with it, the steps are taken in `tick`
+    fn follow_transport(&mut self) -> Vec<JsonUpdateKind> {
+        let Some(transport_rx) = self.transport_rx.as_mut() else {
+            return Vec::new();
+        };
+        if !transport_rx.has_changed().unwrap_or(false) {
+            return Vec::new();
+        }
+        let leader = *transport_rx.borrow_and_update();
+        let previous = self.transport;
+        self.transport.follow(&leader);
+        let mut updates = Vec::new();
+        if self.tempo() != previous.tempo() {
+            updates.push(self.follow_tempo(previous.tempo()));
+        }
+        if self.transport.change_from(&previous) == Some(Change::Started) {
+            updates.push(self.set_enabled(true));
+        }
+        updates
+    }
+
+    // The patterns take a new rhythm from wherever it came
+    fn follow_tempo(&mut self, previous: Tempo) -> JsonUpdateKind {
+        let tempo = self.tempo();
+        if tempo.rhythm != previous.rhythm {
+            let num_slots = tempo.rhythm.num_slots();
+            self.patterns
+                .iter_mut()
+                .for_each(|voices| voices.set_num_slots(num_slots));
+        }
+        update_fields_or_fail(|updates| {
+            updates.push(("rhythm".to_owned(), serialize(tempo.rhythm)?));
+            updates.push(("tempo_bpm".to_owned(), serialize(tempo.tempo_bpm)?));
+            updates.push(("voices".into(), serialize(self.voices())?));
+            Ok(())
+        })
+    }
+
+    fn tempo(&self) -> Tempo {
+        self.transport.tempo()
+    }
+
+    // The controller has the tempo everything plays in, the drum machine only asks it for a new
+    // one and takes it once its transport comes back with it. On its own it changes right away.
+    fn request_tempo(&mut self, tempo: Tempo) {
+        let current = self.tempo();
+        let Some(tx) = &self.tempo_tx else {
+            self.transport.set_tempo(tempo, Instant::now());
+            return;
+        };
+        let mut requests = Vec::new();
+        if tempo.rhythm != current.rhythm {
+            requests.push(command::RequestKind::SetRhythm(tempo.rhythm));
+        }
+        if tempo.tempo_bpm != current.tempo_bpm {
+            requests.push(command::RequestKind::SetTempoBpm(tempo.tempo_bpm));
+        }
+        for request in requests {
+            tx.try_send(request)
+                .unwrap_or_else(|e| tracing::error!("Failed to request a tempo: {e}"));
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        self.enabled && self.transport.is_playing()
+    }
+
+    fn pattern_names(&self) -> Vec<&str> {
+        self.patterns.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    fn voices(&self) -> &Voices {
+        &self.patterns[self.active_pattern]
+    }
+
+    fn voices_mut(&mut self) -> &mut Voices {
+        &mut self.patterns[self.active_pattern]
+    }
+
     fn set_enabled(&mut self, flag: bool) -> JsonUpdateKind {
         self.enabled = flag;
         if flag {
             self.reset();
+            self.count_in_player.start(self.count_in.bars);
         }
-        update_fields_or_fail(|updates| {
-            updates.push(("enabled".to_owned(), serialize(flag)?));
-            Ok(())
-        })
+        field_update("enabled", flag)
     }
 
     fn add_voice(&mut self) -> JsonUpdateKind {
-        self.voices.add_voice();
+        self.voices_mut().add_voice();
         update_fields_or_fail(|updates| {
-            updates.push(("voices".into(), serialize(&self.voices)?));
+            updates.push(("voices".into(), serialize(self.voices())?));
             Ok(())
         })
     }
 
     fn remove_voice(&mut self, index: usize) -> JsonUpdateKind {
-        if self.voices.remove_voice(index).is_ok() {
+        if self.voices_mut().remove_voice(index).is_ok() {
             update_fields_or_fail(|updates| {
-                updates.push(("voices".into(), serialize(&self.voices)?));
+                updates.push(("voices".into(), serialize(self.voices())?));
                 Ok(())
             })
         } else {
@@ -110,18 +462,18 @@ impl DrumMachine {
     }
 
     fn clear_voices(&mut self) -> JsonUpdateKind {
-        self.voices.clear();
+        self.voices_mut().clear();
         update_fields_or_fail(|updates| {
-            updates.push(("voices".into(), serialize(&self.voices)?));
+            updates.push(("voices".into(), serialize(self.voices())?));
             Ok(())
         })
     }
 
     fn set_voice_name(&mut self, voice_index: usize, name: String) -> JsonUpdateKind {
-        let res = self.voices.set_voice_name(voice_index, name).is_ok();
+        let res = self.voices_mut().set_voice_name(voice_index, name).is_ok();
         if res {
             update_fields_or_fail(|updates| {
-                updates.push(("voices".into(), serialize(&self.voices)?));
+                updates.push(("voices".into(), serialize(self.voices())?));
                 Ok(())
             })
         } else {
@@ -135,12 +487,12 @@ impl DrumMachine {
         instrument_index: Option<usize>,
     ) -> JsonUpdateKind {
         let res = self
-            .voices
+            .voices_mut()
             .set_voice_instrument(voice_index, instrument_index)
             .is_ok();
         if res {
             update_fields_or_fail(|updates| {
-                updates.push(("voices".into(), serialize(&self.voices)?));
+                updates.push(("voices".into(), serialize(self.voices())?));
                 Ok(())
             })
         } else {
@@ -149,9 +501,9 @@ impl DrumMachine {
     }
 
     fn set_voice_note(&mut self, voice_index: usize, note: u8) -> JsonUpdateKind {
-        if self.voices.set_voice_note(voice_index, note).is_ok() {
+        if self.voices_mut().set_voice_note(voice_index, note).is_ok() {
             update_fields_or_fail(|updates| {
-                updates.push(("voices".into(), serialize(&self.voices)?));
+                updates.push(("voices".into(), serialize(self.voices())?));
                 Ok(())
             })
         } else {
@@ -161,12 +513,50 @@ impl DrumMachine {
 
     fn set_voice_velocity(&mut self, voice_index: usize, velocity: u8) -> JsonUpdateKind {
         if self
-            .voices
+            .voices_mut()
             .set_voice_velocity(voice_index, velocity)
             .is_ok()
         {
             update_fields_or_fail(|updates| {
-                updates.push(("voices".into(), serialize(&self.voices)?));
+                updates.push(("voices".into(), serialize(self.voices())?));
+                Ok(())
+            })
+        } else {
+            JsonUpdateKind::Failed
+        }
+    }
+
+    fn set_voice_slots(&mut self, voice_index: usize, num_slots: Option<usize>) -> JsonUpdateKind {
+        if self
+            .voices_mut()
+            .set_voice_slots(voice_index, num_slots)
+            .is_ok()
+        {
+            update_fields_or_fail(|updates| {
+                updates.push(("voices".into(), serialize(self.voices())?));
+                Ok(())
+            })
+        } else {
+            JsonUpdateKind::Failed
+        }
+    }
+
+    fn update_voice(&mut self, voice_index: usize, f: impl FnOnce(&mut Voice)) -> JsonUpdateKind {
+        if let Some(voice) = self.voices_mut().voices.get_mut(voice_index) {
+            f(voice);
+            update_fields_or_fail(|updates| {
+                updates.push(("voices".into(), serialize(self.voices())?));
+                Ok(())
+            })
+        } else {
+            JsonUpdateKind::Failed
+        }
+    }
+
+    fn update_accent(&mut self, f: impl FnOnce(&mut Accent) -> bool) -> JsonUpdateKind {
+        if f(&mut self.voices_mut().accent) {
+            update_fields_or_fail(|updates| {
+                updates.push(("voices".into(), serialize(self.voices())?));
                 Ok(())
             })
         } else {
@@ -174,14 +564,95 @@ impl DrumMachine {
         }
     }
 
-    fn set_slot(&mut self, voice_index: usize, slot_index: usize, enabled: bool) -> JsonUpdateKind {
+    fn set_slot_probability(
+        &mut self,
+        voice_index: usize,
+        slot_index: usize,
+        probability: u8,
+    ) -> JsonUpdateKind {
         let res = self
-            .voices
-            .set_slot(voice_index, slot_index, enabled)
+            .voices_mut()
+            .set_slot_probability(voice_index, slot_index, probability.min(100))
+            .is_ok();
+        if res {
+            update_fields_or_fail(|updates| {
+                updates.push(("voices".into(), serialize(self.voices())?));
+                Ok(())
+            })
+        } else {
+            JsonUpdateKind::Failed
+        }
+    }
+
+    fn update_slot(
+        &mut self,
+        voice_index: usize,
+        slot_index: usize,
+        f: impl FnOnce(&mut Slot),
+    ) -> JsonUpdateKind {
+        let res = self
+            .voices_mut()
+            .update_slot(voice_index, slot_index, f)
+            .is_ok();
+        if res {
+            update_fields_or_fail(|updates| {
+                updates.push(("voices".into(), serialize(self.voices())?));
+                Ok(())
+            })
+        } else {
+            JsonUpdateKind::Failed
+        }
+    }
+
+    fn edit_slots(&mut self, voice_index: Option<usize>, edit: Edit) -> JsonUpdateKind {
+        let voices = &mut self.patterns[self.active_pattern].voices;
+        let voices = match voice_index {
+            Some(index) if index < voices.len() => &mut voices[index..=index],
+            Some(_) => return JsonUpdateKind::Failed,
+            None => &mut voices[..],
+        };
+        match edit {
+            Edit::Copy => {
+                self.clipboard = voices.iter().map(|voice| voice.slots.clone()).collect();
+                return JsonUpdateKind::Ok;
+            }
+            Edit::Paste if self.clipboard.is_empty() => return JsonUpdateKind::Failed,
+            Edit::Paste => voices
+                .iter_mut()
+                .zip(&self.clipboard)
+                .for_each(|(voice, source)| edit::paste(&mut voice.slots, source)),
+            Edit::Rotate(steps) => voices
+                .iter_mut()
+                .for_each(|voice| edit::rotate(&mut voice.slots, steps)),
+            Edit::Invert => voices
+                .iter_mut()
+                .for_each(|voice| edit::invert(&mut voice.slots, voice.velocity)),
+            Edit::Clear => voices
+                .iter_mut()
+                .for_each(|voice| edit::clear(&mut voice.slots)),
+        }
+        update_fields_or_fail(|updates| {
+            updates.push(("voices".into(), serialize(self.voices())?));
+            Ok(())
+        })
+    }
+
+    fn set_random_seed(&mut self, seed: Option<u64>) -> JsonUpdateKind {
+        self.chance.set_seed(seed);
+        update_fields_or_fail(|updates| {
+            updates.push(("seed".to_owned(), serialize(seed)?));
+            Ok(())
+        })
+    }
+
+    fn set_slot(&mut self, voice_index: usize, slot_index: usize, velocity: u8) -> JsonUpdateKind {
+        let res = self
+            .voices_mut()
+            .set_slot(voice_index, slot_index, velocity.min(127))
             .is_ok();
         if res {
             update_fields_or_fail(|updates| {
-                updates.push(("voices".into(), serialize(&self.voices)?));
+                updates.push(("voices".into(), serialize(self.voices())?));
                 Ok(())
             })
         } else {
@@ -190,105 +661,584 @@ impl DrumMachine {
     }
 
     fn set_rhythm(&mut self, rhythm: Rhythm) -> JsonUpdateKind {
-        self.rhythm = rhythm;
-        self.voices.set_num_slots(self.rhythm.num_slots());
+        self.request_tempo(Tempo {
+            rhythm,
+            ..self.tempo()
+        });
+        let num_slots = rhythm.num_slots();
+        self.patterns
+            .iter_mut()
+            .for_each(|voices| voices.set_num_slots(num_slots));
         update_fields_or_fail(|updates| {
             updates.push(("rhythm".to_owned(), serialize(rhythm)?));
-            updates.push(("voices".into(), serialize(&self.voices)?));
+            updates.push(("voices".into(), serialize(self.voices())?));
             Ok(())
         })
     }
 
     fn set_tempo_bpm(&mut self, tempo_bpm: f32) -> JsonUpdateKind {
-        self.tempo_bpm = tempo_bpm;
+        self.request_tempo(Tempo {
+            tempo_bpm,
+            ..self.tempo()
+        });
         update_fields_or_fail(|updates| {
             updates.push(("tempo_bpm".to_owned(), serialize(tempo_bpm)?));
             Ok(())
         })
     }
 
+    fn set_swing(&mut self, swing: u8) -> JsonUpdateKind {
+        if swing > MAX_SWING {
+            return JsonUpdateKind::Failed;
+        }
+        self.swing = swing;
+        update_fields_or_fail(|updates| {
+            updates.push(("swing".to_owned(), serialize(swing)?));
+            Ok(())
+        })
+    }
+
+    fn update_humanize(&mut self, f: impl FnOnce(&mut Humanize)) -> JsonUpdateKind {
+        let mut humanize = self.humanize;
+        f(&mut humanize);
+        if !humanize.is_valid() {
+            return JsonUpdateKind::Failed;
+        }
+        self.humanize = humanize;
+        update_fields_or_fail(|updates| {
+            updates.push(("humanize".to_owned(), serialize(humanize)?));
+            Ok(())
+        })
+    }
+
+    fn set_dynamics_enabled(&mut self, flag: bool) -> JsonUpdateKind {
+        self.voices_mut().dynamics.enabled = flag;
+        update_fields_or_fail(|updates| {
+            updates.push(("voices".into(), serialize(self.voices())?));
+            Ok(())
+        })
+    }
+
+    fn set_dynamics_points(&mut self, points: Vec<f32>) -> JsonUpdateKind {
+        let dynamics = Dynamics {
+            enabled: self.voices().dynamics.enabled,
+            points,
+        };
+        if dynamics.points.is_empty() || !dynamics.is_valid() {
+            return JsonUpdateKind::Failed;
+        }
+        self.voices_mut().dynamics = dynamics;
+        update_fields_or_fail(|updates| {
+            updates.push(("voices".into(), serialize(self.voices())?));
+            Ok(())
+        })
+    }
+
+    fn add_pattern(&mut self) -> JsonUpdateKind {
+        self.patterns.push(self.voices().clone());
+        self.patterns_update()
+    }
+
+    fn remove_pattern(&mut self, index: usize) -> JsonUpdateKind {
+        if index >= self.patterns.len() || self.patterns.len() == 1 {
+            return JsonUpdateKind::Failed;
+        }
+        self.stop_fill();
+        self.patterns.remove(index);
+        if self.active_pattern > index || self.active_pattern == self.patterns.len() {
+            self.active_pattern -= 1;
+        }
+        self.song.remove_pattern(index);
+        self.song_position = None;
+        self.queued_pattern = None;
+        self.fill.pattern = match self.fill.pattern {
+            Some(fill) if fill == index => None,
+            Some(fill) if fill > index => Some(fill - 1),
+            fill => fill,
+        };
+        self.count_in.pattern = match self.count_in.pattern {
+            Some(cue) if cue == index => None,
+            Some(cue) if cue > index => Some(cue - 1),
+            cue => cue,
+        };
+        update_fields_or_fail(|updates| {
+            updates.push(("patterns".into(), serialize(self.pattern_names())?));
+            updates.push(("active_pattern".into(), serialize(self.active_pattern)?));
+            updates.push(("voices".into(), serialize(self.voices())?));
+            updates.push(("song".into(), serialize(&self.song)?));
+            updates.push(("song_position".into(), serialize(self.song_position)?));
+            updates.push(("queued_pattern".into(), serialize(self.queued_pattern)?));
+            updates.push(("fill".into(), serialize(self.fill)?));
+            updates.push(("count_in".into(), serialize(self.count_in)?));
+            Ok(())
+        })
+    }
+
+    fn set_pattern_name(&mut self, index: usize, name: String) -> JsonUpdateKind {
+        let Some(pattern) = self.patterns.get_mut(index) else {
+            return JsonUpdateKind::Failed;
+        };
+        pattern.name = name;
+        self.patterns_update()
+    }
+
+    // The song drives the patterns while it's enabled
+    fn queue_pattern(&mut self, index: usize) -> JsonUpdateKind {
+        if self.song.enabled {
+            return JsonUpdateKind::Denied;
+        }
+        if index >= self.patterns.len() {
+            return JsonUpdateKind::Failed;
+        }
+        self.queued_pattern = Some(index);
+        update_fields_or_fail(|updates| {
+            updates.push(("queued_pattern".into(), serialize(self.queued_pattern)?));
+            Ok(())
+        })
+    }
+
+    fn select_pattern(&mut self, index: usize) -> JsonUpdateKind {
+        if self.quantized_changes && self.is_playing() {
+            return self.queue_pattern(index);
+        }
+        if index >= self.patterns.len() {
+            return JsonUpdateKind::Failed;
+        }
+        self.fill_player.stop();
+        self.active_pattern = index;
+        self.queued_pattern = None;
+        self.patterns_update()
+    }
+
+    fn set_quantized_changes(&mut self, flag: bool) -> JsonUpdateKind {
+        self.quantized_changes = flag;
+        update_fields_or_fail(|updates| {
+            updates.push(("quantized_changes".into(), serialize(flag)?));
+            Ok(())
+        })
+    }
+
+    fn patterns_update(&self) -> JsonUpdateKind {
+        update_fields_or_fail(|updates| {
+            updates.push(("patterns".into(), serialize(self.pattern_names())?));
+            updates.push(("active_pattern".into(), serialize(self.active_pattern)?));
+            updates.push(("voices".into(), serialize(self.voices())?));
+            Ok(())
+        })
+    }
+
+    fn update_song(&mut self, update: impl FnOnce(&mut Song)) -> JsonUpdateKind {
+        let mut song = self.song.clone();
+        update(&mut song);
+        if !song.is_valid(self.patterns.len()) {
+            return JsonUpdateKind::Failed;
+        }
+        self.song = song;
+        // the chain starts over with the next bar
+        self.song_position = None;
+        if self.song.enabled {
+            self.queued_pattern = None;
+        }
+        update_fields_or_fail(|updates| {
+            updates.push(("song".into(), serialize(&self.song)?));
+            updates.push(("song_position".into(), serialize(self.song_position)?));
+            updates.push(("queued_pattern".into(), serialize(self.queued_pattern)?));
+            Ok(())
+        })
+    }
+
+    fn set_fill_pattern(&mut self, pattern: Option<usize>) -> JsonUpdateKind {
+        if pattern.is_some_and(|pattern| pattern >= self.patterns.len()) {
+            return JsonUpdateKind::Failed;
+        }
+        self.fill.pattern = pattern;
+        update_fields_or_fail(|updates| {
+            updates.push(("fill".into(), serialize(self.fill)?));
+            Ok(())
+        })
+    }
+
+    fn set_fill_trigger(&mut self, trigger: Option<FillTrigger>) -> JsonUpdateKind {
+        self.fill.trigger = trigger;
+        update_fields_or_fail(|updates| {
+            updates.push(("fill".into(), serialize(self.fill)?));
+            Ok(())
+        })
+    }
+
+    fn hold_fill(&mut self, flag: bool) -> JsonUpdateKind {
+        if self.fill.pattern.is_none() {
+            return JsonUpdateKind::Failed;
+        }
+        if flag {
+            self.fill_player.press();
+        } else {
+            self.fill_player.release();
+        }
+        JsonUpdateKind::Ok
+    }
+
+    // Brings back the pattern interrupted by the fill
+    fn set_count_in(&mut self, count_in: CountIn) -> JsonUpdateKind {
+        if !count_in.is_valid(self.patterns.len()) {
+            return JsonUpdateKind::Failed;
+        }
+        self.count_in = count_in;
+        update_fields_or_fail(|updates| {
+            updates.push(("count_in".into(), serialize(self.count_in)?));
+            Ok(())
+        })
+    }
+
+    fn move_instrument(&mut self, id: usize, new_id: usize) -> JsonUpdateKind {
+        for pattern in &mut self.patterns {
+            pattern.move_instrument(id, new_id);
+        }
+        let clicks = &mut self.count_in.clicks;
+        clicks.instrument_index = clicks
+            .instrument_index
+            .map(|index| moved_node(index, id, new_id));
+        // the note offs of the hits that ring go where the instrument went
+        self.schedule.move_instrument(id, new_id);
+        update_fields_or_fail(|updates| {
+            updates.push(("voices".into(), serialize(self.voices())?));
+            updates.push(("count_in".into(), serialize(self.count_in)?));
+            Ok(())
+        })
+    }
+
+    fn stop_fill(&mut self) {
+        if let Some(pattern) = self.fill_player.stop() {
+            self.active_pattern = pattern.min(self.patterns.len() - 1);
+        }
+    }
+
+    fn receive_midi_messages(&mut self) {
+        let Some(midi_rx) = &mut self.midi_rx else {
+            return;
+        };
+        let mut presses = Vec::new();
+        loop {
+            match midi_rx.try_recv() {
+                Ok(message) => {
+                    if let Some(trigger) = &self.fill.trigger {
+                        presses.extend(trigger.pressed(&message));
+                    }
+                }
+                Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+        for pressed in presses {
+            self.hold_fill(pressed);
+        }
+    }
+
+    // Called at the start of every bar, the count-in goes first, then a queued preset, then the
+    // fill takes over, then a queued pattern, then the song
+    async fn start_bar(&mut self) {
+        if self.count_in_player.is_active() {
+            let counting_in = self.count_in_player.start_bar();
+            if counting_in {
+                // the bars of the groove count from where it lands
+                self.current_bar = None;
+            }
+            if let Some(updater) = &self.json_updater {
+                let update = update_fields_or_fail(|updates| {
+                    updates.push(("counting_in".into(), serialize(counting_in)?));
+                    Ok(())
+                });
+                updater.broadcast(update).await;
+            }
+            if counting_in {
+                return;
+            }
+        }
+        if let Some((_, preset)) = self.queued_preset.take() {
+            self.apply_preset(*preset);
+            if let Some(updater) = &self.json_updater {
+                updater.broadcast(self.preset_update()).await;
+            }
+            return;
+        }
+        let fill_switch = self
+            .fill
+            .pattern
+            .and_then(|fill| self.fill_player.start_bar(fill, self.active_pattern));
+        let update = if let Some(pattern) = fill_switch {
+            self.active_pattern = pattern;
+            update_fields_or_fail(|updates| {
+                updates.push(("active_pattern".into(), serialize(self.active_pattern)?));
+                updates.push(("voices".into(), serialize(self.voices())?));
+                updates.push((
+                    "fill_playing".into(),
+                    serialize(self.fill_player.is_playing())?,
+                ));
+                Ok(())
+            })
+        } else if self.fill_player.is_playing() {
+            return;
+        } else if let Some(pattern) = self.queued_pattern.take() {
+            self.active_pattern = pattern;
+            update_fields_or_fail(|updates| {
+                updates.push(("active_pattern".into(), serialize(self.active_pattern)?));
+                updates.push(("queued_pattern".into(), serialize(self.queued_pattern)?));
+                updates.push(("voices".into(), serialize(self.voices())?));
+                Ok(())
+            })
+        } else if self.song.enabled {
+            self.advance_song()
+        } else {
+            return;
+        };
+        if let Some(updater) = &self.json_updater {
+            updater.broadcast(update).await;
+        }
+    }
+
+    // Switches to the pattern of the next chain position
+    fn advance_song(&mut self) -> JsonUpdateKind {
+        let position = match self.song_position {
+            Some(position) => self.song.next(position),
+            None => self.song.first(),
+        };
+        self.song_position = position;
+        if let Some(pattern) = position.and_then(|p| self.song.pattern_at(p)) {
+            self.active_pattern = pattern;
+        } else {
+            // the chain is over
+            self.enabled = false;
+        }
+        update_fields_or_fail(|updates| {
+            updates.push(("enabled".into(), serialize(self.enabled)?));
+            updates.push(("active_pattern".into(), serialize(self.active_pattern)?));
+            updates.push(("voices".into(), serialize(self.voices())?));
+            updates.push(("song_position".into(), serialize(self.song_position)?));
+            Ok(())
+        })
+    }
+
+    // The position is the transport's, the bars, the song and the dice start over
     fn reset(&mut self) -> JsonUpdateKind {
-        self.last_time = self.timestamp() - self.period();
-        self.current_beat = self.rhythm.num_beats - 1;
-        self.current_div = self.rhythm.num_divs - 1;
+        // the first bar starts together with the beat
+        self.current_bar = None;
+        self.song_position = None;
+        self.count_in_player.stop();
+        self.chance.restart();
+        self.patterns
+            .iter_mut()
+            .flat_map(|pattern| pattern.voices.iter_mut())
+            .for_each(|voice| voice.alternation.restart());
+        self.stop_fill();
         update_fields_or_fail(|updates| {
             updates.push(("current_beat".to_owned(), serialize(self.current_beat)?));
             updates.push(("current_div".to_owned(), serialize(self.current_div)?));
+            updates.push(("active_pattern".to_owned(), serialize(self.active_pattern)?));
+            updates.push(("voices".to_owned(), serialize(self.voices())?));
             Ok(())
         })
     }
 
     fn slot_index(&self, beat_num: u8, div_num: u8) -> usize {
-        beat_num as usize * self.rhythm.num_divs as usize + div_num as usize
-    }
-
-    async fn beat_tick(&mut self, beat_num: u8, div_num: u8) {
-        let slot_index = self.slot_index(beat_num, div_num);
-        for voice in &self.voices.voices {
-            if let Some(instrument_index) = &voice.instrument_index {
-                let channel = voice.channel;
-                if slot_index < voice.slots.len() {
-                    let enabled = voice.slots[slot_index];
-                    if enabled {
-                        self.produce_noise(*instrument_index, channel, voice.note, voice.velocity)
-                            .await;
-                    }
+        beat_num as usize * self.tempo().rhythm.num_divs as usize + div_num as usize
+    }
+
+    // Offset of the division from its place on the grid
+    fn swing_offset(&self, div_num: u8) -> f32 {
+        if div_num % 2 == 1 {
+            self.swing as f32 / 100.0 * self.period()
+        } else {
+            0.0
+        }
+    }
+
+    // Time from the start of the step to the start of the next one
+    fn step_length(&self, div_num: u8) -> f32 {
+        let swing = self.swing as f32 / 100.0 * self.period();
+        if div_num % 2 == 1 {
+            self.period() - swing
+        } else {
+            self.period() + swing
+        }
+    }
+
+    // `time` is when the slot is due on the grid, the hits are scheduled relative to it
+    fn beat_tick(&mut self, beat_num: u8, div_num: u8, time: f32) {
+        let pattern = if !self.count_in_player.is_active() {
+            self.active_pattern
+        } else if !self.count_in_player.is_playing() {
+            return;
+        } else if let Some(cue) = self.count_in.pattern {
+            cue
+        } else {
+            self.click(beat_num, div_num, time);
+            return;
+        };
+        let grid_index = self.slot_index(beat_num, div_num);
+        let grid_slots = self.tempo().rhythm.num_slots();
+        let period = self.period();
+        // voices with their own number of slots don't swing
+        let grid_step = (
+            grid_index,
+            time + self.swing_offset(div_num),
+            self.step_length(div_num),
+        );
+        let mut hits = Vec::new();
+        let boost = self.patterns[pattern].accent.boost(grid_index);
+        let dynamics = self.patterns[pattern].dynamics.clone();
+        let bar = self.current_bar.unwrap_or(0);
+        let voices = &mut self.patterns[pattern].voices;
+        let any_soloed = voices.iter().any(|voice| voice.soloed);
+        for voice in voices {
+            if voice.muted || (any_soloed && !voice.soloed) {
+                continue;
+            }
+            let Some(instrument_index) = voice.instrument_index else {
+                continue;
+            };
+            // (slot index, time, step length)
+            let steps = match voice.num_slots {
+                None => vec![grid_step],
+                Some(num_slots) => {
+                    let step_length = period * grid_slots as f32 / num_slots as f32;
+                    polyrhythm_steps(num_slots, grid_slots, grid_index)
+                        .map(|(index, offset)| (index, time + offset * period, step_length))
+                        .collect()
                 }
+            };
+            for (slot_index, step_time, step_length) in steps {
+                let Some(slot) = voice.slots.get(slot_index) else {
+                    continue;
+                };
+                let bar_position = slot_index as f32 / voice.slots.len() as f32;
+                // the accent goes before the dynamics, which scale it along
+                let velocity = slot.velocity.saturating_add(boost).min(127);
+                let velocity = dynamics.apply(velocity, bar, bar_position);
+                // the dice are rolled for every set slot, so the seeded sequence
+                // doesn't depend on the dynamics
+                if slot.velocity > 0 && self.chance.roll(slot.probability) && velocity > 0 {
+                    let note = Note {
+                        instrument_id: instrument_index,
+                        channel: voice.channel,
+                        note: voice.note,
+                        gate: voice.gate.length(period),
+                    };
+                    // every hit of a ratchet or a flam takes its turn
+                    let slot_hits: Vec<_> = slot
+                        .hits(velocity, step_length)
+                        .into_iter()
+                        .map(|(offset, velocity)| {
+                            let (note, velocity) =
+                                voice
+                                    .alternation
+                                    .hit(&mut self.chance, voice.note, velocity);
+                            (offset, note, velocity)
+                        })
+                        .collect();
+                    hits.push((note, step_time, slot_hits));
+                }
+            }
+        }
+        for (note, step_time, slot_hits) in hits {
+            let delay = self.humanize.delay(&mut self.chance);
+            for (offset, hit_note, velocity) in slot_hits {
+                let velocity = self.humanize.velocity(&mut self.chance, velocity);
+                let note = Note {
+                    note: hit_note,
+                    ..note
+                };
+                self.produce_noise(step_time + delay + offset, &note, velocity);
             }
         }
     }
 
-    async fn produce_noise(&self, instrument_id: usize, channel: u8, note: u8, velocity: u8) {
-        _ = self
-            .sender
-            .send(ControlMessage {
-                instrument_id,
-                channel,
-                note,
-                velocity,
-            })
-            .await;
-        _ = self
-            .sender
-            .send(ControlMessage {
-                instrument_id,
-                channel,
-                note,
-                velocity: 0,
-            })
-            .await;
+    // On every beat of the count-in
+    fn click(&mut self, beat_num: u8, div_num: u8, time: f32) {
+        let clicks = self.count_in.clicks;
+        let Some(instrument_id) = clicks.instrument_index.filter(|_| div_num == 0) else {
+            return;
+        };
+        let note = Note {
+            instrument_id,
+            channel: clicks.channel,
+            note: if beat_num == 0 {
+                clicks.accent_note
+            } else {
+                clicks.note
+            },
+            gate: self.period(),
+        };
+        self.produce_noise(time, &note, clicks.velocity);
+    }
+
+    fn produce_noise(&mut self, time: f32, note: &Note, velocity: u8) {
+        let note_on = ControlMessage {
+            instrument_id: note.instrument_id,
+            channel: note.channel,
+            note: note.note,
+            note_on: true,
+            velocity,
+            time: self.instant(time),
+            other: None,
+        };
+        // a longer note off of an earlier hit of the same note is kept, one note off ends both
+        let note_off_time = match self.schedule.remove_note_offs(time, &note_on) {
+            Some(earlier) => earlier.max(time + note.gate),
+            None => time + note.gate,
+        };
+        let note_off = ControlMessage {
+            note_on: false,
+            velocity: midi::DEFAULT_RELEASE_VELOCITY,
+            time: self.instant(note_off_time),
+            ..note_on.clone()
+        };
+        self.schedule.push(time, note_on);
+        self.schedule.push(note_off_time, note_off);
     }
 
     pub async fn tick(&mut self) {
         self.receive_requests();
-        if self.enabled {
-            let time = self.timestamp();
-            let period = self.period();
-            if time - self.last_time >= period {
-                self.beat_tick(self.current_beat, self.current_div).await;
-                self.advance_div();
-                self.last_time += period;
+        self.receive_midi_messages();
+        for update in self.follow_transport() {
+            if let Some(updater) = &self.json_updater {
+                updater.broadcast(update).await;
+            }
+        }
+        let now = Instant::now();
+        // steps while disabled too, so it comes in together with the others
+        let due = self.transport.next_step_at().unwrap_or(now);
+        if let Some((beat_num, div_num)) = self.transport.step(now) {
+            self.current_beat = beat_num;
+            self.current_div = div_num;
+            if self.enabled && beat_num == 0 && div_num == 0 {
+                self.current_bar = Some(self.current_bar.map_or(0, |bar| bar + 1));
+                self.start_bar().await;
+            }
+            // the song may have ended with the bar
+            if self.enabled {
+                self.beat_tick(beat_num, div_num, self.time_at(due));
             }
         }
+        // runs even when disabled, so the pending note offs still go out
+        for message in self.schedule.take_due(self.time_at(now)) {
+            _ = self.sender.send(message).await;
+        }
     }
 
     pub fn period(&self) -> f32 {
-        60.0 / (self.tempo_bpm * self.rhythm.num_divs as f32)
-    }
-
-    fn advance_div(&mut self) {
-        self.current_div = (self.current_div + 1) % self.rhythm.num_divs;
-        if self.current_div == 0 {
-            self.advance_beat();
-        }
+        let tempo = self.tempo();
+        60.0 / (tempo.tempo_bpm * tempo.rhythm.num_divs as f32)
     }
 
-    fn advance_beat(&mut self) {
-        self.current_beat = (self.current_beat + 1) % self.rhythm.num_beats;
+    // In seconds since the start
+    fn time_at(&self, instant: Instant) -> f32 {
+        instant.saturating_duration_since(self.start).as_secs_f32()
     }
 
-    fn timestamp(&self) -> f32 {
-        self.start.elapsed().unwrap_or(Duration::ZERO).as_secs_f32()
+    // The instant of a time in seconds since the start
+    fn instant(&self, time: f32) -> Option<Instant> {
+        let since_start = Duration::try_from_secs_f32(time).ok()?;
+        Some(self.start + since_start)
     }
 
     fn receive_requests(&mut self) {
@@ -300,23 +1250,70 @@ impl DrumMachine {
         }
     }
 
+    // With quantized changes a playing drum machine takes the preset at the start of the next bar
     fn load_preset_from_file(&mut self, path: &Path) -> JsonUpdateKind {
-        if let Some(path) = self.virtual_paths.translate(path) {
-            if let Ok(file) = fs::read_to_string(path) {
-                if let Ok(source) = serde_json::from_str(&file) {
-                    if self.deserialize_preset(&source).is_ok() {
-                        self.reset();
-                        return update_fields_or_fail(|updates| {
-                            updates.push(("rhythm".to_owned(), serialize(self.rhythm)?));
-                            updates.push(("voices".into(), serialize(&self.voices)?));
-                            updates.push(("tempo_bpm".into(), serialize(self.tempo_bpm)?));
-                            Ok(())
-                        });
-                    }
-                }
+        match self.read_preset_file(path) {
+            Ok(preset) if self.quantized_changes && self.is_playing() => {
+                self.queued_preset = Some((path.to_owned(), Box::new(preset)));
+                self.queued_preset_update()
+            }
+            Ok(preset) => {
+                self.apply_preset(preset);
+                self.queued_preset = None;
+                self.reset();
+                self.preset_update()
+            }
+            Err(e) => {
+                tracing::error!("Failed to load drum machine preset: {e}");
+                JsonUpdateKind::InvalidPreset(e)
             }
         }
-        JsonUpdateKind::Failed
+    }
+
+    fn queued_preset_path(&self) -> Option<&Path> {
+        self.queued_preset.as_ref().map(|(path, _)| path.as_path())
+    }
+
+    fn queued_preset_update(&self) -> JsonUpdateKind {
+        let path = self.queued_preset_path();
+        update_fields_or_fail(|updates| {
+            updates.push(("queued_preset".into(), serialize(path)?));
+            Ok(())
+        })
+    }
+
+    // Everything a preset changes
+    fn preset_update(&self) -> JsonUpdateKind {
+        let queued_preset = self.queued_preset_path();
+        update_fields_or_fail(|updates| {
+            updates.push(("rhythm".to_owned(), serialize(self.tempo().rhythm)?));
+            updates.push(("voices".into(), serialize(self.voices())?));
+            updates.push(("patterns".into(), serialize(self.pattern_names())?));
+            updates.push(("active_pattern".into(), serialize(self.active_pattern)?));
+            updates.push(("tempo_bpm".into(), serialize(self.tempo().tempo_bpm)?));
+            updates.push(("swing".into(), serialize(self.swing)?));
+            updates.push(("humanize".into(), serialize(self.humanize)?));
+            updates.push(("seed".into(), serialize(self.chance.seed())?));
+            updates.push(("song".into(), serialize(&self.song)?));
+            updates.push(("song_position".into(), serialize(self.song_position)?));
+            updates.push(("fill".into(), serialize(self.fill)?));
+            updates.push(("count_in".into(), serialize(self.count_in)?));
+            updates.push(("queued_pattern".into(), serialize(self.queued_pattern)?));
+            updates.push(("queued_preset".into(), serialize(queued_preset)?));
+            Ok(())
+        })
+    }
+
+    fn read_preset_file(&self, path: &Path) -> Result<Preset, PresetError> {
+        let path = self
+            .virtual_paths
+            .translate(path)
+            .ok_or(PresetError::InvalidPath)?;
+        let file = fs::read_to_string(path).map_err(|e| PresetError::Io(e.to_string()))?;
+        let source =
+            serde_json::from_str(&file).map_err(|e| PresetError::InvalidJson(e.to_string()))?;
+        let source = json::migrate(source, PRESET_MIGRATIONS)?;
+        parse_preset(&source)
     }
 
     fn save_preset_to_file(&self, path: &Path) -> JsonUpdateKind {
@@ -332,18 +1329,37 @@ impl DrumMachine {
         JsonUpdateKind::Failed
     }
 
-    fn deserialize_preset(&mut self, source: &serde_json::Value) -> DeserializationResult {
-        deser_field(source, "voices", |v| self.voices = v)?;
-        deser_field(source, "rhythm", |v| self.rhythm = v)?;
-        deser_field(source, "tempo_bpm", |v| self.tempo_bpm = v)?;
-        Ok(())
+    fn apply_preset(&mut self, preset: Preset) {
+        self.patterns = preset.patterns;
+        self.active_pattern = preset.active_pattern;
+        self.queued_pattern = None;
+        self.request_tempo(Tempo {
+            tempo_bpm: preset.tempo_bpm,
+            rhythm: preset.rhythm,
+        });
+        self.swing = preset.swing;
+        self.humanize = preset.humanize;
+        self.song = preset.song;
+        self.song_position = None;
+        self.fill = preset.fill;
+        self.fill_player.stop();
+        self.count_in = preset.count_in;
+        self.chance.set_seed(preset.seed);
     }
 
     fn serialize_preset(&self) -> SerializationResult {
         let result: serde_json::Value = json!({
-            "voices": serialize(&self.voices)?,
-            "rhythm": serialize(self.rhythm)?,
-            "tempo_bpm": serialize(self.tempo_bpm)?,
+            "version": json::latest_version(PRESET_MIGRATIONS),
+            "patterns": serialize(&self.patterns)?,
+            "active_pattern": serialize(self.active_pattern)?,
+            "rhythm": serialize(self.tempo().rhythm)?,
+            "tempo_bpm": serialize(self.tempo().tempo_bpm)?,
+            "swing": serialize(self.swing)?,
+            "humanize": serialize(self.humanize)?,
+            "seed": serialize(self.chance.seed())?,
+            "song": serialize(&self.song)?,
+            "fill": serialize(self.fill)?,
+            "count_in": serialize(self.count_in)?,
         });
         Ok(result)
     }
@@ -358,9 +1374,77 @@ impl DrumMachine {
             RequestKind::SetVoiceInstrument(index, ins) => self.set_voice_instrument(index, ins),
             RequestKind::SetVoiceNote(index, note) => self.set_voice_note(index, note),
             RequestKind::SetVoiceVelocity(index, veloc) => self.set_voice_velocity(index, veloc),
+            RequestKind::SetVoiceSlots(index, num_slots) => self.set_voice_slots(index, num_slots),
+            RequestKind::SetVoiceGate(index, gate) => {
+                if !gate.is_valid() {
+                    return JsonUpdateKind::Failed;
+                }
+                self.update_voice(index, |voice| voice.gate = gate)
+            }
+            RequestKind::SetVoiceMuted(index, flag) => {
+                self.update_voice(index, |voice| voice.muted = flag)
+            }
+            RequestKind::SetVoiceSoloed(index, flag) => {
+                self.update_voice(index, |voice| voice.soloed = flag)
+            }
+            RequestKind::SetVoiceAlternatives(index, alternatives) => {
+                let alternation = Alternation::new(alternatives);
+                if !alternation.is_valid() {
+                    return JsonUpdateKind::Failed;
+                }
+                self.update_voice(index, |voice| {
+                    voice.alternation.alternatives = alternation.alternatives;
+                    voice.alternation.restart();
+                })
+            }
+            RequestKind::SetVoiceAlternationOrder(index, order) => {
+                self.update_voice(index, |voice| voice.alternation.order = order)
+            }
             RequestKind::SetSlot(vi, si, slot) => self.set_slot(vi, si, slot),
+            RequestKind::SetSlotProbability(vi, si, probability) => {
+                self.set_slot_probability(vi, si, probability)
+            }
+            RequestKind::SetSlotRatchet(vi, si, hits) => {
+                self.update_slot(vi, si, |slot| slot.ratchet = hits.clamp(1, MAX_RATCHET))
+            }
+            RequestKind::SetSlotFlam(vi, si, flam) => {
+                self.update_slot(vi, si, |slot| slot.flam = flam.min(MAX_FLAM))
+            }
+            RequestKind::EditSlots(voice_index, edit) => self.edit_slots(voice_index, edit),
+            RequestKind::SetAccentStep(index, flag) => {
+                self.update_accent(|accent| accent.set_step(index, flag))
+            }
+            RequestKind::SetAccentAmount(amount) => self.update_accent(|accent| {
+                accent.amount = amount.min(accent::MAX_AMOUNT);
+                true
+            }),
+            RequestKind::SetRandomSeed(seed) => self.set_random_seed(seed),
             RequestKind::SetRhythm(rhythm) => self.set_rhythm(rhythm),
             RequestKind::SetTempoBpm(tempo_bpm) => self.set_tempo_bpm(tempo_bpm),
+            RequestKind::SetSwing(swing) => self.set_swing(swing),
+            RequestKind::SetHumanizeTiming(ms) => self.update_humanize(|h| h.timing_ms = ms),
+            RequestKind::SetHumanizeVelocity(v) => self.update_humanize(|h| h.velocity = v),
+            RequestKind::SetDynamicsEnabled(flag) => self.set_dynamics_enabled(flag),
+            RequestKind::SetDynamicsPoints(points) => self.set_dynamics_points(points),
+            RequestKind::AddPattern => self.add_pattern(),
+            RequestKind::RemovePattern(index) => self.remove_pattern(index),
+            RequestKind::SetPatternName(index, name) => self.set_pattern_name(index, name),
+            RequestKind::SelectPattern(index) => self.select_pattern(index),
+            RequestKind::QueuePattern(index) => self.queue_pattern(index),
+            RequestKind::SetQuantizedChanges(flag) => self.set_quantized_changes(flag),
+            RequestKind::SetSongEnabled(flag) => self.update_song(|song| song.enabled = flag),
+            RequestKind::SetSongLooped(flag) => self.update_song(|song| song.looped = flag),
+            RequestKind::SetSongChain(chain) => self.update_song(|song| song.chain = chain),
+            RequestKind::SetFillPattern(pattern) => self.set_fill_pattern(pattern),
+            RequestKind::SetFillTrigger(trigger) => self.set_fill_trigger(trigger),
+            RequestKind::TriggerFill => {
+                let update = self.hold_fill(true);
+                self.fill_player.release();
+                update
+            }
+            RequestKind::HoldFill(flag) => self.hold_fill(flag),
+            RequestKind::SetCountIn(count_in) => self.set_count_in(count_in),
+            RequestKind::MoveInstrument(id, new_id) => self.move_instrument(id, new_id),
             RequestKind::Reset => self.reset(),
             RequestKind::LoadPreset(path) => self.load_preset_from_file(&path),
             RequestKind::SavePreset(path) => self.save_preset_to_file(&path),
@@ -370,9 +1454,23 @@ impl DrumMachine {
     pub fn serialize(&self) -> SerializationResult {
         let result: serde_json::Value = json!({
             "enabled": serialize(self.enabled)?,
-            "voices": serialize(&self.voices)?,
-            "rhythm": serialize(self.rhythm)?,
-            "tempo_bpm": serialize(self.tempo_bpm)?,
+            "voices": serialize(self.voices())?,
+            "patterns": serialize(self.pattern_names())?,
+            "queued_pattern": serialize(self.queued_pattern)?,
+            "quantized_changes": serialize(self.quantized_changes)?,
+            "queued_preset": serialize(self.queued_preset_path())?,
+            "active_pattern": serialize(self.active_pattern)?,
+            "rhythm": serialize(self.tempo().rhythm)?,
+            "tempo_bpm": serialize(self.tempo().tempo_bpm)?,
+            "swing": serialize(self.swing)?,
+            "humanize": serialize(self.humanize)?,
+            "seed": serialize(self.chance.seed())?,
+            "song": serialize(&self.song)?,
+            "song_position": serialize(self.song_position)?,
+            "fill": serialize(self.fill)?,
+            "fill_playing": serialize(self.fill_player.is_playing())?,
+            "count_in": serialize(self.count_in)?,
+            "counting_in": serialize(self.count_in_player.is_playing())?,
             "current_beat": serialize(self.current_beat)?,
             "current_div": serialize(self.current_div)?,
         });
@@ -381,20 +1479,266 @@ impl DrumMachine {
 
     pub fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
         deser_field_opt(source, "enabled", |v| self.enabled = v)?;
-        deser_field_opt(source, "voices", |v| self.voices = v)?;
-        deser_field_opt(source, "rhythm", |v| self.rhythm = v)?;
-        deser_field_opt(source, "tempo_bpm", |v| self.tempo_bpm = v)?;
-        // do not load current_beat and current_div
-        self.voices.set_num_slots(self.rhythm.num_slots());
+        deser_field_opt(source, "quantized_changes", |v| self.quantized_changes = v)?;
+        let mut voices: Option<Voices> = None;
+        deser_field_opt(source, "voices", |v| voices = Some(v))?;
+        if let Some(voices) = voices {
+            // like in a preset, the dynamics have to be in range
+            if !voices.dynamics.is_valid() {
+                return Err(DeserializationError);
+            }
+            *self.voices_mut() = voices;
+        }
+        let mut tempo = self.tempo();
+        deser_field_opt(source, "rhythm", |v| tempo.rhythm = v)?;
+        deser_field_opt(source, "tempo_bpm", |v| tempo.tempo_bpm = v)?;
+        deser_field_opt(source, "swing", |v| self.swing = v)?;
+        deser_field_opt(source, "humanize", |v| self.humanize = v)?;
+        // do not load current_beat, current_div, the song and the fill, which need all patterns
+        self.request_tempo(tempo);
+        let num_slots = tempo.rhythm.num_slots();
+        self.voices_mut().set_num_slots(num_slots);
         Ok(())
     }
 }
 
+// A preset read and checked, all of it valid
+struct Preset {
+    patterns: Vec<Voices>,
+    active_pattern: usize,
+    rhythm: Rhythm,
+    tempo_bpm: f32,
+    swing: u8,
+    humanize: Humanize,
+    song: Song,
+    seed: Option<u64>,
+    fill: Fill,
+    count_in: CountIn,
+}
+
+fn parse_preset(source: &serde_json::Value) -> Result<Preset, PresetError> {
+    let patterns: Vec<Voices> = deser_value(source, "patterns")?;
+    let active_pattern: usize = deser_value(source, "active_pattern")?;
+    let rhythm: Rhythm = deser_value(source, "rhythm")?;
+    let tempo_bpm: f32 = deser_value(source, "tempo_bpm")?;
+    let swing: u8 = deser_value(source, "swing")?;
+    let humanize: Humanize = deser_value(source, "humanize")?;
+    let song: Song = deser_value(source, "song")?;
+    let seed: Option<u64> = deser_value(source, "seed")?;
+    let fill: Fill = deser_value(source, "fill")?;
+    let count_in: CountIn = deser_value(source, "count_in")?;
+    for (i, voices) in patterns.iter().enumerate() {
+        validate_preset(voices, &rhythm, tempo_bpm).map_err(|e| match e {
+            PresetError::OutOfRange { field, reason } => {
+                PresetError::out_of_range(format!("patterns[{i}].{field}"), reason)
+            }
+            e => e,
+        })?;
+    }
+    if active_pattern >= patterns.len() {
+        return Err(PresetError::out_of_range(
+            "active_pattern",
+            format!("must be less than {}", patterns.len()),
+        ));
+    }
+    if swing > MAX_SWING {
+        return Err(PresetError::out_of_range(
+            "swing",
+            format!("must be in 0..={MAX_SWING}"),
+        ));
+    }
+    if !humanize.is_valid() {
+        return Err(PresetError::out_of_range(
+            "humanize",
+            format!(
+                "timing_ms must be in 0.0..={} and velocity in 0..={}",
+                humanize::MAX_TIMING_MS,
+                humanize::MAX_VELOCITY
+            ),
+        ));
+    }
+    if fill
+        .pattern
+        .is_some_and(|pattern| pattern >= patterns.len())
+    {
+        return Err(PresetError::out_of_range(
+            "fill.pattern",
+            format!("must be less than {}", patterns.len()),
+        ));
+    }
+    if !count_in.is_valid(patterns.len()) {
+        return Err(PresetError::out_of_range(
+            "count_in",
+            format!(
+                "bars must be in 0..={}, pattern less than {} and the clicks MIDI values",
+                count_in::MAX_BARS,
+                patterns.len()
+            ),
+        ));
+    }
+    if !song.is_valid(patterns.len()) {
+        return Err(PresetError::out_of_range(
+            "song.chain",
+            "must refer to existing patterns and repeat them at least once",
+        ));
+    }
+    Ok(Preset {
+        patterns,
+        active_pattern,
+        rhythm,
+        tempo_bpm,
+        swing,
+        humanize,
+        song,
+        seed,
+        fill,
+        count_in,
+    })
+}
+
+fn validate_preset(voices: &Voices, rhythm: &Rhythm, tempo_bpm: f32) -> Result<(), PresetError> {
+    if rhythm.num_beats == 0 {
+        return Err(PresetError::out_of_range(
+            "rhythm.num_beats",
+            "must be at least 1",
+        ));
+    }
+    if rhythm.num_divs == 0 {
+        return Err(PresetError::out_of_range(
+            "rhythm.num_divs",
+            "must be at least 1",
+        ));
+    }
+    if !(tempo_bpm.is_finite() && tempo_bpm > 0.0) {
+        return Err(PresetError::out_of_range(
+            "tempo_bpm",
+            "must be a positive number",
+        ));
+    }
+    if voices.num_slots != rhythm.num_slots() {
+        return Err(PresetError::out_of_range(
+            "voices.num_slots",
+            format!("must match the rhythm ({} slots)", rhythm.num_slots()),
+        ));
+    }
+    if !voices.dynamics.is_valid() {
+        return Err(PresetError::out_of_range(
+            "dynamics.points",
+            format!("must be in 0.0..={}", dynamics::MAX_SCALE),
+        ));
+    }
+    if !voices.accent.is_valid(voices.num_slots) {
+        return Err(PresetError::out_of_range(
+            "accent",
+            format!(
+                "must have {} steps and an amount in 0..={}",
+                voices.num_slots,
+                accent::MAX_AMOUNT
+            ),
+        ));
+    }
+    for (i, voice) in voices.voices.iter().enumerate() {
+        if voice.channel > 15 {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].channel"),
+                "must be in 0..=15",
+            ));
+        }
+        if voice.note > 127 {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].note"),
+                "must be in 0..=127",
+            ));
+        }
+        if voice.velocity > 127 {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].velocity"),
+                "must be in 0..=127",
+            ));
+        }
+        if voice
+            .num_slots
+            .is_some_and(|num_slots| !(1..=MAX_VOICE_SLOTS).contains(&num_slots))
+        {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].num_slots"),
+                format!("must be in 1..={MAX_VOICE_SLOTS}"),
+            ));
+        }
+        let num_slots = voice.num_slots.unwrap_or(voices.num_slots);
+        if voice.slots.len() != num_slots {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].slots"),
+                format!("must have {num_slots} slots"),
+            ));
+        }
+        if !voice.gate.is_valid() {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].gate"),
+                "must not be negative",
+            ));
+        }
+        if voice.slots.iter().any(|slot| slot.velocity > 127) {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].slots"),
+                "velocities must be in 0..=127",
+            ));
+        }
+        if voice.slots.iter().any(|slot| slot.probability > 100) {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].slots"),
+                "probabilities must be in 0..=100",
+            ));
+        }
+        if voice
+            .slots
+            .iter()
+            .any(|slot| !(1..=MAX_RATCHET).contains(&slot.ratchet))
+        {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].slots"),
+                format!("ratchets must be in 1..={MAX_RATCHET}"),
+            ));
+        }
+        if !voice.alternation.is_valid() {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].alternation"),
+                format!(
+                    "must have up to {} alternatives with notes in 0..=127 and velocities in 1..=100",
+                    alternation::MAX_ALTERNATIVES
+                ),
+            ));
+        }
+        if voice.slots.iter().any(|slot| slot.flam > MAX_FLAM) {
+            return Err(PresetError::out_of_range(
+                format!("voices[{i}].slots"),
+                format!("flams must be in 0..={MAX_FLAM}"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// The slots of a voice with `voice_slots` slots that fall into the step `grid_index` of a bar
+// with `grid_slots` steps, with their offsets from the start of the step in steps
+fn polyrhythm_steps(
+    voice_slots: usize,
+    grid_slots: usize,
+    grid_index: usize,
+) -> impl Iterator<Item = (usize, f32)> {
+    let first = (grid_index * voice_slots).div_ceil(grid_slots);
+    let end = ((grid_index + 1) * voice_slots).div_ceil(grid_slots);
+    (first..end).map(move |index| {
+        let position = index as f32 * grid_slots as f32 / voice_slots as f32;
+        (index, position - grid_index as f32)
+    })
+}
+
 fn interpolate_slots(voice: &mut Voice, factor: usize) {
     let mut interpolated = Vec::with_capacity(voice.slots.len() * factor);
     for item in voice.slots.iter() {
         interpolated.push(*item);
-        interpolated.extend(std::iter::repeat(false).take(factor - 1));
+        interpolated.extend(std::iter::repeat(Slot::default()).take(factor - 1));
     }
     voice.slots = interpolated;
 }
@@ -407,10 +1751,17 @@ fn decimate_slots(voice: &mut Voice, factor: usize) {
     voice.slots = decimated;
 }
 
+// A pattern, every pattern has its own voices
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct Voices {
+    #[serde(default)]
+    name: String,
     num_slots: usize,
     voices: Vec<Voice>,
+    #[serde(default)]
+    accent: Accent,
+    #[serde(default)]
+    dynamics: Dynamics,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -419,8 +1770,100 @@ struct Voice {
     pub instrument_index: Option<usize>,
     pub channel: u8,
     pub note: u8,
+    // velocity of newly set slots
     pub velocity: u8,
-    slots: Vec<bool>,
+    #[serde(default)]
+    pub gate: Gate,
+    // own number of slots, otherwise the voice follows the rhythm
+    #[serde(default)]
+    pub num_slots: Option<usize>,
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub soloed: bool,
+    #[serde(default)]
+    pub alternation: Alternation,
+    slots: Vec<Slot>,
+}
+
+// How long hits are held before their note off
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Gate {
+    Millis(f32),
+    // Fraction of a division
+    Divisions(f32),
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Self::Millis(0.0)
+    }
+}
+
+impl Gate {
+    // In seconds, `period` is the length of a division
+    fn length(&self, period: f32) -> f32 {
+        match *self {
+            Self::Millis(ms) => ms / 1000.0,
+            Self::Divisions(fraction) => fraction * period,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        let (Self::Millis(value) | Self::Divisions(value)) = *self;
+        value.is_finite() && value >= 0.0
+    }
+}
+
+// A note of a voice about to be played
+#[derive(Clone, Copy)]
+struct Note {
+    instrument_id: usize,
+    channel: u8,
+    note: u8,
+    // in seconds
+    gate: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Slot {
+    // 0 means no hit
+    velocity: u8,
+    // in percent
+    probability: u8,
+    // number of hits in the step
+    ratchet: u8,
+    // in percent of the step, 0 means no flam
+    flam: u8,
+}
+
+impl Default for Slot {
+    fn default() -> Self {
+        Self {
+            velocity: 0,
+            probability: 100,
+            ratchet: 1,
+            flam: 0,
+        }
+    }
+}
+
+impl Slot {
+    // (offset from the start of the step, velocity) of every hit
+    fn hits(&self, velocity: u8, step_length: f32) -> Vec<(f32, u8)> {
+        if self.ratchet > 1 {
+            let spacing = step_length / self.ratchet as f32;
+            (0..self.ratchet)
+                .map(|i| (i as f32 * spacing, velocity))
+                .collect()
+        } else if self.flam > 0 {
+            let grace = ((velocity as f32 * FLAM_GRACE_SCALE).round() as u8).max(1);
+            let offset = self.flam as f32 / 100.0 * step_length;
+            vec![(0.0, grace), (offset, velocity)]
+        } else {
+            vec![(0.0, velocity)]
+        }
+    }
 }
 
 impl Voices {
@@ -428,6 +1871,7 @@ impl Voices {
         let prev_num_slots = self.num_slots;
         self.num_slots = num_slots;
         self.update_slots(prev_num_slots);
+        self.accent.set_num_steps(num_slots);
     }
 
     pub fn add_voice(&mut self) {
@@ -437,7 +1881,12 @@ impl Voices {
             channel: 9,
             note: 0,
             velocity: 127,
-            slots: vec![false; self.num_slots],
+            gate: Gate::default(),
+            num_slots: None,
+            muted: false,
+            soloed: false,
+            alternation: Alternation::default(),
+            slots: vec![Slot::default(); self.num_slots],
         });
     }
 
@@ -494,16 +1943,33 @@ impl Voices {
         }
     }
 
+    pub fn set_voice_slots(
+        &mut self,
+        voice_index: usize,
+        num_slots: Option<usize>,
+    ) -> Result<(), ()> {
+        if num_slots.is_some_and(|n| !(1..=MAX_VOICE_SLOTS).contains(&n)) {
+            return Err(());
+        }
+        let grid_slots = self.num_slots;
+        let voice = self.voices.get_mut(voice_index).ok_or(())?;
+        voice.num_slots = num_slots;
+        voice
+            .slots
+            .resize(num_slots.unwrap_or(grid_slots), Slot::default());
+        Ok(())
+    }
+
     pub fn set_slot(
         &mut self,
         voice_index: usize,
         slot_index: usize,
-        enabled: bool,
+        velocity: u8,
     ) -> Result<(), ()> {
         if voice_index < self.voices.len() {
             let voice = &mut self.voices[voice_index];
             if slot_index < voice.slots.len() {
-                voice.slots[slot_index] = enabled;
+                voice.slots[slot_index].velocity = velocity;
                 Ok(())
             } else {
                 Err(())
@@ -513,12 +1979,50 @@ impl Voices {
         }
     }
 
+    pub fn set_slot_probability(
+        &mut self,
+        voice_index: usize,
+        slot_index: usize,
+        probability: u8,
+    ) -> Result<(), ()> {
+        let slot = self
+            .voices
+            .get_mut(voice_index)
+            .and_then(|voice| voice.slots.get_mut(slot_index))
+            .ok_or(())?;
+        slot.probability = probability;
+        Ok(())
+    }
+
+    fn update_slot(
+        &mut self,
+        voice_index: usize,
+        slot_index: usize,
+        f: impl FnOnce(&mut Slot),
+    ) -> Result<(), ()> {
+        let slot = self
+            .voices
+            .get_mut(voice_index)
+            .and_then(|voice| voice.slots.get_mut(slot_index))
+            .ok_or(())?;
+        f(slot);
+        Ok(())
+    }
+
     pub fn set_all_to_silence(&mut self) {
         self.voices
             .iter_mut()
             .for_each(|voice| voice.instrument_index = None);
     }
 
+    pub fn move_instrument(&mut self, id: usize, new_id: usize) {
+        for voice in &mut self.voices {
+            voice.instrument_index = voice
+                .instrument_index
+                .map(|index| moved_node(index, id, new_id));
+        }
+    }
+
     pub fn reindex_instruments(&mut self, removed_index: usize) {
         self.voices
             .iter_mut()
@@ -551,39 +2055,706 @@ impl Voices {
         }
     }
 
-    fn update_slots_interleave(&mut self, factor: usize) {
+    // Voices with their own number of slots keep it
+    fn grid_voices_mut(&mut self) -> impl Iterator<Item = &mut Voice> {
         self.voices
             .iter_mut()
+            .filter(|voice| voice.num_slots.is_none())
+    }
+
+    fn update_slots_interleave(&mut self, factor: usize) {
+        self.grid_voices_mut()
             .for_each(|voice| interpolate_slots(voice, factor));
     }
 
     fn update_slots_append(&mut self, number: usize) {
-        self.voices
-            .iter_mut()
-            .for_each(|voice| voice.slots.resize(voice.slots.len() + number, false));
+        self.grid_voices_mut().for_each(|voice| {
+            voice
+                .slots
+                .resize(voice.slots.len() + number, Slot::default())
+        });
     }
 
     fn update_slots_decimate(&mut self, factor: usize) {
-        self.voices
-            .iter_mut()
+        self.grid_voices_mut()
             .for_each(|voice| decimate_slots(voice, factor));
     }
 
     fn update_slots_cut_out(&mut self, number: usize) {
-        self.voices
-            .iter_mut()
-            .for_each(|voice| voice.slots.resize(voice.slots.len() - number, false));
+        self.grid_voices_mut().for_each(|voice| {
+            voice
+                .slots
+                .resize(voice.slots.len() - number, Slot::default())
+        });
     }
 
     fn update_slots_resize(&mut self, size: usize) {
-        self.voices
-            .iter_mut()
-            .for_each(|voice| voice.slots.resize(size, false));
+        self.grid_voices_mut()
+            .for_each(|voice| voice.slots.resize(size, Slot::default()));
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{
+        accent::Accent,
+        alternation::Alternative,
+        count_in::{Clicks, CountIn},
+        edit::Edit,
+        parse_preset, validate_preset, DrumMachine, Gate, RequestKind, Slot, Voice, Voices,
+        PRESET_MIGRATIONS,
+    };
+    use crate::{
+        control::{
+            self, drum_machine,
+            transport::{Action, Transport},
+            ControlMessage, Tempo,
+        },
+        deser::PresetError,
+        json::{self, JsonUpdateKind},
+        path::VirtualPaths,
+        rhythm::Rhythm,
+    };
+    use serde_json::json;
+    use std::{
+        path::{Path, PathBuf},
+        time::Instant,
+    };
+    use tokio::sync::watch;
+
+    fn drum_machine() -> DrumMachine {
+        let (ctr_tx, _) = control::create_control_channel(1);
+        let (_, req_rx) = drum_machine::create_request_channel(1);
+        DrumMachine::new(ctr_tx, req_rx, VirtualPaths::default())
+    }
+
+    #[test]
+    pub fn validate_preset_ranges() {
+        let rhythm = Rhythm::default();
+        let mut voices = Voices {
+            num_slots: rhythm.num_slots(),
+            voices: vec![Voice {
+                channel: 9,
+                velocity: 127,
+                slots: vec![Slot::default(); rhythm.num_slots()],
+                ..Default::default()
+            }],
+            accent: Accent::new(rhythm.num_slots()),
+            ..Default::default()
+        };
+        assert_eq!(validate_preset(&voices, &rhythm, 90.0), Ok(()));
+        assert!(validate_preset(&voices, &rhythm, 0.0).is_err());
+
+        voices.voices[0].note = 128;
+        assert_eq!(
+            validate_preset(&voices, &rhythm, 90.0),
+            Err(PresetError::out_of_range(
+                "voices[0].note",
+                "must be in 0..=127"
+            ))
+        );
+
+        voices.voices[0].note = 36;
+        voices.voices[0].slots.pop();
+        assert!(validate_preset(&voices, &rhythm, 90.0).is_err());
+
+        let rhythm = Rhythm {
+            num_beats: 0,
+            num_divs: 4,
+        };
+        assert!(validate_preset(&voices, &rhythm, 90.0).is_err());
+    }
+
+    #[test]
+    pub fn v2_preset_migration() {
+        let voices = Voices {
+            num_slots: 2,
+            voices: vec![Voice {
+                channel: 9,
+                note: 36,
+                velocity: 100,
+                slots: vec![
+                    Slot {
+                        velocity: 100,
+                        ..Default::default()
+                    },
+                    Slot::default(),
+                ],
+                ..Default::default()
+            }],
+            accent: Accent::new(2),
+            ..Default::default()
+        };
+        let preset = json!({
+            "version": 2,
+            "voices": {
+                "num_slots": 2,
+                "voices": [{
+                    "name": "",
+                    "instrument_index": null,
+                    "channel": 9,
+                    "note": 36,
+                    "velocity": 100,
+                    "slots": [true, false],
+                }],
+            },
+            "rhythm": { "num_beats": 2, "num_divs": 1 },
+            "tempo_bpm": 120.0,
+            "dynamics": { "enabled": false, "points": [1.0] },
+        });
+        let preset = json::migrate(preset, PRESET_MIGRATIONS).unwrap();
+        assert!(preset.get("voices").is_none());
+        assert!(preset.get("dynamics").is_none());
+
+        let mut dm = drum_machine();
+        dm.apply_preset(parse_preset(&preset).unwrap());
+        assert_eq!(dm.patterns, vec![voices]);
+        assert_eq!(dm.active_pattern, 0);
+
+        let mut invalid = preset.clone();
+        invalid["song"]["chain"] = json!([{ "pattern": 1, "repeats": 4 }]);
+        assert!(parse_preset(&invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn queued_pattern_switch() {
+        let mut dm = drum_machine();
+        dm.add_pattern();
+        dm.set_pattern_name(1, "Chorus".into());
+        assert_eq!(dm.pattern_names(), vec!["", "Chorus"]);
+
+        assert!(matches!(dm.queue_pattern(2), JsonUpdateKind::Failed));
+        dm.queue_pattern(1);
+        assert_eq!(dm.active_pattern, 0);
+        dm.start_bar().await;
+        assert_eq!((dm.active_pattern, dm.queued_pattern), (1, None));
+
+        dm.update_song(|song| song.enabled = true);
+        assert!(matches!(dm.queue_pattern(0), JsonUpdateKind::Denied));
+    }
+
+    #[tokio::test]
+    async fn fill_returns_to_the_pattern() {
+        let mut dm = drum_machine();
+        assert!(matches!(
+            dm.process_request(RequestKind::TriggerFill),
+            JsonUpdateKind::Failed
+        ));
+        dm.add_pattern();
+        dm.add_pattern();
+        dm.process_request(RequestKind::SetFillPattern(Some(2)));
+        dm.select_pattern(1);
+
+        dm.process_request(RequestKind::TriggerFill);
+        assert_eq!(dm.active_pattern, 1);
+        dm.start_bar().await;
+        assert_eq!(dm.active_pattern, 2);
+        dm.start_bar().await;
+        assert_eq!(dm.active_pattern, 1);
+
+        // removing a pattern in front of the fill keeps pointing at it
+        dm.process_request(RequestKind::HoldFill(true));
+        dm.start_bar().await;
+        dm.remove_pattern(0);
+        assert_eq!((dm.active_pattern, dm.fill.pattern), (0, Some(1)));
+        assert!(!dm.fill_player.is_playing());
+    }
+
+    #[tokio::test]
+    async fn quantized_changes_wait_for_the_bar() {
+        let dir = std::env::temp_dir().join(format!("ami-drum-machine-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut dm = drum_machine();
+        dm.virtual_paths
+            .insert(PathBuf::from("presets:"), dir.clone());
+        let path = Path::new("presets:/groove.json");
+        dm.set_tempo_bpm(120.0);
+        assert!(matches!(dm.save_preset_to_file(path), JsonUpdateKind::Ok));
+        dm.set_tempo_bpm(90.0);
+        dm.add_pattern();
+
+        dm.process_request(RequestKind::SetQuantizedChanges(true));
+        dm.select_pattern(1);
+        assert_eq!((dm.active_pattern, dm.queued_pattern), (0, Some(1)));
+        dm.load_preset_from_file(path);
+        assert_eq!(dm.tempo().tempo_bpm, 90.0);
+        // the preset goes first and drops the queued pattern
+        dm.start_bar().await;
+        assert_eq!(dm.tempo().tempo_bpm, 120.0);
+        assert_eq!((dm.patterns.len(), dm.queued_pattern), (1, None));
+
+        // a stopped drum machine changes right away
+        dm.set_enabled(false);
+        dm.set_tempo_bpm(90.0);
+        dm.load_preset_from_file(path);
+        assert_eq!(dm.tempo().tempo_bpm, 120.0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn count_in_before_the_groove() {
+        let mut dm = drum_machine();
+        dm.add_voice();
+        dm.set_voice_instrument(0, Some(0));
+        dm.set_slot(0, 0, 100);
+        let clicks = Clicks {
+            instrument_index: Some(1),
+            ..Default::default()
+        };
+        let count_in = CountIn {
+            bars: 1,
+            pattern: Some(1),
+            clicks,
+        };
+        let res = dm.process_request(RequestKind::SetCountIn(count_in));
+        assert!(matches!(res, JsonUpdateKind::Failed));
+        let count_in = CountIn {
+            pattern: None,
+            ..count_in
+        };
+        dm.process_request(RequestKind::SetCountIn(count_in));
+        let note_ons = |dm: &mut DrumMachine, time| {
+            let due = dm.schedule.take_due(time);
+            due.iter()
+                .filter(|m| m.note_on)
+                .map(|m| (m.instrument_id, m.note))
+                .collect::<Vec<_>>()
+        };
+
+        // nothing plays before the first bar, then the accent and the clicks on the beats
+        dm.set_enabled(true);
+        dm.beat_tick(3, 3, 0.0);
+        assert!(note_ons(&mut dm, 0.0).is_empty());
+        dm.start_bar().await;
+        for (beat, div) in [(0, 0), (0, 1), (1, 0)] {
+            dm.beat_tick(beat, div, beat as f32);
+        }
+        assert_eq!(note_ons(&mut dm, 1.0), [(1, 76), (1, 77)]);
+
+        dm.start_bar().await;
+        dm.beat_tick(0, 0, 2.0);
+        assert_eq!(note_ons(&mut dm, 2.0), [(0, 0)]);
+    }
+
+    #[test]
+    fn instruments_follow_moved_nodes() {
+        let mut dm = drum_machine();
+        dm.add_voice();
+        dm.add_voice();
+        dm.set_voice_instrument(0, Some(0));
+        dm.set_voice_instrument(1, Some(2));
+        dm.add_pattern();
+        let mut count_in = dm.count_in;
+        count_in.clicks.instrument_index = Some(1);
+        dm.set_count_in(count_in);
+        let note_off = ControlMessage {
+            instrument_id: 0,
+            channel: 9,
+            note: 36,
+            note_on: false,
+            velocity: 64,
+            time: None,
+            other: None,
+        };
+        dm.schedule.push(1.0, note_off);
+
+        // the kick synth moves behind the others, they shift down
+        dm.process_request(RequestKind::MoveInstrument(0, 2));
+        for pattern in &dm.patterns {
+            let instruments: Vec<_> = pattern.voices.iter().map(|v| v.instrument_index).collect();
+            assert_eq!(instruments, [Some(2), Some(1)]);
+        }
+        assert_eq!(dm.count_in.clicks.instrument_index, Some(0));
+        assert_eq!(dm.schedule.take_due(1.0)[0].instrument_id, 2);
+    }
+
+    #[test]
+    fn follows_the_transport() {
+        let mut dm = drum_machine();
+        let now = Instant::now();
+        let mut transport = Transport::new(Tempo::default(), now);
+        let (transport_tx, transport_rx) = watch::channel(transport);
+        dm.set_transport_receiver(transport_rx);
+        dm.set_enabled(false);
+
+        transport.apply(Action::Stop, now);
+        transport_tx.send_replace(transport);
+        assert!(dm.follow_transport().is_empty());
+        transport.apply(Action::Continue, now);
+        transport_tx.send_replace(transport);
+        assert!(dm.follow_transport().is_empty());
+        assert!(!dm.is_playing());
+
+        // playing from the top enables it
+        transport.apply(Action::Play, now);
+        transport_tx.send_replace(transport);
+        assert_eq!(dm.follow_transport().len(), 1);
+        assert!(dm.is_playing());
+        transport.apply(Action::Stop, now);
+        transport_tx.send_replace(transport);
+        dm.follow_transport();
+        assert!(!dm.is_playing());
+    }
+
+    #[test]
+    fn plays_in_the_controllers_tempo() {
+        let mut dm = drum_machine();
+        let now = Instant::now();
+        let mut transport = Transport::new(Tempo::default(), now);
+        let (transport_tx, transport_rx) = watch::channel(transport);
+        dm.set_transport_receiver(transport_rx);
+        let (tempo_tx, mut tempo_rx) = tokio::sync::mpsc::channel(4);
+        dm.set_tempo_request_sender(tempo_tx);
+
+        // asked for, it comes with the transport
+        dm.set_tempo_bpm(120.0);
+        let request = tempo_rx.try_recv().unwrap();
+        assert_eq!(request, control::command::RequestKind::SetTempoBpm(120.0));
+        assert_eq!(dm.tempo().tempo_bpm, 90.0);
+
+        let rhythm = Rhythm {
+            num_beats: 3,
+            num_divs: 2,
+        };
+        transport.set_tempo(
+            Tempo {
+                tempo_bpm: 120.0,
+                rhythm,
+            },
+            now,
+        );
+        transport_tx.send_replace(transport);
+        assert_eq!(dm.follow_transport().len(), 1);
+        assert_eq!(dm.period(), 0.25);
+        assert_eq!(dm.voices().num_slots, rhythm.num_slots());
+        assert_eq!(dm.serialize().unwrap()["tempo_bpm"], 120.0);
+    }
+
+    #[test]
+    fn swing_delays_odd_divisions() {
+        let mut dm = drum_machine();
+        dm.add_voice();
+        dm.set_voice_instrument(0, Some(0));
+        dm.set_slot(0, 0, 100);
+        dm.set_slot(0, 1, 100);
+        assert!(matches!(dm.set_swing(80), JsonUpdateKind::Failed));
+        dm.set_swing(50);
+
+        let period = dm.period();
+        dm.beat_tick(0, 0, 1.0);
+        dm.beat_tick(0, 1, 1.0 + period);
+        // note on and off of the straight division
+        assert_eq!(dm.schedule.take_due(1.0).len(), 2);
+        assert!(dm.schedule.take_due(1.0 + period * 1.4).is_empty());
+        assert_eq!(dm.schedule.take_due(1.0 + period * 1.51).len(), 2);
+    }
+
+    #[test]
+    fn three_against_four() {
+        let mut dm = drum_machine();
+        dm.set_rhythm(Rhythm {
+            num_beats: 4,
+            num_divs: 1,
+        });
+        dm.add_voice();
+        dm.set_voice_instrument(0, Some(0));
+        assert!(matches!(
+            dm.set_voice_slots(0, Some(0)),
+            JsonUpdateKind::Failed
+        ));
+        dm.set_voice_slots(0, Some(3));
+        (0..3).for_each(|slot| _ = dm.set_slot(0, slot, 100));
+        // the voice keeps its own length when the rhythm changes
+        dm.set_rhythm(Rhythm {
+            num_beats: 4,
+            num_divs: 2,
+        });
+        assert_eq!(dm.voices().voices[0].slots.len(), 3);
+        dm.set_rhythm(Rhythm {
+            num_beats: 4,
+            num_divs: 1,
+        });
+
+        let period = dm.period();
+        for beat in 0..4 {
+            dm.beat_tick(beat, 0, beat as f32 * period);
+        }
+        // note on and off of every slot of the voice
+        let counts: Vec<_> = [0.0, 4.0 / 3.0, 8.0 / 3.0]
+            .iter()
+            .map(|step| dm.schedule.take_due(step * period + 0.001).len())
+            .collect();
+        assert_eq!(counts, [2, 2, 2]);
+        assert!(dm.schedule.take_due(10.0 * period).is_empty());
+
+        dm.set_voice_slots(0, None);
+        assert_eq!(dm.voices().voices[0].slots.len(), 4);
+    }
+
+    #[test]
+    fn copy_and_paste_rows() {
+        let mut dm = drum_machine();
+        dm.add_voice();
+        dm.add_voice();
+        dm.set_slot(0, 0, 100);
+        assert!(matches!(
+            dm.edit_slots(None, Edit::Paste),
+            JsonUpdateKind::Failed
+        ));
+        dm.edit_slots(Some(0), Edit::Copy);
+        dm.edit_slots(Some(1), Edit::Paste);
+        dm.edit_slots(Some(1), Edit::Rotate(2));
+        let velocities = |dm: &DrumMachine, voice: usize| -> Vec<u8> {
+            dm.voices().voices[voice].slots[..4]
+                .iter()
+                .map(|slot| slot.velocity)
+                .collect()
+        };
+        assert_eq!(velocities(&dm, 1), [0, 0, 100, 0]);
+
+        // the whole pattern
+        dm.edit_slots(None, Edit::Copy);
+        dm.add_pattern();
+        dm.select_pattern(1);
+        dm.edit_slots(None, Edit::Clear);
+        assert_eq!(velocities(&dm, 0), [0; 4]);
+        dm.edit_slots(None, Edit::Paste);
+        assert_eq!(velocities(&dm, 0), [100, 0, 0, 0]);
+        assert_eq!(velocities(&dm, 1), [0, 0, 100, 0]);
+        assert!(matches!(
+            dm.edit_slots(Some(2), Edit::Clear),
+            JsonUpdateKind::Failed
+        ));
+    }
+
+    #[test]
+    fn gate_delays_the_note_off() {
+        let mut dm = drum_machine();
+        dm.add_voice();
+        dm.set_voice_instrument(0, Some(0));
+        dm.set_slot(0, 0, 100);
+        dm.set_slot(0, 1, 100);
+        assert!(matches!(
+            dm.process_request(RequestKind::SetVoiceGate(0, Gate::Millis(-1.0))),
+            JsonUpdateKind::Failed
+        ));
+        dm.process_request(RequestKind::SetVoiceGate(0, Gate::Divisions(1.5)));
+
+        let period = dm.period();
+        dm.beat_tick(0, 0, 0.0);
+        let due = dm.schedule.take_due(0.0);
+        assert!(due.len() == 1 && due[0].note_on);
+        // the second hit comes before the note off of the first one, which moves past it
+        dm.beat_tick(0, 1, period);
+        let due = dm.schedule.take_due(period * 2.0);
+        assert!(due.len() == 1 && due[0].note_on);
+        let due = dm.schedule.take_due(period * 2.6);
+        assert!(due.len() == 1 && !due[0].note_on);
+    }
+
+    #[test]
+    fn accented_steps_play_louder() {
+        let mut dm = drum_machine();
+        for note in [36, 42] {
+            dm.add_voice();
+            let index = dm.voices().voices.len() - 1;
+            dm.set_voice_instrument(index, Some(0));
+            dm.set_voice_note(index, note);
+            dm.set_slot(index, 0, 80);
+            dm.set_slot(index, 1, 120);
+        }
+        let num_slots = dm.tempo().rhythm.num_slots();
+        assert!(matches!(
+            dm.process_request(RequestKind::SetAccentStep(num_slots, true)),
+            JsonUpdateKind::Failed
+        ));
+        dm.process_request(RequestKind::SetAccentStep(0, true));
+        dm.process_request(RequestKind::SetAccentStep(1, true));
+        dm.process_request(RequestKind::SetAccentAmount(30));
+        let velocities = |dm: &mut DrumMachine, div, time| {
+            dm.beat_tick(0, div, time);
+            dm.schedule
+                .take_due(time)
+                .into_iter()
+                .filter(|m| m.note_on)
+                .map(|m| m.velocity)
+                .collect::<Vec<_>>()
+        };
+        let period = dm.period();
+        assert_eq!(velocities(&mut dm, 0, 0.0), [110, 110]);
+        // never beyond the highest velocity
+        assert_eq!(velocities(&mut dm, 1, period), [127, 127]);
+
+        dm.process_request(RequestKind::SetAccentStep(0, false));
+        assert_eq!(velocities(&mut dm, 0, period * 4.0), [80, 80]);
+    }
+
+    #[test]
+    fn dynamics_belong_to_the_pattern() {
+        let mut dm = drum_machine();
+        dm.add_voice();
+        dm.set_voice_instrument(0, Some(0));
+        dm.set_slot(0, 0, 100);
+        dm.process_request(RequestKind::AddPattern);
+        dm.process_request(RequestKind::SetDynamicsEnabled(true));
+        dm.process_request(RequestKind::SetDynamicsPoints(vec![0.5]));
+        let velocities = |dm: &mut DrumMachine, time| {
+            dm.beat_tick(0, 0, time);
+            dm.schedule
+                .take_due(time)
+                .into_iter()
+                .filter(|m| m.note_on)
+                .map(|m| m.velocity)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(velocities(&mut dm, 0.0), [50]);
+        dm.process_request(RequestKind::SelectPattern(1));
+        assert_eq!(velocities(&mut dm, 10.0), [100]);
+
+        // enabled mid-bar, the hits before the first bar count as bar 0
+        dm.process_request(RequestKind::SelectPattern(0));
+        dm.set_enabled(true);
+        assert_eq!(velocities(&mut dm, 20.0), [50]);
+
+        // out of range like in a preset
+        let mut source = dm.serialize().unwrap();
+        source["voices"]["dynamics"]["points"] = json!([2.5]);
+        assert!(dm.deserialize(&source).is_err());
+        assert_eq!(dm.voices().dynamics.points, [0.5]);
+    }
+
+    #[test]
+    fn mute_and_solo() {
+        let mut dm = drum_machine();
+        for note in [36, 38, 42] {
+            dm.add_voice();
+            let index = dm.voices().voices.len() - 1;
+            dm.set_voice_instrument(index, Some(0));
+            dm.set_voice_note(index, note);
+            dm.set_slot(index, 0, 100);
+        }
+        let notes = |dm: &mut DrumMachine| {
+            dm.beat_tick(0, 0, 0.0);
+            dm.schedule
+                .take_due(0.0)
+                .into_iter()
+                .filter(|m| m.note_on)
+                .map(|m| m.note)
+                .collect::<Vec<_>>()
+        };
+        dm.process_request(RequestKind::SetVoiceMuted(0, true));
+        assert_eq!(notes(&mut dm), [38, 42]);
+        dm.process_request(RequestKind::SetVoiceSoloed(2, true));
+        assert_eq!(notes(&mut dm), [42]);
+        dm.process_request(RequestKind::SetVoiceSoloed(2, false));
+        dm.process_request(RequestKind::SetVoiceMuted(0, false));
+        assert_eq!(notes(&mut dm), [36, 38, 42]);
+        assert!(matches!(
+            dm.process_request(RequestKind::SetVoiceMuted(3, true)),
+            JsonUpdateKind::Failed
+        ));
+    }
+
+    #[test]
+    fn ratchet_hits_take_turns() {
+        let mut dm = drum_machine();
+        dm.add_voice();
+        dm.set_voice_instrument(0, Some(0));
+        dm.set_voice_note(0, 42);
+        dm.set_slot(0, 0, 100);
+        dm.process_request(RequestKind::SetSlotRatchet(0, 0, 3));
+        let alternative = Alternative {
+            note: 44,
+            velocity: 50,
+        };
+        dm.process_request(RequestKind::SetVoiceAlternatives(0, vec![alternative]));
+        dm.beat_tick(0, 0, 0.0);
+        let hits: Vec<_> = dm
+            .schedule
+            .take_due(1.0)
+            .into_iter()
+            .filter(|m| m.note_on)
+            .map(|m| (m.note, m.velocity))
+            .collect();
+        assert_eq!(hits, [(42, 100), (44, 50), (42, 100)]);
+
+        let alternative = Alternative {
+            note: 128,
+            velocity: 50,
+        };
+        assert!(matches!(
+            dm.process_request(RequestKind::SetVoiceAlternatives(0, vec![alternative])),
+            JsonUpdateKind::Failed
+        ));
+    }
+
+    #[test]
+    fn new_alternatives_start_over() {
+        let mut dm = drum_machine();
+        dm.add_voice();
+        dm.set_voice_instrument(0, Some(0));
+        dm.set_voice_note(0, 42);
+        dm.set_slot(0, 0, 100);
+        let alternatives = vec![Alternative {
+            note: 46,
+            velocity: 100,
+        }];
+        let set_alternatives = RequestKind::SetVoiceAlternatives(0, alternatives.clone());
+        assert!(matches!(
+            dm.process_request(set_alternatives.clone()),
+            JsonUpdateKind::UpdateFields(_)
+        ));
+        let note = |dm: &mut DrumMachine, time| {
+            dm.beat_tick(0, 0, time);
+            let due = dm.schedule.take_due(time);
+            due.iter().find(|m| m.note_on).map(|m| m.note)
+        };
+        assert_eq!(note(&mut dm, 0.0), Some(42));
+        assert_eq!(note(&mut dm, 1.0), Some(46));
+        // the voice's note goes first again
+        dm.process_request(set_alternatives);
+        assert_eq!(note(&mut dm, 2.0), Some(42));
+        assert_eq!(dm.voices().voices[0].alternation.alternatives, alternatives);
+
+        assert!(matches!(
+            dm.process_request(RequestKind::SetVoiceAlternatives(1, vec![])),
+            JsonUpdateKind::Failed
+        ));
+    }
+
+    #[test]
+    fn ratchets_and_flams_stay_in_the_step() {
+        let mut dm = drum_machine();
+        dm.add_voice();
+        dm.set_voice_instrument(0, Some(0));
+        dm.set_slot(0, 0, 100);
+        dm.process_request(RequestKind::SetSlotRatchet(0, 0, 4));
+        let period = dm.period();
+        dm.beat_tick(0, 0, 1.0);
+        let notes_on = |messages: Vec<ControlMessage>| {
+            messages
+                .into_iter()
+                .filter(|m| m.note_on)
+                .map(|m| m.velocity)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            notes_on(dm.schedule.take_due(1.0 + period * 0.3)),
+            [100, 100]
+        );
+        assert_eq!(
+            notes_on(dm.schedule.take_due(1.0 + period * 0.99)),
+            [100, 100]
+        );
+
+        dm.process_request(RequestKind::SetSlotRatchet(0, 0, 1));
+        dm.process_request(RequestKind::SetSlotFlam(0, 0, 90));
+        dm.beat_tick(0, 0, 1.0);
+        assert_eq!(notes_on(dm.schedule.take_due(1.0)), [60]);
+        assert!(dm.schedule.take_due(1.0 + period * 0.49).is_empty());
+        assert_eq!(notes_on(dm.schedule.take_due(1.0 + period * 0.51)), [100]);
+    }
+
     #[test]
     pub fn interpolate_decimate_slots() {
         //TODO: write new test
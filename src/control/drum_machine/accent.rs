@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+pub const MAX_AMOUNT: u8 = 127;
+const DEFAULT_AMOUNT: u8 = 20;
+
+// A row of steps on the grid of a pattern, the hits of all its voices on an accented step get
+// the amount added to their velocity. Voices with their own number of slots get it on the hits
+// falling into an accented step.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Accent {
+    pub amount: u8,
+    steps: Vec<bool>,
+}
+
+impl Default for Accent {
+    fn default() -> Self {
+        Self {
+            amount: DEFAULT_AMOUNT,
+            steps: Vec::new(),
+        }
+    }
+}
+
+impl Accent {
+    pub fn new(num_steps: usize) -> Self {
+        Self {
+            steps: vec![false; num_steps],
+            ..Default::default()
+        }
+    }
+
+    // False without a step there
+    pub fn set_step(&mut self, index: usize, flag: bool) -> bool {
+        let Some(step) = self.steps.get_mut(index) else {
+            return false;
+        };
+        *step = flag;
+        true
+    }
+
+    // Added to the velocities of the hits on the step
+    pub fn boost(&self, index: usize) -> u8 {
+        match self.steps.get(index) {
+            Some(true) => self.amount,
+            _ => 0,
+        }
+    }
+
+    // Follows the slots of the voices: the accents stay on their beats when the grid gets finer
+    // or coarser by a whole factor, otherwise steps are added or cut off at the end
+    pub fn set_num_steps(&mut self, num_steps: usize) {
+        let len = self.steps.len();
+        if len > 0 && num_steps > len && num_steps.is_multiple_of(len) {
+            let factor = num_steps / len;
+            self.steps = (0..num_steps)
+                .map(|i| i % factor == 0 && self.steps[i / factor])
+                .collect();
+        } else if num_steps > 0 && len > num_steps && len.is_multiple_of(num_steps) {
+            let factor = len / num_steps;
+            self.steps = self.steps.iter().copied().step_by(factor).collect();
+        } else {
+            self.steps.resize(num_steps, false);
+        }
+    }
+
+    pub fn is_valid(&self, num_steps: usize) -> bool {
+        self.amount <= MAX_AMOUNT && self.steps.len() == num_steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Accent;
+
+    #[test]
+    fn accents_stay_on_the_beats() {
+        let mut accent = Accent::new(4);
+        assert!(accent.set_step(1, true));
+        assert!(!accent.set_step(4, true));
+        assert_eq!((accent.boost(0), accent.boost(1)), (0, 20));
+
+        accent.set_num_steps(8);
+        assert_eq!(accent.boost(2), 20);
+        assert_eq!(accent.boost(3), 0);
+        accent.set_num_steps(4);
+        assert_eq!(accent.boost(1), 20);
+        accent.set_num_steps(6);
+        assert!(accent.is_valid(6));
+        assert_eq!(accent.boost(1), 20);
+    }
+}
@@ -0,0 +1,124 @@
+use super::chance::Chance;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_ALTERNATIVES: usize = 8;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Order {
+    #[default]
+    Cycle,
+    // Follows the random seed of the drum machine
+    Random,
+}
+
+// Another sample of the same drum, like the other hand on a hi-hat
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Alternative {
+    pub note: u8,
+    // In percent of the velocity of the hit
+    pub velocity: u8,
+}
+
+// Notes a voice takes turns with on every hit, so repeated hits don't all sound the same. The
+// note of the voice takes its turn too.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alternation {
+    pub order: Order,
+    pub alternatives: Vec<Alternative>,
+    // Turn of the next hit when cycling
+    #[serde(skip)]
+    next: usize,
+}
+
+impl Alternation {
+    pub fn new(alternatives: Vec<Alternative>) -> Self {
+        Self {
+            alternatives,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.alternatives.len() <= MAX_ALTERNATIVES
+            && self
+                .alternatives
+                .iter()
+                .all(|alt| alt.note <= 127 && (1..=100).contains(&alt.velocity))
+    }
+
+    pub fn restart(&mut self) {
+        self.next = 0;
+    }
+
+    // (note, velocity) of the next hit, the dice aren't rolled without alternatives so the hits
+    // of a seed stay the same
+    pub fn hit(&mut self, chance: &mut Chance, note: u8, velocity: u8) -> (u8, u8) {
+        if self.alternatives.is_empty() {
+            return (note, velocity);
+        }
+        let turns = self.alternatives.len() + 1;
+        let turn = match self.order {
+            Order::Cycle => {
+                let turn = self.next % turns;
+                self.next = turn + 1;
+                turn
+            }
+            Order::Random => ((chance.uniform() * turns as f32) as usize).min(turns - 1),
+        };
+        match turn.checked_sub(1) {
+            None => (note, velocity),
+            Some(index) => {
+                let alt = self.alternatives[index];
+                let velocity = (velocity as f32 * alt.velocity as f32 / 100.0).round();
+                (alt.note, (velocity as u8).max(1))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Alternation, Alternative, Order};
+    use crate::control::drum_machine::chance::Chance;
+
+    #[test]
+    fn takes_turns() {
+        let mut chance = Chance::new(Some(5));
+        let mut alternation = Alternation::default();
+        assert_eq!(alternation.hit(&mut chance, 42, 100), (42, 100));
+
+        alternation.alternatives = vec![
+            Alternative {
+                note: 44,
+                velocity: 100,
+            },
+            Alternative {
+                note: 46,
+                velocity: 50,
+            },
+        ];
+        let hits: Vec<_> = (0..4)
+            .map(|_| alternation.hit(&mut chance, 42, 100))
+            .collect();
+        assert_eq!(hits, [(42, 100), (44, 100), (46, 50), (42, 100)]);
+        alternation.restart();
+        assert_eq!(alternation.hit(&mut chance, 42, 1), (42, 1));
+        assert_eq!(alternation.hit(&mut chance, 42, 1), (44, 1));
+        assert_eq!(alternation.hit(&mut chance, 42, 1), (46, 1));
+
+        alternation.order = Order::Random;
+        let first: Vec<_> = (0..32)
+            .map(|_| alternation.hit(&mut chance, 42, 100).0)
+            .collect();
+        chance.restart();
+        let second: Vec<_> = (0..32)
+            .map(|_| alternation.hit(&mut chance, 42, 100).0)
+            .collect();
+        assert_eq!(first, second);
+        assert!([42, 44, 46].iter().all(|note| first.contains(note)));
+
+        assert!(alternation.is_valid());
+        alternation.alternatives[1].velocity = 0;
+        assert!(!alternation.is_valid());
+    }
+}
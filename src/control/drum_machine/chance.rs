@@ -0,0 +1,83 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Dice for slot probabilities, with a seed the same hits come out every time the drum machine
+// starts over, without one every run is different
+#[derive(Debug, Clone)]
+pub struct Chance {
+    seed: Option<u64>,
+    state: u64,
+}
+
+impl Chance {
+    pub fn new(seed: Option<u64>) -> Self {
+        let mut chance = Self { seed, state: 0 };
+        chance.restart();
+        chance
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+        self.restart();
+    }
+
+    pub fn restart(&mut self) {
+        self.state = self.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+    }
+
+    // `probability` is in percent
+    pub fn roll(&mut self, probability: u8) -> bool {
+        match probability {
+            0 => false,
+            100.. => true,
+            _ => self.next() % 100 < probability as u64,
+        }
+    }
+
+    // Uniformly distributed in 0.0..1.0
+    pub fn uniform(&mut self) -> f32 {
+        (self.next() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    // SplitMix64
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Default for Chance {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Chance;
+
+    #[test]
+    fn seeded_rolls_repeat() {
+        let mut chance = Chance::new(Some(7));
+        let first: Vec<_> = (0..64).map(|_| chance.roll(50)).collect();
+        chance.restart();
+        let second: Vec<_> = (0..64).map(|_| chance.roll(50)).collect();
+        assert_eq!(first, second);
+        assert!(first.iter().any(|&hit| hit) && first.iter().any(|&hit| !hit));
+
+        assert!((0..64).all(|_| chance.roll(100)));
+        assert!((0..64).all(|_| !chance.roll(0)));
+        assert!((0..64).all(|_| (0.0..1.0).contains(&chance.uniform())));
+    }
+}
@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+
+pub const MAX_BARS: u8 = 2;
+
+// Played on every beat of the count-in, the first beat of the bar with the accent note
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Clicks {
+    pub instrument_index: Option<usize>,
+    pub channel: u8,
+    pub note: u8,
+    pub accent_note: u8,
+    pub velocity: u8,
+}
+
+impl Default for Clicks {
+    fn default() -> Self {
+        Self {
+            instrument_index: None,
+            channel: 9,
+            // GM low and high wood block
+            note: 77,
+            accent_note: 76,
+            velocity: 100,
+        }
+    }
+}
+
+// Saved with the preset. Bars played when the drum machine starts, before the groove lands:
+// the clicks, or a cue pattern instead of them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CountIn {
+    pub bars: u8,
+    pub pattern: Option<usize>,
+    pub clicks: Clicks,
+}
+
+impl CountIn {
+    pub fn is_valid(&self, num_patterns: usize) -> bool {
+        let Clicks {
+            channel,
+            note,
+            accent_note,
+            velocity,
+            ..
+        } = self.clicks;
+        self.bars <= MAX_BARS
+            && self.pattern.is_none_or(|pattern| pattern < num_patterns)
+            && channel < 16
+            && note < 128
+            && accent_note < 128
+            && velocity < 128
+    }
+}
+
+// Counts the bars down from the start, the fill, a queued pattern and the song wait for the
+// groove to land
+#[derive(Debug, Default, Clone)]
+pub struct CountInPlayer {
+    left: u8,
+    playing: bool,
+}
+
+impl CountInPlayer {
+    pub fn start(&mut self, bars: u8) {
+        self.left = bars;
+        self.playing = false;
+    }
+
+    pub fn stop(&mut self) {
+        self.start(0);
+    }
+
+    // From the start until the groove lands
+    pub fn is_active(&self) -> bool {
+        self.left > 0 || self.playing
+    }
+
+    // In one of the bars of the count-in, before them nothing plays
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    // Whether the bar starting is one of the count-in
+    pub fn start_bar(&mut self) -> bool {
+        self.playing = self.left > 0;
+        self.left = self.left.saturating_sub(1);
+        self.playing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CountIn, CountInPlayer};
+
+    #[test]
+    fn counts_the_bars_down() {
+        let mut player = CountInPlayer::default();
+        assert!(!player.is_active());
+        player.start(2);
+        assert!(player.is_active() && !player.is_playing());
+        assert!(player.start_bar());
+        assert!(player.start_bar());
+        assert!(!player.start_bar());
+        assert!(!player.is_active());
+
+        let count_in = CountIn {
+            bars: 1,
+            pattern: Some(1),
+            ..Default::default()
+        };
+        assert!(count_in.is_valid(2));
+        assert!(!count_in.is_valid(1));
+    }
+}
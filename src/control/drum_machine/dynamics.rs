@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+pub const MAX_SCALE: f32 = 2.0;
+
+// Velocity scale lane of a pattern, one point per bar, the scale glides linearly from the
+// point of the current bar to the one of the next bar (the lane loops), which gives
+// crescendos and decrescendos spanning several bars
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dynamics {
+    pub enabled: bool,
+    pub points: Vec<f32>,
+}
+
+impl Default for Dynamics {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            points: vec![1.0],
+        }
+    }
+}
+
+impl Dynamics {
+    // `position` is the progress within the bar in 0.0..1.0
+    pub fn scale(&self, bar: usize, position: f32) -> f32 {
+        if !self.enabled || self.points.is_empty() {
+            return 1.0;
+        }
+        let len = self.points.len();
+        let index = bar % len;
+        let from = self.points[index];
+        let to = self.points[(index + 1) % len];
+        from + (to - from) * position.clamp(0.0, 1.0)
+    }
+
+    pub fn apply(&self, velocity: u8, bar: usize, position: f32) -> u8 {
+        let velocity = velocity as f32 * self.scale(bar, position);
+        velocity.round().clamp(0.0, 127.0) as u8
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.points
+            .iter()
+            .all(|point| (0.0..=MAX_SCALE).contains(point))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dynamics;
+
+    #[test]
+    fn crescendo() {
+        let dynamics = Dynamics {
+            enabled: true,
+            points: vec![0.5, 1.0],
+        };
+        assert_eq!(dynamics.scale(0, 0.0), 0.5);
+        assert_eq!(dynamics.scale(0, 0.5), 0.75);
+        assert_eq!(dynamics.scale(1, 0.0), 1.0);
+        // the lane loops back to the first point
+        assert_eq!(dynamics.scale(1, 0.5), 0.75);
+        assert_eq!(dynamics.scale(2, 0.0), 0.5);
+        assert_eq!(dynamics.scale(usize::MAX, 1.0), 0.5);
+        assert_eq!(dynamics.apply(100, 0, 0.0), 50);
+        assert_eq!(dynamics.apply(100, 2, 0.5), 75);
+    }
+
+    #[test]
+    fn disabled_or_invalid() {
+        let mut dynamics = Dynamics {
+            enabled: false,
+            points: vec![0.0],
+        };
+        assert_eq!(dynamics.apply(100, 3, 0.3), 100);
+        dynamics.points = vec![0.5, 2.5];
+        assert!(!dynamics.is_valid());
+        dynamics.points = vec![f32::NAN];
+        assert!(!dynamics.is_valid());
+    }
+}
@@ -0,0 +1,76 @@
+use super::Slot;
+use serde::{Deserialize, Serialize};
+
+// Operations on whole rows of slots
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Edit {
+    Copy,
+    // Pastes the copied rows, rows of different lengths are cut off or only partly filled
+    Paste,
+    // Positive steps rotate to the right (later), negative ones to the left
+    Rotate(i32),
+    // Set slots are cleared, empty ones are set to the voice velocity
+    Invert,
+    Clear,
+}
+
+pub(super) fn rotate(slots: &mut [Slot], steps: i32) {
+    if !slots.is_empty() {
+        let steps = steps.rem_euclid(slots.len() as i32) as usize;
+        slots.rotate_right(steps);
+    }
+}
+
+pub(super) fn invert(slots: &mut [Slot], velocity: u8) {
+    for slot in slots {
+        slot.velocity = if slot.velocity > 0 { 0 } else { velocity };
+    }
+}
+
+pub(super) fn clear(slots: &mut [Slot]) {
+    slots.fill(Slot::default());
+}
+
+pub(super) fn paste(slots: &mut [Slot], source: &[Slot]) {
+    slots
+        .iter_mut()
+        .zip(source)
+        .for_each(|(slot, source)| *slot = *source);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clear, invert, paste, rotate};
+    use crate::control::drum_machine::Slot;
+
+    fn row(velocities: &[u8]) -> Vec<Slot> {
+        velocities
+            .iter()
+            .map(|&velocity| Slot {
+                velocity,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn row_operations() {
+        let mut slots = row(&[100, 0, 0, 80]);
+        rotate(&mut slots, 1);
+        assert_eq!(slots, row(&[80, 100, 0, 0]));
+        rotate(&mut slots, -6);
+        assert_eq!(slots, row(&[0, 0, 80, 100]));
+
+        invert(&mut slots, 90);
+        assert_eq!(slots, row(&[90, 90, 0, 0]));
+
+        paste(&mut slots, &row(&[1, 2]));
+        assert_eq!(slots, row(&[1, 2, 0, 0]));
+        paste(&mut slots, &row(&[5, 6, 7, 8, 9]));
+        assert_eq!(slots, row(&[5, 6, 7, 8]));
+
+        clear(&mut slots);
+        assert_eq!(slots, vec![Slot::default(); 4]);
+        rotate(&mut [], 3);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::midi;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FillTrigger {
+    // Note on presses, note off releases
+    Note { channel: u8, note: u8 },
+    // Values from 64 up press, lower ones release, like a sustain pedal
+    ControlChange { channel: u8, controller: u8 },
+}
+
+impl FillTrigger {
+    // `Some(true)` for a press, `Some(false)` for a release
+    pub fn pressed(&self, message: &midi::Message) -> Option<bool> {
+        use midi::MessageKind as MK;
+        match (*self, &message.kind) {
+            (Self::Note { channel, note }, &MK::NoteOn { note: n, velocity })
+                if channel == message.channel && note == n =>
+            {
+                Some(velocity > 0)
+            }
+            (Self::Note { channel, note }, &MK::NoteOff { note: n, .. })
+                if channel == message.channel && note == n =>
+            {
+                Some(false)
+            }
+            (
+                Self::ControlChange {
+                    channel,
+                    controller,
+                },
+                &MK::ControlChange { kind, value },
+            ) if channel == message.channel && controller == kind.as_number() => Some(value >= 64),
+            _ => None,
+        }
+    }
+}
+
+// Saved with the preset
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Fill {
+    pub pattern: Option<usize>,
+    pub trigger: Option<FillTrigger>,
+}
+
+// A press makes the fill pattern take over at the start of the next bar, it plays for one bar,
+// or for as long as the press is held, and then the interrupted pattern comes back
+#[derive(Debug, Default, Clone)]
+pub struct FillPlayer {
+    pending: bool,
+    held: bool,
+    return_to: Option<usize>,
+}
+
+impl FillPlayer {
+    pub fn press(&mut self) {
+        self.pending = true;
+        self.held = true;
+    }
+
+    pub fn release(&mut self) {
+        self.held = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.return_to.is_some()
+    }
+
+    // The interrupted pattern when a fill was playing
+    pub fn stop(&mut self) -> Option<usize> {
+        self.pending = false;
+        self.held = false;
+        self.return_to.take()
+    }
+
+    // The pattern to switch to at the start of a bar, if any
+    pub fn start_bar(&mut self, fill: usize, active: usize) -> Option<usize> {
+        if self.is_playing() {
+            if self.held || self.pending {
+                self.pending = false;
+                return None;
+            }
+            return self.return_to.take();
+        }
+        if self.pending {
+            self.pending = false;
+            self.return_to = Some(active);
+            return Some(fill);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FillPlayer, FillTrigger};
+    use crate::midi::{ControlChangeKind, Message, MessageKind};
+
+    #[test]
+    fn fill_plays_one_bar_or_while_held() {
+        let mut player = FillPlayer::default();
+        assert_eq!(player.start_bar(3, 0), None);
+
+        // a tap plays a single bar
+        player.press();
+        player.release();
+        assert_eq!(player.start_bar(3, 0), Some(3));
+        assert_eq!(player.start_bar(3, 3), Some(0));
+        assert!(!player.is_playing());
+
+        player.press();
+        assert_eq!(player.start_bar(3, 1), Some(3));
+        assert_eq!(player.start_bar(3, 3), None);
+        player.release();
+        assert_eq!(player.start_bar(3, 3), Some(1));
+
+        player.press();
+        player.start_bar(3, 2);
+        assert_eq!(player.stop(), Some(2));
+    }
+
+    #[test]
+    fn trigger_messages() {
+        let note = FillTrigger::Note {
+            channel: 9,
+            note: 49,
+        };
+        let note_on = |velocity| MessageKind::NoteOn { note: 49, velocity };
+        assert_eq!(note.pressed(&Message::new(9, note_on(100))), Some(true));
+        assert_eq!(note.pressed(&Message::new(9, note_on(0))), Some(false));
+        assert_eq!(note.pressed(&Message::new(0, note_on(100))), None);
+
+        let cc = FillTrigger::ControlChange {
+            channel: 0,
+            controller: 64,
+        };
+        let pedal = |value| MessageKind::ControlChange {
+            kind: ControlChangeKind::DamperPedal,
+            value,
+        };
+        assert_eq!(cc.pressed(&Message::new(0, pedal(127))), Some(true));
+        assert_eq!(cc.pressed(&Message::new(0, pedal(0))), Some(false));
+    }
+}
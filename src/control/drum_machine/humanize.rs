@@ -0,0 +1,63 @@
+use super::chance::Chance;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_TIMING_MS: f32 = 50.0;
+pub const MAX_VELOCITY: u8 = 40;
+
+// Random deviations of every hit, hits are only ever delayed, they can't come before the slot
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Humanize {
+    pub timing_ms: f32,
+    pub velocity: u8,
+}
+
+impl Humanize {
+    pub fn is_valid(&self) -> bool {
+        (0.0..=MAX_TIMING_MS).contains(&self.timing_ms) && self.velocity <= MAX_VELOCITY
+    }
+
+    // Delay in seconds
+    pub fn delay(&self, chance: &mut Chance) -> f32 {
+        if self.timing_ms > 0.0 {
+            chance.uniform() * self.timing_ms / 1000.0
+        } else {
+            0.0
+        }
+    }
+
+    // Spreads the velocity by up to `self.velocity` in both directions, a hit never goes silent
+    pub fn velocity(&self, chance: &mut Chance, velocity: u8) -> u8 {
+        if self.velocity == 0 {
+            return velocity;
+        }
+        let spread = self.velocity as f32;
+        let offset = (chance.uniform() * 2.0 - 1.0) * spread;
+        (velocity as f32 + offset).round().clamp(1.0, 127.0) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Humanize;
+    use crate::control::drum_machine::chance::Chance;
+
+    #[test]
+    fn deviations_stay_in_range() {
+        let mut chance = Chance::new(Some(3));
+        let humanize = Humanize {
+            timing_ms: 10.0,
+            velocity: 8,
+        };
+        for _ in 0..256 {
+            assert!((0.0..0.01).contains(&humanize.delay(&mut chance)));
+            assert!((92..=108).contains(&humanize.velocity(&mut chance, 100)));
+            assert!((1..=9).contains(&humanize.velocity(&mut chance, 1)));
+        }
+        assert_eq!(Humanize::default().velocity(&mut chance, 100), 100);
+        assert!(!Humanize {
+            timing_ms: 60.0,
+            velocity: 0
+        }
+        .is_valid());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{control::ControlMessage, render::moved_node};
+
+// Control messages waiting for their time, which is in seconds since the drum machine started,
+// messages with the same time keep the order they were pushed in
+#[derive(Debug, Default, Clone)]
+pub struct Schedule {
+    events: Vec<(f32, ControlMessage)>,
+}
+
+impl Schedule {
+    pub fn push(&mut self, time: f32, message: ControlMessage) {
+        let index = self.events.partition_point(|(t, _)| *t <= time);
+        self.events.insert(index, (time, message));
+    }
+
+    // Removes the note offs of the message's note which are due after `time`, they would cut
+    // a hit starting at `time` short, returns the latest of their times
+    pub fn remove_note_offs(&mut self, time: f32, message: &ControlMessage) -> Option<f32> {
+        let mut latest: Option<f32> = None;
+        self.events.retain(|(t, m)| {
+            let cut = *t > time
+                && !m.note_on
+                && m.instrument_id == message.instrument_id
+                && m.channel == message.channel
+                && m.note == message.note;
+            if cut {
+                latest = Some(latest.map_or(*t, |l| l.max(*t)));
+            }
+            !cut
+        });
+        latest
+    }
+
+    // The renderer moved its node at `id` to `new_id`
+    pub fn move_instrument(&mut self, id: usize, new_id: usize) {
+        for (_, message) in &mut self.events {
+            message.instrument_id = moved_node(message.instrument_id, id, new_id);
+        }
+    }
+
+    pub fn take_due(&mut self, now: f32) -> Vec<ControlMessage> {
+        let count = self.events.partition_point(|(t, _)| *t <= now);
+        self.events
+            .drain(..count)
+            .map(|(_, message)| message)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use crate::control::ControlMessage;
+
+    fn message(note: u8) -> ControlMessage {
+        ControlMessage {
+            instrument_id: 0,
+            channel: 9,
+            note,
+            note_on: true,
+            velocity: 100,
+            time: None,
+            other: None,
+        }
+    }
+
+    fn note_off(note: u8) -> ControlMessage {
+        ControlMessage {
+            note_on: false,
+            ..message(note)
+        }
+    }
+
+    #[test]
+    fn due_in_order() {
+        let mut schedule = Schedule::default();
+        schedule.push(0.2, message(3));
+        schedule.push(0.1, message(1));
+        schedule.push(0.1, message(2));
+        assert!(schedule.take_due(0.05).is_empty());
+        assert_eq!(schedule.take_due(0.1), vec![message(1), message(2)]);
+        assert_eq!(schedule.take_due(1.0), vec![message(3)]);
+        assert!(schedule.take_due(2.0).is_empty());
+    }
+
+    #[test]
+    fn overlapping_note_offs() {
+        let mut schedule = Schedule::default();
+        schedule.push(0.5, note_off(1));
+        schedule.push(0.8, note_off(1));
+        schedule.push(0.8, note_off(2));
+        assert_eq!(schedule.remove_note_offs(0.5, &message(1)), Some(0.8));
+        assert_eq!(schedule.remove_note_offs(0.5, &message(1)), None);
+        assert_eq!(schedule.take_due(1.0), vec![note_off(1), note_off(2)]);
+    }
+}
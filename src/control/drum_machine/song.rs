@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ChainEntry {
+    pub pattern: usize,
+    pub repeats: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SongPosition {
+    pub entry: usize,
+    pub repeat: u32,
+}
+
+// Ordered chain of patterns played through one bar at a time, each entry plays its pattern
+// `repeats` times before moving on to the next one
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Song {
+    pub enabled: bool,
+    pub looped: bool,
+    pub chain: Vec<ChainEntry>,
+}
+
+impl Song {
+    pub fn first(&self) -> Option<SongPosition> {
+        (!self.chain.is_empty()).then_some(SongPosition::default())
+    }
+
+    // The position one bar later, `None` once the chain is over
+    pub fn next(&self, position: SongPosition) -> Option<SongPosition> {
+        let entry = self.chain.get(position.entry)?;
+        if position.repeat + 1 < entry.repeats {
+            Some(SongPosition {
+                entry: position.entry,
+                repeat: position.repeat + 1,
+            })
+        } else if position.entry + 1 < self.chain.len() {
+            Some(SongPosition {
+                entry: position.entry + 1,
+                repeat: 0,
+            })
+        } else if self.looped {
+            self.first()
+        } else {
+            None
+        }
+    }
+
+    pub fn pattern_at(&self, position: SongPosition) -> Option<usize> {
+        self.chain.get(position.entry).map(|entry| entry.pattern)
+    }
+
+    pub fn is_valid(&self, num_patterns: usize) -> bool {
+        self.chain
+            .iter()
+            .all(|entry| entry.pattern < num_patterns && entry.repeats > 0)
+    }
+
+    // Drops the entries of a removed pattern and shifts the ones after it
+    pub fn remove_pattern(&mut self, index: usize) {
+        self.chain.retain(|entry| entry.pattern != index);
+        self.chain
+            .iter_mut()
+            .filter(|entry| entry.pattern > index)
+            .for_each(|entry| entry.pattern -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChainEntry, Song, SongPosition};
+
+    fn play(song: &Song, bars: usize) -> Vec<usize> {
+        let mut position = song.first();
+        let mut patterns = Vec::new();
+        for _ in 0..bars {
+            let Some(p) = position else {
+                break;
+            };
+            patterns.push(song.pattern_at(p).unwrap());
+            position = song.next(p);
+        }
+        patterns
+    }
+
+    #[test]
+    fn chain_playback() {
+        let entry = |pattern, repeats| ChainEntry { pattern, repeats };
+        let mut song = Song {
+            enabled: true,
+            looped: false,
+            chain: vec![entry(0, 2), entry(1, 1), entry(2, 1)],
+        };
+        assert_eq!(play(&song, 10), vec![0, 0, 1, 2]);
+        song.looped = true;
+        assert_eq!(play(&song, 6), vec![0, 0, 1, 2, 0, 0]);
+        assert_eq!(
+            song.next(SongPosition {
+                entry: 5,
+                repeat: 0
+            }),
+            None
+        );
+
+        song.remove_pattern(1);
+        assert_eq!(song.chain, vec![entry(0, 2), entry(1, 1)]);
+        assert!(song.is_valid(2));
+        assert!(!song.is_valid(1));
+    }
+}
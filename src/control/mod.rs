@@ -1,14 +1,27 @@
-use crate::{midi, path::VirtualPaths};
+use crate::{
+    deser::{serialize, NodeState, SerializationResult},
+    json::{JsonUpdateSender, JsonUpdater},
+    midi,
+    pads::NoteTrigger,
+    path::VirtualPaths,
+    rhythm::Rhythm,
+};
 use command::{RequestKind, Responder, ResponseKind};
 use node::ControlPtr;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use tokio::sync::mpsc;
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
+use tokio::sync::{mpsc, watch};
 use tracing::error;
+use transport::Transport;
 
 pub mod command;
 pub mod drum_machine;
 pub mod node;
+pub mod transport;
 
 pub const MAX_BUFFER_SIZE: usize = 192000;
 
@@ -24,31 +37,100 @@ pub struct ControlMessage {
     pub instrument_id: usize,
     pub channel: u8,
     pub note: u8,
+    // note on if true, otherwise note off with `velocity` as its release velocity
+    pub note_on: bool,
     pub velocity: u8,
+    // When it's meant to sound, the renderer places it at that frame of a buffer instead of
+    // the start, without it the message sounds as soon as possible
+    #[serde(skip)]
+    pub time: Option<Instant>,
+    // Any other channel voice message, like the controllers of a MIDI file, is sent instead of
+    // the note
+    #[serde(skip)]
+    pub other: Option<midi::MessageKind>,
+}
+
+// Input notes the controller's nodes and the pads play with, the renderer's nodes don't get
+// them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConsumedInputs {
+    // (channel, note)
+    pub notes: HashSet<(u8, u8)>,
+    // Every note of them
+    pub channels: HashSet<u8>,
+}
+
+impl ConsumedInputs {
+    pub fn contains(&self, channel: u8, note: u8) -> bool {
+        self.channels.contains(&channel) || self.notes.contains(&(channel, note))
+    }
 }
 
 pub type NodeKindConstructor = Box<dyn Fn() -> ControlPtr + 'static + Sync + Send>;
 
+// What the nodes play in, shared with the renderer's nodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tempo {
+    pub tempo_bpm: f32,
+    pub rhythm: Rhythm,
+}
+
+impl Default for Tempo {
+    fn default() -> Self {
+        Self {
+            tempo_bpm: 90.0,
+            rhythm: Default::default(),
+        }
+    }
+}
+
 pub struct Controller {
     registered_node_kinds: HashMap<String, NodeKindConstructor>,
     nodes: Vec<(String, ControlPtr)>,
     midi_rx: midi::Receiver,
     req_rx: command::RequestListener,
+    sender: CtrSender,
+    json_update_tx: Option<JsonUpdateSender>,
     virtual_paths: VirtualPaths,
+    rhythm: Rhythm,
+    tempo_bpm: f32,
+    tempo_tx: watch::Sender<Tempo>,
+    transport: Transport,
+    transport_tx: watch::Sender<Transport>,
+    // While the transport plays setups wait for the start of the next bar, answered as queued
+    quantized_setups: bool,
+    queued_setup: Option<command::Setup>,
+    response_tx: Option<mpsc::Sender<ResponseKind>>,
+    pad_triggers_rx: Option<watch::Receiver<Vec<NoteTrigger>>>,
+    consumed_tx: watch::Sender<ConsumedInputs>,
 }
 
 impl Controller {
     pub fn new(
         midi_rx: midi::Receiver,
         req_rx: command::RequestListener,
+        sender: CtrSender,
         virtual_paths: VirtualPaths,
     ) -> Self {
+        let transport = Transport::new(Tempo::default(), Instant::now());
         Self {
             registered_node_kinds: Default::default(),
             nodes: Default::default(),
             midi_rx,
             req_rx,
+            sender,
+            json_update_tx: None,
             virtual_paths,
+            rhythm: Default::default(),
+            tempo_bpm: 90.0,
+            tempo_tx: watch::Sender::new(Tempo::default()),
+            transport,
+            transport_tx: watch::Sender::new(transport),
+            quantized_setups: false,
+            queued_setup: None,
+            response_tx: None,
+            pad_triggers_rx: None,
+            consumed_tx: watch::Sender::new(Default::default()),
         }
     }
 
@@ -60,19 +142,195 @@ impl Controller {
             .insert(name.to_owned(), Box::new(constructor));
     }
 
-    pub fn tick(&mut self) {
+    // The tempo and rhythm after every change
+    pub fn subscribe_tempo(&self) -> watch::Receiver<Tempo> {
+        self.tempo_tx.subscribe()
+    }
+
+    // The transport after every change of its state and on every beat while it plays
+    pub fn subscribe_transport(&self) -> watch::Receiver<Transport> {
+        self.transport_tx.subscribe()
+    }
+
+    // The notes of the pads trigger them and don't play
+    pub fn set_pad_triggers_receiver(&mut self, rx: watch::Receiver<Vec<NoteTrigger>>) {
+        self.pad_triggers_rx = Some(rx);
+        self.update_consumed_inputs();
+    }
+
+    // What the renderer's nodes don't get of the input, after every change
+    pub fn subscribe_consumed_inputs(&self) -> watch::Receiver<ConsumedInputs> {
+        self.consumed_tx.subscribe()
+    }
+
+    fn update_consumed_inputs(&mut self) {
+        let mut inputs = ConsumedInputs::default();
+        if let Some(rx) = &mut self.pad_triggers_rx {
+            let triggers = rx.borrow_and_update();
+            inputs.notes = triggers.iter().map(|t| (t.channel, t.note)).collect();
+        }
+        for (_, node) in &self.nodes {
+            node.consume_inputs(&mut inputs);
+        }
+        self.consumed_tx.send_if_modified(|consumed| {
+            let modified = *consumed != inputs;
+            *consumed = inputs;
+            modified
+        });
+    }
+
+    // Nodes broadcast the changes they make on their own through it, with their index as id
+    pub fn set_json_update_sender(&mut self, tx: JsonUpdateSender) {
+        self.json_update_tx = Some(tx);
+        self.assign_json_updaters();
+    }
+
+    // Changes the controller makes on its own, like taking a queued setup at the bar line
+    pub fn set_response_sender(&mut self, tx: mpsc::Sender<ResponseKind>) {
+        self.response_tx = Some(tx);
+    }
+
+    // The ids have to follow the nodes when they move around
+    fn assign_json_updaters(&mut self) {
+        let Some(tx) = &self.json_update_tx else {
+            return;
+        };
+        for (id, (_, node)) in self.nodes.iter_mut().enumerate() {
+            node.set_json_updater(JsonUpdater::new(id, tx.clone()));
+        }
+    }
+
+    pub async fn tick(&mut self) {
         self.receive_requests();
+        let pads_changed = self
+            .pad_triggers_rx
+            .as_ref()
+            .is_some_and(|rx| rx.has_changed().unwrap_or(false));
+        if pads_changed {
+            self.update_consumed_inputs();
+        }
         self.receive_midi_messages();
+        let now = Instant::now();
+        for (_, node) in &mut self.nodes {
+            node.tick(now).await;
+        }
+        let status = self.transport.status();
+        if let Some((beat_num, div_num)) = self.transport.step(now) {
+            if beat_num == 0 && div_num == 0 {
+                self.take_queued_setup();
+            }
+            for (_, node) in &mut self.nodes {
+                node.beat_tick(beat_num, div_num).await;
+            }
+        }
+        if self.transport.status() != status {
+            self.transport_tx.send_replace(self.transport);
+        }
+    }
+
+    pub fn period(&self) -> f32 {
+        60.0 / (self.tempo_bpm * self.rhythm.num_divs as f32)
+    }
+
+    fn set_rhythm(&mut self, rhythm: Rhythm) {
+        self.rhythm = rhythm;
+        for (_, node) in &mut self.nodes {
+            node.set_rhythm(rhythm);
+        }
+        self.tempo_tx.send_modify(|tempo| tempo.rhythm = rhythm);
+        self.update_transport_tempo();
+    }
+
+    fn set_tempo_bpm(&mut self, tempo_bpm: f32) {
+        self.tempo_bpm = tempo_bpm;
+        for (_, node) in &mut self.nodes {
+            node.set_tempo_bpm(tempo_bpm);
+        }
+        self.tempo_tx
+            .send_modify(|tempo| tempo.tempo_bpm = tempo_bpm);
+        self.update_transport_tempo();
+    }
+
+    fn update_transport_tempo(&mut self) {
+        let tempo = *self.tempo_tx.borrow();
+        self.transport.set_tempo(tempo, Instant::now());
+        self.transport_tx.send_replace(self.transport);
+    }
+
+    // The nodes keeping their own time follow it
+    fn apply_transport(&mut self, action: transport::Action) {
+        let Some(change) = self.transport.apply(action, Instant::now()) else {
+            return;
+        };
+        for (_, node) in &mut self.nodes {
+            node.follow_transport(change);
+        }
+        self.transport_tx.send_replace(self.transport);
+        if !self.transport.is_playing() {
+            self.take_queued_setup();
+        }
+    }
+
+    fn take_queued_setup(&mut self) {
+        let Some(setup) = self.queued_setup.take() else {
+            return;
+        };
+        let res = self.set_setup(setup);
+        self.update_consumed_inputs();
+        self.send_response(res);
+        self.send_response(ResponseKind::QueuedSetup(false));
+    }
+
+    // The controller ticks on its own, a full channel drops the response
+    fn send_response(&self, res: ResponseKind) {
+        if let Some(tx) = &self.response_tx {
+            tx.try_send(res)
+                .unwrap_or_else(|e| error!("Failed to send a response: {e}"));
+        }
     }
 
     pub fn add_node(&mut self, kind: String, mut node: ControlPtr) {
-        node.set_virtual_paths(self.virtual_paths.clone());
+        self.prepare_node(self.nodes.len(), &mut node);
         self.nodes.push((kind, node));
     }
 
+    fn prepare_node(&self, id: usize, node: &mut ControlPtr) {
+        node.set_virtual_paths(self.virtual_paths.clone());
+        node.set_control_sender(self.sender.clone());
+        node.set_rhythm(self.rhythm);
+        node.set_tempo_bpm(self.tempo_bpm);
+        if let Some(tx) = &self.json_update_tx {
+            node.set_json_updater(JsonUpdater::new(id, tx.clone()));
+        }
+    }
+
+    pub fn serialize(&self) -> SerializationResult {
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        for (kind, node) in &self.nodes {
+            nodes.push(json!({
+                "kind": kind,
+                "instance": node.serialize()?,
+            }));
+        }
+        Ok(json!({
+            "nodes": nodes,
+            "rhythm": serialize(self.rhythm)?,
+            "tempo_bpm": serialize(self.tempo_bpm)?,
+            "transport": serialize(self.transport.status())?,
+            "quantized_setups": serialize(self.quantized_setups)?,
+            "queued_setup": serialize(self.queued_setup.is_some())?,
+        }))
+    }
+
     pub fn receive_requests(&mut self) {
+        let mut received = false;
         while let Ok((kind, responder)) = self.req_rx.try_recv() {
             self.process_request(kind, responder);
+            received = true;
+        }
+        // any of them may change what the nodes play with
+        if received {
+            self.update_consumed_inputs();
         }
     }
 
@@ -121,6 +379,7 @@ impl Controller {
                     respond(responder, ResponseKind::InvalidId);
                 } else {
                     self.nodes.remove(id);
+                    self.assign_json_updaters();
                     respond(responder, ResponseKind::RemoveNode { id })
                 }
             }
@@ -133,9 +392,131 @@ impl Controller {
                     respond(responder, ResponseKind::CloneNode { id })
                 }
             }
-            RequestKind::MoveNode { id, new_id } => todo!(),
+            RequestKind::MoveNode { id, new_id } => {
+                if id >= self.nodes.len() || new_id >= self.nodes.len() {
+                    respond(responder, ResponseKind::InvalidId);
+                } else {
+                    let node = self.nodes.remove(id);
+                    self.nodes.insert(new_id, node);
+                    self.assign_json_updaters();
+                    respond(responder, ResponseKind::MoveNode { id, new_id })
+                }
+            }
+            RequestKind::MoveInstrument { id, new_id } => {
+                let updates = self
+                    .nodes
+                    .iter_mut()
+                    .enumerate()
+                    .filter_map(|(node_id, (_, node))| {
+                        node.move_instrument(id, new_id)
+                            .map(|update| (node_id, update))
+                    })
+                    .collect();
+                respond(responder, ResponseKind::MoveInstrument(updates));
+            }
+            RequestKind::SetRhythm(rhythm) => {
+                if rhythm.num_beats == 0 || rhythm.num_divs == 0 {
+                    respond(responder, ResponseKind::Failed);
+                } else {
+                    self.set_rhythm(rhythm);
+                    respond(responder, ResponseKind::SetRhythm(rhythm));
+                }
+            }
+            RequestKind::SetTempoBpm(tempo_bpm) => {
+                if tempo_bpm.is_finite() && tempo_bpm > 0.0 {
+                    self.set_tempo_bpm(tempo_bpm);
+                    respond(responder, ResponseKind::SetTempoBpm(tempo_bpm));
+                } else {
+                    respond(responder, ResponseKind::Failed);
+                }
+            }
+            RequestKind::Transport(action) => {
+                self.apply_transport(action);
+                respond(responder, ResponseKind::Transport(self.transport.status()));
+            }
+            RequestKind::SetQuantizedSetups(flag) => {
+                self.quantized_setups = flag;
+                if !flag {
+                    self.take_queued_setup();
+                }
+                respond(responder, ResponseKind::QuantizedSetups(flag));
+            }
+            RequestKind::SetSetup(setup) => {
+                if let Err(res) = self.check_setup(&setup) {
+                    respond(responder, res);
+                } else if !self.quantized_setups || !self.transport.is_playing() {
+                    let res = self.set_setup(setup);
+                    respond(responder, res);
+                } else {
+                    // a newer one takes the place of one still waiting
+                    self.queued_setup = Some(setup);
+                    respond(responder, ResponseKind::QueuedSetup(true));
+                }
+            }
         }
     }
+
+    // Before it's queued, so a setup waiting for the bar can always be taken
+    fn check_setup(&self, setup: &command::Setup) -> Result<(), ResponseKind> {
+        let known = setup
+            .nodes
+            .iter()
+            .all(|node| self.registered_node_kinds.contains_key(&node.kind));
+        if !known {
+            return Err(ResponseKind::InvalidNodeKind);
+        }
+        let rhythm = setup.rhythm;
+        let valid = rhythm.num_beats > 0
+            && rhythm.num_divs > 0
+            && setup.tempo_bpm.is_finite()
+            && setup.tempo_bpm > 0.0;
+        if !valid {
+            return Err(ResponseKind::Failed);
+        }
+        Ok(())
+    }
+
+    fn set_setup(&mut self, setup: command::Setup) -> ResponseKind {
+        self.set_rhythm(setup.rhythm);
+        self.set_tempo_bpm(setup.tempo_bpm);
+        self.nodes.truncate(setup.nodes.len());
+        for (id, state) in setup.nodes.iter().enumerate() {
+            let kept = self
+                .nodes
+                .get(id)
+                .is_some_and(|(kind, _)| *kind == state.kind);
+            if !kept {
+                let mut node = self.registered_node_kinds[&state.kind]();
+                self.prepare_node(id, &mut node);
+                if id < self.nodes.len() {
+                    self.nodes[id] = (state.kind.clone(), node);
+                } else {
+                    self.nodes.push((state.kind.clone(), node));
+                }
+            }
+            if self.nodes[id].1.deserialize(&state.instance).is_err() {
+                error!(
+                    "Failed to set up controller node {id} of kind {}",
+                    state.kind
+                );
+            }
+        }
+
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|(kind, node)| NodeState {
+                kind: kind.clone(),
+                instance: node.serialize().unwrap_or_default(),
+            })
+            .collect();
+        let setup = command::Setup {
+            nodes,
+            rhythm: self.rhythm,
+            tempo_bpm: self.tempo_bpm,
+        };
+        ResponseKind::Setup(setup)
+    }
 }
 
 fn respond(responder: Responder, response_kind: ResponseKind) {
@@ -143,3 +524,147 @@ fn respond(responder: Responder, response_kind: ResponseKind) {
         error!("Failed to send a response: {e:?}");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        command::{self, RequestKind, ResponseKind},
+        node::{self, metronome},
+        transport, Controller,
+    };
+    use crate::{
+        json::JsonUpdateKind, midi, pads::NoteTrigger, path::VirtualPaths, rhythm::Rhythm,
+    };
+    use serde_json::json;
+
+    fn controller() -> Controller {
+        let (_midi_tx, midi_rx) = midi::create_channel(1);
+        let (_req_tx, req_rx) = command::create_request_channel(1);
+        let (ctr_tx, _ctr_rx) = super::create_control_channel(1);
+        let mut controller = Controller::new(midi_rx, req_rx, ctr_tx, VirtualPaths::default());
+        controller.register_node_kind("Metronome", || Box::<metronome::Node>::default());
+        controller
+    }
+
+    fn request(controller: &mut Controller, kind: RequestKind) -> ResponseKind {
+        let (res_tx, mut res_rx) = command::create_response_channel();
+        controller.process_request(kind, res_tx);
+        res_rx.try_recv().unwrap()
+    }
+
+    #[test]
+    fn renames_nodes() {
+        let mut controller = controller();
+        let kind = "Metronome".to_owned();
+        request(&mut controller, RequestKind::AddNode { kind });
+        let rename = RequestKind::NodeRequest {
+            id: 0,
+            kind: node::RequestKind::SetName("Verse Beat".into()),
+        };
+        let ResponseKind::NodeResponse { id: 0, kind } = request(&mut controller, rename) else {
+            panic!("not a node response");
+        };
+        let update = vec![("name".to_owned(), json!("Verse Beat"))];
+        assert_eq!(kind, JsonUpdateKind::UpdateFields(update));
+        let state = controller.serialize().unwrap();
+        assert_eq!(state["nodes"][0]["instance"]["name"], "Verse Beat");
+    }
+
+    #[test]
+    fn moves_nodes() {
+        let mut controller = controller();
+        for name in ["Intro", "Verse", "Chorus"] {
+            let kind = "Metronome".to_owned();
+            request(&mut controller, RequestKind::AddNode { kind });
+            let id = controller.nodes.len() - 1;
+            let kind = node::RequestKind::SetName(name.into());
+            request(&mut controller, RequestKind::NodeRequest { id, kind });
+        }
+        let res = request(&mut controller, RequestKind::MoveNode { id: 0, new_id: 2 });
+        assert_eq!(res, ResponseKind::MoveNode { id: 0, new_id: 2 });
+        let res = request(&mut controller, RequestKind::MoveNode { id: 3, new_id: 0 });
+        assert_eq!(res, ResponseKind::InvalidId);
+        let state = controller.serialize().unwrap();
+        let names: Vec<_> = (0..3)
+            .map(|id| state["nodes"][id]["instance"]["name"].clone())
+            .collect();
+        assert_eq!(names, ["Verse", "Chorus", "Intro"]);
+    }
+
+    #[test]
+    fn nodes_follow_moved_instruments() {
+        let mut controller = controller();
+        for (id, instrument) in [Some(0), Some(3), None].into_iter().enumerate() {
+            let kind = "Metronome".to_owned();
+            request(&mut controller, RequestKind::AddNode { kind });
+            let kind = metronome::RequestKind::SetInstrument(instrument);
+            let kind = node::RequestKind::Metronome(kind);
+            request(&mut controller, RequestKind::NodeRequest { id, kind });
+        }
+
+        // the first synth moves behind the others, they shift down
+        let move_instrument = RequestKind::MoveInstrument { id: 0, new_id: 2 };
+        let res = request(&mut controller, move_instrument);
+        let update = vec![("instrument_index".to_owned(), json!(2))];
+        let updates = vec![(0, JsonUpdateKind::UpdateFields(update))];
+        assert_eq!(res, ResponseKind::MoveInstrument(updates));
+        let state = controller.serialize().unwrap();
+        let instruments: Vec<_> = (0..3)
+            .map(|id| state["nodes"][id]["instance"]["instrument_index"].clone())
+            .collect();
+        assert_eq!(instruments, [json!(2), json!(3), json!(null)]);
+    }
+
+    #[tokio::test]
+    async fn pad_notes_are_consumed() {
+        let mut controller = controller();
+        let consumed = controller.subscribe_consumed_inputs();
+        let trigger = NoteTrigger {
+            channel: 9,
+            note: 36,
+        };
+        let (triggers_tx, triggers_rx) = tokio::sync::watch::channel(vec![trigger]);
+        controller.set_pad_triggers_receiver(triggers_rx);
+        assert!(consumed.borrow().contains(9, 36));
+        assert!(!consumed.borrow().contains(9, 38));
+
+        triggers_tx.send_replace(vec![]);
+        controller.tick().await;
+        assert!(!consumed.borrow().contains(9, 36));
+    }
+
+    #[tokio::test]
+    async fn quantized_setups_wait_for_the_bar() {
+        let mut controller = controller();
+        let (res_tx, mut res_rx) = tokio::sync::mpsc::channel(4);
+        controller.set_response_sender(res_tx);
+        request(&mut controller, RequestKind::SetQuantizedSetups(true));
+        let setup = |tempo_bpm| command::Setup {
+            nodes: vec![],
+            rhythm: Rhythm::default(),
+            tempo_bpm,
+        };
+        let queued = ResponseKind::QueuedSetup(true);
+
+        // the transport starts with the first bar
+        let res = request(&mut controller, RequestKind::SetSetup(setup(120.0)));
+        assert_eq!(res, queued);
+        assert_eq!(controller.serialize().unwrap()["queued_setup"], true);
+        controller.tick().await;
+        assert!(matches!(res_rx.try_recv(), Ok(ResponseKind::Setup(_))));
+        assert_eq!(res_rx.try_recv(), Ok(ResponseKind::QueuedSetup(false)));
+        assert_eq!(controller.tempo_bpm, 120.0);
+
+        // a newer setup takes the place of the queued one, stopping takes it right away
+        request(&mut controller, RequestKind::SetSetup(setup(100.0)));
+        let res = request(&mut controller, RequestKind::SetSetup(setup(80.0)));
+        assert_eq!(res, queued);
+        assert!(res_rx.try_recv().is_err());
+        let stop = RequestKind::Transport(transport::Action::Stop);
+        request(&mut controller, stop);
+        assert!(matches!(res_rx.try_recv(), Ok(ResponseKind::Setup(_))));
+        assert_eq!(res_rx.try_recv(), Ok(ResponseKind::QueuedSetup(false)));
+        assert!(res_rx.try_recv().is_err());
+        assert_eq!(controller.tempo_bpm, 80.0);
+    }
+}
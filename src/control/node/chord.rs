@@ -0,0 +1,465 @@
+use super::{
+    field_update, move_instrument_index, Control, ControlPtr, RequestKind as NodeRequestKind,
+};
+use crate::{
+    control::{command::ResponseCallback, ConsumedInputs, ControlMessage, CtrSender},
+    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult},
+    json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater},
+    midi,
+    path::VirtualPaths,
+    render::moved_node,
+    rhythm::Rhythm,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+
+const DEFAULT_NAME: &str = "Chord Generator";
+const NUM_DEGREES: usize = 7;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestKind {
+    SetInstrument(Option<usize>),
+    SetInputChannel(Option<u8>),
+    SetOutputChannel(u8),
+    SetKey(u8),
+    SetScale(Scale),
+    SetDegreeChord(usize, ChordKind),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Scale {
+    Major,
+    Minor,
+}
+
+impl Scale {
+    pub fn intervals(&self) -> [u8; NUM_DEGREES] {
+        match self {
+            Scale::Major => [0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => [0, 2, 3, 5, 7, 8, 10],
+        }
+    }
+
+    // Triads built from the notes of the scale
+    pub fn diatonic_chords(&self) -> [ChordKind; NUM_DEGREES] {
+        use ChordKind::*;
+        match self {
+            Scale::Major => [Major, Minor, Minor, Major, Major, Minor, Dim],
+            Scale::Minor => [Minor, Dim, Major, Minor, Minor, Major, Major],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ChordKind {
+    Single,
+    Major,
+    Minor,
+    Dim,
+    Aug,
+    Sus2,
+    Sus4,
+    Major7,
+    Minor7,
+    Dominant7,
+    HalfDim7,
+}
+
+impl ChordKind {
+    pub fn intervals(&self) -> &'static [u8] {
+        match self {
+            ChordKind::Single => &[0],
+            ChordKind::Major => &[0, 4, 7],
+            ChordKind::Minor => &[0, 3, 7],
+            ChordKind::Dim => &[0, 3, 6],
+            ChordKind::Aug => &[0, 4, 8],
+            ChordKind::Sus2 => &[0, 2, 7],
+            ChordKind::Sus4 => &[0, 5, 7],
+            ChordKind::Major7 => &[0, 4, 7, 11],
+            ChordKind::Minor7 => &[0, 3, 7, 10],
+            ChordKind::Dominant7 => &[0, 4, 7, 10],
+            ChordKind::HalfDim7 => &[0, 3, 6, 10],
+        }
+    }
+}
+
+// Notes outside of the scale are not harmonized
+pub fn chord_notes(key: u8, scale: Scale, chords: &[ChordKind], note: u8) -> Vec<u8> {
+    let pitch_class = (note + 12 - key % 12) % 12;
+    let chord = scale
+        .intervals()
+        .iter()
+        .position(|&interval| interval == pitch_class)
+        .and_then(|degree| chords.get(degree))
+        .unwrap_or(&ChordKind::Single);
+    chord
+        .intervals()
+        .iter()
+        .map(|&interval| note as u16 + interval as u16)
+        .filter(|&note| note < 128)
+        .map(|note| note as u8)
+        .collect()
+}
+
+pub struct Node {
+    name: String,
+    enabled: bool,
+    instrument_index: Option<usize>,
+    input_channel: Option<u8>,
+    output_channel: u8,
+    key: u8,
+    scale: Scale,
+    chords: Vec<ChordKind>,
+    // notes sent for each held input note, so they are released even if the settings change
+    sounding: HashMap<u8, (usize, Vec<u8>)>,
+    // how many held chords share a tone, it's released with the last of them
+    tones: HashMap<(usize, u8), usize>,
+    sender: Option<CtrSender>,
+    json_updater: Option<JsonUpdater>,
+}
+
+impl Node {
+    fn set_name(&mut self, name: &str) -> JsonUpdateKind {
+        self.name = name.into();
+        field_update("name", name)
+    }
+
+    fn set_enabled(&mut self, flag: bool) -> JsonUpdateKind {
+        self.enabled = flag;
+        if !flag {
+            self.release_all();
+        }
+        field_update("enabled", flag)
+    }
+
+    fn process_chord_request(&mut self, kind: RequestKind) -> JsonUpdateKind {
+        match kind {
+            RequestKind::SetInstrument(index) => {
+                self.release_all();
+                self.instrument_index = index;
+                update_fields_or_fail(|updates| {
+                    updates.push(("instrument_index".into(), serialize(index)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetInputChannel(channel) => {
+                self.input_channel = channel.map(|c| c.min(15));
+                update_fields_or_fail(|updates| {
+                    updates.push(("input_channel".into(), serialize(self.input_channel)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetOutputChannel(channel) => {
+                self.output_channel = channel.min(15);
+                update_fields_or_fail(|updates| {
+                    updates.push(("output_channel".into(), serialize(self.output_channel)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetKey(key) => {
+                self.key = key % 12;
+                update_fields_or_fail(|updates| {
+                    updates.push(("key".into(), serialize(self.key)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetScale(scale) => {
+                self.scale = scale;
+                self.chords = scale.diatonic_chords().to_vec();
+                update_fields_or_fail(|updates| {
+                    updates.push(("scale".into(), serialize(scale)?));
+                    updates.push(("chords".into(), serialize(&self.chords)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetDegreeChord(degree, chord) => {
+                if degree < self.chords.len() {
+                    self.chords[degree] = chord;
+                    update_fields_or_fail(|updates| {
+                        updates.push(("chords".into(), serialize(&self.chords)?));
+                        Ok(())
+                    })
+                } else {
+                    JsonUpdateKind::Failed
+                }
+            }
+        }
+    }
+
+    fn send(&self, instrument_id: usize, note: u8, note_on: bool, velocity: u8) {
+        if let Some(sender) = &self.sender {
+            let msg = ControlMessage {
+                instrument_id,
+                channel: self.output_channel,
+                note,
+                note_on,
+                velocity,
+                time: None,
+                other: None,
+            };
+            if sender.try_send(msg).is_err() {
+                tracing::warn!("Chord generator failed to send a note, the channel is full");
+            }
+        }
+    }
+
+    fn note_on(&mut self, note: u8, velocity: u8) {
+        let Some(instrument_id) = self.instrument_index else {
+            return;
+        };
+        self.note_off(note, midi::DEFAULT_RELEASE_VELOCITY);
+        let notes = chord_notes(self.key, self.scale, &self.chords, note);
+        for &note in &notes {
+            let count = self.tones.entry((instrument_id, note)).or_default();
+            *count += 1;
+            if *count == 1 {
+                self.send(instrument_id, note, true, velocity);
+            }
+        }
+        self.sounding.insert(note, (instrument_id, notes));
+    }
+
+    fn note_off(&mut self, note: u8, velocity: u8) {
+        let Some((instrument_id, notes)) = self.sounding.remove(&note) else {
+            return;
+        };
+        for note in notes {
+            let Some(count) = self.tones.get_mut(&(instrument_id, note)) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                self.tones.remove(&(instrument_id, note));
+                self.send(instrument_id, note, false, velocity);
+            }
+        }
+    }
+
+    fn release_all(&mut self) {
+        let held: Vec<u8> = self.sounding.keys().copied().collect();
+        for note in held {
+            self.note_off(note, midi::DEFAULT_RELEASE_VELOCITY);
+        }
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_NAME.into(),
+            enabled: true,
+            instrument_index: None,
+            input_channel: None,
+            output_channel: 0,
+            key: 0,
+            scale: Scale::Major,
+            chords: Scale::Major.diatonic_chords().to_vec(),
+            sounding: HashMap::new(),
+            tones: HashMap::new(),
+            sender: None,
+            json_updater: None,
+        }
+    }
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            instrument_index: self.instrument_index,
+            input_channel: self.input_channel,
+            output_channel: self.output_channel,
+            key: self.key,
+            scale: self.scale,
+            chords: self.chords.clone(),
+            sounding: HashMap::new(),
+            tones: HashMap::new(),
+            sender: self.sender.clone(),
+            json_updater: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Control for Node {
+    async fn reset(&mut self) {
+        self.release_all();
+    }
+
+    async fn beat_tick(&mut self, _beat_num: u8, _div_num: u8) {}
+
+    fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+
+    fn set_rhythm(&mut self, _rhythm: Rhythm) {}
+
+    fn set_tempo_bpm(&mut self, _tempo_bpm: f32) {}
+
+    fn receive_midi_message(&mut self, message: &midi::Message) {
+        if !self.enabled || self.input_channel.is_some_and(|c| c != message.channel) {
+            return;
+        }
+        match message.kind {
+            midi::MessageKind::NoteOn { note, velocity } => self.note_on(note, velocity),
+            midi::MessageKind::NoteOff { note, velocity } => self.note_off(note, velocity),
+            _ => {}
+        }
+    }
+
+    // The input notes only play as chords
+    fn consume_inputs(&self, inputs: &mut ConsumedInputs) {
+        if !self.enabled || self.instrument_index.is_none() {
+            return;
+        }
+        if let Some(channel) = self.input_channel {
+            inputs.channels.insert(channel);
+        } else {
+            inputs.channels.extend(0..16);
+        }
+    }
+
+    // The held chords are released where the instrument went
+    fn move_instrument(&mut self, id: usize, new_id: usize) -> Option<JsonUpdateKind> {
+        for (instrument_id, _) in self.sounding.values_mut() {
+            *instrument_id = moved_node(*instrument_id, id, new_id);
+        }
+        self.tones = std::mem::take(&mut self.tones)
+            .into_iter()
+            .map(|((instrument_id, note), count)| {
+                ((moved_node(instrument_id, id, new_id), note), count)
+            })
+            .collect();
+        move_instrument_index(&mut self.instrument_index, id, new_id)
+    }
+
+    fn set_control_sender(&mut self, sender: CtrSender) {
+        self.sender = Some(sender);
+    }
+
+    fn set_json_updater(&mut self, updater: JsonUpdater) {
+        self.json_updater = Some(updater);
+    }
+
+    fn process_request(&mut self, kind: NodeRequestKind, cb: ResponseCallback) {
+        type RK = NodeRequestKind;
+        match kind {
+            RK::SetName(name) => cb(self.set_name(&name)),
+            RK::SetEnabled(flag) => cb(self.set_enabled(flag)),
+            RK::Chord(kind) => cb(self.process_chord_request(kind)),
+            _ => cb(JsonUpdateKind::Denied),
+        }
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        let result: serde_json::Value = json!({
+            "name": serialize(&self.name)?,
+            "enabled": serialize(self.enabled)?,
+            "instrument_index": serialize(self.instrument_index)?,
+            "input_channel": serialize(self.input_channel)?,
+            "output_channel": serialize(self.output_channel)?,
+            "key": serialize(self.key)?,
+            "scale": serialize(self.scale)?,
+            "chords": serialize(&self.chords)?,
+        });
+        Ok(result)
+    }
+
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        deser_field_opt(source, "name", |v| self.name = v)?;
+        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
+        deser_field_opt(source, "instrument_index", |v| self.instrument_index = v)?;
+        deser_field_opt(source, "input_channel", |v| self.input_channel = v)?;
+        deser_field_opt(source, "output_channel", |v| self.output_channel = v)?;
+        deser_field_opt(source, "key", |v| self.key = v)?;
+        deser_field_opt(source, "scale", |v| self.scale = v)?;
+        deser_field_opt(source, "chords", |v| self.chords = v)?;
+        self.chords.resize(NUM_DEGREES, ChordKind::Single);
+        Ok(())
+    }
+
+    fn clone_node(&self) -> ControlPtr {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chord_notes, ChordKind, Node, Scale};
+    use crate::{
+        control::{node::Control, ConsumedInputs},
+        midi,
+    };
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn diatonic_chords() {
+        let chords = Scale::Major.diatonic_chords();
+        // C major: C, Dm, Bdim
+        assert_eq!(chord_notes(0, Scale::Major, &chords, 60), vec![60, 64, 67]);
+        assert_eq!(chord_notes(0, Scale::Major, &chords, 62), vec![62, 65, 69]);
+        assert_eq!(chord_notes(0, Scale::Major, &chords, 71), vec![71, 74, 77]);
+        // chromatic notes are left alone
+        assert_eq!(chord_notes(0, Scale::Major, &chords, 61), vec![61]);
+
+        // A minor: Am, E minor
+        let chords = Scale::Minor.diatonic_chords();
+        assert_eq!(chord_notes(9, Scale::Minor, &chords, 57), vec![57, 60, 64]);
+        assert_eq!(chord_notes(9, Scale::Minor, &chords, 64), vec![64, 67, 71]);
+    }
+
+    #[test]
+    fn custom_chords_and_range() {
+        let mut chords = Scale::Major.diatonic_chords();
+        chords[4] = ChordKind::Dominant7;
+        assert_eq!(
+            chord_notes(0, Scale::Major, &chords, 67),
+            vec![67, 71, 74, 77]
+        );
+        assert_eq!(chord_notes(0, Scale::Major, &chords, 127), vec![127]);
+    }
+
+    #[test]
+    fn shared_tones_last_until_the_last_chord() {
+        let (tx, mut rx) = mpsc::channel(32);
+        let mut node = Node {
+            instrument_index: Some(0),
+            ..Default::default()
+        };
+        node.set_control_sender(tx);
+        let mut inputs = ConsumedInputs::default();
+        node.consume_inputs(&mut inputs);
+        assert!(inputs.contains(3, 60));
+
+        let mut received = || {
+            let mut notes = Vec::new();
+            while let Ok(msg) = rx.try_recv() {
+                notes.push((msg.note, msg.note_on));
+            }
+            notes
+        };
+        let play = |node: &mut Node, note, note_on| {
+            let kind = if note_on {
+                midi::MessageKind::NoteOn {
+                    note,
+                    velocity: 100,
+                }
+            } else {
+                midi::MessageKind::NoteOff { note, velocity: 0 }
+            };
+            node.receive_midi_message(&midi::Message::new(0, kind));
+        };
+        // C and Em share E and G
+        play(&mut node, 60, true);
+        play(&mut node, 64, true);
+        assert_eq!(
+            received(),
+            vec![(60, true), (64, true), (67, true), (71, true)]
+        );
+        play(&mut node, 60, false);
+        assert_eq!(received(), vec![(60, false)]);
+        play(&mut node, 64, false);
+        assert_eq!(received(), vec![(64, false), (67, false), (71, false)]);
+    }
+}
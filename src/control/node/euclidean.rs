@@ -0,0 +1,314 @@
+use super::{field_update, produce_noise, Control, ControlPtr, RequestKind as NodeRequestKind};
+use crate::{
+    control::{command::ResponseCallback, CtrSender},
+    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult},
+    json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater},
+    midi,
+    path::VirtualPaths,
+    render::moved_node,
+    rhythm::Rhythm,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const DEFAULT_NAME: &str = "Euclidean Rhythm";
+const MAX_STEPS: u8 = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestKind {
+    AddVoice,
+    RemoveVoice(usize),
+    ClearVoices,
+    SetVoiceInstrument(usize, Option<usize>),
+    SetVoiceChannel(usize, u8),
+    SetVoiceNote(usize, u8),
+    SetVoiceVelocity(usize, u8),
+    // (voice, steps, pulses, rotation)
+    SetVoicePattern(usize, u8, u8, u8),
+}
+
+// Distributes `pulses` onsets as evenly as possible over `steps` steps, the result is rotated
+// to the left by `rotation` steps
+pub fn pattern(steps: u8, pulses: u8, rotation: u8) -> Vec<bool> {
+    let steps = steps as usize;
+    let pulses = pulses as usize;
+    (0..steps)
+        .map(|i| ((i + rotation as usize) * pulses) % steps < pulses)
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Voice {
+    instrument_index: Option<usize>,
+    channel: u8,
+    note: u8,
+    velocity: u8,
+    steps: u8,
+    pulses: u8,
+    rotation: u8,
+    pattern: Vec<bool>,
+    #[serde(skip)]
+    position: usize,
+}
+
+impl Default for Voice {
+    fn default() -> Self {
+        Self {
+            instrument_index: None,
+            channel: 9,
+            note: 36,
+            velocity: 127,
+            steps: 8,
+            pulses: 3,
+            rotation: 0,
+            pattern: pattern(8, 3, 0),
+            position: 0,
+        }
+    }
+}
+
+impl Voice {
+    fn set_pattern(&mut self, steps: u8, pulses: u8, rotation: u8) {
+        self.steps = steps.min(MAX_STEPS);
+        self.pulses = pulses.min(self.steps);
+        self.rotation = if self.steps > 0 {
+            rotation % self.steps
+        } else {
+            0
+        };
+        self.pattern = pattern(self.steps, self.pulses, self.rotation);
+        self.position = 0;
+    }
+
+    // Returns whether the current step is a pulse and moves to the next step
+    fn advance(&mut self) -> bool {
+        if self.pattern.is_empty() {
+            return false;
+        }
+        let pulse = self.pattern[self.position % self.pattern.len()];
+        self.position = (self.position + 1) % self.pattern.len();
+        pulse
+    }
+}
+
+pub struct Node {
+    name: String,
+    enabled: bool,
+    voices: Vec<Voice>,
+    sender: Option<CtrSender>,
+    json_updater: Option<JsonUpdater>,
+}
+
+impl Node {
+    fn set_name(&mut self, name: &str) -> JsonUpdateKind {
+        self.name = name.into();
+        field_update("name", name)
+    }
+
+    fn set_enabled(&mut self, flag: bool) -> JsonUpdateKind {
+        self.enabled = flag;
+        field_update("enabled", flag)
+    }
+
+    fn update_voices(&mut self, update: impl FnOnce(&mut Vec<Voice>) -> bool) -> JsonUpdateKind {
+        if update(&mut self.voices) {
+            update_fields_or_fail(|updates| {
+                updates.push(("voices".into(), serialize(&self.voices)?));
+                Ok(())
+            })
+        } else {
+            JsonUpdateKind::Failed
+        }
+    }
+
+    fn update_voice(&mut self, index: usize, update: impl FnOnce(&mut Voice)) -> JsonUpdateKind {
+        self.update_voices(|voices| {
+            if let Some(voice) = voices.get_mut(index) {
+                update(voice);
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    fn process_euclidean_request(&mut self, kind: RequestKind) -> JsonUpdateKind {
+        match kind {
+            RequestKind::AddVoice => self.update_voices(|voices| {
+                voices.push(Voice::default());
+                true
+            }),
+            RequestKind::RemoveVoice(index) => self.update_voices(|voices| {
+                if index < voices.len() {
+                    voices.remove(index);
+                    true
+                } else {
+                    false
+                }
+            }),
+            RequestKind::ClearVoices => self.update_voices(|voices| {
+                voices.clear();
+                true
+            }),
+            RequestKind::SetVoiceInstrument(index, instrument_index) => {
+                self.update_voice(index, |v| v.instrument_index = instrument_index)
+            }
+            RequestKind::SetVoiceChannel(index, channel) => {
+                self.update_voice(index, |v| v.channel = channel.min(15))
+            }
+            RequestKind::SetVoiceNote(index, note) => {
+                self.update_voice(index, |v| v.note = note.min(127))
+            }
+            RequestKind::SetVoiceVelocity(index, velocity) => {
+                self.update_voice(index, |v| v.velocity = velocity.min(127))
+            }
+            RequestKind::SetVoicePattern(index, steps, pulses, rotation) => {
+                self.update_voice(index, |v| v.set_pattern(steps, pulses, rotation))
+            }
+        }
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_NAME.into(),
+            enabled: true,
+            voices: Vec::new(),
+            sender: None,
+            json_updater: None,
+        }
+    }
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            voices: self.voices.clone(),
+            sender: self.sender.clone(),
+            json_updater: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Control for Node {
+    async fn reset(&mut self) {
+        self.voices.iter_mut().for_each(|voice| voice.position = 0);
+    }
+
+    // Every voice moves one step per rhythm division, voices with different step counts
+    // drift against each other, which is what makes the polyrhythms
+    async fn beat_tick(&mut self, _beat_num: u8, _div_num: u8) {
+        if !self.enabled {
+            return;
+        }
+        let mut hits = Vec::new();
+        for voice in &mut self.voices {
+            if voice.advance() {
+                if let Some(instrument_index) = voice.instrument_index {
+                    hits.push((instrument_index, voice.channel, voice.note, voice.velocity));
+                }
+            }
+        }
+        for (instrument_index, channel, note, velocity) in hits {
+            produce_noise(&self.sender, instrument_index, channel, note, velocity).await;
+        }
+    }
+
+    fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+
+    // The voices step with the beat ticks, whatever the rhythm and the tempo
+    fn set_rhythm(&mut self, _rhythm: Rhythm) {}
+
+    fn set_tempo_bpm(&mut self, _tempo_bpm: f32) {}
+
+    fn receive_midi_message(&mut self, _message: &midi::Message) {}
+
+    fn move_instrument(&mut self, id: usize, new_id: usize) -> Option<JsonUpdateKind> {
+        let mut moved = false;
+        for voice in &mut self.voices {
+            let index = voice
+                .instrument_index
+                .map(|index| moved_node(index, id, new_id));
+            moved |= index != voice.instrument_index;
+            voice.instrument_index = index;
+        }
+        moved.then(|| self.update_voices(|_| true))
+    }
+
+    fn set_control_sender(&mut self, sender: CtrSender) {
+        self.sender = Some(sender);
+    }
+
+    fn set_json_updater(&mut self, updater: JsonUpdater) {
+        self.json_updater = Some(updater);
+    }
+
+    fn process_request(&mut self, kind: NodeRequestKind, cb: ResponseCallback) {
+        type RK = NodeRequestKind;
+        match kind {
+            RK::SetName(name) => cb(self.set_name(&name)),
+            RK::SetEnabled(flag) => cb(self.set_enabled(flag)),
+            RK::Euclidean(kind) => cb(self.process_euclidean_request(kind)),
+            _ => cb(JsonUpdateKind::Denied),
+        }
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        let result: serde_json::Value = json!({
+            "name": serialize(&self.name)?,
+            "enabled": serialize(self.enabled)?,
+            "voices": serialize(&self.voices)?,
+        });
+        Ok(result)
+    }
+
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        deser_field_opt(source, "name", |v| self.name = v)?;
+        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
+        deser_field_opt(source, "voices", |v| self.voices = v)?;
+        // the pattern is derived data, don't trust the stored one
+        for voice in &mut self.voices {
+            voice.set_pattern(voice.steps, voice.pulses, voice.rotation);
+        }
+        Ok(())
+    }
+
+    fn clone_node(&self) -> ControlPtr {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pattern, Voice};
+
+    fn to_string(pattern: &[bool]) -> String {
+        pattern.iter().map(|&p| if p { 'x' } else { '.' }).collect()
+    }
+
+    #[test]
+    fn euclidean_patterns() {
+        assert_eq!(to_string(&pattern(8, 3, 0)), "x..x..x.");
+        assert_eq!(to_string(&pattern(8, 3, 1)), "..x..x.x");
+        assert_eq!(to_string(&pattern(4, 4, 0)), "xxxx");
+        assert_eq!(to_string(&pattern(5, 0, 0)), ".....");
+        assert!(pattern(0, 0, 0).is_empty());
+        assert_eq!(pattern(16, 5, 3).iter().filter(|&&p| p).count(), 5);
+    }
+
+    #[test]
+    fn voice_advance() {
+        let mut voice = Voice::default();
+        voice.set_pattern(3, 1, 0);
+        let hits: Vec<_> = (0..6).map(|_| voice.advance()).collect();
+        assert_eq!(hits, vec![true, false, false, true, false, false]);
+
+        voice.set_pattern(4, 9, 6);
+        assert_eq!((voice.pulses, voice.rotation), (4, 2));
+    }
+}
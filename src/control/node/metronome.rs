@@ -0,0 +1,332 @@
+use super::{
+    field_update, move_instrument_index, produce_noise, Control, ControlPtr,
+    RequestKind as NodeRequestKind,
+};
+use crate::{
+    control::{command::ResponseCallback, CtrSender},
+    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult},
+    json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater},
+    midi,
+    path::VirtualPaths,
+    rhythm::Rhythm,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const DEFAULT_NAME: &str = "Metronome";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestKind {
+    SetInstrument(Option<usize>),
+    SetChannel(u8),
+    // (note, velocity)
+    SetClick(u8, u8),
+    SetAccent(u8, u8),
+    SetAccentEnabled(bool),
+    SetCountOffBars(u8),
+    // Clicks for the count-off bars starting with the next bar, even when disabled
+    StartCountOff,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Click {
+    note: u8,
+    velocity: u8,
+}
+
+pub struct Node {
+    name: String,
+    enabled: bool,
+    instrument_index: Option<usize>,
+    channel: u8,
+    click: Click,
+    accent: Click,
+    accent_enabled: bool,
+    count_off_bars: u8,
+    count_off_pending: bool,
+    count_off_beats_left: usize,
+    rhythm: Rhythm,
+    sender: Option<CtrSender>,
+    json_updater: Option<JsonUpdater>,
+}
+
+impl Node {
+    fn set_name(&mut self, name: &str) -> JsonUpdateKind {
+        self.name = name.into();
+        field_update("name", name)
+    }
+
+    fn set_enabled(&mut self, flag: bool) -> JsonUpdateKind {
+        self.enabled = flag;
+        field_update("enabled", flag)
+    }
+
+    fn process_metronome_request(&mut self, kind: RequestKind) -> JsonUpdateKind {
+        match kind {
+            RequestKind::SetInstrument(instrument_index) => {
+                self.instrument_index = instrument_index;
+                update_fields_or_fail(|updates| {
+                    updates.push(("instrument_index".into(), serialize(instrument_index)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetChannel(channel) => {
+                self.channel = channel.min(15);
+                update_fields_or_fail(|updates| {
+                    updates.push(("channel".into(), serialize(self.channel)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetClick(note, velocity) => {
+                self.click = Click {
+                    note: note.min(127),
+                    velocity: velocity.min(127),
+                };
+                update_fields_or_fail(|updates| {
+                    updates.push(("click".into(), serialize(self.click)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetAccent(note, velocity) => {
+                self.accent = Click {
+                    note: note.min(127),
+                    velocity: velocity.min(127),
+                };
+                update_fields_or_fail(|updates| {
+                    updates.push(("accent".into(), serialize(self.accent)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetAccentEnabled(flag) => {
+                self.accent_enabled = flag;
+                update_fields_or_fail(|updates| {
+                    updates.push(("accent_enabled".into(), serialize(flag)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetCountOffBars(bars) => {
+                self.count_off_bars = bars;
+                update_fields_or_fail(|updates| {
+                    updates.push(("count_off_bars".into(), serialize(bars)?));
+                    Ok(())
+                })
+            }
+            RequestKind::StartCountOff => {
+                if self.count_off_bars == 0 {
+                    return JsonUpdateKind::Failed;
+                }
+                self.count_off_pending = true;
+                JsonUpdateKind::Ok
+            }
+        }
+    }
+
+    // The click to play on the given beat, if any, also advances the count-off
+    fn click_at(&mut self, beat_num: u8, div_num: u8) -> Option<Click> {
+        if div_num != 0 {
+            return None;
+        }
+        if beat_num == 0 && self.count_off_pending {
+            self.count_off_pending = false;
+            self.count_off_beats_left =
+                self.count_off_bars as usize * self.rhythm.num_beats as usize;
+        }
+        let counting_off = self.count_off_beats_left > 0;
+        if counting_off {
+            self.count_off_beats_left -= 1;
+        }
+        if !self.enabled && !counting_off {
+            return None;
+        }
+        if beat_num == 0 && self.accent_enabled {
+            Some(self.accent)
+        } else {
+            Some(self.click)
+        }
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_NAME.into(),
+            enabled: false,
+            instrument_index: None,
+            channel: 9,
+            // GM low and high wood block
+            click: Click {
+                note: 77,
+                velocity: 100,
+            },
+            accent: Click {
+                note: 76,
+                velocity: 127,
+            },
+            accent_enabled: true,
+            count_off_bars: 1,
+            count_off_pending: false,
+            count_off_beats_left: 0,
+            rhythm: Default::default(),
+            sender: None,
+            json_updater: None,
+        }
+    }
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            instrument_index: self.instrument_index,
+            channel: self.channel,
+            click: self.click,
+            accent: self.accent,
+            accent_enabled: self.accent_enabled,
+            count_off_bars: self.count_off_bars,
+            count_off_pending: false,
+            count_off_beats_left: 0,
+            rhythm: self.rhythm,
+            sender: self.sender.clone(),
+            json_updater: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Control for Node {
+    async fn reset(&mut self) {
+        self.count_off_pending = false;
+        self.count_off_beats_left = 0;
+    }
+
+    async fn beat_tick(&mut self, beat_num: u8, div_num: u8) {
+        let Some(click) = self.click_at(beat_num, div_num) else {
+            return;
+        };
+        if let Some(instrument_index) = self.instrument_index {
+            produce_noise(
+                &self.sender,
+                instrument_index,
+                self.channel,
+                click.note,
+                click.velocity,
+            )
+            .await;
+        }
+    }
+
+    fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+
+    fn set_rhythm(&mut self, rhythm: Rhythm) {
+        self.rhythm = rhythm;
+    }
+
+    fn set_tempo_bpm(&mut self, _tempo_bpm: f32) {}
+
+    fn receive_midi_message(&mut self, _message: &midi::Message) {}
+
+    fn move_instrument(&mut self, id: usize, new_id: usize) -> Option<JsonUpdateKind> {
+        move_instrument_index(&mut self.instrument_index, id, new_id)
+    }
+
+    fn set_control_sender(&mut self, sender: CtrSender) {
+        self.sender = Some(sender);
+    }
+
+    fn set_json_updater(&mut self, updater: JsonUpdater) {
+        self.json_updater = Some(updater);
+    }
+
+    fn process_request(&mut self, kind: NodeRequestKind, cb: ResponseCallback) {
+        type RK = NodeRequestKind;
+        match kind {
+            RK::SetName(name) => cb(self.set_name(&name)),
+            RK::SetEnabled(flag) => cb(self.set_enabled(flag)),
+            RK::Metronome(kind) => cb(self.process_metronome_request(kind)),
+            _ => cb(JsonUpdateKind::Denied),
+        }
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        let result: serde_json::Value = json!({
+            "name": serialize(&self.name)?,
+            "enabled": serialize(self.enabled)?,
+            "instrument_index": serialize(self.instrument_index)?,
+            "channel": serialize(self.channel)?,
+            "click": serialize(self.click)?,
+            "accent": serialize(self.accent)?,
+            "accent_enabled": serialize(self.accent_enabled)?,
+            "count_off_bars": serialize(self.count_off_bars)?,
+        });
+        Ok(result)
+    }
+
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        deser_field_opt(source, "name", |v| self.name = v)?;
+        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
+        deser_field_opt(source, "instrument_index", |v| self.instrument_index = v)?;
+        deser_field_opt(source, "channel", |v| self.channel = v)?;
+        deser_field_opt(source, "click", |v| self.click = v)?;
+        deser_field_opt(source, "accent", |v| self.accent = v)?;
+        deser_field_opt(source, "accent_enabled", |v| self.accent_enabled = v)?;
+        deser_field_opt(source, "count_off_bars", |v| self.count_off_bars = v)?;
+        Ok(())
+    }
+
+    fn clone_node(&self) -> ControlPtr {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Node, RequestKind};
+    use crate::rhythm::Rhythm;
+
+    fn notes(node: &mut Node, bars: usize) -> Vec<Option<u8>> {
+        let rhythm = node.rhythm;
+        let mut result = Vec::new();
+        for _ in 0..bars {
+            for beat in 0..rhythm.num_beats {
+                for div in 0..rhythm.num_divs {
+                    if let Some(click) = node.click_at(beat, div) {
+                        result.push(Some(click.note));
+                    } else if div == 0 {
+                        result.push(None);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn accented_clicks() {
+        let mut node = Node {
+            enabled: true,
+            rhythm: Rhythm {
+                num_beats: 3,
+                num_divs: 2,
+            },
+            ..Default::default()
+        };
+        assert_eq!(notes(&mut node, 2), [76, 77, 77, 76, 77, 77].map(Some));
+        node.process_metronome_request(RequestKind::SetAccentEnabled(false));
+        assert_eq!(notes(&mut node, 1), [77, 77, 77].map(Some));
+    }
+
+    #[test]
+    fn count_off() {
+        let mut node = Node::default();
+        node.process_metronome_request(RequestKind::SetCountOffBars(2));
+        node.process_metronome_request(RequestKind::StartCountOff);
+        // the count-off waits for the start of a bar
+        assert_eq!(node.click_at(2, 0), None);
+        assert_eq!(node.click_at(3, 0), None);
+        let clicks = notes(&mut node, 3);
+        assert_eq!(clicks.iter().filter(|c| c.is_some()).count(), 8);
+        assert!(clicks[8..].iter().all(|c| c.is_none()));
+    }
+}
@@ -0,0 +1,670 @@
+use super::{
+    field_update, move_instrument_index, Control, ControlPtr, RequestKind as NodeRequestKind,
+};
+use crate::{
+    control::{command::ResponseCallback, transport::Change, ControlMessage, CtrSender},
+    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult},
+    json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater},
+    midi::{
+        self,
+        smf::{self, Sequence},
+        ControlChangeKind, MessageKind,
+    },
+    path::VirtualPaths,
+    rhythm::Rhythm,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_NAME: &str = "MIDI File Player";
+
+// How often the position is broadcast while playing
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestKind {
+    SetInstrument(Option<usize>),
+    LoadFile(PathBuf),
+    Play,
+    Pause,
+    // Pauses and goes back to the start
+    Stop,
+    // Seconds from the start
+    Seek(f32),
+    SetLooping(bool),
+    // A fixed tempo instead of the tempo map of the file
+    SetTempoOverride(Option<f32>),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum TransportState {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+// The channel voice messages of the file are played, SysEx stays in the file
+pub struct Node {
+    name: String,
+    enabled: bool,
+    instrument_index: Option<usize>,
+    file: Option<PathBuf>,
+    looping: bool,
+    tempo_override: Option<f32>,
+    sequence: Option<Sequence>,
+    state: TransportState,
+    position: f64,
+    next_event: usize,
+    last_tick: Option<Instant>,
+    last_progress: Option<Instant>,
+    // (channel, note) of the notes that are on
+    sounding: BTreeSet<(u8, u8)>,
+    // channels with the damper pedal down, lifted along with the notes
+    pedals: BTreeSet<u8>,
+    virtual_paths: VirtualPaths,
+    sender: Option<CtrSender>,
+    json_updater: Option<JsonUpdater>,
+}
+
+impl Node {
+    fn set_name(&mut self, name: &str) -> JsonUpdateKind {
+        self.name = name.into();
+        field_update("name", name)
+    }
+
+    fn set_enabled(&mut self, flag: bool) -> JsonUpdateKind {
+        self.enabled = flag;
+        field_update("enabled", flag)
+    }
+
+    fn process_player_request(&mut self, kind: RequestKind) -> JsonUpdateKind {
+        match kind {
+            RequestKind::SetInstrument(instrument_index) => {
+                self.instrument_index = instrument_index;
+                update_fields_or_fail(|updates| {
+                    updates.push(("instrument_index".into(), serialize(instrument_index)?));
+                    Ok(())
+                })
+            }
+            RequestKind::LoadFile(path) => self.load_file(&path),
+            RequestKind::Play => {
+                if self.sequence.is_none() {
+                    return JsonUpdateKind::Failed;
+                }
+                self.state = TransportState::Playing;
+                self.last_tick = None;
+                self.transport_update()
+            }
+            RequestKind::Pause => {
+                if self.state == TransportState::Playing {
+                    self.state = TransportState::Paused;
+                }
+                self.transport_update()
+            }
+            RequestKind::Stop => {
+                self.state = TransportState::Stopped;
+                self.move_to(0.0);
+                self.transport_update()
+            }
+            RequestKind::Seek(seconds) => {
+                let Some(sequence) = &self.sequence else {
+                    return JsonUpdateKind::Failed;
+                };
+                let tick = sequence.tick_at(seconds.max(0.0) as f64, self.tempo_override);
+                self.move_to(tick.min(sequence.length as f64));
+                self.transport_update()
+            }
+            RequestKind::SetLooping(flag) => {
+                self.looping = flag;
+                update_fields_or_fail(|updates| {
+                    updates.push(("looping".into(), serialize(flag)?));
+                    Ok(())
+                })
+            }
+            RequestKind::SetTempoOverride(tempo_bpm) => {
+                if tempo_bpm.is_some_and(|t| !t.is_finite() || t <= 0.0) {
+                    return JsonUpdateKind::Failed;
+                }
+                self.tempo_override = tempo_bpm;
+                update_fields_or_fail(|updates| {
+                    updates.push(("tempo_override".into(), serialize(tempo_bpm)?));
+                    updates.push(("position".into(), serialize(self.position_seconds())?));
+                    updates.push(("length".into(), serialize(self.length_seconds())?));
+                    Ok(())
+                })
+            }
+        }
+    }
+
+    fn load_file(&mut self, path: &Path) -> JsonUpdateKind {
+        match self.read_file(path) {
+            Ok(sequence) => {
+                self.sequence = Some(sequence);
+                self.file = Some(path.to_owned());
+                self.state = TransportState::Stopped;
+                self.move_to(0.0);
+                update_fields_or_fail(|updates| {
+                    updates.push(("file".into(), serialize(&self.file)?));
+                    updates.push(("state".into(), serialize(self.state)?));
+                    updates.push(("position".into(), serialize(0.0)?));
+                    updates.push(("length".into(), serialize(self.length_seconds())?));
+                    Ok(())
+                })
+            }
+            Err(e) => {
+                tracing::error!("Failed to load MIDI file: {e}");
+                JsonUpdateKind::Failed
+            }
+        }
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Sequence, Box<dyn std::error::Error>> {
+        let real_path = self
+            .virtual_paths
+            .translate(path)
+            .ok_or("Invalid virtual path")?;
+        Ok(smf::read(&fs::read(real_path)?)?)
+    }
+
+    fn transport_update(&self) -> JsonUpdateKind {
+        update_fields_or_fail(|updates| {
+            updates.push(("state".into(), serialize(self.state)?));
+            updates.push(("position".into(), serialize(self.position_seconds())?));
+            Ok(())
+        })
+    }
+
+    fn position_seconds(&self) -> f32 {
+        self.sequence.as_ref().map_or(0.0, |s| {
+            s.seconds_at(self.position, self.tempo_override) as f32
+        })
+    }
+
+    fn length_seconds(&self) -> f32 {
+        self.sequence.as_ref().map_or(0.0, |s| {
+            s.seconds_at(s.length as f64, self.tempo_override) as f32
+        })
+    }
+
+    // Jumps to a tick, the notes that were on are released with the next tick
+    fn move_to(&mut self, tick: f64) {
+        self.position = tick;
+        self.next_event = self
+            .sequence
+            .as_ref()
+            .map_or(0, |s| s.events.partition_point(|(t, _)| (*t as f64) < tick));
+        self.last_tick = None;
+    }
+
+    // Advances the position to `now` and returns the messages that became due with the time
+    // they're meant to sound
+    fn advance(&mut self, now: Instant) -> Vec<ControlMessage> {
+        let mut messages = self.release_notes(now);
+        if self.state != TransportState::Playing {
+            return messages;
+        }
+        if let Some(sequence) = self.sequence.take() {
+            self.advance_sequence(&sequence, now, &mut messages);
+            self.sequence = Some(sequence);
+        }
+        messages
+    }
+
+    fn advance_sequence(
+        &mut self,
+        sequence: &Sequence,
+        now: Instant,
+        messages: &mut Vec<ControlMessage>,
+    ) {
+        let elapsed = self.last_tick.map_or(Duration::ZERO, |t| now - t);
+        self.last_tick = Some(now);
+        let length = sequence.length as f64;
+        let length_seconds = sequence.seconds_at(length, self.tempo_override);
+        let mut end_seconds =
+            sequence.seconds_at(self.position, self.tempo_override) + elapsed.as_secs_f64();
+        loop {
+            let end = sequence.tick_at(end_seconds, self.tempo_override);
+            while let Some((tick, message)) = sequence.events.get(self.next_event) {
+                if *tick as f64 > end.min(length) {
+                    break;
+                }
+                self.next_event += 1;
+                let seconds = sequence.seconds_at(*tick as f64, self.tempo_override);
+                let late = Duration::from_secs_f64((end_seconds - seconds).max(0.0));
+                let time = now.checked_sub(late).unwrap_or(now);
+                if let Some(message) = self.control_message(message, time) {
+                    messages.push(message);
+                }
+            }
+            if end < length {
+                self.position = end;
+                return;
+            }
+            if !self.looping || length_seconds <= 0.0 {
+                self.state = TransportState::Stopped;
+                self.move_to(0.0);
+                return;
+            }
+            // the rest of the elapsed time plays from the start again
+            end_seconds -= length_seconds;
+            self.position = 0.0;
+            self.next_event = 0;
+        }
+    }
+
+    fn control_message(
+        &mut self,
+        message: &midi::Message,
+        time: Instant,
+    ) -> Option<ControlMessage> {
+        let instrument_id = self.instrument_index.filter(|_| self.enabled)?;
+        let channel = message.channel;
+        if let MessageKind::ControlChange {
+            kind: ControlChangeKind::DamperPedal,
+            value,
+        } = message.kind
+        {
+            if value >= 64 {
+                self.pedals.insert(channel);
+            } else {
+                self.pedals.remove(&channel);
+            }
+        }
+        let (note, note_on, velocity) = match message.kind {
+            MessageKind::NoteOn { note, velocity } if velocity > 0 => {
+                self.sounding.insert((channel, note));
+                (note, true, velocity)
+            }
+            MessageKind::NoteOn { note, .. } => {
+                self.sounding.remove(&(channel, note));
+                (note, false, midi::DEFAULT_RELEASE_VELOCITY)
+            }
+            MessageKind::NoteOff { note, velocity } => {
+                self.sounding.remove(&(channel, note));
+                (note, false, velocity)
+            }
+            MessageKind::SystemExclusive { .. } => return None,
+            ref kind => {
+                return Some(ControlMessage {
+                    instrument_id,
+                    channel,
+                    note: 0,
+                    note_on: false,
+                    velocity: 0,
+                    time: Some(time),
+                    other: Some(kind.clone()),
+                });
+            }
+        };
+        Some(ControlMessage {
+            instrument_id,
+            channel,
+            note,
+            note_on,
+            velocity,
+            time: Some(time),
+            other: None,
+        })
+    }
+
+    // Note offs for the notes that are on while the player doesn't play them anymore
+    fn release_notes(&mut self, now: Instant) -> Vec<ControlMessage> {
+        let stopped = self.state != TransportState::Playing || self.last_tick.is_none();
+        let Some(instrument_id) = self.instrument_index.filter(|_| stopped) else {
+            return Vec::new();
+        };
+        let pedals = std::mem::take(&mut self.pedals)
+            .into_iter()
+            .map(|channel| ControlMessage {
+                instrument_id,
+                channel,
+                note: 0,
+                note_on: false,
+                velocity: 0,
+                time: Some(now),
+                other: Some(MessageKind::ControlChange {
+                    kind: ControlChangeKind::DamperPedal,
+                    value: 0,
+                }),
+            });
+        std::mem::take(&mut self.sounding)
+            .into_iter()
+            .map(|(channel, note)| ControlMessage {
+                instrument_id,
+                channel,
+                note,
+                note_on: false,
+                velocity: midi::DEFAULT_RELEASE_VELOCITY,
+                time: Some(now),
+                other: None,
+            })
+            .chain(pedals)
+            .collect()
+    }
+
+    fn progress_due(&mut self, now: Instant, state_before: TransportState) -> bool {
+        if self.state != state_before {
+            self.last_progress = Some(now);
+            return true;
+        }
+        if self.state != TransportState::Playing {
+            return false;
+        }
+        let due = self
+            .last_progress
+            .is_none_or(|t| now - t >= PROGRESS_INTERVAL);
+        if due {
+            self.last_progress = Some(now);
+        }
+        due
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_NAME.into(),
+            enabled: true,
+            instrument_index: None,
+            file: None,
+            looping: false,
+            tempo_override: None,
+            sequence: None,
+            state: TransportState::Stopped,
+            position: 0.0,
+            next_event: 0,
+            last_tick: None,
+            last_progress: None,
+            sounding: BTreeSet::new(),
+            pedals: BTreeSet::new(),
+            virtual_paths: Default::default(),
+            sender: None,
+            json_updater: None,
+        }
+    }
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            instrument_index: self.instrument_index,
+            file: self.file.clone(),
+            looping: self.looping,
+            tempo_override: self.tempo_override,
+            sequence: self.sequence.clone(),
+            virtual_paths: self.virtual_paths.clone(),
+            sender: self.sender.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl Control for Node {
+    async fn reset(&mut self) {
+        self.state = TransportState::Stopped;
+        self.move_to(0.0);
+    }
+
+    async fn beat_tick(&mut self, _beat_num: u8, _div_num: u8) {}
+
+    async fn tick(&mut self, now: Instant) {
+        let state_before = self.state;
+        let messages = self.advance(now);
+        if let Some(sender) = &self.sender {
+            for message in messages {
+                _ = sender.send(message).await;
+            }
+        }
+        if self.progress_due(now, state_before) {
+            if let Some(updater) = &self.json_updater {
+                updater.broadcast(self.transport_update()).await;
+            }
+        }
+    }
+
+    // Playing from the top plays the file from its start, stopping pauses it
+    fn follow_transport(&mut self, change: Change) {
+        match change {
+            Change::Started if self.sequence.is_some() => {
+                self.state = TransportState::Playing;
+                self.move_to(0.0);
+            }
+            Change::Stopped if self.state == TransportState::Playing => {
+                self.state = TransportState::Paused;
+            }
+            Change::Continued if self.state == TransportState::Paused => {
+                self.state = TransportState::Playing;
+                self.last_tick = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_virtual_paths(&mut self, vp: VirtualPaths) {
+        self.virtual_paths = vp;
+    }
+
+    fn set_rhythm(&mut self, _rhythm: Rhythm) {}
+
+    fn set_tempo_bpm(&mut self, _tempo_bpm: f32) {}
+
+    fn receive_midi_message(&mut self, _message: &midi::Message) {}
+
+    // The notes that are on follow it, their note offs go where the instrument went
+    fn move_instrument(&mut self, id: usize, new_id: usize) -> Option<JsonUpdateKind> {
+        move_instrument_index(&mut self.instrument_index, id, new_id)
+    }
+
+    fn set_control_sender(&mut self, sender: CtrSender) {
+        self.sender = Some(sender);
+    }
+
+    fn set_json_updater(&mut self, updater: JsonUpdater) {
+        self.json_updater = Some(updater);
+    }
+
+    fn process_request(&mut self, kind: NodeRequestKind, cb: ResponseCallback) {
+        type RK = NodeRequestKind;
+        match kind {
+            RK::SetName(name) => cb(self.set_name(&name)),
+            RK::SetEnabled(flag) => cb(self.set_enabled(flag)),
+            RK::MidiFilePlayer(kind) => cb(self.process_player_request(kind)),
+            _ => cb(JsonUpdateKind::Denied),
+        }
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        let result: serde_json::Value = json!({
+            "name": serialize(&self.name)?,
+            "enabled": serialize(self.enabled)?,
+            "instrument_index": serialize(self.instrument_index)?,
+            "file": serialize(&self.file)?,
+            "looping": serialize(self.looping)?,
+            "tempo_override": serialize(self.tempo_override)?,
+            "state": serialize(self.state)?,
+            "position": serialize(self.position_seconds())?,
+            "length": serialize(self.length_seconds())?,
+        });
+        Ok(result)
+    }
+
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        deser_field_opt(source, "name", |v| self.name = v)?;
+        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
+        deser_field_opt(source, "instrument_index", |v| self.instrument_index = v)?;
+        deser_field_opt(source, "looping", |v| self.looping = v)?;
+        deser_field_opt(source, "tempo_override", |v| self.tempo_override = v)?;
+        let mut file: Option<PathBuf> = None;
+        deser_field_opt(source, "file", |v| file = v)?;
+        if let Some(path) = file {
+            self.load_file(&path);
+        }
+        Ok(())
+    }
+
+    fn clone_node(&self) -> ControlPtr {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Node, RequestKind, TransportState};
+    use crate::{
+        control::{node::Control, transport::Change},
+        midi::{smf::Sequence, ControlChangeKind, Message, MessageKind},
+    };
+    use std::time::{Duration, Instant};
+
+    // Two quarter notes at 60 bpm, a second each
+    fn node() -> Node {
+        let note = |tick, note_on| {
+            let kind = if note_on {
+                MessageKind::NoteOn {
+                    note: 60,
+                    velocity: 100,
+                }
+            } else {
+                MessageKind::NoteOff {
+                    note: 60,
+                    velocity: 0,
+                }
+            };
+            (tick, Message::new(0, kind))
+        };
+        Node {
+            instrument_index: Some(1),
+            sequence: Some(Sequence {
+                ticks_per_quarter: 480,
+                events: vec![note(0, true), note(480, false), note(480, true)],
+                tempos: vec![(0, 1_000_000)],
+                length: 960,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn notes(node: &mut Node, now: Instant) -> Vec<bool> {
+        node.advance(now).iter().map(|m| m.note_on).collect()
+    }
+
+    #[test]
+    fn transport() {
+        let mut node = node();
+        let start = Instant::now();
+        let secs = |s: f32| start + Duration::from_secs_f32(s);
+        assert!(notes(&mut node, start).is_empty());
+
+        node.process_player_request(RequestKind::Play);
+        assert_eq!(notes(&mut node, start), [true]);
+        assert!(notes(&mut node, secs(0.5)).is_empty());
+        assert_eq!(notes(&mut node, secs(1.25)), [false, true]);
+
+        // the note that's on gets released
+        node.process_player_request(RequestKind::Pause);
+        assert_eq!(notes(&mut node, secs(1.5)), [false]);
+        assert_eq!(node.position_seconds(), 1.25);
+
+        node.process_player_request(RequestKind::Seek(0.5));
+        node.process_player_request(RequestKind::SetTempoOverride(Some(120.0)));
+        assert_eq!(node.position_seconds(), 0.25);
+        node.process_player_request(RequestKind::Play);
+        assert!(notes(&mut node, secs(2.0)).is_empty());
+        // at double speed the rest of the file takes 0.75 s
+        assert_eq!(notes(&mut node, secs(3.0)), [false, true]);
+        assert_eq!(node.state, TransportState::Stopped);
+        assert_eq!(notes(&mut node, secs(3.5)), [false]);
+    }
+
+    #[test]
+    fn controllers_are_played() {
+        let pedal = |value| MessageKind::ControlChange {
+            kind: ControlChangeKind::DamperPedal,
+            value,
+        };
+        let kinds = [
+            MessageKind::ProgramChange { program: 5 },
+            pedal(127),
+            MessageKind::PitchWheel { value: 0x3000 },
+            MessageKind::SystemExclusive { data: vec![0x7E] },
+        ];
+        let mut node = Node {
+            instrument_index: Some(1),
+            sequence: Some(Sequence {
+                ticks_per_quarter: 480,
+                events: kinds
+                    .iter()
+                    .map(|k| (0, Message::new(2, k.clone())))
+                    .collect(),
+                tempos: vec![(0, 1_000_000)],
+                length: 480,
+            }),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        node.process_player_request(RequestKind::Play);
+        let played: Vec<_> = node.advance(start).into_iter().map(|m| m.other).collect();
+        assert_eq!(
+            played,
+            [
+                Some(kinds[0].clone()),
+                Some(pedal(127)),
+                Some(kinds[2].clone())
+            ]
+        );
+
+        // the pedal is lifted with the notes
+        node.process_player_request(RequestKind::Pause);
+        let released: Vec<_> = node.advance(start).into_iter().map(|m| m.other).collect();
+        assert_eq!(released, [Some(pedal(0))]);
+    }
+
+    #[test]
+    fn looping() {
+        let mut node = node();
+        let start = Instant::now();
+        node.process_player_request(RequestKind::SetLooping(true));
+        node.process_player_request(RequestKind::Play);
+        assert_eq!(notes(&mut node, start), [true]);
+        let messages = node.advance(start + Duration::from_millis(2500));
+        let note_ons: Vec<_> = messages.iter().map(|m| m.note_on).collect();
+        assert_eq!(note_ons, [false, true, true]);
+        // the note of the second round is 0.5 s late
+        let late = start + Duration::from_millis(2500) - messages[2].time.unwrap();
+        assert!((late.as_secs_f32() - 0.5).abs() < 1e-3);
+        assert_eq!(node.state, TransportState::Playing);
+        assert!((node.position_seconds() - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn follows_the_transport() {
+        let mut node = node();
+        let start = Instant::now();
+        let secs = |s: f32| start + Duration::from_secs_f32(s);
+        node.follow_transport(Change::Started);
+        assert_eq!(notes(&mut node, start), [true]);
+        node.follow_transport(Change::Stopped);
+        assert_eq!(notes(&mut node, secs(0.5)), [false]);
+        assert_eq!(node.state, TransportState::Paused);
+        node.follow_transport(Change::Continued);
+        assert!(notes(&mut node, secs(5.0)).is_empty());
+        assert_eq!(notes(&mut node, secs(6.0)), [false, true]);
+
+        // a player stopped on its own stays so
+        node.process_player_request(RequestKind::Stop);
+        node.follow_transport(Change::Continued);
+        assert_eq!(notes(&mut node, secs(7.0)), [false]);
+        assert_eq!(node.state, TransportState::Stopped);
+    }
+}
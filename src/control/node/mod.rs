@@ -1,14 +1,22 @@
-use super::{command::ResponseCallback, drum_machine, CtrSender};
+use super::{
+    command::ResponseCallback, drum_machine, transport, ConsumedInputs, ControlMessage, CtrSender,
+};
 use crate::{
-    deser::{DeserializationResult, SerializationResult},
-    json::JsonUpdater,
+    deser::{serialize, DeserializationResult, SerializationResult},
+    json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater},
     midi,
     path::VirtualPaths,
+    render::moved_node,
     rhythm::Rhythm,
 };
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Instant};
+
+pub mod chord;
+pub mod euclidean;
+pub mod metronome;
+pub mod midi_file_player;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum RequestKind {
@@ -19,16 +27,30 @@ pub enum RequestKind {
     SetUserPreset(usize),
     SetUserPresetEnabled(usize, bool),
     DrumMachine(drum_machine::RequestKind),
+    Euclidean(euclidean::RequestKind),
+    Chord(chord::RequestKind),
+    Metronome(metronome::RequestKind),
+    MidiFilePlayer(midi_file_player::RequestKind),
 }
 
 #[async_trait]
 pub trait Control: Sync + Send {
     async fn reset(&mut self);
     async fn beat_tick(&mut self, beat_num: u8, div_num: u8);
+    // Called on every tick of the controller, for nodes that keep their own time
+    async fn tick(&mut self, _now: Instant) {}
+    // Play, stop and continue of the transport, the beat ticks only come while it plays
+    fn follow_transport(&mut self, _change: transport::Change) {}
     fn set_virtual_paths(&mut self, vp: VirtualPaths);
     fn set_rhythm(&mut self, rhythm: Rhythm);
     fn set_tempo_bpm(&mut self, tempo_bpm: f32);
     fn receive_midi_message(&mut self, message: &midi::Message);
+    // The input it plays with, the renderer's nodes don't get it
+    fn consume_inputs(&self, _inputs: &mut ConsumedInputs) {}
+    // The renderer moved its node at `id` to `new_id`, the update if the node plays it by index
+    fn move_instrument(&mut self, _id: usize, _new_id: usize) -> Option<JsonUpdateKind> {
+        None
+    }
     fn set_control_sender(&mut self, sender: CtrSender);
     fn set_json_updater(&mut self, updater: JsonUpdater);
     fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback);
@@ -38,3 +60,54 @@ pub trait Control: Sync + Send {
 }
 
 pub type ControlPtr = Box<dyn Control>;
+
+// Update of a single field of a node, like its name or whether it's enabled
+pub fn field_update<T: Serialize>(field: &str, value: T) -> JsonUpdateKind {
+    update_fields_or_fail(|updates| {
+        updates.push((field.to_owned(), serialize(value)?));
+        Ok(())
+    })
+}
+
+// The instrument index of a node after the renderer moved its node at `id` to `new_id`, with
+// the update of the field if it changed
+pub fn move_instrument_index(
+    index: &mut Option<usize>,
+    id: usize,
+    new_id: usize,
+) -> Option<JsonUpdateKind> {
+    let moved = index.map(|index| moved_node(index, id, new_id));
+    (moved != *index).then(|| {
+        *index = moved;
+        field_update("instrument_index", moved)
+    })
+}
+
+// A hit with its note off right away, the instrument lets it ring out
+pub async fn produce_noise(
+    sender: &Option<CtrSender>,
+    instrument_id: usize,
+    channel: u8,
+    note: u8,
+    velocity: u8,
+) {
+    let Some(sender) = sender else {
+        return;
+    };
+    let note_on = ControlMessage {
+        instrument_id,
+        channel,
+        note,
+        note_on: true,
+        velocity,
+        time: None,
+        other: None,
+    };
+    let note_off = ControlMessage {
+        note_on: false,
+        velocity: midi::DEFAULT_RELEASE_VELOCITY,
+        ..note_on.clone()
+    };
+    _ = sender.send(note_on).await;
+    _ = sender.send(note_off).await;
+}
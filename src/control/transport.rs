@@ -0,0 +1,270 @@
+// Whether the music plays and where it is, shared by the controller's nodes and the drum machine
+// so they start, stop and continue together instead of each running on a clock of its own. Play
+// starts from the top, stop keeps the position and continue goes on from there.
+
+use super::Tempo;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Action {
+    Play,
+    Stop,
+    Continue,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum State {
+    #[default]
+    Stopped,
+    Playing,
+}
+
+// Counted from 0, like the beats of the rhythm
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub bar: u32,
+    pub beat: u8,
+}
+
+// What the clients see of it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    pub state: State,
+    pub position: Position,
+}
+
+// What those following it have to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Started,
+    Stopped,
+    Continued,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transport {
+    state: State,
+    position: Position,
+    tempo: Tempo,
+    // The beats played up to `since`, when it last started, continued or changed tempo
+    beats: f64,
+    since: Instant,
+    // Divisions of the beat from the top
+    next_step: u64,
+    // Counts the plays from the top, so followers tell them from continuing
+    run: u32,
+}
+
+impl Transport {
+    // Playing from the top, the way everything starts up
+    pub fn new(tempo: Tempo, now: Instant) -> Self {
+        Self {
+            state: State::Playing,
+            position: Default::default(),
+            tempo,
+            beats: 0.0,
+            since: now,
+            next_step: 0,
+            run: 0,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.state == State::Playing
+    }
+
+    pub fn tempo(&self) -> Tempo {
+        self.tempo
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            state: self.state,
+            position: self.position,
+        }
+    }
+
+    pub fn apply(&mut self, action: Action, now: Instant) -> Option<Change> {
+        match action {
+            Action::Play => {
+                self.state = State::Playing;
+                self.position = Default::default();
+                self.beats = 0.0;
+                self.since = now;
+                self.next_step = 0;
+                self.run = self.run.wrapping_add(1);
+                Some(Change::Started)
+            }
+            Action::Stop if self.is_playing() => {
+                self.beats = self.beats_at(now);
+                self.state = State::Stopped;
+                Some(Change::Stopped)
+            }
+            Action::Continue if !self.is_playing() => {
+                self.since = now;
+                self.state = State::Playing;
+                Some(Change::Continued)
+            }
+            _ => None,
+        }
+    }
+
+    // The beats played so far stay, with a new rhythm the steps go on from the next division
+    pub fn set_tempo(&mut self, tempo: Tempo, now: Instant) {
+        self.beats = self.beats_at(now);
+        self.since = now;
+        if tempo.rhythm != self.tempo.rhythm {
+            self.next_step = (self.beats * tempo.rhythm.num_divs as f64).floor() as u64 + 1;
+        }
+        self.tempo = tempo;
+    }
+
+    fn beats_at(&self, now: Instant) -> f64 {
+        match self.state {
+            State::Playing => {
+                let elapsed = now.saturating_duration_since(self.since).as_secs_f64();
+                self.beats + elapsed * self.tempo.tempo_bpm as f64 / 60.0
+            }
+            State::Stopped => self.beats,
+        }
+    }
+
+    // The beat and division of the next step once it's due, one at a time, moving the position
+    // along with them
+    pub fn step(&mut self, now: Instant) -> Option<(u8, u8)> {
+        let num_divs = self.tempo.rhythm.num_divs as u64;
+        let num_beats = self.tempo.rhythm.num_beats as u64;
+        if !self.is_playing() || self.beats_at(now) * (num_divs as f64) < self.next_step as f64 {
+            return None;
+        }
+        let step = self.next_step;
+        self.next_step += 1;
+        let beats = step / num_divs;
+        let beat = (beats % num_beats) as u8;
+        self.position = Position {
+            bar: (beats / num_beats) as u32,
+            beat,
+        };
+        Some((beat, (step % num_divs) as u8))
+    }
+
+    // When the next step is due while it plays, for placing the notes of the step on time
+    pub fn next_step_at(&self) -> Option<Instant> {
+        if !self.is_playing() {
+            return None;
+        }
+        let beats = self.next_step as f64 / self.tempo.rhythm.num_divs as f64 - self.beats;
+        let secs = (beats * 60.0 / self.tempo.tempo_bpm as f64).max(0.0);
+        Some(self.since + Duration::try_from_secs_f64(secs).ok()?)
+    }
+
+    // A copy stepped on its own takes the state and tempo of the one it follows, without taking
+    // the steps it already took again
+    pub fn follow(&mut self, leader: &Transport) {
+        let next_step = if leader.run == self.run && leader.tempo.rhythm == self.tempo.rhythm {
+            self.next_step.max(leader.next_step)
+        } else {
+            leader.next_step
+        };
+        *self = Transport {
+            next_step,
+            ..*leader
+        };
+    }
+
+    // Between two states it was in, none if it went on as it was
+    pub fn change_from(&self, previous: &Transport) -> Option<Change> {
+        if self.run != previous.run && self.is_playing() {
+            Some(Change::Started)
+        } else if self.state != previous.state || self.run != previous.run {
+            match self.state {
+                State::Playing => Some(Change::Continued),
+                State::Stopped => Some(Change::Stopped),
+            }
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Change, Position, State, Transport};
+    use crate::{control::Tempo, rhythm::Rhythm};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn plays_stops_and_continues() {
+        let start = Instant::now();
+        let secs = |s: f32| start + Duration::from_secs_f32(s);
+        // a beat a second, two divisions and two beats to the bar
+        let tempo = Tempo {
+            tempo_bpm: 60.0,
+            rhythm: Rhythm {
+                num_beats: 2,
+                num_divs: 2,
+            },
+        };
+        let mut transport = Transport::new(tempo, start);
+        let steps = |transport: &mut Transport, now| {
+            std::iter::from_fn(|| transport.step(now)).collect::<Vec<_>>()
+        };
+        assert_eq!(steps(&mut transport, secs(0.0)), [(0, 0)]);
+        let steps_on = [(0, 1), (1, 0), (1, 1), (0, 0), (0, 1)];
+        assert_eq!(steps(&mut transport, secs(2.6)), steps_on);
+        let position = Position { bar: 1, beat: 0 };
+        assert_eq!(transport.status().position, position);
+
+        let before = transport;
+        let change = transport.apply(Action::Stop, secs(2.75));
+        assert_eq!(change, Some(Change::Stopped));
+        assert_eq!(transport.change_from(&before), change);
+        assert!(steps(&mut transport, secs(10.0)).is_empty());
+        assert_eq!(transport.apply(Action::Stop, secs(10.0)), None);
+
+        // the quarter second left of the division
+        let before = transport;
+        let change = transport.apply(Action::Continue, secs(10.0));
+        assert_eq!(change, Some(Change::Continued));
+        assert_eq!(transport.change_from(&before), change);
+        assert!(steps(&mut transport, secs(10.2)).is_empty());
+        assert_eq!(steps(&mut transport, secs(10.25)), [(1, 0)]);
+
+        let before = transport;
+        transport.apply(Action::Play, secs(11.0));
+        assert_eq!(transport.change_from(&before), Some(Change::Started));
+        assert_eq!(transport.status().state, State::Playing);
+        assert_eq!(transport.status().position, Position::default());
+        assert_eq!(steps(&mut transport, secs(11.0)), [(0, 0)]);
+    }
+
+    #[test]
+    fn followers_keep_their_steps() {
+        let start = Instant::now();
+        let secs = |s: f32| start + Duration::from_secs_f32(s);
+        let mut tempo = Tempo {
+            tempo_bpm: 60.0,
+            rhythm: Default::default(),
+        };
+        let mut leader = Transport::new(tempo, start);
+        let mut follower = leader;
+        let num_divs = tempo.rhythm.num_divs as f32;
+        assert_eq!(follower.next_step_at(), Some(start));
+        follower.step(start);
+        assert_eq!(follower.next_step_at(), Some(secs(1.0 / num_divs)));
+
+        // the leader behind doesn't bring the step back, a new tempo is taken
+        tempo.tempo_bpm = 120.0;
+        leader.set_tempo(tempo, start);
+        follower.follow(&leader);
+        assert_eq!(follower.tempo(), tempo);
+        assert_eq!(follower.next_step_at(), Some(secs(0.5 / num_divs)));
+        assert!(follower.step(start).is_none());
+
+        // playing from the top again starts the steps over
+        leader.apply(Action::Play, secs(1.0));
+        follower.follow(&leader);
+        assert_eq!(follower.step(secs(1.0)), Some((0, 0)));
+    }
+}
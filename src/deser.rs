@@ -1,4 +1,5 @@
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Debug, PartialEq)]
 pub struct SerializationError;
@@ -9,6 +10,14 @@ pub struct DeserializationError;
 pub type SerializationResult = Result<serde_json::Value, SerializationError>;
 pub type DeserializationResult = Result<(), DeserializationError>;
 
+// A node of the renderer or the controller like the clients see it, `instance` is what the node
+// serializes to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeState {
+    pub kind: String,
+    pub instance: serde_json::Value,
+}
+
 pub fn serialize<T: Serialize>(value: T) -> SerializationResult {
     serde_json::to_value(value).map_err(|_| SerializationError)
 }
@@ -47,3 +56,77 @@ pub fn deser_field_opt<T: DeserializeOwned>(
     }
     Ok(())
 }
+
+// Describes why a preset could not be loaded, reported back to the client as is
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PresetError {
+    InvalidPath,
+    Io(String),
+    InvalidJson(String),
+    Migration(String),
+    MissingField(String),
+    WrongType { field: String, reason: String },
+    OutOfRange { field: String, reason: String },
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PresetError::InvalidPath => "Preset path is not valid.".fmt(f),
+            PresetError::Io(reason) => write!(f, "Failed to read preset: {reason}"),
+            PresetError::InvalidJson(reason) => write!(f, "Preset is not valid JSON: {reason}"),
+            PresetError::Migration(reason) => reason.fmt(f),
+            PresetError::MissingField(field) => write!(f, "Preset field `{field}` is missing."),
+            PresetError::WrongType { field, reason } => {
+                write!(f, "Preset field `{field}` has a wrong type: {reason}")
+            }
+            PresetError::OutOfRange { field, reason } => {
+                write!(f, "Preset field `{field}` is out of range: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl PresetError {
+    pub fn out_of_range(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::OutOfRange {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+pub fn deser_value<T: DeserializeOwned>(
+    source: &serde_json::Value,
+    field_name: &str,
+) -> Result<T, PresetError> {
+    let val = source
+        .get(field_name)
+        .ok_or_else(|| PresetError::MissingField(field_name.to_owned()))?;
+    serde_json::from_value(val.clone()).map_err(|e| PresetError::WrongType {
+        field: field_name.to_owned(),
+        reason: e.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deser_value, PresetError};
+    use serde_json::json;
+
+    #[test]
+    fn deser_value_errors() {
+        let source = json!({ "tempo_bpm": "fast", "num": 3 });
+        assert_eq!(deser_value::<u8>(&source, "num"), Ok(3));
+        assert_eq!(
+            deser_value::<u8>(&source, "voices"),
+            Err(PresetError::MissingField("voices".into()))
+        );
+        assert!(matches!(
+            deser_value::<f32>(&source, "tempo_bpm"),
+            Err(PresetError::WrongType { field, .. }) if field == "tempo_bpm"
+        ));
+    }
+}
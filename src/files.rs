@@ -0,0 +1,534 @@
+use crate::path::VirtualPaths;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+// Soundfonts can be big, but an embedded install has little space
+pub const MAX_UPLOAD_SIZE: u64 = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FileError {
+    InvalidPath,
+    NotFound,
+    Exists,
+    TooLarge,
+    // The file is there but not what it should be
+    InvalidFile,
+    Io(String),
+}
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Self::NotFound,
+            io::ErrorKind::AlreadyExists => Self::Exists,
+            _ => Self::Io(e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FileKind {
+    Directory,
+    Soundfont,
+    Sfz,
+    Sample,
+    Preset,
+    // Scala scales and keyboard mappings
+    Tuning,
+    Other,
+}
+
+impl FileKind {
+    fn of(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("sf2" | "sf3") => Self::Soundfont,
+            Some("sfz") => Self::Sfz,
+            Some("wav" | "flac" | "ogg" | "aif" | "aiff" | "mp3") => Self::Sample,
+            Some("json") => Self::Preset,
+            Some("scl" | "kbm") => Self::Tuning,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileInfo {
+    // relative to the listed directory
+    pub path: PathBuf,
+    pub kind: FileKind,
+    // in bytes, 0 for directories
+    pub size: u64,
+    // seconds since the Unix epoch
+    pub modified: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiskUsage {
+    // virtual root
+    pub root: PathBuf,
+    // total size of the files under the root, `None` if it couldn't be read
+    pub used: Option<u64>,
+    // space of the file system the root is on
+    pub free: Option<u64>,
+    pub total: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SoundfontPreset {
+    pub bank: u16,
+    pub preset: u8,
+    pub name: String,
+}
+
+// The presets of a soundfont ordered by bank and preset number, for picking one by name
+pub async fn soundfont_presets(
+    virtual_paths: &VirtualPaths,
+    path: &Path,
+) -> Result<Vec<SoundfontPreset>, FileError> {
+    let path = translate(virtual_paths, path)?;
+    // big soundfonts take a while to parse
+    let parse = move || -> Result<_, FileError> {
+        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let font = rustysynth::SoundFont::new(&mut file).map_err(|_| FileError::InvalidFile)?;
+        let mut presets: Vec<_> = font
+            .get_presets()
+            .iter()
+            .map(|preset| SoundfontPreset {
+                bank: preset.get_bank_number() as u16,
+                preset: preset.get_patch_number() as u8,
+                name: preset.get_name().to_owned(),
+            })
+            .collect();
+        presets.sort_by_key(|preset| (preset.bank, preset.preset));
+        Ok(presets)
+    };
+    tokio::task::spawn_blocking(parse)
+        .await
+        .map_err(|e| FileError::Io(e.to_string()))?
+}
+
+// When files were last modified, to notice them changing
+#[derive(Debug, Default)]
+pub struct ModifiedTimes {
+    times: HashMap<PathBuf, SystemTime>,
+}
+
+impl ModifiedTimes {
+    // The real paths modified since the last update, files seen for the first time count as
+    // unchanged and the ones not given any more are forgotten
+    pub async fn update(&mut self, paths: &[PathBuf]) -> Vec<PathBuf> {
+        let mut times = HashMap::with_capacity(paths.len());
+        let mut changed = Vec::new();
+        for path in paths {
+            // a file being replaced may be gone for a moment
+            let Ok(modified) = fs::metadata(path).await.and_then(|m| m.modified()) else {
+                if let Some(&last) = self.times.get(path) {
+                    times.insert(path.clone(), last);
+                }
+                continue;
+            };
+            if self.times.get(path).is_some_and(|&last| last != modified) {
+                changed.push(path.clone());
+            }
+            times.insert(path.clone(), modified);
+        }
+        self.times = times;
+        changed
+    }
+}
+
+// Paths from the clients stay under their root, with `..` they could reach anything AMI can
+pub(crate) fn translate(virtual_paths: &VirtualPaths, path: &Path) -> Result<PathBuf, FileError> {
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(FileError::InvalidPath);
+    }
+    let real_path = virtual_paths
+        .translate(path)
+        .ok_or(FileError::InvalidPath)?;
+    let within_root = virtual_paths
+        .roots()
+        .filter(|root| path.starts_with(root))
+        .filter_map(|root| virtual_paths.translate(root))
+        .any(|base| crate::path::is_path_within_base(&real_path, &base));
+    if within_root {
+        Ok(real_path)
+    } else {
+        Err(FileError::InvalidPath)
+    }
+}
+
+// Copies a file or a whole directory tree, nothing gets overwritten
+pub async fn copy(virtual_paths: &VirtualPaths, from: &Path, to: &Path) -> Result<(), FileError> {
+    let from = translate(virtual_paths, from)?;
+    let to = translate(virtual_paths, to)?;
+    if to.starts_with(&from) {
+        // a directory can't be copied into itself
+        return Err(FileError::InvalidPath);
+    }
+    if fs::try_exists(&to).await? {
+        return Err(FileError::Exists);
+    }
+    let mut pending = vec![(from, to)];
+    while let Some((from, to)) = pending.pop() {
+        if fs::metadata(&from).await?.is_dir() {
+            fs::create_dir(&to).await?;
+            let mut dir = fs::read_dir(&from).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                pending.push((entry.path(), to.join(entry.file_name())));
+            }
+        } else {
+            fs::copy(&from, &to).await?;
+        }
+    }
+    Ok(())
+}
+
+// Writes a file at a real path, which must not exist yet
+pub async fn write_new(path: &Path, bytes: &[u8]) -> Result<(), FileError> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await?;
+    file.write_all(bytes).await?;
+    Ok(())
+}
+
+// Entries of a directory, with `recursive` also the ones of all its subdirectories, symbolic
+// links to directories are listed but not followed
+pub async fn read_dir(
+    virtual_paths: &VirtualPaths,
+    path: &Path,
+    recursive: bool,
+) -> Result<Vec<FileInfo>, FileError> {
+    let base = translate(virtual_paths, path)?;
+    let mut entries = Vec::new();
+    let mut pending = vec![base.clone()];
+    while let Some(dir_path) = pending.pop() {
+        let mut dir = fs::read_dir(&dir_path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            let path = entry.path();
+            let kind = if metadata.is_dir() {
+                FileKind::Directory
+            } else {
+                FileKind::of(&path)
+            };
+            if recursive && metadata.is_dir() {
+                pending.push(path.clone());
+            }
+            entries.push(FileInfo {
+                path: crate::path::remove_prefix(&path, &base),
+                kind,
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|t| t.as_secs()),
+            });
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+pub async fn disk_usage(virtual_paths: &VirtualPaths) -> Vec<DiskUsage> {
+    let mut roots: Vec<_> = virtual_paths.roots().map(Path::to_owned).collect();
+    roots.sort();
+    let mut usage = Vec::with_capacity(roots.len());
+    for root in roots {
+        let used = read_dir(virtual_paths, &root, true)
+            .await
+            .ok()
+            .map(|entries| entries.iter().map(|entry| entry.size).sum());
+        let space = virtual_paths
+            .translate(&root)
+            .and_then(|path| file_system_space(&path));
+        usage.push(DiskUsage {
+            root,
+            used,
+            free: space.map(|(free, _)| free),
+            total: space.map(|(_, total)| total),
+        });
+    }
+    usage
+}
+
+// (free, total) bytes of the file system, free counts only what's available to unprivileged
+// users
+#[cfg(unix)]
+fn file_system_space(path: &Path) -> Option<(u64, u64)> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs is plain old data, all zeros is a valid value
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: the path is NUL terminated and `stat` is valid for writes
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let fragment_size = stat.f_frsize as u64;
+    Some((
+        stat.f_bavail as u64 * fragment_size,
+        stat.f_blocks as u64 * fragment_size,
+    ))
+}
+
+#[cfg(not(unix))]
+fn file_system_space(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+// A file being received, the data goes to a `.part` file next to the target, which gets its
+// name only once the upload is complete, so an interrupted upload never leaves a broken file
+pub struct Upload {
+    path: PathBuf,
+    part_path: PathBuf,
+    file: fs::File,
+    received: u64,
+}
+
+impl Upload {
+    // `size` is the announced size of the file, if known
+    pub async fn create(
+        virtual_paths: &VirtualPaths,
+        path: &Path,
+        size: Option<u64>,
+    ) -> Result<Self, FileError> {
+        if size.is_some_and(|size| size > MAX_UPLOAD_SIZE) {
+            return Err(FileError::TooLarge);
+        }
+        let path = translate(virtual_paths, path)?;
+        let Some(file_name) = path.file_name() else {
+            return Err(FileError::InvalidPath);
+        };
+        if fs::try_exists(&path).await? {
+            return Err(FileError::Exists);
+        }
+        let mut part_name = file_name.to_owned();
+        part_name.push(".part");
+        let part_path = path.with_file_name(part_name);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part_path)
+            .await?;
+        Ok(Self {
+            path,
+            part_path,
+            file,
+            received: 0,
+        })
+    }
+
+    // Returns the number of bytes received so far
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<u64, FileError> {
+        self.received += chunk.len() as u64;
+        if self.received > MAX_UPLOAD_SIZE {
+            return Err(FileError::TooLarge);
+        }
+        self.file.write_all(chunk).await?;
+        Ok(self.received)
+    }
+
+    pub async fn finish(mut self) -> Result<(), FileError> {
+        self.file.flush().await?;
+        if fs::try_exists(&self.path).await? {
+            self.abort().await;
+            return Err(FileError::Exists);
+        }
+        fs::rename(&self.part_path, &self.path).await?;
+        Ok(())
+    }
+
+    pub async fn abort(self) {
+        drop(self.file);
+        _ = fs::remove_file(&self.part_path).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        copy, disk_usage, read_dir, soundfont_presets, FileError, FileKind, ModifiedTimes, Upload,
+        MAX_UPLOAD_SIZE,
+    };
+    use crate::{path::VirtualPaths, testing::TempDir};
+    use std::{
+        fs,
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    #[tokio::test]
+    async fn copy_tree() {
+        let root = TempDir::new("copy");
+        fs::create_dir_all(root.join("kits/rock")).unwrap();
+        fs::write(root.join("kits/rock/kick.wav"), "kick").unwrap();
+        let mut vp = VirtualPaths::default();
+        vp.insert("beats:".into(), root.to_owned());
+
+        let copied = copy(&vp, Path::new("beats:/kits"), Path::new("beats:/copy")).await;
+        assert_eq!(copied, Ok(()));
+        let kick = fs::read_to_string(root.join("copy/rock/kick.wav")).unwrap();
+        assert_eq!(kick, "kick");
+
+        let again = copy(&vp, Path::new("beats:/kits"), Path::new("beats:/copy")).await;
+        assert_eq!(again, Err(FileError::Exists));
+        let missing = copy(&vp, Path::new("beats:/none"), Path::new("beats:/x")).await;
+        assert_eq!(missing, Err(FileError::NotFound));
+        let inside = copy(&vp, Path::new("beats:/kits"), Path::new("beats:/kits/x")).await;
+        assert_eq!(inside, Err(FileError::InvalidPath));
+        let unknown = copy(&vp, Path::new("other:/kits"), Path::new("beats:/x")).await;
+        assert_eq!(unknown, Err(FileError::InvalidPath));
+        // neither read nor written outside of the root
+        let outside = Path::new("beats:/../outside");
+        let read = copy(&vp, outside, Path::new("beats:/x")).await;
+        assert_eq!(read, Err(FileError::InvalidPath));
+        let written = copy(&vp, Path::new("beats:/kits"), outside).await;
+        assert_eq!(written, Err(FileError::InvalidPath));
+        assert!(!root.with_file_name("outside").exists());
+    }
+
+    #[tokio::test]
+    async fn deep_listing() {
+        let root = TempDir::new("list");
+        fs::create_dir_all(root.join("kits/rock")).unwrap();
+        fs::write(root.join("kits/rock/kick.WAV"), "kick").unwrap();
+        fs::write(root.join("piano.sf2"), "sf").unwrap();
+        let mut vp = VirtualPaths::default();
+        vp.insert("samples:".into(), root.to_owned());
+
+        let list = |recursive| read_dir(&vp, Path::new("samples:"), recursive);
+        let flat = list(false).await.unwrap();
+        assert_eq!(flat.len(), 2);
+        let deep = list(true).await.unwrap();
+        let kinds: Vec<_> = deep
+            .iter()
+            .map(|info| (info.path.to_str().unwrap().replace('\\', "/"), info.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("kits".to_owned(), FileKind::Directory),
+                ("kits/rock".to_owned(), FileKind::Directory),
+                ("kits/rock/kick.WAV".to_owned(), FileKind::Sample),
+                ("piano.sf2".to_owned(), FileKind::Soundfont),
+            ]
+        );
+        assert_eq!(deep[2].size, 4);
+        assert!(deep[2].modified.is_some());
+        // the file system around the root isn't for the clients to see
+        for path in ["samples:/..", "samples:/kits/../../.."] {
+            let outside = read_dir(&vp, Path::new(path), true).await;
+            assert_eq!(outside, Err(FileError::InvalidPath));
+        }
+
+        vp.insert("beats:".into(), root.join("missing"));
+        let usage = disk_usage(&vp).await;
+        assert_eq!(usage[0].root, Path::new("beats:"));
+        assert_eq!(usage[0].used, None);
+        assert_eq!(usage[1].used, Some(6));
+        if cfg!(unix) {
+            assert!(usage[1].free <= usage[1].total && usage[1].total > Some(0));
+        }
+    }
+
+    #[tokio::test]
+    async fn upload() {
+        let root = TempDir::new("upload");
+        let mut vp = VirtualPaths::default();
+        vp.insert("samples:".into(), root.to_owned());
+        let path = Path::new("samples:/piano.sf2");
+
+        let mut upload = Upload::create(&vp, path, Some(6)).await.unwrap();
+        assert_eq!(upload.write(b"abc").await, Ok(3));
+        assert_eq!(upload.write(b"def").await, Ok(6));
+        // nothing is visible before the upload is complete
+        assert!(!root.join("piano.sf2").exists());
+        upload.finish().await.unwrap();
+        assert_eq!(fs::read(root.join("piano.sf2")).unwrap(), b"abcdef");
+
+        assert!(matches!(
+            Upload::create(&vp, path, None).await,
+            Err(FileError::Exists)
+        ));
+        let too_large = Some(MAX_UPLOAD_SIZE + 1);
+        let other = Path::new("samples:/other.sf2");
+        assert!(matches!(
+            Upload::create(&vp, other, too_large).await,
+            Err(FileError::TooLarge)
+        ));
+        Upload::create(&vp, other, None)
+            .await
+            .unwrap()
+            .abort()
+            .await;
+        assert_eq!(fs::read_dir(&*root).unwrap().count(), 1);
+
+        // nothing gets written outside of the root
+        let outside = Path::new("samples:/../escaped.sf2");
+        assert!(matches!(
+            Upload::create(&vp, outside, None).await,
+            Err(FileError::InvalidPath)
+        ));
+        assert!(!root.with_file_name("escaped.sf2").exists());
+    }
+
+    #[tokio::test]
+    async fn broken_soundfont() {
+        let root = TempDir::new("presets");
+        fs::write(root.join("broken.sf2"), "RIFF").unwrap();
+        let mut vp = VirtualPaths::default();
+        vp.insert("samples:".into(), root.to_owned());
+
+        let presets = |path| soundfont_presets(&vp, Path::new(path));
+        assert_eq!(
+            presets("samples:/broken.sf2").await,
+            Err(FileError::InvalidFile)
+        );
+        assert_eq!(presets("samples:/none.sf2").await, Err(FileError::NotFound));
+        assert_eq!(
+            presets("other:/piano.sf2").await,
+            Err(FileError::InvalidPath)
+        );
+    }
+
+    #[tokio::test]
+    async fn modified_times() {
+        let root = TempDir::new("modified");
+        let path = root.join("piano.sfz");
+        fs::write(&path, "<region>").unwrap();
+        let set_modified = |secs| {
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            file.set_modified(time).unwrap();
+        };
+        set_modified(1000);
+
+        let mut times = ModifiedTimes::default();
+        let paths = [path.clone()];
+        assert!(times.update(&paths).await.is_empty());
+        assert!(times.update(&paths).await.is_empty());
+        set_modified(2000);
+        assert_eq!(times.update(&paths).await, paths);
+
+        // gone for a moment while an editor saves it
+        fs::remove_file(&path).unwrap();
+        assert!(times.update(&paths).await.is_empty());
+        fs::write(&path, "<region>").unwrap();
+        set_modified(3000);
+        assert_eq!(times.update(&paths).await, paths);
+    }
+}
@@ -0,0 +1,253 @@
+// Footswitches on the GPIO pins of a Raspberry Pi, for an instrument that works without a
+// screen or a MIDI controller.
+//
+// The pins are read through the sysfs GPIO interface, which needs no extra library and is
+// there on every Raspberry Pi OS kernel. It can't turn on the pull-ups, `gpio=17=ip,pu` in
+// `/boot/config.txt` does that.
+
+use crate::{
+    app::{self, App},
+    config::{Footswitch, FootswitchAction},
+    control::drum_machine,
+    midi::{self, ControlChangeKind, MessageKind},
+    pads::Action,
+    performances, setlist,
+};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+const GPIO_CLASS_PATH: &str = "/sys/class/gpio";
+const POLL_PERIOD: Duration = Duration::from_millis(2);
+// How long a pin has to stay put before it counts, longer than any switch bounces
+pub const DEBOUNCE_TIME: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    Pressed,
+    Released,
+}
+
+// Only a level that stays the same for the debounce time changes the state of the switch
+#[derive(Debug, Clone)]
+pub struct Debouncer {
+    time: Duration,
+    pressed: bool,
+    // The level the pin last changed to and when
+    level: bool,
+    since: Instant,
+}
+
+impl Debouncer {
+    pub fn new(time: Duration, now: Instant) -> Self {
+        Self {
+            time,
+            pressed: false,
+            level: false,
+            since: now,
+        }
+    }
+
+    pub fn update(&mut self, pressed: bool, now: Instant) -> Option<Edge> {
+        if pressed != self.level {
+            self.level = pressed;
+            self.since = now;
+        }
+        if self.level == self.pressed || now.duration_since(self.since) < self.time {
+            return None;
+        }
+        self.pressed = self.level;
+        Some(if self.pressed {
+            Edge::Pressed
+        } else {
+            Edge::Released
+        })
+    }
+}
+
+pub struct Pin {
+    value: File,
+    active_high: bool,
+}
+
+impl Pin {
+    // Exported pins stay exported, another run finds them already there
+    pub fn open(number: u32, active_high: bool) -> std::io::Result<Self> {
+        let class = Path::new(GPIO_CLASS_PATH);
+        let dir = class.join(format!("gpio{number}"));
+        if !dir.exists() {
+            std::fs::write(class.join("export"), number.to_string())?;
+        }
+        std::fs::write(dir.join("direction"), "in")?;
+        let value = File::open(dir.join("value"))?;
+        Ok(Self { value, active_high })
+    }
+
+    pub fn is_pressed(&mut self) -> std::io::Result<bool> {
+        let mut level = [0];
+        self.value.seek(SeekFrom::Start(0))?;
+        self.value.read_exact(&mut level)?;
+        Ok((level[0] == b'1') == self.active_high)
+    }
+}
+
+// Polls the pins for as long as AMI runs, pins that can't be opened are left out
+pub async fn run(footswitches: Vec<Footswitch>, app: App) {
+    let now = Instant::now();
+    let mut switches = Vec::new();
+    for footswitch in footswitches {
+        match Pin::open(footswitch.pin, footswitch.active_high) {
+            Ok(pin) => {
+                info!("| Footswitch on GPIO {}", footswitch.pin);
+                switches.push((pin, Debouncer::new(DEBOUNCE_TIME, now), footswitch));
+            }
+            Err(e) => warn!("Can't open GPIO {}: {e}", footswitch.pin),
+        }
+    }
+    if switches.is_empty() {
+        return;
+    }
+    let mut interval = tokio::time::interval(POLL_PERIOD);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        for (pin, debouncer, footswitch) in &mut switches {
+            let Ok(pressed) = pin.is_pressed() else {
+                continue;
+            };
+            if let Some(edge) = debouncer.update(pressed, now) {
+                press(&app, &footswitch.action, edge).await;
+            }
+        }
+    }
+}
+
+async fn press(app: &App, action: &FootswitchAction, edge: Edge) {
+    let pressed = edge == Edge::Pressed;
+    if let Some(message) = virtual_message(action, pressed) {
+        let _ = app.midi_tx.send(message);
+        return;
+    }
+    let mut clients = app.clients.clone();
+    let (requesters, cache) = (&app.requesters, &app.cache);
+    type DK = drum_machine::RequestKind;
+    let action = match action {
+        FootswitchAction::HoldFill => Action::DrumMachine(DK::HoldFill(pressed)),
+        _ if !pressed => return,
+        FootswitchAction::NextEntry | FootswitchAction::PreviousEntry => {
+            let req = match action {
+                FootswitchAction::NextEntry => setlist::RequestKind::Next,
+                _ => setlist::RequestKind::Previous,
+            };
+            app::process_setlist_request(req, &app.setlist, requesters, cache, &mut clients).await;
+            return;
+        }
+        FootswitchAction::Pad(index) => {
+            app::trigger_pad(*index, &app.pads, requesters, cache, &mut clients).await;
+            return;
+        }
+        FootswitchAction::Performance(index) => {
+            let req = performances::RequestKind::Switch(*index);
+            app.handle_performance_request(req).await;
+            return;
+        }
+        FootswitchAction::ToggleDrumMachine => {
+            let enabled = cache.lock().await.get()["drum_machine"]["enabled"]
+                .as_bool()
+                .unwrap_or(false);
+            Action::DrumMachine(DK::SetEnabled(!enabled))
+        }
+        FootswitchAction::TriggerFill => Action::DrumMachine(DK::TriggerFill),
+        FootswitchAction::ControlChange { .. } | FootswitchAction::Note { .. } => return,
+    };
+    app::run_action(action, requesters, cache, &mut clients).await;
+}
+
+// The MIDI event of a switch that plays like a pedal or a key, it goes to everything listening
+// to the MIDI input
+fn virtual_message(action: &FootswitchAction, pressed: bool) -> Option<midi::Message> {
+    let (channel, kind) = match *action {
+        FootswitchAction::ControlChange {
+            channel,
+            controller,
+        } => {
+            let kind = MessageKind::ControlChange {
+                kind: ControlChangeKind::from_number(controller)?,
+                value: if pressed { 127 } else { 0 },
+            };
+            (channel, kind)
+        }
+        FootswitchAction::Note {
+            channel,
+            note,
+            velocity,
+        } if pressed => (channel, MessageKind::NoteOn { note, velocity }),
+        FootswitchAction::Note { channel, note, .. } => {
+            (channel, MessageKind::NoteOff { note, velocity: 0 })
+        }
+        _ => return None,
+    };
+    Some(midi::Message::new(channel, kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{virtual_message, Debouncer, Edge};
+    use crate::{
+        config::FootswitchAction,
+        midi::{ControlChangeKind, Message, MessageKind},
+    };
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn debounces() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut debouncer = Debouncer::new(Duration::from_millis(20), start);
+        assert_eq!(debouncer.update(false, at(0)), None);
+
+        // bouncing, only the level that stays counts
+        assert_eq!(debouncer.update(true, at(2)), None);
+        assert_eq!(debouncer.update(false, at(4)), None);
+        assert_eq!(debouncer.update(true, at(6)), None);
+        assert_eq!(debouncer.update(true, at(25)), None);
+        assert_eq!(debouncer.update(true, at(26)), Some(Edge::Pressed));
+        assert_eq!(debouncer.update(true, at(100)), None);
+
+        // a short drop while held down is no release
+        assert_eq!(debouncer.update(false, at(102)), None);
+        assert_eq!(debouncer.update(true, at(110)), None);
+        assert_eq!(debouncer.update(true, at(200)), None);
+        assert_eq!(debouncer.update(false, at(202)), None);
+        assert_eq!(debouncer.update(false, at(222)), Some(Edge::Released));
+    }
+
+    #[test]
+    fn virtual_messages() {
+        let sustain = FootswitchAction::ControlChange {
+            channel: 2,
+            controller: 64,
+        };
+        let kind = MessageKind::ControlChange {
+            kind: ControlChangeKind::DamperPedal,
+            value: 127,
+        };
+        assert_eq!(virtual_message(&sustain, true), Some(Message::new(2, kind)));
+        let key = FootswitchAction::Note {
+            channel: 9,
+            note: 36,
+            velocity: 100,
+        };
+        let kind = MessageKind::NoteOff {
+            note: 36,
+            velocity: 0,
+        };
+        assert_eq!(virtual_message(&key, false), Some(Message::new(9, kind)));
+        assert_eq!(virtual_message(&FootswitchAction::TriggerFill, true), None);
+    }
+}
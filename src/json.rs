@@ -1,11 +1,17 @@
-use crate::deser::SerializationError;
+use crate::deser::{PresetError, SerializationError};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use tokio::sync::mpsc;
 
 pub type JsonFieldUpdate = (String, serde_json::Value);
 pub type JsonUpdateSender = mpsc::Sender<(usize, JsonUpdateKind)>;
 pub type JsonUpdateListener = mpsc::Receiver<(usize, JsonUpdateKind)>;
 
+pub const VERSION_FIELD: &str = "version";
+
+// Upgrades a preset in place by exactly one version, `Err` holds the reason why it's not possible
+pub type Migration = fn(&mut serde_json::Value) -> Result<(), String>;
+
 pub fn create_json_update_channel(buffer: usize) -> (JsonUpdateSender, JsonUpdateListener) {
     mpsc::channel(buffer)
 }
@@ -17,6 +23,7 @@ pub enum JsonUpdateKind {
     Failed,
     Ok,
     UpdateFields(Vec<JsonFieldUpdate>),
+    InvalidPreset(PresetError),
 }
 
 pub fn update_fields_or_fail(
@@ -46,4 +53,136 @@ impl JsonUpdater {
             .await
             .unwrap_or_else(|e| tracing::error!("Error: {e}"));
     }
-}
\ No newline at end of file
+
+    // For the audio thread, the update is dropped while the channel is full
+    pub fn try_broadcast(&self, kind: JsonUpdateKind) {
+        self.tx
+            .try_send((self.id, kind))
+            .unwrap_or_else(|e| tracing::error!("Error: {e}"));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrationError {
+    NotAnObject,
+    InvalidVersion,
+    UnsupportedVersion { version: u64, latest: u64 },
+    Failed { from: u64, reason: String },
+}
+
+impl std::error::Error for MigrationError {}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::NotAnObject => "Preset is not a JSON object.".fmt(f),
+            MigrationError::InvalidVersion => "Preset version is not a valid number.".fmt(f),
+            MigrationError::UnsupportedVersion { version, latest } => write!(
+                f,
+                "Preset version {version} is newer than the latest supported version {latest}."
+            ),
+            MigrationError::Failed { from, reason } => write!(
+                f,
+                "Failed to migrate preset from version {from} to {}: {reason}",
+                from + 1
+            ),
+        }
+    }
+}
+
+impl From<MigrationError> for PresetError {
+    fn from(e: MigrationError) -> Self {
+        PresetError::Migration(e.to_string())
+    }
+}
+
+// The latest version is the number of migrations, version 0 stands for presets
+// saved before versioning was introduced (no version field)
+pub fn latest_version(migrations: &[Migration]) -> u64 {
+    migrations.len() as u64
+}
+
+pub fn get_version(source: &serde_json::Value) -> Result<u64, MigrationError> {
+    let object = source.as_object().ok_or(MigrationError::NotAnObject)?;
+    match object.get(VERSION_FIELD) {
+        Some(version) => version.as_u64().ok_or(MigrationError::InvalidVersion),
+        None => Ok(0),
+    }
+}
+
+pub fn set_version(target: &mut serde_json::Value, version: u64) {
+    if let Some(object) = target.as_object_mut() {
+        object.insert(VERSION_FIELD.to_owned(), version.into());
+    }
+}
+
+pub fn migrate(
+    mut source: serde_json::Value,
+    migrations: &[Migration],
+) -> Result<serde_json::Value, MigrationError> {
+    let version = get_version(&source)?;
+    let latest = latest_version(migrations);
+    if version > latest {
+        return Err(MigrationError::UnsupportedVersion { version, latest });
+    }
+    for (from, migration) in migrations.iter().enumerate().skip(version as usize) {
+        migration(&mut source).map_err(|reason| MigrationError::Failed {
+            from: from as u64,
+            reason,
+        })?;
+        set_version(&mut source, from as u64 + 1);
+    }
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate, Migration, MigrationError};
+    use serde_json::json;
+
+    const MIGRATIONS: &[Migration] = &[
+        |_| Ok(()),
+        |v| {
+            v["tempo"] = v["bpm"].take();
+            Ok(())
+        },
+    ];
+
+    #[test]
+    fn migrate_unversioned() {
+        let res = migrate(json!({ "bpm": 90 }), MIGRATIONS);
+        assert_eq!(res, Ok(json!({ "version": 2, "bpm": null, "tempo": 90 })));
+    }
+
+    #[test]
+    fn migrate_latest() {
+        let res = migrate(json!({ "version": 2, "tempo": 90 }), MIGRATIONS);
+        assert_eq!(res, Ok(json!({ "version": 2, "tempo": 90 })));
+    }
+
+    #[test]
+    fn migrate_errors() {
+        assert_eq!(
+            migrate(json!({ "version": 3 }), MIGRATIONS),
+            Err(MigrationError::UnsupportedVersion {
+                version: 3,
+                latest: 2
+            })
+        );
+        assert_eq!(
+            migrate(json!({ "version": "1" }), MIGRATIONS),
+            Err(MigrationError::InvalidVersion)
+        );
+        assert_eq!(
+            migrate(json!([]), MIGRATIONS),
+            Err(MigrationError::NotAnObject)
+        );
+        assert_eq!(
+            migrate(json!({}), &[|_| Err("broken".into())]),
+            Err(MigrationError::Failed {
+                from: 0,
+                reason: "broken".into()
+            })
+        );
+    }
+}
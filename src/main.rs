@@ -1,30 +1,46 @@
+use app::App;
 use clap::Parser;
-use control::drum_machine::{self, DrumMachine};
-use json::JsonUpdateKind;
 use midi::MidiReader;
-use render::{
-    command,
-    node::{self, fluidlite_synth, oxi_synth, rusty_synth, sfizz_synth},
-    Renderer,
-};
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use render::{command, node};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 use webserver::{Clients, ServerMessageKind};
 
+pub mod app;
 pub mod audio;
+pub mod config;
 pub mod control;
 pub mod deser;
+pub mod files;
+#[cfg(feature = "gpio")]
+pub mod gpio;
 pub mod json;
 pub mod midi;
+pub mod osc;
+pub mod pads;
+#[cfg(feature = "panel")]
+pub mod panel;
 pub mod path;
+pub mod performances;
 pub mod render;
 pub mod rhythm;
+pub mod session;
+pub mod setlist;
+pub mod sfz;
+pub mod snapshots;
+pub mod surface;
+pub mod sync;
 pub mod synth;
+pub mod systemd;
 mod webserver;
 
+#[cfg(test)]
+mod testing;
+
 const VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
+const MIDI_INPUT_SLOTS: usize = 16;
 
 #[derive(Parser, Debug)]
 #[command(about = "Simple software for adding two integers numbers.")]
@@ -37,16 +53,116 @@ pub struct Args {
 
     // #[clap(index=2)]
     // b: Option<i32>,
+    #[arg(long, help = "TOML file with the settings of this deployment")]
+    config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Print the audio output devices and MIDI input ports, then exit"
+    )]
+    list_devices: bool,
+
     #[arg(short, long, help = "Path to samples directory")]
-    samples: PathBuf,
+    samples: Option<PathBuf>,
 
     #[arg(short, long, help = "Path to beats directory")]
-    beats: PathBuf,
+    beats: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Render without an audio device, for servers or only using the MIDI side"
+    )]
+    null_audio: bool,
+
+    #[arg(
+        long,
+        help = "MIDI input slot (0-15) for the USB MIDI gadget port (f_midi)"
+    )]
+    usb_gadget_slot: Option<usize>,
+
+    #[arg(
+        long,
+        default_value_t = 300,
+        help = "Active Sensing timeout in ms before all notes are turned off (0 disables it)"
+    )]
+    active_sensing_timeout: u64,
+
+    #[arg(
+        long,
+        help = "Drop identical MIDI events arriving on different slots within this many ms"
+    )]
+    dedup_window: Option<u64>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u8).range(0..32),
+        help = "MSB controllers merged with their LSB controller (32 higher) into 14-bit values"
+    )]
+    cc_14_bit: Vec<u8>,
+
+    #[arg(
+        long,
+        help = "Password clients need to change anything, without it everyone can"
+    )]
+    password: Option<String>,
+
+    #[arg(long, requires = "tls_key", help = "TLS certificate (PEM) for https and wss")]
+    tls_cert: Option<PathBuf>,
+
+    #[arg(long, requires = "tls_cert", help = "TLS private key (PEM) for https and wss")]
+    tls_key: Option<PathBuf>,
+
+    #[arg(long, help = "Serve the web client from this directory instead of the built-in one")]
+    web_root: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "UDP port to receive OSC on, with a password surfaces send /authenticate first"
+    )]
+    osc_port: Option<u16>,
+
+    #[arg(
+        long,
+        requires = "osc_port",
+        help = "Address to send the OSC state changes to"
+    )]
+    osc_target: Vec<SocketAddr>,
+
+    #[arg(
+        long,
+        default_value_t = 1,
+        help = "Threads rendering the instruments in parallel, up to the number of CPU cores"
+    )]
+    render_threads: usize,
+
+    #[arg(
+        long,
+        help = "Keep the timing of MIDI input within a buffer, adds up to a buffer of latency"
+    )]
+    midi_jitter_compensation: bool,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u8).range(0..120),
+        help = "Controller number that silences every instrument when pressed, for stuck notes"
+    )]
+    panic_cc: Option<u8>,
+
+    #[arg(
+        long,
+        help = "Treat MIDI input as MPE, channel 1 is the master channel of the zone"
+    )]
+    mpe: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    if args.list_devices {
+        list_devices();
+        return Ok(());
+    }
 
     let subscriber = FmtSubscriber::builder()
         .with_max_level(Level::TRACE)
@@ -58,93 +174,226 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         VERSION.unwrap_or("?")
     );
 
-    info!("| Samples directory: {:?}", args.samples);
-    info!("| Beats directory: {:?}", args.beats);
+    let config = match &args.config {
+        Some(path) => {
+            info!("| Config file: {path:?}");
+            config::load(path)?
+        }
+        None => config::Config::default(),
+    };
+    let samples = args
+        .samples
+        .or(config.samples)
+        .ok_or("No samples directory, set it with --samples or in the config")?;
+    let beats = args
+        .beats
+        .or(config.beats)
+        .ok_or("No beats directory, set it with --beats or in the config")?;
+
+    info!("| Samples directory: {:?}", samples);
+    info!("| Beats directory: {:?}", beats);
 
-    let (midi_tx, midi_rx) = midi::create_channel(32);
-    let (req_tx, req_rx) = command::create_request_channel(32);
+    let (midi_tx, _) = midi::create_channel(32);
 
     let mut virtual_paths = crate::path::VirtualPaths::default();
-    virtual_paths.insert("samples:".into(), args.samples);
-    virtual_paths.insert("beats:".into(), args.beats);
+    virtual_paths.insert("samples:".into(), samples);
+    virtual_paths.insert("beats:".into(), beats);
 
     info!("| Available MIDI ports:");
     for port in midi::MidiReader::get_available_ports() {
         info!("| - {port}");
     }
 
-    let clients = Clients::new(256);
-    let mut midi_reader = midi::MidiReader::with_slots(midi_tx.clone(), 16);
+    let mut midi_reader = midi::MidiReader::with_slots(midi_tx.clone(), MIDI_INPUT_SLOTS);
 
-    if midi_reader
-        .connect_input(0, "VMPK Output:out 130:0")
-        .is_ok()
-    {
-        tracing::debug!("MIDI port connected: VMPK Output:out 130:0");
-    }
-    if midi_reader
-        .connect_input(0, "Hammer 88 Pro:Hammer 88 Pro USB MIDI 20:0")
-        .is_ok()
-    {
-        tracing::debug!("MIDI port connected: Hammer 88 Pro:Hammer 88 Pro USB MIDI 20:0");
+    if let Some(slot) = args.usb_gadget_slot {
+        if slot >= MIDI_INPUT_SLOTS {
+            return Err(format!(
+                "USB MIDI gadget slot {slot} doesn't exist, there are {MIDI_INPUT_SLOTS} slots"
+            )
+            .into());
+        }
+        if midi::gadget::is_peripheral_mode_available() {
+            info!("| USB MIDI gadget mode enabled on slot {slot}");
+        } else {
+            tracing::warn!("USB MIDI gadget slot set, but no USB device controller was found");
+        }
     }
 
-    let midi_reader = Arc::new(Mutex::new(midi_reader));
+    midi_reader.set_active_sensing_timeout(
+        (args.active_sensing_timeout > 0)
+            .then(|| Duration::from_millis(args.active_sensing_timeout)),
+    );
 
-    tokio::spawn(run_midi_logger(midi_rx, clients.clone()));
-    tokio::spawn(run_midi_port_watchdog(clients.clone()));
+    midi_reader.set_dedup_window(args.dedup_window.map(Duration::from_millis));
+    if let Some(window) = args.dedup_window {
+        info!("| MIDI input deduplication window: {window} ms");
+    }
 
-    let (dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(32);
-    let (dm_req_tx, dm_req_rx) = drum_machine::create_request_channel(32);
-    let mut drum_machine = DrumMachine::new(dm_ctr_tx, dm_req_rx, virtual_paths.clone());
-    let drum_machine_json = drum_machine
-        .serialize()
-        .expect("Failed to serialize Drum Machine");
+    midi_reader.set_merged_cc_pairs(&args.cc_14_bit);
+    if !args.cc_14_bit.is_empty() {
+        let controllers = midi_reader.merged_cc_pairs();
+        info!("| 14-bit controllers: {controllers:?}");
+    }
 
-    tokio::spawn(async move {
-        loop {
-            drum_machine.tick().await;
-            tokio::time::sleep(Duration::from_secs_f32(drum_machine.period().min(0.01))).await;
+    let mut app = App::new(midi_tx, midi_reader, virtual_paths);
+
+    tokio::spawn(run_midi_port_watchdog(
+        app.clients.clone(),
+        Arc::clone(&app.midi_reader),
+        args.usb_gadget_slot,
+        config.midi.auto_connect,
+    ));
+    tokio::spawn(run_active_sensing_watchdog(Arc::clone(&app.midi_reader)));
+    if config.hot_reload != config::HotReload::Off {
+        tokio::spawn(run_instrument_file_watchdog(app.clone(), config.hot_reload));
+    }
+    #[cfg(feature = "gpio")]
+    tokio::spawn(gpio::run(config.footswitches, app.clone()));
+    #[cfg(not(feature = "gpio"))]
+    if !config.footswitches.is_empty() {
+        tracing::warn!("Footswitches configured, but AMI was built without the gpio feature");
+    }
+    if let Some(surface) = config.surface {
+        tokio::spawn(surface::run(surface, app.clone()));
+    }
+    #[cfg(feature = "panel")]
+    if let Some(panel) = config.panel {
+        tokio::spawn(panel::run(panel, app.clone()));
+    }
+    #[cfg(not(feature = "panel"))]
+    if config.panel.is_some() {
+        tracing::warn!("A panel configured, but AMI was built without the panel feature");
+    }
+
+    if args.render_threads > 1 {
+        app.renderer
+            .lock()
+            .await
+            .set_render_threads(args.render_threads);
+        info!("| Render threads: {}", args.render_threads);
+    }
+    if args.midi_jitter_compensation {
+        app.renderer.lock().await.set_midi_jitter_compensation(true);
+        info!("| MIDI jitter compensation enabled");
+    }
+    if let Some(cc) = args.panic_cc {
+        let kind = midi::ControlChangeKind::from_number(cc);
+        app.renderer.lock().await.set_panic_cc(kind);
+        info!("| Panic on CC {cc}");
+    }
+    if args.mpe {
+        let zones = midi::mpe::Zones::default();
+        app.renderer.lock().await.set_mpe(Some(zones));
+        info!("| MPE with {} member channels", zones.lower);
+    }
+    if !config.preload.is_empty() {
+        let mut paths = Vec::new();
+        for path in &config.preload {
+            match app.virtual_paths.translate(path) {
+                Some(real_path) => paths.push(real_path),
+                None => tracing::warn!("Can't preload {path:?}, it's not under a virtual root"),
+            }
         }
-    });
+        info!("| Preloading {} files", paths.len());
+        app.renderer.lock().await.soundfont_cache().preload(paths);
+    }
+    let performances = config.performances;
+    if !performances.slots.is_empty() {
+        info!("| Performances: {}", performances.slots.len());
+    }
+    for req in [
+        performances::RequestKind::SetSlots(performances.slots),
+        performances::RequestKind::SetProgramChangeChannel(performances.program_change_channel),
+    ] {
+        if !app.handle_performance_request(req).await {
+            tracing::warn!("Failed to set up the performances");
+        }
+    }
 
-    let mut renderer = Renderer::new(
-        midi_tx.subscribe(),
-        req_rx,
-        dm_ctr_rx,
-        virtual_paths.clone(),
-    );
-    renderer.register_node_kind("RustySynth", || Box::<rusty_synth::Node>::default());
-    renderer.register_node_kind("OxiSynth", || Box::<oxi_synth::Node>::default());
-    renderer.register_node_kind("FluidliteSynth", || Box::<fluidlite_synth::Node>::default());
-    renderer.register_node_kind("SfizzSynth", || Box::<sfizz_synth::Node>::default());
+    let renderer = Arc::clone(&app.renderer);
+    let audio_config = config.audio;
+    let input_host = audio_config.host.clone();
+    let input_device = audio_config.input.clone();
+    let null_audio = args.null_audio || audio_config.null_output;
+    if null_audio {
+        info!("| Null audio output");
+    }
+    let (audio_req_tx, audio_status_rx) = audio::output::spawn(move || {
+        let mut audio_ctr = audio::output::Controller::new(renderer);
 
-    let renderer = Arc::new(Mutex::new(renderer));
-    let mut audio_ctr = audio::output::Controller::new(renderer);
+        #[cfg(not(target_os = "windows"))]
+        {
+            audio_ctr.sample_rate = 44100;
+        }
+        #[cfg(target_os = "windows")]
+        {
+            audio_ctr.sample_rate = 48000;
+        }
 
-    #[cfg(not(target_os = "windows"))]
-    {
-        audio_ctr.sample_rate = 44100;
+        audio_ctr.buffer_size = 2048;
+        if let Some(sample_rate) = audio_config.sample_rate {
+            audio_ctr.sample_rate = sample_rate;
+        }
+        if let Some(buffer_size) = audio_config.buffer_size {
+            audio_ctr.buffer_size = buffer_size;
+        }
+        if let Some(channels) = audio_config.channels {
+            audio_ctr.channels = channels;
+        }
+        match audio_config.device {
+            _ if null_audio => audio_ctr.connect_to_null_output(),
+            Some(device_name) => {
+                let host_name = audio_config
+                    .host
+                    .unwrap_or_else(audio::info::get_default_host_name);
+                audio_ctr.connect_to_output_device(&host_name, &device_name)?;
+            }
+            None => audio_ctr.connect_to_default_output_device()?,
+        }
+        Ok(audio_ctr)
+    })
+    .expect("Failed to connect to output device");
+    app.set_audio_output(audio_req_tx, audio_status_rx);
+    if let Some(device_name) = input_device {
+        let (input, sample_rate) = {
+            let renderer = app.renderer.lock().await;
+            (renderer.audio_input().clone(), renderer.sample_rate())
+        };
+        let host_name = input_host.unwrap_or_else(audio::info::get_default_host_name);
+        let sample_rate = sample_rate.unwrap_or(44100);
+        match audio::input::spawn(host_name, device_name.clone(), sample_rate, input) {
+            Ok(()) => info!("| Audio input: {device_name}"),
+            Err(e) => tracing::warn!("Failed to open the audio input {device_name}: {e:?}"),
+        }
     }
-    #[cfg(target_os = "windows")]
-    {
-        audio_ctr.sample_rate = 48000;
+    if let Some(timeout) = systemd::watchdog_timeout() {
+        tokio::spawn(systemd::run_watchdog(app.clone(), timeout));
     }
 
-    audio_ctr.buffer_size = 2048;
-    audio_ctr
-        .connect_to_default_output_device()
-        .expect("Failed to connect to output device");
-
-    let cache = Arc::new(Mutex::new(webserver::Cache::new(drum_machine_json)));
+    if let Some(port) = args.osc_port {
+        let socket = tokio::net::UdpSocket::bind(("0.0.0.0", port)).await?;
+        info!("| OSC on port {port}, sending to {:?}", args.osc_target);
+        let app = app.clone();
+        tokio::spawn(osc::run(
+            socket,
+            args.osc_target,
+            app.clients.clone(),
+            args.password.as_deref().map(Into::into),
+            move |addr, req| {
+                let app = app.clone();
+                async move { app.handle_client_message(addr, req).await }
+            },
+        ));
+    }
 
-    let req_tx2 = req_tx.clone();
-    let cache2 = Arc::clone(&cache);
+    let req_tx2 = app.requesters.renderer.clone();
+    let cache2 = Arc::clone(&app.cache);
     tokio::spawn(async move {
         let req = command::RequestKind::AddNode {
             kind: "OxiSynth".into(),
         };
-        if let Some(res) = send_renderer_request(&req_tx2, req).await {
+        if let Some(res) = app::send_renderer_request(&req_tx2, req).await {
             cache2.lock().await.cache_renderer_response(&res);
         }
 
@@ -154,148 +403,279 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             kind: node::RequestKind::LoadFile(file_path),
         };
 
-        if let Some(res) = send_renderer_request(&req_tx2, req).await {
+        if let Some(res) = app::send_renderer_request(&req_tx2, req).await {
             cache2.lock().await.cache_renderer_response(&res);
         }
     });
 
-    let shared_state = webserver::SharedState {
-        clients: Clients::clone(&clients),
-        midi_reader: Arc::clone(&midi_reader),
-        cache: Arc::clone(&cache),
-    };
-
-    webserver::run(3000, shared_state, move |addr, req| {
-        let midi_reader = Arc::clone(&midi_reader);
-        let mut clients = Clients::clone(&clients);
-        let cache = Arc::clone(&cache);
-        let req_tx = req_tx.clone();
-        let dm_req_tx = dm_req_tx.clone();
-        let vp = virtual_paths.clone();
-        async move {
-            use webserver::ClientMessageKind;
-            use webserver::ServerMessageKind;
-            match req {
-                ClientMessageKind::Ping => ServerMessageKind::Pong,
-                ClientMessageKind::Report(report) => {
-                    info!("Report from [{addr}]: {report}");
-                    ServerMessageKind::Ack
-                }
-                ClientMessageKind::ConnectMidiInput(slot, name) => {
-                    let mut midi_reader = midi_reader.lock().await;
-                    if let Ok(()) = midi_reader.connect_input(slot, &name) {
-                        clients.broadcast(ServerMessageKind::ConnectedMidiInputs(
-                            midi_reader.connected_input_names(),
-                        ));
-                        ServerMessageKind::Ack
-                    } else {
-                        ServerMessageKind::Nak
-                    }
-                }
-                ClientMessageKind::DisconnectMidiInput(slot) => {
-                    let mut midi_reader = midi_reader.lock().await;
-                    if let Ok(()) = midi_reader.disconnect_input(slot) {
-                        clients.broadcast(ServerMessageKind::ConnectedMidiInputs(
-                            midi_reader.connected_input_names(),
-                        ));
-                        ServerMessageKind::Ack
-                    } else {
-                        ServerMessageKind::Nak
-                    }
-                }
-                ClientMessageKind::RendererRequest(req) => {
-                    let res = send_renderer_request(&req_tx, req).await;
-                    let mut cache = cache.lock().await;
-                    if let Some(res) = res {
-                        cache.cache_renderer_response(&res);
-                        clients.broadcast(ServerMessageKind::RendererResponse(res));
-                        ServerMessageKind::Ack
-                    } else {
-                        ServerMessageKind::Nak
-                    }
-                }
-                ClientMessageKind::ReadDir(path) => {
-                    if let Some(path) = vp.translate(&path) {
-                        if let Ok(dir) = std::fs::read_dir(&path) {
-                            let entries = dir
-                                .into_iter()
-                                .flatten()
-                                .map(|x| {
-                                    (
-                                        x.path().is_dir(),
-                                        crate::path::remove_prefix(x.path().as_path(), &path),
-                                    )
-                                })
-                                .collect();
-                            return ServerMessageKind::DirInfo(Some(entries));
-                        }
-                    }
-                    ServerMessageKind::DirInfo(None)
-                }
-                ClientMessageKind::DrumMachineRequest(req) => {
-                    let res = send_drum_machine_request(&dm_req_tx, req).await;
-                    let mut cache = cache.lock().await;
-                    if let Some(res) = res {
-                        cache.chache_drum_machine_update(&res);
-                        clients.broadcast(ServerMessageKind::DrumMachineUpdate(res));
-                        ServerMessageKind::Ack
-                    } else {
-                        ServerMessageKind::Nak
-                    }
-                }
+    if let Some(path) = config.session {
+        info!("| Session: {path:?}");
+        let app = app.clone();
+        tokio::spawn(async move {
+            let mut clients = app.clients.clone();
+            for req in [
+                setlist::RequestKind::LoadSetlist(path),
+                setlist::RequestKind::Select(0),
+            ] {
+                let (setlist, cache) = (&app.setlist, &app.cache);
+                app::process_setlist_request(req, setlist, &app.requesters, cache, &mut clients)
+                    .await;
             }
+        });
+    }
+
+    if let Some(path) = &config.autosave {
+        info!("| Autosave: {path:?}");
+    }
+
+    let mut state = app.shared_state();
+    state.password = args.password.map(Into::into);
+    if state.password.is_some() {
+        info!("| Clients need a password");
+    }
+    if let Some(web_root) = &args.web_root {
+        info!("| Web client directory: {web_root:?}");
+    }
+    state.web_root = args.web_root;
+    let tls = args
+        .tls_cert
+        .zip(args.tls_key)
+        .map(|(cert_path, key_path)| webserver::TlsConfig {
+            cert_path,
+            key_path,
+        });
+    let handler_app = app.clone();
+    let server = webserver::run(config.webserver.port, tls, state, move |addr, req| {
+        let app = handler_app.clone();
+        async move { app.handle_client_message(addr, req).await }
+    });
+    systemd::notify(systemd::READY);
+    tokio::select! {
+        _ = server => {}
+        _ = terminated() => {
+            info!("Terminated, shutting down");
+            systemd::notify(systemd::STOPPING);
+            app.shutdown(config.autosave.as_deref()).await;
         }
-    })
-    .await;
+    }
 
     Ok(())
 }
 
-async fn run_midi_logger(mut midi_rx: midi::Receiver, mut clients: Clients) {
-    while let Ok(message) = midi_rx.recv().await {
-        clients.broadcast(ServerMessageKind::MidiEvent(message));
+// Ctrl+C or SIGTERM, how systemd stops a service
+#[cfg(unix)]
+async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => Some(sigterm),
+        Err(e) => {
+            tracing::warn!("Can't handle SIGTERM: {e}");
+            None
+        }
+    };
+    let sigterm = async {
+        match &mut sigterm {
+            Some(sigterm) => _ = sigterm.recv().await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// The names as the config wants them, with what every audio device supports
+fn list_devices() {
+    let outputs = audio::info::get_available_outputs();
+    let mut hosts: Vec<_> = outputs.hosts.into_iter().collect();
+    hosts.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mark = |is_default: bool| if is_default { " (default)" } else { "" };
+    println!("Audio outputs:");
+    for (host_name, devices) in hosts {
+        let is_default = host_name == outputs.default;
+        println!("  host = {host_name:?}{}", mark(is_default));
+        for device in devices.devices {
+            let is_default = devices.default.as_ref() == Some(&device.name);
+            println!("    device = {:?}{}", device.name, mark(is_default));
+            // a range for every sample format, which don't matter here
+            let mut configs: Vec<_> = device
+                .configs
+                .iter()
+                .map(|c| (c.num_channels, c.min_sample_rate, c.max_sample_rate))
+                .collect();
+            configs.sort();
+            configs.dedup();
+            for (channels, min_rate, max_rate) in configs {
+                if min_rate == max_rate {
+                    println!("      {channels} channels, {min_rate} Hz");
+                } else {
+                    println!("      {channels} channels, {min_rate} - {max_rate} Hz");
+                }
+            }
+        }
+    }
+    println!("Audio inputs:");
+    let mut inputs = audio::info::get_available_inputs();
+    inputs.sort();
+    for (host_name, devices) in inputs {
+        println!("  host = {host_name:?}");
+        for device in devices {
+            println!("    input = {device:?}");
+        }
+    }
+    println!("MIDI inputs:");
+    for port in MidiReader::get_available_ports() {
+        println!("  port = {port:?}");
+    }
+    println!("MIDI outputs:");
+    for port in midi::MidiWriter::get_available_ports() {
+        println!("  port = {port:?}");
     }
 }
 
-async fn run_midi_port_watchdog(mut clients: Clients) {
+async fn run_midi_port_watchdog(
+    mut clients: Clients,
+    midi_reader: Arc<Mutex<MidiReader>>,
+    gadget_slot: Option<usize>,
+    auto_connect: Vec<config::AutoConnect>,
+) {
+    let mut known_ports = Vec::new();
+    let mut gadget_failed = false;
     loop {
-        clients.broadcast(ServerMessageKind::AvailableMidiInputs(
-            MidiReader::get_available_ports(),
-        ));
+        let ports = MidiReader::get_available_ports();
+        if let Some(slot) = gadget_slot {
+            update_gadget_connection(&mut clients, &midi_reader, slot, &ports, &mut gadget_failed)
+                .await;
+        }
+        let new_ports: Vec<_> = ports.iter().filter(|p| !known_ports.contains(*p)).collect();
+        auto_connect_ports(&mut clients, &midi_reader, &auto_connect, &new_ports).await;
+        known_ports.clone_from(&ports);
+        clients.broadcast(ServerMessageKind::AvailableMidiInputs(ports));
         tokio::time::sleep(Duration::from_millis(1000)).await;
     }
 }
 
-async fn send_renderer_request(
-    req_tx: &command::Requester,
-    req: command::RequestKind,
-) -> Option<command::ResponseKind> {
-    let (res_tx, res_rx) = command::create_response_channel();
+// Only ports that just showed up, so a port disconnected by hand stays disconnected
+async fn auto_connect_ports(
+    clients: &mut Clients,
+    midi_reader: &Mutex<MidiReader>,
+    rules: &[config::AutoConnect],
+    new_ports: &[&String],
+) {
+    let mut midi_reader = midi_reader.lock().await;
+    let mut changed = false;
+    for rule in rules {
+        let Some(port) = new_ports.iter().find(|port| rule.matches(port)) else {
+            continue;
+        };
+        if midi_reader.is_slot_connected(rule.slot) {
+            continue;
+        }
+        if midi_reader.connect_input(rule.slot, port).is_ok() {
+            info!("MIDI port {port} connected to slot {}", rule.slot);
+            changed = true;
+        }
+    }
+    if changed {
+        clients.broadcast(ServerMessageKind::ConnectedMidiInputs(
+            midi_reader.connected_input_names(),
+        ));
+    }
+}
 
-    if let Ok(()) = req_tx.send((req, res_tx)).await {
-        if let Ok(response_kind) = res_rx.await {
-            Some(response_kind)
-        } else {
-            None
+// Polls instead of watching, instrument files live on SD cards and network shares alike
+async fn run_instrument_file_watchdog(app: App, mode: config::HotReload) {
+    let mut modified_times = files::ModifiedTimes::default();
+    let mut clients = app.clients.clone();
+    loop {
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+        let loaded: Vec<_> = app
+            .cache
+            .lock()
+            .await
+            .loaded_files()
+            .into_iter()
+            .filter_map(|(id, path)| Some((id, app.virtual_paths.translate(&path)?, path)))
+            .collect();
+        let real_paths: Vec<_> = loaded
+            .iter()
+            .map(|(_, real_path, _)| real_path.clone())
+            .collect();
+        let changed = modified_times.update(&real_paths).await;
+        for (id, _, path) in loaded.into_iter().filter(|(_, p, _)| changed.contains(p)) {
+            if mode == config::HotReload::Ask {
+                clients.broadcast(ServerMessageKind::InstrumentFileChanged(id, path));
+                continue;
+            }
+            info!("Reloading {path:?} of node {id}");
+            let req = command::RequestKind::NodeRequest {
+                id,
+                kind: node::RequestKind::LoadFile(path),
+            };
+            if let Some(res) = app::send_renderer_request(&app.requesters.renderer, req).await {
+                app.cache.lock().await.cache_renderer_response(&res);
+                clients.broadcast(ServerMessageKind::RendererResponse(res));
+            }
         }
-    } else {
-        None
     }
 }
 
-async fn send_drum_machine_request(
-    req_tx: &drum_machine::Requester,
-    req: drum_machine::RequestKind,
-) -> Option<JsonUpdateKind> {
-    let (res_tx, res_rx) = drum_machine::create_response_channel();
+async fn run_active_sensing_watchdog(midi_reader: Arc<Mutex<MidiReader>>) {
+    loop {
+        midi_reader.lock().await.check_active_sensing();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
 
-    if let Ok(()) = req_tx.send((req, res_tx)).await {
-        if let Ok(response_kind) = res_rx.await {
-            Some(response_kind)
-        } else {
-            None
+// The gadget port comes and goes with the USB cable, so keep the slot in sync with it. A port
+// that fails to connect is tried again every time, but only reported until it goes away.
+async fn update_gadget_connection(
+    clients: &mut Clients,
+    midi_reader: &Mutex<MidiReader>,
+    slot: usize,
+    ports: &[String],
+    failed: &mut bool,
+) {
+    let mut midi_reader = midi_reader.lock().await;
+    let gadget_port = midi::gadget::find_gadget_port(ports);
+    let connected = midi_reader.is_slot_connected(slot);
+    if gadget_port.is_none() {
+        *failed = false;
+    }
+    let changed = if gadget_port.is_some() && !connected {
+        match midi_reader.connect_gadget_input(slot) {
+            Ok(()) => {
+                *failed = false;
+                true
+            }
+            Err(e) => {
+                if !*failed {
+                    tracing::warn!("Failed to connect the USB MIDI gadget to slot {slot}: {e}");
+                }
+                *failed = true;
+                false
+            }
         }
+    } else if gadget_port.is_none() && connected {
+        let names = midi_reader.connected_input_names();
+        let is_gadget = names
+            .get(slot)
+            .and_then(Option::as_ref)
+            .is_some_and(|name| midi::gadget::is_gadget_port(name));
+        is_gadget && midi_reader.disconnect_input(slot).is_ok()
     } else {
-        None
+        false
+    };
+    if changed {
+        info!("USB MIDI gadget connection changed (slot {slot})");
+        clients.broadcast(ServerMessageKind::ConnectedMidiInputs(
+            midi_reader.connected_input_names(),
+        ));
     }
 }
@@ -0,0 +1,122 @@
+// 14-bit controllers of MIDI 1.0
+//
+// Controllers 0 to 31 can get a second byte of resolution from the controller 32 higher. For
+// the pairs chosen to be merged the LSB doesn't go through on its own, it gives the MSB
+// controller again with both bytes in `Message::hi_res`, nodes without high resolution see the
+// MSB value they already had.
+
+use super::{ControlChangeKind, Message, MessageKind};
+
+const NUM_PAIRS: u8 = 32;
+const NUM_CHANNELS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Pairs([bool; NUM_PAIRS as usize]);
+
+impl Pairs {
+    // MSB controller numbers, the ones above 31 have no pair and are left out
+    pub fn new(msb_controllers: &[u8]) -> Self {
+        let mut pairs = Self::default();
+        for &number in msb_controllers {
+            if let Some(pair) = pairs.0.get_mut(number as usize) {
+                *pair = true;
+            }
+        }
+        pairs
+    }
+
+    pub fn msb_controllers(&self) -> Vec<u8> {
+        (0..NUM_PAIRS).filter(|&n| self.0[n as usize]).collect()
+    }
+
+    fn is_merged(&self, msb_number: u8) -> bool {
+        self.0.get(msb_number as usize).copied().unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Combiner {
+    // Last MSB of every pair of every channel
+    msbs: [[u8; NUM_PAIRS as usize]; NUM_CHANNELS],
+}
+
+impl Combiner {
+    pub fn push(&mut self, pairs: &Pairs, message: Message) -> Message {
+        let MessageKind::ControlChange { kind, value } = message.kind else {
+            return message;
+        };
+        // MIDI 2.0 controllers have the resolution already
+        if message.hi_res.is_some() {
+            return message;
+        }
+        let number = kind.as_number();
+        let msbs = &mut self.msbs[(message.channel & 0x0F) as usize];
+        if number < NUM_PAIRS && pairs.is_merged(number) {
+            // a new MSB starts with an LSB of 0
+            msbs[number as usize] = value;
+            let hi_res = upscale_14_to_32((value as u16) << 7);
+            return Message {
+                hi_res: Some(hi_res),
+                ..message
+            };
+        }
+        let msb_number = number.wrapping_sub(NUM_PAIRS);
+        if !(NUM_PAIRS..NUM_PAIRS * 2).contains(&number) || !pairs.is_merged(msb_number) {
+            return message;
+        }
+        let Some(msb_kind) = ControlChangeKind::from_number(msb_number) else {
+            return message;
+        };
+        let msb = msbs[msb_number as usize];
+        let hi_res = upscale_14_to_32(((msb as u16) << 7) | value as u16);
+        Message {
+            kind: MessageKind::ControlChange {
+                kind: msb_kind,
+                value: msb,
+            },
+            hi_res: Some(hi_res),
+            ..message
+        }
+    }
+}
+
+// The bits get repeated, so the maximum stays the maximum
+fn upscale_14_to_32(value: u16) -> u32 {
+    let value = (value & 0x3FFF) as u32;
+    (value << 18) | (value << 4) | (value >> 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Combiner, Pairs};
+    use crate::midi::{ControlChangeKind, Message, MessageKind};
+
+    fn cc(number: u8, value: u8) -> Message {
+        let kind = ControlChangeKind::from_number(number).unwrap();
+        Message::new(0, MessageKind::ControlChange { kind, value })
+    }
+
+    #[test]
+    fn expression_pedal() {
+        let pairs = Pairs::new(&[11, 40]);
+        assert_eq!(pairs.msb_controllers(), [11]);
+        let mut combiner = Combiner::default();
+        let msb = combiner.push(&pairs, cc(11, 64));
+        assert_eq!(msb.kind, cc(11, 64).kind);
+        assert_eq!(
+            msb.hi_res_normalized().map(|v| (v * 100.0).round()),
+            Some(50.0)
+        );
+
+        let lsb = combiner.push(&pairs, cc(43, 127));
+        assert_eq!(lsb.kind, cc(11, 64).kind);
+        assert!(lsb.hi_res > msb.hi_res);
+        combiner.push(&pairs, cc(11, 127));
+        let max = combiner.push(&pairs, cc(43, 127));
+        assert_eq!(max.hi_res, Some(u32::MAX));
+
+        // not merged, both go through as they are
+        assert_eq!(combiner.push(&pairs, cc(1, 5)), cc(1, 5));
+        assert_eq!(combiner.push(&pairs, cc(33, 5)), cc(33, 5));
+    }
+}
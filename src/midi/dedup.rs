@@ -0,0 +1,77 @@
+use super::Message;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// Drops messages that already arrived through another input slot shortly before,
+// which happens when one device ends up connected twice (directly and through a thru box)
+#[derive(Debug, Clone)]
+pub struct Deduplicator {
+    window: Duration,
+    recent: VecDeque<(usize, Message, Instant)>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn is_duplicate(&mut self, slot: usize, message: &Message, now: Instant) -> bool {
+        while let Some((_, _, time)) = self.recent.front() {
+            if now.duration_since(*time) > self.window {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+        let position = self
+            .recent
+            .iter()
+            .position(|(s, msg, _)| *s != slot && msg == message);
+        if let Some(position) = position {
+            // every copy swallows only one original
+            self.recent.remove(position);
+            true
+        } else {
+            self.recent.push_back((slot, message.clone(), now));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deduplicator;
+    use crate::midi::{Message, MessageKind};
+    use std::time::{Duration, Instant};
+
+    fn note_on(note: u8) -> Message {
+        Message::new(0, MessageKind::NoteOn { note, velocity: 90 })
+    }
+
+    #[test]
+    fn duplicates_across_slots() {
+        let mut dedup = Deduplicator::new(Duration::from_millis(5));
+        let msg = note_on(60);
+        let other = note_on(62);
+        let t0 = Instant::now();
+
+        assert!(!dedup.is_duplicate(0, &msg, t0));
+        // the same slot may repeat a message
+        assert!(!dedup.is_duplicate(0, &msg, t0));
+        assert!(!dedup.is_duplicate(1, &other, t0));
+        assert!(dedup.is_duplicate(1, &msg, t0 + Duration::from_millis(2)));
+        assert!(dedup.is_duplicate(1, &msg, t0 + Duration::from_millis(3)));
+        assert!(!dedup.is_duplicate(1, &msg, t0 + Duration::from_millis(4)));
+        // too late to be a copy
+        assert!(!dedup.is_duplicate(2, &other, t0 + Duration::from_millis(10)));
+    }
+}
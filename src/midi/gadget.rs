@@ -0,0 +1,40 @@
+// USB MIDI gadget support (Raspberry Pi in peripheral mode)
+//
+// When the `g_midi` (or configfs `f_midi`) gadget function is loaded, the host computer sees
+// the Pi as a class-compliant MIDI device and the Pi itself gets an ALSA raw MIDI port whose
+// name starts with `f_midi`. Events sent by the host arrive on that port, so connecting it
+// to a regular reader slot feeds them into the normal pipeline.
+
+use std::path::Path;
+
+const GADGET_PORT_PREFIX: &str = "f_midi";
+const UDC_CLASS_PATH: &str = "/sys/class/udc";
+
+pub fn is_gadget_port(port_name: &str) -> bool {
+    port_name.starts_with(GADGET_PORT_PREFIX)
+}
+
+pub fn find_gadget_port(port_names: &[String]) -> Option<&String> {
+    port_names.iter().find(|name| is_gadget_port(name))
+}
+
+// A USB device controller is only present when the board runs in peripheral (OTG) mode
+pub fn is_peripheral_mode_available() -> bool {
+    Path::new(UDC_CLASS_PATH)
+        .read_dir()
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn find_gadget_port() {
+        let ports = vec![
+            String::from("Midi Through:Midi Through Port-0 14:0"),
+            String::from("f_midi:f_midi 20:0"),
+        ];
+        assert_eq!(super::find_gadget_port(&ports), Some(&ports[1]));
+        assert_eq!(super::find_gadget_port(&ports[..1]), None);
+    }
+}
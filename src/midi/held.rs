@@ -0,0 +1,122 @@
+use super::{ControlChangeKind, Message, MessageKind, DEFAULT_RELEASE_VELOCITY};
+
+// The keys and pedals held down on every channel, so whoever stops listening halfway through
+// can let go of them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Held {
+    // A bit for every note
+    notes: [u128; 16],
+    // A bit for every channel
+    damper: u16,
+    sostenuto: u16,
+}
+
+impl Held {
+    pub fn push(&mut self, message: &Message) {
+        let channel = message.channel as usize & 0x0F;
+        let bit = 1u16 << channel;
+        match message.kind {
+            MessageKind::NoteOn { note, velocity } if velocity > 0 => {
+                self.notes[channel] |= 1 << (note & 0x7F);
+            }
+            MessageKind::NoteOn { note, .. } | MessageKind::NoteOff { note, .. } => {
+                self.notes[channel] &= !(1 << (note & 0x7F));
+            }
+            MessageKind::ControlChange { kind, value } => {
+                let pedal = match kind {
+                    ControlChangeKind::DamperPedal => &mut self.damper,
+                    ControlChangeKind::Sostenuto => &mut self.sostenuto,
+                    ControlChangeKind::AllNotesOff | ControlChangeKind::AllSoundsOff => {
+                        self.notes[channel] = 0;
+                        return;
+                    }
+                    _ => return,
+                };
+                if value >= 64 {
+                    *pedal |= bit;
+                } else {
+                    *pedal &= !bit;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // No key nor pedal down
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // The pedals up, then a note off for every key, after it nothing is held
+    pub fn release(&mut self) -> Vec<Message> {
+        let mut messages = vec![];
+        for (kind, pedal) in [
+            (ControlChangeKind::DamperPedal, self.damper),
+            (ControlChangeKind::Sostenuto, self.sostenuto),
+        ] {
+            for channel in (0..16).filter(|channel| pedal & (1 << channel) != 0) {
+                let kind = MessageKind::ControlChange { kind, value: 0 };
+                messages.push(Message::new(channel, kind));
+            }
+        }
+        self.damper = 0;
+        self.sostenuto = 0;
+        messages.extend(self.note_offs());
+        messages
+    }
+
+    // A note off for every key, the pedals stay down
+    pub fn note_offs(&mut self) -> Vec<Message> {
+        let mut messages = vec![];
+        for (channel, notes) in self.notes.iter().enumerate() {
+            for note in (0..128).filter(|note| notes & (1 << note) != 0) {
+                let kind = MessageKind::NoteOff {
+                    note,
+                    velocity: DEFAULT_RELEASE_VELOCITY,
+                };
+                messages.push(Message::new(channel as u8, kind));
+            }
+        }
+        self.notes = [0; 16];
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Held;
+    use crate::midi::{ControlChangeKind, Message, MessageKind, DEFAULT_RELEASE_VELOCITY};
+
+    #[test]
+    fn releases_what_is_held() {
+        let mut held = Held::default();
+        let note_on =
+            |channel, note, velocity| Message::new(channel, MessageKind::NoteOn { note, velocity });
+        let damper = |value| {
+            let kind = ControlChangeKind::DamperPedal;
+            Message::new(2, MessageKind::ControlChange { kind, value })
+        };
+        // a note on of velocity 0 is a note off
+        for msg in [
+            note_on(0, 60, 90),
+            note_on(0, 64, 90),
+            note_on(9, 127, 90),
+            damper(127),
+            note_on(0, 64, 0),
+        ] {
+            held.push(&msg);
+        }
+        assert!(!held.is_empty());
+
+        let note_off = |channel, note| {
+            let velocity = DEFAULT_RELEASE_VELOCITY;
+            Message::new(channel, MessageKind::NoteOff { note, velocity })
+        };
+        assert_eq!(
+            held.release(),
+            [damper(0), note_off(0, 60), note_off(9, 127)]
+        );
+        assert!(held.is_empty());
+        assert!(held.release().is_empty());
+    }
+}
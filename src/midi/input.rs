@@ -0,0 +1,230 @@
+// What happens to the messages of an input slot before anything else sees them. The settings
+// belong to the slot, not to the port, so they stay when a keyboard gets plugged in again.
+
+use super::{Message, MessageKind};
+use serde::{Deserialize, Serialize};
+
+const NUM_CHANNELS: usize = 16;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub channel_map: ChannelMap,
+    pub dropped: Dropped,
+    pub velocity_curve: VelocityCurve,
+}
+
+impl Settings {
+    // None when the message is dropped
+    pub fn apply(&self, message: Message) -> Option<Message> {
+        if self.dropped.drops(&message.kind) {
+            return None;
+        }
+        // the channel of a SysEx means nothing
+        if let MessageKind::SystemExclusive { .. } = message.kind {
+            return Some(message);
+        }
+        let message = self.velocity_curve.apply(message);
+        Some(Message {
+            channel: self.channel_map.map(message.channel),
+            ..message
+        })
+    }
+}
+
+// Evens out the feel of a keyboard for every instrument at once. An exponent above 1 takes
+// harder playing for loud notes, below 1 less, then the velocity is scaled into min to max.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VelocityCurve {
+    pub exponent: f32,
+    pub min: u8,
+    pub max: u8,
+}
+
+impl Default for VelocityCurve {
+    fn default() -> Self {
+        Self {
+            exponent: 1.0,
+            min: 0,
+            max: 127,
+        }
+    }
+}
+
+impl VelocityCurve {
+    fn apply(&self, message: Message) -> Message {
+        let MessageKind::NoteOn { note, velocity } = message.kind else {
+            return message;
+        };
+        // a note on without velocity is a note off
+        if velocity == 0 || *self == Self::default() {
+            return message;
+        }
+        let value = message
+            .hi_res_normalized()
+            .unwrap_or(velocity as f32 / 127.0);
+        let value = self.map(value);
+        Message {
+            kind: MessageKind::NoteOn {
+                note,
+                velocity: ((value * 127.0).round() as u8).clamp(1, 127),
+            },
+            hi_res: message
+                .hi_res
+                .map(|_| (value * u16::MAX as f32).round().max(1.0) as u32),
+            ..message
+        }
+    }
+
+    // From 0 to 1
+    fn map(&self, value: f32) -> f32 {
+        let min = self.min.min(127) as f32 / 127.0;
+        let max = self.max.min(127) as f32 / 127.0;
+        let curved = value.clamp(0.0, 1.0).powf(self.exponent.max(0.01));
+        min + (max - min) * curved
+    }
+}
+
+// Kinds of messages that never make it past the input, some controllers send aftertouch all
+// the time and keep the synths busy with it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Dropped {
+    // Channel and polyphonic
+    pub aftertouch: bool,
+    pub program_change: bool,
+    pub pitch_wheel: bool,
+    pub system_exclusive: bool,
+    // Controller numbers
+    pub control_changes: Vec<u8>,
+}
+
+impl Dropped {
+    fn drops(&self, kind: &MessageKind) -> bool {
+        match kind {
+            MessageKind::ChannelAftertouch { .. } | MessageKind::PolyphonicAftertouch { .. } => {
+                self.aftertouch
+            }
+            MessageKind::ProgramChange { .. } => self.program_change,
+            MessageKind::PitchWheel { .. } | MessageKind::PerNotePitchWheel { .. } => {
+                self.pitch_wheel
+            }
+            MessageKind::SystemExclusive { .. } => self.system_exclusive,
+            MessageKind::ControlChange { kind, .. } => {
+                self.control_changes.contains(&kind.as_number())
+            }
+            _ => false,
+        }
+    }
+}
+
+// The channel every channel of the input goes to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ChannelMap([u8; NUM_CHANNELS]);
+
+impl Default for ChannelMap {
+    fn default() -> Self {
+        Self(std::array::from_fn(|channel| channel as u8))
+    }
+}
+
+impl ChannelMap {
+    // Everything onto a single channel, for keyboards that only send on the first one
+    pub fn all_to(channel: u8) -> Self {
+        Self([channel & 0x0F; NUM_CHANNELS])
+    }
+
+    pub fn set(&mut self, from: u8, to: u8) {
+        self.0[(from & 0x0F) as usize] = to & 0x0F;
+    }
+
+    pub fn map(&self, channel: u8) -> u8 {
+        self.0[(channel & 0x0F) as usize] & 0x0F
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChannelMap, Dropped, Settings, VelocityCurve};
+    use crate::midi::{ControlChangeKind, Message, MessageKind};
+
+    #[test]
+    fn channel_map() {
+        let note_on = |channel| {
+            let kind = MessageKind::NoteOn {
+                note: 60,
+                velocity: 100,
+            };
+            Message::new(channel, kind)
+        };
+        let mut settings = Settings::default();
+        assert_eq!(settings.apply(note_on(3)), Some(note_on(3)));
+
+        settings.channel_map = ChannelMap::all_to(1);
+        assert_eq!(settings.apply(note_on(0)), Some(note_on(1)));
+        assert_eq!(settings.apply(note_on(9)), Some(note_on(1)));
+
+        settings.channel_map.set(9, 9);
+        assert_eq!(settings.apply(note_on(9)), Some(note_on(9)));
+        let sysex = Message::new(0, MessageKind::SystemExclusive { data: vec![1] });
+        assert_eq!(settings.apply(sysex.clone()), Some(sysex));
+    }
+
+    #[test]
+    fn velocity_curve() {
+        let note_on = |velocity| {
+            let kind = MessageKind::NoteOn { note: 60, velocity };
+            Message::new(0, kind)
+        };
+        let mut settings = Settings::default();
+        assert_eq!(settings.apply(note_on(50)), Some(note_on(50)));
+
+        // softer, squared and in the upper half
+        settings.velocity_curve = VelocityCurve {
+            exponent: 2.0,
+            min: 64,
+            max: 127,
+        };
+        assert_eq!(settings.apply(note_on(127)), Some(note_on(127)));
+        assert_eq!(settings.apply(note_on(1)), Some(note_on(64)));
+        assert_eq!(settings.apply(note_on(64)), Some(note_on(80)));
+        assert_eq!(settings.apply(note_on(0)), Some(note_on(0)));
+
+        // MIDI 2.0 velocities keep their resolution
+        let hi_res = Message::with_hi_res(0, note_on(64).kind, u16::MAX as u32);
+        let curved = settings.apply(hi_res).unwrap();
+        assert_eq!(curved.kind, note_on(127).kind);
+        assert_eq!(curved.hi_res, Some(u16::MAX as u32));
+    }
+
+    #[test]
+    fn dropped_messages() {
+        let settings = Settings {
+            dropped: Dropped {
+                aftertouch: true,
+                control_changes: vec![1],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let cc = |number| {
+            let kind = ControlChangeKind::from_number(number).unwrap();
+            Message::new(0, MessageKind::ControlChange { kind, value: 5 })
+        };
+        let pressure = Message::new(0, MessageKind::ChannelAftertouch { pressure: 5 });
+        let poly = Message::new(
+            0,
+            MessageKind::PolyphonicAftertouch {
+                note: 1,
+                pressure: 5,
+            },
+        );
+        assert_eq!(settings.apply(pressure), None);
+        assert_eq!(settings.apply(poly), None);
+        assert_eq!(settings.apply(cc(1)), None);
+        assert_eq!(settings.apply(cc(7)), Some(cc(7)));
+        let program = Message::new(0, MessageKind::ProgramChange { program: 5 });
+        assert_eq!(settings.apply(program.clone()), Some(program));
+    }
+}
@@ -1,11 +1,28 @@
+pub mod cc_pairs;
+pub mod dedup;
+pub mod gadget;
+pub mod held;
+pub mod input;
+pub mod monitor;
+pub mod mpe;
+pub mod parameter;
 mod reader;
 mod msg;
+pub mod parser;
+pub mod recorder;
+pub mod smf;
+pub mod trigger;
+pub mod ump;
+mod writer;
 
 pub use reader::ReaderError;
 pub use reader::MidiReader;
+pub use writer::MidiWriter;
+pub use writer::WriterError;
 pub use msg::ControlChangeKind;
 pub use msg::MessageKind;
 pub use msg::Message;
+pub use msg::DEFAULT_RELEASE_VELOCITY;
 
 pub type Sender = tokio::sync::broadcast::Sender<msg::Message>;
 pub type Receiver = tokio::sync::broadcast::Receiver<msg::Message>;
@@ -0,0 +1,151 @@
+// What the MIDI monitor of a client gets of the MIDI events. Every client sets it for itself,
+// so a slow connection doesn't get flooded during dense playing.
+
+use super::{Message, MessageKind};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+const THROTTLE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+    // On and off
+    Note,
+    PolyphonicAftertouch,
+    ControlChange,
+    ProgramChange,
+    ChannelAftertouch,
+    PitchWheel,
+    // Per-note controllers and pitch
+    PerNote,
+    // Registered and non-registered
+    Parameter,
+    SystemExclusive,
+}
+
+impl EventKind {
+    pub fn of(kind: &MessageKind) -> Self {
+        match kind {
+            MessageKind::NoteOn { .. } | MessageKind::NoteOff { .. } => Self::Note,
+            MessageKind::PolyphonicAftertouch { .. } => Self::PolyphonicAftertouch,
+            MessageKind::ControlChange { .. } => Self::ControlChange,
+            MessageKind::ProgramChange { .. } => Self::ProgramChange,
+            MessageKind::ChannelAftertouch { .. } => Self::ChannelAftertouch,
+            MessageKind::PitchWheel { .. } => Self::PitchWheel,
+            MessageKind::PerNoteController { .. } | MessageKind::PerNotePitchWheel { .. } => {
+                Self::PerNote
+            }
+            MessageKind::RegisteredParameter { .. }
+            | MessageKind::NonRegisteredParameter { .. } => Self::Parameter,
+            MessageKind::SystemExclusive { .. } => Self::SystemExclusive,
+        }
+    }
+}
+
+// (channel, kind) of an event, all the monitor needs to know about it
+pub type Event = (u8, EventKind);
+
+pub fn event_of(message: &Message) -> Event {
+    (message.channel, EventKind::of(&message.kind))
+}
+
+// `None` lets everything through
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub enabled: bool,
+    pub channels: Option<Vec<u8>>,
+    pub kinds: Option<Vec<EventKind>>,
+    // Events beyond it are dropped until the second is over
+    pub max_per_second: Option<u32>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            channels: None,
+            kinds: None,
+            max_per_second: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Monitor {
+    settings: Settings,
+    // Of the throttle, starts with the first event
+    window_start: Option<Instant>,
+    sent: u32,
+}
+
+impl Monitor {
+    pub fn set_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+    }
+
+    // Counts the event as sent when it passes
+    pub fn passes(&mut self, (channel, kind): Event, now: Instant) -> bool {
+        let settings = &self.settings;
+        let passes = settings.enabled
+            && settings
+                .channels
+                .as_ref()
+                .is_none_or(|channels| channels.contains(&channel))
+            && settings
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| kinds.contains(&kind));
+        if !passes {
+            return false;
+        }
+        let Some(max_per_second) = settings.max_per_second else {
+            return true;
+        };
+        let window_over = self
+            .window_start
+            .is_none_or(|start| now.saturating_duration_since(start) >= THROTTLE_WINDOW);
+        if window_over {
+            self.window_start = Some(now);
+            self.sent = 0;
+        }
+        if self.sent >= max_per_second {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventKind, Monitor, Settings};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn filters_and_throttles() {
+        let now = Instant::now();
+        let mut monitor = Monitor::default();
+        assert!(monitor.passes((9, EventKind::ChannelAftertouch), now));
+
+        monitor.set_settings(Settings {
+            channels: Some(vec![0]),
+            kinds: Some(vec![EventKind::Note, EventKind::ControlChange]),
+            max_per_second: Some(2),
+            ..Default::default()
+        });
+        assert!(!monitor.passes((1, EventKind::Note), now));
+        assert!(!monitor.passes((0, EventKind::ChannelAftertouch), now));
+        assert!(monitor.passes((0, EventKind::Note), now));
+        assert!(monitor.passes((0, EventKind::ControlChange), now));
+        assert!(!monitor.passes((0, EventKind::Note), now));
+        let later = now + Duration::from_secs(1);
+        assert!(monitor.passes((0, EventKind::Note), later));
+
+        monitor.set_settings(Settings {
+            enabled: false,
+            ..Default::default()
+        });
+        assert!(!monitor.passes((0, EventKind::Note), later));
+    }
+}
@@ -0,0 +1,214 @@
+// MIDI Polyphonic Expression
+//
+// Resources:
+// https://midi.org/mpe-midi-polyphonic-expression
+//
+// Every note of an MPE controller gets a member channel of its own, the channel wide messages of
+// that channel are the expression of the note. They're translated into the per-note messages
+// MIDI 2.0 sources send, so nodes handle both the same way.
+
+use super::{parameter::MPE_CONFIGURATION, ControlChangeKind, Message, MessageKind};
+use serde::{Deserialize, Serialize};
+
+// MPE and MIDI 2.0 both default to a per-note pitch bend range of 48 semitones
+pub const PER_NOTE_PITCH_BEND_RANGE: f32 = 48.0;
+
+// The third dimension (slide on a Seaboard, Y axis on a Linnstrument) is sent as CC74, as per-note
+// controller it's the assignable one with the same number
+pub const TIMBRE_CONTROLLER: u16 = 0x100 + 74;
+
+const NUM_CHANNELS: usize = 16;
+const LOWER_MASTER: u8 = 0;
+const UPPER_MASTER: u8 = 15;
+
+// Number of member channels of the lower zone (channels 2 up) and the upper zone (channels 15
+// down), 0 turns a zone off
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Zones {
+    pub lower: u8,
+    pub upper: u8,
+}
+
+impl Default for Zones {
+    // All but the master channel in the lower zone, what controllers start with
+    fn default() -> Self {
+        Self {
+            lower: 15,
+            upper: 0,
+        }
+    }
+}
+
+impl Zones {
+    pub fn is_member(&self, channel: u8) -> bool {
+        let lower = (1..=self.lower).contains(&channel);
+        let upper = self.upper > 0 && (UPPER_MASTER - self.upper..UPPER_MASTER).contains(&channel);
+        lower || upper
+    }
+
+    // A zone configured by the MPE Configuration Message takes channels away from the other one
+    fn configure(&mut self, master: u8, members: u8) {
+        let members = members.min(15);
+        if master == LOWER_MASTER {
+            self.lower = members;
+            self.upper = self.upper.min(14 - members.min(14));
+        } else {
+            self.upper = members;
+            self.lower = self.lower.min(14 - members.min(14));
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mpe {
+    zones: Zones,
+    // The note sounding on every member channel
+    notes: [Option<u8>; NUM_CHANNELS],
+}
+
+impl Mpe {
+    pub fn new(zones: Zones) -> Self {
+        Self {
+            zones,
+            notes: [None; NUM_CHANNELS],
+        }
+    }
+
+    pub fn zones(&self) -> Zones {
+        self.zones
+    }
+
+    // None for expression without a note to go with, controllers send their initial values
+    // before the note on and those are neutral anyway
+    pub fn translate(&mut self, message: Message) -> Option<Message> {
+        let channel = message.channel & 0x0F;
+        if channel == LOWER_MASTER || channel == UPPER_MASTER {
+            // the MPE Configuration Message, the MSB is the number of member channels
+            if let MessageKind::RegisteredParameter {
+                parameter: MPE_CONFIGURATION,
+                value,
+            } = message.kind
+            {
+                self.zones.configure(channel, (value >> 7) as u8);
+                self.notes = [None; NUM_CHANNELS];
+            }
+            return Some(message);
+        }
+        if !self.zones.is_member(channel) {
+            return Some(message);
+        }
+        let slot = &mut self.notes[channel as usize];
+        let kind = match message.kind {
+            MessageKind::NoteOn { note, velocity } if velocity > 0 => {
+                *slot = Some(note);
+                return Some(message);
+            }
+            MessageKind::NoteOn { note, .. } | MessageKind::NoteOff { note, .. } => {
+                if *slot == Some(note) {
+                    *slot = None;
+                }
+                return Some(message);
+            }
+            MessageKind::PitchWheel { value } => MessageKind::PerNotePitchWheel {
+                note: (*slot)?,
+                value,
+            },
+            MessageKind::ChannelAftertouch { pressure } => MessageKind::PolyphonicAftertouch {
+                note: (*slot)?,
+                pressure,
+            },
+            MessageKind::ControlChange {
+                kind: ControlChangeKind::SoundController5,
+                value,
+            } => MessageKind::PerNoteController {
+                note: (*slot)?,
+                index: TIMBRE_CONTROLLER,
+                value,
+            },
+            _ => return Some(message),
+        };
+        Some(Message { kind, ..message })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mpe, Zones, TIMBRE_CONTROLLER};
+    use crate::midi::{parameter::MPE_CONFIGURATION, ControlChangeKind, Message, MessageKind};
+
+    fn kinds(mpe: &mut Mpe, messages: &[(u8, MessageKind)]) -> Vec<Option<MessageKind>> {
+        messages
+            .iter()
+            .map(|(channel, kind)| {
+                mpe.translate(Message::new(*channel, kind.clone()))
+                    .map(|m| m.kind)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn member_channels_carry_per_note_expression() {
+        let mut mpe = Mpe::new(Zones::default());
+        let note_on = MessageKind::NoteOn {
+            note: 60,
+            velocity: 90,
+        };
+        let bend = MessageKind::PitchWheel { value: 9000 };
+        let timbre = MessageKind::ControlChange {
+            kind: ControlChangeKind::SoundController5,
+            value: 100,
+        };
+        let out = kinds(
+            &mut mpe,
+            &[
+                (1, bend.clone()),
+                (1, note_on.clone()),
+                (1, bend.clone()),
+                (1, MessageKind::ChannelAftertouch { pressure: 30 }),
+                (1, timbre),
+                (0, bend.clone()),
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                None,
+                Some(note_on),
+                Some(MessageKind::PerNotePitchWheel {
+                    note: 60,
+                    value: 9000
+                }),
+                Some(MessageKind::PolyphonicAftertouch {
+                    note: 60,
+                    pressure: 30
+                }),
+                Some(MessageKind::PerNoteController {
+                    note: 60,
+                    index: TIMBRE_CONTROLLER,
+                    value: 100
+                }),
+                // the master channel bends every note
+                Some(bend),
+            ]
+        );
+    }
+
+    #[test]
+    fn configuration_message() {
+        let mut mpe = Mpe::new(Zones::default());
+        let members = |count: u16| MessageKind::RegisteredParameter {
+            parameter: MPE_CONFIGURATION,
+            value: count << 7,
+        };
+        // upper zone with 5 members, channels 10 to 14
+        kinds(&mut mpe, &[(15, members(5))]);
+        assert_eq!(mpe.zones(), Zones { lower: 9, upper: 5 });
+        assert!(mpe.zones().is_member(10) && !mpe.zones().is_member(15));
+        assert!(mpe.zones().is_member(9));
+
+        kinds(&mut mpe, &[(0, members(0))]);
+        assert!(!mpe.zones().is_member(1));
+        let out = kinds(&mut mpe, &[(1, MessageKind::PitchWheel { value: 0 })]);
+        assert_eq!(out, [Some(MessageKind::PitchWheel { value: 0 })]);
+    }
+}
@@ -3,8 +3,12 @@
 // https://www.midi.org/specifications-old/item/table-3-control-change-messages-data-bytes-2
 
 use serde::{Deserialize, Serialize};
+use std::time::Instant;
 
-#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+// Release velocity to use when the source doesn't provide one (as the MIDI spec suggests)
+pub const DEFAULT_RELEASE_VELOCITY: u8 = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MessageKind {
     NoteOff { note: u8, velocity: u8 },
     NoteOn { note: u8, velocity: u8 },
@@ -13,6 +17,16 @@ pub enum MessageKind {
     ProgramChange { program: u8 },
     ChannelAftertouch { pressure: u8 },
     PitchWheel { value: u16 },
+    // MIDI 2.0 only (no MIDI 1.0 equivalent), values are downscaled to 7/14 bits,
+    // controller `index` 0..=255 are registered controllers, 256..=511 assignable ones
+    PerNoteController { note: u8, index: u16, value: u8 },
+    PerNotePitchWheel { note: u8, value: u16 },
+    // A whole RPN/NRPN data entry, 14-bit parameter and value. From MIDI 1.0 sources it follows
+    // the controllers it was assembled from, MIDI 2.0 sends it as a message of its own.
+    RegisteredParameter { parameter: u16, value: u16 },
+    NonRegisteredParameter { parameter: u16, value: u16 },
+    // The bytes between 0xF0 and 0xF7, the channel of the message means nothing
+    SystemExclusive { data: Vec<u8> },
 }
 
 impl MessageKind {
@@ -25,6 +39,13 @@ impl MessageKind {
             MessageKind::ProgramChange { .. } => 0xC0,
             MessageKind::ChannelAftertouch { .. } => 0xD0,
             MessageKind::PitchWheel { .. } => 0xE0,
+            // MIDI 2.0 channel voice opcodes
+            MessageKind::PerNoteController { index, .. } if index < 0x100 => 0x00,
+            MessageKind::PerNoteController { .. } => 0x10,
+            MessageKind::PerNotePitchWheel { .. } => 0x60,
+            MessageKind::RegisteredParameter { .. } => 0x20,
+            MessageKind::NonRegisteredParameter { .. } => 0x30,
+            MessageKind::SystemExclusive { .. } => 0xF0,
         }
     }
 }
@@ -430,13 +451,68 @@ impl ControlChangeKind {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Message {
     pub kind: MessageKind,
     pub channel: u8,
+    // Full resolution value of the message when it came from a MIDI 2.0 source:
+    // 16 bits for note velocities, 32 bits for controllers, pressure and pitch wheels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hi_res: Option<u32>,
+    // When the message came in, messages made up by AMI itself have none
+    #[serde(skip)]
+    pub time: Option<Instant>,
+    // The input slot it came in on
+    #[serde(skip)]
+    pub slot: Option<usize>,
 }
 
 impl Message {
+    pub fn new(channel: u8, kind: MessageKind) -> Self {
+        Self {
+            kind,
+            channel,
+            hi_res: None,
+            time: None,
+            slot: None,
+        }
+    }
+
+    pub fn with_hi_res(channel: u8, kind: MessageKind, hi_res: u32) -> Self {
+        Self {
+            kind,
+            channel,
+            hi_res: Some(hi_res),
+            time: None,
+            slot: None,
+        }
+    }
+
+    pub fn at(self, time: Instant) -> Self {
+        Self {
+            time: Some(time),
+            ..self
+        }
+    }
+
+    pub fn from_slot(self, slot: usize) -> Self {
+        Self {
+            slot: Some(slot),
+            ..self
+        }
+    }
+
+    // High resolution value mapped to 0.0..=1.0
+    pub fn hi_res_normalized(&self) -> Option<f32> {
+        let value = self.hi_res?;
+        match self.kind {
+            MessageKind::NoteOn { .. } | MessageKind::NoteOff { .. } => {
+                Some(value as f32 / u16::MAX as f32)
+            }
+            _ => Some((value as f64 / u32::MAX as f64) as f32),
+        }
+    }
+
     pub fn decode(bytes: &[u8]) -> Option<Self> {
         if bytes.is_empty() {
             None
@@ -445,6 +521,36 @@ impl Message {
         }
     }
 
+    // The MIDI 1.0 bytes of the message, MIDI 2.0 only messages have none and neither have
+    // parameters, they take a sequence of controllers
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let status = self.kind.as_number() | (self.channel & 0x0F);
+        let bytes = match self.kind {
+            MessageKind::SystemExclusive { ref data } => {
+                let mut bytes = Vec::with_capacity(data.len() + 2);
+                bytes.push(0xF0);
+                bytes.extend_from_slice(data);
+                bytes.push(0xF7);
+                bytes
+            }
+            MessageKind::NoteOff { note, velocity } | MessageKind::NoteOn { note, velocity } => {
+                vec![status, note, velocity]
+            }
+            MessageKind::PolyphonicAftertouch { note, pressure } => vec![status, note, pressure],
+            MessageKind::ControlChange { kind, value } => vec![status, kind.as_number(), value],
+            MessageKind::ProgramChange { program } => vec![status, program],
+            MessageKind::ChannelAftertouch { pressure } => vec![status, pressure],
+            MessageKind::PitchWheel { value } => {
+                vec![status, (value & 0x7F) as u8, ((value >> 7) & 0x7F) as u8]
+            }
+            MessageKind::PerNoteController { .. }
+            | MessageKind::PerNotePitchWheel { .. }
+            | MessageKind::RegisteredParameter { .. }
+            | MessageKind::NonRegisteredParameter { .. } => return None,
+        };
+        Some(bytes)
+    }
+
     pub fn get_pitch_wheel_signed(value: u16) -> i16 {
         (value as i16) - 8192
     }
@@ -461,6 +567,10 @@ impl Message {
 }
 
 fn decode_non_empty_message(bytes: &[u8]) -> Option<Message> {
+    if let [0xF0, data @ .., 0xF7] = bytes {
+        let data = data.to_vec();
+        return Some(Message::new(0, MessageKind::SystemExclusive { data }));
+    }
     let cmd = bytes[0] & 0xF0;
     let channel = bytes[0] & 0x0F;
     let kind = match cmd {
@@ -473,7 +583,7 @@ fn decode_non_empty_message(bytes: &[u8]) -> Option<Message> {
         0xE0 => parse_pitch_wheel(bytes)?,
         _ => None?,
     };
-    Some(Message { kind, channel })
+    Some(Message::new(channel, kind))
 }
 
 fn parse_note_on(bytes: &[u8]) -> Option<MessageKind> {
@@ -482,9 +592,10 @@ fn parse_note_on(bytes: &[u8]) -> Option<MessageKind> {
     } else {
         let velocity = bytes[2];
         if velocity == 0 {
+            // a zero velocity note on carries no release velocity
             Some(MessageKind::NoteOff {
                 note: bytes[1],
-                velocity,
+                velocity: DEFAULT_RELEASE_VELOCITY,
             })
         } else {
             Some(MessageKind::NoteOn {
@@ -0,0 +1,163 @@
+// Registered and non-registered parameters (RPN/NRPN) of MIDI 1.0
+//
+// Resources:
+// https://www.midi.org/specifications-old/item/table-3-control-change-messages-data-bytes-2
+//
+// A parameter gets selected with CC101/100 (RPN) or CC99/98 (NRPN) and set with data entry,
+// CC6 and optionally CC38. The controllers of each channel are followed and every data entry
+// gives a single message with the whole parameter number and value.
+
+use super::{ControlChangeKind, Message, MessageKind};
+
+pub const PITCH_BEND_SENSITIVITY: u16 = 0x0000;
+pub const FINE_TUNING: u16 = 0x0001;
+pub const COARSE_TUNING: u16 = 0x0002;
+pub const MPE_CONFIGURATION: u16 = 0x0006;
+// Selecting it stops data entry from changing the parameter selected before
+pub const NULL: u16 = 0x3FFF;
+
+const NUM_CHANNELS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Registered,
+    NonRegistered,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Channel {
+    selected: Option<Kind>,
+    parameter_msb: u8,
+    parameter_lsb: u8,
+    value_msb: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Assembler {
+    channels: [Channel; NUM_CHANNELS],
+}
+
+impl Assembler {
+    // The parameter message completed by the message, it comes after the controller itself.
+    // The value is 14 bits, data entry MSB alone gives it with an LSB of 0.
+    pub fn push(&mut self, message: &Message) -> Option<Message> {
+        let MessageKind::ControlChange { kind, value } = message.kind else {
+            return None;
+        };
+        let channel = &mut self.channels[(message.channel & 0x0F) as usize];
+        let select = |channel: &mut Channel, kind| {
+            if channel.selected != Some(kind) {
+                channel.selected = Some(kind);
+                channel.parameter_msb = 0;
+                channel.parameter_lsb = 0;
+            }
+            channel.value_msb = None;
+        };
+        match kind {
+            ControlChangeKind::RegisteredParameterNumberMsb => {
+                select(channel, Kind::Registered);
+                channel.parameter_msb = value;
+            }
+            ControlChangeKind::RegisteredParameterNumberLsb => {
+                select(channel, Kind::Registered);
+                channel.parameter_lsb = value;
+            }
+            ControlChangeKind::NonRegisteredParameterNumberMsb => {
+                select(channel, Kind::NonRegistered);
+                channel.parameter_msb = value;
+            }
+            ControlChangeKind::NonRegisteredParameterNumberLsb => {
+                select(channel, Kind::NonRegistered);
+                channel.parameter_lsb = value;
+            }
+            ControlChangeKind::DataEntryMsb => {
+                channel.value_msb = Some(value);
+                return parameter_message(message.channel, channel, combine(value, 0));
+            }
+            ControlChangeKind::DataEntryLsb => {
+                let value = combine(channel.value_msb?, value);
+                return parameter_message(message.channel, channel, value);
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+fn parameter_message(channel_number: u8, channel: &Channel, value: u16) -> Option<Message> {
+    let parameter = combine(channel.parameter_msb, channel.parameter_lsb);
+    if parameter == NULL {
+        return None;
+    }
+    let kind = match channel.selected? {
+        Kind::Registered => MessageKind::RegisteredParameter { parameter, value },
+        Kind::NonRegistered => MessageKind::NonRegisteredParameter { parameter, value },
+    };
+    Some(Message::new(channel_number, kind))
+}
+
+fn combine(msb: u8, lsb: u8) -> u16 {
+    ((msb as u16 & 0x7F) << 7) | (lsb as u16 & 0x7F)
+}
+
+// Semitones of a pitch bend sensitivity value, the LSB is in cents
+pub fn pitch_bend_range_of(value: u16) -> f32 {
+    (value >> 7) as f32 + (value & 0x7F) as f32 / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pitch_bend_range_of, Assembler, PITCH_BEND_SENSITIVITY};
+    use crate::midi::{ControlChangeKind, Message, MessageKind};
+
+    fn push_all(assembler: &mut Assembler, channel: u8, ccs: &[(u8, u8)]) -> Vec<MessageKind> {
+        ccs.iter()
+            .filter_map(|&(number, value)| {
+                let kind = MessageKind::ControlChange {
+                    kind: ControlChangeKind::from_number(number).unwrap(),
+                    value,
+                };
+                assembler.push(&Message::new(channel, kind))
+            })
+            .map(|message| message.kind)
+            .collect()
+    }
+
+    #[test]
+    fn pitch_bend_range() {
+        let mut assembler = Assembler::default();
+        let out = push_all(&mut assembler, 3, &[(101, 0), (100, 0), (6, 12), (38, 50)]);
+        assert_eq!(
+            out,
+            [
+                MessageKind::RegisteredParameter {
+                    parameter: PITCH_BEND_SENSITIVITY,
+                    value: 12 << 7
+                },
+                MessageKind::RegisteredParameter {
+                    parameter: PITCH_BEND_SENSITIVITY,
+                    value: (12 << 7) | 50
+                },
+            ]
+        );
+        assert_eq!(pitch_bend_range_of((12 << 7) | 50), 12.5);
+        // the other channels have nothing selected
+        assert!(push_all(&mut assembler, 4, &[(6, 2)]).is_empty());
+    }
+
+    #[test]
+    fn nrpn_and_null() {
+        let mut assembler = Assembler::default();
+        let out = push_all(&mut assembler, 0, &[(99, 1), (98, 8), (6, 64)]);
+        assert_eq!(
+            out,
+            [MessageKind::NonRegisteredParameter {
+                parameter: (1 << 7) | 8,
+                value: 64 << 7
+            }]
+        );
+        // a new selection needs a new data entry MSB before the LSB counts
+        assert!(push_all(&mut assembler, 0, &[(101, 0), (38, 1)]).is_empty());
+        assert!(push_all(&mut assembler, 0, &[(101, 127), (100, 127), (6, 1)]).is_empty());
+    }
+}
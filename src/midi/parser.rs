@@ -0,0 +1,182 @@
+// Byte stream MIDI 1.0 parser, for sources that don't deliver one message at a time
+// Resources:
+// https://www.midi.org/specifications-old/item/table-1-summary-of-midi-message
+
+use super::{Message, MessageKind};
+
+pub const ACTIVE_SENSING: u8 = 0xFE;
+
+// Longer SysEx messages are dropped, patch dumps of most devices are well below
+pub const MAX_SYSEX_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Default, Clone)]
+pub struct Parser {
+    status: Option<u8>,
+    data: [u8; 2],
+    len: usize,
+    // The SysEx data so far, none when it's too long or there's no SysEx
+    sysex: Option<Vec<u8>>,
+    in_sysex: bool,
+}
+
+impl Parser {
+    pub fn push(&mut self, byte: u8) -> Option<Message> {
+        match byte {
+            // realtime messages can be interleaved anywhere, even inside other messages,
+            // and must not affect the running status
+            0xF8..=0xFF => None,
+            0xF0 => {
+                self.status = None;
+                self.in_sysex = true;
+                self.sysex = Some(vec![]);
+                None
+            }
+            0xF7 => {
+                self.status = None;
+                self.in_sysex = false;
+                let data = self.sysex.take()?;
+                Some(Message::new(0, MessageKind::SystemExclusive { data }))
+            }
+            // channel messages set the running status, system common messages clear it,
+            // a SysEx they interrupt is lost
+            0x80..=0xF6 => {
+                self.in_sysex = false;
+                self.sysex = None;
+                self.len = 0;
+                self.status = (data_len(byte) > 0).then_some(byte);
+                None
+            }
+            _ => self.push_data(byte),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn push_data(&mut self, byte: u8) -> Option<Message> {
+        if self.in_sysex {
+            if let Some(sysex) = &mut self.sysex {
+                sysex.push(byte);
+                if sysex.len() > MAX_SYSEX_LEN {
+                    self.sysex = None;
+                }
+            }
+            return None;
+        }
+        let status = self.status?;
+        self.data[self.len] = byte;
+        self.len += 1;
+        let len = data_len(status);
+        if self.len < len {
+            return None;
+        }
+        self.len = 0;
+        if status >= 0xF0 {
+            self.status = None;
+            None
+        } else {
+            let mut bytes = [status, 0, 0];
+            bytes[1..=len].copy_from_slice(&self.data[..len]);
+            Message::decode(&bytes[..=len])
+        }
+    }
+}
+
+fn data_len(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        0x80..=0xEF | 0xF2 => 2,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Parser;
+    use crate::midi::{ControlChangeKind, Message, MessageKind};
+
+    fn parse(bytes: &[u8]) -> Vec<Message> {
+        let mut parser = Parser::default();
+        bytes.iter().filter_map(|&b| parser.push(b)).collect()
+    }
+
+    fn note_on(note: u8, velocity: u8) -> Message {
+        Message::new(0, MessageKind::NoteOn { note, velocity })
+    }
+
+    #[test]
+    fn running_status() {
+        assert_eq!(
+            parse(&[0x90, 60, 100, 62, 101, 0xC1, 5, 6]),
+            vec![
+                note_on(60, 100),
+                note_on(62, 101),
+                Message::new(1, MessageKind::ProgramChange { program: 5 }),
+                Message::new(1, MessageKind::ProgramChange { program: 6 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn interleaved_realtime() {
+        assert_eq!(
+            parse(&[0x90, 0xF8, 60, 0xFE, 100, 0xFA, 62, 101]),
+            vec![note_on(60, 100), note_on(62, 101)]
+        );
+    }
+
+    #[test]
+    fn system_messages_clear_running_status() {
+        // SysEx and song select in the middle of a running status stream
+        assert_eq!(
+            parse(&[0x90, 60, 100, 0xF0, 1, 2, 3, 0xF7, 62, 101, 0xF3, 4, 64, 0]),
+            vec![
+                note_on(60, 100),
+                Message::new(
+                    0,
+                    MessageKind::SystemExclusive {
+                        data: vec![1, 2, 3]
+                    }
+                )
+            ]
+        );
+        assert_eq!(
+            parse(&[0xB2, 123, 0]),
+            vec![Message::new(
+                2,
+                MessageKind::ControlChange {
+                    kind: ControlChangeKind::AllNotesOff,
+                    value: 0
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn system_exclusive() {
+        let sysex = Message::new(
+            0,
+            MessageKind::SystemExclusive {
+                data: vec![0x7E, 0x7F, 0x09, 0x01],
+            },
+        );
+        // GM on, with a clock in the middle
+        let bytes = [0xF0, 0x7E, 0x7F, 0xF8, 0x09, 0x01, 0xF7];
+        assert_eq!(parse(&bytes), vec![sysex.clone()]);
+        assert_eq!(
+            sysex.encode().unwrap(),
+            [0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]
+        );
+        assert_eq!(Message::decode(&sysex.encode().unwrap()), Some(sysex));
+        // cut short by a note on
+        assert_eq!(
+            parse(&[0xF0, 1, 2, 0x90, 60, 100, 0xF7]),
+            vec![note_on(60, 100)]
+        );
+        let mut long = vec![0xF0];
+        long.resize(super::MAX_SYSEX_LEN + 2, 0x01);
+        long.push(0xF7);
+        assert!(parse(&long).is_empty());
+    }
+}
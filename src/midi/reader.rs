@@ -1,8 +1,31 @@
-use std::{error::Error, fmt};
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::Read,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use midir::MidiInput;
 
-use super::{Message, Sender};
+use super::{
+    cc_pairs::{Combiner, Pairs},
+    dedup::Deduplicator,
+    gadget, input,
+    parameter::Assembler,
+    held::Held,
+    parser::{self, Parser},
+    ump, ControlChangeKind, Message, MessageKind, Sender,
+};
+
+// The MIDI spec allows at most 300 ms between messages once Active Sensing was received
+pub const DEFAULT_ACTIVE_SENSING_TIMEOUT: Duration = Duration::from_millis(300);
 
 pub type Result<T> = std::result::Result<T, ReaderError>;
 
@@ -23,39 +46,235 @@ impl fmt::Display for ReaderError {
     }
 }
 
+#[derive(Debug)]
+struct ActiveSensing {
+    enabled: bool,
+    last_activity: Instant,
+}
+
+// A MIDI 2.0 endpoint read by a thread of its own, it stops with the first read after the drop
+struct UmpInput {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for UmpInput {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// Only held to keep the input open
+#[allow(dead_code)]
+enum Input {
+    Port(midir::MidiInputConnection<()>),
+    Ump(UmpInput),
+}
+
+struct Connection {
+    name: String,
+    sensing: Arc<Mutex<ActiveSensing>>,
+    // What the input holds down, let go of when it disconnects
+    held: Arc<Mutex<Held>>,
+    _input: Input,
+}
+
 pub struct MidiReader {
-    connections: Vec<Option<(String, midir::MidiInputConnection<()>)>>,
+    connections: Vec<Option<Connection>>,
     tx: Sender,
+    active_sensing_timeout: Option<Duration>,
+    dedup: Arc<Mutex<Option<Deduplicator>>>,
+    cc_pairs: Arc<Mutex<Pairs>>,
+    // Of every slot
+    settings: Arc<Mutex<Vec<input::Settings>>>,
 }
 
 impl MidiReader {
     pub fn with_slots(tx: Sender, num_of_slots: usize) -> Self {
         let mut connections = vec![];
         connections.resize_with(num_of_slots, || None);
-        Self { connections, tx }
+        Self {
+            connections,
+            tx,
+            active_sensing_timeout: Some(DEFAULT_ACTIVE_SENSING_TIMEOUT),
+            dedup: Default::default(),
+            cc_pairs: Default::default(),
+            settings: Arc::new(Mutex::new(vec![Default::default(); num_of_slots])),
+        }
+    }
+
+    pub fn set_input_settings(&mut self, slot: usize, settings: input::Settings) -> Result<()> {
+        let mut all = self
+            .settings
+            .lock()
+            .map_err(|_| ReaderError::InvalidSlot(slot))?;
+        let slot_settings = all.get_mut(slot).ok_or(ReaderError::InvalidSlot(slot))?;
+        *slot_settings = settings;
+        Ok(())
+    }
+
+    pub fn input_settings(&self) -> Vec<input::Settings> {
+        self.settings
+            .lock()
+            .map(|settings| settings.clone())
+            .unwrap_or_default()
+    }
+
+    // MSB controllers (0 to 31) merged with their LSB controller into 14-bit values
+    pub fn set_merged_cc_pairs(&mut self, msb_controllers: &[u8]) {
+        if let Ok(mut pairs) = self.cc_pairs.lock() {
+            *pairs = Pairs::new(msb_controllers);
+        }
+    }
+
+    pub fn merged_cc_pairs(&self) -> Vec<u8> {
+        self.cc_pairs
+            .lock()
+            .map(|pairs| pairs.msb_controllers())
+            .unwrap_or_default()
+    }
+
+    // Identical messages coming from different slots within the window are dropped,
+    // None turns the deduplication off
+    pub fn set_dedup_window(&mut self, window: Option<Duration>) {
+        if let Ok(mut dedup) = self.dedup.lock() {
+            *dedup = window.map(Deduplicator::new);
+        }
+    }
+
+    pub fn dedup_window(&self) -> Option<Duration> {
+        self.dedup
+            .lock()
+            .ok()
+            .and_then(|dedup| dedup.as_ref().map(|d| d.window()))
+    }
+
+    // None disables the automatic all notes off when a sensing device goes silent
+    pub fn set_active_sensing_timeout(&mut self, timeout: Option<Duration>) {
+        self.active_sensing_timeout = timeout;
+    }
+
+    // Sends all notes off on every channel for each device that stopped sending
+    // Active Sensing, sensing is re-armed by the next Active Sensing message
+    pub fn check_active_sensing(&mut self) {
+        let Some(timeout) = self.active_sensing_timeout else {
+            return;
+        };
+        for (slot, con) in self.connections.iter().enumerate() {
+            let Some(con) = con else {
+                continue;
+            };
+            let timed_out = if let Ok(mut sensing) = con.sensing.lock() {
+                let timed_out = sensing.enabled && sensing.last_activity.elapsed() > timeout;
+                if timed_out {
+                    sensing.enabled = false;
+                }
+                timed_out
+            } else {
+                false
+            };
+            if timed_out {
+                tracing::warn!("MIDI input {} went silent, sending all notes off", con.name);
+                for channel in 0..16 {
+                    let kind = MessageKind::ControlChange {
+                        kind: ControlChangeKind::AllNotesOff,
+                        value: 0,
+                    };
+                    let msg = Message::new(channel, kind).from_slot(slot);
+                    _ = self.tx.send(msg.at(Instant::now()));
+                }
+            }
+        }
     }
 
+    // MIDI 1.0 ports followed by the MIDI 2.0 endpoints
     pub fn get_available_ports() -> Vec<String> {
-        midir::MidiInput::new("")
+        let mut ports = midir::MidiInput::new("")
             .map(get_available_ports_of)
-            .unwrap_or_else(|_| vec![])
+            .unwrap_or_else(|_| vec![]);
+        ports.extend(ump::available_ports());
+        ports
     }
 
     pub fn connect_input(&mut self, slot: usize, port_name: &str) -> Result<()> {
+        if let Some(device) = ump::device_of_port(port_name) {
+            return self.connect_ump_input(slot, port_name, device);
+        }
+        let output = self.output(slot);
+        let held = Arc::clone(&output.held);
         if let Some(con) = self.connections.get_mut(slot) {
             let midi_in = midir::MidiInput::new("").map_err(|_| ReaderError::ConnectError)?;
             let index = get_port_index(&midi_in, port_name).ok_or(ReaderError::ConnectError)?;
-            let conn = connect_midi_in_to_port(midi_in, index, self.tx.clone())?;
-            *con = Some((port_name.into(), conn));
+            let sensing = Arc::new(Mutex::new(ActiveSensing {
+                enabled: false,
+                last_activity: Instant::now(),
+            }));
+            let conn = connect_midi_in_to_port(midi_in, index, Arc::clone(&sensing), output)?;
+            *con = Some(Connection {
+                name: port_name.into(),
+                sensing,
+                held,
+                _input: Input::Port(conn),
+            });
             Ok(())
         } else {
             Err(ReaderError::InvalidSlot(slot))
         }
     }
 
+    fn connect_ump_input(&mut self, slot: usize, port_name: &str, device: &Path) -> Result<()> {
+        let output = self.output(slot);
+        let held = Arc::clone(&output.held);
+        let Some(con) = self.connections.get_mut(slot) else {
+            return Err(ReaderError::InvalidSlot(slot));
+        };
+        let file = File::open(device).map_err(|_| ReaderError::ConnectError)?;
+        // MIDI 2.0 has no Active Sensing, it stays disabled
+        let sensing = Arc::new(Mutex::new(ActiveSensing {
+            enabled: false,
+            last_activity: Instant::now(),
+        }));
+        let input = read_ump_device(file, output)?;
+        *con = Some(Connection {
+            name: port_name.into(),
+            sensing,
+            held,
+            _input: Input::Ump(input),
+        });
+        Ok(())
+    }
+
+    fn output(&self, slot: usize) -> Output {
+        Output {
+            slot,
+            tx: self.tx.clone(),
+            dedup: Arc::clone(&self.dedup),
+            cc_pairs: Arc::clone(&self.cc_pairs),
+            settings: Arc::clone(&self.settings),
+            parameters: Default::default(),
+            controllers: Default::default(),
+            held: Default::default(),
+        }
+    }
+
+    pub fn connect_gadget_input(&mut self, slot: usize) -> Result<()> {
+        let ports = Self::get_available_ports();
+        let port_name = gadget::find_gadget_port(&ports).ok_or(ReaderError::ConnectError)?;
+        self.connect_input(slot, port_name)
+    }
+
+    pub fn is_slot_connected(&self, slot: usize) -> bool {
+        matches!(self.connections.get(slot), Some(Some(_)))
+    }
+
+    // The notes and pedals the input holds are let go of, so they don't hang
     pub fn disconnect_input(&mut self, slot: usize) -> Result<()> {
         if let Some(con) = self.connections.get_mut(slot) {
-            *con = None;
+            if let Some(con) = con.take() {
+                let messages = con.held.lock().map(|mut held| held.release());
+                for msg in messages.unwrap_or_default() {
+                    _ = self.tx.send(msg.from_slot(slot).at(Instant::now()));
+                }
+            }
             Ok(())
         } else {
             Err(ReaderError::InvalidSlot(slot))
@@ -65,7 +284,7 @@ impl MidiReader {
     pub fn connected_input_names(&self) -> Vec<Option<String>> {
         self.connections
             .iter()
-            .map(|opt| opt.as_ref().map(|(s, _)| s.clone()))
+            .map(|opt| opt.as_ref().map(|con| con.name.clone()))
             .collect()
     }
 }
@@ -91,17 +310,24 @@ fn get_port_index(midi_in: &MidiInput, port_name: &str) -> Option<usize> {
 fn connect_midi_in_to_port(
     midi_in: MidiInput,
     port_index: usize,
-    tx: Sender,
+    sensing: Arc<Mutex<ActiveSensing>>,
+    mut output: Output,
 ) -> Result<midir::MidiInputConnection<()>> {
     let ports = midi_in.ports();
+    let mut parser = Parser::default();
     midi_in
         .connect(
             &ports[port_index],
             "",
             move |_, message, _| {
-                if let Some(msg) = Message::decode(message) {
-                    if tx.receiver_count() > 0 {
-                        _ = tx.send(msg);
+                let received = Instant::now();
+                if let Ok(mut sensing) = sensing.lock() {
+                    sensing.last_activity = received;
+                    sensing.enabled |= message.contains(&parser::ACTIVE_SENSING);
+                }
+                for &byte in message {
+                    if let Some(msg) = parser.push(byte) {
+                        output.send(msg, received);
                     }
                 }
             },
@@ -109,3 +335,92 @@ fn connect_midi_in_to_port(
         )
         .map_err(|_| ReaderError::ConnectError)
 }
+
+// Reads of a UMP device return whole packets
+fn read_ump_device(mut file: File, mut output: Output) -> Result<UmpInput> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::clone(&stop);
+    thread::Builder::new()
+        .name("ump-input".into())
+        .spawn(move || {
+            let mut buf = [0u8; 256];
+            while !stopped.load(Ordering::Relaxed) {
+                let len = match file.read(&mut buf) {
+                    Ok(len) if len > 0 => len,
+                    _ => break,
+                };
+                let received = Instant::now();
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let words = ump::words_from_bytes(&buf[..len]);
+                for msg in ump::decode_stream(&words) {
+                    output.send(msg, received);
+                }
+            }
+        })
+        .map_err(|_| ReaderError::ConnectError)?;
+    Ok(UmpInput { stop })
+}
+
+// Where the messages of one input go, what's assembled from several messages is per input
+struct Output {
+    slot: usize,
+    tx: Sender,
+    dedup: Arc<Mutex<Option<Deduplicator>>>,
+    cc_pairs: Arc<Mutex<Pairs>>,
+    settings: Arc<Mutex<Vec<input::Settings>>>,
+    parameters: Assembler,
+    controllers: Combiner,
+    held: Arc<Mutex<Held>>,
+}
+
+impl Output {
+    fn send(&mut self, msg: Message, received: Instant) {
+        let msg = match self.settings.lock() {
+            Ok(settings) => match settings.get(self.slot) {
+                Some(settings) => settings.apply(msg),
+                None => Some(msg),
+            },
+            Err(_) => Some(msg),
+        };
+        let Some(msg) = msg else {
+            return;
+        };
+        // the parameters need the data entry LSB even when it's merged into a 14-bit value
+        let parameter = self.parameters.push(&msg);
+        let pairs = self.cc_pairs.lock().map(|pairs| *pairs).unwrap_or_default();
+        let msg = self.controllers.push(&pairs, msg);
+        self.forward(msg, received);
+        if let Some(parameter) = parameter {
+            self.forward(parameter, received);
+        }
+    }
+
+    fn forward(&self, msg: Message, received: Instant) {
+        // stamped only now, the deduplicator compares messages without time
+        if is_duplicate(&self.dedup, self.slot, &msg, received) {
+            return;
+        }
+        if let Ok(mut held) = self.held.lock() {
+            held.push(&msg);
+        }
+        if self.tx.receiver_count() > 0 {
+            _ = self.tx.send(msg.from_slot(self.slot).at(received));
+        }
+    }
+}
+
+fn is_duplicate(
+    dedup: &Mutex<Option<Deduplicator>>,
+    slot: usize,
+    msg: &Message,
+    received: Instant,
+) -> bool {
+    match dedup.lock() {
+        Ok(mut dedup) => dedup
+            .as_mut()
+            .is_some_and(|dedup| dedup.is_duplicate(slot, msg, received)),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,235 @@
+use super::{smf, Message};
+use crate::{
+    files::{self, FileError},
+    path::VirtualPaths,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+// About an hour of dense playing, a forgotten recording doesn't eat up all the memory
+pub const MAX_EVENTS: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestKind {
+    // (virtual path of the .mid file, which input to record), the file must not exist yet
+    Start(PathBuf, Filter),
+    // (virtual path without extension, which input to record), the master bus goes to a .wav and
+    // the input to a .mid file with the same start, so takes can be re-rendered or edited later
+    StartSession(PathBuf, Filter),
+    // Writes the file
+    Stop,
+    GetStatus,
+}
+
+// Which input gets recorded, `None` records everything
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Filter {
+    pub slots: Option<Vec<usize>>,
+    pub channels: Option<Vec<u8>>,
+}
+
+impl Filter {
+    pub fn passes(&self, message: &Message) -> bool {
+        let slot_passes = match (&self.slots, message.slot) {
+            (None, _) => true,
+            (Some(slots), Some(slot)) => slots.contains(&slot),
+            (Some(_), None) => false,
+        };
+        let channel_passes = self
+            .channels
+            .as_ref()
+            .is_none_or(|channels| channels.contains(&message.channel));
+        slot_passes && channel_passes
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    // Virtual path of the file being recorded, none when not recording
+    pub path: Option<PathBuf>,
+    pub events: usize,
+    pub tempo_bpm: f32,
+    // Virtual path of the audio of a session
+    pub audio_path: Option<PathBuf>,
+}
+
+struct Recording {
+    virtual_path: PathBuf,
+    path: PathBuf,
+    filter: Filter,
+    tempo_bpm: f32,
+    start: Instant,
+    fixed_start: bool,
+    audio_path: Option<PathBuf>,
+    events: Vec<(Duration, Message)>,
+}
+
+#[derive(Default)]
+pub struct Recorder {
+    recording: Option<Recording>,
+}
+
+impl Recorder {
+    // Without a start the time starts with the first recorded message, not with the request
+    pub fn start(
+        &mut self,
+        virtual_paths: &VirtualPaths,
+        path: &Path,
+        filter: Filter,
+        tempo_bpm: f32,
+        start: Option<Instant>,
+    ) -> Result<(), FileError> {
+        let real_path = files::translate(virtual_paths, path)?;
+        if real_path.file_name().is_none() {
+            return Err(FileError::InvalidPath);
+        }
+        if real_path.exists() {
+            return Err(FileError::Exists);
+        }
+        self.recording = Some(Recording {
+            virtual_path: path.to_owned(),
+            path: real_path,
+            filter,
+            tempo_bpm,
+            start: start.unwrap_or_else(Instant::now),
+            fixed_start: start.is_some(),
+            audio_path: None,
+            events: Vec::new(),
+        });
+        Ok(())
+    }
+
+    // Only for the status, the audio is recorded elsewhere
+    pub fn set_audio_path(&mut self, path: PathBuf) {
+        if let Some(recording) = &mut self.recording {
+            recording.audio_path = Some(path);
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn record(&mut self, message: &Message) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+        if !recording.filter.passes(message) || recording.events.len() >= MAX_EVENTS {
+            return;
+        }
+        let time = message.time.unwrap_or_else(Instant::now);
+        if recording.events.is_empty() && !recording.fixed_start {
+            recording.start = time;
+        }
+        let since_start = time.saturating_duration_since(recording.start);
+        recording.events.push((since_start, message.clone()));
+    }
+
+    // The real path to write to and the file, none when not recording
+    pub fn stop(&mut self) -> Option<(PathBuf, Vec<u8>)> {
+        let recording = self.recording.take()?;
+        let bytes = smf::write(&recording.events, recording.tempo_bpm);
+        Some((recording.path, bytes))
+    }
+
+    pub fn status(&self) -> Status {
+        match &self.recording {
+            Some(recording) => Status {
+                path: Some(recording.virtual_path.clone()),
+                events: recording.events.len(),
+                tempo_bpm: recording.tempo_bpm,
+                audio_path: recording.audio_path.clone(),
+            },
+            None => Status::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, Recorder};
+    use crate::{
+        files::FileError,
+        midi::{Message, MessageKind},
+        path::VirtualPaths,
+        testing::TempDir,
+    };
+    use std::{
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn records_filtered_input() {
+        let dir = TempDir::new("recorder");
+        let mut virtual_paths = VirtualPaths::default();
+        virtual_paths.insert(PathBuf::from("recordings:"), dir.to_path_buf());
+        let mut recorder = Recorder::default();
+        let filter = Filter {
+            slots: Some(vec![1]),
+            channels: None,
+        };
+        let path = Path::new("recordings:/take.mid");
+        recorder
+            .start(&virtual_paths, path, filter, 120.0, None)
+            .unwrap();
+
+        let now = Instant::now();
+        let note_on = Message::new(
+            0,
+            MessageKind::NoteOn {
+                note: 60,
+                velocity: 90,
+            },
+        );
+        recorder.record(&note_on.clone().from_slot(0).at(now));
+        recorder.record(&note_on.clone().from_slot(1).at(now));
+        recorder.record(
+            &note_on
+                .clone()
+                .from_slot(1)
+                .at(now + Duration::from_millis(500)),
+        );
+        let status = recorder.status();
+        assert_eq!(status.path.as_deref(), Some(path));
+        assert_eq!(status.events, 2);
+
+        let (real_path, bytes) = recorder.stop().unwrap();
+        assert_eq!(real_path, dir.join("take.mid"));
+        // tempo, two notes a quarter apart and the end of the track
+        assert_eq!(bytes[22..].len(), 7 + 4 + 5 + 4);
+        assert!(!recorder.is_recording() && recorder.stop().is_none());
+
+        std::fs::write(&real_path, bytes).unwrap();
+        let res = recorder.start(&virtual_paths, path, Filter::default(), 120.0, None);
+        assert_eq!(res, Err(FileError::Exists));
+
+        // a session starts at its own time, messages before it count as at the start
+        let path = Path::new("recordings:/session.mid");
+        let start = Some(now + Duration::from_millis(250));
+        recorder
+            .start(&virtual_paths, path, Filter::default(), 120.0, start)
+            .unwrap();
+        recorder.record(&note_on.clone().at(now));
+        recorder.record(&note_on.at(now + Duration::from_millis(500)));
+        let (_, bytes) = recorder.stop().unwrap();
+        // the second note is an eighth after the start, 240 ticks
+        assert_eq!(&bytes[22 + 7..22 + 7 + 4], [0x00, 0x90, 60, 90]);
+        assert_eq!(&bytes[22 + 11..22 + 13], [0x81, 0x70]);
+    }
+
+    #[test]
+    fn recordings_stay_under_their_root() {
+        let dir = TempDir::new("recorder-root");
+        let mut virtual_paths = VirtualPaths::default();
+        virtual_paths.insert(PathBuf::from("recordings:"), dir.join("recordings"));
+        let mut recorder = Recorder::default();
+        let path = Path::new("recordings:/../take.mid");
+        let res = recorder.start(&virtual_paths, path, Filter::default(), 120.0, None);
+        assert_eq!(res, Err(FileError::InvalidPath));
+        assert!(!recorder.is_recording());
+    }
+}
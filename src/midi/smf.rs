@@ -0,0 +1,416 @@
+// Reading and writing of Standard MIDI Files. Format 0 with a single track is all a recording
+// needs, reading also takes format 1 files with their tracks merged.
+
+use super::{Message, MessageKind};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Ticks per quarter note, fine enough for the timing of live playing at any usual tempo
+pub const TICKS_PER_QUARTER: u16 = 480;
+
+// 120 bpm, what a file plays at until its first tempo event
+pub const DEFAULT_US_PER_QUARTER: u32 = 500_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SmfError {
+    NotAMidiFile,
+    Truncated,
+    // Divisions in SMPTE frames instead of ticks per quarter note
+    UnsupportedDivision,
+}
+
+impl std::error::Error for SmfError {}
+
+impl std::fmt::Display for SmfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SmfError::NotAMidiFile => "Not a Standard MIDI File.".fmt(f),
+            SmfError::Truncated => "The MIDI file ends unexpectedly.".fmt(f),
+            SmfError::UnsupportedDivision => "SMPTE timing is not supported.".fmt(f),
+        }
+    }
+}
+
+// The channel messages of a file and its tempo map, both in order of ticks
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sequence {
+    pub ticks_per_quarter: u16,
+    pub events: Vec<(u64, Message)>,
+    // (tick, microseconds per quarter note), the first one is at tick 0
+    pub tempos: Vec<(u64, u32)>,
+    // The end of the longest track
+    pub length: u64,
+}
+
+impl Sequence {
+    // A fixed tempo replaces the tempo map of the file
+    pub fn seconds_at(&self, tick: f64, tempo_override: Option<f32>) -> f64 {
+        if let Some(tempo_bpm) = tempo_override {
+            return tick / self.ticks_per_second(us_per_quarter(tempo_bpm));
+        }
+        let mut seconds = 0.0;
+        for (i, &(start, us)) in self.tempos.iter().enumerate() {
+            let end = self.tempos.get(i + 1).map_or(f64::INFINITY, |t| t.0 as f64);
+            seconds += (tick.min(end) - start as f64).max(0.0) / self.ticks_per_second(us);
+            if tick <= end {
+                break;
+            }
+        }
+        seconds
+    }
+
+    pub fn tick_at(&self, seconds: f64, tempo_override: Option<f32>) -> f64 {
+        if let Some(tempo_bpm) = tempo_override {
+            return seconds * self.ticks_per_second(us_per_quarter(tempo_bpm));
+        }
+        let mut seconds_left = seconds;
+        for (i, &(start, us)) in self.tempos.iter().enumerate() {
+            let ticks_per_second = self.ticks_per_second(us);
+            let end = self.tempos.get(i + 1).map_or(f64::INFINITY, |t| t.0 as f64);
+            let span = (end - start as f64) / ticks_per_second;
+            if seconds_left <= span {
+                return start as f64 + seconds_left * ticks_per_second;
+            }
+            seconds_left -= span;
+        }
+        0.0
+    }
+
+    fn ticks_per_second(&self, us_per_quarter: u32) -> f64 {
+        self.ticks_per_quarter as f64 * 1_000_000.0 / us_per_quarter.max(1) as f64
+    }
+}
+
+fn us_per_quarter(tempo_bpm: f32) -> u32 {
+    (60_000_000.0 / tempo_bpm.max(1.0) as f64).round() as u32
+}
+
+pub fn read(bytes: &[u8]) -> Result<Sequence, SmfError> {
+    let mut chunks = Chunks(bytes);
+    let header = match chunks.next()? {
+        Some((b"MThd", header)) if header.len() >= 6 => header,
+        _ => return Err(SmfError::NotAMidiFile),
+    };
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    if division & 0x8000 != 0 || division == 0 {
+        return Err(SmfError::UnsupportedDivision);
+    }
+    let mut sequence = Sequence {
+        ticks_per_quarter: division,
+        ..Default::default()
+    };
+    while let Some((id, data)) = chunks.next()? {
+        // unknown chunks are meant to be skipped
+        if id == b"MTrk" {
+            read_track(data, &mut sequence)?;
+        }
+    }
+    // stable sorts keep the order of the messages of a tick within a track
+    sequence.events.sort_by_key(|(tick, _)| *tick);
+    sequence.tempos.sort_by_key(|(tick, _)| *tick);
+    if sequence.tempos.first().is_none_or(|(tick, _)| *tick > 0) {
+        sequence.tempos.insert(0, (0, DEFAULT_US_PER_QUARTER));
+    }
+    Ok(sequence)
+}
+
+// (id, data)
+type Chunk<'a> = (&'a [u8], &'a [u8]);
+
+struct Chunks<'a>(&'a [u8]);
+
+impl<'a> Chunks<'a> {
+    fn next(&mut self) -> Result<Option<Chunk<'a>>, SmfError> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        if self.0.len() < 8 {
+            return Err(SmfError::Truncated);
+        }
+        let len = u32::from_be_bytes(self.0[4..8].try_into().unwrap()) as usize;
+        let data = self.0.get(8..8 + len).ok_or(SmfError::Truncated)?;
+        let id = &self.0[..4];
+        self.0 = &self.0[8 + len..];
+        Ok(Some((id, data)))
+    }
+}
+
+fn read_track(data: &[u8], sequence: &mut Sequence) -> Result<(), SmfError> {
+    let mut pos = 0;
+    let mut tick = 0u64;
+    let mut running_status = None;
+    while pos < data.len() {
+        tick += read_var_len(data, &mut pos)? as u64;
+        let byte = *data.get(pos).ok_or(SmfError::Truncated)?;
+        match byte {
+            0xFF => {
+                let kind = *data.get(pos + 1).ok_or(SmfError::Truncated)?;
+                pos += 2;
+                let len = read_var_len(data, &mut pos)? as usize;
+                let payload = data.get(pos..pos + len).ok_or(SmfError::Truncated)?;
+                pos += len;
+                match (kind, payload) {
+                    (0x51, &[a, b, c]) => sequence
+                        .tempos
+                        .push((tick, u32::from_be_bytes([0, a, b, c]))),
+                    (0x2F, _) => break,
+                    _ => {}
+                }
+            }
+            0xF0 | 0xF7 => {
+                pos += 1;
+                let len = read_var_len(data, &mut pos)? as usize;
+                let payload = data.get(pos..pos + len).ok_or(SmfError::Truncated)?;
+                pos += len;
+                running_status = None;
+                // only complete ones, SysEx split into packets and escapes are skipped
+                if let (0xF0, [sysex @ .., 0xF7]) = (byte, payload) {
+                    let kind = MessageKind::SystemExclusive {
+                        data: sysex.to_vec(),
+                    };
+                    sequence.events.push((tick, Message::new(0, kind)));
+                }
+            }
+            _ => {
+                let status = if byte & 0x80 != 0 {
+                    pos += 1;
+                    running_status = Some(byte);
+                    byte
+                } else {
+                    running_status.ok_or(SmfError::NotAMidiFile)?
+                };
+                let len = match status & 0xF0 {
+                    0xC0 | 0xD0 => 1,
+                    _ => 2,
+                };
+                let message_data = data.get(pos..pos + len).ok_or(SmfError::Truncated)?;
+                pos += len;
+                let mut bytes = [status, 0, 0];
+                bytes[1..=len].copy_from_slice(message_data);
+                if let Some(message) = Message::decode(&bytes[..=len]) {
+                    sequence.events.push((tick, message));
+                }
+            }
+        }
+    }
+    sequence.length = sequence.length.max(tick);
+    Ok(())
+}
+
+fn read_var_len(data: &[u8], pos: &mut usize) -> Result<u32, SmfError> {
+    let mut value = 0u32;
+    // at most four bytes
+    for _ in 0..4 {
+        let byte = *data.get(*pos).ok_or(SmfError::Truncated)?;
+        *pos += 1;
+        value = (value << 7) | (byte & 0x7F) as u32;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(SmfError::NotAMidiFile)
+}
+
+// A format 0 file of the messages, which are (time since the start, message) in order of time,
+// the tempo is written as meta event so the notes line up with bars in a DAW
+pub fn write(events: &[(Duration, Message)], tempo_bpm: f32) -> Vec<u8> {
+    let tempo_bpm = tempo_bpm.max(1.0);
+    let ticks_per_second = tempo_bpm as f64 / 60.0 * TICKS_PER_QUARTER as f64;
+    let us_per_quarter = us_per_quarter(tempo_bpm);
+
+    let mut track = Vec::new();
+    push_var_len(&mut track, 0);
+    track.extend_from_slice(&[0xFF, 0x51, 0x03]);
+    track.extend_from_slice(&us_per_quarter.min(0xFF_FFFF).to_be_bytes()[1..]);
+
+    let mut last_tick = 0;
+    for (time, message) in events {
+        let Some(bytes) = message.encode() else {
+            continue;
+        };
+        let tick = (time.as_secs_f64() * ticks_per_second).round() as u64;
+        let delta = tick.saturating_sub(last_tick);
+        last_tick = last_tick.max(tick);
+        push_var_len(&mut track, delta.min(0x0FFF_FFFF) as u32);
+        // a SysEx event has the length of the rest after the F0
+        if let [0xF0, rest @ ..] = bytes.as_slice() {
+            track.push(0xF0);
+            push_var_len(&mut track, rest.len() as u32);
+            track.extend_from_slice(rest);
+        } else {
+            track.extend_from_slice(&bytes);
+        }
+    }
+    push_var_len(&mut track, 0);
+    track.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+
+    let mut bytes = Vec::with_capacity(22 + track.len());
+    bytes.extend_from_slice(b"MThd");
+    bytes.extend_from_slice(&6u32.to_be_bytes());
+    // format 0, one track
+    bytes.extend_from_slice(&0u16.to_be_bytes());
+    bytes.extend_from_slice(&1u16.to_be_bytes());
+    bytes.extend_from_slice(&TICKS_PER_QUARTER.to_be_bytes());
+    bytes.extend_from_slice(b"MTrk");
+    bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&track);
+    bytes
+}
+
+// Seven bits per byte, most significant first, all but the last byte have the top bit set
+fn push_var_len(bytes: &mut Vec<u8>, value: u32) {
+    let mut groups = [0u8; 4];
+    let mut len = 0;
+    let mut value = value;
+    loop {
+        groups[len] = (value & 0x7F) as u8;
+        len += 1;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+    }
+    for i in (0..len).rev() {
+        bytes.push(groups[i] | if i > 0 { 0x80 } else { 0 });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{push_var_len, read, read_var_len, write, SmfError};
+    use crate::midi::{Message, MessageKind};
+    use std::time::Duration;
+
+    #[test]
+    fn variable_length_quantities() {
+        for (value, expected) in [
+            (0, vec![0x00]),
+            (0x7F, vec![0x7F]),
+            (0x80, vec![0x81, 0x00]),
+            (0x3FFF, vec![0xFF, 0x7F]),
+            (0x0FFF_FFFF, vec![0xFF, 0xFF, 0xFF, 0x7F]),
+        ] {
+            let mut bytes = Vec::new();
+            push_var_len(&mut bytes, value);
+            assert_eq!(bytes, expected);
+            assert_eq!(read_var_len(&bytes, &mut 0), Ok(value));
+        }
+    }
+
+    #[test]
+    fn format_0_file() {
+        let note_on = Message::new(
+            9,
+            MessageKind::NoteOn {
+                note: 36,
+                velocity: 100,
+            },
+        );
+        let note_off = Message::new(
+            9,
+            MessageKind::NoteOff {
+                note: 36,
+                velocity: 64,
+            },
+        );
+        let per_note = Message::new(0, MessageKind::PerNotePitchWheel { note: 1, value: 0 });
+        // at 120 bpm a quarter note is half a second
+        let events = [
+            (Duration::ZERO, note_on),
+            (Duration::from_millis(250), per_note),
+            (Duration::from_millis(500), note_off),
+        ];
+        let bytes = write(&events, 120.0);
+
+        assert_eq!(&bytes[..14], b"MThd\0\0\0\x06\0\0\0\x01\x01\xE0");
+        assert_eq!(&bytes[14..18], b"MTrk");
+        let track = &bytes[22..];
+        assert_eq!(
+            u32::from_be_bytes(bytes[18..22].try_into().unwrap()) as usize,
+            track.len()
+        );
+        assert_eq!(
+            track,
+            [
+                0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, // 500000 us per quarter
+                0x00, 0x99, 36, 100, // note on right away
+                0x83, 0x60, 0x89, 36, 64, // note off 480 ticks later
+                0x00, 0xFF, 0x2F, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn read_back() {
+        let note_on = Message::new(
+            2,
+            MessageKind::NoteOn {
+                note: 64,
+                velocity: 80,
+            },
+        );
+        let note_off = Message::new(
+            2,
+            MessageKind::NoteOff {
+                note: 64,
+                velocity: 0,
+            },
+        );
+        let sysex = Message::new(
+            0,
+            MessageKind::SystemExclusive {
+                data: vec![0x7E, 0x7F, 0x09, 0x01],
+            },
+        );
+        let events = [
+            (Duration::from_millis(250), note_on.clone()),
+            (Duration::from_millis(1000), sysex.clone()),
+            (Duration::from_millis(1250), note_off.clone()),
+        ];
+        // 60 bpm, a second per quarter note
+        let sequence = read(&write(&events, 60.0)).unwrap();
+        assert_eq!(sequence.ticks_per_quarter, 480);
+        assert_eq!(sequence.tempos, [(0, 1_000_000)]);
+        assert_eq!(
+            sequence.events,
+            [(120, note_on), (480, sysex), (600, note_off)]
+        );
+        assert_eq!(sequence.length, 600);
+        assert!((sequence.seconds_at(600.0, None) - 1.25).abs() < 1e-9);
+        assert!((sequence.seconds_at(600.0, Some(120.0)) - 0.625).abs() < 1e-9);
+        assert!((sequence.tick_at(0.25, None) - 120.0).abs() < 1e-9);
+
+        assert_eq!(read(b"RIFF\0\0\0\0"), Err(SmfError::NotAMidiFile));
+        let bytes = write(&events, 60.0);
+        assert_eq!(read(&bytes[..bytes.len() - 2]), Err(SmfError::Truncated));
+    }
+
+    #[test]
+    fn tempo_map() {
+        // two tracks, the tempo doubles after the first quarter, the notes use running status
+        let mut bytes = b"MThd\0\0\0\x06\0\x01\0\x02\0\x60".to_vec();
+        let tempo_track = [
+            0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 1 s per quarter
+            0x60, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, // 0.5 s per quarter
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let note_track = [
+            0x00, 0x90, 60, 100, //
+            0x81, 0x40, 60, 0, // 192 ticks later with running status
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        for track in [&tempo_track[..], &note_track[..]] {
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(track);
+        }
+        let sequence = read(&bytes).unwrap();
+        assert_eq!(sequence.tempos, [(0, 1_000_000), (96, 500_000)]);
+        assert_eq!(sequence.events.len(), 2);
+        assert_eq!(sequence.events[1].0, 192);
+        assert_eq!(sequence.length, 192);
+        assert!((sequence.seconds_at(192.0, None) - 1.5).abs() < 1e-9);
+        assert!((sequence.tick_at(1.25, None) - 144.0).abs() < 1e-9);
+        assert!((sequence.tick_at(0.5, None) - 48.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,46 @@
+use super::{Message, MessageKind};
+use serde::{Deserialize, Serialize};
+
+// A footswitch or a key sends either notes or controllers, a controller counts as pressed from
+// 64 up
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Trigger {
+    Note { channel: u8, note: u8 },
+    ControlChange { channel: u8, controller: u8 },
+}
+
+impl Trigger {
+    pub fn is_pressed_by(&self, message: &Message) -> bool {
+        match (*self, &message.kind) {
+            (Self::Note { channel, note }, &MessageKind::NoteOn { note: n, velocity }) => {
+                channel == message.channel && note == n && velocity > 0
+            }
+            (
+                Self::ControlChange {
+                    channel,
+                    controller,
+                },
+                &MessageKind::ControlChange { kind, value },
+            ) => channel == message.channel && controller == kind.as_number() && value >= 64,
+            _ => false,
+        }
+    }
+
+    // Pressing and letting go alike
+    pub fn is_sent_by(&self, message: &Message) -> bool {
+        match (*self, &message.kind) {
+            (
+                Self::Note { channel, note },
+                &MessageKind::NoteOn { note: n, .. } | &MessageKind::NoteOff { note: n, .. },
+            ) => channel == message.channel && note == n,
+            (
+                Self::ControlChange {
+                    channel,
+                    controller,
+                },
+                &MessageKind::ControlChange { kind, .. },
+            ) => channel == message.channel && controller == kind.as_number(),
+            _ => false,
+        }
+    }
+}
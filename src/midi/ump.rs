@@ -0,0 +1,342 @@
+// Universal MIDI Packet (MIDI 2.0) decoding
+//
+// Resources:
+// https://midi.org/universal-midi-packet-ump-and-midi-2-0-protocol-specification
+//
+// Packets are translated into the internal `Message`, 7-bit (14-bit for pitch wheels) values
+// are always filled in so every node keeps working, the original resolution is preserved
+// in `Message::hi_res`.
+
+use super::{ControlChangeKind, Message, MessageKind};
+use std::path::Path;
+
+const MT_MIDI1_CHANNEL_VOICE: u8 = 0x2;
+const MT_MIDI2_CHANNEL_VOICE: u8 = 0x4;
+
+// ALSA (Linux 6.5 and later) has a raw device per MIDI 2.0 endpoint next to the MIDI 1.0 ports,
+// they're listed among the ports with this prefix before the device path
+pub const PORT_PREFIX: &str = "UMP:";
+const DEVICE_DIR: &str = "/dev/snd";
+const DEVICE_FILE_PREFIX: &str = "umpC";
+
+pub fn available_ports() -> Vec<String> {
+    let Ok(entries) = Path::new(DEVICE_DIR).read_dir() else {
+        return vec![];
+    };
+    let mut ports: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(DEVICE_FILE_PREFIX)
+        })
+        .map(|entry| format!("{PORT_PREFIX}{}", entry.path().display()))
+        .collect();
+    ports.sort();
+    ports
+}
+
+// The device path of a port from `available_ports`
+pub fn device_of_port(port_name: &str) -> Option<&Path> {
+    port_name.strip_prefix(PORT_PREFIX).map(Path::new)
+}
+
+// The device hands out packets as native endian words
+pub fn words_from_bytes(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+        .collect()
+}
+
+pub fn message_type(first_word: u32) -> u8 {
+    (first_word >> 28) as u8
+}
+
+pub fn group(first_word: u32) -> u8 {
+    ((first_word >> 24) & 0x0F) as u8
+}
+
+// Number of 32-bit words of the packet starting with the given word
+pub fn packet_len(first_word: u32) -> usize {
+    match message_type(first_word) {
+        0x0..=0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8..=0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+// Decodes a stream of packets, unsupported packets (system, data, flex data...) are skipped
+pub fn decode_stream(words: &[u32]) -> Vec<Message> {
+    let mut messages = vec![];
+    let mut rest = words;
+    while let Some(first_word) = rest.first() {
+        let len = packet_len(*first_word);
+        if rest.len() < len {
+            break;
+        }
+        messages.extend(decode(&rest[..len]));
+        rest = &rest[len..];
+    }
+    messages
+}
+
+pub fn decode(packet: &[u32]) -> Vec<Message> {
+    let Some(first_word) = packet.first() else {
+        return vec![];
+    };
+    match message_type(*first_word) {
+        MT_MIDI1_CHANNEL_VOICE => decode_midi1(*first_word).into_iter().collect(),
+        MT_MIDI2_CHANNEL_VOICE if packet.len() >= 2 => decode_midi2(packet[0], packet[1]),
+        _ => vec![],
+    }
+}
+
+fn decode_midi1(word: u32) -> Option<Message> {
+    let bytes = [(word >> 16) as u8, (word >> 8) as u8, word as u8];
+    Message::decode(&bytes)
+}
+
+fn decode_midi2(word0: u32, word1: u32) -> Vec<Message> {
+    let opcode = ((word0 >> 20) & 0x0F) as u8;
+    let channel = ((word0 >> 16) & 0x0F) as u8;
+    let index = ((word0 >> 8) & 0x7F) as u8;
+    let velocity = (word1 >> 16) as u16;
+    let message = |kind, hi_res| vec![Message::with_hi_res(channel, kind, hi_res)];
+    match opcode {
+        0x2 => message(
+            MessageKind::RegisteredParameter {
+                parameter: bank_and_index(word0),
+                value: downscale_32_to_14(word1),
+            },
+            word1,
+        ),
+        0x3 => message(
+            MessageKind::NonRegisteredParameter {
+                parameter: bank_and_index(word0),
+                value: downscale_32_to_14(word1),
+            },
+            word1,
+        ),
+        0x0 | 0x1 => message(
+            MessageKind::PerNoteController {
+                note: index,
+                index: (word0 & 0xFF) as u16 + if opcode == 0x1 { 0x100 } else { 0 },
+                value: downscale_32_to_7(word1),
+            },
+            word1,
+        ),
+        0x6 => message(
+            MessageKind::PerNotePitchWheel {
+                note: index,
+                value: downscale_32_to_14(word1),
+            },
+            word1,
+        ),
+        0x8 => message(
+            MessageKind::NoteOff {
+                note: index,
+                velocity: downscale_16_to_7(velocity),
+            },
+            velocity as u32,
+        ),
+        // Zero velocity is a valid note on in MIDI 2.0, so it must not turn into a note off
+        0x9 => message(
+            MessageKind::NoteOn {
+                note: index,
+                velocity: downscale_16_to_7(velocity).max(1),
+            },
+            velocity as u32,
+        ),
+        0xA => message(
+            MessageKind::PolyphonicAftertouch {
+                note: index,
+                pressure: downscale_32_to_7(word1),
+            },
+            word1,
+        ),
+        0xB => match ControlChangeKind::from_number(index) {
+            Some(kind) => message(
+                MessageKind::ControlChange {
+                    kind,
+                    value: downscale_32_to_7(word1),
+                },
+                word1,
+            ),
+            None => vec![],
+        },
+        0xC => decode_midi2_program_change(channel, word0, word1),
+        0xD => message(
+            MessageKind::ChannelAftertouch {
+                pressure: downscale_32_to_7(word1),
+            },
+            word1,
+        ),
+        0xE => message(
+            MessageKind::PitchWheel {
+                value: downscale_32_to_14(word1),
+            },
+            word1,
+        ),
+        _ => vec![],
+    }
+}
+
+fn decode_midi2_program_change(channel: u8, word0: u32, word1: u32) -> Vec<Message> {
+    let mut messages = Vec::with_capacity(3);
+    let bank_valid = word0 & 0x01 != 0;
+    if bank_valid {
+        let msb = ((word1 >> 8) & 0x7F) as u8;
+        let lsb = (word1 & 0x7F) as u8;
+        messages.push(Message::new(
+            channel,
+            MessageKind::ControlChange {
+                kind: ControlChangeKind::BankSelectMsb,
+                value: msb,
+            },
+        ));
+        messages.push(Message::new(
+            channel,
+            MessageKind::ControlChange {
+                kind: ControlChangeKind::BankSelectLsb,
+                value: lsb,
+            },
+        ));
+    }
+    let program = ((word1 >> 24) & 0x7F) as u8;
+    messages.push(Message::new(
+        channel,
+        MessageKind::ProgramChange { program },
+    ));
+    messages
+}
+
+// The 14-bit parameter number of registered and assignable controllers, as RPN/NRPN have it
+fn bank_and_index(word0: u32) -> u16 {
+    (((word0 >> 8) & 0x7F) << 7 | (word0 & 0x7F)) as u16
+}
+
+fn downscale_16_to_7(value: u16) -> u8 {
+    (value >> 9) as u8
+}
+
+fn downscale_32_to_7(value: u32) -> u8 {
+    (value >> 25) as u8
+}
+
+fn downscale_32_to_14(value: u32) -> u16 {
+    (value >> 18) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_stream, device_of_port, words_from_bytes};
+    use crate::midi::{ControlChangeKind, Message, MessageKind};
+    use std::path::Path;
+
+    #[test]
+    fn decode_midi1_in_ump() {
+        let msgs = decode(&[0x2091_3C64]);
+        assert_eq!(
+            msgs,
+            vec![Message::new(
+                1,
+                MessageKind::NoteOn {
+                    note: 60,
+                    velocity: 100
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn decode_midi2_note_on() {
+        let msgs = decode(&[0x4092_3C00, 0x0000_0000]);
+        assert_eq!(
+            msgs,
+            vec![Message::with_hi_res(
+                2,
+                MessageKind::NoteOn {
+                    note: 60,
+                    velocity: 1
+                },
+                0
+            )]
+        );
+        let msgs = decode(&[0x4090_3C00, 0xFFFF_0000]);
+        assert_eq!(msgs[0].hi_res, Some(0xFFFF));
+        assert_eq!(msgs[0].hi_res_normalized(), Some(1.0));
+    }
+
+    #[test]
+    fn decode_midi2_control_change() {
+        let msgs = decode(&[0x40B0_0B00, 0x8000_0000]);
+        assert_eq!(
+            msgs,
+            vec![Message::with_hi_res(
+                0,
+                MessageKind::ControlChange {
+                    kind: ControlChangeKind::ExpressionControllerMsb,
+                    value: 64
+                },
+                0x8000_0000
+            )]
+        );
+    }
+
+    #[test]
+    fn decode_midi2_registered_controller() {
+        // pitch bend sensitivity of 12 semitones, the data is 32 bits left aligned
+        let msgs = decode(&[0x4025_0000, 12 << 25]);
+        assert_eq!(
+            msgs[0].kind,
+            MessageKind::RegisteredParameter {
+                parameter: 0,
+                value: 12 << 7
+            }
+        );
+        let msgs = decode(&[0x4031_0108, 0]);
+        assert_eq!(
+            (msgs[0].channel, msgs[0].kind.clone()),
+            (
+                1,
+                MessageKind::NonRegisteredParameter {
+                    parameter: (1 << 7) | 8,
+                    value: 0
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn decode_stream_skips_unsupported() {
+        let words = [0x1000_0000, 0x40E0_0000, 0x8000_0000, 0x2080_3C00];
+        let msgs = decode_stream(&words);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].kind, MessageKind::PitchWheel { value: 8192 });
+        assert_eq!(
+            msgs[1].kind,
+            MessageKind::NoteOff {
+                note: 60,
+                velocity: 0
+            }
+        );
+    }
+
+    #[test]
+    fn device_ports() {
+        assert_eq!(
+            device_of_port("UMP:/dev/snd/umpC1D0"),
+            Some(Path::new("/dev/snd/umpC1D0"))
+        );
+        assert_eq!(device_of_port("f_midi:f_midi 20:0"), None);
+        let bytes: Vec<u8> = [0x2090_3C64u32, 0x4090_3C00]
+            .iter()
+            .flat_map(|word| word.to_ne_bytes())
+            .chain([0x12])
+            .collect();
+        assert_eq!(words_from_bytes(&bytes), [0x2090_3C64, 0x4090_3C00]);
+    }
+}
@@ -0,0 +1,71 @@
+use std::{error::Error, fmt};
+
+use midir::{MidiOutput, MidiOutputConnection};
+
+use super::Message;
+
+pub type Result<T> = std::result::Result<T, WriterError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriterError {
+    ConnectError,
+    // The port went away or the message has no MIDI 1.0 bytes
+    SendError,
+}
+
+impl Error for WriterError {}
+
+impl fmt::Display for WriterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriterError::ConnectError => "Failed to connect MIDI output port.".fmt(f),
+            WriterError::SendError => "Failed to send MIDI message.".fmt(f),
+        }
+    }
+}
+
+// A MIDI 1.0 output port, for feedback to the devices AMI is played with
+pub struct MidiWriter {
+    name: String,
+    connection: MidiOutputConnection,
+}
+
+impl MidiWriter {
+    pub fn get_available_ports() -> Vec<String> {
+        let Ok(midi_out) = MidiOutput::new("") else {
+            return vec![];
+        };
+        midi_out
+            .ports()
+            .iter()
+            .filter_map(|port| midi_out.port_name(port).ok())
+            .collect()
+    }
+
+    pub fn connect(port_name: &str) -> Result<Self> {
+        let midi_out = MidiOutput::new("").map_err(|_| WriterError::ConnectError)?;
+        let port = midi_out
+            .ports()
+            .into_iter()
+            .find(|port| midi_out.port_name(port).is_ok_and(|name| name == port_name))
+            .ok_or(WriterError::ConnectError)?;
+        let connection = midi_out
+            .connect(&port, "")
+            .map_err(|_| WriterError::ConnectError)?;
+        Ok(Self {
+            name: port_name.into(),
+            connection,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn send(&mut self, message: &Message) -> Result<()> {
+        let bytes = message.encode().ok_or(WriterError::SendError)?;
+        self.connection
+            .send(&bytes)
+            .map_err(|_| WriterError::SendError)
+    }
+}
@@ -0,0 +1,368 @@
+mod packet;
+
+pub use packet::{decode, encode, Arg, Message, PacketError};
+
+use crate::{
+    control,
+    json::JsonUpdateKind,
+    render::command,
+    webserver::{self, ClientMessageKind, Clients, ServerMessageKind},
+};
+use serde_json::{json, Value};
+use std::{future::Future, net::SocketAddr, sync::Arc};
+use tokio::{net::UdpSocket, sync::broadcast::error::RecvError};
+use tracing::{debug, error, warn};
+
+// Surfaces which sent something get the state changes too, up to this many of them
+pub const MAX_PEERS: usize = 16;
+
+// The request an OSC message stands for, the address names the request like the web client
+// does and the arguments are its fields:
+// - `/drum_machine/<request>`
+// - `/pads/<request>`
+// - `/controller/<request>` and `/controller/<node id>/<request>`
+// - `/renderer/<node id>/<request>`
+// - `/authenticate <password>`
+pub fn to_request(message: &Message) -> Option<ClientMessageKind> {
+    let parts: Vec<_> = message.address.strip_prefix('/')?.split('/').collect();
+    let request = |name: &str| match message.args.as_slice() {
+        [] => json!(name),
+        [arg] => json!({ name: arg_to_json(arg) }),
+        args => json!({ name: args.iter().map(arg_to_json).collect::<Vec<_>>() }),
+    };
+    let node_request = |id: &str, name: &str| -> Option<Value> {
+        let id: usize = id.parse().ok()?;
+        Some(json!({ "NodeRequest": { "id": id, "kind": request(name) } }))
+    };
+    let value = match parts.as_slice() {
+        ["drum_machine", name] => json!({ "DrumMachineRequest": request(name) }),
+        ["pads", name] => json!({ "PadRequest": request(name) }),
+        ["controller", name] => json!({ "ControllerRequest": request(name) }),
+        ["controller", id, name] => json!({ "ControllerRequest": node_request(id, name)? }),
+        ["renderer", id, name] => json!({ "RendererRequest": node_request(id, name)? }),
+        ["authenticate"] => request("Authenticate"),
+        _ => return None,
+    };
+    serde_json::from_value(value).ok()
+}
+
+// Faders send floats even for whole numbers, those have to fit integer fields too
+fn arg_to_json(arg: &Arg) -> Value {
+    match arg {
+        Arg::Int(i) => json!(i),
+        Arg::Float(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f32 => json!(*f as i64),
+        Arg::Float(f) => json!(f),
+        Arg::String(s) => json!(s),
+        Arg::Bool(b) => json!(b),
+        Arg::Nil => Value::Null,
+    }
+}
+
+// The state changes of an update as OSC messages, one per changed field at
+// `/<drum_machine|pads|controller|renderer>[/<node id>]/<field>`, fields which aren't numbers,
+// strings, booleans or lists of them don't fit on a control surface and are left out
+pub fn from_update(update: &ServerMessageKind) -> Vec<Message> {
+    let fields = |prefix: String, kind: &JsonUpdateKind| -> Vec<Message> {
+        let JsonUpdateKind::UpdateFields(updates) = kind else {
+            return Vec::new();
+        };
+        updates
+            .iter()
+            .filter_map(|(field, value)| {
+                Some(Message {
+                    address: format!("{prefix}/{field}"),
+                    args: json_to_args(value)?,
+                })
+            })
+            .collect()
+    };
+    match update {
+        ServerMessageKind::DrumMachineUpdate(kind) => fields("/drum_machine".into(), kind),
+        ServerMessageKind::PadUpdate(kind) => fields("/pads".into(), kind),
+        ServerMessageKind::RendererResponse(command::ResponseKind::NodeResponse { id, kind }) => {
+            fields(format!("/renderer/{id}"), kind)
+        }
+        ServerMessageKind::ControllerResponse(res) => match res {
+            control::command::ResponseKind::NodeResponse { id, kind } => {
+                fields(format!("/controller/{id}"), kind)
+            }
+            control::command::ResponseKind::SetTempoBpm(tempo_bpm) => vec![Message {
+                address: "/controller/tempo_bpm".into(),
+                args: vec![Arg::Float(*tempo_bpm)],
+            }],
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+fn json_to_args(value: &Value) -> Option<Vec<Arg>> {
+    match value {
+        Value::Array(values) => values.iter().map(json_to_arg).collect(),
+        value => Some(vec![json_to_arg(value)?]),
+    }
+}
+
+fn json_to_arg(value: &Value) -> Option<Arg> {
+    match value {
+        Value::Null => Some(Arg::Nil),
+        Value::Bool(b) => Some(Arg::Bool(*b)),
+        Value::Number(n) => match n.as_i64().map(i32::try_from) {
+            Some(Ok(i)) => Some(Arg::Int(i)),
+            _ => n.as_f64().map(|f| Arg::Float(f as f32)),
+        },
+        Value::String(s) => Some(Arg::String(s.clone())),
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+// Serves OSC on the socket: incoming messages go through the request handler like the ones
+// of web clients, and every broadcast state change is sent to the targets and to the surfaces
+// which talked to us. With a password surfaces are spectators like web clients, until they
+// authenticate from their address.
+pub async fn run<F, Fut>(
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    clients: Clients,
+    password: Option<Arc<str>>,
+    handler: F,
+) where
+    F: Fn(SocketAddr, ClientMessageKind) -> Fut,
+    Fut: Future<Output = ServerMessageKind>,
+{
+    let mut brd_rx = clients.subscribe();
+    let mut peers = targets;
+    let mut editors = Vec::new();
+    let mut buf = vec![0; 65536];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, addr) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        error!("OSC receive error: {e}");
+                        continue;
+                    }
+                };
+                if !peers.contains(&addr) && peers.len() < MAX_PEERS {
+                    peers.push(addr);
+                }
+                let messages = match decode(&buf[..len]) {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        debug!("Invalid OSC packet from [{addr}]: {e:?}");
+                        continue;
+                    }
+                };
+                for message in messages {
+                    match to_request(&message) {
+                        Some(ClientMessageKind::Authenticate(given)) => {
+                            let res = webserver::authenticate(password.as_deref(), &given).await;
+                            if !matches!(res, ServerMessageKind::Ack) {
+                                warn!("Failed OSC authentication from [{addr}]");
+                            } else if !editors.contains(&addr) && editors.len() < MAX_PEERS {
+                                editors.push(addr);
+                            }
+                        }
+                        Some(req) if password.is_none() || editors.contains(&addr) => {
+                            handler(addr, req).await;
+                        }
+                        Some(req) if req.is_read_only() => {
+                            handler(addr, req).await;
+                        }
+                        Some(_) => {
+                            debug!("Unauthorized OSC message from [{addr}]: {}", message.address)
+                        }
+                        None => debug!("Unknown OSC message from [{addr}]: {}", message.address),
+                    }
+                }
+            }
+            msg = brd_rx.recv() => {
+                let payload = match msg {
+                    Ok(msg) => msg.payload,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                for message in from_update(&payload) {
+                    let bytes = encode(&message);
+                    for peer in &peers {
+                        if let Err(e) = socket.send_to(&bytes, peer).await {
+                            debug!("OSC send error to [{peer}]: {e}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, from_update, run, to_request, Arg, Message};
+    use crate::{
+        control::drum_machine,
+        json::JsonUpdateKind,
+        render::{command, node},
+        webserver::{ClientMessageKind, Clients, ServerMessageKind},
+    };
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::{net::UdpSocket, sync::mpsc};
+
+    fn message(address: &str, args: Vec<Arg>) -> Message {
+        Message {
+            address: address.into(),
+            args,
+        }
+    }
+
+    #[test]
+    fn addresses_to_requests() {
+        let req = to_request(&message(
+            "/drum_machine/SetTempoBpm",
+            vec![Arg::Float(120.0)],
+        ));
+        assert!(matches!(
+            req,
+            Some(ClientMessageKind::DrumMachineRequest(drum_machine::RequestKind::SetTempoBpm(t))) if t == 120.0
+        ));
+        let req = to_request(&message(
+            "/drum_machine/SetVoiceVelocity",
+            vec![Arg::Float(1.0), Arg::Int(90)],
+        ));
+        assert!(matches!(
+            req,
+            Some(ClientMessageKind::DrumMachineRequest(
+                drum_machine::RequestKind::SetVoiceVelocity(1, 90)
+            ))
+        ));
+        let req = to_request(&message("/renderer/2/SetGain", vec![Arg::Float(0.5)]));
+        assert!(matches!(
+            req,
+            Some(ClientMessageKind::RendererRequest(command::RequestKind::NodeRequest {
+                id: 2,
+                kind: node::RequestKind::SetGain(g),
+            })) if g == 0.5
+        ));
+        assert!(to_request(&message("/drum_machine/AddVoice", vec![])).is_some());
+
+        assert!(to_request(&message("/drum_machine/NoSuchRequest", vec![])).is_none());
+        assert!(to_request(&message("/renderer/x/SetGain", vec![Arg::Float(0.5)])).is_none());
+        assert!(to_request(&message("/mixer/volume", vec![Arg::Float(0.5)])).is_none());
+    }
+
+    #[test]
+    fn updates_to_messages() {
+        let update = ServerMessageKind::DrumMachineUpdate(JsonUpdateKind::UpdateFields(vec![
+            ("tempo_bpm".into(), json!(120.0)),
+            ("enabled".into(), json!(true)),
+            ("voices".into(), json!([{ "name": "kick" }])),
+        ]));
+        assert_eq!(
+            from_update(&update),
+            vec![
+                message("/drum_machine/tempo_bpm", vec![Arg::Float(120.0)]),
+                message("/drum_machine/enabled", vec![Arg::Bool(true)]),
+            ]
+        );
+        let update = ServerMessageKind::RendererResponse(command::ResponseKind::NodeResponse {
+            id: 1,
+            kind: JsonUpdateKind::UpdateFields(vec![("bank_and_preset".into(), json!([0, 5]))]),
+        });
+        assert_eq!(
+            from_update(&update),
+            vec![message(
+                "/renderer/1/bank_and_preset",
+                vec![Arg::Int(0), Arg::Int(5)]
+            )]
+        );
+        assert!(from_update(&ServerMessageKind::Pong).is_empty());
+    }
+
+    #[tokio::test]
+    async fn serves_surfaces() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let surface = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut clients = Clients::new(16);
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        tokio::spawn(run(
+            server,
+            Vec::new(),
+            clients.clone(),
+            None,
+            move |_, req| {
+                let req_tx = req_tx.clone();
+                async move {
+                    req_tx.send(req).await.unwrap();
+                    ServerMessageKind::Ack
+                }
+            },
+        ));
+
+        let bytes = encode(&message("/drum_machine/SetEnabled", vec![Arg::Bool(true)]));
+        surface.send_to(&bytes, server_addr).await.unwrap();
+        let req = tokio::time::timeout(Duration::from_secs(5), req_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(
+            req,
+            Some(ClientMessageKind::DrumMachineRequest(
+                drum_machine::RequestKind::SetEnabled(true)
+            ))
+        ));
+
+        clients.broadcast(ServerMessageKind::DrumMachineUpdate(
+            JsonUpdateKind::UpdateFields(vec![("enabled".into(), json!(true))]),
+        ));
+        let mut buf = [0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), surface.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            decode(&buf[..len]).unwrap(),
+            vec![message("/drum_machine/enabled", vec![Arg::Bool(true)])]
+        );
+    }
+
+    #[tokio::test]
+    async fn surfaces_authenticate() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let surface = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (req_tx, mut req_rx) = mpsc::channel(4);
+        let password = Some("secret".into());
+        let clients = Clients::new(16);
+        tokio::spawn(run(server, Vec::new(), clients, password, move |_, req| {
+            let req_tx = req_tx.clone();
+            async move {
+                req_tx.send(req).await.unwrap();
+                ServerMessageKind::Ack
+            }
+        }));
+        let send = |address: &str, args| {
+            let bytes = encode(&message(address, args));
+            let surface = &surface;
+            async move { surface.send_to(&bytes, server_addr).await.unwrap() }
+        };
+        let enable = || vec![Arg::Bool(true)];
+
+        // the surface is only in control with the password
+        send("/drum_machine/SetEnabled", enable()).await;
+        send("/authenticate", vec![Arg::String("guess".into())]).await;
+        send("/drum_machine/SetEnabled", enable()).await;
+        send("/authenticate", vec![Arg::String("secret".into())]).await;
+        send("/drum_machine/SetSwing", vec![Arg::Int(10)]).await;
+        let req = tokio::time::timeout(Duration::from_secs(5), req_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(
+            req,
+            Some(ClientMessageKind::DrumMachineRequest(
+                drum_machine::RequestKind::SetSwing(10)
+            ))
+        ));
+        assert!(req_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,193 @@
+// Encoding and decoding of OSC 1.0 packets, just the argument types control surfaces send
+
+const BUNDLE_TAG: &[u8] = b"#bundle\0";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Bool(bool),
+    Nil,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub address: String,
+    pub args: Vec<Arg>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketError {
+    Truncated,
+    InvalidString,
+    InvalidAddress,
+    UnsupportedType(char),
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PacketError> {
+        let end = self.pos.checked_add(len).ok_or(PacketError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(PacketError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> Result<[u8; 4], PacketError> {
+        Ok(self.take(4)?.try_into().expect("Four bytes were taken"))
+    }
+
+    // Null terminated and padded to four bytes
+    fn string(&mut self) -> Result<String, PacketError> {
+        let rest = &self.bytes[self.pos..];
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(PacketError::Truncated)?;
+        let s = std::str::from_utf8(&rest[..len]).map_err(|_| PacketError::InvalidString)?;
+        self.take(padded(len + 1))?;
+        Ok(s.to_owned())
+    }
+}
+
+fn padded(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+// The messages of a packet, bundles are flattened and their time tags ignored
+pub fn decode(bytes: &[u8]) -> Result<Vec<Message>, PacketError> {
+    let mut messages = Vec::new();
+    decode_into(bytes, &mut messages)?;
+    Ok(messages)
+}
+
+fn decode_into(bytes: &[u8], messages: &mut Vec<Message>) -> Result<(), PacketError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if bytes.starts_with(BUNDLE_TAG) {
+        reader.take(BUNDLE_TAG.len() + 8)?;
+        while reader.pos < bytes.len() {
+            let len = u32::from_be_bytes(reader.word()?) as usize;
+            decode_into(reader.take(len)?, messages)?;
+        }
+        return Ok(());
+    }
+
+    let address = reader.string()?;
+    if !address.starts_with('/') {
+        return Err(PacketError::InvalidAddress);
+    }
+    // the type tag string is optional in old implementations
+    let tags = if reader.pos < bytes.len() {
+        reader.string()?
+    } else {
+        ",".to_owned()
+    };
+    let Some(tags) = tags.strip_prefix(',') else {
+        return Err(PacketError::InvalidString);
+    };
+    let mut args = Vec::with_capacity(tags.len());
+    for tag in tags.chars() {
+        args.push(match tag {
+            'i' => Arg::Int(i32::from_be_bytes(reader.word()?)),
+            'f' => Arg::Float(f32::from_be_bytes(reader.word()?)),
+            'd' => {
+                let bytes = reader.take(8)?.try_into().expect("Eight bytes were taken");
+                Arg::Float(f64::from_be_bytes(bytes) as f32)
+            }
+            's' | 'S' => Arg::String(reader.string()?),
+            'T' => Arg::Bool(true),
+            'F' => Arg::Bool(false),
+            'N' => Arg::Nil,
+            tag => return Err(PacketError::UnsupportedType(tag)),
+        });
+    }
+    messages.push(Message { address, args });
+    Ok(())
+}
+
+pub fn encode(message: &Message) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    push_string(&mut bytes, &message.address);
+    let tags: String = std::iter::once(',')
+        .chain(message.args.iter().map(|arg| match arg {
+            Arg::Int(_) => 'i',
+            Arg::Float(_) => 'f',
+            Arg::String(_) => 's',
+            Arg::Bool(true) => 'T',
+            Arg::Bool(false) => 'F',
+            Arg::Nil => 'N',
+        }))
+        .collect();
+    push_string(&mut bytes, &tags);
+    for arg in &message.args {
+        match arg {
+            Arg::Int(i) => bytes.extend_from_slice(&i.to_be_bytes()),
+            Arg::Float(f) => bytes.extend_from_slice(&f.to_be_bytes()),
+            Arg::String(s) => push_string(&mut bytes, s),
+            Arg::Bool(_) | Arg::Nil => {}
+        }
+    }
+    bytes
+}
+
+fn push_string(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend_from_slice(s.as_bytes());
+    bytes.resize(bytes.len() + padded(s.len() + 1) - s.len(), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, Arg, Message, PacketError};
+
+    #[test]
+    fn round_trip() {
+        let message = Message {
+            address: "/drum_machine/SetVoiceName".into(),
+            args: vec![
+                Arg::Int(3),
+                Arg::String("kick".into()),
+                Arg::Float(0.5),
+                Arg::Bool(true),
+                Arg::Nil,
+            ],
+        };
+        let bytes = encode(&message);
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(decode(&bytes), Ok(vec![message]));
+
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 2]),
+            Err(PacketError::Truncated)
+        );
+        assert_eq!(decode(b"tempo\0\0\0"), Err(PacketError::InvalidAddress));
+    }
+
+    #[test]
+    fn bundles_are_flattened() {
+        let first = encode(&Message {
+            address: "/a".into(),
+            args: vec![Arg::Int(1)],
+        });
+        let second = encode(&Message {
+            address: "/b".into(),
+            args: vec![],
+        });
+        let mut bundle = b"#bundle\0".to_vec();
+        bundle.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        for element in [&first, &second] {
+            bundle.extend_from_slice(&(element.len() as u32).to_be_bytes());
+            bundle.extend_from_slice(element);
+        }
+        let messages = decode(&bundle).unwrap();
+        let addresses: Vec<_> = messages.iter().map(|m| m.address.as_str()).collect();
+        assert_eq!(addresses, ["/a", "/b"]);
+    }
+}
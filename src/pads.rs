@@ -0,0 +1,208 @@
+use crate::{
+    control::{self, drum_machine},
+    deser::serialize,
+    json::{update_fields_or_fail, JsonUpdateKind},
+    midi,
+    render::command,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Action {
+    Renderer(Box<command::RequestKind>),
+    DrumMachine(drum_machine::RequestKind),
+    Controller(control::command::RequestKind),
+    // Every pad at once, like from a session. Pads can't have it, they run under the pads' lock.
+    Pads(Vec<Pad>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NoteTrigger {
+    pub channel: u8,
+    pub note: u8,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pad {
+    pub name: String,
+    pub trigger: Option<NoteTrigger>,
+    pub actions: Vec<Action>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestKind {
+    AddPad(Pad),
+    SetPad(usize, Pad),
+    RemovePad(usize),
+    TriggerPad(usize),
+    // They're saved with the session
+    SetPads(Vec<Pad>),
+}
+
+pub struct Pads {
+    pads: Vec<Pad>,
+    // The notes triggering them, the controller keeps them from the renderer's nodes
+    triggers_tx: watch::Sender<Vec<NoteTrigger>>,
+}
+
+impl Default for Pads {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pads {
+    pub fn new() -> Self {
+        Self {
+            pads: Vec::new(),
+            triggers_tx: watch::Sender::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe_triggers(&self) -> watch::Receiver<Vec<NoteTrigger>> {
+        self.triggers_tx.subscribe()
+    }
+
+    pub fn actions(&self, index: usize) -> Option<Vec<Action>> {
+        self.pads.get(index).map(|pad| pad.actions.clone())
+    }
+
+    // Pads triggered by a MIDI message, only note ons with a non zero velocity count
+    pub fn triggered_by(&self, message: &midi::Message) -> Vec<usize> {
+        let midi::MessageKind::NoteOn { note, velocity } = message.kind else {
+            return vec![];
+        };
+        if velocity == 0 {
+            return vec![];
+        }
+        let trigger = NoteTrigger {
+            channel: message.channel,
+            note,
+        };
+        self.pads
+            .iter()
+            .enumerate()
+            .filter(|(_, pad)| pad.trigger == Some(trigger))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    // Triggering is left to the caller, it's the one with access to the other subsystems
+    pub fn process_request(&mut self, kind: RequestKind) -> JsonUpdateKind {
+        let valid = match &kind {
+            RequestKind::AddPad(pad) | RequestKind::SetPad(_, pad) => pad.is_valid(),
+            RequestKind::SetPads(pads) => pads.iter().all(Pad::is_valid),
+            _ => true,
+        };
+        if !valid {
+            return JsonUpdateKind::Failed;
+        }
+        match kind {
+            RequestKind::AddPad(pad) => {
+                self.pads.push(pad);
+                self.pads_update()
+            }
+            RequestKind::SetPad(index, pad) => {
+                if let Some(p) = self.pads.get_mut(index) {
+                    *p = pad;
+                    self.pads_update()
+                } else {
+                    JsonUpdateKind::InvalidId
+                }
+            }
+            RequestKind::RemovePad(index) => {
+                if index < self.pads.len() {
+                    self.pads.remove(index);
+                    self.pads_update()
+                } else {
+                    JsonUpdateKind::InvalidId
+                }
+            }
+            RequestKind::TriggerPad(_) => JsonUpdateKind::Denied,
+            RequestKind::SetPads(pads) => {
+                self.pads = pads;
+                self.pads_update()
+            }
+        }
+    }
+
+    // After every change
+    fn pads_update(&self) -> JsonUpdateKind {
+        let triggers = self.pads.iter().filter_map(|pad| pad.trigger).collect();
+        self.triggers_tx.send_replace(triggers);
+        update_fields_or_fail(|updates| {
+            updates.push(("pads".to_owned(), serialize(&self.pads)?));
+            Ok(())
+        })
+    }
+}
+
+impl Pad {
+    fn is_valid(&self) -> bool {
+        !self
+            .actions
+            .iter()
+            .any(|action| matches!(action, Action::Pads(_)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, NoteTrigger, Pad, Pads, RequestKind};
+    use crate::{
+        json::JsonUpdateKind,
+        midi::{Message, MessageKind},
+    };
+
+    #[test]
+    fn midi_triggers() {
+        let mut pads = Pads::new();
+        let trigger = Some(NoteTrigger {
+            channel: 9,
+            note: 36,
+        });
+        pads.pads = vec![
+            Pad {
+                trigger,
+                ..Default::default()
+            },
+            Pad::default(),
+            Pad {
+                trigger,
+                ..Default::default()
+            },
+        ];
+        let note_on =
+            |channel, velocity| Message::new(channel, MessageKind::NoteOn { note: 36, velocity });
+        assert_eq!(pads.triggered_by(&note_on(9, 100)), vec![0, 2]);
+        assert!(pads.triggered_by(&note_on(0, 100)).is_empty());
+        assert!(pads.triggered_by(&note_on(9, 0)).is_empty());
+    }
+
+    #[test]
+    fn pads_come_with_the_session() {
+        let mut pads = Pads::new();
+        let triggers = pads.subscribe_triggers();
+        let trigger = NoteTrigger {
+            channel: 9,
+            note: 36,
+        };
+        let pad = Pad {
+            trigger: Some(trigger),
+            ..Default::default()
+        };
+        let res = pads.process_request(RequestKind::SetPads(vec![pad.clone(), Pad::default()]));
+        assert!(matches!(res, JsonUpdateKind::UpdateFields(_)));
+        assert_eq!(*triggers.borrow(), [trigger]);
+
+        // running under the lock of the pads, they can't replace them
+        let replacing = Pad {
+            actions: vec![Action::Pads(vec![])],
+            ..pad
+        };
+        let res = pads.process_request(RequestKind::AddPad(replacing));
+        assert_eq!(res, JsonUpdateKind::Failed);
+        assert_eq!(pads.pads.len(), 2);
+    }
+}
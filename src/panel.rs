@@ -0,0 +1,260 @@
+// Front panel of a box without a screen: a rotary encoder changes a parameter and a small
+// display shows the song and the parameters, with a mark on the one the knob changes. Requests
+// go through the same handler as the ones of the web clients, so they see every change.
+
+mod encoder;
+mod lcd;
+
+use crate::{
+    app::App,
+    config::{self, PanelParameter},
+    control::drum_machine,
+    gpio::{self, Debouncer, Edge, Pin},
+    setlist,
+    webserver::ClientMessageKind,
+};
+use encoder::Decoder;
+use lcd::Lcd;
+use serde_json::Value;
+use std::{
+    net::SocketAddr,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+const POLL_PERIOD: Duration = Duration::from_millis(1);
+// The display follows the state at this pace, only the lines that change get written
+const REDRAW_PERIOD: Duration = Duration::from_millis(100);
+const MIN_TEMPO_BPM: f32 = 20.0;
+const MAX_TEMPO_BPM: f32 = 300.0;
+
+struct Knob {
+    a: Pin,
+    b: Pin,
+    decoder: Decoder,
+    button: Option<(Pin, Debouncer)>,
+}
+
+impl Knob {
+    fn open(encoder: &config::Encoder) -> std::io::Result<Self> {
+        let button = match encoder.button {
+            Some(pin) => Some((
+                Pin::open(pin, false)?,
+                Debouncer::new(gpio::DEBOUNCE_TIME, Instant::now()),
+            )),
+            None => None,
+        };
+        Ok(Self {
+            a: Pin::open(encoder.a, false)?,
+            b: Pin::open(encoder.b, false)?,
+            decoder: Decoder::default(),
+            button,
+        })
+    }
+
+    // (detents turned, whether the button got pressed)
+    fn poll(&mut self, now: Instant) -> std::io::Result<(i8, bool)> {
+        let detents = self
+            .decoder
+            .update(self.a.is_pressed()?, self.b.is_pressed()?);
+        let pressed = match &mut self.button {
+            Some((pin, debouncer)) => {
+                debouncer.update(pin.is_pressed()?, now) == Some(Edge::Pressed)
+            }
+            None => false,
+        };
+        Ok((detents, pressed))
+    }
+}
+
+// Runs for as long as AMI runs, a part of the panel that can't be opened is left out
+pub async fn run(panel: config::Panel, app: App) {
+    let mut knob = panel.encoder.as_ref().and_then(|encoder| {
+        Knob::open(encoder)
+            .inspect(|_| info!("| Panel encoder on GPIO {} and {}", encoder.a, encoder.b))
+            .inspect_err(|e| warn!("Can't open the panel encoder: {e}"))
+            .ok()
+    });
+    let display = panel.display.map(|lcd| {
+        info!("| Panel display on {:?}", lcd.bus);
+        spawn_display(lcd)
+    });
+    if knob.is_none() && display.is_none() {
+        return;
+    }
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let parameters = panel.parameters;
+    let mut selected = 0;
+    let mut shown = Vec::new();
+    let mut redraw_at = Instant::now();
+    let mut interval = tokio::time::interval(POLL_PERIOD);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        if let Some(Ok((detents, pressed))) = knob.as_mut().map(|knob| knob.poll(now)) {
+            if pressed && !parameters.is_empty() {
+                selected = (selected + 1) % parameters.len();
+                redraw_at = now;
+            }
+            if let Some(&parameter) = parameters.get(selected).filter(|_| detents != 0) {
+                let state = app.cache.lock().await.get().clone();
+                for req in turn(&state, parameter, detents) {
+                    app.handle_client_message(addr, req).await;
+                }
+            }
+        }
+        let Some((display_tx, rows)) = &display else {
+            continue;
+        };
+        if now < redraw_at {
+            continue;
+        }
+        redraw_at = now + REDRAW_PERIOD;
+        let lines = lines(app.cache.lock().await.get(), &parameters, selected, *rows);
+        if lines != shown {
+            if display_tx.send(lines.clone()).is_err() {
+                return;
+            }
+            shown = lines;
+        }
+    }
+}
+
+// Writing to the display takes a few ms, so it's done on a thread of its own. Gives the rows of
+// the display and a sender of the lines to show.
+fn spawn_display(display: config::Display) -> (mpsc::Sender<Vec<String>>, usize) {
+    let (lines_tx, lines_rx) = mpsc::channel::<Vec<String>>();
+    let rows = display.rows;
+    std::thread::spawn(move || {
+        let res = Lcd::open(&display.bus, display.address, display.columns, display.rows);
+        let mut lcd = match res {
+            Ok(lcd) => lcd,
+            Err(e) => {
+                warn!("Can't open the panel display: {e}");
+                return;
+            }
+        };
+        while let Ok(lines) = lines_rx.recv() {
+            if let Err(e) = lcd.show(&lines) {
+                warn!("Panel display error: {e}");
+                break;
+            }
+        }
+    });
+    (lines_tx, rows)
+}
+
+// The requests of turning the knob by some detents, the state is the one the clients get
+fn turn(state: &Value, parameter: PanelParameter, detents: i8) -> Vec<ClientMessageKind> {
+    type DK = drum_machine::RequestKind;
+    match parameter {
+        PanelParameter::Entry => {
+            let req = if detents > 0 {
+                setlist::RequestKind::Next
+            } else {
+                setlist::RequestKind::Previous
+            };
+            (0..detents.unsigned_abs())
+                .map(|_| ClientMessageKind::SetlistRequest(req.clone()))
+                .collect()
+        }
+        PanelParameter::Tempo => {
+            let tempo_bpm = state["drum_machine"]["tempo_bpm"].as_f64().unwrap_or(120.0);
+            let tempo_bpm =
+                (tempo_bpm.round() as f32 + detents as f32).clamp(MIN_TEMPO_BPM, MAX_TEMPO_BPM);
+            let req = DK::SetTempoBpm(tempo_bpm);
+            vec![ClientMessageKind::DrumMachineRequest(req)]
+        }
+        PanelParameter::DrumMachine => {
+            let req = DK::SetEnabled(detents > 0);
+            vec![ClientMessageKind::DrumMachineRequest(req)]
+        }
+    }
+}
+
+// The song on the first row and the parameters on the others, scrolled to the selected one
+fn lines(
+    state: &Value,
+    parameters: &[PanelParameter],
+    selected: usize,
+    rows: usize,
+) -> Vec<String> {
+    let setlist = &state["setlist"];
+    let entries = setlist["entries"].as_array().map_or(0, Vec::len);
+    let current = setlist["current"].as_u64().map(|index| index as usize);
+    let name = current.and_then(|index| setlist["entries"][index]["name"].as_str());
+    let title = match (current, name) {
+        (Some(index), Some(name)) => format!("{} {name}", index + 1),
+        _ => "AMI".to_owned(),
+    };
+
+    let visible = rows.saturating_sub(1).max(1);
+    let first = (selected + 1).saturating_sub(visible);
+    let mut lines = vec![title];
+    for (index, parameter) in parameters.iter().enumerate().skip(first).take(visible) {
+        let mark = if index == selected { '>' } else { ' ' };
+        let value = match parameter {
+            PanelParameter::Entry => match current {
+                Some(index) => format!("Song {}/{entries}", index + 1),
+                None => format!("Song -/{entries}"),
+            },
+            PanelParameter::Tempo => {
+                let tempo_bpm = state["drum_machine"]["tempo_bpm"].as_f64().unwrap_or(0.0);
+                format!("Tempo {tempo_bpm:.0}")
+            }
+            PanelParameter::DrumMachine => {
+                let enabled = state["drum_machine"]["enabled"].as_bool();
+                let enabled = if enabled == Some(true) { "on" } else { "off" };
+                format!("Drums {enabled}")
+            }
+        };
+        lines.push(format!("{mark}{value}"));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lines, turn};
+    use crate::config::PanelParameter;
+    use serde_json::json;
+
+    #[test]
+    fn shows_and_turns_parameters() {
+        let state = json!({
+            "drum_machine": { "enabled": true, "tempo_bpm": 119.6 },
+            "setlist": {
+                "entries": [{ "name": "Intro" }, { "name": "Blues in F" }],
+                "current": 1,
+            },
+        });
+        let parameters = [
+            PanelParameter::Entry,
+            PanelParameter::Tempo,
+            PanelParameter::DrumMachine,
+        ];
+        assert_eq!(
+            lines(&state, &parameters, 0, 2),
+            ["2 Blues in F", ">Song 2/2"]
+        );
+        assert_eq!(
+            lines(&state, &parameters, 2, 3),
+            ["2 Blues in F", " Tempo 120", ">Drums on"]
+        );
+        assert_eq!(lines(&json!({}), &[], 0, 2), ["AMI"]);
+
+        let requests =
+            |parameter, detents| serde_json::to_value(turn(&state, parameter, detents)).unwrap();
+        assert_eq!(
+            requests(PanelParameter::Tempo, -3),
+            json!([{ "DrumMachineRequest": { "SetTempoBpm": 117.0 } }])
+        );
+        assert_eq!(
+            requests(PanelParameter::Entry, 2),
+            json!([{ "SetlistRequest": "Next" }, { "SetlistRequest": "Next" }])
+        );
+    }
+}
@@ -0,0 +1,54 @@
+// Quadrature decoding of a rotary encoder. The two pins go through a gray code, a step in one
+// direction is 00 01 11 10, every transition that skips a state is taken for bounce.
+
+// Transitions of a detent of the usual encoders
+const TRANSITIONS_PER_DETENT: i8 = 4;
+
+// By (previous state << 2 | state), where a state is (a << 1 | b)
+const TRANSITION_TABLE: [i8; 16] = [0, 1, -1, 0, -1, 0, 0, 1, 1, 0, 0, -1, 0, -1, 1, 0];
+
+#[derive(Debug, Default, Clone)]
+pub struct Decoder {
+    state: u8,
+    transitions: i8,
+}
+
+impl Decoder {
+    // Detents turned since the last update, clockwise is positive
+    pub fn update(&mut self, a: bool, b: bool) -> i8 {
+        let state = (a as u8) << 1 | b as u8;
+        self.transitions += TRANSITION_TABLE[(self.state << 2 | state) as usize];
+        self.state = state;
+        let detents = self.transitions / TRANSITIONS_PER_DETENT;
+        self.transitions %= TRANSITIONS_PER_DETENT;
+        detents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Decoder;
+
+    fn turn(decoder: &mut Decoder, states: &[(bool, bool)]) -> i8 {
+        states.iter().map(|&(a, b)| decoder.update(a, b)).sum()
+    }
+
+    #[test]
+    fn counts_detents() {
+        let clockwise = [(false, true), (true, true), (true, false), (false, false)];
+        let mut decoder = Decoder::default();
+        assert_eq!(turn(&mut decoder, &clockwise[..3]), 0);
+        assert_eq!(decoder.update(false, false), 1);
+        assert_eq!(turn(&mut decoder, &clockwise), 1);
+
+        let mut counter_clockwise = clockwise;
+        counter_clockwise.reverse();
+        counter_clockwise.rotate_left(1);
+        assert_eq!(turn(&mut decoder, &counter_clockwise), -1);
+
+        // bouncing back and forth on one pin goes nowhere
+        let bounce = [(false, true), (false, false), (false, true), (false, false)];
+        assert_eq!(turn(&mut decoder, &bounce), 0);
+        assert_eq!(turn(&mut decoder, &clockwise), 1);
+    }
+}
@@ -0,0 +1,127 @@
+// HD44780 compatible displays through a PCF8574 I2C backpack, the character LCDs and OLEDs
+// sold for the Raspberry Pi. The backpack drives the controller in 4-bit mode, with the
+// register select on P0, enable on P2, the backlight on P3 and the data on P4 to P7.
+
+use std::{fs::File, io::Write, os::fd::AsRawFd, path::Path, thread, time::Duration};
+
+// From linux/i2c-dev.h
+const I2C_SLAVE: u32 = 0x0703;
+
+const REGISTER_SELECT: u8 = 0x01;
+const ENABLE: u8 = 0x04;
+const BACKLIGHT: u8 = 0x08;
+
+const CLEAR: u8 = 0x01;
+const ENTRY_MODE_LEFT_TO_RIGHT: u8 = 0x06;
+const DISPLAY_ON: u8 = 0x0C;
+const FUNCTION_SET_4_BIT_2_LINES: u8 = 0x28;
+const SET_ADDRESS: u8 = 0x80;
+
+pub struct Lcd {
+    bus: File,
+    columns: usize,
+    rows: usize,
+    // What's on the display, so only the lines that change get written
+    lines: Vec<String>,
+}
+
+impl Lcd {
+    pub fn open(bus: &Path, address: u16, columns: usize, rows: usize) -> std::io::Result<Self> {
+        let bus = File::options().read(true).write(true).open(bus)?;
+        let address = libc::c_ulong::from(address);
+        // SAFETY: I2C_SLAVE takes the address as the argument and the descriptor stays open
+        if unsafe { libc::ioctl(bus.as_raw_fd(), I2C_SLAVE as _, address) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut lcd = Self {
+            bus,
+            columns,
+            rows,
+            lines: vec![String::new(); rows],
+        };
+        lcd.init()?;
+        Ok(lcd)
+    }
+
+    // Lines past the rows are left out, characters past the columns are cut off
+    pub fn show(&mut self, lines: &[String]) -> std::io::Result<()> {
+        for row in 0..self.rows {
+            let line = fit(lines.get(row).map_or("", |line| line), self.columns);
+            if self.lines[row] == line {
+                continue;
+            }
+            self.command(SET_ADDRESS | row_address(row, self.columns))?;
+            let mut bytes = Vec::new();
+            for c in line.bytes() {
+                push_byte(&mut bytes, c, REGISTER_SELECT);
+            }
+            self.bus.write_all(&bytes)?;
+            self.lines[row] = line;
+        }
+        Ok(())
+    }
+
+    // The controller starts in 8-bit mode or halfway through a 4-bit byte, the three 8-bit
+    // function sets get it to a known state from both
+    fn init(&mut self) -> std::io::Result<()> {
+        thread::sleep(Duration::from_millis(50));
+        for wait in [4100, 100, 100] {
+            self.bus.write_all(&nibble(0x30, 0))?;
+            thread::sleep(Duration::from_micros(wait));
+        }
+        self.bus.write_all(&nibble(0x20, 0))?;
+        self.command(FUNCTION_SET_4_BIT_2_LINES)?;
+        self.command(DISPLAY_ON)?;
+        self.command(ENTRY_MODE_LEFT_TO_RIGHT)?;
+        self.command(CLEAR)?;
+        thread::sleep(Duration::from_millis(2));
+        self.lines.fill(" ".repeat(self.columns));
+        Ok(())
+    }
+
+    fn command(&mut self, command: u8) -> std::io::Result<()> {
+        let mut bytes = Vec::new();
+        push_byte(&mut bytes, command, 0);
+        self.bus.write_all(&bytes)
+    }
+}
+
+// The high nibble of `data` latched by a pulse on enable
+fn nibble(data: u8, mode: u8) -> [u8; 2] {
+    let bits = (data & 0xF0) | mode | BACKLIGHT;
+    [bits | ENABLE, bits]
+}
+
+fn push_byte(bytes: &mut Vec<u8>, byte: u8, mode: u8) {
+    bytes.extend(nibble(byte, mode));
+    bytes.extend(nibble(byte << 4, mode));
+}
+
+// Rows 2 and 3 continue rows 0 and 1 in the memory of the controller
+fn row_address(row: usize, columns: usize) -> u8 {
+    let start = if row.is_multiple_of(2) { 0x00 } else { 0x40 };
+    (start + (row / 2) * columns) as u8
+}
+
+// Padded with spaces to the columns, the controller only has ASCII
+fn fit(line: &str, columns: usize) -> String {
+    let ascii = line.chars().map(|c| if c.is_ascii() { c } else { '?' });
+    let line: String = ascii.take(columns).collect();
+    format!("{line:<columns$}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fit, nibble, row_address};
+
+    #[test]
+    fn lays_out_lines() {
+        assert_eq!(fit("Tempo 120", 12), "Tempo 120   ");
+        assert_eq!(fit("Café del Mar", 6), "Caf? d");
+        assert_eq!(row_address(1, 16), 0x40);
+        assert_eq!(row_address(2, 20), 0x14);
+        assert_eq!(row_address(3, 20), 0x54);
+        // RS, backlight and the high nibble, with and without enable
+        assert_eq!(nibble(0x41, 0x01), [0x4D, 0x49]);
+    }
+}
@@ -26,6 +26,10 @@ impl VirtualPaths {
         None
     }
 
+    pub fn roots(&self) -> impl Iterator<Item = &Path> {
+        self.paths.keys().map(PathBuf::as_path)
+    }
+
     pub fn translate_back(&self, path: &Path) -> Option<PathBuf> {
         let path = path.to_str().expect("Path is not a valid UTF-8");
         let path = &PathBuf::from(&path.replace(std::path::MAIN_SEPARATOR, "/"));
@@ -0,0 +1,207 @@
+// A bank of sessions to switch between on stage, a whole sound and mix at a time. The session
+// files get read when the slots are set and the files they play get preloaded, so a switch only
+// waits for the nodes to take the settings.
+
+use crate::{
+    deser::serialize,
+    json::{update_fields_or_fail, JsonUpdateKind},
+    midi,
+    pads::Action,
+    path::VirtualPaths,
+    session,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestKind {
+    // Virtual paths of session files, one slot each. None of them get set if one can't be read.
+    SetSlots(Vec<PathBuf>),
+    // Program changes on the channel switch to the slot of their number
+    SetProgramChangeChannel(Option<u8>),
+    Switch(usize),
+}
+
+pub struct Performances {
+    // (path, session)
+    slots: Vec<(PathBuf, Value)>,
+    current: Option<usize>,
+    program_change_channel: Option<u8>,
+    virtual_paths: VirtualPaths,
+}
+
+impl Performances {
+    pub fn new(virtual_paths: VirtualPaths) -> Self {
+        Self {
+            slots: vec![],
+            current: None,
+            program_change_channel: None,
+            virtual_paths,
+        }
+    }
+
+    // The switch a program change stands for, if the message is one
+    pub fn switch_by(&self, message: &midi::Message) -> Option<RequestKind> {
+        match message.kind {
+            midi::MessageKind::ProgramChange { program }
+                if self.program_change_channel == Some(message.channel) =>
+            {
+                Some(RequestKind::Switch(program as usize))
+            }
+            _ => None,
+        }
+    }
+
+    // Virtual paths of the files the nodes of the slots play, to preload
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self
+            .slots
+            .iter()
+            .filter_map(|(_, session)| session["nodes"].as_array())
+            .flatten()
+            .filter_map(|node| node["instance"]["loaded_file"].as_str())
+            .map(PathBuf::from)
+            .collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    // Switching is left to the caller like triggering pads, it gets the actions bringing the
+    // session of the slot up
+    pub fn process_request(&mut self, kind: RequestKind) -> (JsonUpdateKind, Vec<Action>) {
+        match kind {
+            RequestKind::SetSlots(paths) => (self.set_slots(&paths), vec![]),
+            RequestKind::SetProgramChangeChannel(channel) => {
+                if channel.is_some_and(|channel| channel > 15) {
+                    return (JsonUpdateKind::Failed, vec![]);
+                }
+                self.program_change_channel = channel;
+                (self.performances_update(), vec![])
+            }
+            RequestKind::Switch(index) => {
+                let actions = self
+                    .slots
+                    .get(index)
+                    .and_then(|(_, session)| session::actions(session));
+                match actions {
+                    Some(actions) => {
+                        self.current = Some(index);
+                        (self.performances_update(), actions)
+                    }
+                    None => (JsonUpdateKind::InvalidId, vec![]),
+                }
+            }
+        }
+    }
+
+    fn set_slots(&mut self, paths: &[PathBuf]) -> JsonUpdateKind {
+        let mut slots = Vec::with_capacity(paths.len());
+        for path in paths {
+            match self.read_session(path) {
+                Some(session) => slots.push((path.clone(), session)),
+                None => return JsonUpdateKind::Failed,
+            }
+        }
+        self.slots = slots;
+        self.current = None;
+        self.performances_update()
+    }
+
+    // Only files that bring up a renderer and a controller make a slot
+    fn read_session(&self, path: &Path) -> Option<Value> {
+        let path = self.virtual_paths.translate(path)?;
+        let file = fs::read_to_string(path).ok()?;
+        let session = session::migrate(serde_json::from_str(&file).ok()?).ok()?;
+        session::actions(&session)?;
+        Some(session)
+    }
+
+    fn performances_update(&self) -> JsonUpdateKind {
+        let paths: Vec<&Path> = self.slots.iter().map(|(path, _)| path.as_path()).collect();
+        update_fields_or_fail(|updates| {
+            updates.push(("slots".to_owned(), serialize(paths)?));
+            updates.push(("current".to_owned(), serialize(self.current)?));
+            updates.push((
+                "program_change_channel".to_owned(),
+                serialize(self.program_change_channel)?,
+            ));
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Performances, RequestKind};
+    use crate::{
+        json::JsonUpdateKind,
+        midi::{Message, MessageKind},
+        path::VirtualPaths,
+    };
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    #[test]
+    fn switches_between_slots() {
+        let dir = std::env::temp_dir().join(format!("ami-performances-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut virtual_paths = VirtualPaths::default();
+        virtual_paths.insert(PathBuf::from("beats:"), dir.clone());
+        let session = |file: &str| {
+            json!({
+                "nodes": [{ "kind": "OxiSynth", "instance": { "loaded_file": file } }],
+                "controller": {
+                    "nodes": [],
+                    "rhythm": { "num_beats": 4, "num_divs": 4 },
+                    "tempo_bpm": 90.0,
+                },
+            })
+        };
+        let ballad = session("samples:/piano.sf2").to_string();
+        let rocker = session("samples:/organ.sf2").to_string();
+        std::fs::write(dir.join("ballad.session"), ballad).unwrap();
+        std::fs::write(dir.join("rocker.session"), rocker).unwrap();
+        std::fs::write(dir.join("broken.session"), "{}").unwrap();
+
+        let mut performances = Performances::new(virtual_paths);
+        let slots = vec![
+            PathBuf::from("beats:/ballad.session"),
+            PathBuf::from("beats:/rocker.session"),
+        ];
+        let (res, _) = performances.process_request(RequestKind::SetSlots(slots.clone()));
+        assert!(matches!(res, JsonUpdateKind::UpdateFields(_)));
+        let mut broken = slots.clone();
+        broken.push(PathBuf::from("beats:/broken.session"));
+        let (res, _) = performances.process_request(RequestKind::SetSlots(broken));
+        assert_eq!(res, JsonUpdateKind::Failed);
+        let files = [
+            PathBuf::from("samples:/organ.sf2"),
+            PathBuf::from("samples:/piano.sf2"),
+        ];
+        assert_eq!(performances.files(), files);
+
+        let (_, actions) = performances.process_request(RequestKind::Switch(1));
+        assert_eq!(actions.len(), 2);
+        assert_eq!(performances.current, Some(1));
+        let (res, actions) = performances.process_request(RequestKind::Switch(2));
+        assert_eq!(res, JsonUpdateKind::InvalidId);
+        assert!(actions.is_empty());
+
+        let change = Message::new(2, MessageKind::ProgramChange { program: 0 });
+        assert_eq!(performances.switch_by(&change), None);
+        performances.process_request(RequestKind::SetProgramChangeChannel(Some(2)));
+        assert_eq!(
+            performances.switch_by(&change),
+            Some(RequestKind::Switch(0))
+        );
+        let req = RequestKind::SetProgramChangeChannel(Some(16));
+        let (res, _) = performances.process_request(req);
+        assert_eq!(res, JsonUpdateKind::Failed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
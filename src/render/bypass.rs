@@ -0,0 +1,77 @@
+// Nodes taken out of the mix without unloading them, to try a sound without a layer or to keep
+// one ready for later. A bypassed node still gets its messages and renders, so it comes back
+// with the notes that are held, but nothing of it gets to the output. There are only
+// instruments so far, an effect would let its input through instead.
+
+use super::moved_node;
+
+#[derive(Debug, Clone, Default)]
+pub struct Bypass {
+    // Ids of the bypassed nodes, in order
+    nodes: Vec<usize>,
+}
+
+impl Bypass {
+    pub fn get(&self) -> &[usize] {
+        &self.nodes
+    }
+
+    pub fn set(&mut self, mut nodes: Vec<usize>) {
+        nodes.sort_unstable();
+        nodes.dedup();
+        self.nodes = nodes;
+    }
+
+    pub fn set_bypassed(&mut self, node: usize, bypassed: bool) {
+        match (self.nodes.binary_search(&node), bypassed) {
+            (Err(index), true) => self.nodes.insert(index, node),
+            (Ok(index), false) => {
+                self.nodes.remove(index);
+            }
+            _ => (),
+        }
+    }
+
+    pub fn is_bypassed(&self, node: usize) -> bool {
+        self.nodes.binary_search(&node).is_ok()
+    }
+
+    // The nodes after it move up
+    pub fn remove_node(&mut self, node: usize) {
+        self.nodes.retain(|&id| id != node);
+        for id in &mut self.nodes {
+            if *id > node {
+                *id -= 1;
+            }
+        }
+    }
+
+    // The flag follows the node, the nodes in between shift by one
+    pub fn move_node(&mut self, id: usize, new_id: usize) {
+        let nodes = self.nodes.iter().map(|&node| moved_node(node, id, new_id));
+        self.set(nodes.collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bypass;
+
+    #[test]
+    fn follows_the_nodes() {
+        let mut bypass = Bypass::default();
+        bypass.set_bypassed(3, true);
+        bypass.set_bypassed(1, true);
+        bypass.set_bypassed(1, true);
+        assert_eq!(bypass.get(), [1, 3]);
+        assert!(bypass.is_bypassed(3) && !bypass.is_bypassed(2));
+
+        bypass.move_node(1, 4);
+        assert_eq!(bypass.get(), [2, 4]);
+        bypass.remove_node(0);
+        assert_eq!(bypass.get(), [1, 3]);
+        bypass.remove_node(3);
+        bypass.set_bypassed(1, false);
+        assert!(bypass.get().is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+// Capturing of the master bus to a WAV file. The audio thread only hands over buffers, a
+// thread of its own does the writing, so a slow disk can't cause dropouts.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::mpsc,
+    thread::JoinHandle,
+    time::Instant,
+};
+
+// Buffers on their way to the disk, when the writer falls this far behind audio gets dropped
+const MAX_PENDING_BUFFERS: usize = 256;
+
+struct Block {
+    // Interleaved left and right
+    samples: Vec<f32>,
+    // When the buffer started, the renderer treats a buffer as the one before the time it's
+    // rendered at
+    time: Instant,
+}
+
+// The audio thread's end, it allocates only until enough buffers are in circulation
+pub struct Tap {
+    block_tx: mpsc::SyncSender<Block>,
+    spare_rx: mpsc::Receiver<Vec<f32>>,
+}
+
+impl Tap {
+    pub fn push(&mut self, lbuf: &[f32], rbuf: &[f32], time: Instant) {
+        let mut samples = self.spare_rx.try_recv().unwrap_or_default();
+        samples.clear();
+        samples.extend(lbuf.iter().zip(rbuf).flat_map(|(&l, &r)| [l, r]));
+        _ = self.block_tx.try_send(Block { samples, time });
+    }
+}
+
+pub struct Capture {
+    // Virtual path of the file
+    pub path: PathBuf,
+    writer: JoinHandle<io::Result<u64>>,
+}
+
+impl Capture {
+    // Waits for the file to be complete once the tap is gone, returns the number of frames
+    pub fn finish(self) -> io::Result<u64> {
+        self.writer
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("The capture writer panicked")))
+    }
+}
+
+// Frame 0 of the file is the audio at `start`, audio from before is left out and silence fills
+// in until the first buffer
+pub fn start(path: PathBuf, file: File, sample_rate: u32, start: Instant) -> (Tap, Capture) {
+    let (block_tx, block_rx) = mpsc::sync_channel(MAX_PENDING_BUFFERS);
+    let (spare_tx, spare_rx) = mpsc::channel();
+    let writer = std::thread::Builder::new()
+        .name("audio-capture".into())
+        .spawn(move || write_wav(file, sample_rate, start, block_rx, spare_tx))
+        .expect("Failed to spawn the audio capture writer");
+    (Tap { block_tx, spare_rx }, Capture { path, writer })
+}
+
+fn write_wav(
+    file: File,
+    sample_rate: u32,
+    start: Instant,
+    block_rx: mpsc::Receiver<Block>,
+    spare_tx: mpsc::Sender<Vec<f32>>,
+) -> io::Result<u64> {
+    let mut out = BufWriter::new(file);
+    write_header(&mut out, sample_rate, 0)?;
+    let mut frames = 0u64;
+    while let Ok(block) = block_rx.recv() {
+        let offset = if block.time >= start {
+            (block.time - start).as_secs_f64()
+        } else {
+            -(start - block.time).as_secs_f64()
+        };
+        let first_frame = (offset * sample_rate as f64).round() as i64;
+        // silence for the gap up to the first buffer, the rest of them simply follow
+        if frames == 0 && first_frame > 0 {
+            for _ in 0..first_frame * 2 {
+                out.write_all(&0f32.to_le_bytes())?;
+            }
+            frames = first_frame as u64;
+        }
+        let skip = if frames == 0 {
+            (-first_frame).max(0) as usize * 2
+        } else {
+            0
+        };
+        let samples = block.samples.get(skip..).unwrap_or_default();
+        for sample in samples {
+            out.write_all(&sample.to_le_bytes())?;
+        }
+        frames += samples.len() as u64 / 2;
+        _ = spare_tx.send(block.samples);
+    }
+    out.seek(SeekFrom::Start(0))?;
+    write_header(&mut out, sample_rate, frames)?;
+    out.flush()?;
+    Ok(frames)
+}
+
+// 32 bit float stereo, no conversion or dithering needed and what DAWs take anyway
+fn write_header(out: &mut impl Write, sample_rate: u32, frames: u64) -> io::Result<()> {
+    const CHANNELS: u16 = 2;
+    const BYTES_PER_SAMPLE: u16 = 4;
+    let data_len = (frames * (CHANNELS * BYTES_PER_SAMPLE) as u64).min(u32::MAX as u64 - 36);
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len as u32).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // IEEE float
+    out.write_all(&3u16.to_le_bytes())?;
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    let block_align = CHANNELS * BYTES_PER_SAMPLE;
+    out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&(BYTES_PER_SAMPLE * 8).to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&(data_len as u32).to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::start;
+    use std::{
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn wav_from_the_start() {
+        let path = std::env::temp_dir().join(format!("ami-capture-{}.wav", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        // 1 kHz, so a frame per millisecond
+        let (mut tap, capture) = start(PathBuf::from("rec:/take.wav"), file, 1000, now + ms(2));
+        tap.push(&[1.0; 4], &[-1.0; 4], now);
+        tap.push(&[0.5; 4], &[-0.5; 4], now + ms(4));
+        drop(tap);
+        assert_eq!(capture.finish().unwrap(), 6);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 6 * 8);
+        let samples: Vec<f32> = bytes[44..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        // the first two frames were before the start
+        assert_eq!(samples[..4], [1.0, -1.0, 1.0, -1.0]);
+        assert_eq!(samples[4..6], [0.5, -0.5]);
+        assert_eq!(samples.len(), 12);
+    }
+}
@@ -10,4 +10,5 @@ pub enum UpdateMidiFilterKind {
     ProgramChange(bool),
     ChannelAftertouch(bool),
     PitchWheel(bool),
+    SystemExclusive(bool),
 }
\ No newline at end of file
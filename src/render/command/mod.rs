@@ -1,5 +1,10 @@
+use crate::deser::NodeState;
 use crate::json::JsonUpdateKind;
-use crate::render::node;
+use crate::midi;
+use crate::render::{
+    expression, groups::Group, layers::Instrument, modulation::Route, node, octave, outputs,
+    pitch_shift, zones::Zone,
+};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
@@ -27,6 +32,71 @@ pub enum RequestKind {
     RemoveNode { id: usize },
     CloneNode { id: usize },
     MoveNode { id: usize, new_id: usize },
+    // Silences every node, for stuck notes
+    Panic,
+    // Of every node but those ignoring it, up to MAX_GLOBAL_TRANSPOSITION semitones either way
+    SetGlobalTransposition(i8),
+    // The controller sweeping the global transposition, none for no controller
+    SetTranspositionCc(Option<midi::ControlChangeKind>),
+    // Octaves up or down on top of the global transposition, up to octave::MAX_OCTAVES
+    SetOctaveShift(i8),
+    // The keys or controllers shifting it an octave up and down, they don't play
+    SetOctaveShiftTriggers(octave::Triggers),
+    // All of them at once, like from a setlist entry
+    SetZones(Vec<Zone>),
+    AddZone(Zone),
+    SetZone { index: usize, zone: Zone },
+    RemoveZone { index: usize },
+    SetLayeredInstruments(Vec<Instrument>),
+    AddLayeredInstrument(Instrument),
+    SetLayeredInstrument { index: usize, instrument: Instrument },
+    RemoveLayeredInstrument { index: usize },
+    // The one that gets the input, none for the nodes outside of them only
+    SelectLayeredInstrument(Option<usize>),
+    // The expression pedal handled by the renderer, none leaves it to the nodes
+    SetExpression(Option<expression::Settings>),
+    // Out of the mix while staying loaded
+    SetBypassed { id: usize, bypassed: bool },
+    // Mixed together, muted and soloed as a whole
+    SetGroups(Vec<Group>),
+    AddGroup(Group),
+    SetGroup { index: usize, group: Group },
+    RemoveGroup { index: usize },
+    // To a stereo pair of the audio output, 0 for outputs 1/2, overriding the one of its group
+    SetOutputPair { id: usize, pair: usize },
+    // Pairs getting the main mix on top of their nodes, like in-ear monitors with the click
+    SetMonitorPairs(Vec<usize>),
+    // Of the node's output, replacing the one it has
+    SetPitchShift(pitch_shift::Shift),
+    RemovePitchShift { id: usize },
+    SetModulationRoutes(Vec<Route>),
+    AddModulationRoute(Route),
+    SetModulationRoute { index: usize, route: Route },
+    RemoveModulationRoute { index: usize },
+    // Everything at once, like from a snapshot
+    SetSetup(Setup),
+}
+
+// The nodes and how the input gets to them, named like in the state the clients get. Nodes of
+// the same kind as the one in their place keep playing and only take the settings, the others
+// get replaced.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Setup {
+    pub nodes: Vec<NodeState>,
+    pub zones: Vec<Zone>,
+    pub layered_instruments: Vec<Instrument>,
+    pub selected_layered_instrument: Option<usize>,
+    pub modulation_routes: Vec<Route>,
+    pub expression: Option<expression::Settings>,
+    pub bypassed: Vec<usize>,
+    pub groups: Vec<Group>,
+    pub output_routes: Vec<outputs::Route>,
+    pub monitor_pairs: Vec<usize>,
+    pub pitch_shifts: Vec<pitch_shift::Shift>,
+    pub global_transposition: i8,
+    pub transposition_cc: Option<midi::ControlChangeKind>,
+    pub octave_shift_triggers: octave::Triggers,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -54,4 +124,30 @@ pub enum ResponseKind {
         id: usize,
         new_id: usize,
     },
+    Panic,
+    GlobalTransposition(i8),
+    TranspositionCc(Option<midi::ControlChangeKind>),
+    OctaveShift(i8),
+    OctaveShiftTriggers(octave::Triggers),
+    // Every zone, after any change to them
+    Zones(Vec<Zone>),
+    // Every layered instrument and the selected one, after any change to them
+    LayeredInstruments {
+        instruments: Vec<Instrument>,
+        selected: Option<usize>,
+    },
+    Expression(Option<expression::Settings>),
+    // Ids of every bypassed node, after any change to them
+    Bypassed(Vec<usize>),
+    // Every group, after any change to them
+    Groups(Vec<Group>),
+    // Every route to an output pair other than 0, after any change to them
+    OutputRoutes(Vec<outputs::Route>),
+    MonitorPairs(Vec<usize>),
+    // Every pitch shift, after any change to them
+    PitchShifts(Vec<pitch_shift::Shift>),
+    // Every modulation route, after any change to them
+    ModulationRoutes(Vec<Route>),
+    // With the nodes as they serialize after taking the settings
+    Setup(Box<Setup>),
 }
@@ -0,0 +1,193 @@
+// Expression pedal handled by the renderer instead of the synths, which all take CC 11
+// differently or not at all. The pedal sets a gain of the whole mix or of some nodes, smoothed
+// so a pedal with coarse steps doesn't zipper.
+
+use super::{modulation::Curve, moved_node};
+use crate::midi::{ControlChangeKind, Message, MessageKind};
+use serde::{Deserialize, Serialize};
+
+// Time constant of the smoothing, in seconds
+const SMOOTHING_TIME: f32 = 0.02;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Scope {
+    Master,
+    // Ids of the nodes
+    Nodes(Vec<usize>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    // Taken by the renderer, the nodes don't get it anymore
+    pub controller: ControlChangeKind,
+    pub curve: Curve,
+    pub scope: Scope,
+}
+
+#[derive(Debug, Clone)]
+pub struct Expression {
+    settings: Option<Settings>,
+    // Where the pedal is and where the gain is on its way there
+    target: f32,
+    gain: f32,
+    // Gain of every frame of the part of the buffer being rendered
+    ramp: Vec<f32>,
+}
+
+impl Default for Expression {
+    fn default() -> Self {
+        Self {
+            settings: None,
+            target: 1.0,
+            gain: 1.0,
+            ramp: Vec::new(),
+        }
+    }
+}
+
+impl Expression {
+    pub fn get(&self) -> Option<&Settings> {
+        self.settings.as_ref()
+    }
+
+    // Full gain until the pedal moves, none turns it off
+    pub fn set(&mut self, settings: Option<Settings>) {
+        if settings.is_none() {
+            self.target = 1.0;
+            self.gain = 1.0;
+        }
+        self.settings = settings;
+    }
+
+    // The node goes out of the scope, the nodes after it move up
+    pub fn remove_node(&mut self, node: usize) {
+        if let Some(Settings {
+            scope: Scope::Nodes(nodes),
+            ..
+        }) = &mut self.settings
+        {
+            nodes.retain(|&id| id != node);
+            for id in nodes {
+                if *id > node {
+                    *id -= 1;
+                }
+            }
+        }
+    }
+
+    // The scope follows the node, the nodes in between shift by one
+    pub fn move_node(&mut self, id: usize, new_id: usize) {
+        if let Some(Settings {
+            scope: Scope::Nodes(nodes),
+            ..
+        }) = &mut self.settings
+        {
+            for node in nodes {
+                *node = moved_node(*node, id, new_id);
+            }
+        }
+    }
+
+    // Whether the message is the pedal, it goes no further then
+    pub fn receive(&mut self, message: &Message) -> bool {
+        let Some(settings) = &self.settings else {
+            return false;
+        };
+        match message.kind {
+            MessageKind::ControlChange { kind, value } if kind == settings.controller => {
+                self.target = settings.curve.apply(value as f32 / 127.0);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Moves the gain on by `len` frames, without a sample rate it jumps to the pedal
+    pub fn advance(&mut self, len: usize, sample_rate: Option<u32>) {
+        if self.settings.is_none() {
+            return;
+        }
+        let step = match sample_rate {
+            Some(sample_rate) => 1.0 - (-1.0 / (SMOOTHING_TIME * sample_rate as f32)).exp(),
+            None => 1.0,
+        };
+        self.ramp.clear();
+        for _ in 0..len {
+            self.gain += (self.target - self.gain) * step;
+            self.ramp.push(self.gain);
+        }
+    }
+
+    // The gains of the frames `advance` moved on by when the pedal is on the whole mix
+    pub fn master_ramp(&self) -> Option<&[f32]> {
+        match &self.settings {
+            Some(Settings {
+                scope: Scope::Master,
+                ..
+            }) => Some(&self.ramp),
+            _ => None,
+        }
+    }
+
+    // Same for a node it's on
+    pub fn node_ramp(&self, node: usize) -> Option<&[f32]> {
+        match &self.settings {
+            Some(Settings {
+                scope: Scope::Nodes(nodes),
+                ..
+            }) if nodes.contains(&node) => Some(&self.ramp),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Expression, Scope, Settings};
+    use crate::{
+        midi::{ControlChangeKind, Message, MessageKind},
+        render::modulation::Curve,
+    };
+
+    fn pedal(value: u8) -> Message {
+        let kind = MessageKind::ControlChange {
+            kind: ControlChangeKind::ExpressionControllerMsb,
+            value,
+        };
+        Message::new(0, kind)
+    }
+
+    #[test]
+    fn smooths_the_pedal() {
+        let mut expression = Expression::default();
+        assert!(!expression.receive(&pedal(0)));
+
+        expression.set(Some(Settings {
+            controller: ControlChangeKind::ExpressionControllerMsb,
+            curve: Curve::Exponential,
+            scope: Scope::Nodes(vec![1, 3]),
+        }));
+        assert!(expression.master_ramp().is_none());
+        assert!(expression.node_ramp(0).is_none());
+        expression.advance(4, Some(48000));
+        assert_eq!(expression.node_ramp(1), Some(&[1.0; 4][..]));
+
+        assert!(expression.receive(&pedal(0)));
+        let modulation = MessageKind::ControlChange {
+            kind: ControlChangeKind::ModulationWheelMsb,
+            value: 0,
+        };
+        assert!(!expression.receive(&Message::new(0, modulation)));
+        expression.advance(480, Some(48000));
+        let ramp = expression.node_ramp(3).unwrap();
+        assert!(ramp.windows(2).all(|pair| pair[1] < pair[0]));
+        // 10 ms is half the time constant
+        assert!((ramp[479] - (-0.5f32).exp()).abs() < 1e-3);
+        expression.advance(48000, Some(48000));
+        assert!(expression.node_ramp(3).unwrap()[47999] < 1e-6);
+
+        expression.remove_node(1);
+        assert!(expression.node_ramp(1).is_none());
+        assert!(expression.node_ramp(2).is_some());
+    }
+}
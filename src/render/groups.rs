@@ -0,0 +1,122 @@
+// Groups of nodes, like "Keys" and "Drums", mixed together: a group has a gain and can be muted
+// or soloed as a whole. With a group soloed only the soloed groups are heard, nodes outside of
+// any group included. The nodes of a group can also play on an output pair of their own.
+
+use super::moved_node;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Group {
+    pub name: String,
+    // Ids of the nodes, a node is in one group at most
+    pub nodes: Vec<usize>,
+    pub gain: f32,
+    pub muted: bool,
+    pub soloed: bool,
+    // Where the nodes without a route of their own play, see `outputs`
+    #[serde(default)]
+    pub output_pair: usize,
+}
+
+impl Group {
+    pub fn is_valid(&self) -> bool {
+        self.gain.is_finite() && self.gain >= 0.0
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Groups {
+    groups: Vec<Group>,
+}
+
+impl Groups {
+    pub fn get(&self) -> &[Group] {
+        &self.groups
+    }
+
+    pub fn set(&mut self, groups: Vec<Group>) {
+        self.groups = groups;
+    }
+
+    pub fn group_of(&self, node: usize) -> Option<&Group> {
+        self.groups.iter().find(|group| group.nodes.contains(&node))
+    }
+
+    // Of the node's output, 1.0 for a node outside of the groups while none is soloed
+    pub fn gain(&self, node: usize) -> f32 {
+        let soloing = self.groups.iter().any(|group| group.soloed);
+        match self.group_of(node) {
+            Some(group) if group.muted || (soloing && !group.soloed) => 0.0,
+            Some(group) => group.gain,
+            None if soloing => 0.0,
+            None => 1.0,
+        }
+    }
+
+    // The node leaves its group, the nodes after it move up
+    pub fn remove_node(&mut self, node: usize) {
+        for group in &mut self.groups {
+            group.nodes.retain(|&id| id != node);
+            for id in &mut group.nodes {
+                if *id > node {
+                    *id -= 1;
+                }
+            }
+        }
+    }
+
+    // The node stays in its group, the nodes in between shift by one
+    pub fn move_node(&mut self, id: usize, new_id: usize) {
+        for group in &mut self.groups {
+            for node in &mut group.nodes {
+                *node = moved_node(*node, id, new_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Group, Groups};
+
+    fn group(nodes: Vec<usize>, gain: f32) -> Group {
+        Group {
+            name: String::new(),
+            nodes,
+            gain,
+            muted: false,
+            soloed: false,
+            output_pair: 0,
+        }
+    }
+
+    #[test]
+    fn mute_and_solo() {
+        let mut groups = Groups::default();
+        groups.set(vec![group(vec![0, 1], 0.5), group(vec![3], 1.0)]);
+        let gains = |groups: &Groups| (0..4).map(|node| groups.gain(node)).collect::<Vec<_>>();
+        assert_eq!(gains(&groups), [0.5, 0.5, 1.0, 1.0]);
+
+        let mut muted = groups.get().to_vec();
+        muted[0].muted = true;
+        groups.set(muted);
+        assert_eq!(gains(&groups), [0.0, 0.0, 1.0, 1.0]);
+
+        let mut soloed = groups.get().to_vec();
+        soloed[0].muted = false;
+        soloed[1].soloed = true;
+        groups.set(soloed);
+        assert_eq!(gains(&groups), [0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn follows_the_nodes() {
+        let mut groups = Groups::default();
+        groups.set(vec![group(vec![0, 2], 1.0), group(vec![3], 1.0)]);
+        groups.move_node(3, 0);
+        assert_eq!(groups.get()[0].nodes, [1, 3]);
+        assert_eq!(groups.get()[1].nodes, [0]);
+        groups.remove_node(1);
+        assert_eq!(groups.get()[0].nodes, [2]);
+    }
+}
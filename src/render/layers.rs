@@ -0,0 +1,287 @@
+// Layered instruments: several nodes played as one, each with its own volume, detune and the
+// velocities it sounds at, like a pad that only comes in above velocity 90. One layered
+// instrument is selected at a time and gets the input, the nodes of the others get nothing new.
+
+use super::moved_node;
+use crate::midi::{Message, MessageKind};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const NUM_CHANNELS: u8 = 16;
+
+const PITCH_WHEEL_CENTER: u16 = 0x2000;
+const PITCH_WHEEL_MAX: u16 = 0x3FFF;
+
+// The pitch bend range the synths start with, in cents
+const PITCH_BEND_RANGE: f32 = 200.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    pub node: usize,
+    // Gain of the node's output
+    pub volume: f32,
+    // Cents, done with the pitch wheel so it takes the node's pitch bend range
+    pub detune: i8,
+    // Inclusive
+    pub min_velocity: u8,
+    pub max_velocity: u8,
+}
+
+impl Layer {
+    fn takes(&self, velocity: u8) -> bool {
+        (self.min_velocity..=self.max_velocity).contains(&velocity)
+    }
+
+    fn pitch_wheel(&self, value: u16) -> u16 {
+        let offset = self.detune as f32 / PITCH_BEND_RANGE * PITCH_WHEEL_CENTER as f32;
+        (value as f32 + offset.round()).clamp(0.0, PITCH_WHEEL_MAX as f32) as u16
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Instrument {
+    pub name: String,
+    pub layers: Vec<Layer>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Layers {
+    instruments: Vec<Instrument>,
+    selected: Option<usize>,
+    // (channel, key) of a sounding note to the nodes it went to
+    sounding: HashMap<(u8, u8), Vec<usize>>,
+    // The pitch wheel of every channel as played, the layers get it detuned
+    pitch_wheels: [u16; NUM_CHANNELS as usize],
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Self {
+            instruments: Vec::new(),
+            selected: None,
+            sounding: HashMap::new(),
+            pitch_wheels: [PITCH_WHEEL_CENTER; NUM_CHANNELS as usize],
+        }
+    }
+}
+
+impl Layers {
+    pub fn get(&self) -> &[Instrument] {
+        &self.instruments
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    // A selection past the instruments selects none
+    pub fn set(&mut self, instruments: Vec<Instrument>, selected: Option<usize>) {
+        self.selected = selected.filter(|&index| index < instruments.len());
+        self.instruments = instruments;
+    }
+
+    fn selected_layers(&self) -> &[Layer] {
+        self.selected
+            .and_then(|index| self.instruments.get(index))
+            .map_or(&[], |instrument| &instrument.layers)
+    }
+
+    fn layers(&self) -> impl Iterator<Item = &Layer> {
+        self.instruments
+            .iter()
+            .flat_map(|instrument| &instrument.layers)
+    }
+
+    pub fn is_layered(&self, node: usize) -> bool {
+        self.layers().any(|layer| layer.node == node)
+    }
+
+    // Of the selected instrument first, a node can be in more than one
+    pub fn volume(&self, node: usize) -> f32 {
+        self.selected_layers()
+            .iter()
+            .chain(self.layers())
+            .find(|layer| layer.node == node)
+            .map_or(1.0, |layer| layer.volume)
+    }
+
+    // The layers of the node go with it, the ones of the nodes after it move up
+    pub fn remove_node(&mut self, node: usize) {
+        for instrument in &mut self.instruments {
+            instrument.layers.retain(|layer| layer.node != node);
+            for layer in &mut instrument.layers {
+                if layer.node > node {
+                    layer.node -= 1;
+                }
+            }
+        }
+        self.sounding.clear();
+    }
+
+    // Same for the layers
+    pub fn move_node(&mut self, id: usize, new_id: usize) {
+        let layers = self.instruments.iter_mut().flat_map(|i| &mut i.layers);
+        for layer in layers {
+            layer.node = moved_node(layer.node, id, new_id);
+        }
+        for node in self.sounding.values_mut().flatten() {
+            *node = moved_node(*node, id, new_id);
+        }
+    }
+
+    pub fn clear_sounding(&mut self) {
+        self.sounding.clear();
+    }
+
+    // The pitch wheels of the selected instrument's nodes with their detune, for after the
+    // selection or the layers changed
+    pub fn retune(&self, routed: &mut Vec<(usize, Message)>) {
+        for layer in self.selected_layers() {
+            for channel in 0..NUM_CHANNELS {
+                let value = layer.pitch_wheel(self.pitch_wheels[channel as usize]);
+                let kind = MessageKind::PitchWheel { value };
+                routed.push((layer.node, Message::new(channel, kind)));
+            }
+        }
+    }
+
+    // The messages for the nodes with layers
+    pub fn route(&mut self, message: &Message, routed: &mut Vec<(usize, Message)>) {
+        let channel = message.channel;
+        match message.kind {
+            MessageKind::NoteOn { note, velocity } if velocity > 0 => {
+                let layers = self
+                    .selected
+                    .and_then(|index| self.instruments.get(index))
+                    .map_or(&[][..], |instrument| &instrument.layers);
+                let sounding = self.sounding.entry((channel, note)).or_default();
+                for layer in layers.iter().filter(|layer| layer.takes(velocity)) {
+                    sounding.push(layer.node);
+                    routed.push((layer.node, message.clone()));
+                }
+            }
+            // the note off goes where the note on went, even with another instrument selected
+            MessageKind::NoteOn { note, .. } | MessageKind::NoteOff { note, .. } => {
+                let Some(nodes) = self.sounding.remove(&(channel, note)) else {
+                    return;
+                };
+                routed.extend(nodes.into_iter().map(|node| (node, message.clone())));
+            }
+            MessageKind::PolyphonicAftertouch { note, .. } => {
+                let nodes = self.sounding.get(&(channel, note)).into_iter().flatten();
+                routed.extend(nodes.map(|&node| (node, message.clone())));
+            }
+            MessageKind::PitchWheel { value } => {
+                self.pitch_wheels[(channel & 0x0F) as usize] = value;
+                for layer in self.selected_layers() {
+                    // only a detuned one loses the MIDI 2.0 resolution
+                    let message = match layer.detune {
+                        0 => message.clone(),
+                        _ => {
+                            let value = layer.pitch_wheel(value);
+                            Message::new(channel, MessageKind::PitchWheel { value })
+                        }
+                    };
+                    routed.push((layer.node, message));
+                }
+            }
+            _ => {
+                let mut nodes: Vec<usize> = self
+                    .selected_layers()
+                    .iter()
+                    .map(|layer| layer.node)
+                    .collect();
+                nodes.sort_unstable();
+                nodes.dedup();
+                routed.extend(nodes.into_iter().map(|node| (node, message.clone())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Instrument, Layer, Layers};
+    use crate::midi::{Message, MessageKind};
+
+    fn layer(node: usize, min_velocity: u8, detune: i8) -> Layer {
+        Layer {
+            node,
+            volume: 0.5,
+            detune,
+            min_velocity,
+            max_velocity: 127,
+        }
+    }
+
+    fn note_on(note: u8, velocity: u8) -> Message {
+        Message::new(0, MessageKind::NoteOn { note, velocity })
+    }
+
+    fn route(layers: &mut Layers, message: Message) -> Vec<(usize, Message)> {
+        let mut routed = vec![];
+        layers.route(&message, &mut routed);
+        routed
+    }
+
+    #[test]
+    fn velocity_windows_and_detune() {
+        let mut layers = Layers::default();
+        let piano_and_pad = Instrument {
+            name: "Piano + pad".into(),
+            layers: vec![layer(0, 0, 0), layer(1, 90, 100)],
+        };
+        let strings = Instrument {
+            name: "Strings".into(),
+            layers: vec![layer(2, 0, 0)],
+        };
+        layers.set(vec![piano_and_pad, strings], Some(0));
+        assert!(layers.is_layered(1) && !layers.is_layered(3));
+        assert_eq!(layers.volume(1), 0.5);
+        assert_eq!(layers.volume(3), 1.0);
+
+        assert_eq!(route(&mut layers, note_on(60, 80)), [(0, note_on(60, 80))]);
+        assert_eq!(
+            route(&mut layers, note_on(62, 100)),
+            [(0, note_on(62, 100)), (1, note_on(62, 100))]
+        );
+
+        // a semitone up at the default range of two is a quarter of the pitch wheel
+        let bend = |value| Message::new(0, MessageKind::PitchWheel { value });
+        let routed = route(&mut layers, bend(0x2000));
+        assert_eq!(routed, [(0, bend(0x2000)), (1, bend(0x3000))]);
+        let routed = route(&mut layers, bend(0x3FFF));
+        assert_eq!(routed, [(0, bend(0x3FFF)), (1, bend(0x3FFF))]);
+
+        // the held notes end on the nodes they started on
+        layers.set(layers.get().to_vec(), Some(1));
+        assert_eq!(
+            route(&mut layers, note_on(64, 100)),
+            [(2, note_on(64, 100))]
+        );
+        assert_eq!(
+            route(&mut layers, note_on(62, 0)),
+            [(0, note_on(62, 0)), (1, note_on(62, 0))]
+        );
+
+        layers.set(layers.get().to_vec(), Some(0));
+        let mut routed = vec![];
+        layers.retune(&mut routed);
+        assert_eq!(routed.len(), 32);
+        assert_eq!(routed[16], (1, bend(0x3FFF)));
+        assert_eq!(routed[17].1.kind, MessageKind::PitchWheel { value: 0x3000 });
+    }
+
+    #[test]
+    fn removed_nodes() {
+        let mut layers = Layers::default();
+        let instrument = Instrument {
+            name: "Layered".into(),
+            layers: vec![layer(0, 0, 0), layer(2, 0, 0)],
+        };
+        layers.set(vec![instrument], Some(3));
+        assert_eq!(layers.selected(), None);
+        layers.remove_node(0);
+        assert_eq!(layers.get()[0].layers, [layer(1, 0, 0)]);
+    }
+}
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Seconds of audio the load is averaged over
+pub const LOAD_WINDOW: f32 = 0.5;
+
+// Render time in percent of the audio time rendered, above 100 the output can't keep up
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Load {
+    // In the order of the renderer's nodes
+    pub nodes: Vec<f32>,
+    pub total: f32,
+    // The slowest single buffer of the window, the one that causes dropouts first
+    pub peak: f32,
+}
+
+#[derive(Debug, Default)]
+pub struct LoadMeter {
+    node_times: Vec<Duration>,
+    total_time: Duration,
+    audio_time: f32,
+    peak: f32,
+}
+
+impl LoadMeter {
+    pub fn add_node_time(&mut self, index: usize, time: Duration) {
+        if index >= self.node_times.len() {
+            self.node_times.resize(index + 1, Duration::ZERO);
+        }
+        self.node_times[index] += time;
+    }
+
+    // `audio_time` is the length of the buffer in seconds, returns the load once a whole
+    // window was rendered
+    pub fn finish_buffer(&mut self, time: Duration, audio_time: f32) -> Option<Load> {
+        if audio_time <= 0.0 {
+            return None;
+        }
+        self.total_time += time;
+        self.audio_time += audio_time;
+        self.peak = self.peak.max(percent(time, audio_time));
+        if self.audio_time < LOAD_WINDOW {
+            return None;
+        }
+        let load = Load {
+            nodes: self
+                .node_times
+                .iter()
+                .map(|&t| percent(t, self.audio_time))
+                .collect(),
+            total: percent(self.total_time, self.audio_time),
+            peak: self.peak,
+        };
+        self.reset();
+        Some(load)
+    }
+
+    // The node times are attributed by index, so this has to happen when nodes move around
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+fn percent(time: Duration, audio_time: f32) -> f32 {
+    time.as_secs_f32() / audio_time * 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoadMeter, LOAD_WINDOW};
+    use std::time::Duration;
+
+    #[test]
+    fn load_over_a_window() {
+        let mut meter = LoadMeter::default();
+        let buffer = LOAD_WINDOW / 4.0;
+        let ms = |ms: f32| Duration::from_secs_f32(ms / 1000.0);
+        for i in 0..3 {
+            meter.add_node_time(0, ms(12.5));
+            meter.add_node_time(1, ms(25.0));
+            let slow = if i == 1 { 50.0 } else { 0.0 };
+            assert_eq!(meter.finish_buffer(ms(37.5 + slow), buffer), None);
+        }
+        meter.add_node_time(0, ms(12.5));
+        meter.add_node_time(1, ms(25.0));
+        let load = meter.finish_buffer(ms(37.5), buffer).unwrap();
+
+        let close = |a: f32, b: f32| (a - b).abs() < 0.01;
+        assert!(close(load.nodes[0], 10.0) && close(load.nodes[1], 20.0));
+        assert!(close(load.total, 40.0));
+        assert!(close(load.peak, 70.0));
+        // starts over after the window
+        assert_eq!(meter.finish_buffer(ms(1.0), buffer), None);
+    }
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+// Seconds of audio the levels are measured over, about the refresh rate of a meter
+pub const METER_WINDOW: f32 = 0.05;
+
+// Linear amplitudes of the left and right channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Level {
+    pub peak: [f32; 2],
+    pub rms: [f32; 2],
+    // A sample reached full scale
+    pub clipped: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Levels {
+    pub master: Level,
+    // In the order of the renderer's nodes
+    pub nodes: Vec<Level>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Accumulator {
+    peak: [f32; 2],
+    sum_of_squares: [f64; 2],
+    clipped: bool,
+}
+
+impl Accumulator {
+    fn add(&mut self, lbuf: &[f32], rbuf: &[f32]) {
+        for (channel, buf) in [lbuf, rbuf].into_iter().enumerate() {
+            for &x in buf {
+                let x = x.abs();
+                self.peak[channel] = self.peak[channel].max(x);
+                self.sum_of_squares[channel] += (x * x) as f64;
+                self.clipped |= x >= 1.0;
+            }
+        }
+    }
+
+    fn level(&self, frames: usize) -> Level {
+        let rms = |channel: usize| (self.sum_of_squares[channel] / frames as f64).sqrt() as f32;
+        Level {
+            peak: self.peak,
+            rms: [rms(0), rms(1)],
+            clipped: self.clipped,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LevelMeter {
+    master: Accumulator,
+    nodes: Vec<Accumulator>,
+    frames: usize,
+}
+
+impl LevelMeter {
+    pub fn add_node(&mut self, index: usize, lbuf: &[f32], rbuf: &[f32]) {
+        if index >= self.nodes.len() {
+            self.nodes.resize(index + 1, Accumulator::default());
+        }
+        self.nodes[index].add(lbuf, rbuf);
+    }
+
+    // Returns the levels once a whole window was measured
+    pub fn add_master(&mut self, lbuf: &[f32], rbuf: &[f32], sample_rate: u32) -> Option<Levels> {
+        self.master.add(lbuf, rbuf);
+        self.frames += lbuf.len();
+        if (self.frames as f32) < METER_WINDOW * sample_rate as f32 {
+            return None;
+        }
+        let levels = Levels {
+            master: self.master.level(self.frames),
+            nodes: self.nodes.iter().map(|a| a.level(self.frames)).collect(),
+        };
+        self.reset();
+        Some(levels)
+    }
+
+    // The node levels are attributed by index, so this has to happen when nodes move around
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LevelMeter, METER_WINDOW};
+
+    #[test]
+    fn peak_rms_and_clipping() {
+        let sample_rate = 1000;
+        let frames = (METER_WINDOW * sample_rate as f32) as usize / 2;
+        let mut meter = LevelMeter::default();
+        let square = |amplitude: f32| -> Vec<f32> {
+            (0..frames)
+                .map(|i| if i % 2 == 0 { amplitude } else { -amplitude })
+                .collect()
+        };
+        let silence = vec![0.0; frames];
+
+        meter.add_node(0, &square(0.5), &silence);
+        assert_eq!(meter.add_master(&square(0.5), &silence, sample_rate), None);
+        meter.add_node(0, &square(0.25), &silence);
+        meter.add_node(1, &silence, &square(1.0));
+        let levels = meter
+            .add_master(&square(0.25), &square(1.0), sample_rate)
+            .unwrap();
+
+        assert_eq!(levels.master.peak, [0.5, 1.0]);
+        let rms = ((0.25f32 + 0.0625) / 2.0).sqrt();
+        assert!((levels.master.rms[0] - rms).abs() < 1e-6);
+        assert!(levels.master.clipped);
+        assert_eq!(levels.nodes[0].peak, [0.5, 0.0]);
+        assert!(!levels.nodes[0].clipped && levels.nodes[1].clipped);
+    }
+}
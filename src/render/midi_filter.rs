@@ -17,6 +17,13 @@ pub struct MidiFilter {
     pub program_change: bool,
     pub channel_aftertouch: bool,
     pub pitch_wheel: bool,
+    #[serde(default = "passes")]
+    pub system_exclusive: bool,
+}
+
+// Filters from before a kind of message was added let it pass
+fn passes() -> bool {
+    true
 }
 
 impl MidiFilter {
@@ -39,6 +46,16 @@ impl MidiFilter {
             midi::MessageKind::ProgramChange { .. } => self.program_change,
             midi::MessageKind::ChannelAftertouch { .. } => self.channel_aftertouch,
             midi::MessageKind::PitchWheel { .. } => self.pitch_wheel,
+            midi::MessageKind::PerNoteController { note, .. } => self.notes[note as usize],
+            midi::MessageKind::PerNotePitchWheel { note, .. } => {
+                self.pitch_wheel && self.notes[note as usize]
+            }
+            // filtered along with the data entry they're set by
+            midi::MessageKind::RegisteredParameter { .. }
+            | midi::MessageKind::NonRegisteredParameter { .. } => {
+                self.control_commands[midi::ControlChangeKind::DataEntryMsb.as_number() as usize]
+            }
+            midi::MessageKind::SystemExclusive { .. } => self.system_exclusive,
         }
     }
 }
@@ -53,6 +70,7 @@ impl Default for MidiFilter {
             program_change: true,
             channel_aftertouch: true,
             pitch_wheel: true,
+            system_exclusive: true,
         }
     }
 }
@@ -74,6 +92,7 @@ pub trait MidiFilterUser {
             Kind::ProgramChange(fl) => f.program_change = fl,
             Kind::ChannelAftertouch(fl) => f.channel_aftertouch = fl,
             Kind::PitchWheel(fl) => f.pitch_wheel = fl,
+            Kind::SystemExclusive(fl) => f.system_exclusive = fl,
         }
         Ok(())
     }
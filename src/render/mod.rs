@@ -1,16 +1,52 @@
-use crate::{control, midi, path::VirtualPaths};
+use crate::{
+    audio, control,
+    deser::NodeState,
+    json::{JsonUpdateSender, JsonUpdater},
+    midi,
+    path::VirtualPaths,
+};
 use command::{RequestKind, Responder, ResponseKind};
+use load::{Load, LoadMeter};
+use meter::{LevelMeter, Levels};
 use node::RenderPtr;
-use std::collections::HashMap;
+use pool::WorkerPool;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
 use tracing::error;
 
+pub mod bypass;
+pub mod capture;
+pub mod layers;
 pub mod command;
+pub mod expression;
+pub mod groups;
+pub mod load;
+pub mod meter;
 pub mod midi_filter;
+pub mod modulation;
 pub mod node;
+pub mod octave;
+pub mod outputs;
+pub mod pedals;
+pub mod per_note;
+pub mod pitch_shift;
+pub mod pool;
 pub mod preset_map;
+pub mod retired;
+pub mod retuning;
+pub mod soundfonts;
 pub mod velocity_map;
+pub mod voices;
+pub mod zones;
 
 pub const MAX_BUFFER_SIZE: usize = 192000;
+// Semitones either way the whole instrument transposes
+pub const MAX_GLOBAL_TRANSPOSITION: i8 = 24;
 
 pub type NodeKindConstructor = Box<dyn Fn() -> RenderPtr + 'static + Sync + Send>;
 
@@ -22,7 +58,57 @@ pub struct Renderer {
     dm_ctr_rx: control::CtrReceiver,
     sample_rate: Option<u32>,
     global_transposition: i8,
+    // Sweeps the global transposition from an octave down to an octave up, 64 is none
+    transposition_cc: Option<midi::ControlChangeKind>,
+    // The changes the transposition CC makes
+    transposition_tx: watch::Sender<i8>,
+    octave_shift: octave::OctaveShift,
+    // The changes the octave shift triggers make
+    octaves_tx: watch::Sender<i8>,
     virtual_paths: VirtualPaths,
+    json_update_tx: Option<JsonUpdateSender>,
+    // Of the controller, the nodes playing in time follow it
+    tempo_rx: Option<watch::Receiver<control::Tempo>>,
+    tempo: control::Tempo,
+    // Of the controller, its nodes and the pads play with them
+    consumed_rx: Option<watch::Receiver<control::ConsumedInputs>>,
+    consumed: control::ConsumedInputs,
+    // (channel, note) of the note ons kept from the nodes, their note offs are kept too
+    swallowed: HashSet<(u8, u8)>,
+    load_meter: LoadMeter,
+    load_tx: watch::Sender<Load>,
+    level_meter: LevelMeter,
+    levels_tx: watch::Sender<Levels>,
+    soundfonts: soundfonts::SoundfontCache,
+    audio_input: audio::input::Input,
+    // Every node renders in here first, so its levels can be measured
+    node_lbuf: Vec<f32>,
+    node_rbuf: Vec<f32>,
+    pool: Option<WorkerPool>,
+    // (frame, node id or all of them, message) of the messages for the buffer being rendered
+    timed_messages: Vec<(usize, Option<usize>, midi::Message)>,
+    midi_jitter_compensation: bool,
+    // Gets the master bus while a session is recorded
+    capture: Option<capture::Tap>,
+    // Pressing it (a value from 64 up) is a panic
+    panic_cc: Option<midi::ControlChangeKind>,
+    // The keys and pedals down on the input
+    held: midi::held::Held,
+    // Turns the expression of MPE member channels into per-note messages
+    mpe: Option<midi::mpe::Mpe>,
+    zones: zones::Zones,
+    layers: layers::Layers,
+    modulation: modulation::Modulation,
+    expression: expression::Expression,
+    bypass: bypass::Bypass,
+    groups: groups::Groups,
+    outputs: outputs::Outputs,
+    pitch_shifts: pitch_shift::PitchShifts,
+    retired: retired::Retired,
+    // (node id, message) of what the zones, layers and modulation routes made of a message
+    routed: Vec<(usize, midi::Message)>,
+    // (gain, step per frame) while fading out for good, the output stays silent after it
+    fade_out: Option<(f32, f32)>,
 }
 
 impl Renderer {
@@ -40,7 +126,43 @@ impl Renderer {
             dm_ctr_rx,
             sample_rate: None,
             global_transposition: 0,
+            transposition_cc: None,
+            transposition_tx: watch::Sender::new(0),
+            octave_shift: Default::default(),
+            octaves_tx: watch::Sender::new(0),
             virtual_paths,
+            json_update_tx: None,
+            tempo_rx: None,
+            consumed_rx: None,
+            consumed: Default::default(),
+            swallowed: HashSet::new(),
+            tempo: Default::default(),
+            load_meter: Default::default(),
+            load_tx: watch::Sender::new(Load::default()),
+            level_meter: Default::default(),
+            levels_tx: watch::Sender::new(Levels::default()),
+            soundfonts: Default::default(),
+            audio_input: Default::default(),
+            node_lbuf: Vec::new(),
+            node_rbuf: Vec::new(),
+            pool: None,
+            timed_messages: Vec::new(),
+            midi_jitter_compensation: false,
+            capture: None,
+            panic_cc: None,
+            held: Default::default(),
+            mpe: None,
+            zones: Default::default(),
+            layers: Default::default(),
+            modulation: Default::default(),
+            expression: Default::default(),
+            bypass: Default::default(),
+            groups: Default::default(),
+            outputs: Default::default(),
+            pitch_shifts: Default::default(),
+            retired: Default::default(),
+            routed: Vec::new(),
+            fade_out: None,
         }
     }
 
@@ -52,34 +174,366 @@ impl Renderer {
             .insert(name.to_owned(), Box::new(constructor));
     }
 
+    // Nodes broadcast the changes they make on their own through it, with their index as id
+    pub fn set_json_update_sender(&mut self, tx: JsonUpdateSender) {
+        self.json_update_tx = Some(tx);
+        self.assign_json_updaters();
+    }
+
+    // The ids have to follow the nodes when they move around
+    fn assign_json_updaters(&mut self) {
+        let Some(tx) = &self.json_update_tx else {
+            return;
+        };
+        for (id, (_, node)) in self.nodes.iter_mut().enumerate() {
+            node.set_json_updater(JsonUpdater::new(id, tx.clone()));
+        }
+    }
+
+    pub fn set_tempo_receiver(&mut self, tempo_rx: watch::Receiver<control::Tempo>) {
+        self.tempo_rx = Some(tempo_rx);
+    }
+
+    pub fn set_consumed_inputs_receiver(
+        &mut self,
+        mut rx: watch::Receiver<control::ConsumedInputs>,
+    ) {
+        self.consumed = rx.borrow_and_update().clone();
+        self.consumed_rx = Some(rx);
+    }
+
+    fn receive_consumed_inputs(&mut self) {
+        let Some(rx) = &mut self.consumed_rx else {
+            return;
+        };
+        if rx.has_changed().unwrap_or(false) {
+            self.consumed = rx.borrow_and_update().clone();
+        }
+    }
+
+    // A note let through keeps its note off when its input gets consumed while it's held
+    fn is_consumed(&mut self, msg: &midi::Message) -> bool {
+        match msg.kind {
+            midi::MessageKind::NoteOn { note, velocity } if velocity > 0 => {
+                let consumed = self.consumed.contains(msg.channel, note);
+                if consumed {
+                    self.swallowed.insert((msg.channel, note));
+                }
+                consumed
+            }
+            midi::MessageKind::NoteOn { note, .. } | midi::MessageKind::NoteOff { note, .. } => {
+                self.swallowed.remove(&(msg.channel, note))
+            }
+            midi::MessageKind::PolyphonicAftertouch { note, .. } => {
+                self.swallowed.contains(&(msg.channel, note))
+            }
+            _ => false,
+        }
+    }
+
+    fn receive_tempo(&mut self) {
+        let Some(tempo_rx) = &mut self.tempo_rx else {
+            return;
+        };
+        if !tempo_rx.has_changed().unwrap_or(false) {
+            return;
+        }
+        self.tempo = *tempo_rx.borrow_and_update();
+        for (_, node) in &mut self.nodes {
+            node.set_tempo(self.tempo);
+        }
+    }
+
     pub fn set_sample_rate(&mut self, sample_rate: u32) {
         self.sample_rate = Some(sample_rate);
         for (_, node) in &mut self.nodes {
             node.set_sample_rate(sample_rate);
         }
+        self.pitch_shifts.set_sample_rate(sample_rate);
+    }
+
+    // A new load every LOAD_WINDOW seconds of rendered audio
+    pub fn subscribe_load(&self) -> watch::Receiver<Load> {
+        self.load_tx.subscribe()
+    }
+
+    // New levels every METER_WINDOW seconds of rendered audio
+    pub fn subscribe_levels(&self) -> watch::Receiver<Levels> {
+        self.levels_tx.subscribe()
+    }
+
+    // With more than one thread the nodes render in parallel, one thread is the audio thread
+    pub fn set_render_threads(&mut self, num_threads: usize) {
+        self.pool = (num_threads > 1).then(|| WorkerPool::new(num_threads - 1));
+    }
+
+    // MIDI input gets placed in the buffer by the time it came in, like the drum machine's
+    // notes, which keeps the timing of fast playing at the cost of up to a buffer of latency
+    pub fn set_midi_jitter_compensation(&mut self, enabled: bool) {
+        self.midi_jitter_compensation = enabled;
+    }
+
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    pub fn set_capture(&mut self, tap: Option<capture::Tap>) {
+        self.capture = tap;
+    }
+
+    pub fn set_panic_cc(&mut self, kind: Option<midi::ControlChangeKind>) {
+        self.panic_cc = kind;
+    }
+
+    pub fn set_mpe(&mut self, zones: Option<midi::mpe::Zones>) {
+        self.mpe = zones.map(midi::mpe::Mpe::new);
+    }
+
+    // There are no MIDI outputs, once there are they get the panic too
+    pub fn panic(&mut self) {
+        self.zones.clear_sounding();
+        self.layers.clear_sounding();
+        for (_, node) in &mut self.nodes {
+            node.panic();
+        }
+        self.retired.clear();
+    }
+
+    // Before AMI stops, so it doesn't stop in the middle of a sound. Without a sample rate there's
+    // no output to fade.
+    pub fn fade_out(&mut self, time: Duration) {
+        let frames = self
+            .sample_rate
+            .map_or(0.0, |rate| time.as_secs_f32() * rate as f32);
+        let step = if frames >= 1.0 { 1.0 / frames } else { 1.0 };
+        self.fade_out = Some((1.0, step));
     }
 
     pub fn set_global_transposition(&mut self, transposition: i8) {
+        self.transpose(transposition, self.octave_shift.octaves());
+    }
+
+    // What the nodes get as their global transposition, the octave shift on top of it
+    fn node_transposition(&self) -> i8 {
+        self.global_transposition + self.octave_shift.octaves() * 12
+    }
+
+    // The keys held while the transposition changes get their note offs first, so they don't
+    // hang when they come up at another pitch
+    fn transpose(&mut self, transposition: i8, octaves: i8) {
+        let before = self.node_transposition();
         self.global_transposition = transposition;
+        self.octave_shift.set_octaves(octaves);
+        let transposition = self.node_transposition();
+        if transposition != before {
+            for msg in self.held.note_offs() {
+                self.receive_input(&msg);
+            }
+        }
         for (_, node) in &mut self.nodes {
             node.set_global_transposition(transposition);
         }
     }
 
+    // Of the transposition CC, none for any other message
+    fn transposition_of(&self, msg: &midi::Message) -> Option<i8> {
+        let midi::MessageKind::ControlChange { kind, value } = msg.kind else {
+            return None;
+        };
+        (Some(kind) == self.transposition_cc)
+            .then(|| ((value as f32 - 64.0) / 64.0 * 12.0).round() as i8)
+    }
+
+    pub fn subscribe_transposition(&self) -> watch::Receiver<i8> {
+        self.transposition_tx.subscribe()
+    }
+
+    pub fn subscribe_octave_shift(&self) -> watch::Receiver<i8> {
+        self.octaves_tx.subscribe()
+    }
+
+    // Stereo pairs of the audio output, pair 0 included. The nodes routed to the others don't
+    // get in the buffers `render` gets, they are in `output_pair` after it.
+    pub fn set_output_pairs(&mut self, num_pairs: usize) {
+        self.outputs.set_num_pairs(num_pairs);
+    }
+
+    // Of the buffer rendered last, from pair 1 on
+    pub fn output_pair(&self, pair: usize) -> Option<(&[f32], &[f32])> {
+        self.outputs.bus(pair)
+    }
+
     pub fn render(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
+        let now = Instant::now();
         self.receive_requests();
-        self.receive_midi_messages();
-        self.receive_drum_machine_messages();
-        self.render_audio(lbuf, rbuf);
+        self.receive_tempo();
+        self.receive_consumed_inputs();
+        let len = lbuf.len().min(rbuf.len());
+        self.outputs.resize(len);
+        self.receive_midi_messages(len);
+        self.receive_drum_machine_messages(len);
+
+        // the buffer is rendered in pieces which start at the frames messages are due at
+        let mut messages = std::mem::take(&mut self.timed_messages);
+        messages.sort_by_key(|(frame, ..)| *frame);
+        let mut messages_iter = messages.drain(..).peekable();
+        let mut start = 0;
+        loop {
+            while let Some((_, id, msg)) = messages_iter.next_if(|(frame, ..)| *frame <= start) {
+                match id {
+                    Some(id) => {
+                        if let Some((_, node)) = self.nodes.get_mut(id) {
+                            node.receive_midi_message(&msg);
+                        }
+                    }
+                    None => {
+                        if self.is_consumed(&msg) || self.expression.receive(&msg) {
+                            continue;
+                        }
+                        if let Some(octaves) = self.octave_shift.receive(&msg) {
+                            if octaves != self.octave_shift.octaves() {
+                                self.transpose(self.global_transposition, octaves);
+                                self.octaves_tx.send_replace(octaves);
+                            }
+                            continue;
+                        }
+                        if let Some(transposition) = self.transposition_of(&msg) {
+                            if transposition != self.global_transposition {
+                                self.set_global_transposition(transposition);
+                                self.transposition_tx.send_replace(transposition);
+                            }
+                            continue;
+                        }
+                        self.receive_input(&msg);
+                    }
+                }
+            }
+            let end = messages_iter.peek().map_or(len, |(frame, ..)| *frame);
+            self.render_audio(lbuf, rbuf, start..end);
+            if end >= len {
+                break;
+            }
+            start = end;
+        }
+        drop(messages_iter);
+        self.timed_messages = messages;
+        self.outputs.mix_into_monitors(&lbuf[..len], &rbuf[..len]);
+        if let Some((gain, step)) = &mut self.fade_out {
+            // every pair fades the same way
+            let start_gain = *gain;
+            let main = (&mut lbuf[..len], &mut rbuf[..len]);
+            for (lbuf, rbuf) in std::iter::once(main).chain(self.outputs.buses_mut(0..len)) {
+                *gain = start_gain;
+                for (l, r) in lbuf.iter_mut().zip(rbuf) {
+                    *gain = (*gain - *step).max(0.0);
+                    *l *= *gain;
+                    *r *= *gain;
+                }
+            }
+        }
+
+        if let (Some(tap), Some(sample_rate)) = (&mut self.capture, self.sample_rate) {
+            // the same time the buffer stands for when timed messages get placed in it
+            let period = Duration::from_secs_f64(len as f64 / sample_rate as f64);
+            let buffer_start = now.checked_sub(period).unwrap_or(now);
+            tap.push(&lbuf[..len], &rbuf[..len], buffer_start);
+        }
+    }
+
+    // To the nodes outside of zones and layers, and to those the zones, layers and modulation
+    // routes make something of it for
+    fn receive_input(&mut self, msg: &midi::Message) {
+        self.held.push(msg);
+        self.retired.receive_midi_message(msg);
+        for (id, (_, node)) in self.nodes.iter_mut().enumerate() {
+            if !self.zones.is_zoned(id) && !self.layers.is_layered(id) {
+                node.receive_midi_message(msg);
+            }
+        }
+        self.zones.route(msg, &mut self.routed);
+        self.layers.route(msg, &mut self.routed);
+        self.modulation.route(msg, &mut self.routed);
+        self.send_routed();
+    }
+
+    fn send_routed(&mut self) {
+        for (id, msg) in self.routed.drain(..) {
+            if let Some((_, node)) = self.nodes.get_mut(id) {
+                node.receive_midi_message(&msg);
+            }
+        }
+    }
+
+    // Shared by every node
+    pub fn soundfont_cache(&self) -> &soundfonts::SoundfontCache {
+        &self.soundfonts
+    }
+
+    // Shared by every node too, the input stream pushes into it
+    pub fn audio_input(&self) -> &audio::input::Input {
+        &self.audio_input
     }
 
     pub fn add_node(&mut self, kind: String, mut node: RenderPtr) {
+        self.prepare_node(self.nodes.len(), &mut node);
+        self.nodes.push((kind, node));
+    }
+
+    fn prepare_node(&self, id: usize, node: &mut RenderPtr) {
+        node.set_soundfont_cache(self.soundfonts.clone());
+        node.set_audio_input(self.audio_input.clone());
+        node.set_tempo(self.tempo);
+        if let Some(tx) = &self.json_update_tx {
+            node.set_json_updater(JsonUpdater::new(id, tx.clone()));
+        }
         if let Some(sample_rate) = self.sample_rate {
             node.set_sample_rate(sample_rate);
         }
         node.set_virtual_paths(self.virtual_paths.clone());
-        node.set_global_transposition(self.global_transposition);
-        self.nodes.push((kind, node));
+        node.set_global_transposition(self.node_transposition());
+    }
+
+    fn remove_node(&mut self, id: usize) -> RenderPtr {
+        let (_, node) = self.nodes.remove(id);
+        self.zones.remove_node(id);
+        self.layers.remove_node(id);
+        self.modulation.remove_node(id);
+        self.expression.remove_node(id);
+        self.bypass.remove_node(id);
+        self.groups.remove_node(id);
+        self.outputs.remove_node(id);
+        self.pitch_shifts.remove_node(id);
+        self.assign_json_updaters();
+        self.load_meter.reset();
+        self.level_meter.reset();
+        node
+    }
+
+    // (volume, output pair) the node has in the mix, none for a bypassed node
+    fn node_mix(&self, id: usize) -> Option<(f32, usize)> {
+        if self.bypass.is_bypassed(id) {
+            return None;
+        }
+        let volume = self.layers.volume(id) * self.modulation.gain(id) * self.groups.gain(id);
+        Some((volume, self.outputs.pair(id, &self.groups)))
+    }
+
+    // The nodes in between shift by one, whatever in the renderer points at nodes follows them.
+    // The drum machine and the controller nodes get their own request from the app.
+    fn move_node(&mut self, id: usize, new_id: usize) {
+        let node = self.nodes.remove(id);
+        self.nodes.insert(new_id, node);
+        self.zones.move_node(id, new_id);
+        self.layers.move_node(id, new_id);
+        self.modulation.move_node(id, new_id);
+        self.expression.move_node(id, new_id);
+        self.bypass.move_node(id, new_id);
+        self.groups.move_node(id, new_id);
+        self.outputs.move_node(id, new_id);
+        self.pitch_shifts.move_node(id, new_id);
+        self.assign_json_updaters();
+        self.load_meter.reset();
+        self.level_meter.reset();
     }
 
     pub fn receive_requests(&mut self) {
@@ -88,47 +542,157 @@ impl Renderer {
         }
     }
 
-    fn receive_midi_messages(&mut self) {
+    fn receive_midi_messages(&mut self, len: usize) {
+        let now = Instant::now();
         while let Ok(msg) = self.midi_rx.try_recv() {
-            for (_, node) in &mut self.nodes {
-                node.receive_midi_message(&msg)
+            let msg = match &mut self.mpe {
+                Some(mpe) => match mpe.translate(msg) {
+                    Some(msg) => msg,
+                    None => continue,
+                },
+                None => msg,
+            };
+            if let midi::MessageKind::ControlChange { kind, value } = msg.kind {
+                if Some(kind) == self.panic_cc && value >= 64 {
+                    // what came in before the panic is dropped along with the stuck notes
+                    self.timed_messages.clear();
+                    self.panic();
+                    continue;
+                }
             }
+            let frame = match self.midi_jitter_compensation {
+                true => self.frame_of(msg.time, now, len),
+                false => 0,
+            };
+            self.timed_messages.push((frame, None, msg));
         }
     }
 
-    fn receive_drum_machine_messages(&mut self) {
+    fn receive_drum_machine_messages(&mut self, len: usize) {
+        let now = Instant::now();
         while let Ok(msg) = self.dm_ctr_rx.try_recv() {
-            let node_id = msg.instrument_id;
-            if node_id < self.nodes.len() {
-                let node = &mut self.nodes[node_id].1;
-                if msg.velocity > 0 {
-                    let msg = midi::Message {
-                        kind: midi::MessageKind::NoteOn {
-                            note: msg.note,
-                            velocity: msg.velocity,
-                        },
-                        channel: msg.channel,
-                    };
-                    node.receive_midi_message(&msg);
-                } else {
-                    let msg = midi::Message {
-                        kind: midi::MessageKind::NoteOff {
-                            note: msg.note,
-                            velocity: 0,
-                        },
-                        channel: msg.channel,
-                    };
-                    node.receive_midi_message(&msg);
+            let kind = if let Some(kind) = msg.other {
+                kind
+            } else if msg.note_on {
+                midi::MessageKind::NoteOn {
+                    note: msg.note,
+                    velocity: msg.velocity,
                 }
-            }
+            } else {
+                midi::MessageKind::NoteOff {
+                    note: msg.note,
+                    velocity: msg.velocity,
+                }
+            };
+            let frame = self.frame_of(msg.time, now, len);
+            let midi_msg = midi::Message::new(msg.channel, kind);
+            self.timed_messages
+                .push((frame, Some(msg.instrument_id), midi_msg));
         }
     }
 
-    fn render_audio(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
+    // The frame of a buffer of `len` frames a message due at `time` belongs at. The buffer stands
+    // for the time of one buffer before now, so timed messages sound a buffer later than they're
+    // due, but keep their spacing instead of being moved to the start of a buffer.
+    fn frame_of(&self, time: Option<Instant>, now: Instant, len: usize) -> usize {
+        let (Some(time), Some(sample_rate)) = (time, self.sample_rate) else {
+            return 0;
+        };
+        let period = Duration::from_secs_f64(len as f64 / sample_rate as f64);
+        let Some(buffer_start) = now.checked_sub(period) else {
+            return 0;
+        };
+        let since_start = time.saturating_duration_since(buffer_start).as_secs_f64();
+        ((since_start * sample_rate as f64) as usize).min(len.saturating_sub(1))
+    }
+
+    // Of the frames of the buffers and of the other output pairs
+    fn render_audio(&mut self, lbuf: &mut [f32], rbuf: &mut [f32], frames: Range<usize>) {
+        let (lbuf, rbuf) = (&mut lbuf[frames.clone()], &mut rbuf[frames.clone()]);
         lbuf.fill(0.0);
         rbuf.fill(0.0);
-        for (_, node) in &mut self.nodes {
-            node.render_additive(lbuf, rbuf)
+        for (bus_lbuf, bus_rbuf) in self.outputs.buses_mut(frames.clone()) {
+            bus_lbuf.fill(0.0);
+            bus_rbuf.fill(0.0);
+        }
+        let len = lbuf.len().min(rbuf.len());
+        let start = Instant::now();
+        self.expression.advance(len, self.sample_rate);
+        let mut mix = |index: usize, node_lbuf: &[f32], node_rbuf: &[f32], time: Duration| {
+            self.load_meter.add_node_time(index, time);
+            self.level_meter.add_node(index, node_lbuf, node_rbuf);
+            if self.bypass.is_bypassed(index) {
+                return;
+            }
+            let amount = self.modulation.pitch_shift(index);
+            let (node_lbuf, node_rbuf) = self
+                .pitch_shifts
+                .process(index, node_lbuf, node_rbuf, amount);
+            let volume =
+                self.layers.volume(index) * self.modulation.gain(index) * self.groups.gain(index);
+            let pair = self.outputs.pair(index, &self.groups);
+            let (out_lbuf, out_rbuf) = match self.outputs.bus_mut(pair, frames.clone()) {
+                Some(bus) => bus,
+                None => (&mut *lbuf, &mut *rbuf),
+            };
+            match self.expression.node_ramp(index) {
+                Some(ramp) => {
+                    add_ramped_buf_to_buf(out_lbuf, node_lbuf, volume, ramp);
+                    add_ramped_buf_to_buf(out_rbuf, node_rbuf, volume, ramp);
+                }
+                None => {
+                    add_amplified_buf_to_buf(out_lbuf, node_lbuf, volume);
+                    add_amplified_buf_to_buf(out_rbuf, node_rbuf, volume);
+                }
+            }
+        };
+        match &mut self.pool {
+            Some(pool) if self.nodes.len() > 1 => pool.render(&mut self.nodes, len, mix),
+            _ => {
+                if self.node_lbuf.len() < len {
+                    self.node_lbuf.resize(len, 0.0);
+                    self.node_rbuf.resize(len, 0.0);
+                }
+                let (node_lbuf, node_rbuf) =
+                    (&mut self.node_lbuf[..len], &mut self.node_rbuf[..len]);
+                for (index, (_, node)) in self.nodes.iter_mut().enumerate() {
+                    node_lbuf.fill(0.0);
+                    node_rbuf.fill(0.0);
+                    let node_start = Instant::now();
+                    node.render_additive(node_lbuf, node_rbuf);
+                    mix(index, node_lbuf, node_rbuf, node_start.elapsed());
+                }
+            }
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            let outputs = &mut self.outputs;
+            self.retired
+                .render(len, sample_rate, |pair, volume, node_lbuf, node_rbuf| {
+                    let (out_lbuf, out_rbuf) = match outputs.bus_mut(pair, frames.clone()) {
+                        Some(bus) => bus,
+                        None => (&mut *lbuf, &mut *rbuf),
+                    };
+                    add_amplified_buf_to_buf(out_lbuf, node_lbuf, volume);
+                    add_amplified_buf_to_buf(out_rbuf, node_rbuf, volume);
+                });
+        }
+        if let Some(ramp) = self.expression.master_ramp() {
+            ramp_buffer(lbuf, ramp);
+            ramp_buffer(rbuf, ramp);
+            for (bus_lbuf, bus_rbuf) in self.outputs.buses_mut(frames) {
+                ramp_buffer(bus_lbuf, ramp);
+                ramp_buffer(bus_rbuf, ramp);
+            }
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            let audio_time = lbuf.len() as f32 / sample_rate as f32;
+            if let Some(load) = self.load_meter.finish_buffer(start.elapsed(), audio_time) {
+                self.load_tx.send_replace(load);
+            }
+            let levels = self.level_meter.add_master(lbuf, rbuf, sample_rate);
+            if let Some(levels) = levels {
+                self.levels_tx.send_replace(levels);
+            }
         }
     }
 
@@ -168,7 +732,13 @@ impl Renderer {
                 if id >= self.nodes.len() {
                     respond(responder, ResponseKind::InvalidId);
                 } else {
-                    self.nodes.remove(id);
+                    // let go of, the release rings out
+                    let mix = self.node_mix(id);
+                    let mut node = self.remove_node(id);
+                    node.release();
+                    if let Some((volume, pair)) = mix {
+                        self.retired.add(node, volume, pair);
+                    }
                     respond(responder, ResponseKind::RemoveNode { id })
                 }
             }
@@ -181,14 +751,457 @@ impl Renderer {
                     respond(responder, ResponseKind::CloneNode { id })
                 }
             }
-            RequestKind::MoveNode { id, new_id } => todo!(),
+            RequestKind::MoveNode { id, new_id } => {
+                if id >= self.nodes.len() || new_id >= self.nodes.len() {
+                    respond(responder, ResponseKind::InvalidId);
+                } else {
+                    self.move_node(id, new_id);
+                    respond(responder, ResponseKind::MoveNode { id, new_id })
+                }
+            }
+            RequestKind::Panic => {
+                self.panic();
+                respond(responder, ResponseKind::Panic);
+            }
+            RequestKind::SetGlobalTransposition(transposition) => {
+                if !transposition_is_valid(transposition) {
+                    respond(responder, ResponseKind::Failed);
+                    return;
+                }
+                self.set_global_transposition(transposition);
+                respond(responder, ResponseKind::GlobalTransposition(transposition));
+            }
+            RequestKind::SetOctaveShift(octaves) => {
+                if !(-octave::MAX_OCTAVES..=octave::MAX_OCTAVES).contains(&octaves) {
+                    respond(responder, ResponseKind::Failed);
+                    return;
+                }
+                self.transpose(self.global_transposition, octaves);
+                respond(responder, ResponseKind::OctaveShift(octaves));
+            }
+            RequestKind::SetOctaveShiftTriggers(triggers) => {
+                self.octave_shift.set_triggers(triggers);
+                respond(responder, ResponseKind::OctaveShiftTriggers(triggers));
+            }
+            RequestKind::SetTranspositionCc(kind) => {
+                self.transposition_cc = kind;
+                respond(responder, ResponseKind::TranspositionCc(kind));
+            }
+            RequestKind::SetZones(zones) => self.update_zones(responder, |_| Some(zones)),
+            RequestKind::AddZone(zone) => self.update_zones(responder, |mut zones| {
+                zones.push(zone);
+                Some(zones)
+            }),
+            RequestKind::SetZone { index, zone } => {
+                self.update_zones(responder, |mut zones| {
+                    *zones.get_mut(index)? = zone;
+                    Some(zones)
+                })
+            }
+            RequestKind::RemoveZone { index } => self.update_zones(responder, |mut zones| {
+                (index < zones.len()).then(|| {
+                    zones.remove(index);
+                    zones
+                })
+            }),
+            RequestKind::SetLayeredInstruments(instruments) => {
+                self.update_layers(responder, |_, selected| Some((instruments, selected)))
+            }
+            RequestKind::AddLayeredInstrument(instrument) => {
+                self.update_layers(responder, |mut instruments, selected| {
+                    instruments.push(instrument);
+                    Some((instruments, selected))
+                })
+            }
+            RequestKind::SetLayeredInstrument { index, instrument } => {
+                self.update_layers(responder, |mut instruments, selected| {
+                    *instruments.get_mut(index)? = instrument;
+                    Some((instruments, selected))
+                })
+            }
+            RequestKind::RemoveLayeredInstrument { index } => {
+                self.update_layers(responder, |mut instruments, selected| {
+                    if index >= instruments.len() {
+                        return None;
+                    }
+                    instruments.remove(index);
+                    let selected = match selected {
+                        Some(selected) if selected == index => None,
+                        Some(selected) if selected > index => Some(selected - 1),
+                        selected => selected,
+                    };
+                    Some((instruments, selected))
+                })
+            }
+            RequestKind::SelectLayeredInstrument(selected) => {
+                self.update_layers(responder, |instruments, _| {
+                    let valid = selected.is_none_or(|index| index < instruments.len());
+                    valid.then_some((instruments, selected))
+                })
+            }
+            RequestKind::SetExpression(settings) => {
+                if !expression_is_valid(settings.as_ref(), self.nodes.len()) {
+                    respond(responder, ResponseKind::Failed);
+                    return;
+                }
+                self.expression.set(settings.clone());
+                respond(responder, ResponseKind::Expression(settings));
+            }
+            RequestKind::SetBypassed { id, bypassed } => {
+                if id >= self.nodes.len() {
+                    respond(responder, ResponseKind::InvalidId);
+                    return;
+                }
+                self.bypass.set_bypassed(id, bypassed);
+                let nodes = self.bypass.get().to_vec();
+                respond(responder, ResponseKind::Bypassed(nodes));
+            }
+            RequestKind::SetOutputPair { id, pair } => {
+                if id >= self.nodes.len() {
+                    respond(responder, ResponseKind::InvalidId);
+                    return;
+                }
+                self.outputs.set_pair(id, pair);
+                let routes = self.outputs.get().to_vec();
+                respond(responder, ResponseKind::OutputRoutes(routes));
+            }
+            RequestKind::SetMonitorPairs(pairs) => {
+                self.outputs.set_monitors(pairs);
+                let pairs = self.outputs.monitors().to_vec();
+                respond(responder, ResponseKind::MonitorPairs(pairs));
+            }
+            RequestKind::SetPitchShift(shift) => {
+                if shift.node >= self.nodes.len() {
+                    respond(responder, ResponseKind::InvalidId);
+                    return;
+                }
+                if !shift.is_valid() {
+                    respond(responder, ResponseKind::Failed);
+                    return;
+                }
+                self.pitch_shifts.set_shift(shift);
+                let shifts = self.pitch_shifts.get().to_vec();
+                respond(responder, ResponseKind::PitchShifts(shifts));
+            }
+            RequestKind::RemovePitchShift { id } => {
+                self.pitch_shifts.remove_shift(id);
+                let shifts = self.pitch_shifts.get().to_vec();
+                respond(responder, ResponseKind::PitchShifts(shifts));
+            }
+            RequestKind::SetGroups(groups) => self.update_groups(responder, |_| Some(groups)),
+            RequestKind::AddGroup(group) => self.update_groups(responder, |mut groups| {
+                groups.push(group);
+                Some(groups)
+            }),
+            RequestKind::SetGroup { index, group } => {
+                self.update_groups(responder, |mut groups| {
+                    *groups.get_mut(index)? = group;
+                    Some(groups)
+                })
+            }
+            RequestKind::RemoveGroup { index } => self.update_groups(responder, |mut groups| {
+                (index < groups.len()).then(|| {
+                    groups.remove(index);
+                    groups
+                })
+            }),
+            RequestKind::SetModulationRoutes(routes) => {
+                self.update_modulation(responder, |_| Some(routes))
+            }
+            RequestKind::AddModulationRoute(route) => {
+                self.update_modulation(responder, |mut routes| {
+                    routes.push(route);
+                    Some(routes)
+                })
+            }
+            RequestKind::SetModulationRoute { index, route } => {
+                self.update_modulation(responder, |mut routes| {
+                    *routes.get_mut(index)? = route;
+                    Some(routes)
+                })
+            }
+            RequestKind::RemoveModulationRoute { index } => {
+                self.update_modulation(responder, |mut routes| {
+                    (index < routes.len()).then(|| {
+                        routes.remove(index);
+                        routes
+                    })
+                })
+            }
+            RequestKind::SetSetup(setup) => self.set_setup(setup, responder),
         }
     }
+
+    fn set_setup(&mut self, setup: command::Setup, responder: Responder) {
+        let known = setup
+            .nodes
+            .iter()
+            .all(|node| self.registered_node_kinds.contains_key(&node.kind));
+        if !known {
+            respond(responder, ResponseKind::InvalidNodeKind);
+            return;
+        }
+        let num_nodes = setup.nodes.len();
+        let selected = setup.selected_layered_instrument;
+        let valid = zones_are_valid(&setup.zones, num_nodes)
+            && layers_are_valid(&setup.layered_instruments, num_nodes)
+            && selected.is_none_or(|index| index < setup.layered_instruments.len())
+            && routes_are_valid(&setup.modulation_routes, num_nodes)
+            && expression_is_valid(setup.expression.as_ref(), num_nodes)
+            && setup.bypassed.iter().all(|&node| node < num_nodes)
+            && groups_are_valid(&setup.groups, num_nodes)
+            && output_routes_are_valid(&setup.output_routes, num_nodes)
+            && pitch_shifts_are_valid(&setup.pitch_shifts, num_nodes)
+            && transposition_is_valid(setup.global_transposition);
+        if !valid {
+            respond(responder, ResponseKind::Failed);
+            return;
+        }
+
+        // the nodes going away ring out
+        while self.nodes.len() > num_nodes {
+            let id = self.nodes.len() - 1;
+            let mix = self.node_mix(id);
+            let node = self.remove_node(id);
+            if let Some((volume, pair)) = mix {
+                self.retired.add(node, volume, pair);
+            }
+        }
+        for (id, state) in setup.nodes.iter().enumerate() {
+            let kept = self
+                .nodes
+                .get(id)
+                .is_some_and(|(kind, _)| *kind == state.kind);
+            if !kept {
+                let mut node = self.registered_node_kinds[&state.kind]();
+                self.prepare_node(id, &mut node);
+                if id < self.nodes.len() {
+                    let (_, old) =
+                        std::mem::replace(&mut self.nodes[id], (state.kind.clone(), node));
+                    if let Some((volume, pair)) = self.node_mix(id) {
+                        self.retired.add(old, volume, pair);
+                    }
+                    self.load_meter.reset();
+                    self.level_meter.reset();
+                } else {
+                    self.nodes.push((state.kind.clone(), node));
+                }
+            }
+            let node = &mut self.nodes[id].1;
+            let loaded = loaded_file(node.serialize().ok().as_ref());
+            if node.deserialize(&state.instance).is_err() {
+                error!("Failed to set up render node {id} of kind {}", state.kind);
+            }
+            // taking the settings doesn't load the file
+            let file = loaded_file(Some(&state.instance));
+            if let Some(path) = file.filter(|file| Some(file) != loaded.as_ref()) {
+                node.process_request(node::RequestKind::LoadFile(path), Box::new(|_| {}));
+            }
+        }
+
+        self.zones.set(setup.zones);
+        self.layers.set(setup.layered_instruments, selected);
+        self.layers.retune(&mut self.routed);
+        self.send_routed();
+        self.modulation.set(setup.modulation_routes);
+        self.expression.set(setup.expression);
+        self.bypass.set(setup.bypassed);
+        self.groups.set(setup.groups);
+        self.outputs.set(setup.output_routes);
+        self.outputs.set_monitors(setup.monitor_pairs);
+        self.pitch_shifts.set(setup.pitch_shifts);
+        // over what the nodes had in their settings
+        self.set_global_transposition(setup.global_transposition);
+        self.transposition_cc = setup.transposition_cc;
+        self.octave_shift.set_triggers(setup.octave_shift_triggers);
+
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|(kind, node)| NodeState {
+                kind: kind.clone(),
+                instance: node.serialize().unwrap_or_default(),
+            })
+            .collect();
+        let setup = command::Setup {
+            nodes,
+            zones: self.zones.get().to_vec(),
+            layered_instruments: self.layers.get().to_vec(),
+            selected_layered_instrument: self.layers.selected(),
+            modulation_routes: self.modulation.get().to_vec(),
+            expression: self.expression.get().cloned(),
+            bypassed: self.bypass.get().to_vec(),
+            groups: self.groups.get().to_vec(),
+            output_routes: self.outputs.get().to_vec(),
+            monitor_pairs: self.outputs.monitors().to_vec(),
+            pitch_shifts: self.pitch_shifts.get().to_vec(),
+            global_transposition: self.global_transposition,
+            transposition_cc: self.transposition_cc,
+            octave_shift_triggers: self.octave_shift.triggers(),
+        };
+        respond(responder, ResponseKind::Setup(Box::new(setup)));
+    }
+
+    // `change` gives the new instruments and selection, none for an instrument that doesn't exist
+    fn update_layers<F>(&mut self, responder: Responder, change: F)
+    where
+        F: FnOnce(
+            Vec<layers::Instrument>,
+            Option<usize>,
+        ) -> Option<(Vec<layers::Instrument>, Option<usize>)>,
+    {
+        let current = self.layers.get().to_vec();
+        let Some((instruments, selected)) = change(current, self.layers.selected()) else {
+            respond(responder, ResponseKind::InvalidId);
+            return;
+        };
+        if !layers_are_valid(&instruments, self.nodes.len()) {
+            respond(responder, ResponseKind::Failed);
+            return;
+        }
+        self.layers.set(instruments.clone(), selected);
+        self.layers.retune(&mut self.routed);
+        self.send_routed();
+        let selected = self.layers.selected();
+        respond(
+            responder,
+            ResponseKind::LayeredInstruments {
+                instruments,
+                selected,
+            },
+        );
+    }
+
+    // `change` gives the new routes, none for a route that doesn't exist
+    fn update_modulation<F>(&mut self, responder: Responder, change: F)
+    where
+        F: FnOnce(Vec<modulation::Route>) -> Option<Vec<modulation::Route>>,
+    {
+        let Some(routes) = change(self.modulation.get().to_vec()) else {
+            respond(responder, ResponseKind::InvalidId);
+            return;
+        };
+        if !routes_are_valid(&routes, self.nodes.len()) {
+            respond(responder, ResponseKind::Failed);
+            return;
+        }
+        self.modulation.set(routes.clone());
+        respond(responder, ResponseKind::ModulationRoutes(routes));
+    }
+
+    // `change` gives the new groups, none for a group that doesn't exist
+    fn update_groups<F>(&mut self, responder: Responder, change: F)
+    where
+        F: FnOnce(Vec<groups::Group>) -> Option<Vec<groups::Group>>,
+    {
+        let Some(groups) = change(self.groups.get().to_vec()) else {
+            respond(responder, ResponseKind::InvalidId);
+            return;
+        };
+        if !groups_are_valid(&groups, self.nodes.len()) {
+            respond(responder, ResponseKind::Failed);
+            return;
+        }
+        self.groups.set(groups.clone());
+        respond(responder, ResponseKind::Groups(groups));
+    }
+
+    // `change` gives the new zones, none for a zone that doesn't exist
+    fn update_zones<F>(&mut self, responder: Responder, change: F)
+    where
+        F: FnOnce(Vec<zones::Zone>) -> Option<Vec<zones::Zone>>,
+    {
+        let Some(zones) = change(self.zones.get().to_vec()) else {
+            respond(responder, ResponseKind::InvalidId);
+            return;
+        };
+        if !zones_are_valid(&zones, self.nodes.len()) {
+            respond(responder, ResponseKind::Failed);
+            return;
+        }
+        self.zones.set(zones.clone());
+        respond(responder, ResponseKind::Zones(zones));
+    }
+}
+
+// Where node `node` ends up when the node at `id` moves to `new_id`
+pub fn moved_node(node: usize, id: usize, new_id: usize) -> usize {
+    match node {
+        node if node == id => new_id,
+        node if id < node && node <= new_id => node - 1,
+        node if new_id <= node && node < id => node + 1,
+        node => node,
+    }
+}
+
+fn loaded_file(instance: Option<&serde_json::Value>) -> Option<PathBuf> {
+    instance?["loaded_file"].as_str().map(PathBuf::from)
+}
+
+fn transposition_is_valid(transposition: i8) -> bool {
+    (-MAX_GLOBAL_TRANSPOSITION..=MAX_GLOBAL_TRANSPOSITION).contains(&transposition)
+}
+
+fn zones_are_valid(zones: &[zones::Zone], num_nodes: usize) -> bool {
+    zones
+        .iter()
+        .all(|zone| zone.node < num_nodes && zone.low_key <= zone.high_key)
+}
+
+fn layers_are_valid(instruments: &[layers::Instrument], num_nodes: usize) -> bool {
+    instruments
+        .iter()
+        .flat_map(|instrument| &instrument.layers)
+        .all(|layer| layer.node < num_nodes && layer.min_velocity <= layer.max_velocity)
+}
+
+fn routes_are_valid(routes: &[modulation::Route], num_nodes: usize) -> bool {
+    routes
+        .iter()
+        .all(|route| route.node < num_nodes && route.is_valid())
+}
+
+// A node is in one group at most
+fn groups_are_valid(groups: &[groups::Group], num_nodes: usize) -> bool {
+    let mut grouped = vec![false; num_nodes];
+    groups.iter().all(|group| {
+        group.is_valid()
+            && group
+                .nodes
+                .iter()
+                .all(|&node| node < num_nodes && !std::mem::replace(&mut grouped[node], true))
+    })
+}
+
+fn output_routes_are_valid(routes: &[outputs::Route], num_nodes: usize) -> bool {
+    routes.iter().all(|route| route.node < num_nodes)
+}
+
+fn pitch_shifts_are_valid(shifts: &[pitch_shift::Shift], num_nodes: usize) -> bool {
+    shifts
+        .iter()
+        .all(|shift| shift.node < num_nodes && shift.is_valid())
 }
 
+fn expression_is_valid(settings: Option<&expression::Settings>, num_nodes: usize) -> bool {
+    match settings.map(|settings| &settings.scope) {
+        Some(expression::Scope::Nodes(nodes)) => nodes.iter().all(|&node| node < num_nodes),
+        _ => true,
+    }
+}
+
+// Buffers are processed in fixed-size chunks, which the compiler turns into SIMD instructions
+// (NEON on the Pi, SSE/AVX elsewhere) without needing the unstable std::simd
+const LANES: usize = 8;
+
 pub fn amplify_buffer(buffer: &mut [f32], gain: f32) {
     if gain != 1.0 {
-        buffer.iter_mut().for_each(|x| *x *= gain);
+        let mut chunks = buffer.chunks_exact_mut(LANES);
+        for chunk in &mut chunks {
+            let chunk: &mut [f32; LANES] = chunk.try_into().expect("Chunks have LANES samples");
+            chunk.iter_mut().for_each(|x| *x *= gain);
+        }
+        chunks.into_remainder().iter_mut().for_each(|x| *x *= gain);
     }
 }
 
@@ -203,9 +1216,47 @@ pub fn render_nodes_to_bufs(nodes: &mut [RenderPtr], lbuf: &mut [f32], rbuf: &mu
 }
 
 pub fn add_buf_to_buf(buffer: &mut [f32], tmp_buffer: &[f32]) {
-    let len = usize::min(buffer.len(), tmp_buffer.len());
-    for i in 0..len {
-        buffer[i] += tmp_buffer[i];
+    zip_chunks(buffer, tmp_buffer, |x, y| *x += y);
+}
+
+// Gain staging and summing in one pass over the buffers
+pub fn add_amplified_buf_to_buf(buffer: &mut [f32], tmp_buffer: &[f32], gain: f32) {
+    if gain == 1.0 {
+        add_buf_to_buf(buffer, tmp_buffer);
+    } else {
+        zip_chunks(buffer, tmp_buffer, |x, y| *x += y * gain);
+    }
+}
+
+// For gains changing from frame to frame, `ramp` has the gain of every frame
+pub fn add_ramped_buf_to_buf(buffer: &mut [f32], tmp_buffer: &[f32], gain: f32, ramp: &[f32]) {
+    for ((x, &y), &ramp_gain) in buffer.iter_mut().zip(tmp_buffer).zip(ramp) {
+        *x += y * gain * ramp_gain;
+    }
+}
+
+pub fn ramp_buffer(buffer: &mut [f32], ramp: &[f32]) {
+    for (x, &gain) in buffer.iter_mut().zip(ramp) {
+        *x *= gain;
+    }
+}
+
+// Applies `f` to the samples both buffers have, the closure gets inlined into the chunk loop
+#[inline(always)]
+fn zip_chunks(buffer: &mut [f32], other: &[f32], f: impl Fn(&mut f32, f32)) {
+    let len = usize::min(buffer.len(), other.len());
+    let mut chunks = buffer[..len].chunks_exact_mut(LANES);
+    let mut other_chunks = other[..len].chunks_exact(LANES);
+    for (chunk, other_chunk) in (&mut chunks).zip(&mut other_chunks) {
+        let chunk: &mut [f32; LANES] = chunk.try_into().expect("Chunks have LANES samples");
+        let other_chunk: &[f32; LANES] = other_chunk.try_into().expect("Chunks have LANES samples");
+        for (x, &y) in chunk.iter_mut().zip(other_chunk) {
+            f(x, y);
+        }
+    }
+    let remainder = chunks.into_remainder();
+    for (x, &y) in remainder.iter_mut().zip(other_chunks.remainder()) {
+        f(x, y);
     }
 }
 
@@ -217,6 +1268,429 @@ fn respond(responder: Responder, response_kind: ResponseKind) {
 
 #[cfg(test)]
 mod tests {
+    use super::{
+        command::ResponseCallback,
+        node::{Render, RenderPtr, RequestKind},
+        Renderer,
+    };
+    use crate::{
+        control::{self, ControlMessage},
+        deser::{DeserializationResult, SerializationResult},
+        json::JsonUpdater,
+        midi,
+        path::VirtualPaths,
+    };
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    // Notes the frame every message arrives at, a panic arrives as usize::MAX and a release as
+    // usize::MAX - 1. Plays a constant 1.0 on the left.
+    #[derive(Clone)]
+    struct Probe {
+        frames_rendered: usize,
+        arrivals: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Render for Probe {
+        fn render_additive(&mut self, lbuf: &mut [f32], _rbuf: &mut [f32]) {
+            lbuf.iter_mut().for_each(|x| *x += 1.0);
+            self.frames_rendered += lbuf.len();
+        }
+        fn reset_rendering(&mut self) {}
+        fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+        fn set_sample_rate(&mut self, _sample_rate: u32) {}
+        fn receive_midi_message(&mut self, _message: &midi::Message) {
+            self.arrivals.lock().unwrap().push(self.frames_rendered);
+        }
+        fn panic(&mut self) {
+            self.arrivals.lock().unwrap().push(usize::MAX);
+        }
+        fn release(&mut self) {
+            self.arrivals.lock().unwrap().push(usize::MAX - 1);
+        }
+        fn set_global_transposition(&mut self, _transposition: i8) {}
+        fn set_json_updater(&mut self, _updater: JsonUpdater) {}
+        fn process_request(&mut self, _kind: RequestKind, _cb: ResponseCallback) {}
+        fn serialize(&self) -> SerializationResult {
+            Ok(serde_json::Value::Null)
+        }
+        fn deserialize(&mut self, _source: &serde_json::Value) -> DeserializationResult {
+            Ok(())
+        }
+        fn clone_node(&self) -> RenderPtr {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn timed_messages_arrive_at_their_frame() {
+        let (_midi_tx, midi_rx) = midi::create_channel(1);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(4);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        renderer.set_sample_rate(1000);
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let probe = Probe {
+            frames_rendered: 0,
+            arrivals: Arc::clone(&arrivals),
+        };
+        renderer.add_node("Probe".into(), Box::new(probe));
+
+        // a buffer of 100 frames is 100 ms long, and stands for the 100 ms before rendering
+        let now = Instant::now();
+        for ago_ms in [None, Some(70), Some(120), Some(40)] {
+            let time = ago_ms.map(|ms| now - Duration::from_millis(ms));
+            let message = ControlMessage {
+                instrument_id: 0,
+                channel: 9,
+                note: 36,
+                note_on: true,
+                velocity: 100,
+                time,
+                other: None,
+            };
+            dm_ctr_tx.try_send(message).unwrap();
+        }
+        let (mut lbuf, mut rbuf) = (vec![0.0; 100], vec![0.0; 100]);
+        renderer.render(&mut lbuf, &mut rbuf);
+
+        // untimed and overdue messages go at the start, the others a little earlier than due
+        // as the renderer takes its own now after this one, frame_of has the exact frames
+        let arrivals = arrivals.lock().unwrap().clone();
+        assert_eq!(arrivals[..2], [0, 0]);
+        assert!(arrivals[2] <= 30 && arrivals[3] <= 60, "{arrivals:?}");
+        assert!(arrivals[2] <= arrivals[3], "{arrivals:?}");
+    }
+
+    #[test]
+    fn frame_of() {
+        let (_midi_tx, midi_rx) = midi::create_channel(1);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        let now = Instant::now();
+        let ago = |ms| Some(now - Duration::from_millis(ms));
+        assert_eq!(renderer.frame_of(ago(70), now, 100), 0);
+
+        // a buffer of 100 frames is 100 ms long, and stands for the 100 ms before now
+        renderer.set_sample_rate(1000);
+        assert_eq!(renderer.frame_of(None, now, 100), 0);
+        assert_eq!(renderer.frame_of(ago(120), now, 100), 0);
+        assert_eq!(renderer.frame_of(ago(70), now, 100), 30);
+        assert_eq!(renderer.frame_of(ago(40), now, 100), 60);
+        let later = Some(now + Duration::from_millis(10));
+        assert_eq!(renderer.frame_of(later, now, 100), 99);
+    }
+
+    #[test]
+    fn midi_jitter_compensation() {
+        let (midi_tx, midi_rx) = midi::create_channel(4);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        renderer.set_sample_rate(1000);
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let probe = Probe {
+            frames_rendered: 0,
+            arrivals: Arc::clone(&arrivals),
+        };
+        renderer.add_node("Probe".into(), Box::new(probe));
+        let note_on = midi::Message::new(
+            0,
+            midi::MessageKind::NoteOn {
+                note: 60,
+                velocity: 90,
+            },
+        );
+        let (mut lbuf, mut rbuf) = (vec![0.0; 100], vec![0.0; 100]);
+
+        midi_tx
+            .send(note_on.clone().at(Instant::now() - Duration::from_millis(40)))
+            .unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+        renderer.set_midi_jitter_compensation(true);
+        midi_tx
+            .send(note_on.at(Instant::now() - Duration::from_millis(40)))
+            .unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+
+        // in the second buffer, a little earlier than due as the renderer takes its own now
+        let arrivals = arrivals.lock().unwrap().clone();
+        assert_eq!(arrivals[0], 0);
+        assert!((100..=160).contains(&arrivals[1]), "{arrivals:?}");
+    }
+
+    #[test]
+    fn panic_cc() {
+        let (midi_tx, midi_rx) = midi::create_channel(4);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let probe = Probe {
+            frames_rendered: 0,
+            arrivals: Arc::clone(&arrivals),
+        };
+        renderer.add_node("Probe".into(), Box::new(probe));
+        let kind = midi::ControlChangeKind::from_number(80).unwrap();
+        renderer.set_panic_cc(Some(kind));
+        let cc = |value| midi::Message::new(3, midi::MessageKind::ControlChange { kind, value });
+        let (mut lbuf, mut rbuf) = (vec![0.0; 10], vec![0.0; 10]);
+
+        // releasing the pedal is an ordinary message
+        midi_tx.send(cc(0)).unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+        midi_tx.send(cc(0)).unwrap();
+        midi_tx.send(cc(127)).unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+
+        assert_eq!(*arrivals.lock().unwrap(), [0, usize::MAX]);
+    }
+
+    #[test]
+    fn consumed_notes_stay_out() {
+        let (midi_tx, midi_rx) = midi::create_channel(8);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let probe = Probe {
+            frames_rendered: 0,
+            arrivals: Arc::clone(&arrivals),
+        };
+        renderer.add_node("Probe".into(), Box::new(probe));
+        let mut consumed = control::ConsumedInputs::default();
+        consumed.notes.insert((9, 36));
+        let (consumed_tx, consumed_rx) = tokio::sync::watch::channel(consumed);
+        renderer.set_consumed_inputs_receiver(consumed_rx);
+        let note =
+            |note, velocity| midi::Message::new(9, midi::MessageKind::NoteOn { note, velocity });
+        let (mut lbuf, mut rbuf) = (vec![0.0; 10], vec![0.0; 10]);
+
+        midi_tx.send(note(36, 100)).unwrap();
+        midi_tx.send(note(38, 100)).unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(arrivals.lock().unwrap().len(), 1);
+
+        // the note off of a kept note on is kept too, even once the pad is gone
+        consumed_tx.send_replace(Default::default());
+        midi_tx.send(note(36, 0)).unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(arrivals.lock().unwrap().len(), 1);
+        midi_tx.send(note(36, 100)).unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(arrivals.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn transposition_cc() {
+        let (midi_tx, midi_rx) = midi::create_channel(4);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let probe = Probe {
+            frames_rendered: 0,
+            arrivals: Arc::clone(&arrivals),
+        };
+        renderer.add_node("Probe".into(), Box::new(probe));
+        let transposition_rx = renderer.subscribe_transposition();
+        let kind = midi::ControlChangeKind::from_number(20).unwrap();
+        let (res_tx, _res_rx) = super::command::create_response_channel();
+        let req = super::command::RequestKind::SetTranspositionCc(Some(kind));
+        renderer.process_request(req, res_tx);
+        let cc = |value| midi::Message::new(0, midi::MessageKind::ControlChange { kind, value });
+        let note_on = midi::MessageKind::NoteOn {
+            note: 60,
+            velocity: 100,
+        };
+        let (mut lbuf, mut rbuf) = (vec![0.0; 10], vec![0.0; 10]);
+
+        // the held note gets its note off before the transposition changes
+        midi_tx.send(midi::Message::new(0, note_on)).unwrap();
+        midi_tx.send(cc(127)).unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(renderer.global_transposition, 12);
+        assert_eq!(*transposition_rx.borrow(), 12);
+        assert_eq!(*arrivals.lock().unwrap(), [0, 0]);
+
+        midi_tx.send(cc(64)).unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(renderer.global_transposition, 0);
+        assert_eq!(arrivals.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn fades_out() {
+        let (_midi_tx, midi_rx) = midi::create_channel(1);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        renderer.set_sample_rate(1000);
+        let probe = Probe {
+            frames_rendered: 0,
+            arrivals: Default::default(),
+        };
+        renderer.add_node("Probe".into(), Box::new(probe));
+        let (mut lbuf, mut rbuf) = (vec![0.0; 3], vec![0.0; 3]);
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(lbuf, [1.0; 3]);
+
+        renderer.fade_out(Duration::from_millis(4));
+        for expected in [[0.75, 0.5, 0.25], [0.0; 3]] {
+            renderer.render(&mut lbuf, &mut rbuf);
+            assert_eq!(lbuf, expected);
+        }
+    }
+
+    #[test]
+    fn bypassed_nodes_stay_out_of_the_mix() {
+        let (_midi_tx, midi_rx) = midi::create_channel(1);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        renderer.set_sample_rate(1000);
+        for _ in 0..2 {
+            let probe = Probe {
+                frames_rendered: 0,
+                arrivals: Default::default(),
+            };
+            renderer.add_node("Probe".into(), Box::new(probe));
+        }
+        let (mut lbuf, mut rbuf) = (vec![0.0; 3], vec![0.0; 3]);
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(lbuf, [2.0; 3]);
+
+        let (res_tx, mut res_rx) = super::command::create_response_channel();
+        let req = super::command::RequestKind::SetBypassed {
+            id: 1,
+            bypassed: true,
+        };
+        renderer.process_request(req, res_tx);
+        let res = res_rx.try_recv().unwrap();
+        assert_eq!(res, super::command::ResponseKind::Bypassed(vec![1]));
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(lbuf, [1.0; 3]);
+    }
+
+    #[test]
+    fn routed_nodes_play_on_their_pair() {
+        let (_midi_tx, midi_rx) = midi::create_channel(1);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        renderer.set_sample_rate(1000);
+        renderer.set_output_pairs(2);
+        for _ in 0..3 {
+            let probe = Probe {
+                frames_rendered: 0,
+                arrivals: Default::default(),
+            };
+            renderer.add_node("Probe".into(), Box::new(probe));
+        }
+        let (res_tx, mut res_rx) = super::command::create_response_channel();
+        let req = super::command::RequestKind::SetOutputPair { id: 2, pair: 1 };
+        renderer.process_request(req, res_tx);
+        let route = super::outputs::Route { node: 2, pair: 1 };
+        let res = res_rx.try_recv().unwrap();
+        assert_eq!(res, super::command::ResponseKind::OutputRoutes(vec![route]));
+
+        let (mut lbuf, mut rbuf) = (vec![0.0; 3], vec![0.0; 3]);
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(lbuf, [2.0; 3]);
+        let (bus_lbuf, _) = renderer.output_pair(1).unwrap();
+        assert_eq!(bus_lbuf, [1.0; 3]);
+
+        // a device with fewer pairs gets everything on pair 0
+        renderer.set_output_pairs(1);
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(lbuf, [3.0; 3]);
+        assert!(renderer.output_pair(1).is_none());
+    }
+
+    #[test]
+    fn monitor_pairs_get_the_main_mix() {
+        let (_midi_tx, midi_rx) = midi::create_channel(1);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        renderer.set_sample_rate(1000);
+        renderer.set_output_pairs(3);
+        for _ in 0..2 {
+            let probe = Probe {
+                frames_rendered: 0,
+                arrivals: Default::default(),
+            };
+            renderer.add_node("Probe".into(), Box::new(probe));
+        }
+        // the click goes to the in-ears only
+        let (res_tx, _res_rx) = super::command::create_response_channel();
+        let req = super::command::RequestKind::SetOutputPair { id: 1, pair: 2 };
+        renderer.process_request(req, res_tx);
+        let (res_tx, mut res_rx) = super::command::create_response_channel();
+        let req = super::command::RequestKind::SetMonitorPairs(vec![2, 0, 2]);
+        renderer.process_request(req, res_tx);
+        let res = res_rx.try_recv().unwrap();
+        assert_eq!(res, super::command::ResponseKind::MonitorPairs(vec![2]));
+
+        let (mut lbuf, mut rbuf) = (vec![0.0; 3], vec![0.0; 3]);
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(lbuf, [1.0; 3]);
+        assert_eq!(renderer.output_pair(1).unwrap().0, [0.0; 3]);
+        assert_eq!(renderer.output_pair(2).unwrap().0, [2.0; 3]);
+    }
+
+    #[test]
+    fn replaced_nodes_ring_out() {
+        let (midi_tx, midi_rx) = midi::create_channel(4);
+        let (_req_tx, req_rx) = super::command::create_request_channel(1);
+        let (_dm_ctr_tx, dm_ctr_rx) = control::create_control_channel(1);
+        let mut renderer = Renderer::new(midi_rx, req_rx, dm_ctr_rx, VirtualPaths::default());
+        renderer.set_sample_rate(1000);
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let probe = Probe {
+            frames_rendered: 0,
+            arrivals: Arc::clone(&arrivals),
+        };
+        renderer.add_node("Probe".into(), Box::new(probe));
+        let note = |kind| midi::Message::new(0, kind);
+        let note_on = midi::MessageKind::NoteOn {
+            note: 60,
+            velocity: 100,
+        };
+        let note_off = midi::MessageKind::NoteOff {
+            note: 60,
+            velocity: 0,
+        };
+        let (mut lbuf, mut rbuf) = (vec![0.0; 100], vec![0.0; 100]);
+        midi_tx.send(note(note_on.clone())).unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+
+        let (res_tx, mut res_rx) = super::command::create_response_channel();
+        let req = super::command::RequestKind::SetSetup(Default::default());
+        renderer.process_request(req, res_tx);
+        assert!(matches!(
+            res_rx.try_recv(),
+            Ok(super::command::ResponseKind::Setup(_))
+        ));
+
+        // the held note still sounds, a new one doesn't get to the node, the note off does
+        // and lets go of it
+        midi_tx.send(note(note_on)).unwrap();
+        midi_tx.send(note(note_off)).unwrap();
+        renderer.render(&mut lbuf, &mut rbuf);
+        assert_eq!(lbuf, [1.0; 100]);
+        assert_eq!(*arrivals.lock().unwrap(), [0, 100, usize::MAX - 1]);
+
+        // the probe never goes silent, it fades out and is gone after ten seconds
+        for _ in 0..101 {
+            renderer.render(&mut lbuf, &mut rbuf);
+        }
+        assert_eq!(lbuf, [0.0; 100]);
+    }
+
     #[test]
     fn amplify_buffer() {
         let gain = 3.2;
@@ -224,4 +1698,31 @@ mod tests {
         super::amplify_buffer(&mut buffer, gain);
         assert_eq!(buffer, [1.0 * gain, 0.0 * gain, 3.2 * gain])
     }
+
+    #[test]
+    fn add_amplified_buf_to_buf() {
+        // longer than a chunk and not a multiple of one
+        let tmp_buffer: Vec<f32> = (0..19).map(|i| i as f32).collect();
+        let mut buffer = vec![1.0; 21];
+        super::add_amplified_buf_to_buf(&mut buffer, &tmp_buffer, 0.5);
+        let expected: Vec<f32> = (0..21)
+            .map(|i| if i < 19 { 1.0 + i as f32 * 0.5 } else { 1.0 })
+            .collect();
+        assert_eq!(buffer, expected);
+
+        super::add_buf_to_buf(&mut buffer[..3], &tmp_buffer);
+        assert_eq!(buffer[..4], [1.0, 2.5, 4.0, 2.5]);
+    }
+
+    #[test]
+    fn moved_nodes() {
+        let moved = |id, new_id| -> Vec<usize> {
+            (0..4)
+                .map(|node| super::moved_node(node, id, new_id))
+                .collect()
+        };
+        assert_eq!(moved(1, 3), [0, 3, 1, 2]);
+        assert_eq!(moved(3, 0), [1, 2, 3, 0]);
+        assert_eq!(moved(2, 2), [0, 1, 2, 3]);
+    }
 }
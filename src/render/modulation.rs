@@ -0,0 +1,258 @@
+// Modulation matrix: routes from a controller to a parameter of a node, like the mod wheel
+// opening up the filter of a pad or aftertouch bringing in reverb, each with a depth and a
+// curve. The gain is applied to the output of the node and the pitch shift to its pitch shifter,
+// the other parameters go to the node as the controllers synths take them with.
+
+use super::moved_node;
+use crate::midi::{ControlChangeKind, Message, MessageKind};
+use serde::{Deserialize, Serialize};
+
+const PITCH_WHEEL_MAX: u16 = 0x3FFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Source {
+    ControlChange(ControlChangeKind),
+    ChannelAftertouch,
+    PitchWheel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Target {
+    Gain,
+    // Brightness, the filter cutoff of the instruments that map it
+    Filter,
+    Reverb,
+    Chorus,
+    // How much of its semitones the pitch shifter of the node shifts by
+    PitchShift,
+}
+
+impl Target {
+    fn controller(&self) -> Option<ControlChangeKind> {
+        match self {
+            Self::Gain | Self::PitchShift => None,
+            Self::Filter => Some(ControlChangeKind::SoundController5),
+            Self::Reverb => Some(ControlChangeKind::Effects1Depth),
+            Self::Chorus => Some(ControlChangeKind::Effects3Depth),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Curve {
+    Linear,
+    // Slow at first, for fine control of the low end
+    Exponential,
+    // Fast at first
+    Logarithmic,
+}
+
+impl Curve {
+    pub fn apply(&self, value: f32) -> f32 {
+        match self {
+            Self::Linear => value,
+            Self::Exponential => value * value,
+            Self::Logarithmic => value.sqrt(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub source: Source,
+    // Every channel without one
+    pub channel: Option<u8>,
+    // Id of the node
+    pub node: usize,
+    pub target: Target,
+    // How far down from the top the source takes the parameter, in -1.0..=1.0. A negative depth
+    // turns the source around, so the parameter goes down as the source goes up.
+    pub depth: f32,
+    pub curve: Curve,
+}
+
+impl Route {
+    pub fn is_valid(&self) -> bool {
+        (-1.0..=1.0).contains(&self.depth) && self.channel.is_none_or(|channel| channel < 16)
+    }
+
+    // In 0.0..=1.0, none for a message from something else
+    fn source_value(&self, message: &Message) -> Option<f32> {
+        if self
+            .channel
+            .is_some_and(|channel| channel != message.channel)
+        {
+            return None;
+        }
+        match (self.source, &message.kind) {
+            (Source::ControlChange(source), MessageKind::ControlChange { kind, value })
+                if source == *kind =>
+            {
+                Some(*value as f32 / 127.0)
+            }
+            (Source::ChannelAftertouch, MessageKind::ChannelAftertouch { pressure }) => {
+                Some(*pressure as f32 / 127.0)
+            }
+            (Source::PitchWheel, MessageKind::PitchWheel { value }) => {
+                Some(*value as f32 / PITCH_WHEEL_MAX as f32)
+            }
+            _ => None,
+        }
+    }
+
+    // The parameter in 0.0..=1.0 for a source value
+    fn amount(&self, value: f32) -> f32 {
+        let value = self.curve.apply(value.clamp(0.0, 1.0));
+        let value = if self.depth < 0.0 { 1.0 - value } else { value };
+        1.0 - self.depth.abs() * (1.0 - value)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Modulation {
+    routes: Vec<Route>,
+    // Of every route, none until its source moves
+    amounts: Vec<Option<f32>>,
+}
+
+impl Modulation {
+    pub fn get(&self) -> &[Route] {
+        &self.routes
+    }
+
+    // The parameters stay where they are until the sources move again
+    pub fn set(&mut self, routes: Vec<Route>) {
+        self.amounts = vec![None; routes.len()];
+        self.routes = routes;
+    }
+
+    // The routes of the node go with it, the ones of the nodes after it move up
+    pub fn remove_node(&mut self, node: usize) {
+        let routes = std::mem::take(&mut self.routes);
+        let amounts = std::mem::take(&mut self.amounts);
+        for (mut route, amount) in routes.into_iter().zip(amounts) {
+            if route.node == node {
+                continue;
+            }
+            if route.node > node {
+                route.node -= 1;
+            }
+            self.routes.push(route);
+            self.amounts.push(amount);
+        }
+    }
+
+    // The routes follow the node, the nodes in between shift by one
+    pub fn move_node(&mut self, id: usize, new_id: usize) {
+        for route in &mut self.routes {
+            route.node = moved_node(route.node, id, new_id);
+        }
+    }
+
+    // Of the node's output, 1.0 for a node without gain routes
+    pub fn gain(&self, node: usize) -> f32 {
+        self.amount(node, Target::Gain)
+    }
+
+    // Of the semitones of the node's pitch shifter, 1.0 for a node without pitch shift routes
+    pub fn pitch_shift(&self, node: usize) -> f32 {
+        self.amount(node, Target::PitchShift)
+    }
+
+    fn amount(&self, node: usize, target: Target) -> f32 {
+        self.routes
+            .iter()
+            .zip(&self.amounts)
+            .filter(|(route, _)| route.node == node && route.target == target)
+            .map(|(_, amount)| amount.unwrap_or(1.0))
+            .product()
+    }
+
+    // The control changes for the nodes of the routes the message is the source of
+    pub fn route(&mut self, message: &Message, routed: &mut Vec<(usize, Message)>) {
+        for (route, amount) in self.routes.iter().zip(&mut self.amounts) {
+            let Some(value) = route.source_value(message) else {
+                continue;
+            };
+            let route_amount = route.amount(value);
+            *amount = Some(route_amount);
+            if let Some(kind) = route.target.controller() {
+                let value = (route_amount * 127.0).round() as u8;
+                let kind = MessageKind::ControlChange { kind, value };
+                routed.push((route.node, Message::new(message.channel, kind)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Curve, Modulation, Route, Source, Target};
+    use crate::midi::{ControlChangeKind, Message, MessageKind};
+
+    fn route(node: usize, source: Source, target: Target, depth: f32) -> Route {
+        Route {
+            source,
+            channel: None,
+            node,
+            target,
+            depth,
+            curve: Curve::Linear,
+        }
+    }
+
+    fn cc(kind: ControlChangeKind, value: u8) -> Message {
+        Message::new(0, MessageKind::ControlChange { kind, value })
+    }
+
+    fn modulate(modulation: &mut Modulation, message: Message) -> Vec<(usize, Message)> {
+        let mut routed = vec![];
+        modulation.route(&message, &mut routed);
+        routed
+    }
+
+    #[test]
+    fn routes_sources_to_parameters() {
+        let wheel = Source::ControlChange(ControlChangeKind::ModulationWheelMsb);
+        let mut modulation = Modulation::default();
+        let mut brightness = route(1, wheel, Target::Filter, 0.5);
+        brightness.curve = Curve::Exponential;
+        modulation.set(vec![
+            route(0, wheel, Target::Gain, 1.0),
+            brightness,
+            route(1, Source::ChannelAftertouch, Target::Gain, -0.5),
+            route(1, wheel, Target::PitchShift, 1.0),
+        ]);
+        assert_eq!(modulation.gain(0), 1.0);
+
+        let routed = modulate(
+            &mut modulation,
+            cc(ControlChangeKind::ModulationWheelMsb, 0),
+        );
+        assert_eq!(routed, [(1, cc(ControlChangeKind::SoundController5, 64))]);
+        assert_eq!(modulation.gain(0), 0.0);
+        assert_eq!(modulation.pitch_shift(1), 0.0);
+        let routed = modulate(
+            &mut modulation,
+            cc(ControlChangeKind::ModulationWheelMsb, 127),
+        );
+        assert_eq!(routed, [(1, cc(ControlChangeKind::SoundController5, 127))]);
+        assert_eq!(modulation.gain(0), 1.0);
+        assert!(modulate(
+            &mut modulation,
+            cc(ControlChangeKind::ExpressionControllerMsb, 0)
+        )
+        .is_empty());
+
+        // turned around, full pressure takes it down by half
+        let pressure = Message::new(3, MessageKind::ChannelAftertouch { pressure: 127 });
+        assert!(modulate(&mut modulation, pressure).is_empty());
+        assert_eq!(modulation.gain(1), 0.5);
+
+        modulation.remove_node(0);
+        assert_eq!(modulation.get().len(), 3);
+        assert_eq!(modulation.gain(0), 0.5);
+
+        assert!(!route(0, wheel, Target::Gain, 1.5).is_valid());
+    }
+}
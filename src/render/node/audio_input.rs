@@ -0,0 +1,147 @@
+// Live audio from the input device in the graph, mixed with the synths like any node. MIDI
+// doesn't play it, the input does.
+
+use super::{Render, RenderPtr};
+use crate::{
+    audio::input::{Feed, Input},
+    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult},
+    json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater},
+    midi,
+    path::VirtualPaths,
+    render::{command::ResponseCallback, node::RequestKind},
+};
+use serde_json::json;
+
+const DEFAULT_NAME: &str = "Audio Input";
+
+pub struct Node {
+    name: String,
+    enabled: bool,
+    gain: f32,
+    // Played on both sides, none for channels 1/2 as a pair
+    channel: Option<u16>,
+    input: Option<Input>,
+    feed: Option<Feed>,
+}
+
+impl Node {
+    fn set_name(&mut self, name: &str) -> JsonUpdateKind {
+        self.name = name.into();
+        update_fields_or_fail(|updates| {
+            updates.push(("name".to_owned(), serialize(name)?));
+            Ok(())
+        })
+    }
+
+    fn set_enabled(&mut self, flag: bool) -> JsonUpdateKind {
+        self.enabled = flag;
+        update_fields_or_fail(|updates| {
+            updates.push(("enabled".to_owned(), serialize(flag)?));
+            Ok(())
+        })
+    }
+
+    fn set_gain(&mut self, gain: f32) -> JsonUpdateKind {
+        self.gain = gain;
+        update_fields_or_fail(|updates| {
+            updates.push(("gain".into(), serialize(gain)?));
+            Ok(())
+        })
+    }
+
+    fn set_input_channel(&mut self, channel: Option<u16>) -> JsonUpdateKind {
+        self.channel = channel;
+        update_fields_or_fail(|updates| {
+            updates.push(("input_channel".into(), serialize(channel)?));
+            Ok(())
+        })
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_NAME.into(),
+            enabled: true,
+            gain: 1.0,
+            channel: None,
+            input: None,
+            feed: None,
+        }
+    }
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            gain: self.gain,
+            channel: self.channel,
+            input: self.input.clone(),
+            feed: self.input.as_ref().map(Input::listen),
+        }
+    }
+}
+
+impl Render for Node {
+    fn render_additive(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
+        // a disabled node keeps taking the input, so it doesn't come back late
+        let gain = if self.enabled { self.gain } else { 0.0 };
+        if let Some(feed) = &mut self.feed {
+            feed.add_to(lbuf, rbuf, self.channel.map(usize::from), gain);
+        }
+    }
+
+    fn reset_rendering(&mut self) {}
+
+    fn set_audio_input(&mut self, input: Input) {
+        self.feed = Some(input.listen());
+        self.input = Some(input);
+    }
+
+    fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+
+    fn set_sample_rate(&mut self, _sample_rate: u32) {}
+
+    fn receive_midi_message(&mut self, _message: &midi::Message) {}
+
+    fn panic(&mut self) {}
+
+    fn set_global_transposition(&mut self, _transposition: i8) {}
+
+    fn set_json_updater(&mut self, _updater: JsonUpdater) {}
+
+    fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback) {
+        type RK = RequestKind;
+        match kind {
+            RK::SetName(name) => cb(self.set_name(&name)),
+            RK::SetEnabled(flag) => cb(self.set_enabled(flag)),
+            RK::SetGain(gain) => cb(self.set_gain(gain)),
+            RK::SetInputChannel(channel) => cb(self.set_input_channel(channel)),
+            _ => cb(JsonUpdateKind::Denied),
+        }
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        let result: serde_json::Value = json!({
+            "name": serialize(&self.name)?,
+            "enabled": serialize(self.enabled)?,
+            "gain": serialize(self.gain)?,
+            "input_channel": serialize(self.channel)?,
+        });
+        Ok(result)
+    }
+
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        deser_field_opt(source, "name", |v| self.name = v)?;
+        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
+        deser_field_opt(source, "gain", |v| self.gain = v)?;
+        deser_field_opt(source, "input_channel", |v| self.channel = v)?;
+        Ok(())
+    }
+
+    fn clone_node(&self) -> RenderPtr {
+        Box::new(self.clone())
+    }
+}
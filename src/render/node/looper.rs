@@ -0,0 +1,473 @@
+// Loops recorded from the audio input and played in the mix, with overdubs layered on top, for
+// building up a song live. The first recording closes on a whole number of beats or bars of the
+// controller's tempo: closed early it goes on recording up to the line, closed late what came
+// after the line goes over the start, so the loop stays in time either way. The node only plays
+// the loop, an audio input node is what makes the input itself heard.
+
+use super::{Render, RenderPtr};
+use crate::{
+    audio::input::{Feed, Input},
+    control::Tempo,
+    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult},
+    json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater},
+    midi,
+    path::VirtualPaths,
+    render::{add_buf_to_buf, command::ResponseCallback, node::RequestKind},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const DEFAULT_NAME: &str = "Looper";
+// A first recording this long closes by itself
+const MAX_LOOP_SECONDS: u32 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Action {
+    // Enough for a single footswitch: records, plays, overdubs, plays, overdubs...
+    Record,
+    Overdub,
+    Play,
+    // From the start with the next `Play`
+    Stop,
+    Clear,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum State {
+    #[default]
+    Empty,
+    Recording,
+    Playing,
+    Overdubbing,
+    Stopped,
+}
+
+// What the length of the first recording rounds to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Quantize {
+    Off,
+    Beat,
+    #[default]
+    Bar,
+}
+
+// A control change of 64 and up does the action, like a footswitch sends when pressed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Control {
+    pub controller: midi::ControlChangeKind,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Looper {
+    state: State,
+    // The loop, as much as there is of it while recording the first time
+    lbuf: Vec<f32>,
+    rbuf: Vec<f32>,
+    position: usize,
+    // (length, state after it) of a first recording closed before the line
+    closing: Option<(usize, State)>,
+}
+
+impl Looper {
+    // `unit` is what the first recording rounds to in frames, none for as long as it is
+    fn apply(&mut self, action: Action, unit: Option<usize>) {
+        use State::*;
+        self.state = match (self.state, action) {
+            (_, Action::Clear) => {
+                self.lbuf.clear();
+                self.rbuf.clear();
+                self.position = 0;
+                self.closing = None;
+                Empty
+            }
+            (Empty, Action::Record | Action::Overdub) => Recording,
+            (Recording, Action::Record | Action::Play) => return self.close(Playing, unit),
+            (Recording, Action::Overdub) => return self.close(Overdubbing, unit),
+            (Recording, Action::Stop) => return self.close(Stopped, unit),
+            (Playing | Stopped, Action::Record | Action::Overdub) => Overdubbing,
+            (Overdubbing | Stopped, Action::Record | Action::Overdub | Action::Play) => Playing,
+            (Playing | Overdubbing, Action::Stop) => {
+                self.position = 0;
+                Stopped
+            }
+            (state, _) => state,
+        };
+    }
+
+    fn close(&mut self, next: State, unit: Option<usize>) {
+        let recorded = self.lbuf.len();
+        let len = match unit {
+            Some(unit) if unit > 0 => ((recorded + unit / 2) / unit).max(1) * unit,
+            _ => recorded,
+        };
+        if len == 0 {
+            return self.apply(Action::Clear, None);
+        }
+        if len > recorded {
+            self.closing = Some((len, next));
+            return;
+        }
+        for buf in [&mut self.lbuf, &mut self.rbuf] {
+            let (start, late) = buf.split_at_mut(len);
+            add_buf_to_buf(start, late);
+            buf.truncate(len);
+        }
+        self.position = recorded - len;
+        self.closing = None;
+        self.state = next;
+    }
+
+    // Records the input and adds the loop to the output, whether the state changed on its own
+    fn process(
+        &mut self,
+        (input_lbuf, input_rbuf): (&[f32], &[f32]),
+        (lbuf, rbuf): (&mut [f32], &mut [f32]),
+        gain: f32,
+        max_len: usize,
+    ) -> bool {
+        let len = input_lbuf
+            .len()
+            .min(input_rbuf.len())
+            .min(lbuf.len())
+            .min(rbuf.len());
+        if self.state == State::Recording && self.lbuf.capacity() < max_len {
+            // once for every recording, the loop doesn't allocate while it grows
+            self.lbuf.reserve_exact(max_len - self.lbuf.len());
+            self.rbuf.reserve_exact(max_len - self.rbuf.len());
+        }
+        let mut changed = false;
+        for n in 0..len {
+            match self.state {
+                State::Recording => {
+                    self.lbuf.push(input_lbuf[n]);
+                    self.rbuf.push(input_rbuf[n]);
+                    let recorded = self.lbuf.len();
+                    match self.closing {
+                        Some((len, next)) if recorded >= len => self.close(next, None),
+                        None if recorded >= max_len => self.close(State::Playing, None),
+                        _ => continue,
+                    }
+                    changed = true;
+                }
+                State::Playing | State::Overdubbing => {
+                    let position = self.position;
+                    lbuf[n] += self.lbuf[position] * gain;
+                    rbuf[n] += self.rbuf[position] * gain;
+                    if self.state == State::Overdubbing {
+                        self.lbuf[position] += input_lbuf[n];
+                        self.rbuf[position] += input_rbuf[n];
+                    }
+                    self.position = (position + 1) % self.lbuf.len();
+                }
+                State::Empty | State::Stopped => break,
+            }
+        }
+        changed
+    }
+}
+
+pub struct Node {
+    name: String,
+    enabled: bool,
+    gain: f32,
+    // Recorded on both sides, none for channels 1/2 as a pair
+    channel: Option<u16>,
+    quantize: Quantize,
+    controls: Vec<Control>,
+    looper: Looper,
+    sample_rate: u32,
+    tempo: Tempo,
+    input: Option<Input>,
+    feed: Option<Feed>,
+    // The input of the buffer being rendered
+    input_lbuf: Vec<f32>,
+    input_rbuf: Vec<f32>,
+    json_updater: Option<JsonUpdater>,
+}
+
+impl Node {
+    fn set_name(&mut self, name: &str) -> JsonUpdateKind {
+        self.name = name.into();
+        update_fields_or_fail(|updates| {
+            updates.push(("name".to_owned(), serialize(name)?));
+            Ok(())
+        })
+    }
+
+    fn set_enabled(&mut self, flag: bool) -> JsonUpdateKind {
+        self.enabled = flag;
+        update_fields_or_fail(|updates| {
+            updates.push(("enabled".to_owned(), serialize(flag)?));
+            Ok(())
+        })
+    }
+
+    fn set_gain(&mut self, gain: f32) -> JsonUpdateKind {
+        self.gain = gain;
+        update_fields_or_fail(|updates| {
+            updates.push(("gain".into(), serialize(gain)?));
+            Ok(())
+        })
+    }
+
+    fn set_input_channel(&mut self, channel: Option<u16>) -> JsonUpdateKind {
+        self.channel = channel;
+        update_fields_or_fail(|updates| {
+            updates.push(("input_channel".into(), serialize(channel)?));
+            Ok(())
+        })
+    }
+
+    fn set_controls(&mut self, controls: Vec<Control>) -> JsonUpdateKind {
+        let result = update_fields_or_fail(|updates| {
+            updates.push(("loop_controls".into(), serialize(&controls)?));
+            Ok(())
+        });
+        self.controls = controls;
+        result
+    }
+
+    fn set_quantize(&mut self, quantize: Quantize) -> JsonUpdateKind {
+        self.quantize = quantize;
+        update_fields_or_fail(|updates| {
+            updates.push(("loop_quantize".into(), serialize(quantize)?));
+            Ok(())
+        })
+    }
+
+    fn apply(&mut self, action: Action) -> JsonUpdateKind {
+        self.looper.apply(action, self.unit());
+        self.state_update()
+    }
+
+    fn state_update(&self) -> JsonUpdateKind {
+        update_fields_or_fail(|updates| {
+            updates.push(("loop_state".into(), serialize(self.looper.state)?));
+            Ok(())
+        })
+    }
+
+    // For a change the clients didn't ask for
+    fn broadcast_state(&self) {
+        if let Some(updater) = &self.json_updater {
+            updater.try_broadcast(self.state_update());
+        }
+    }
+
+    // In frames
+    fn unit(&self) -> Option<usize> {
+        let beat = 60.0 / self.tempo.tempo_bpm * self.sample_rate as f32;
+        match self.quantize {
+            Quantize::Off => None,
+            Quantize::Beat => Some(beat as usize),
+            Quantize::Bar => Some((beat * self.tempo.rhythm.num_beats as f32) as usize),
+        }
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_NAME.into(),
+            enabled: true,
+            gain: 1.0,
+            channel: None,
+            quantize: Default::default(),
+            controls: Vec::new(),
+            looper: Default::default(),
+            sample_rate: 44100,
+            tempo: Default::default(),
+            input: None,
+            feed: None,
+            input_lbuf: Vec::new(),
+            input_rbuf: Vec::new(),
+            json_updater: None,
+        }
+    }
+}
+
+// Without the loop
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            enabled: self.enabled,
+            gain: self.gain,
+            channel: self.channel,
+            quantize: self.quantize,
+            controls: self.controls.clone(),
+            sample_rate: self.sample_rate,
+            tempo: self.tempo,
+            input: self.input.clone(),
+            feed: self.input.as_ref().map(Input::listen),
+            ..Default::default()
+        }
+    }
+}
+
+impl Render for Node {
+    fn render_additive(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
+        let len = lbuf.len().min(rbuf.len());
+        if self.input_lbuf.len() < len {
+            self.input_lbuf.resize(len, 0.0);
+            self.input_rbuf.resize(len, 0.0);
+        }
+        let (input_lbuf, input_rbuf) = (&mut self.input_lbuf[..len], &mut self.input_rbuf[..len]);
+        input_lbuf.fill(0.0);
+        input_rbuf.fill(0.0);
+        if let Some(feed) = &mut self.feed {
+            feed.add_to(input_lbuf, input_rbuf, self.channel.map(usize::from), 1.0);
+        }
+        // a disabled node keeps looping, so it comes back in time
+        let gain = if self.enabled { self.gain } else { 0.0 };
+        let max_len = (MAX_LOOP_SECONDS * self.sample_rate) as usize;
+        let input = (&*input_lbuf, &*input_rbuf);
+        if self.looper.process(input, (lbuf, rbuf), gain, max_len) {
+            self.broadcast_state();
+        }
+    }
+
+    fn reset_rendering(&mut self) {
+        self.looper.apply(Action::Clear, None);
+    }
+
+    fn set_audio_input(&mut self, input: Input) {
+        self.feed = Some(input.listen());
+        self.input = Some(input);
+    }
+
+    fn set_tempo(&mut self, tempo: Tempo) {
+        self.tempo = tempo;
+    }
+
+    fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn receive_midi_message(&mut self, message: &midi::Message) {
+        let midi::MessageKind::ControlChange { kind, value } = message.kind else {
+            return;
+        };
+        if value < 64 {
+            return;
+        }
+        let (state, unit) = (self.looper.state, self.unit());
+        for control in &self.controls {
+            if control.controller == kind {
+                self.looper.apply(control.action, unit);
+            }
+        }
+        if self.looper.state != state {
+            self.broadcast_state();
+        }
+    }
+
+    // The loop stops, it's kept
+    fn panic(&mut self) {
+        if self.looper.state != State::Empty {
+            self.looper.apply(Action::Stop, self.unit());
+            self.broadcast_state();
+        }
+    }
+
+    fn set_global_transposition(&mut self, _transposition: i8) {}
+
+    fn set_json_updater(&mut self, updater: JsonUpdater) {
+        self.json_updater = Some(updater);
+    }
+
+    fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback) {
+        type RK = RequestKind;
+        match kind {
+            RK::SetName(name) => cb(self.set_name(&name)),
+            RK::SetEnabled(flag) => cb(self.set_enabled(flag)),
+            RK::SetGain(gain) => cb(self.set_gain(gain)),
+            RK::SetInputChannel(channel) => cb(self.set_input_channel(channel)),
+            RK::Loop(action) => cb(self.apply(action)),
+            RK::SetLoopControls(controls) => cb(self.set_controls(controls)),
+            RK::SetLoopQuantize(quantize) => cb(self.set_quantize(quantize)),
+            _ => cb(JsonUpdateKind::Denied),
+        }
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        let result: serde_json::Value = json!({
+            "name": serialize(&self.name)?,
+            "enabled": serialize(self.enabled)?,
+            "gain": serialize(self.gain)?,
+            "input_channel": serialize(self.channel)?,
+            "loop_quantize": serialize(self.quantize)?,
+            "loop_controls": serialize(&self.controls)?,
+            "loop_state": serialize(self.looper.state)?,
+        });
+        Ok(result)
+    }
+
+    // The state comes from the loop, not the settings
+    fn deserialize(&mut self, source: &serde_json::Value) -> DeserializationResult {
+        deser_field_opt(source, "name", |v| self.name = v)?;
+        deser_field_opt(source, "enabled", |v| self.enabled = v)?;
+        deser_field_opt(source, "gain", |v| self.gain = v)?;
+        deser_field_opt(source, "input_channel", |v| self.channel = v)?;
+        deser_field_opt(source, "loop_quantize", |v| self.quantize = v)?;
+        deser_field_opt(source, "loop_controls", |v| self.controls = v)?;
+        Ok(())
+    }
+
+    fn clone_node(&self) -> RenderPtr {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Looper, State};
+
+    // Of the loop, frame by frame
+    fn play(looper: &mut Looper, input: &[f32]) -> Vec<f32> {
+        let (mut lbuf, mut rbuf) = (vec![0.0; input.len()], vec![0.0; input.len()]);
+        looper.process((input, input), (&mut lbuf, &mut rbuf), 1.0, 100);
+        lbuf
+    }
+
+    #[test]
+    fn closes_on_the_line() {
+        let mut looper = Looper::default();
+        looper.apply(Action::Record, Some(4));
+        play(&mut looper, &[1.0, 2.0, 3.0]);
+        // early, it goes on up to 4 frames
+        looper.apply(Action::Record, Some(4));
+        assert_eq!(looper.state, State::Recording);
+        assert_eq!(play(&mut looper, &[4.0, 0.0, 0.0]), [0.0, 1.0, 2.0]);
+        assert_eq!(looper.state, State::Playing);
+
+        looper.apply(Action::Clear, None);
+        looper.apply(Action::Record, Some(4));
+        play(&mut looper, &[1.0, 2.0, 3.0, 4.0, 5.0]);
+        // late, the fifth frame goes over the first one
+        looper.apply(Action::Play, Some(4));
+        assert_eq!(looper.state, State::Playing);
+        assert_eq!(play(&mut looper, &[0.0; 5]), [2.0, 3.0, 4.0, 6.0, 2.0]);
+    }
+
+    #[test]
+    fn overdubs() {
+        let mut looper = Looper::default();
+        looper.apply(Action::Record, None);
+        play(&mut looper, &[1.0, 2.0]);
+        looper.apply(Action::Overdub, None);
+        assert_eq!(looper.state, State::Overdubbing);
+        assert_eq!(play(&mut looper, &[1.0, 1.0, 1.0]), [1.0, 2.0, 2.0]);
+        // played, the input doesn't get in
+        looper.apply(Action::Record, None);
+        assert_eq!(play(&mut looper, &[5.0, 5.0]), [3.0, 3.0]);
+
+        looper.apply(Action::Stop, None);
+        assert_eq!(play(&mut looper, &[1.0]), [0.0]);
+        looper.apply(Action::Play, None);
+        assert_eq!(play(&mut looper, &[0.0, 0.0, 0.0]), [3.0, 3.0, 3.0]);
+    }
+}
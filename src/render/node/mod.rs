@@ -1,20 +1,41 @@
 use super::{
     command::{midi_filter::UpdateMidiFilterKind, ResponseCallback},
-    velocity_map,
+    soundfonts::SoundfontCache,
+    velocity_map, voices,
 };
 use crate::{
-    deser::{DeserializationResult, SerializationResult}, json::JsonUpdater, midi, path::VirtualPaths
+    audio::input::Input,
+    control::Tempo,
+    deser::{DeserializationResult, SerializationResult}, json::JsonUpdater, midi, path::VirtualPaths, synth::tuning
 };
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+pub mod audio_input;
 pub mod fluidlite_synth;
+pub mod looper;
 pub mod oxi_synth;
 pub mod rusty_synth;
 pub mod sfizz_synth;
 
 pub const NUM_USER_PRESETS: usize = 16;
 
+// What a panic sends straight to a synth, past its MIDI filter: pedals up, then every note and
+// every sound off
+pub const PANIC_CONTROLLERS: [midi::ControlChangeKind; 4] = [
+    midi::ControlChangeKind::DamperPedal,
+    midi::ControlChangeKind::Sostenuto,
+    midi::ControlChangeKind::AllNotesOff,
+    midi::ControlChangeKind::AllSoundsOff,
+];
+
+// What letting go of a node sends, the notes stop as if the keys came up
+pub const RELEASE_CONTROLLERS: [midi::ControlChangeKind; 3] = [
+    midi::ControlChangeKind::DamperPedal,
+    midi::ControlChangeKind::Sostenuto,
+    midi::ControlChangeKind::AllNotesOff,
+];
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum RequestKind {
     SetName(String),
@@ -42,14 +63,41 @@ pub enum RequestKind {
     UpdateMidiFilter(UpdateMidiFilterKind),
     SetUserPreset(usize),
     SetUserPresetEnabled(usize, bool),
+    // Frames of every sample kept in memory, the rest streams from disk while playing, none
+    // keeps the whole samples. Only for nodes playing samples from disk.
+    SetPreloadSize(Option<u32>),
+    // Scala files to play in, none for 12-TET. sfizz tunes every note, the soundfont synths bend
+    // the note played last.
+    SetTuning(Option<tuning::Files>),
+    // Notes sounding at once, none for as many as the synth plays. With a limit the node holds
+    // the damper pedal itself, so the notes it sustains can be stolen too.
+    SetPolyphony(Option<u16>),
+    SetVoiceStealing(voices::Stealing),
+    // Only for the audio input node, the channel to play on both sides, none for channels 1/2
+    SetInputChannel(Option<u16>),
+    // Only for the looper node
+    Loop(looper::Action),
+    SetLoopControls(Vec<looper::Control>),
+    SetLoopQuantize(looper::Quantize),
 }
 
 pub trait Render: Sync + Send {
     fn render_additive(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]);
     fn reset_rendering(&mut self);
+    // Only for the nodes playing soundfonts with RustySynth
+    fn set_soundfont_cache(&mut self, _cache: SoundfontCache) {}
+    // Only for the nodes playing the live input
+    fn set_audio_input(&mut self, _input: Input) {}
+    // Only for the nodes playing in time, the tempo of the controller
+    fn set_tempo(&mut self, _tempo: Tempo) {}
     fn set_virtual_paths(&mut self, vp: VirtualPaths);
     fn set_sample_rate(&mut self, sample_rate: u32);
     fn receive_midi_message(&mut self, message: &midi::Message);
+    // Silences stuck notes, whatever the MIDI filter lets through
+    fn panic(&mut self);
+    // Only for the nodes playing notes, lets go of them past the MIDI filter before the node is
+    // removed or replaced
+    fn release(&mut self) {}
     fn set_global_transposition(&mut self, transposition: i8);
     fn set_json_updater(&mut self, updater: JsonUpdater);
     fn process_request(&mut self, kind: RequestKind, cb: ResponseCallback);
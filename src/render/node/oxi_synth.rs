@@ -1,13 +1,18 @@
-use super::Render;
+use super::{Render, PANIC_CONTROLLERS, RELEASE_CONTROLLERS};
 use crate::{
-    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult}, json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater}, midi::{self, ControlChangeKind}, path::VirtualPaths, render::{
+    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult}, files::FileError, json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater}, midi::{self, ControlChangeKind}, path::VirtualPaths, render::{
         self,
         command::{midi_filter::UpdateMidiFilterKind, ResponseCallback},
         midi_filter::{self, MidiFilterUser},
         node::RequestKind,
+        pedals::Pedals,
+        per_note::Expression,
         preset_map::{Preset, PresetMap},
+        retuning::Retuning,
+        soundfonts::SoundfontCache,
         velocity_map,
-    }
+        voices::{Stealing, Voices},
+    }, synth::tuning::{self, Tuning}
 };
 use oxisynth::{SoundFont, Synth};
 use serde::{Deserialize, Serialize};
@@ -63,9 +68,14 @@ pub struct Node {
     name: String,
     enabled: bool,
     midi_filter: midi_filter::MidiFilter,
+    // The synth only knows the damper pedal, sostenuto and soft pedal are emulated
+    pedals: Pedals,
+    voices: Voices,
+    expression: Expression,
     synth: Option<Synth>,
     last_file: Option<PathBuf>,
     last_virtual_paths: Option<VirtualPaths>,
+    soundfonts: SoundfontCache,
     last_sample_rate: Option<u32>,
     last_bank: Option<u16>,
     last_preset: Option<u8>,
@@ -77,6 +87,8 @@ pub struct Node {
     global_transposition: i8,
     velocity_mapping: velocity_map::Kind,
     ignore_global_transposition: bool,
+    tuning: Option<tuning::Files>,
+    retuning: Option<Retuning>,
     tmp_lbuf: Vec<f32>,
     tmp_rbuf: Vec<f32>,
     user_presets: Vec<bool>,
@@ -112,6 +124,52 @@ impl Node {
         }
     }
 
+    // The synth only bends the note played last, see `Retuning`
+    fn set_tuning(&mut self, files: Option<tuning::Files>) -> JsonUpdateKind {
+        let Ok(tuning) = self.load_tuning(files.as_ref()) else {
+            return JsonUpdateKind::Failed;
+        };
+        // back in 12-TET the pitch wheel is where it's played
+        if let (None, Some(retuning)) = (&tuning, &self.retuning) {
+            self.send_pitch_wheel(retuning.played_pitch_wheel());
+        }
+        self.tuning = files;
+        self.retuning = tuning.map(Retuning::new);
+        update_fields_or_fail(|updates| {
+            updates.push(("tuning".into(), serialize(&self.tuning)?));
+            Ok(())
+        })
+    }
+
+    fn load_tuning(&self, files: Option<&tuning::Files>) -> Result<Option<Tuning>, FileError> {
+        match (files, &self.last_virtual_paths) {
+            (None, _) => Ok(None),
+            (Some(files), Some(vp)) => Tuning::load(vp, files).map(Some),
+            (Some(_), None) => Err(FileError::InvalidPath),
+        }
+    }
+
+    fn set_polyphony(&mut self, limit: Option<u16>) -> JsonUpdateKind {
+        let Some(released) = self.voices.set_limit(limit) else {
+            return JsonUpdateKind::Failed;
+        };
+        for note in released {
+            self.note_off(note);
+        }
+        update_fields_or_fail(|updates| {
+            updates.push(("polyphony".into(), serialize(self.voices.limit())?));
+            Ok(())
+        })
+    }
+
+    fn set_voice_stealing(&mut self, stealing: Stealing) -> JsonUpdateKind {
+        self.voices.set_stealing(stealing);
+        update_fields_or_fail(|updates| {
+            updates.push(("voice_stealing".into(), serialize(stealing)?));
+            Ok(())
+        })
+    }
+
     fn set_gain(&mut self, gain: f32) -> JsonUpdateKind {
         self.gain = gain;
         update_fields_or_fail(|updates| {
@@ -245,9 +303,22 @@ impl Node {
 
     fn process_midi_message_kind(&mut self, kind: &midi::MessageKind) {
         use midi::MessageKind as Kind;
+        // per-note expression goes to the whole channel
+        if let Some(kind) = self.expression.follow(kind) {
+            self.process_midi_message_kind(&kind);
+        }
         match *kind {
-            Kind::NoteOn { note, velocity } => self.note_on(note, velocity),
-            Kind::NoteOff { note, .. } => self.note_off(note),
+            Kind::NoteOn { note, velocity } => {
+                if let Some(velocity) = self.pedals.note_on(note, velocity) {
+                    self.note_on(note, velocity);
+                }
+            }
+            // oxisynth note off events have no velocity
+            Kind::NoteOff { note, .. } => {
+                if self.pedals.note_off(note) {
+                    self.note_off(note);
+                }
+            }
             Kind::PolyphonicAftertouch { note, pressure } => {
                 self.polyphonic_aftertouch(note, pressure);
             }
@@ -255,11 +326,30 @@ impl Node {
             Kind::ProgramChange { program } => self.program_change(program),
             Kind::ChannelAftertouch { pressure } => self.channel_aftertouch(pressure),
             Kind::PitchWheel { value } => self.pitch_wheel(value),
+            Kind::PerNoteController { .. } => {}
+            Kind::PerNotePitchWheel { .. } => {}
+            Kind::RegisteredParameter { .. } => {}
+            Kind::NonRegisteredParameter { .. } => {}
+            Kind::SystemExclusive { .. } => {}
         }
     }
 
     fn note_on(&mut self, note: u8, velocity: u8) {
-        let note = self.transpose_note(note);
+        if velocity == 0 {
+            self.note_off(note);
+            return;
+        }
+        if let Some(stolen) = self.voices.note_on(note, velocity) {
+            self.note_off(stolen);
+        }
+        let mut note = self.transpose_note(note);
+        if let Some(retuning) = &mut self.retuning {
+            let Some((key, pitch_wheel)) = retuning.note_on(note) else {
+                return;
+            };
+            note = key;
+            self.send_pitch_wheel(pitch_wheel);
+        }
         if let Some(synth) = &mut self.synth {
             _ = synth.send_event(oxisynth::MidiEvent::NoteOn {
                 channel: 0,
@@ -270,7 +360,13 @@ impl Node {
     }
 
     fn note_off(&mut self, note: u8) {
-        let note = self.transpose_note(note);
+        if !self.voices.note_off(note) {
+            return;
+        }
+        let mut note = self.transpose_note(note);
+        if let Some(retuning) = &mut self.retuning {
+            note = retuning.note_off(note);
+        }
         if let Some(synth) = &mut self.synth {
             _ = synth.send_event(oxisynth::MidiEvent::NoteOff {
                 channel: 0,
@@ -290,6 +386,31 @@ impl Node {
     }
 
     fn control_change(&mut self, kind: ControlChangeKind, value: u8) {
+        match kind {
+            ControlChangeKind::DamperPedal if self.voices.holds_damper() => {
+                for note in self.voices.set_damper(value) {
+                    self.note_off(note);
+                }
+                return;
+            }
+            ControlChangeKind::Sostenuto => {
+                for note in self.pedals.set_sostenuto(value) {
+                    self.note_off(note);
+                }
+                return;
+            }
+            ControlChangeKind::SoftPedal => {
+                self.pedals.set_soft(value);
+                return;
+            }
+            ControlChangeKind::AllNotesOff
+            | ControlChangeKind::AllSoundsOff
+            | ControlChangeKind::ResetAllControllers => {
+                self.pedals.reset();
+                self.voices.reset();
+            }
+            _ => {}
+        }
         self.last_cc.insert(kind.as_number(), value);
         if let Some(synth) = &mut self.synth {
             _ = synth.send_event(oxisynth::MidiEvent::ControlChange {
@@ -319,6 +440,14 @@ impl Node {
     }
 
     fn pitch_wheel(&mut self, value: u16) {
+        let value = match &mut self.retuning {
+            Some(retuning) => retuning.pitch_wheel(value),
+            None => value,
+        };
+        self.send_pitch_wheel(value);
+    }
+
+    fn send_pitch_wheel(&mut self, value: u16) {
         self.last_pitch_wheel = value;
         if let Some(synth) = &mut self.synth {
             _ = synth.send_event(oxisynth::MidiEvent::PitchBend { channel: 0, value });
@@ -334,18 +463,14 @@ impl Node {
                 let reverb = self.reverb;
                 let last_cc = self.last_cc.clone();
                 let last_pitch_wheel = self.last_pitch_wheel;
+                let soundfonts = self.soundfonts.clone();
                 self.sf_load_handle = Some(thread::spawn(
                     move || -> Result<SoundFontLoadRes, String> {
                         let font = SoundFont::load(
                             &mut File::open(file.clone()).map_err(|e| e.to_string())?,
                         )
                         .map_err(|_| String::from("Failed to parse SoundFont file"))?;
-                        let preset_map = get_preset_map(
-                            &rustysynth::SoundFont::new(
-                                &mut File::open(file).map_err(|e| e.to_string())?,
-                            )
-                            .map_err(|e| e.to_string())?,
-                        );
+                        let preset_map = get_preset_map(&*soundfonts.load(&file)?);
 
                         if let (Some(bank), Some(preset)) = (last_bank, last_preset) {
                             if preset_map.has_preset(bank, preset) {
@@ -542,9 +667,13 @@ impl Default for Node {
             name: DEFAULT_NAME.into(),
             enabled: true,
             midi_filter: Default::default(),
+            pedals: Default::default(),
+            voices: Default::default(),
+            expression: Default::default(),
             synth: None,
             last_file: None,
             last_virtual_paths: None,
+            soundfonts: Default::default(),
             last_sample_rate: None,
             last_bank: None,
             last_preset: None,
@@ -556,6 +685,8 @@ impl Default for Node {
             global_transposition: 0,
             velocity_mapping: velocity_map::Kind::Identity,
             ignore_global_transposition: false,
+            tuning: None,
+            retuning: None,
             tmp_lbuf: vec![],
             tmp_rbuf: vec![],
             user_presets: vec![true; super::NUM_USER_PRESETS],
@@ -573,9 +704,13 @@ impl Clone for Node {
             name: self.name.clone(),
             enabled: self.enabled,
             midi_filter: self.midi_filter.clone(),
+            pedals: Default::default(),
+            voices: self.voices.clone(),
+            expression: Default::default(),
             synth: None,
             last_file: self.last_file.clone(),
             last_virtual_paths: self.last_virtual_paths.clone(),
+            soundfonts: self.soundfonts.clone(),
             last_sample_rate: self.last_sample_rate,
             last_bank: self.last_bank,
             last_preset: self.last_preset,
@@ -587,6 +722,8 @@ impl Clone for Node {
             global_transposition: self.global_transposition,
             velocity_mapping: self.velocity_mapping,
             ignore_global_transposition: self.ignore_global_transposition,
+            tuning: self.tuning.clone(),
+            retuning: self.retuning.clone(),
             tmp_lbuf: vec![0.0; self.tmp_lbuf.len()],
             tmp_rbuf: vec![0.0; self.tmp_rbuf.len()],
             user_presets: self.user_presets.clone(),
@@ -610,10 +747,8 @@ impl Render for Node {
         if let Some(synth) = &mut self.synth {
             synth.write_f32(len, tmp_lbuf, 0, 1, tmp_rbuf, 0, 1);
         }
-        render::amplify_buffer(tmp_lbuf, self.gain);
-        render::amplify_buffer(tmp_rbuf, self.gain);
-        render::add_buf_to_buf(lbuf, tmp_lbuf);
-        render::add_buf_to_buf(rbuf, tmp_rbuf);
+        render::add_amplified_buf_to_buf(lbuf, tmp_lbuf, self.gain);
+        render::add_amplified_buf_to_buf(rbuf, tmp_rbuf, self.gain);
     }
 
     fn reset_rendering(&mut self) {
@@ -622,8 +757,17 @@ impl Render for Node {
         }
     }
 
+    fn set_soundfont_cache(&mut self, cache: SoundfontCache) {
+        self.soundfonts = cache;
+    }
+
     fn set_virtual_paths(&mut self, vp: VirtualPaths) {
         self.last_virtual_paths = Some(vp);
+        // a tuning deserialized before the paths were known
+        if self.retuning.is_none() {
+            let tuning = self.load_tuning(self.tuning.as_ref()).ok().flatten();
+            self.retuning = tuning.map(Retuning::new);
+        }
     }
 
     fn set_sample_rate(&mut self, sample_rate: u32) {
@@ -639,6 +783,18 @@ impl Render for Node {
         }
     }
 
+    fn panic(&mut self) {
+        for kind in PANIC_CONTROLLERS {
+            self.control_change(kind, 0);
+        }
+    }
+
+    fn release(&mut self) {
+        for kind in RELEASE_CONTROLLERS {
+            self.control_change(kind, 0);
+        }
+    }
+
     fn set_global_transposition(&mut self, transposition: i8) {
         self.global_transposition = transposition;
     }
@@ -679,6 +835,9 @@ impl Render for Node {
             RK::UpdateMidiFilter(kind) => cb(self.update_midi_filter(kind)),
             RK::SetUserPreset(preset) => cb(self.set_user_preset(preset)),
             RK::SetUserPresetEnabled(p, f) => cb(self.set_user_preset_enabled(p, f)),
+            RK::SetTuning(files) => cb(self.set_tuning(files)),
+            RK::SetPolyphony(limit) => cb(self.set_polyphony(limit)),
+            RK::SetVoiceStealing(stealing) => cb(self.set_voice_stealing(stealing)),
             _ => cb(JsonUpdateKind::Denied),
         };
     }
@@ -700,6 +859,9 @@ impl Render for Node {
             "cc": serialize(self.last_cc.clone())?,
             "pitch_wheel": serialize(self.last_pitch_wheel)?,
             "user_presets": serialize(&self.user_presets)?,
+            "tuning": serialize(&self.tuning)?,
+            "polyphony": serialize(self.voices.limit())?,
+            "voice_stealing": serialize(self.voices.stealing())?,
             "reverb": serialize(self.reverb)?,
         });
         Ok(result)
@@ -723,6 +885,11 @@ impl Render for Node {
         deser_field_opt(source, "cc", |v| self.last_cc = v)?;
         deser_field_opt(source, "pitch_wheel", |v| self.last_pitch_wheel = v)?;
         deser_field_opt(source, "user_presets", |v| self.user_presets = v)?;
+        deser_field_opt(source, "tuning", |v| self.tuning = v)?;
+        let tuning = self.load_tuning(self.tuning.as_ref()).ok().flatten();
+        self.retuning = tuning.map(Retuning::new);
+        deser_field_opt(source, "polyphony", |v| _ = self.voices.set_limit(v))?;
+        deser_field_opt(source, "voice_stealing", |v| self.voices.set_stealing(v))?;
         deser_field_opt(source, "reverb", |v| self.reverb = v)?;
         Ok(())
     }
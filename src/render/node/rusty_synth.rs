@@ -1,22 +1,25 @@
-use super::Render;
+use super::{Render, PANIC_CONTROLLERS, RELEASE_CONTROLLERS};
 use crate::{
-    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult}, json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater}, midi::{self, ControlChangeKind}, path::VirtualPaths, render::{
+    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult}, files::FileError, json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater}, midi::{self, ControlChangeKind}, path::VirtualPaths, render::{
         self,
         command::{midi_filter::UpdateMidiFilterKind, ResponseCallback},
         midi_filter::{self, MidiFilterUser},
         node::RequestKind,
+        pedals::Pedals,
+        per_note::Expression,
         preset_map::{Preset, PresetMap},
+        retuning::Retuning,
+        soundfonts::SoundfontCache,
         velocity_map,
-    }
+        voices::{Stealing, Voices},
+    }, synth::tuning::{self, Tuning}
 };
 use rustysynth::{SoundFont, Synthesizer, SynthesizerSettings};
 use serde_json::json;
 use std::{
     fmt::Display,
-    fs::File,
     mem,
     path::{Path, PathBuf},
-    sync::Arc,
     thread::{self, JoinHandle},
 };
 
@@ -40,9 +43,14 @@ pub struct Node {
     name: String,
     enabled: bool,
     midi_filter: midi_filter::MidiFilter,
+    // The synth only knows the damper pedal, sostenuto and soft pedal are emulated
+    pedals: Pedals,
+    voices: Voices,
+    expression: Expression,
     synth: Option<Synthesizer>,
     last_file: Option<PathBuf>,
     last_virtual_paths: Option<VirtualPaths>,
+    soundfonts: SoundfontCache,
     last_sample_rate: Option<u32>,
     last_bank: Option<u16>,
     last_preset: Option<u8>,
@@ -52,6 +60,8 @@ pub struct Node {
     global_transposition: i8,
     velocity_mapping: velocity_map::Kind,
     ignore_global_transposition: bool,
+    tuning: Option<tuning::Files>,
+    retuning: Option<Retuning>,
     tmp_lbuf: Vec<f32>,
     tmp_rbuf: Vec<f32>,
     user_presets: Vec<bool>,
@@ -86,6 +96,52 @@ impl Node {
         }
     }
 
+    // The synth only bends the note played last, see `Retuning`
+    fn set_tuning(&mut self, files: Option<tuning::Files>) -> JsonUpdateKind {
+        let Ok(tuning) = self.load_tuning(files.as_ref()) else {
+            return JsonUpdateKind::Failed;
+        };
+        // back in 12-TET the pitch wheel is where it's played
+        if let (None, Some(retuning)) = (&tuning, &self.retuning) {
+            self.send_pitch_wheel(retuning.played_pitch_wheel());
+        }
+        self.tuning = files;
+        self.retuning = tuning.map(Retuning::new);
+        update_fields_or_fail(|updates| {
+            updates.push(("tuning".into(), serialize(&self.tuning)?));
+            Ok(())
+        })
+    }
+
+    fn load_tuning(&self, files: Option<&tuning::Files>) -> Result<Option<Tuning>, FileError> {
+        match (files, &self.last_virtual_paths) {
+            (None, _) => Ok(None),
+            (Some(files), Some(vp)) => Tuning::load(vp, files).map(Some),
+            (Some(_), None) => Err(FileError::InvalidPath),
+        }
+    }
+
+    fn set_polyphony(&mut self, limit: Option<u16>) -> JsonUpdateKind {
+        let Some(released) = self.voices.set_limit(limit) else {
+            return JsonUpdateKind::Failed;
+        };
+        for note in released {
+            self.note_off(note);
+        }
+        update_fields_or_fail(|updates| {
+            updates.push(("polyphony".into(), serialize(self.voices.limit())?));
+            Ok(())
+        })
+    }
+
+    fn set_voice_stealing(&mut self, stealing: Stealing) -> JsonUpdateKind {
+        self.voices.set_stealing(stealing);
+        update_fields_or_fail(|updates| {
+            updates.push(("voice_stealing".into(), serialize(stealing)?));
+            Ok(())
+        })
+    }
+
     fn set_gain(&mut self, gain: f32) -> JsonUpdateKind {
         self.gain = gain;
         update_fields_or_fail(|updates| {
@@ -171,38 +227,109 @@ impl Node {
 
     fn process_midi_message(&mut self, message: &midi::Message) {
         use midi::MessageKind as Kind;
+        // per-note expression goes to the whole channel
+        if let Some(kind) = self.expression.follow(&message.kind) {
+            self.process_midi_message(&midi::Message::new(message.channel, kind));
+        }
         match message.kind {
-            Kind::NoteOn { note, velocity } => self.note_on(note, velocity),
-            Kind::NoteOff { note, .. } => self.note_off(note),
+            Kind::NoteOn { note, velocity } => {
+                if let Some(velocity) = self.pedals.note_on(note, velocity) {
+                    self.note_on(note, velocity);
+                }
+            }
+            // rustysynth has no release velocity
+            Kind::NoteOff { note, .. } => {
+                if self.pedals.note_off(note) {
+                    self.note_off(note);
+                }
+            }
             Kind::PolyphonicAftertouch { .. } => {}
             Kind::ControlChange { kind, value } => self.control_change(kind, value),
             Kind::ProgramChange { .. } => {}
             Kind::ChannelAftertouch { .. } => {}
             Kind::PitchWheel { value } => self.pitch_wheel(value),
+            Kind::PerNoteController { .. } => {}
+            Kind::PerNotePitchWheel { .. } => {}
+            Kind::RegisteredParameter { .. } => {}
+            Kind::NonRegisteredParameter { .. } => {}
+            Kind::SystemExclusive { .. } => {}
         }
     }
 
     fn note_on(&mut self, note: u8, velocity: u8) {
-        let note = self.transpose_note(note);
+        if velocity == 0 {
+            self.note_off(note);
+            return;
+        }
+        if let Some(stolen) = self.voices.note_on(note, velocity) {
+            self.note_off(stolen);
+        }
+        let mut note = self.transpose_note(note);
+        if let Some(retuning) = &mut self.retuning {
+            let Some((key, pitch_wheel)) = retuning.note_on(note) else {
+                return;
+            };
+            note = key;
+            self.send_pitch_wheel(pitch_wheel);
+        }
         if let Some(s) = self.synth.as_mut() {
             s.note_on(0, note as i32, velocity as i32)
         }
     }
 
     fn note_off(&mut self, note: u8) {
-        let note = self.transpose_note(note);
+        if !self.voices.note_off(note) {
+            return;
+        }
+        let mut note = self.transpose_note(note);
+        if let Some(retuning) = &mut self.retuning {
+            note = retuning.note_off(note);
+        }
         if let Some(s) = self.synth.as_mut() {
             s.note_off(0, note as i32)
         }
     }
 
     fn control_change(&mut self, kind: ControlChangeKind, value: u8) {
+        match kind {
+            ControlChangeKind::DamperPedal if self.voices.holds_damper() => {
+                for note in self.voices.set_damper(value) {
+                    self.note_off(note);
+                }
+                return;
+            }
+            ControlChangeKind::Sostenuto => {
+                for note in self.pedals.set_sostenuto(value) {
+                    self.note_off(note);
+                }
+                return;
+            }
+            ControlChangeKind::SoftPedal => {
+                self.pedals.set_soft(value);
+                return;
+            }
+            ControlChangeKind::AllNotesOff
+            | ControlChangeKind::AllSoundsOff
+            | ControlChangeKind::ResetAllControllers => {
+                self.pedals.reset();
+                self.voices.reset();
+            }
+            _ => {}
+        }
         if let Some(s) = self.synth.as_mut() {
             s.process_midi_message(0, 0xB0, kind.as_number() as i32, value as i32)
         }
     }
 
     fn pitch_wheel(&mut self, value: u16) {
+        let value = match &mut self.retuning {
+            Some(retuning) => retuning.pitch_wheel(value),
+            None => value,
+        };
+        self.send_pitch_wheel(value);
+    }
+
+    fn send_pitch_wheel(&mut self, value: u16) {
         let data1 = (value & 0x7F) | 0x80;
         let data2 = (value >> 7) & 0x7F;
         if let Some(s) = self.synth.as_mut() {
@@ -220,11 +347,10 @@ impl Node {
                 let mut last_bank = self.last_bank;
                 let mut last_preset = self.last_preset;
                 let block_size = self.tmp_lbuf.len();
+                let soundfonts = self.soundfonts.clone();
                 self.synth_init_handle =
                     Some(thread::spawn(move || -> Result<SynthInitRes, String> {
-                        let mut sf2 = File::open(file).map_err(|e| e.to_string())?;
-                        let sound_font =
-                            Arc::new(SoundFont::new(&mut sf2).map_err(|e| e.to_string())?);
+                        let sound_font = soundfonts.load(&file)?;
                         let preset_map = get_preset_map(&sound_font);
                         let mut settings = SynthesizerSettings::new(sample_rate as i32);
                         settings.block_size = block_size;
@@ -392,9 +518,13 @@ impl Default for Node {
             name: DEFAULT_NAME.into(),
             enabled: true,
             midi_filter: Default::default(),
+            pedals: Default::default(),
+            voices: Default::default(),
+            expression: Default::default(),
             synth: None,
             last_file: None,
             last_virtual_paths: None,
+            soundfonts: Default::default(),
             last_sample_rate: None,
             last_bank: None,
             last_preset: None,
@@ -404,6 +534,8 @@ impl Default for Node {
             global_transposition: 0,
             velocity_mapping: velocity_map::Kind::Identity,
             ignore_global_transposition: false,
+            tuning: None,
+            retuning: None,
             tmp_lbuf: vec![],
             tmp_rbuf: vec![],
             user_presets: vec![true; super::NUM_USER_PRESETS],
@@ -420,9 +552,13 @@ impl Clone for Node {
             name: self.name.clone(),
             enabled: self.enabled,
             midi_filter: self.midi_filter.clone(),
+            pedals: Default::default(),
+            voices: self.voices.clone(),
+            expression: Default::default(),
             synth: None,
             last_file: self.last_file.clone(),
             last_virtual_paths: self.last_virtual_paths.clone(),
+            soundfonts: self.soundfonts.clone(),
             last_sample_rate: self.last_sample_rate,
             last_bank: self.last_bank,
             last_preset: self.last_preset,
@@ -432,6 +568,8 @@ impl Clone for Node {
             global_transposition: self.global_transposition,
             velocity_mapping: self.velocity_mapping,
             ignore_global_transposition: self.ignore_global_transposition,
+            tuning: self.tuning.clone(),
+            retuning: self.retuning.clone(),
             tmp_lbuf: vec![0.0; self.tmp_lbuf.len()],
             tmp_rbuf: vec![0.0; self.tmp_rbuf.len()],
             user_presets: self.user_presets.clone(),
@@ -462,10 +600,8 @@ impl Render for Node {
             // if self.last_timestamp % 100 == 0 {
             //     tracing::trace!("{:?}", duration);
             // }
-            render::amplify_buffer(tmp_lbuf, self.gain);
-            render::amplify_buffer(tmp_rbuf, self.gain);
-            render::add_buf_to_buf(lbuf, tmp_lbuf);
-            render::add_buf_to_buf(rbuf, tmp_rbuf);
+            render::add_amplified_buf_to_buf(lbuf, tmp_lbuf, self.gain);
+            render::add_amplified_buf_to_buf(rbuf, tmp_rbuf, self.gain);
         }
     }
 
@@ -475,8 +611,17 @@ impl Render for Node {
         }
     }
 
+    fn set_soundfont_cache(&mut self, cache: SoundfontCache) {
+        self.soundfonts = cache;
+    }
+
     fn set_virtual_paths(&mut self, vp: VirtualPaths) {
         self.last_virtual_paths = Some(vp);
+        // a tuning deserialized before the paths were known
+        if self.retuning.is_none() {
+            let tuning = self.load_tuning(self.tuning.as_ref()).ok().flatten();
+            self.retuning = tuning.map(Retuning::new);
+        }
     }
 
     fn set_sample_rate(&mut self, sample_rate: u32) {
@@ -490,6 +635,18 @@ impl Render for Node {
         }
     }
 
+    fn panic(&mut self) {
+        for kind in PANIC_CONTROLLERS {
+            self.control_change(kind, 0);
+        }
+    }
+
+    fn release(&mut self) {
+        for kind in RELEASE_CONTROLLERS {
+            self.control_change(kind, 0);
+        }
+    }
+
     fn set_global_transposition(&mut self, transposition: i8) {
         self.global_transposition = transposition;
     }
@@ -514,6 +671,9 @@ impl Render for Node {
             RK::UpdateMidiFilter(kind) => cb(self.update_midi_filter(kind)),
             RK::SetUserPreset(preset) => cb(self.set_user_preset(preset)),
             RK::SetUserPresetEnabled(p, f) => cb(self.set_user_preset_enabled(p, f)),
+            RK::SetTuning(files) => cb(self.set_tuning(files)),
+            RK::SetPolyphony(limit) => cb(self.set_polyphony(limit)),
+            RK::SetVoiceStealing(stealing) => cb(self.set_voice_stealing(stealing)),
             _ => cb(JsonUpdateKind::Denied),
         };
     }
@@ -533,6 +693,9 @@ impl Render for Node {
             "bank": serialize(self.last_bank)?,
             "preset": serialize(self.last_preset)?,
             "user_presets": serialize(&self.user_presets)?,
+            "tuning": serialize(&self.tuning)?,
+            "polyphony": serialize(self.voices.limit())?,
+            "voice_stealing": serialize(self.voices.stealing())?,
         });
         Ok(result)
     }
@@ -553,6 +716,11 @@ impl Render for Node {
         deser_field_opt(source, "bank", |v| self.last_bank = v)?;
         deser_field_opt(source, "preset", |v| self.last_preset = v)?;
         deser_field_opt(source, "user_presets", |v| self.user_presets = v)?;
+        deser_field_opt(source, "tuning", |v| self.tuning = v)?;
+        let tuning = self.load_tuning(self.tuning.as_ref()).ok().flatten();
+        self.retuning = tuning.map(Retuning::new);
+        deser_field_opt(source, "polyphony", |v| _ = self.voices.set_limit(v))?;
+        deser_field_opt(source, "voice_stealing", |v| self.voices.set_stealing(v))?;
         Ok(())
     }
 
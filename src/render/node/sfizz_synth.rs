@@ -1,12 +1,14 @@
-use super::Render;
+use super::{Render, PANIC_CONTROLLERS, RELEASE_CONTROLLERS};
 use crate::{
-    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult}, json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater}, midi::{self, ControlChangeKind}, path::VirtualPaths, render::{
+    deser::{deser_field_opt, serialize, DeserializationResult, SerializationResult}, files::FileError, json::{update_fields_or_fail, JsonUpdateKind, JsonUpdater}, midi::{self, ControlChangeKind}, path::VirtualPaths, render::{
         self,
         command::{midi_filter::UpdateMidiFilterKind, ResponseCallback},
         midi_filter::{self, MidiFilterUser},
         node::RequestKind,
+        per_note::Expression,
         velocity_map,
-    }, synth::sfizz
+        voices::{Stealing, Voices},
+    }, synth::{sfizz, tuning::{self, Tuning}}
 };
 use serde_json::json;
 use std::{
@@ -24,11 +26,18 @@ pub struct Node {
     name: String,
     enabled: bool,
     midi_filter: midi_filter::MidiFilter,
+    // sfizz has no per-note pitch bend
+    expression: Expression,
+    voices: Voices,
     synth: Option<Mutex<sfizz::Synth>>,
     last_file: Option<PathBuf>,
     last_virtual_paths: Option<VirtualPaths>,
     last_sample_rate: Option<u32>,
     last_buffer_size: Option<usize>,
+    // None keeps whole samples in memory
+    preload_size: Option<u32>,
+    tuning: Option<tuning::Files>,
+    loaded_tuning: Option<Tuning>,
     gain: f32,
     transposition: i8,
     global_transposition: i8,
@@ -73,9 +82,15 @@ impl Node {
             if let Some(file) = vp.translate(file) {
                 let sample_rate = self.last_sample_rate;
                 let buffer_size = self.last_buffer_size;
+                let preload_size = self.preload_size;
+                let tuning = self.loaded_tuning.clone();
                 self.file_load_handle = Some(thread::spawn(
                     move || -> Result<Mutex<sfizz::Synth>, String> {
                         let mut synth = sfizz::Synth::default();
+                        synth.set_preload_size(preload_frames(preload_size));
+                        if let Some(tuning) = &tuning {
+                            synth.set_tuning(tuning);
+                        }
                         if let Some(sample_rate) = sample_rate {
                             synth.set_sample_rate(sample_rate);
                         }
@@ -97,6 +112,71 @@ impl Node {
         }
     }
 
+    // A loaded instrument reloads the sample heads on the synth's background thread
+    fn set_preload_size(&mut self, size: Option<u32>) -> JsonUpdateKind {
+        if size.is_some_and(|size| size < sfizz::MIN_PRELOAD_SIZE) {
+            return JsonUpdateKind::Failed;
+        }
+        self.preload_size = size;
+        if let Some(synth) = &self.synth {
+            if let Ok(mut synth) = synth.lock() {
+                synth.set_preload_size(preload_frames(size));
+            }
+        }
+        update_fields_or_fail(|updates| {
+            updates.push(("preload_size".into(), serialize(size)?));
+            Ok(())
+        })
+    }
+
+    fn set_tuning(&mut self, files: Option<tuning::Files>) -> JsonUpdateKind {
+        let Ok(tuning) = self.load_tuning(files.as_ref()) else {
+            return JsonUpdateKind::Failed;
+        };
+        if let Some(synth) = &self.synth {
+            if let Ok(mut synth) = synth.lock() {
+                if !synth.set_tuning(tuning.as_ref().unwrap_or(&Tuning::default())) {
+                    return JsonUpdateKind::Failed;
+                }
+            }
+        }
+        self.tuning = files;
+        self.loaded_tuning = tuning;
+        update_fields_or_fail(|updates| {
+            updates.push(("tuning".into(), serialize(&self.tuning)?));
+            Ok(())
+        })
+    }
+
+    fn load_tuning(&self, files: Option<&tuning::Files>) -> Result<Option<Tuning>, FileError> {
+        match (files, &self.last_virtual_paths) {
+            (None, _) => Ok(None),
+            (Some(files), Some(vp)) => Tuning::load(vp, files).map(Some),
+            (Some(_), None) => Err(FileError::InvalidPath),
+        }
+    }
+
+    fn set_polyphony(&mut self, limit: Option<u16>) -> JsonUpdateKind {
+        let Some(released) = self.voices.set_limit(limit) else {
+            return JsonUpdateKind::Failed;
+        };
+        for note in released {
+            self.note_off(note, 0, None);
+        }
+        update_fields_or_fail(|updates| {
+            updates.push(("polyphony".into(), serialize(self.voices.limit())?));
+            Ok(())
+        })
+    }
+
+    fn set_voice_stealing(&mut self, stealing: Stealing) -> JsonUpdateKind {
+        self.voices.set_stealing(stealing);
+        update_fields_or_fail(|updates| {
+            updates.push(("voice_stealing".into(), serialize(stealing)?));
+            Ok(())
+        })
+    }
+
     fn set_gain(&mut self, gain: f32) -> JsonUpdateKind {
         self.gain = gain;
         update_fields_or_fail(|updates| {
@@ -166,31 +246,67 @@ impl Node {
 
     fn process_midi_message(&mut self, message: &midi::Message) {
         use midi::MessageKind as Kind;
+        if let Some(kind) = self.expression.follow(&message.kind) {
+            self.process_midi_message(&midi::Message::new(message.channel, kind));
+        }
         match message.kind {
-            Kind::NoteOn { note, velocity } => self.note_on(note, velocity),
-            Kind::NoteOff { note, velocity } => self.note_off(note, velocity),
+            Kind::NoteOn { note, velocity } => {
+                self.note_on(note, velocity, message.hi_res_normalized())
+            }
+            Kind::NoteOff { note, velocity } => {
+                self.note_off(note, velocity, message.hi_res_normalized())
+            }
             Kind::PolyphonicAftertouch { note, pressure } => self.poly_aftt(note, pressure),
-            Kind::ControlChange { kind, value } => self.cc(kind, value),
+            Kind::ControlChange { kind, value } => {
+                self.cc(kind, value, message.hi_res_normalized())
+            }
             Kind::ProgramChange { .. } => {}
             Kind::ChannelAftertouch { pressure } => self.channel_aftt(pressure),
-            Kind::PitchWheel { value } => self.pitch_wheel(value),
+            Kind::PitchWheel { value } => self.pitch_wheel(value, message.hi_res_normalized()),
+            Kind::PerNoteController { .. } => {}
+            Kind::PerNotePitchWheel { .. } => {}
+            Kind::RegisteredParameter { .. } => {}
+            Kind::NonRegisteredParameter { .. } => {}
+            Kind::SystemExclusive { .. } => {}
         }
     }
 
-    fn note_on(&mut self, note: u8, velocity: u8) {
+    fn note_on(&mut self, note: u8, velocity: u8, hi_res_velocity: Option<f32>) {
+        if velocity == 0 && hi_res_velocity.is_none() {
+            self.note_off(note, 0, None);
+            return;
+        }
+        if let Some(stolen) = self.voices.note_on(note, velocity) {
+            self.note_off(stolen, 0, None);
+        }
         let note = self.transpose_note(note);
+        // keys the keyboard mapping leaves out don't sound
+        if let Some(tuning) = &self.loaded_tuning {
+            if tuning.pitch(note).is_none() {
+                return;
+            }
+        }
         if let Some(synth) = &self.synth {
             if let Ok(mut synth) = synth.lock() {
-                synth.send_note_on(note, velocity);
+                match hi_res_velocity {
+                    Some(velocity) => synth.send_hd_note_on(note, velocity),
+                    None => synth.send_note_on(note, velocity),
+                }
             }
         }
     }
 
-    fn note_off(&mut self, note: u8, velocity: u8) {
+    fn note_off(&mut self, note: u8, velocity: u8, hi_res_velocity: Option<f32>) {
+        if !self.voices.note_off(note) {
+            return;
+        }
         let note = self.transpose_note(note);
         if let Some(synth) = &self.synth {
             if let Ok(mut synth) = synth.lock() {
-                synth.send_note_off(note, velocity);
+                match hi_res_velocity {
+                    Some(velocity) => synth.send_hd_note_off(note, velocity),
+                    None => synth.send_note_off(note, velocity),
+                }
             }
         }
     }
@@ -204,10 +320,26 @@ impl Node {
         }
     }
 
-    fn cc(&mut self, kind: ControlChangeKind, value: u8) {
+    // Sostenuto and soft pedal included, SFZ instruments map them with their own opcodes
+    fn cc(&mut self, kind: ControlChangeKind, value: u8, hi_res_value: Option<f32>) {
+        match kind {
+            ControlChangeKind::DamperPedal if self.voices.holds_damper() => {
+                for note in self.voices.set_damper(value) {
+                    self.note_off(note, 0, None);
+                }
+                return;
+            }
+            ControlChangeKind::AllNotesOff
+            | ControlChangeKind::AllSoundsOff
+            | ControlChangeKind::ResetAllControllers => self.voices.reset(),
+            _ => {}
+        }
         if let Some(synth) = &self.synth {
             if let Ok(mut synth) = synth.lock() {
-                synth.send_cc(kind.as_number(), value);
+                match hi_res_value {
+                    Some(value) => synth.send_hd_cc(kind.as_number(), value),
+                    None => synth.send_cc(kind.as_number(), value),
+                }
             }
         }
     }
@@ -220,10 +352,13 @@ impl Node {
         }
     }
 
-    fn pitch_wheel(&mut self, value: u16) {
+    fn pitch_wheel(&mut self, value: u16, hi_res_value: Option<f32>) {
         if let Some(synth) = &self.synth {
             if let Ok(mut synth) = synth.lock() {
-                synth.send_pitch_wheel(midi::Message::get_pitch_wheel_signed(value));
+                match hi_res_value {
+                    Some(value) => synth.send_hd_pitch_wheel(value * 2.0 - 1.0),
+                    None => synth.send_pitch_wheel(midi::Message::get_pitch_wheel_signed(value)),
+                }
             }
         }
     }
@@ -315,11 +450,16 @@ impl Default for Node {
             name: DEFAULT_NAME.into(),
             enabled: true,
             midi_filter: Default::default(),
+            expression: Default::default(),
+            voices: Default::default(),
             synth: Some(Mutex::new(sfizz::Synth::default())),
             last_file: None,
             last_virtual_paths: None,
             last_sample_rate: None,
             last_buffer_size: None,
+            preload_size: Some(sfizz::DEFAULT_PRELOAD_SIZE),
+            tuning: None,
+            loaded_tuning: None,
             gain: 1.0,
             transposition: 0,
             global_transposition: 0,
@@ -341,11 +481,16 @@ impl Clone for Node {
             name: self.name.clone(),
             enabled: self.enabled,
             midi_filter: self.midi_filter.clone(),
+            expression: Default::default(),
+            voices: self.voices.clone(),
             synth: None,
             last_file: self.last_file.clone(),
             last_virtual_paths: self.last_virtual_paths.clone(),
             last_sample_rate: self.last_sample_rate,
             last_buffer_size: self.last_buffer_size,
+            preload_size: self.preload_size,
+            tuning: self.tuning.clone(),
+            loaded_tuning: self.loaded_tuning.clone(),
             gain: self.gain,
             transposition: self.transposition,
             global_transposition: self.global_transposition,
@@ -374,10 +519,8 @@ impl Render for Node {
                 synth.render_block(tmp_lbuf, tmp_rbuf);
             }
         }
-        render::amplify_buffer(tmp_lbuf, self.gain);
-        render::amplify_buffer(tmp_rbuf, self.gain);
-        render::add_buf_to_buf(lbuf, tmp_lbuf);
-        render::add_buf_to_buf(rbuf, tmp_rbuf);
+        render::add_amplified_buf_to_buf(lbuf, tmp_lbuf, self.gain);
+        render::add_amplified_buf_to_buf(rbuf, tmp_rbuf, self.gain);
     }
 
     fn reset_rendering(&mut self) {
@@ -390,6 +533,10 @@ impl Render for Node {
 
     fn set_virtual_paths(&mut self, vp: VirtualPaths) {
         self.last_virtual_paths = Some(vp);
+        // a tuning deserialized before the paths were known
+        if self.loaded_tuning.is_none() {
+            self.loaded_tuning = self.load_tuning(self.tuning.as_ref()).ok().flatten();
+        }
     }
 
     fn set_sample_rate(&mut self, sample_rate: u32) {
@@ -407,6 +554,18 @@ impl Render for Node {
         }
     }
 
+    fn panic(&mut self) {
+        for kind in PANIC_CONTROLLERS {
+            self.cc(kind, 0, None);
+        }
+    }
+
+    fn release(&mut self) {
+        for kind in RELEASE_CONTROLLERS {
+            self.cc(kind, 0, None);
+        }
+    }
+
     fn set_global_transposition(&mut self, transposition: i8) {
         self.global_transposition = transposition;
     }
@@ -430,6 +589,10 @@ impl Render for Node {
             RK::UpdateMidiFilter(kind) => cb(self.update_midi_filter(&kind)),
             RK::SetUserPreset(preset) => cb(self.set_user_preset(preset)),
             RK::SetUserPresetEnabled(p, f) => cb(self.set_user_preset_enabled(p, f)),
+            RK::SetPreloadSize(size) => cb(self.set_preload_size(size)),
+            RK::SetTuning(files) => cb(self.set_tuning(files)),
+            RK::SetPolyphony(limit) => cb(self.set_polyphony(limit)),
+            RK::SetVoiceStealing(stealing) => cb(self.set_voice_stealing(stealing)),
             _ => cb(JsonUpdateKind::Denied),
         }
     }
@@ -446,6 +609,10 @@ impl Render for Node {
             "ignore_global_transposition": serialize(self.ignore_global_transposition)?,
             "loaded_file": serialize(&self.last_file)?,
             "user_presets": serialize(&self.user_presets)?,
+            "preload_size": serialize(self.preload_size)?,
+            "tuning": serialize(&self.tuning)?,
+            "polyphony": serialize(self.voices.limit())?,
+            "voice_stealing": serialize(self.voices.stealing())?,
         });
         Ok(result)
     }
@@ -464,6 +631,11 @@ impl Render for Node {
         })?;
         deser_field_opt(source, "loaded_file", |v| self.last_file = v)?;
         deser_field_opt(source, "user_presets", |v| self.user_presets = v)?;
+        deser_field_opt(source, "preload_size", |v| self.preload_size = v)?;
+        deser_field_opt(source, "tuning", |v| self.tuning = v)?;
+        self.loaded_tuning = self.load_tuning(self.tuning.as_ref()).ok().flatten();
+        deser_field_opt(source, "polyphony", |v| _ = self.voices.set_limit(v))?;
+        deser_field_opt(source, "voice_stealing", |v| self.voices.set_stealing(v))?;
         Ok(())
     }
 
@@ -472,6 +644,11 @@ impl Render for Node {
     }
 }
 
+// sfizz loads no more of a sample than it has
+fn preload_frames(size: Option<u32>) -> u32 {
+    size.unwrap_or(u32::MAX)
+}
+
 impl MidiFilterUser for Node {
     fn midi_filter_mut(&mut self) -> &mut midi_filter::MidiFilter {
         &mut self.midi_filter
@@ -0,0 +1,96 @@
+// Shifts the keyboard by octaves for parts a short keyboard doesn't reach, from two keys or
+// controllers of its own. The keys shifting it don't play.
+
+use crate::midi::{self, trigger::Trigger};
+use serde::{Deserialize, Serialize};
+
+// Either way
+pub const MAX_OCTAVES: i8 = 3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Triggers {
+    pub up: Option<Trigger>,
+    pub down: Option<Trigger>,
+}
+
+#[derive(Default)]
+pub struct OctaveShift {
+    triggers: Triggers,
+    octaves: i8,
+}
+
+impl OctaveShift {
+    pub fn triggers(&self) -> Triggers {
+        self.triggers
+    }
+
+    pub fn set_triggers(&mut self, triggers: Triggers) {
+        self.triggers = triggers;
+    }
+
+    pub fn octaves(&self) -> i8 {
+        self.octaves
+    }
+
+    pub fn set_octaves(&mut self, octaves: i8) {
+        self.octaves = octaves.clamp(-MAX_OCTAVES, MAX_OCTAVES);
+    }
+
+    // The octaves after the message, none when it isn't from one of the triggers. Letting go of
+    // a trigger leaves them as they are.
+    pub fn receive(&self, message: &midi::Message) -> Option<i8> {
+        let Triggers { up, down } = self.triggers;
+        let step = if up.is_some_and(|t| t.is_pressed_by(message)) {
+            1
+        } else if down.is_some_and(|t| t.is_pressed_by(message)) {
+            -1
+        } else if [up, down].iter().flatten().any(|t| t.is_sent_by(message)) {
+            0
+        } else {
+            return None;
+        };
+        Some((self.octaves + step).clamp(-MAX_OCTAVES, MAX_OCTAVES))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OctaveShift, Triggers, MAX_OCTAVES};
+    use crate::midi::{trigger::Trigger, Message, MessageKind};
+
+    #[test]
+    fn lowest_keys_shift() {
+        let mut shift = OctaveShift::default();
+        shift.set_triggers(Triggers {
+            up: Some(Trigger::Note {
+                channel: 0,
+                note: 22,
+            }),
+            down: Some(Trigger::Note {
+                channel: 0,
+                note: 21,
+            }),
+        });
+        let note_on = |note| Message::new(0, MessageKind::NoteOn { note, velocity: 90 });
+        let note_off = |note| Message::new(0, MessageKind::NoteOff { note, velocity: 0 });
+
+        // the other keys play
+        for (message, expected) in [
+            (note_on(22), Some(1)),
+            (note_off(22), Some(1)),
+            (note_on(22), Some(2)),
+            (note_on(21), Some(1)),
+            (note_on(23), None),
+        ] {
+            let octaves = shift.receive(&message);
+            assert_eq!(octaves, expected);
+            if let Some(octaves) = octaves {
+                shift.set_octaves(octaves);
+            }
+        }
+
+        shift.set_octaves(-MAX_OCTAVES);
+        assert_eq!(shift.receive(&note_on(21)), Some(-MAX_OCTAVES));
+    }
+}
@@ -0,0 +1,195 @@
+// Stereo pairs of an audio interface with more than two channels, so the drums can go to
+// outputs 3/4 for a mix of their own at front of house. Pair 0 is outputs 1/2, the main mix the
+// meters and the recordings get. A node plays on the pair it's routed to, else on the one of its
+// group, and whatever is routed to a pair the device doesn't have plays on pair 0. A monitor pair
+// gets the main mix on top of its nodes, so with the metronome's node routed to it the drummer
+// hears the band and the click in their in-ears while the click stays out of the main mix.
+
+use super::{add_buf_to_buf, groups::Groups, moved_node};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub node: usize,
+    pub pair: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Outputs {
+    // In the order of the nodes, the ones without a route play where their group does
+    routes: Vec<Route>,
+    // (left, right) of the pairs from 1 on, as many as the device has
+    buses: Vec<(Vec<f32>, Vec<f32>)>,
+    // In order, without pair 0
+    monitors: Vec<usize>,
+}
+
+impl Outputs {
+    pub fn get(&self) -> &[Route] {
+        &self.routes
+    }
+
+    // A route to pair 0 is the same as none, of two routes of a node the first one counts
+    pub fn set(&mut self, mut routes: Vec<Route>) {
+        routes.retain(|route| route.pair != 0);
+        routes.sort_by_key(|route| route.node);
+        routes.dedup_by_key(|route| route.node);
+        self.routes = routes;
+    }
+
+    pub fn set_pair(&mut self, node: usize, pair: usize) {
+        match self.routes.binary_search_by_key(&node, |route| route.node) {
+            Ok(index) if pair == 0 => {
+                self.routes.remove(index);
+            }
+            Ok(index) => self.routes[index].pair = pair,
+            Err(index) if pair != 0 => self.routes.insert(index, Route { node, pair }),
+            Err(_) => (),
+        }
+    }
+
+    pub fn monitors(&self) -> &[usize] {
+        &self.monitors
+    }
+
+    pub fn set_monitors(&mut self, mut pairs: Vec<usize>) {
+        pairs.retain(|&pair| pair != 0);
+        pairs.sort_unstable();
+        pairs.dedup();
+        self.monitors = pairs;
+    }
+
+    // Pair 0 included
+    pub fn num_pairs(&self) -> usize {
+        self.buses.len() + 1
+    }
+
+    pub fn set_num_pairs(&mut self, num_pairs: usize) {
+        self.buses
+            .resize_with(num_pairs.max(1) - 1, Default::default);
+    }
+
+    // Where the node plays
+    pub fn pair(&self, node: usize, groups: &Groups) -> usize {
+        let pair = match self.routes.binary_search_by_key(&node, |route| route.node) {
+            Ok(index) => self.routes[index].pair,
+            Err(_) => groups.group_of(node).map_or(0, |group| group.output_pair),
+        };
+        if pair < self.num_pairs() {
+            pair
+        } else {
+            0
+        }
+    }
+
+    // The buses get as long as the buffer being rendered, only growing them allocates
+    pub fn resize(&mut self, len: usize) {
+        for (lbuf, rbuf) in &mut self.buses {
+            lbuf.resize(len, 0.0);
+            rbuf.resize(len, 0.0);
+        }
+    }
+
+    // From pair 1 on, pair 0 is the buffer the renderer gets
+    pub fn bus(&self, pair: usize) -> Option<(&[f32], &[f32])> {
+        let (lbuf, rbuf) = self.buses.get(pair.checked_sub(1)?)?;
+        Some((lbuf, rbuf))
+    }
+
+    pub fn bus_mut(
+        &mut self,
+        pair: usize,
+        frames: Range<usize>,
+    ) -> Option<(&mut [f32], &mut [f32])> {
+        let (lbuf, rbuf) = self.buses.get_mut(pair.checked_sub(1)?)?;
+        Some((&mut lbuf[frames.clone()], &mut rbuf[frames]))
+    }
+
+    pub fn buses_mut(
+        &mut self,
+        frames: Range<usize>,
+    ) -> impl Iterator<Item = (&mut [f32], &mut [f32])> + '_ {
+        self.buses
+            .iter_mut()
+            .map(move |(lbuf, rbuf)| (&mut lbuf[frames.clone()], &mut rbuf[frames.clone()]))
+    }
+
+    // Once the main mix is done, of the frames the buses have
+    pub fn mix_into_monitors(&mut self, lbuf: &[f32], rbuf: &[f32]) {
+        for &pair in &self.monitors {
+            if let Some((bus_lbuf, bus_rbuf)) = self.buses.get_mut(pair - 1) {
+                add_buf_to_buf(bus_lbuf, lbuf);
+                add_buf_to_buf(bus_rbuf, rbuf);
+            }
+        }
+    }
+
+    // The nodes after it move up
+    pub fn remove_node(&mut self, node: usize) {
+        self.routes.retain(|route| route.node != node);
+        for route in &mut self.routes {
+            if route.node > node {
+                route.node -= 1;
+            }
+        }
+    }
+
+    // The route follows the node, the nodes in between shift by one
+    pub fn move_node(&mut self, id: usize, new_id: usize) {
+        let routes = self.routes.iter().map(|route| Route {
+            node: moved_node(route.node, id, new_id),
+            pair: route.pair,
+        });
+        self.set(routes.collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Outputs, Route};
+    use crate::render::groups::{Group, Groups};
+
+    #[test]
+    fn routes_nodes_and_groups() {
+        let mut groups = Groups::default();
+        groups.set(vec![Group {
+            name: "Drums".to_owned(),
+            nodes: vec![1, 2],
+            gain: 1.0,
+            muted: false,
+            soloed: false,
+            output_pair: 1,
+        }]);
+        let mut outputs = Outputs::default();
+        outputs.set_num_pairs(3);
+        outputs.set_pair(2, 2);
+        outputs.set_pair(3, 5);
+        let pairs = |outputs: &Outputs| {
+            (0..4)
+                .map(|node| outputs.pair(node, &groups))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pairs(&outputs), [0, 1, 2, 0]);
+
+        outputs.set_num_pairs(1);
+        assert_eq!(pairs(&outputs), [0; 4]);
+        assert!(outputs.bus(1).is_none());
+    }
+
+    #[test]
+    fn follows_the_nodes() {
+        let mut outputs = Outputs::default();
+        outputs.set(vec![Route { node: 3, pair: 1 }, Route { node: 0, pair: 0 }]);
+        assert_eq!(outputs.get(), [Route { node: 3, pair: 1 }]);
+        outputs.set_pair(1, 2);
+        outputs.move_node(3, 0);
+        assert_eq!(
+            outputs.get(),
+            [Route { node: 0, pair: 1 }, Route { node: 2, pair: 2 }]
+        );
+        outputs.remove_node(1);
+        outputs.set_pair(0, 0);
+        assert_eq!(outputs.get(), [Route { node: 1, pair: 2 }]);
+    }
+}
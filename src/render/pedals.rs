@@ -0,0 +1,120 @@
+// Sostenuto and soft pedal for synths that only know the damper pedal. The node sends notes
+// through it and gets them back changed, held back or let go by the pedals.
+
+// How much softer notes get with the soft pedal down, about what an una corda does to a piano
+pub const SOFT_PEDAL_VELOCITY: f32 = 0.7;
+
+const NUM_NOTES: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct Pedals {
+    keys_down: [bool; NUM_NOTES],
+    sostenuto: bool,
+    // The keys that were down when the sostenuto went down
+    sustained: [bool; NUM_NOTES],
+    // Released while sustained, the note offs come with the sostenuto going up
+    held_back: [bool; NUM_NOTES],
+    soft: bool,
+}
+
+impl Default for Pedals {
+    fn default() -> Self {
+        Self {
+            keys_down: [false; NUM_NOTES],
+            sostenuto: false,
+            sustained: [false; NUM_NOTES],
+            held_back: [false; NUM_NOTES],
+            soft: false,
+        }
+    }
+}
+
+impl Pedals {
+    // The velocity to play the note with, none when a velocity of 0 makes it a held back note off
+    pub fn note_on(&mut self, note: u8, velocity: u8) -> Option<u8> {
+        if velocity == 0 {
+            return self.note_off(note).then_some(0);
+        }
+        let note = note as usize % NUM_NOTES;
+        self.keys_down[note] = true;
+        self.held_back[note] = false;
+        if self.soft {
+            Some(((velocity as f32 * SOFT_PEDAL_VELOCITY).round() as u8).max(1))
+        } else {
+            Some(velocity)
+        }
+    }
+
+    // Whether the note off goes to the synth now
+    pub fn note_off(&mut self, note: u8) -> bool {
+        let note = note as usize % NUM_NOTES;
+        self.keys_down[note] = false;
+        if self.sostenuto && self.sustained[note] {
+            self.held_back[note] = true;
+            false
+        } else {
+            true
+        }
+    }
+
+    // The notes to send note offs for now
+    pub fn set_sostenuto(&mut self, value: u8) -> Vec<u8> {
+        let down = value >= 64;
+        if down == self.sostenuto {
+            return vec![];
+        }
+        self.sostenuto = down;
+        if down {
+            self.sustained = self.keys_down;
+            return vec![];
+        }
+        self.sustained = [false; NUM_NOTES];
+        let released = (0..NUM_NOTES as u8)
+            .filter(|&note| self.held_back[note as usize])
+            .collect();
+        self.held_back = [false; NUM_NOTES];
+        released
+    }
+
+    pub fn set_soft(&mut self, value: u8) {
+        self.soft = value >= 64;
+    }
+
+    // After all notes off or a reset of all controllers
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pedals;
+
+    #[test]
+    fn sostenuto_holds_only_the_keys_that_were_down() {
+        let mut pedals = Pedals::default();
+        pedals.note_on(48, 100);
+        pedals.note_on(52, 100);
+        pedals.note_off(52);
+        pedals.set_sostenuto(127);
+        // played after the pedal went down, not held
+        pedals.note_on(60, 100);
+        assert!(pedals.note_off(60));
+        assert!(!pedals.note_off(48));
+        assert_eq!(pedals.note_on(48, 0), None);
+        assert!(pedals.set_sostenuto(100).is_empty());
+        assert_eq!(pedals.set_sostenuto(0), [48]);
+        assert!(pedals.note_off(48));
+    }
+
+    #[test]
+    fn soft_pedal() {
+        let mut pedals = Pedals::default();
+        pedals.set_soft(127);
+        assert_eq!(pedals.note_on(60, 100), Some(70));
+        assert_eq!(pedals.note_on(61, 1), Some(1));
+        pedals.set_soft(0);
+        assert_eq!(pedals.note_on(60, 100), Some(100));
+        assert_eq!(pedals.note_on(60, 0), Some(0));
+    }
+}
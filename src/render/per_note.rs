@@ -0,0 +1,169 @@
+// Per-note expression for synths that play everything on one channel. The expression of the
+// note played last goes to the whole channel, which is what a mono synth does with MPE and
+// sounds right as long as one note at a time gets bent.
+
+use crate::midi::{
+    mpe::{PER_NOTE_PITCH_BEND_RANGE, TIMBRE_CONTROLLER},
+    parameter::{self, PITCH_BEND_SENSITIVITY},
+    ControlChangeKind, MessageKind,
+};
+
+// The pitch bend range the synths start with
+const DEFAULT_CHANNEL_PITCH_BEND_RANGE: f32 = 2.0;
+
+const PITCH_WHEEL_CENTER: u16 = 0x2000;
+
+#[derive(Debug, Default, Clone)]
+pub struct Expression {
+    // Held notes in the order they were played
+    notes: Vec<u8>,
+    bent: bool,
+    // Set by the pitch bend sensitivity parameter, the synth gets it as well
+    channel_range: Option<f32>,
+}
+
+impl Expression {
+    // The channel message to handle before the message itself: the one standing in for per-note
+    // expression of the last note, or the pitch wheel going back to the center for a new note
+    pub fn follow(&mut self, kind: &MessageKind) -> Option<MessageKind> {
+        match *kind {
+            MessageKind::NoteOn { note, velocity } if velocity > 0 => self.note_on(note),
+            MessageKind::NoteOn { note, .. } | MessageKind::NoteOff { note, .. } => {
+                self.note_off(note);
+                None
+            }
+            MessageKind::ControlChange {
+                kind: ControlChangeKind::AllNotesOff | ControlChangeKind::AllSoundsOff,
+                ..
+            } => {
+                self.notes.clear();
+                None
+            }
+            MessageKind::RegisteredParameter {
+                parameter: PITCH_BEND_SENSITIVITY,
+                value,
+            } => {
+                self.channel_range = Some(parameter::pitch_bend_range_of(value));
+                None
+            }
+            _ => self.translate(kind),
+        }
+    }
+
+    // A bend of the note before doesn't carry over
+    fn note_on(&mut self, note: u8) -> Option<MessageKind> {
+        self.note_off(note);
+        self.notes.push(note);
+        self.bent.then(|| {
+            self.bent = false;
+            MessageKind::PitchWheel {
+                value: PITCH_WHEEL_CENTER,
+            }
+        })
+    }
+
+    fn note_off(&mut self, note: u8) {
+        self.notes.retain(|&n| n != note);
+    }
+
+    // None when it isn't for the last note
+    fn translate(&mut self, kind: &MessageKind) -> Option<MessageKind> {
+        match *kind {
+            MessageKind::PerNotePitchWheel { note, value } if self.is_last(note) => {
+                let semitones = (value as f32 - PITCH_WHEEL_CENTER as f32)
+                    / PITCH_WHEEL_CENTER as f32
+                    * PER_NOTE_PITCH_BEND_RANGE;
+                let range = self
+                    .channel_range
+                    .unwrap_or(DEFAULT_CHANNEL_PITCH_BEND_RANGE)
+                    .max(f32::EPSILON);
+                let value =
+                    PITCH_WHEEL_CENTER as f32 + semitones / range * PITCH_WHEEL_CENTER as f32;
+                let value = value.round().clamp(0.0, 0x3FFF as f32) as u16;
+                self.bent = value != PITCH_WHEEL_CENTER;
+                Some(MessageKind::PitchWheel { value })
+            }
+            MessageKind::PerNoteController {
+                note,
+                index: TIMBRE_CONTROLLER,
+                value,
+            } if self.is_last(note) => Some(MessageKind::ControlChange {
+                kind: ControlChangeKind::SoundController5,
+                value,
+            }),
+            _ => None,
+        }
+    }
+
+    fn is_last(&self, note: u8) -> bool {
+        self.notes.last() == Some(&note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expression;
+    use crate::midi::{mpe::TIMBRE_CONTROLLER, parameter::PITCH_BEND_SENSITIVITY, MessageKind};
+
+    #[test]
+    fn expression_of_the_last_note() {
+        let mut expression = Expression::default();
+        let bend = |note, value| MessageKind::PerNotePitchWheel { note, value };
+        let on = |note| MessageKind::NoteOn { note, velocity: 1 };
+        assert_eq!(expression.follow(&on(60)), None);
+        assert_eq!(expression.follow(&on(64)), None);
+        assert_eq!(expression.follow(&bend(60, 0x3000)), None);
+        // 3/4 of a semitone, out of 48 and out of 2
+        assert_eq!(
+            expression.follow(&bend(64, 0x2080)),
+            Some(MessageKind::PitchWheel { value: 0x2C00 })
+        );
+        assert_eq!(
+            expression.follow(&bend(64, 0x3FFF)),
+            Some(MessageKind::PitchWheel { value: 0x3FFF })
+        );
+        let timbre = MessageKind::PerNoteController {
+            note: 64,
+            index: TIMBRE_CONTROLLER,
+            value: 10,
+        };
+        assert!(matches!(
+            expression.follow(&timbre),
+            Some(MessageKind::ControlChange { value: 10, .. })
+        ));
+
+        // back to the note before, which wasn't bent
+        expression.follow(&MessageKind::NoteOff {
+            note: 64,
+            velocity: 0,
+        });
+        assert!(expression.follow(&bend(60, 0x2100)).is_some());
+        assert_eq!(
+            expression.follow(&on(67)),
+            Some(MessageKind::PitchWheel { value: 0x2000 })
+        );
+        assert_eq!(expression.follow(&on(69)), None);
+    }
+
+    #[test]
+    fn channel_pitch_bend_range() {
+        let mut expression = Expression::default();
+        expression.follow(&MessageKind::RegisteredParameter {
+            parameter: PITCH_BEND_SENSITIVITY,
+            value: 24 << 7,
+        });
+        expression.follow(&MessageKind::NoteOn {
+            note: 60,
+            velocity: 1,
+        });
+        // 12 semitones, half of the 24 the channel bends
+        let bend = MessageKind::PerNotePitchWheel {
+            note: 60,
+            value: 0x2000 + 0x800,
+        };
+        assert_eq!(
+            expression.follow(&bend),
+            Some(MessageKind::PitchWheel { value: 0x3000 })
+        );
+    }
+}
@@ -0,0 +1,374 @@
+// Pitch shifters on the outputs of nodes, like the audio input a fifth up or a synth an octave
+// down, an octave either way at most. The sound gets cut into grains a period of the pitch found
+// in it long, which get laid out closer together or further apart (PSOLA). Played at their own
+// speed the grains keep the formants, so a voice doesn't sound like a chipmunk, played faster or
+// slower they shift the formants along like a tape would. The output is late by about two and a
+// half periods of the lowest pitch the shifter looks for, 30 ms or so.
+
+use super::moved_node;
+use serde::{Deserialize, Serialize};
+
+const MIN_FREQUENCY: f32 = 80.0;
+const MAX_FREQUENCY: f32 = 1000.0;
+// The period of the sound until a pitch is found, in Hz
+const DEFAULT_FREQUENCY: f32 = 200.0;
+// Frames between looking for the pitch
+const DETECTION_HOP: usize = 256;
+// The pitch is looked for in the sound at about this sample rate
+const DETECTION_RATE: u32 = 12000;
+// Of the normalized differences, a lag below it is a period
+const DETECTION_THRESHOLD: f32 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Shift {
+    pub node: usize,
+    // In -12.0..=12.0, the modulation routes to the pitch shift scale it
+    pub semitones: f32,
+    pub preserve_formants: bool,
+}
+
+impl Shift {
+    pub fn is_valid(&self) -> bool {
+        (-12.0..=12.0).contains(&self.semitones)
+    }
+}
+
+#[derive(Default)]
+pub struct PitchShifts {
+    // In the order of the nodes, one each at most
+    shifts: Vec<Shift>,
+    // Of every shift, none until it first plays
+    shifters: Vec<Option<Shifter>>,
+    sample_rate: Option<u32>,
+}
+
+impl PitchShifts {
+    pub fn get(&self) -> &[Shift] {
+        &self.shifts
+    }
+
+    // Of two shifts of a node the first one counts, a node keeping a shift keeps playing through
+    // the same shifter
+    pub fn set(&mut self, mut shifts: Vec<Shift>) {
+        shifts.sort_by_key(|shift| shift.node);
+        shifts.dedup_by_key(|shift| shift.node);
+        let mut shifters = Vec::with_capacity(shifts.len());
+        for shift in &shifts {
+            let kept = self
+                .shifts
+                .iter()
+                .position(|old| old.node == shift.node)
+                .and_then(|index| self.shifters[index].take());
+            shifters.push(kept);
+        }
+        self.shifts = shifts;
+        self.shifters = shifters;
+    }
+
+    pub fn set_shift(&mut self, shift: Shift) {
+        let mut shifts = self.shifts.clone();
+        shifts.retain(|old| old.node != shift.node);
+        shifts.push(shift);
+        self.set(shifts);
+    }
+
+    pub fn remove_shift(&mut self, node: usize) {
+        if let Ok(index) = self.shifts.binary_search_by_key(&node, |shift| shift.node) {
+            self.shifts.remove(index);
+            self.shifters.remove(index);
+        }
+    }
+
+    // The shifters start over
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = Some(sample_rate);
+        self.shifters.iter_mut().for_each(|shifter| *shifter = None);
+    }
+
+    // The output of the node shifted, as it is without a shift. `amount` scales the semitones.
+    pub fn process<'a>(
+        &'a mut self,
+        node: usize,
+        lbuf: &'a [f32],
+        rbuf: &'a [f32],
+        amount: f32,
+    ) -> (&'a [f32], &'a [f32]) {
+        let Ok(index) = self.shifts.binary_search_by_key(&node, |shift| shift.node) else {
+            return (lbuf, rbuf);
+        };
+        let shift = self.shifts[index];
+        let sample_rate = self.sample_rate.unwrap_or(44100);
+        let shifter = self.shifters[index].get_or_insert_with(|| Shifter::new(sample_rate));
+        let ratio = 2f32.powf(shift.semitones * amount / 12.0);
+        shifter.process(lbuf, rbuf, ratio, shift.preserve_formants)
+    }
+
+    // The shift of the node goes with it, the nodes after it move up
+    pub fn remove_node(&mut self, node: usize) {
+        self.remove_shift(node);
+        for shift in &mut self.shifts {
+            if shift.node > node {
+                shift.node -= 1;
+            }
+        }
+    }
+
+    // The shift follows the node, the nodes in between shift by one
+    pub fn move_node(&mut self, id: usize, new_id: usize) {
+        for shift in &mut self.shifts {
+            shift.node = moved_node(shift.node, id, new_id);
+        }
+        let mut pairs: Vec<_> = std::mem::take(&mut self.shifts)
+            .into_iter()
+            .zip(std::mem::take(&mut self.shifters))
+            .collect();
+        pairs.sort_by_key(|(shift, _)| shift.node);
+        (self.shifts, self.shifters) = pairs.into_iter().unzip();
+    }
+}
+
+struct Shifter {
+    sample_rate: u32,
+    // Of both sides, rings of `mask + 1` frames
+    input: [Vec<f32>; 2],
+    output: [Vec<f32>; 2],
+    mask: usize,
+    // Frames taken in so far, where they are in the rings follows from it
+    written: usize,
+    // Of the output behind the input, in frames
+    latency: usize,
+    max_period: f64,
+    period: f64,
+    // Centers of the next grain in the output and of the one it was taken from in the input, in
+    // frames like `written`
+    next_grain: f64,
+    grain_source: f64,
+    until_detection: usize,
+    // Frames summed up for every one the pitch is looked for in
+    decimation: usize,
+    decimated: Vec<f32>,
+    differences: Vec<f32>,
+    // What `process` hands out
+    lbuf: Vec<f32>,
+    rbuf: Vec<f32>,
+}
+
+impl Shifter {
+    fn new(sample_rate: u32) -> Self {
+        let max_period = (sample_rate as f32 / MIN_FREQUENCY).ceil() as usize;
+        // a grain reaches half a period past the source and a period past its center, which is
+        // up to a period ahead of the output
+        let latency = max_period * 5 / 2 + 2;
+        let size = (4 * latency).next_power_of_two();
+        let decimation = (sample_rate / DETECTION_RATE).max(1) as usize;
+        let max_lag = max_period / decimation;
+        Self {
+            sample_rate,
+            input: [vec![0.0; size], vec![0.0; size]],
+            output: [vec![0.0; size], vec![0.0; size]],
+            mask: size - 1,
+            written: 0,
+            latency,
+            max_period: max_period as f64,
+            period: (sample_rate as f32 / DEFAULT_FREQUENCY) as f64,
+            next_grain: 0.0,
+            grain_source: 0.0,
+            until_detection: 0,
+            decimation,
+            decimated: vec![0.0; 2 * max_lag],
+            differences: vec![0.0; max_lag + 1],
+            lbuf: vec![],
+            rbuf: vec![],
+        }
+    }
+
+    fn process(
+        &mut self,
+        lbuf: &[f32],
+        rbuf: &[f32],
+        ratio: f32,
+        preserve_formants: bool,
+    ) -> (&[f32], &[f32]) {
+        let len = lbuf.len().min(rbuf.len());
+        self.lbuf.resize(len, 0.0);
+        self.rbuf.resize(len, 0.0);
+        for n in 0..len {
+            let position = self.written & self.mask;
+            self.input[0][position] = lbuf[n];
+            self.input[1][position] = rbuf[n];
+            self.written += 1;
+            if self.until_detection == 0 {
+                self.until_detection = DETECTION_HOP;
+                if let Some(period) = self.detect_period() {
+                    self.period = period;
+                }
+            }
+            self.until_detection -= 1;
+
+            let now = self.written as f64 - self.latency as f64;
+            while self.next_grain <= now + self.max_period {
+                self.add_grain(ratio as f64, preserve_formants);
+            }
+            let position = self.written.wrapping_sub(self.latency) & self.mask;
+            self.lbuf[n] = std::mem::take(&mut self.output[0][position]);
+            self.rbuf[n] = std::mem::take(&mut self.output[1][position]);
+        }
+        (&self.lbuf, &self.rbuf)
+    }
+
+    // Hann windowed, overlapping so the windows add up to about 1
+    fn add_grain(&mut self, ratio: f64, preserve_formants: bool) {
+        let center = self.next_grain;
+        // a whole number of periods from the source of the grain before, so the grains line up
+        while self.grain_source + self.period / 2.0 < center {
+            self.grain_source += self.period;
+        }
+        // (half of the length in the output, input frames per output frame, spacing, gain)
+        let (half, step, spacing, gain) = if preserve_formants {
+            (self.period, 1.0, self.period / ratio, 1.0 / ratio)
+        } else {
+            let half = self.period.min(self.max_period * ratio) / ratio;
+            (half, ratio, half, 1.0)
+        };
+        let start = (center - half).ceil() as i64;
+        let end = (center + half).floor() as i64;
+        for frame in start..=end {
+            let phase = (frame as f64 - (center - half)) / (2.0 * half);
+            let window = 0.5 - 0.5 * (std::f64::consts::TAU * phase).cos();
+            let source = self.grain_source + (frame as f64 - center) * step;
+            let weight = (window * gain) as f32;
+            let position = frame as usize & self.mask;
+            for side in 0..2 {
+                let sample = read(&self.input[side], self.mask, source);
+                self.output[side][position] += sample * weight;
+            }
+        }
+        self.next_grain += spacing;
+    }
+
+    // The period of the latest sound in frames, none without a clear pitch, with the normalized
+    // differences of YIN
+    fn detect_period(&mut self) -> Option<f64> {
+        let max_lag = self.differences.len() - 1;
+        let len = self.decimated.len();
+        for (i, value) in self.decimated.iter_mut().enumerate() {
+            let end = self.written.wrapping_sub((len - i - 1) * self.decimation);
+            *value = (0..self.decimation)
+                .map(|k| {
+                    let position = end.wrapping_sub(k + 1) & self.mask;
+                    self.input[0][position] + self.input[1][position]
+                })
+                .sum();
+        }
+        let mut sum = 0.0;
+        self.differences[0] = 1.0;
+        for lag in 1..=max_lag {
+            let difference: f32 = (0..max_lag)
+                .map(|i| self.decimated[i] - self.decimated[i + lag])
+                .map(|d| d * d)
+                .sum();
+            sum += difference;
+            self.differences[lag] = if sum > 0.0 {
+                difference * lag as f32 / sum
+            } else {
+                1.0
+            };
+        }
+        let min_lag = (self.sample_rate as f32 / MAX_FREQUENCY) as usize / self.decimation;
+        let mut lag =
+            (min_lag.max(2)..max_lag).find(|&lag| self.differences[lag] < DETECTION_THRESHOLD)?;
+        while lag + 1 < max_lag && self.differences[lag + 1] < self.differences[lag] {
+            lag += 1;
+        }
+        // between the lags around the minimum
+        let (before, at, after) = (
+            self.differences[lag - 1],
+            self.differences[lag],
+            self.differences[lag + 1],
+        );
+        let curvature = before - 2.0 * at + after;
+        let offset = if curvature > 0.0 {
+            0.5 * (before - after) / curvature
+        } else {
+            0.0
+        };
+        Some((lag as f32 + offset) as f64 * self.decimation as f64)
+    }
+}
+
+// Between the frames around `position`
+fn read(ring: &[f32], mask: usize, position: f64) -> f32 {
+    let floor = position.floor();
+    let fraction = (position - floor) as f32;
+    let index = floor as i64 as usize;
+    let (a, b) = (ring[index & mask], ring[index.wrapping_add(1) & mask]);
+    a + (b - a) * fraction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PitchShifts, Shift};
+
+    // Of 220 Hz
+    fn sawtooth(sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| (220.0 * n as f32 / sample_rate as f32).fract() * 2.0 - 1.0)
+            .collect()
+    }
+
+    // Of the frequency in the buffer
+    fn magnitude(buffer: &[f32], frequency: f32, sample_rate: u32) -> f32 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (n, sample) in buffer.iter().enumerate() {
+            let phase = std::f32::consts::TAU * frequency * n as f32 / sample_rate as f32;
+            re += sample * phase.cos();
+            im += sample * phase.sin();
+        }
+        (re * re + im * im).sqrt() * 2.0 / buffer.len() as f32
+    }
+
+    #[test]
+    fn shifts_by_semitones() {
+        for preserve_formants in [false, true] {
+            for semitones in [12.0, 7.0, -7.0] {
+                let mut shifts = PitchShifts::default();
+                shifts.set_sample_rate(48000);
+                shifts.set(vec![Shift {
+                    node: 1,
+                    semitones,
+                    preserve_formants,
+                }]);
+                let input = sawtooth(48000, 48000);
+                let (output, _) = shifts.process(1, &input, &input, 1.0);
+                // after the latency and the pitch is found
+                let output = &output[12000..];
+                let shifted = 220.0 * 2f32.powf(semitones / 12.0);
+                assert!(magnitude(output, shifted, 48000) > 0.2, "{semitones}");
+                assert!(magnitude(output, 220.0, 48000) < 0.02, "{semitones}");
+            }
+        }
+    }
+
+    #[test]
+    fn follows_the_nodes() {
+        let mut shifts = PitchShifts::default();
+        let shift = |node: usize| Shift {
+            node,
+            semitones: 3.0,
+            preserve_formants: false,
+        };
+        shifts.set(vec![shift(2), shift(0), shift(2)]);
+        assert_eq!(shifts.get(), [shift(0), shift(2)]);
+        let input = [1.0; 4];
+        let (output, _) = shifts.process(1, &input, &input, 1.0);
+        assert_eq!(output, input);
+
+        shifts.move_node(2, 0);
+        assert_eq!(shifts.get(), [shift(0), shift(1)]);
+        shifts.remove_node(0);
+        shifts.set_shift(shift(3));
+        assert_eq!(shifts.get(), [shift(0), shift(3)]);
+        shifts.remove_shift(0);
+        assert_eq!(shifts.get(), [shift(3)]);
+    }
+}
@@ -0,0 +1,220 @@
+use super::node::RenderPtr;
+use crate::audio::realtime;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// A node travels to a worker with the buffers it renders into, and back with them filled
+struct Job {
+    index: usize,
+    kind: String,
+    node: RenderPtr,
+    len: usize,
+    lbuf: Vec<f32>,
+    rbuf: Vec<f32>,
+    time: Duration,
+}
+
+impl Job {
+    fn run(&mut self) {
+        self.lbuf.resize(self.len, 0.0);
+        self.rbuf.resize(self.len, 0.0);
+        self.lbuf.fill(0.0);
+        self.rbuf.fill(0.0);
+        let start = Instant::now();
+        // a node that panics is silent for the buffer, the job still comes back so whoever
+        // waits for it doesn't wait forever
+        let rendered = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.node.render_additive(&mut self.lbuf, &mut self.rbuf);
+        }));
+        if rendered.is_err() {
+            tracing::error!("A {} node panicked while rendering", self.kind);
+            self.lbuf.fill(0.0);
+            self.rbuf.fill(0.0);
+        }
+        self.time = start.elapsed();
+    }
+}
+
+// Renders the nodes of a buffer on worker threads and on the calling thread at once. Nodes
+// don't feed into each other, each one only adds to the master bus, so any of them can render
+// anywhere as long as the results are summed in order.
+pub struct WorkerPool {
+    job_tx: mpsc::Sender<Job>,
+    done_rx: mpsc::Receiver<Job>,
+    // Buffers go back and forth with the jobs, so none get allocated once they're big enough
+    spare_bufs: Vec<(Vec<f32>, Vec<f32>)>,
+    done: Vec<Option<Job>>,
+}
+
+impl WorkerPool {
+    pub fn new(num_workers: usize) -> Self {
+        let (job_tx, job_rx) = mpsc::channel::<Job>();
+        let (done_tx, done_rx) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        for n in 0..num_workers {
+            let job_rx = Arc::clone(&job_rx);
+            let done_tx = done_tx.clone();
+            std::thread::Builder::new()
+                .name(format!("render-worker-{n}"))
+                .spawn(move || {
+                    let mut promoted = false;
+                    loop {
+                        // the lock is only held while waiting, never while rendering
+                        let job = job_rx
+                            .lock()
+                            .map_err(|_| ())
+                            .and_then(|rx| rx.recv().map_err(|_| ()));
+                        let Ok(mut job) = job else {
+                            break;
+                        };
+                        realtime::promote_current_thread_once(&mut promoted);
+                        job.run();
+                        if done_tx.send(job).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .expect("Failed to spawn a render worker");
+        }
+        Self {
+            job_tx,
+            done_rx,
+            spare_bufs: Vec::new(),
+            done: Vec::new(),
+        }
+    }
+
+    // Renders `len` frames of every node, then calls `mix` with each node's index, buffers and
+    // render time in the order of the nodes
+    pub fn render<F>(&mut self, nodes: &mut Vec<(String, RenderPtr)>, len: usize, mut mix: F)
+    where
+        F: FnMut(usize, &[f32], &[f32], Duration),
+    {
+        let num_nodes = nodes.len();
+        let mut jobs = nodes.drain(..).enumerate().map(|(index, (kind, node))| {
+            let (lbuf, rbuf) = self.spare_bufs.pop().unwrap_or_default();
+            Job {
+                index,
+                kind,
+                node,
+                len,
+                lbuf,
+                rbuf,
+                time: Duration::ZERO,
+            }
+        });
+        // the calling thread takes the first node instead of just waiting
+        let Some(mut own_job) = jobs.next() else {
+            return;
+        };
+        for job in jobs {
+            self.job_tx
+                .send(job)
+                .expect("The render workers stopped unexpectedly");
+        }
+        own_job.run();
+
+        self.done.resize_with(num_nodes, || None);
+        self.done[0] = Some(own_job);
+        for _ in 1..num_nodes {
+            let job = self
+                .done_rx
+                .recv()
+                .expect("A render worker stopped unexpectedly");
+            let index = job.index;
+            self.done[index] = Some(job);
+        }
+        for job in self.done.drain(..) {
+            let job = job.expect("Every node was rendered");
+            mix(job.index, &job.lbuf, &job.rbuf, job.time);
+            nodes.push((job.kind, job.node));
+            self.spare_bufs.push((job.lbuf, job.rbuf));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WorkerPool;
+    use crate::{
+        deser::{DeserializationResult, SerializationResult},
+        json::JsonUpdater,
+        midi,
+        path::VirtualPaths,
+        render::{
+            command::ResponseCallback,
+            node::{Render, RenderPtr, RequestKind},
+        },
+    };
+
+    // Renders its own value, so the mix shows where every sample came from, NaN panics
+    struct Constant(f32);
+
+    impl Render for Constant {
+        fn render_additive(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
+            assert!(!self.0.is_nan(), "the node broke");
+            lbuf.iter_mut().for_each(|x| *x += self.0);
+            rbuf.iter_mut().for_each(|x| *x -= self.0);
+        }
+        fn reset_rendering(&mut self) {}
+        fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+        fn set_sample_rate(&mut self, _sample_rate: u32) {}
+        fn receive_midi_message(&mut self, _message: &midi::Message) {}
+        fn panic(&mut self) {}
+        fn set_global_transposition(&mut self, _transposition: i8) {}
+        fn set_json_updater(&mut self, _updater: JsonUpdater) {}
+        fn process_request(&mut self, _kind: RequestKind, _cb: ResponseCallback) {}
+        fn serialize(&self) -> SerializationResult {
+            Ok(serde_json::Value::Null)
+        }
+        fn deserialize(&mut self, _source: &serde_json::Value) -> DeserializationResult {
+            Ok(())
+        }
+        fn clone_node(&self) -> RenderPtr {
+            Box::new(Constant(self.0))
+        }
+    }
+
+    #[test]
+    fn renders_every_node_in_order() {
+        let mut pool = WorkerPool::new(3);
+        let mut nodes: Vec<(String, RenderPtr)> = (0..7)
+            .map(|i| {
+                (
+                    format!("node {i}"),
+                    Box::new(Constant(i as f32)) as RenderPtr,
+                )
+            })
+            .collect();
+        for len in [64, 128] {
+            let mut mixed = Vec::new();
+            pool.render(&mut nodes, len, |index, lbuf, rbuf, _| {
+                assert_eq!((lbuf.len(), rbuf.len()), (len, len));
+                mixed.push((index, lbuf[0], rbuf[len - 1]));
+            });
+            let expected: Vec<_> = (0..7).map(|i| (i, i as f32, -(i as f32))).collect();
+            assert_eq!(mixed, expected);
+        }
+        let kinds: Vec<_> = nodes.iter().map(|(kind, _)| kind.as_str()).collect();
+        assert_eq!(kinds[0], "node 0");
+        assert_eq!(kinds[6], "node 6");
+    }
+
+    #[test]
+    fn panicking_nodes_are_silent() {
+        let mut pool = WorkerPool::new(2);
+        let mut nodes: Vec<(String, RenderPtr)> = [1.0, f32::NAN, 3.0]
+            .into_iter()
+            .map(|value| ("node".to_owned(), Box::new(Constant(value)) as RenderPtr))
+            .collect();
+        let mut mixed = Vec::new();
+        pool.render(&mut nodes, 16, |index, lbuf, _, _| {
+            mixed.push((index, lbuf[0]))
+        });
+        assert_eq!(mixed, vec![(0, 1.0), (1, 0.0), (2, 3.0)]);
+        assert_eq!(nodes.len(), 3);
+    }
+}
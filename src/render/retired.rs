@@ -0,0 +1,111 @@
+// Nodes a new setup replaced, still playing the notes that were held when it came, so switching
+// sounds mid-song doesn't cut off a sustained chord. They get the note offs and the pedals but no
+// new notes, and go once they are silent, or fade out when they ring for too long. Once nothing
+// is held they are let go of, note offs the zones and layers changed on the way don't hang.
+
+use super::node::RenderPtr;
+use crate::midi::{self, held::Held};
+
+const RING_OUT_SECONDS: f32 = 10.0;
+// At the end of the ring out
+const FADE_SECONDS: f32 = 0.5;
+// Of the peak, -80 dB
+const SILENCE: f32 = 0.0001;
+// A node silent for this long is done
+const SILENT_SECONDS: f32 = 0.1;
+
+struct Node {
+    node: RenderPtr,
+    // What it had in the mix and the output pair it played on
+    volume: f32,
+    pair: usize,
+    // Frames rendered since it was replaced and of them the silent ones at the end
+    age: usize,
+    silent: usize,
+}
+
+#[derive(Default)]
+pub struct Retired {
+    nodes: Vec<Node>,
+    // On the input
+    held: Held,
+    lbuf: Vec<f32>,
+    rbuf: Vec<f32>,
+}
+
+impl Retired {
+    pub fn add(&mut self, mut node: RenderPtr, volume: f32, pair: usize) {
+        if self.held.is_empty() {
+            node.release();
+        }
+        self.nodes.push(Node {
+            node,
+            volume,
+            pair,
+            age: 0,
+            silent: 0,
+        });
+    }
+
+    // Everything but new notes, those only count as held
+    pub fn receive_midi_message(&mut self, message: &midi::Message) {
+        let was_held = !self.held.is_empty();
+        self.held.push(message);
+        if let midi::MessageKind::NoteOn { velocity, .. } = message.kind {
+            if velocity > 0 {
+                return;
+            }
+        }
+        for node in &mut self.nodes {
+            node.node.receive_midi_message(message);
+            if was_held && self.held.is_empty() {
+                node.node.release();
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+    }
+
+    // `mix` gets (output pair, volume, left, right) of every node
+    pub fn render(
+        &mut self,
+        len: usize,
+        sample_rate: u32,
+        mut mix: impl FnMut(usize, f32, &[f32], &[f32]),
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        if self.lbuf.len() < len {
+            self.lbuf.resize(len, 0.0);
+            self.rbuf.resize(len, 0.0);
+        }
+        let (lbuf, rbuf) = (&mut self.lbuf[..len], &mut self.rbuf[..len]);
+        let ring_out = (RING_OUT_SECONDS * sample_rate as f32) as usize;
+        let fade = (FADE_SECONDS * sample_rate as f32).max(1.0);
+        let silent = (SILENT_SECONDS * sample_rate as f32) as usize;
+        for node in &mut self.nodes {
+            lbuf.fill(0.0);
+            rbuf.fill(0.0);
+            node.node.render_additive(lbuf, rbuf);
+            for (n, (l, r)) in lbuf.iter_mut().zip(rbuf.iter_mut()).enumerate() {
+                let left = ring_out.saturating_sub(node.age + n) as f32;
+                if left < fade {
+                    *l *= left / fade;
+                    *r *= left / fade;
+                }
+            }
+            let peak = lbuf
+                .iter()
+                .chain(rbuf.iter())
+                .fold(0.0f32, |peak, x| peak.max(x.abs()));
+            node.silent = if peak < SILENCE { node.silent + len } else { 0 };
+            node.age += len;
+            mix(node.pair, node.volume, lbuf, rbuf);
+        }
+        self.nodes
+            .retain(|node| node.age < ring_out && node.silent < silent);
+    }
+}
@@ -0,0 +1,103 @@
+// Microtuning for synths that only play 12-TET: a note sounds on the key closest to its pitch
+// and the pitch wheel bends it the rest of the way. There's one pitch wheel, so it's right for
+// the note played last, which is what a mono line needs; the notes of a chord are only in tune
+// as far as they're off by the same amount.
+
+use crate::synth::tuning::Tuning;
+
+// The pitch bend range the synths start with, in semitones
+const PITCH_BEND_RANGE: f32 = 2.0;
+
+const PITCH_WHEEL_CENTER: u16 = 0x2000;
+const PITCH_WHEEL_MAX: u16 = 0x3FFF;
+
+#[derive(Debug, Clone)]
+pub struct Retuning {
+    tuning: Tuning,
+    // (key, key it sounds on) of the held notes
+    notes: Vec<(u8, u8)>,
+    // As played
+    pitch_wheel: u16,
+    // Semitones the note played last is off its key
+    bend: f32,
+}
+
+impl Retuning {
+    pub fn new(tuning: Tuning) -> Self {
+        Self {
+            tuning,
+            notes: Vec::new(),
+            pitch_wheel: PITCH_WHEEL_CENTER,
+            bend: 0.0,
+        }
+    }
+
+    // The key the note sounds on and the pitch wheel for it, none for a key the tuning leaves
+    // out
+    pub fn note_on(&mut self, key: u8) -> Option<(u8, u16)> {
+        let pitch = self.tuning.pitch(key)?;
+        let sounding = pitch.round().clamp(0.0, 127.0);
+        self.bend = pitch - sounding;
+        self.notes.retain(|&(held, _)| held != key);
+        self.notes.push((key, sounding as u8));
+        Some((sounding as u8, self.bent_pitch_wheel()))
+    }
+
+    // The key the note sounds on, its own one for a note played before the tuning was set
+    pub fn note_off(&mut self, key: u8) -> u8 {
+        match self.notes.iter().position(|&(held, _)| held == key) {
+            Some(index) => self.notes.remove(index).1,
+            None => key,
+        }
+    }
+
+    // The played pitch wheel with the bend of the note played last
+    pub fn pitch_wheel(&mut self, value: u16) -> u16 {
+        self.pitch_wheel = value;
+        self.bent_pitch_wheel()
+    }
+
+    // For going back to 12-TET
+    pub fn played_pitch_wheel(&self) -> u16 {
+        self.pitch_wheel
+    }
+
+    fn bent_pitch_wheel(&self) -> u16 {
+        let offset = self.bend / PITCH_BEND_RANGE * PITCH_WHEEL_CENTER as f32;
+        (self.pitch_wheel as f32 + offset)
+            .round()
+            .clamp(0.0, PITCH_WHEEL_MAX as f32) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Retuning;
+    use crate::synth::tuning::Tuning;
+
+    #[test]
+    fn bends_to_the_pitch() {
+        // quarter tones from middle C, then only every other key of them
+        let scale = "Quarter tones\n2\n50.0\n100.0\n";
+        let mapping = "2\n0\n127\n60\n60\n261.6255653\n0\n0\nx\n";
+        let tuning = Tuning::parse(scale, Some(mapping)).unwrap();
+        let mut retuning = Retuning::new(Tuning::parse(scale, None).unwrap());
+
+        assert_eq!(retuning.note_on(60), Some((60, 0x2000)));
+        // a quarter tone up goes to the next key and down an eighth of the wheel from there
+        assert_eq!(retuning.note_on(61), Some((61, 0x1800)));
+        assert_eq!(retuning.note_on(62), Some((61, 0x2000)));
+        assert_eq!(retuning.pitch_wheel(0x3000), 0x3000);
+        assert_eq!(retuning.note_on(63), Some((62, 0x2800)));
+        assert_eq!(retuning.pitch_wheel(0x3FFF), 0x37FF);
+        assert_eq!(retuning.played_pitch_wheel(), 0x3FFF);
+
+        assert_eq!(retuning.note_off(62), 61);
+        assert_eq!(retuning.note_off(62), 62);
+
+        let mut retuning = Retuning::new(tuning);
+        assert_eq!(retuning.note_on(60), Some((60, 0x2000)));
+        assert_eq!(retuning.note_on(61), None);
+        assert_eq!(retuning.note_on(62), Some((61, 0x2000)));
+    }
+}
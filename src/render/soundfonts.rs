@@ -0,0 +1,146 @@
+// Soundfonts parsed once and shared by every node playing them, so a second RustySynth with the
+// same piano costs no memory and switching to it is instant. FluidLite and sfizz load their files
+// into their C libraries, which can't share them; for an .sfz preloading only reads the samples
+// once so they come from the OS file cache.
+
+use crate::sfz;
+use rustysynth::SoundFont;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+use tracing::{info, warn};
+
+pub type SoundfontCache = FileCache<SoundFont>;
+
+// Loaded files by their real path and when they were modified, a changed file gets loaded anew.
+// They stay in memory as long as a node holds them, preloaded ones for good.
+pub struct FileCache<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+struct Inner<T> {
+    loaded: HashMap<(PathBuf, Option<SystemTime>), Weak<T>>,
+    preloaded: Vec<Arc<T>>,
+}
+
+impl<T> Default for FileCache<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                loaded: HashMap::new(),
+                preloaded: Vec::new(),
+            })),
+        }
+    }
+}
+
+impl<T> Clone for FileCache<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> FileCache<T> {
+    // The loaded file or the one `load` makes of it, without holding the cache while loading
+    pub fn get_or_load<F>(&self, path: &Path, load: F) -> Result<Arc<T>, String>
+    where
+        F: FnOnce(&Path) -> Result<T, String>,
+    {
+        let real_path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let key = (real_path, modified);
+        if let Some(loaded) = self.get(&key) {
+            return Ok(loaded);
+        }
+        let loaded = Arc::new(load(path)?);
+        let mut inner = self.inner.lock().unwrap();
+        // another node may have been quicker
+        if let Some(other) = inner.loaded.get(&key).and_then(Weak::upgrade) {
+            return Ok(other);
+        }
+        inner.loaded.retain(|_, loaded| loaded.strong_count() > 0);
+        inner.loaded.insert(key, Arc::downgrade(&loaded));
+        Ok(loaded)
+    }
+
+    fn get(&self, key: &(PathBuf, Option<SystemTime>)) -> Option<Arc<T>> {
+        let inner = self.inner.lock().unwrap();
+        inner.loaded.get(key).and_then(Weak::upgrade)
+    }
+
+    fn keep(&self, loaded: Arc<T>) {
+        self.inner.lock().unwrap().preloaded.push(loaded);
+    }
+}
+
+impl SoundfontCache {
+    pub fn load(&self, path: &Path) -> Result<Arc<SoundFont>, String> {
+        self.get_or_load(path, |path| {
+            let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+            SoundFont::new(&mut std::io::BufReader::new(file)).map_err(|e| e.to_string())
+        })
+    }
+
+    // Loads the files of real paths one after the other on a thread of its own
+    pub fn preload(&self, paths: Vec<PathBuf>) -> JoinHandle<()> {
+        let cache = self.clone();
+        thread::spawn(move || {
+            for path in paths {
+                let is_sfz = path
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("sfz"));
+                let res = match is_sfz {
+                    true => read_sfz_samples(&path),
+                    false => cache.load(&path).map(|font| cache.keep(font)),
+                };
+                match res {
+                    Ok(()) => info!("Preloaded {path:?}"),
+                    Err(e) => warn!("Could not preload {path:?}: {e}"),
+                }
+            }
+        })
+    }
+}
+
+fn read_sfz_samples(path: &Path) -> Result<(), String> {
+    let inspection = sfz::inspect_file(path).map_err(|e| format!("{e:?}"))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for sample in inspection.samples.iter().filter(|sample| !sample.missing) {
+        let mut file = std::fs::File::open(dir.join(&sample.path)).map_err(|e| e.to_string())?;
+        std::io::copy(&mut file, &mut std::io::sink()).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileCache;
+    use std::{path::Path, sync::Arc};
+
+    #[test]
+    fn shares_loaded_files() {
+        let cache = FileCache::<String>::default();
+        let load = |path: &Path| Ok(path.display().to_string());
+        let piano = cache.get_or_load(Path::new("piano.sf2"), load).unwrap();
+        let again = cache
+            .get_or_load(Path::new("piano.sf2"), |_| Err("loaded again".into()))
+            .unwrap();
+        assert!(Arc::ptr_eq(&piano, &again));
+
+        // once no node holds it, it's gone
+        drop((piano, again));
+        let res = cache.get_or_load(Path::new("piano.sf2"), |_| Err("gone".into()));
+        assert_eq!(res, Err("gone".into()));
+
+        let organ = cache.get_or_load(Path::new("organ.sf2"), load).unwrap();
+        cache.keep(organ);
+        let kept = cache.get_or_load(Path::new("organ.sf2"), |_| Err("not kept".into()));
+        assert_eq!(kept.as_deref().map(String::as_str), Ok("organ.sf2"));
+    }
+}
@@ -0,0 +1,207 @@
+// Polyphony limit for a node. A sustain pedal chord can start more voices than a small board
+// renders in time, so past the limit a note takes the place of one already sounding. The synth
+// only lets a note go on its note off when the damper pedal is up, so with a limit the node
+// holds the damper pedal itself and the notes it sustains count as sounding.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stealing {
+    Oldest,
+    // The one played with the lowest velocity, the oldest of those
+    Quietest,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    note: u8,
+    velocity: u8,
+    key_down: bool,
+}
+
+#[derive(Debug)]
+pub struct Voices {
+    limit: Option<u16>,
+    stealing: Stealing,
+    // Oldest first
+    sounding: Vec<Voice>,
+    damper: bool,
+}
+
+impl Default for Voices {
+    fn default() -> Self {
+        Self {
+            limit: None,
+            stealing: Stealing::Oldest,
+            sounding: Vec::new(),
+            damper: false,
+        }
+    }
+}
+
+// A copy starts with no notes sounding
+impl Clone for Voices {
+    fn clone(&self) -> Self {
+        Self {
+            limit: self.limit,
+            stealing: self.stealing,
+            ..Default::default()
+        }
+    }
+}
+
+impl Voices {
+    pub fn limit(&self) -> Option<u16> {
+        self.limit
+    }
+
+    pub fn stealing(&self) -> Stealing {
+        self.stealing
+    }
+
+    // The notes to send note offs for now, the ones over a lower limit and the ones the damper
+    // pedal holds when there's no limit anymore. None for a limit of 0.
+    pub fn set_limit(&mut self, limit: Option<u16>) -> Option<Vec<u8>> {
+        if limit == Some(0) {
+            return None;
+        }
+        self.limit = limit;
+        let released = match limit {
+            Some(limit) => (limit as usize..self.sounding.len())
+                .filter_map(|_| self.steal())
+                .collect(),
+            None => {
+                let released = self.sounding.iter().filter(|voice| !voice.key_down);
+                let released = released.map(|voice| voice.note).collect();
+                self.sounding.clear();
+                self.damper = false;
+                released
+            }
+        };
+        Some(released)
+    }
+
+    pub fn set_stealing(&mut self, stealing: Stealing) {
+        self.stealing = stealing;
+    }
+
+    // Whether damper pedal messages go to `set_damper` instead of the synth
+    pub fn holds_damper(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    // The note to send a note off for before this one, if it takes its place
+    pub fn note_on(&mut self, note: u8, velocity: u8) -> Option<u8> {
+        let limit = self.limit? as usize;
+        self.sounding.retain(|voice| voice.note != note);
+        let stolen = if self.sounding.len() >= limit {
+            self.steal()
+        } else {
+            None
+        };
+        self.sounding.push(Voice {
+            note,
+            velocity,
+            key_down: true,
+        });
+        stolen
+    }
+
+    // Whether the note off goes to the synth now
+    pub fn note_off(&mut self, note: u8) -> bool {
+        if !self.damper {
+            self.sounding.retain(|voice| voice.note != note);
+            return true;
+        }
+        match self.sounding.iter_mut().find(|voice| voice.note == note) {
+            Some(voice) => {
+                voice.key_down = false;
+                false
+            }
+            None => true,
+        }
+    }
+
+    // The notes to send note offs for now
+    pub fn set_damper(&mut self, value: u8) -> Vec<u8> {
+        self.damper = value >= 64;
+        if self.damper {
+            return vec![];
+        }
+        let released = self.sounding.iter().filter(|voice| !voice.key_down);
+        let released = released.map(|voice| voice.note).collect();
+        self.sounding.retain(|voice| voice.key_down);
+        released
+    }
+
+    // After all notes off or a reset of all controllers
+    pub fn reset(&mut self) {
+        self.sounding.clear();
+        self.damper = false;
+    }
+
+    fn steal(&mut self) -> Option<u8> {
+        let index = match self.stealing {
+            Stealing::Oldest => 0,
+            Stealing::Quietest => {
+                let quietest = self.sounding.iter().map(|voice| voice.velocity).min()?;
+                self.sounding
+                    .iter()
+                    .position(|voice| voice.velocity == quietest)?
+            }
+        };
+        (index < self.sounding.len()).then(|| self.sounding.remove(index).note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stealing, Voices};
+
+    #[test]
+    fn steals_past_the_limit() {
+        let mut voices = Voices::default();
+        assert_eq!(voices.note_on(60, 100), None);
+        assert!(!voices.holds_damper());
+        assert_eq!(voices.set_limit(Some(0)), None);
+        assert_eq!(voices.set_limit(Some(3)), Some(vec![]));
+
+        voices.note_on(60, 100);
+        voices.note_on(62, 20);
+        voices.note_on(64, 80);
+        assert_eq!(voices.note_on(65, 90), Some(60));
+        voices.set_stealing(Stealing::Quietest);
+        assert_eq!(voices.note_on(67, 90), Some(62));
+        // played again, it doesn't take another voice
+        assert_eq!(voices.note_on(67, 10), None);
+        assert!(voices.note_off(64));
+        assert_eq!(voices.note_on(69, 50), None);
+        assert_eq!(voices.set_limit(Some(1)), Some(vec![67, 69]));
+        assert_eq!(voices.note_on(71, 50), Some(65));
+    }
+
+    #[test]
+    fn holds_the_damper() {
+        let mut voices = Voices::default();
+        voices.set_limit(Some(2));
+        assert!(voices.holds_damper());
+        voices.set_damper(127);
+        voices.note_on(60, 100);
+        assert!(!voices.note_off(60));
+        voices.note_on(62, 100);
+        // sustained notes sound and get stolen too
+        assert_eq!(voices.note_on(64, 100), Some(60));
+        assert!(voices.note_off(60));
+        assert!(!voices.note_off(62));
+        assert_eq!(voices.set_damper(0), [62]);
+        assert!(voices.note_off(64));
+
+        voices.set_damper(127);
+        voices.note_on(65, 100);
+        voices.note_off(65);
+        voices.note_on(67, 100);
+        assert_eq!(voices.set_limit(None), Some(vec![65]));
+        assert!(!voices.holds_damper());
+        assert!(voices.note_off(67));
+    }
+}
@@ -0,0 +1,233 @@
+// Splits and layers across the nodes. A zone takes the notes of a key range (and a channel)
+// to a node, transposed and with scaled velocities. Nodes no zone points to get every message
+// like before, the others only get what their zones let through.
+
+use super::moved_node;
+use crate::midi::{Message, MessageKind};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Zone {
+    pub name: String,
+    // Inclusive
+    pub low_key: u8,
+    pub high_key: u8,
+    // Every channel without one
+    pub channel: Option<u8>,
+    // Id of the node
+    pub node: usize,
+    pub transpose: i8,
+    pub velocity_scale: f32,
+}
+
+impl Zone {
+    fn takes_channel(&self, channel: u8) -> bool {
+        self.channel
+            .is_none_or(|zone_channel| zone_channel == channel)
+    }
+
+    fn takes(&self, channel: u8, key: u8) -> bool {
+        self.takes_channel(channel) && (self.low_key..=self.high_key).contains(&key)
+    }
+
+    fn transposed(&self, key: u8) -> Option<u8> {
+        let note = key as i16 + self.transpose as i16;
+        (0..=127).contains(&note).then_some(note as u8)
+    }
+
+    fn scaled(&self, velocity: u8) -> u8 {
+        ((velocity as f32 * self.velocity_scale).round() as u8).clamp(1, 127)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Zones {
+    zones: Vec<Zone>,
+    // (channel, key) of a sounding note to the (node, note) it went to, so the note off goes
+    // there even when the zones changed in between
+    sounding: HashMap<(u8, u8), Vec<(usize, u8)>>,
+}
+
+impl Zones {
+    pub fn get(&self) -> &[Zone] {
+        &self.zones
+    }
+
+    pub fn set(&mut self, zones: Vec<Zone>) {
+        self.zones = zones;
+    }
+
+    pub fn is_zoned(&self, node: usize) -> bool {
+        self.zones.iter().any(|zone| zone.node == node)
+    }
+
+    // The zones of the node go with it, the ones of the nodes after it move up
+    pub fn remove_node(&mut self, node: usize) {
+        self.zones.retain(|zone| zone.node != node);
+        for zone in &mut self.zones {
+            if zone.node > node {
+                zone.node -= 1;
+            }
+        }
+        self.sounding.clear();
+    }
+
+    // The zones and the sounding notes follow the node, the nodes in between shift by one
+    pub fn move_node(&mut self, id: usize, new_id: usize) {
+        for zone in &mut self.zones {
+            zone.node = moved_node(zone.node, id, new_id);
+        }
+        for (node, _) in self.sounding.values_mut().flatten() {
+            *node = moved_node(*node, id, new_id);
+        }
+    }
+
+    pub fn clear_sounding(&mut self) {
+        self.sounding.clear();
+    }
+
+    // The messages for the nodes with zones
+    pub fn route(&mut self, message: &Message, routed: &mut Vec<(usize, Message)>) {
+        let channel = message.channel;
+        let with_note = |node, kind| {
+            let mut message = message.clone();
+            message.kind = kind;
+            (node, message)
+        };
+        match message.kind {
+            MessageKind::NoteOn { note, velocity } if velocity > 0 => {
+                let sounding = self.sounding.entry((channel, note)).or_default();
+                for zone in self.zones.iter().filter(|zone| zone.takes(channel, note)) {
+                    let Some(zone_note) = zone.transposed(note) else {
+                        continue;
+                    };
+                    sounding.push((zone.node, zone_note));
+                    let kind = MessageKind::NoteOn {
+                        note: zone_note,
+                        velocity: zone.scaled(velocity),
+                    };
+                    routed.push(with_note(zone.node, kind));
+                }
+            }
+            MessageKind::NoteOn { note, velocity } | MessageKind::NoteOff { note, velocity } => {
+                let Some(sounding) = self.sounding.remove(&(channel, note)) else {
+                    return;
+                };
+                for (node, zone_note) in sounding {
+                    let kind = MessageKind::NoteOff {
+                        note: zone_note,
+                        velocity,
+                    };
+                    routed.push(with_note(node, kind));
+                }
+            }
+            MessageKind::PolyphonicAftertouch { note, pressure } => {
+                for &(node, zone_note) in self.sounding.get(&(channel, note)).into_iter().flatten()
+                {
+                    let kind = MessageKind::PolyphonicAftertouch {
+                        note: zone_note,
+                        pressure,
+                    };
+                    routed.push(with_note(node, kind));
+                }
+            }
+            // everything else goes to every node of the channel once
+            _ => {
+                let mut nodes: Vec<usize> = self
+                    .zones
+                    .iter()
+                    .filter(|zone| zone.takes_channel(channel))
+                    .map(|zone| zone.node)
+                    .collect();
+                nodes.sort_unstable();
+                nodes.dedup();
+                routed.extend(nodes.into_iter().map(|node| (node, message.clone())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Zone, Zones};
+    use crate::midi::{ControlChangeKind, Message, MessageKind};
+
+    fn zone(low_key: u8, high_key: u8, node: usize, transpose: i8) -> Zone {
+        Zone {
+            name: format!("{low_key}-{high_key}"),
+            low_key,
+            high_key,
+            channel: None,
+            node,
+            transpose,
+            velocity_scale: 1.0,
+        }
+    }
+
+    fn note_on(note: u8, velocity: u8) -> Message {
+        Message::new(0, MessageKind::NoteOn { note, velocity })
+    }
+
+    fn note_off(note: u8) -> Message {
+        let kind = MessageKind::NoteOff { note, velocity: 0 };
+        Message::new(0, kind)
+    }
+
+    fn route(zones: &mut Zones, message: Message) -> Vec<(usize, Message)> {
+        let mut routed = vec![];
+        zones.route(&message, &mut routed);
+        routed
+    }
+
+    #[test]
+    fn split_and_layer() {
+        let mut zones = Zones::default();
+        // bass an octave down on the left, a layer of two nodes on the right
+        let mut pad = zone(60, 127, 2, 0);
+        pad.velocity_scale = 0.5;
+        zones.set(vec![zone(0, 59, 0, -12), zone(60, 127, 1, 0), pad]);
+        assert!(zones.is_zoned(1) && !zones.is_zoned(3));
+
+        assert_eq!(route(&mut zones, note_on(48, 100)), [(0, note_on(36, 100))]);
+        assert_eq!(
+            route(&mut zones, note_on(64, 100)),
+            [(1, note_on(64, 100)), (2, note_on(64, 50))]
+        );
+        let kind = MessageKind::ControlChange {
+            kind: ControlChangeKind::DamperPedal,
+            value: 127,
+        };
+        let pedal = Message::new(0, kind);
+        let routed = route(&mut zones, pedal.clone());
+        assert_eq!(routed, [(0, pedal.clone()), (1, pedal.clone()), (2, pedal)]);
+
+        // the note off goes where the note on went, even with the zones changed
+        zones.set(vec![zone(0, 127, 1, 0)]);
+        assert_eq!(route(&mut zones, note_off(48)), [(0, note_off(36))]);
+        assert_eq!(
+            route(&mut zones, note_on(64, 0)),
+            [(1, note_off(64)), (2, note_off(64))]
+        );
+        assert_eq!(route(&mut zones, note_off(64)), []);
+    }
+
+    #[test]
+    fn removed_nodes() {
+        let mut zones = Zones::default();
+        zones.set(vec![zone(0, 59, 0, 0), zone(60, 127, 2, 0)]);
+        zones.remove_node(0);
+        assert_eq!(zones.get(), [zone(60, 127, 1, 0)]);
+    }
+
+    #[test]
+    fn moved_nodes() {
+        let mut zones = Zones::default();
+        zones.set(vec![zone(0, 59, 0, 0), zone(60, 127, 2, 0)]);
+        route(&mut zones, note_on(48, 100));
+        zones.move_node(0, 2);
+        assert_eq!(zones.get(), [zone(0, 59, 2, 0), zone(60, 127, 1, 0)]);
+        // the note off follows the node
+        assert_eq!(route(&mut zones, note_off(48)), [(2, note_off(48))]);
+    }
+}
@@ -0,0 +1,129 @@
+// The sounds and the mix of a performance as the clients see them: the render and controller
+// nodes, the drum machine, the pads and how the renderer spreads the input over the nodes. The
+// setlist has a file of its own.
+
+use crate::{
+    control,
+    files::FileError,
+    json::{self, Migration, MigrationError},
+    pads::{Action, Pad},
+    path::VirtualPaths,
+    render::command,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+const FIELDS: [&str; 17] = [
+    "nodes",
+    "zones",
+    "layered_instruments",
+    "selected_layered_instrument",
+    "modulation_routes",
+    "expression",
+    "bypassed",
+    "groups",
+    "output_routes",
+    "monitor_pairs",
+    "pitch_shifts",
+    "global_transposition",
+    "transposition_cc",
+    "octave_shift_triggers",
+    "drum_machine",
+    "controller",
+    "pads",
+];
+
+const SESSION_MIGRATIONS: &[Migration] = &[
+    // v0 -> v1: the version field was introduced, the layout stayed the same
+    |_| Ok(()),
+];
+
+// The session part of the state the clients get
+pub fn of_state(state: &Value) -> Value {
+    let session: Map<String, Value> = FIELDS
+        .iter()
+        .filter_map(|&field| Some((field.to_owned(), state.get(field)?.clone())))
+        .collect();
+    Value::Object(session)
+}
+
+// The requests bringing back the renderer, the controller and the pads of a session, none if it
+// doesn't look like one. The drum machine has its presets for that, sessions saved before the
+// pads were part of them keep the pads there are.
+pub fn actions(session: &Value) -> Option<Vec<Action>> {
+    let renderer = command::Setup::deserialize(session).ok()?;
+    let controller = control::command::Setup::deserialize(&session["controller"]).ok()?;
+    let mut actions = vec![
+        Action::Renderer(Box::new(command::RequestKind::SetSetup(renderer))),
+        Action::Controller(control::command::RequestKind::SetSetup(controller)),
+    ];
+    if let Some(pads) = session.get("pads") {
+        actions.push(Action::Pads(Vec::<Pad>::deserialize(pads).ok()?));
+    }
+    Some(actions)
+}
+
+// A session read from a file in the layout of this version
+pub fn migrate(session: Value) -> Result<Value, MigrationError> {
+    json::migrate(session, SESSION_MIGRATIONS)
+}
+
+// Written next to the file first, so stopping halfway leaves the last one as it was
+pub async fn save(
+    virtual_paths: &VirtualPaths,
+    path: &Path,
+    state: &Value,
+) -> Result<(), FileError> {
+    let path = virtual_paths
+        .translate(path)
+        .ok_or(FileError::InvalidPath)?;
+    let mut session = of_state(state);
+    json::set_version(&mut session, json::latest_version(SESSION_MIGRATIONS));
+    let source = serde_json::to_vec_pretty(&session).map_err(|e| FileError::Io(e.to_string()))?;
+    let partial = path.with_extension("partial");
+    tokio::fs::write(&partial, source).await?;
+    tokio::fs::rename(&partial, &path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::path::VirtualPaths;
+    use serde_json::json;
+    use std::path::{Path, PathBuf};
+
+    #[tokio::test]
+    async fn saves_the_session() {
+        let dir = std::env::temp_dir().join(format!("ami-session-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut virtual_paths = VirtualPaths::default();
+        virtual_paths.insert(PathBuf::from("beats:"), dir.clone());
+        let state = json!({
+            "nodes": [{ "kind": "OxiSynth", "instance": { "gain": 0.5 } }],
+            "drum_machine": { "tempo_bpm": 96.0 },
+            "setlist": { "current": 3 },
+        });
+
+        let path = Path::new("beats:/autosave.session");
+        super::save(&virtual_paths, path, &state).await.unwrap();
+        super::save(&virtual_paths, path, &state).await.unwrap();
+        let saved = std::fs::read(dir.join("autosave.session")).unwrap();
+        let saved: serde_json::Value = serde_json::from_slice(&saved).unwrap();
+        let expected = json!({
+            "version": 1,
+            "nodes": [{ "kind": "OxiSynth", "instance": { "gain": 0.5 } }],
+            "drum_machine": { "tempo_bpm": 96.0 },
+        });
+        assert_eq!(saved, expected);
+        // sessions from before the versions read like the latest ones
+        let mut unversioned = expected.clone();
+        unversioned.as_object_mut().unwrap().remove("version");
+        assert_eq!(super::migrate(unversioned).unwrap(), expected);
+        assert!(!dir.join("autosave.partial").exists());
+
+        let outside = Path::new("nowhere:/autosave.session");
+        assert!(super::save(&virtual_paths, outside, &state).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
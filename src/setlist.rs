@@ -0,0 +1,352 @@
+use crate::{
+    control::{self, node::midi_file_player},
+    deser::serialize,
+    files,
+    json::{self, update_fields_or_fail, JsonUpdateKind, Migration},
+    midi::{self, trigger::Trigger},
+    pads::Action,
+    path::VirtualPaths,
+};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path, path::PathBuf};
+
+const SETLIST_MIGRATIONS: &[Migration] = &[
+    // v0 -> v1: the version field was introduced, the layout stayed the same
+    |_| Ok(()),
+];
+
+// One song of a gig
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub name: String,
+    // Bring up the sounds of the song, like the actions of a pad
+    pub actions: Vec<Action>,
+    // Loaded into the MIDI file player node, it doesn't start playing on its own
+    pub backing_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestKind {
+    AddEntry(Entry),
+    SetEntry(usize, Entry),
+    RemoveEntry(usize),
+    // (from, to)
+    MoveEntry(usize, usize),
+    // Controller node id of the MIDI file player the backing files go to
+    SetPlayer(Option<usize>),
+    SetNextTrigger(Option<Trigger>),
+    SetPreviousTrigger(Option<Trigger>),
+    Select(usize),
+    Next,
+    Previous,
+    LoadSetlist(PathBuf),
+    SaveSetlist(PathBuf),
+}
+
+// What a setlist file holds, the position in it is not part of it
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct SetlistFile {
+    entries: Vec<Entry>,
+    player: Option<usize>,
+    next_trigger: Option<Trigger>,
+    previous_trigger: Option<Trigger>,
+}
+
+pub struct Setlist {
+    file: SetlistFile,
+    current: Option<usize>,
+    virtual_paths: VirtualPaths,
+}
+
+impl Setlist {
+    pub fn new(virtual_paths: VirtualPaths) -> Self {
+        Self {
+            file: SetlistFile::default(),
+            current: None,
+            virtual_paths,
+        }
+    }
+
+    // The request a footswitch press stands for, if the message is one
+    pub fn navigation_by(&self, message: &midi::Message) -> Option<RequestKind> {
+        let pressed = |trigger: Option<Trigger>| trigger.is_some_and(|t| t.is_pressed_by(message));
+        if pressed(self.file.next_trigger) {
+            Some(RequestKind::Next)
+        } else if pressed(self.file.previous_trigger) {
+            Some(RequestKind::Previous)
+        } else {
+            None
+        }
+    }
+
+    // Selecting is left to the caller like triggering pads, navigation requests only move the
+    // position and return the actions of the entry to run
+    pub fn process_request(&mut self, kind: RequestKind) -> (JsonUpdateKind, Vec<Action>) {
+        let index = match kind {
+            RequestKind::Select(index) => index,
+            RequestKind::Next => self.current.map_or(0, |current| current + 1),
+            RequestKind::Previous => match self.current {
+                Some(current) if current > 0 => current - 1,
+                _ => return (JsonUpdateKind::Failed, vec![]),
+            },
+            kind => return (self.edit(kind), vec![]),
+        };
+        match self.select(index) {
+            Some(actions) => (self.current_update(), actions),
+            None => (JsonUpdateKind::InvalidId, vec![]),
+        }
+    }
+
+    fn select(&mut self, index: usize) -> Option<Vec<Action>> {
+        let entry = self.file.entries.get(index)?;
+        let mut actions = entry.actions.clone();
+        if let (Some(path), Some(id)) = (&entry.backing_file, self.file.player) {
+            let kind = midi_file_player::RequestKind::LoadFile(path.clone());
+            actions.push(Action::Controller(
+                control::command::RequestKind::NodeRequest {
+                    id,
+                    kind: control::node::RequestKind::MidiFilePlayer(kind),
+                },
+            ));
+        }
+        self.current = Some(index);
+        Some(actions)
+    }
+
+    fn edit(&mut self, kind: RequestKind) -> JsonUpdateKind {
+        let entries = &mut self.file.entries;
+        match kind {
+            RequestKind::AddEntry(entry) => entries.push(entry),
+            RequestKind::SetEntry(index, entry) => match entries.get_mut(index) {
+                Some(e) => *e = entry,
+                None => return JsonUpdateKind::InvalidId,
+            },
+            RequestKind::RemoveEntry(index) => {
+                if index >= entries.len() {
+                    return JsonUpdateKind::InvalidId;
+                }
+                entries.remove(index);
+                self.current = match self.current {
+                    Some(current) if current > index => Some(current - 1),
+                    Some(current) if current == index => None,
+                    current => current,
+                };
+            }
+            RequestKind::MoveEntry(from, to) => {
+                if from >= entries.len() || to >= entries.len() {
+                    return JsonUpdateKind::InvalidId;
+                }
+                let entry = entries.remove(from);
+                entries.insert(to, entry);
+                // the position stays with the song
+                self.current = self.current.map(|current| match current {
+                    c if c == from => to,
+                    c if from < c && c <= to => c - 1,
+                    c if to <= c && c < from => c + 1,
+                    c => c,
+                });
+            }
+            RequestKind::SetPlayer(player) => self.file.player = player,
+            RequestKind::SetNextTrigger(trigger) => self.file.next_trigger = trigger,
+            RequestKind::SetPreviousTrigger(trigger) => self.file.previous_trigger = trigger,
+            RequestKind::LoadSetlist(path) => return self.load_from_file(&path),
+            RequestKind::SaveSetlist(path) => return self.save_to_file(&path),
+            RequestKind::Select(_) | RequestKind::Next | RequestKind::Previous => {
+                return JsonUpdateKind::Denied
+            }
+        }
+        self.setlist_update()
+    }
+
+    fn setlist_update(&self) -> JsonUpdateKind {
+        update_fields_or_fail(|updates| {
+            updates.push(("entries".to_owned(), serialize(&self.file.entries)?));
+            updates.push(("player".to_owned(), serialize(self.file.player)?));
+            updates.push((
+                "next_trigger".to_owned(),
+                serialize(self.file.next_trigger)?,
+            ));
+            updates.push((
+                "previous_trigger".to_owned(),
+                serialize(self.file.previous_trigger)?,
+            ));
+            updates.push(("current".to_owned(), serialize(self.current)?));
+            Ok(())
+        })
+    }
+
+    fn current_update(&self) -> JsonUpdateKind {
+        update_fields_or_fail(|updates| {
+            updates.push(("current".to_owned(), serialize(self.current)?));
+            Ok(())
+        })
+    }
+
+    fn load_from_file(&mut self, path: &Path) -> JsonUpdateKind {
+        if let Ok(path) = files::translate(&self.virtual_paths, path) {
+            if let Ok(file) = fs::read_to_string(path) {
+                if let Ok(setlist) = read_setlist(&file) {
+                    self.file = setlist;
+                    self.current = None;
+                    return self.setlist_update();
+                }
+            }
+        }
+        JsonUpdateKind::Failed
+    }
+
+    fn save_to_file(&self, path: &Path) -> JsonUpdateKind {
+        if let Ok(path) = files::translate(&self.virtual_paths, path) {
+            if let Ok(source) = serialize_setlist(&self.file) {
+                if fs::write(path, source).is_ok() {
+                    return JsonUpdateKind::Ok;
+                }
+            }
+        }
+        JsonUpdateKind::Failed
+    }
+}
+
+// Setlists saved by earlier versions are brought up to date first
+fn read_setlist(file: &str) -> Result<SetlistFile, String> {
+    let source = serde_json::from_str(file).map_err(|e| e.to_string())?;
+    let source = json::migrate(source, SETLIST_MIGRATIONS).map_err(|e| e.to_string())?;
+    serde_json::from_value(source).map_err(|e| e.to_string())
+}
+
+fn serialize_setlist(setlist: &SetlistFile) -> Result<String, serde_json::Error> {
+    let mut source = serde_json::to_value(setlist)?;
+    json::set_version(&mut source, json::latest_version(SETLIST_MIGRATIONS));
+    serde_json::to_string_pretty(&source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_setlist, serialize_setlist, Entry, RequestKind, Setlist, Trigger};
+    use crate::{
+        control::{self, node::midi_file_player},
+        json::JsonUpdateKind,
+        midi::{ControlChangeKind, Message, MessageKind},
+        pads::Action,
+        path::VirtualPaths,
+        testing::TempDir,
+    };
+    use std::path::PathBuf;
+
+    fn entry(name: &str) -> Entry {
+        Entry {
+            name: name.into(),
+            actions: vec![Action::Controller(
+                control::command::RequestKind::SetTempoBpm(100.0),
+            )],
+            backing_file: Some(PathBuf::from(format!("midi:/{name}.mid"))),
+        }
+    }
+
+    #[test]
+    fn navigation() {
+        let mut setlist = Setlist::new(VirtualPaths::default());
+        for name in ["intro", "verse", "outro"] {
+            setlist.process_request(RequestKind::AddEntry(entry(name)));
+        }
+        let (_, actions) = setlist.process_request(RequestKind::Previous);
+        assert!(actions.is_empty());
+        // without a player the backing file goes nowhere
+        let (_, actions) = setlist.process_request(RequestKind::Next);
+        assert_eq!(actions, entry("intro").actions);
+        assert_eq!(setlist.current, Some(0));
+
+        setlist.process_request(RequestKind::SetPlayer(Some(2)));
+        let (_, actions) = setlist.process_request(RequestKind::Next);
+        let load = midi_file_player::RequestKind::LoadFile(PathBuf::from("midi:/verse.mid"));
+        assert_eq!(
+            actions[1],
+            Action::Controller(control::command::RequestKind::NodeRequest {
+                id: 2,
+                kind: control::node::RequestKind::MidiFilePlayer(load),
+            })
+        );
+
+        // the position follows the song when the list changes
+        setlist.process_request(RequestKind::MoveEntry(1, 2));
+        assert_eq!(setlist.current, Some(2));
+        let (res, actions) = setlist.process_request(RequestKind::Next);
+        assert_eq!((res, actions.len()), (JsonUpdateKind::InvalidId, 0));
+        setlist.process_request(RequestKind::RemoveEntry(0));
+        assert_eq!(setlist.current, Some(1));
+        setlist.process_request(RequestKind::Previous);
+        assert_eq!(setlist.current, Some(0));
+    }
+
+    #[test]
+    fn footswitch() {
+        let mut setlist = Setlist::new(VirtualPaths::default());
+        setlist.process_request(RequestKind::SetNextTrigger(Some(Trigger::ControlChange {
+            channel: 0,
+            controller: 64,
+        })));
+        setlist.process_request(RequestKind::SetPreviousTrigger(Some(Trigger::Note {
+            channel: 9,
+            note: 36,
+        })));
+        let pedal = |value| {
+            Message::new(
+                0,
+                MessageKind::ControlChange {
+                    kind: ControlChangeKind::from_number(64).unwrap(),
+                    value,
+                },
+            )
+        };
+        assert_eq!(setlist.navigation_by(&pedal(127)), Some(RequestKind::Next));
+        assert_eq!(setlist.navigation_by(&pedal(0)), None);
+        let kick = Message::new(
+            9,
+            MessageKind::NoteOn {
+                note: 36,
+                velocity: 1,
+            },
+        );
+        assert_eq!(setlist.navigation_by(&kick), Some(RequestKind::Previous));
+    }
+
+    #[test]
+    fn setlists_from_before_the_versions() {
+        let old =
+            r#"{ "entries": [], "player": 2, "next_trigger": null, "previous_trigger": null }"#;
+        let setlist = read_setlist(old).unwrap();
+        assert_eq!(setlist.player, Some(2));
+        let saved = serialize_setlist(&setlist).unwrap();
+        assert!(saved.contains(r#""version": 1"#));
+        assert_eq!(read_setlist(&saved).unwrap(), setlist);
+        assert!(read_setlist(r#"{ "version": 9, "entries": [] }"#).is_err());
+    }
+
+    #[test]
+    fn files_stay_under_their_root() {
+        let dir = TempDir::new("setlist");
+        std::fs::create_dir_all(dir.join("beats")).unwrap();
+        let mut virtual_paths = VirtualPaths::default();
+        virtual_paths.insert(PathBuf::from("beats:"), dir.join("beats"));
+        let mut setlist = Setlist::new(virtual_paths);
+        let save = |setlist: &mut Setlist, path: &str| {
+            setlist
+                .process_request(RequestKind::SaveSetlist(PathBuf::from(path)))
+                .0
+        };
+        assert!(matches!(
+            save(&mut setlist, "beats:/gig.json"),
+            JsonUpdateKind::Ok
+        ));
+        assert!(matches!(
+            save(&mut setlist, "beats:/../gig.json"),
+            JsonUpdateKind::Failed
+        ));
+        assert!(!dir.join("gig.json").exists());
+        let load = RequestKind::LoadSetlist(PathBuf::from("beats:/../beats/gig.json"));
+        assert!(matches!(
+            setlist.process_request(load).0,
+            JsonUpdateKind::Failed
+        ));
+    }
+}
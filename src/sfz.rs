@@ -0,0 +1,414 @@
+// Looks into an SFZ instrument without loading it: which keys and velocities its regions cover
+// and which of its samples are missing. Only the opcodes for that are read, everything else of
+// the format is left to sfizz.
+
+use crate::{files::FileError, path::VirtualPaths};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+// Nested deeper, an #include is most likely including itself
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    // As written in the file, relative to its directory
+    pub sample: String,
+    // Inclusive
+    pub low_key: u8,
+    pub high_key: u8,
+    pub low_velocity: u8,
+    pub high_velocity: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    pub path: String,
+    pub missing: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Inspection {
+    pub regions: Vec<Region>,
+    // Lowest and highest key of all regions
+    pub key_range: Option<(u8, u8)>,
+    // Every distinct (low, high) velocity range, from soft to loud
+    pub velocity_layers: Vec<(u8, u8)>,
+    // Every sample once, sorted by path
+    pub samples: Vec<Sample>,
+}
+
+pub async fn inspect(virtual_paths: &VirtualPaths, path: &Path) -> Result<Inspection, FileError> {
+    let path = virtual_paths
+        .translate(path)
+        .ok_or(FileError::InvalidPath)?;
+    tokio::task::spawn_blocking(move || inspect_file(&path))
+        .await
+        .map_err(|e| FileError::Io(e.to_string()))?
+}
+
+pub fn inspect_file(path: &Path) -> Result<Inspection, FileError> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut text = String::new();
+    read_with_includes(path, dir, 0, &mut text)?;
+    let regions = parse(&text);
+
+    let mut samples: Vec<Sample> = regions
+        .iter()
+        // `*sine` and friends are made up by sfizz
+        .filter(|region| !region.sample.starts_with('*'))
+        .map(|region| Sample {
+            path: region.sample.clone(),
+            missing: !dir.join(&region.sample).is_file(),
+        })
+        .collect();
+    samples.sort_by(|a, b| a.path.cmp(&b.path));
+    samples.dedup();
+
+    let key_range = regions
+        .iter()
+        .map(|region| (region.low_key, region.high_key))
+        .reduce(|(low, high), (region_low, region_high)| {
+            (low.min(region_low), high.max(region_high))
+        });
+    let mut velocity_layers: Vec<_> = regions
+        .iter()
+        .map(|region| (region.low_velocity, region.high_velocity))
+        .collect();
+    velocity_layers.sort_unstable();
+    velocity_layers.dedup();
+
+    Ok(Inspection {
+        regions,
+        key_range,
+        velocity_layers,
+        samples,
+    })
+}
+
+// Paths of includes are relative to the directory of the instrument, not of the including file
+fn read_with_includes(
+    path: &Path,
+    dir: &Path,
+    depth: usize,
+    text: &mut String,
+) -> Result<(), FileError> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(FileError::InvalidFile);
+    }
+    let bytes = std::fs::read(path)?;
+    for line in String::from_utf8_lossy(&bytes).lines() {
+        let included = line
+            .trim()
+            .strip_prefix("#include")
+            .map(|rest| rest.trim().trim_matches('"'));
+        match included {
+            Some(include) => {
+                let include_path = dir.join(normalize(include));
+                read_with_includes(&include_path, dir, depth + 1, text)?;
+            }
+            None => {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+    }
+    Ok(())
+}
+
+// Windows paths are common in SFZ files
+fn normalize(path: &str) -> PathBuf {
+    PathBuf::from(path.replace('\\', "/"))
+}
+
+type Opcodes = HashMap<String, String>;
+
+// Index into the levels the opcodes of a header go to, regions are kept apart
+fn level_of(header: &str) -> Option<usize> {
+    match header {
+        "control" => Some(0),
+        "global" => Some(1),
+        "master" => Some(2),
+        "group" => Some(3),
+        _ => None,
+    }
+}
+
+pub fn parse(text: &str) -> Vec<Region> {
+    let mut defines: Vec<(String, String)> = Vec::new();
+    // of <control>, <global>, <master> and <group>, a region takes them from the lowest up
+    let mut levels: [Opcodes; 4] = Default::default();
+    // opcodes before any header are global, the ones of headers like <curve> get ignored
+    let mut level = Some(1);
+    let mut region: Option<Opcodes> = None;
+    let mut regions = Vec::new();
+
+    for line in strip_comments(text).lines() {
+        let line = line.trim();
+        if let Some(define) = line.strip_prefix("#define") {
+            if let Some((name, value)) = define.trim().split_once(char::is_whitespace) {
+                defines.push((name.to_owned(), value.trim().to_owned()));
+                // longer names first, so $FOO doesn't replace the start of $FOOBAR
+                defines.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+            }
+            continue;
+        }
+        let mut line = line.to_owned();
+        for (name, value) in &defines {
+            line = line.replace(name.as_str(), value);
+        }
+
+        for token in tokenize(&line) {
+            match token {
+                Token::Header(header) => {
+                    regions.extend(region.take().and_then(|r| to_region(&levels, &r)));
+                    level = level_of(header);
+                    if let Some(level) = level {
+                        // a new group forgets the last one, a new master its groups too
+                        levels[level..].iter_mut().for_each(Opcodes::clear);
+                    } else if header == "region" {
+                        region = Some(Opcodes::new());
+                    }
+                }
+                Token::Opcode(name, value) => {
+                    let opcodes = match (&mut region, level) {
+                        (Some(region), _) => region,
+                        (None, Some(level)) => &mut levels[level],
+                        (None, None) => continue,
+                    };
+                    opcodes.insert(name.to_owned(), value.to_owned());
+                }
+            }
+        }
+    }
+    regions.extend(region.take().and_then(|r| to_region(&levels, &r)));
+    regions
+}
+
+fn to_region(levels: &[Opcodes; 4], region: &Opcodes) -> Option<Region> {
+    let inherited = || std::iter::once(region).chain(levels[1..].iter().rev());
+    let get = |name: &str| inherited().find_map(|opcodes| opcodes.get(name));
+    let sample = get("sample")?;
+    let default_path = levels[0].get("default_path").map_or("", String::as_str);
+    // key= of a level is both of them, it wins over the ones of the levels above
+    let key_of = |name: &str| {
+        inherited()
+            .find_map(|opcodes| opcodes.get(name).or_else(|| opcodes.get("key")))
+            .and_then(|key| parse_key(key))
+    };
+    let velocity_of = |name| get(name).and_then(|value| value.parse::<u8>().ok());
+    Some(Region {
+        sample: normalize(&format!("{default_path}{sample}"))
+            .to_string_lossy()
+            .into_owned(),
+        low_key: key_of("lokey").unwrap_or(0),
+        high_key: key_of("hikey").unwrap_or(127),
+        low_velocity: velocity_of("lovel").unwrap_or(1).min(127),
+        high_velocity: velocity_of("hivel").unwrap_or(127).min(127),
+    })
+}
+
+// A MIDI number or a note name like c4, f#3 or eb-1, c4 being 60
+fn parse_key(key: &str) -> Option<u8> {
+    if let Ok(number) = key.parse::<u8>() {
+        return (number < 128).then_some(number);
+    }
+    let key = key.to_ascii_lowercase();
+    let mut chars = key.chars();
+    let semitone: i32 = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.chars().next()? {
+        '#' => (1, &rest[1..]),
+        'b' => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let octave: i32 = octave.parse().ok()?;
+    let number = (octave + 1) * 12 + semitone + accidental;
+    u8::try_from(number).ok().filter(|&number| number < 128)
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let line_comment = rest.find("//");
+        let block_comment = rest.find("/*");
+        match (line_comment, block_comment) {
+            (Some(line), block) if block.is_none_or(|block| line < block) => {
+                stripped.push_str(&rest[..line]);
+                rest = rest[line..]
+                    .find('\n')
+                    .map_or("", |end| &rest[line + end..]);
+            }
+            (_, Some(block)) => {
+                stripped.push_str(&rest[..block]);
+                // keeps the line break of a comment spanning lines, opcodes end at them
+                let end = rest[block..]
+                    .find("*/")
+                    .map_or(rest.len(), |end| block + end + 2);
+                if rest[block..end].contains('\n') {
+                    stripped.push('\n');
+                }
+                rest = &rest[end..];
+            }
+            _ => {
+                stripped.push_str(rest);
+                rest = "";
+            }
+        }
+    }
+    stripped
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Header(&'a str),
+    Opcode(&'a str, &'a str),
+}
+
+// A value goes on until the next header, the next opcode or the end of the line, sample paths
+// can have spaces in them
+fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        if let Some(header) = rest.strip_prefix('<') {
+            let Some(end) = header.find('>') else {
+                break;
+            };
+            tokens.push(Token::Header(header[..end].trim()));
+            rest = header[end + 1..].trim_start();
+            continue;
+        }
+        let Some(equals) = rest.find('=') else {
+            break;
+        };
+        let name = rest[..equals].trim();
+        let value_start = equals + 1;
+        let value_end =
+            next_token_start(&rest[value_start..]).map_or(rest.len(), |end| value_start + end);
+        tokens.push(Token::Opcode(name, rest[value_start..value_end].trim()));
+        rest = rest[value_end..].trim_start();
+    }
+    tokens
+}
+
+// Where the header or opcode after a value starts
+fn next_token_start(value: &str) -> Option<usize> {
+    let header = value.find('<');
+    let opcode = value
+        .char_indices()
+        .filter(|(_, c)| c.is_whitespace())
+        .map(|(index, _)| index)
+        .find(|&index| {
+            let word = value[index..].trim_start();
+            let name_len = word
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(word.len());
+            name_len > 0 && word[name_len..].starts_with('=')
+        });
+    match (header, opcode) {
+        (Some(header), Some(opcode)) => Some(header.min(opcode)),
+        (header, opcode) => header.or(opcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inspect, parse, parse_key, Region, Sample};
+    use crate::{files::FileError, path::VirtualPaths};
+    use std::{fs, path::Path};
+
+    fn region(sample: &str, keys: (u8, u8), velocities: (u8, u8)) -> Region {
+        Region {
+            sample: sample.to_owned(),
+            low_key: keys.0,
+            high_key: keys.1,
+            low_velocity: velocities.0,
+            high_velocity: velocities.1,
+        }
+    }
+
+    #[test]
+    fn regions() {
+        let regions = parse(
+            r#"
+            // a comment <region> sample=nothing.wav
+            <control> default_path=samples\
+            #define $SOFT 64
+            <global> hikey=c5
+            <group> lovel=1 hivel=$SOFT
+            <region> sample=piano soft c4.wav lokey=60 /* a
+            comment */ <region> sample=piano soft d4.wav key=d4
+            <group> lovel=65
+            <region>
+            sample=piano loud.wav lokey=c#-1
+            <region> sample=*sine
+            "#,
+        );
+        assert_eq!(
+            regions,
+            [
+                region("samples/piano soft c4.wav", (60, 72), (1, 64)),
+                region("samples/piano soft d4.wav", (62, 62), (1, 64)),
+                region("samples/piano loud.wav", (1, 72), (65, 127)),
+                region("samples/*sine", (0, 72), (65, 127)),
+            ]
+        );
+        assert_eq!(parse_key("eb3"), Some(51));
+        assert_eq!(parse_key("g9"), Some(127));
+        assert_eq!(parse_key("a9"), None);
+    }
+
+    #[tokio::test]
+    async fn missing_samples() {
+        let root = std::env::temp_dir().join(format!("ami-sfz-{}", std::process::id()));
+        fs::create_dir_all(root.join("samples")).unwrap();
+        fs::write(root.join("samples/soft.wav"), "").unwrap();
+        let sfz = "<group> lovel=1 hivel=64\n#include \"soft.sfzh\"\n<group>\n\
+                   <region> sample=samples/loud.wav lovel=65 lokey=48 hikey=59\n";
+        fs::write(root.join("piano.sfz"), sfz).unwrap();
+        fs::write(
+            root.join("soft.sfzh"),
+            "<region> sample=samples\\soft.wav key=60\n",
+        )
+        .unwrap();
+        fs::write(root.join("loop.sfz"), "#include \"loop.sfz\"\n").unwrap();
+        let mut vp = VirtualPaths::default();
+        vp.insert("samples:".into(), root.clone());
+
+        let inspection = inspect(&vp, Path::new("samples:/piano.sfz")).await.unwrap();
+        assert_eq!(inspection.regions.len(), 2);
+        assert_eq!(inspection.key_range, Some((48, 60)));
+        assert_eq!(inspection.velocity_layers, [(1, 64), (65, 127)]);
+        let sample = |path: &str, missing| Sample {
+            path: path.to_owned(),
+            missing,
+        };
+        assert_eq!(
+            inspection.samples,
+            [
+                sample("samples/loud.wav", true),
+                sample("samples/soft.wav", false)
+            ]
+        );
+
+        let looped = inspect(&vp, Path::new("samples:/loop.sfz")).await;
+        assert_eq!(looped, Err(FileError::InvalidFile));
+        let missing = inspect(&vp, Path::new("samples:/none.sfz")).await;
+        assert_eq!(missing, Err(FileError::NotFound));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
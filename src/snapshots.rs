@@ -0,0 +1,123 @@
+// Sessions kept in memory to flip between while tweaking the sounds and the mix, like the A and B
+// of a mixing desk. Nothing gets written to a file.
+
+use crate::{
+    deser::serialize,
+    json::{update_fields_or_fail, JsonUpdateKind},
+    pads::Action,
+    session,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestKind {
+    // Of the current state, a snapshot of the same name gets replaced
+    Capture(String),
+    Recall(usize),
+    Remove(usize),
+}
+
+#[derive(Default)]
+pub struct Snapshots {
+    // (name, session)
+    snapshots: Vec<(String, Value)>,
+}
+
+impl Snapshots {
+    // Recalling is left to the caller like triggering pads, it gets the actions bringing the
+    // snapshot back. `state` is the one the clients get.
+    pub fn process_request(
+        &mut self,
+        kind: RequestKind,
+        state: &Value,
+    ) -> (JsonUpdateKind, Vec<Action>) {
+        match kind {
+            RequestKind::Capture(name) => {
+                let session = session::of_state(state);
+                match self.snapshots.iter_mut().find(|(n, _)| *n == name) {
+                    Some((_, snapshot)) => *snapshot = session,
+                    None => self.snapshots.push((name, session)),
+                }
+                (self.snapshots_update(), vec![])
+            }
+            RequestKind::Recall(index) => {
+                let actions = self
+                    .snapshots
+                    .get(index)
+                    .and_then(|(_, session)| session::actions(session));
+                match actions {
+                    Some(actions) => (JsonUpdateKind::Ok, actions),
+                    None => (JsonUpdateKind::InvalidId, vec![]),
+                }
+            }
+            RequestKind::Remove(index) => {
+                if index < self.snapshots.len() {
+                    self.snapshots.remove(index);
+                    (self.snapshots_update(), vec![])
+                } else {
+                    (JsonUpdateKind::InvalidId, vec![])
+                }
+            }
+        }
+    }
+
+    // Only the names, the sessions themselves stay here
+    fn snapshots_update(&self) -> JsonUpdateKind {
+        let names: Vec<&str> = self
+            .snapshots
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        update_fields_or_fail(|updates| {
+            updates.push(("snapshots".to_owned(), serialize(names)?));
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RequestKind, Snapshots};
+    use crate::json::JsonUpdateKind;
+    use serde_json::json;
+
+    #[test]
+    fn captures_and_recalls() {
+        let state = |gain| {
+            json!({
+                "nodes": [{ "kind": "OxiSynth", "instance": { "gain": gain } }],
+                "zones": [],
+                "controller": {
+                    "nodes": [],
+                    "rhythm": { "num_beats": 4, "num_divs": 4 },
+                    "tempo_bpm": 90.0,
+                },
+            })
+        };
+        let mut snapshots = Snapshots::default();
+        let names = |res| match res {
+            JsonUpdateKind::UpdateFields(updates) => updates[0].1.clone(),
+            res => panic!("{res:?}"),
+        };
+        let (res, _) = snapshots.process_request(RequestKind::Capture("A".into()), &state(1.0));
+        assert_eq!(names(res), json!(["A"]));
+        snapshots.process_request(RequestKind::Capture("B".into()), &state(0.5));
+        let (res, _) = snapshots.process_request(RequestKind::Capture("A".into()), &state(0.8));
+        assert_eq!(names(res), json!(["A", "B"]));
+
+        let (res, actions) = snapshots.process_request(RequestKind::Recall(0), &state(0.5));
+        assert_eq!(res, JsonUpdateKind::Ok);
+        let actions = serde_json::to_value(actions).unwrap();
+        assert_eq!(
+            actions[0]["Renderer"]["SetSetup"]["nodes"][0]["instance"]["gain"],
+            0.8
+        );
+        assert_eq!(actions[1]["Controller"]["SetSetup"]["tempo_bpm"], 90.0);
+
+        let (res, actions) = snapshots.process_request(RequestKind::Recall(2), &state(0.5));
+        assert_eq!((res, actions.len()), (JsonUpdateKind::InvalidId, 0));
+        let (res, _) = snapshots.process_request(RequestKind::Remove(0), &state(0.5));
+        assert_eq!(names(res), json!(["B"]));
+    }
+}
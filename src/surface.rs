@@ -0,0 +1,319 @@
+// A Mackie Control or HUI surface as a hardware mixer: the eight strips are the gains and mutes
+// of a bank of eight render nodes, play and stop run the drum machine. The LEDs and the motor
+// faders follow the state the clients get, whoever changed it.
+
+mod mackie;
+
+use crate::{
+    app::App,
+    config::{self, SurfaceProtocol},
+    control::drum_machine,
+    midi::{parser::Parser, Message, MidiWriter},
+    render::{command, node},
+    webserver::ClientMessageKind,
+};
+use mackie::{Button, Decoder, Event, Feedback, STRIPS};
+use serde_json::Value;
+use std::{net::SocketAddr, time::Duration};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+const RECONNECT_PERIOD: Duration = Duration::from_secs(2);
+const FEEDBACK_PERIOD: Duration = Duration::from_millis(50);
+const HUI_PING_PERIOD: Duration = Duration::from_secs(1);
+// Where the fader sits for a gain of 1.0, like the 0 dB mark of a mixing desk
+const UNITY_POSITION: f32 = 0.75;
+
+struct Connection {
+    // Only held to keep the input open
+    _input: midir::MidiInputConnection<()>,
+    input_rx: mpsc::UnboundedReceiver<Message>,
+    output: MidiWriter,
+}
+
+#[derive(Debug, Default)]
+struct Mixer {
+    // The first node of the strips is `bank * STRIPS`
+    bank: usize,
+    touched: [bool; STRIPS],
+    // What the surface shows, nothing before the first feedback
+    shown: Vec<Feedback>,
+}
+
+impl Mixer {
+    // The requests for an event, the state is the one the clients get
+    fn handle(&mut self, state: &Value, event: Event) -> Vec<ClientMessageKind> {
+        let nodes = state["nodes"].as_array().map_or(0, Vec::len);
+        let node_request = |id, kind| {
+            let req = command::RequestKind::NodeRequest { id, kind };
+            vec![ClientMessageKind::RendererRequest(req)]
+        };
+        type DK = drum_machine::RequestKind;
+        match event {
+            Event::Fader { strip, position } => {
+                let id = self.bank * STRIPS + strip;
+                if id >= nodes {
+                    return vec![];
+                }
+                node_request(id, node::RequestKind::SetGain(gain(position)))
+            }
+            Event::Touch { strip, touched } => {
+                self.touched[strip] = touched;
+                vec![]
+            }
+            Event::Pressed(Button::Mute(strip)) => {
+                let id = self.bank * STRIPS + strip;
+                if id >= nodes {
+                    return vec![];
+                }
+                let enabled = state["nodes"][id]["instance"]["enabled"].as_bool();
+                node_request(id, node::RequestKind::SetEnabled(enabled == Some(false)))
+            }
+            Event::Pressed(Button::Play) => {
+                vec![ClientMessageKind::DrumMachineRequest(DK::SetEnabled(true))]
+            }
+            Event::Pressed(Button::Stop) => {
+                vec![ClientMessageKind::DrumMachineRequest(DK::SetEnabled(false))]
+            }
+            Event::Pressed(Button::BankLeft) => {
+                self.bank = self.bank.saturating_sub(1);
+                vec![]
+            }
+            Event::Pressed(Button::BankRight) => {
+                if (self.bank + 1) * STRIPS < nodes {
+                    self.bank += 1;
+                }
+                vec![]
+            }
+        }
+    }
+
+    // What changed since the last feedback, touched faders are left alone until they're let go
+    fn feedback(&mut self, state: &Value) -> Vec<Feedback> {
+        let mut feedback = Vec::with_capacity(STRIPS * 2 + 2);
+        for strip in 0..STRIPS {
+            let node = &state["nodes"][self.bank * STRIPS + strip]["instance"];
+            let gain = node["gain"].as_f64().map_or(0.0, |gain| gain as f32);
+            let position = mackie::fader_value(fader_position(gain));
+            feedback.push(Feedback::Fader { strip, position });
+            feedback.push(Feedback::Led {
+                button: Button::Mute(strip),
+                on: node["enabled"].as_bool() == Some(false),
+            });
+        }
+        let playing = state["drum_machine"]["enabled"].as_bool() == Some(true);
+        feedback.push(Feedback::Led {
+            button: Button::Play,
+            on: playing,
+        });
+        feedback.push(Feedback::Led {
+            button: Button::Stop,
+            on: !playing,
+        });
+
+        if self.shown.len() != feedback.len() {
+            self.shown.clone_from(&feedback);
+            return feedback;
+        }
+        let mut changed = Vec::new();
+        for (shown, feedback) in self.shown.iter_mut().zip(feedback) {
+            let touched = matches!(feedback, Feedback::Fader { strip, .. } if self.touched[strip]);
+            if *shown != feedback && !touched {
+                *shown = feedback;
+                changed.push(feedback);
+            }
+        }
+        changed
+    }
+}
+
+fn gain(position: f32) -> f32 {
+    (position / UNITY_POSITION).powi(2)
+}
+
+fn fader_position(gain: f32) -> f32 {
+    (gain.max(0.0).sqrt() * UNITY_POSITION).min(1.0)
+}
+
+// Runs for as long as AMI runs, connecting again when the surface comes back
+pub async fn run(surface: config::Surface, app: App) {
+    loop {
+        if let Some(connection) = connect(&surface) {
+            info!("| Control surface on {}", connection.output.name());
+            serve(surface.protocol, connection, &app).await;
+            warn!("Control surface disconnected");
+        }
+        tokio::time::sleep(RECONNECT_PERIOD).await;
+    }
+}
+
+fn connect(surface: &config::Surface) -> Option<Connection> {
+    let output_name = MidiWriter::get_available_ports()
+        .into_iter()
+        .find(|name| name.contains(&surface.output))?;
+    let output = MidiWriter::connect(&output_name).ok()?;
+
+    let midi_in = midir::MidiInput::new("").ok()?;
+    let port = midi_in.ports().into_iter().find(|port| {
+        midi_in
+            .port_name(port)
+            .is_ok_and(|name| name.contains(&surface.input))
+    })?;
+    let (input_tx, input_rx) = mpsc::unbounded_channel();
+    let mut parser = Parser::default();
+    let input = midi_in
+        .connect(
+            &port,
+            "",
+            move |_, bytes, _| {
+                for &byte in bytes {
+                    if let Some(message) = parser.push(byte) {
+                        let _ = input_tx.send(message);
+                    }
+                }
+            },
+            (),
+        )
+        .ok()?;
+    Some(Connection {
+        _input: input,
+        input_rx,
+        output,
+    })
+}
+
+// Until the surface can't be written to anymore
+async fn serve(protocol: SurfaceProtocol, mut connection: Connection, app: &App) {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut decoder = Decoder::new(protocol);
+    let mut mixer = Mixer::default();
+    let mut feedback_interval = tokio::time::interval(FEEDBACK_PERIOD);
+    let mut ping_interval = tokio::time::interval(HUI_PING_PERIOD);
+    loop {
+        tokio::select! {
+            Some(message) = connection.input_rx.recv() => {
+                let mut events: Vec<_> = decoder.decode(&message).into_iter().collect();
+                while let Ok(message) = connection.input_rx.try_recv() {
+                    events.extend(decoder.decode(&message));
+                }
+                for event in latest_faders(events) {
+                    let state = app.cache.lock().await.get().clone();
+                    for req in mixer.handle(&state, event) {
+                        app.handle_client_message(addr, req).await;
+                    }
+                }
+            }
+            _ = feedback_interval.tick() => {
+                let state = app.cache.lock().await.get().clone();
+                for feedback in mixer.feedback(&state) {
+                    for message in mackie::encode(protocol, feedback) {
+                        if connection.output.send(&message).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            _ = ping_interval.tick(), if protocol == SurfaceProtocol::Hui => {
+                if connection.output.send(&mackie::hui_ping()).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Faders send a lot, only where they end up counts
+fn latest_faders(mut events: Vec<Event>) -> Vec<Event> {
+    let mut moved = [false; STRIPS];
+    events.reverse();
+    events.retain(|event| match *event {
+        Event::Fader { strip, .. } => !std::mem::replace(&mut moved[strip], true),
+        _ => true,
+    });
+    events.reverse();
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        latest_faders,
+        mackie::{Button, Event, Feedback},
+        Mixer,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn mixes_the_nodes() {
+        let node = |gain, enabled| json!({ "instance": { "gain": gain, "enabled": enabled } });
+        let mut nodes = vec![node(1.0, true); 9];
+        nodes[8] = node(0.0, false);
+        let mut state = json!({ "nodes": nodes, "drum_machine": { "enabled": false } });
+        let mut mixer = Mixer::default();
+
+        let feedback = mixer.feedback(&state);
+        assert_eq!(feedback.len(), 18);
+        let unity = Feedback::Fader {
+            strip: 0,
+            position: 12287,
+        };
+        assert_eq!(feedback[0], unity);
+        assert!(mixer.feedback(&state).is_empty());
+
+        let req = mixer.handle(&state, Event::Pressed(Button::Mute(2)));
+        let req = serde_json::to_value(req).unwrap();
+        let set_enabled = json!({ "NodeRequest": { "id": 2, "kind": { "SetEnabled": false } } });
+        assert_eq!(req, json!([{ "RendererRequest": set_enabled }]));
+
+        // the touched fader is left alone, the mute LED follows
+        mixer.handle(
+            &state,
+            Event::Touch {
+                strip: 2,
+                touched: true,
+            },
+        );
+        state["nodes"][2] = node(0.5, false);
+        let mute = Feedback::Led {
+            button: Button::Mute(2),
+            on: true,
+        };
+        assert_eq!(mixer.feedback(&state), [mute]);
+        mixer.handle(
+            &state,
+            Event::Touch {
+                strip: 2,
+                touched: false,
+            },
+        );
+        assert_eq!(mixer.feedback(&state).len(), 1);
+
+        // the second bank only has the ninth node
+        mixer.handle(&state, Event::Pressed(Button::BankRight));
+        mixer.handle(&state, Event::Pressed(Button::BankRight));
+        assert_eq!(mixer.bank, 1);
+        let fader = Event::Fader {
+            strip: 1,
+            position: 1.0,
+        };
+        assert!(mixer.handle(&state, fader).is_empty());
+        let feedback = mixer.feedback(&state);
+        assert!(feedback.contains(&Feedback::Fader {
+            strip: 0,
+            position: 0
+        }));
+    }
+
+    #[test]
+    fn keeps_the_latest_faders() {
+        let fader = |strip, position| Event::Fader { strip, position };
+        let events = vec![
+            fader(0, 0.1),
+            fader(1, 0.1),
+            Event::Pressed(Button::Play),
+            fader(0, 0.2),
+        ];
+        let latest = [fader(1, 0.1), Event::Pressed(Button::Play), fader(0, 0.2)];
+        assert_eq!(latest_faders(events), latest);
+    }
+}
@@ -0,0 +1,324 @@
+// The Mackie Control (MCU) and HUI protocols, as far as a mixer of the nodes needs them.
+//
+// MCU sends the faders as pitch wheels on the channel of the strip and the buttons as notes,
+// the LEDs light up with the same notes. HUI sends the faders as pairs of controllers and the
+// buttons as a zone followed by a port in it, its LEDs take the same pairs.
+
+use crate::{
+    config::SurfaceProtocol,
+    midi::{ControlChangeKind, Message, MessageKind},
+};
+
+pub const STRIPS: usize = 8;
+
+const FADER_MAX: u16 = 0x3FFF;
+
+const MCU_MUTE: u8 = 0x10;
+const MCU_BANK_LEFT: u8 = 0x2E;
+const MCU_BANK_RIGHT: u8 = 0x2F;
+const MCU_STOP: u8 = 0x5D;
+const MCU_PLAY: u8 = 0x5E;
+const MCU_FADER_TOUCH: u8 = 0x68;
+
+const HUI_FADER_MSB: u8 = 0x00;
+const HUI_FADER_LSB: u8 = 0x20;
+// Of the buttons from the surface and of the LEDs to it
+const HUI_ZONE_IN: u8 = 0x0F;
+const HUI_PORT_IN: u8 = 0x2F;
+const HUI_ZONE_OUT: u8 = 0x0C;
+const HUI_PORT_OUT: u8 = 0x2C;
+const HUI_PRESSED: u8 = 0x40;
+// Zones 0 to 7 are the strips
+const HUI_TOUCH_PORT: u8 = 0;
+const HUI_MUTE_PORT: u8 = 2;
+const HUI_BANK_ZONE: u8 = 0x0A;
+const HUI_BANK_LEFT_PORT: u8 = 1;
+const HUI_BANK_RIGHT_PORT: u8 = 3;
+const HUI_TRANSPORT_ZONE: u8 = 0x0E;
+const HUI_STOP_PORT: u8 = 3;
+const HUI_PLAY_PORT: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Button {
+    Mute(usize),
+    Play,
+    Stop,
+    BankLeft,
+    BankRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    // In 0.0..=1.0
+    Fader { strip: usize, position: f32 },
+    // Motor faders get no feedback while they're touched, they would fight the hand
+    Touch { strip: usize, touched: bool },
+    Pressed(Button),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feedback {
+    Fader { strip: usize, position: u16 },
+    Led { button: Button, on: bool },
+}
+
+// Turns the messages of a surface into events, HUI takes a few messages for some of them
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    protocol: SurfaceProtocol,
+    zone: Option<u8>,
+    fader_msb: [u8; STRIPS],
+}
+
+impl Decoder {
+    pub fn new(protocol: SurfaceProtocol) -> Self {
+        Self {
+            protocol,
+            zone: None,
+            fader_msb: [0; STRIPS],
+        }
+    }
+
+    pub fn decode(&mut self, message: &Message) -> Option<Event> {
+        match self.protocol {
+            SurfaceProtocol::Mcu => decode_mcu(message),
+            SurfaceProtocol::Hui => self.decode_hui(message),
+        }
+    }
+
+    fn decode_hui(&mut self, message: &Message) -> Option<Event> {
+        let MessageKind::ControlChange { kind, value } = message.kind else {
+            return None;
+        };
+        match kind.as_number() {
+            HUI_ZONE_IN => {
+                self.zone = Some(value);
+                None
+            }
+            HUI_PORT_IN => {
+                let pressed = value & HUI_PRESSED != 0;
+                hui_button(self.zone?, value & !HUI_PRESSED, pressed)
+            }
+            cc @ HUI_FADER_MSB..=0x07 => {
+                self.fader_msb[(cc - HUI_FADER_MSB) as usize] = value;
+                None
+            }
+            cc @ HUI_FADER_LSB..=0x27 => {
+                let strip = (cc - HUI_FADER_LSB) as usize;
+                let value = (self.fader_msb[strip] as u16) << 7 | value as u16;
+                let position = value as f32 / FADER_MAX as f32;
+                Some(Event::Fader { strip, position })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn decode_mcu(message: &Message) -> Option<Event> {
+    let (note, pressed) = match message.kind {
+        MessageKind::PitchWheel { value } => {
+            let strip = message.channel as usize;
+            let position = value as f32 / FADER_MAX as f32;
+            return (strip < STRIPS).then_some(Event::Fader { strip, position });
+        }
+        MessageKind::NoteOn { note, velocity } => (note, velocity > 0),
+        MessageKind::NoteOff { note, .. } => (note, false),
+        _ => return None,
+    };
+    let button = match note {
+        MCU_FADER_TOUCH..=0x6F => {
+            let strip = (note - MCU_FADER_TOUCH) as usize;
+            return Some(Event::Touch {
+                strip,
+                touched: pressed,
+            });
+        }
+        _ if !pressed => return None,
+        MCU_MUTE..=0x17 => Button::Mute((note - MCU_MUTE) as usize),
+        MCU_BANK_LEFT => Button::BankLeft,
+        MCU_BANK_RIGHT => Button::BankRight,
+        MCU_STOP => Button::Stop,
+        MCU_PLAY => Button::Play,
+        _ => return None,
+    };
+    Some(Event::Pressed(button))
+}
+
+fn hui_button(zone: u8, port: u8, pressed: bool) -> Option<Event> {
+    let button = match (zone, port) {
+        (0..=7, HUI_TOUCH_PORT) => {
+            let strip = zone as usize;
+            return Some(Event::Touch {
+                strip,
+                touched: pressed,
+            });
+        }
+        _ if !pressed => return None,
+        (0..=7, HUI_MUTE_PORT) => Button::Mute(zone as usize),
+        (HUI_BANK_ZONE, HUI_BANK_LEFT_PORT) => Button::BankLeft,
+        (HUI_BANK_ZONE, HUI_BANK_RIGHT_PORT) => Button::BankRight,
+        (HUI_TRANSPORT_ZONE, HUI_STOP_PORT) => Button::Stop,
+        (HUI_TRANSPORT_ZONE, HUI_PLAY_PORT) => Button::Play,
+        _ => return None,
+    };
+    Some(Event::Pressed(button))
+}
+
+pub fn encode(protocol: SurfaceProtocol, feedback: Feedback) -> Vec<Message> {
+    match (protocol, feedback) {
+        (SurfaceProtocol::Mcu, Feedback::Fader { strip, position }) => {
+            let kind = MessageKind::PitchWheel {
+                value: position.min(FADER_MAX),
+            };
+            vec![Message::new(strip as u8, kind)]
+        }
+        (SurfaceProtocol::Mcu, Feedback::Led { button, on }) => {
+            let note = match button {
+                Button::Mute(strip) => MCU_MUTE + strip as u8,
+                Button::Play => MCU_PLAY,
+                Button::Stop => MCU_STOP,
+                Button::BankLeft => MCU_BANK_LEFT,
+                Button::BankRight => MCU_BANK_RIGHT,
+            };
+            let velocity = if on { 0x7F } else { 0 };
+            vec![Message::new(0, MessageKind::NoteOn { note, velocity })]
+        }
+        (SurfaceProtocol::Hui, Feedback::Fader { strip, position }) => {
+            let position = position.min(FADER_MAX);
+            vec![
+                cc(HUI_FADER_MSB + strip as u8, (position >> 7) as u8),
+                cc(HUI_FADER_LSB + strip as u8, (position & 0x7F) as u8),
+            ]
+        }
+        (SurfaceProtocol::Hui, Feedback::Led { button, on }) => {
+            let (zone, port) = match button {
+                Button::Mute(strip) => (strip as u8, HUI_MUTE_PORT),
+                Button::Play => (HUI_TRANSPORT_ZONE, HUI_PLAY_PORT),
+                Button::Stop => (HUI_TRANSPORT_ZONE, HUI_STOP_PORT),
+                Button::BankLeft => (HUI_BANK_ZONE, HUI_BANK_LEFT_PORT),
+                Button::BankRight => (HUI_BANK_ZONE, HUI_BANK_RIGHT_PORT),
+            };
+            let port = if on { port | HUI_PRESSED } else { port };
+            vec![cc(HUI_ZONE_OUT, zone), cc(HUI_PORT_OUT, port)]
+        }
+    }
+}
+
+// HUI surfaces go offline without a ping from the host every second
+pub fn hui_ping() -> Message {
+    Message::new(
+        0,
+        MessageKind::NoteOn {
+            note: 0,
+            velocity: 0,
+        },
+    )
+}
+
+pub fn fader_value(position: f32) -> u16 {
+    (position.clamp(0.0, 1.0) * FADER_MAX as f32).round() as u16
+}
+
+fn cc(controller: u8, value: u8) -> Message {
+    let kind = ControlChangeKind::from_number(controller).expect("A controller below 128");
+    Message::new(0, MessageKind::ControlChange { kind, value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, Button, Decoder, Event, Feedback};
+    use crate::{
+        config::SurfaceProtocol,
+        midi::{Message, MessageKind},
+    };
+
+    fn note(note: u8, velocity: u8) -> Message {
+        Message::new(0, MessageKind::NoteOn { note, velocity })
+    }
+
+    fn bytes(messages: Vec<Message>) -> Vec<Vec<u8>> {
+        messages.iter().map(|m| m.encode().unwrap()).collect()
+    }
+
+    fn decode_bytes(decoder: &mut Decoder, bytes: &[u8]) -> Option<Event> {
+        decoder.decode(&Message::decode(bytes).unwrap())
+    }
+
+    #[test]
+    fn mcu() {
+        let mut decoder = Decoder::new(SurfaceProtocol::Mcu);
+        let fader = Message::new(2, MessageKind::PitchWheel { value: 0x3FFF });
+        let event = Event::Fader {
+            strip: 2,
+            position: 1.0,
+        };
+        assert_eq!(decoder.decode(&fader), Some(event));
+        assert_eq!(
+            decoder.decode(&note(0x13, 127)),
+            Some(Event::Pressed(Button::Mute(3)))
+        );
+        assert_eq!(decoder.decode(&note(0x13, 0)), None);
+        let touch = Event::Touch {
+            strip: 1,
+            touched: false,
+        };
+        assert_eq!(decoder.decode(&note(0x69, 0)), Some(touch));
+        assert_eq!(
+            decoder.decode(&note(0x5E, 127)),
+            Some(Event::Pressed(Button::Play))
+        );
+
+        let led = Feedback::Led {
+            button: Button::Mute(7),
+            on: true,
+        };
+        assert_eq!(
+            bytes(encode(SurfaceProtocol::Mcu, led)),
+            [[0x90, 0x17, 0x7F]]
+        );
+        let fader = Feedback::Fader {
+            strip: 4,
+            position: 0x2000,
+        };
+        assert_eq!(
+            bytes(encode(SurfaceProtocol::Mcu, fader)),
+            [[0xE4, 0x00, 0x40]]
+        );
+    }
+
+    #[test]
+    fn hui() {
+        let mut decoder = Decoder::new(SurfaceProtocol::Hui);
+        assert_eq!(decode_bytes(&mut decoder, &[0xB0, 0x05, 0x40]), None);
+        let event = Event::Fader {
+            strip: 5,
+            position: 0x2000 as f32 / 0x3FFF as f32,
+        };
+        assert_eq!(decode_bytes(&mut decoder, &[0xB0, 0x25, 0x00]), Some(event));
+
+        assert_eq!(decode_bytes(&mut decoder, &[0xB0, 0x0F, 0x03]), None);
+        let mute = Some(Event::Pressed(Button::Mute(3)));
+        assert_eq!(decode_bytes(&mut decoder, &[0xB0, 0x2F, 0x42]), mute);
+        assert_eq!(decode_bytes(&mut decoder, &[0xB0, 0x2F, 0x02]), None);
+        assert_eq!(decode_bytes(&mut decoder, &[0xB0, 0x0F, 0x0E]), None);
+        let play = Some(Event::Pressed(Button::Play));
+        assert_eq!(decode_bytes(&mut decoder, &[0xB0, 0x2F, 0x44]), play);
+
+        let led = Feedback::Led {
+            button: Button::Stop,
+            on: true,
+        };
+        assert_eq!(
+            bytes(encode(SurfaceProtocol::Hui, led)),
+            [[0xB0, 0x0C, 0x0E], [0xB0, 0x2C, 0x43]]
+        );
+        let fader = Feedback::Fader {
+            strip: 1,
+            position: 0x3FFF,
+        };
+        assert_eq!(
+            bytes(encode(SurfaceProtocol::Hui, fader)),
+            [[0xB0, 0x01, 0x7F], [0xB0, 0x21, 0x7F]]
+        );
+    }
+}
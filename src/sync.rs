@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// How many deltas are kept for clients catching up after a short disconnect
+pub const MAX_DELTA_HISTORY: usize = 256;
+
+// A change of the shared state in the style of JSON Patch (RFC 6902), paths are JSON pointers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+// The changes of one update, `seq` counts up by one with every delta so clients can tell when
+// they missed one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Delta {
+    pub seq: u64,
+    pub ops: Vec<PatchOp>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatchError {
+    InvalidPath,
+}
+
+pub fn pointer<S: AsRef<str>>(tokens: impl IntoIterator<Item = S>) -> String {
+    tokens
+        .into_iter()
+        .map(|t| format!("/{}", t.as_ref().replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+fn split_pointer(path: &str) -> Result<(String, String), PatchError> {
+    let (parent, last) = path.rsplit_once('/').ok_or(PatchError::InvalidPath)?;
+    Ok((
+        parent.to_owned(),
+        last.replace("~1", "/").replace("~0", "~"),
+    ))
+}
+
+// Applies the ops in order, a failing op leaves the ones before it applied
+pub fn apply(state: &mut Value, ops: &[PatchOp]) -> Result<(), PatchError> {
+    for op in ops {
+        match op {
+            PatchOp::Add { path, value } | PatchOp::Replace { path, value } => {
+                let (parent, key) = split_pointer(path)?;
+                match state.pointer_mut(&parent).ok_or(PatchError::InvalidPath)? {
+                    Value::Object(fields) => {
+                        fields.insert(key, value.clone());
+                    }
+                    Value::Array(items) if key == "-" => items.push(value.clone()),
+                    Value::Array(items) => {
+                        let index: usize = key.parse().map_err(|_| PatchError::InvalidPath)?;
+                        match op {
+                            PatchOp::Add { .. } if index <= items.len() => {
+                                items.insert(index, value.clone())
+                            }
+                            PatchOp::Replace { .. } if index < items.len() => {
+                                items[index] = value.clone()
+                            }
+                            _ => return Err(PatchError::InvalidPath),
+                        }
+                    }
+                    _ => return Err(PatchError::InvalidPath),
+                }
+            }
+            PatchOp::Remove { path } => {
+                let (parent, key) = split_pointer(path)?;
+                let removed = match state.pointer_mut(&parent).ok_or(PatchError::InvalidPath)? {
+                    Value::Object(fields) => fields.remove(&key),
+                    Value::Array(items) => key
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&index| index < items.len())
+                        .map(|index| items.remove(index)),
+                    _ => None,
+                };
+                removed.ok_or(PatchError::InvalidPath)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, pointer, PatchError, PatchOp};
+    use serde_json::json;
+
+    #[test]
+    fn patching() {
+        let mut state = json!({ "nodes": [], "drum_machine": { "tempo_bpm": 90 } });
+        let ops = [
+            PatchOp::Add {
+                path: pointer(["nodes", "-"]),
+                value: json!({ "gain": 1 }),
+            },
+            PatchOp::Add {
+                path: pointer(["nodes", "0"]),
+                value: json!({ "gain": 2 }),
+            },
+            PatchOp::Replace {
+                path: pointer(["nodes", "1", "gain"]),
+                value: json!(3),
+            },
+            PatchOp::Replace {
+                path: pointer(["drum_machine", "a/b~"]),
+                value: json!(true),
+            },
+            PatchOp::Remove {
+                path: pointer(["nodes", "0"]),
+            },
+        ];
+        apply(&mut state, &ops).unwrap();
+        assert_eq!(
+            state,
+            json!({ "nodes": [{ "gain": 3 }], "drum_machine": { "tempo_bpm": 90, "a/b~": true } })
+        );
+        assert_eq!(pointer(["a/b~"]), "/a~1b~0");
+
+        let missing = PatchOp::Remove {
+            path: pointer(["nodes", "4"]),
+        };
+        assert_eq!(apply(&mut state, &[missing]), Err(PatchError::InvalidPath));
+        assert_eq!(
+            serde_json::to_value(PatchOp::Remove { path: "/x".into() }).unwrap(),
+            json!({ "op": "remove", "path": "/x" })
+        );
+    }
+}
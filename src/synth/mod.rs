@@ -1,2 +1,2 @@
-
-pub mod sfizz;
\ No newline at end of file
+pub mod sfizz;
+pub mod tuning;
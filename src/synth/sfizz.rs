@@ -2,6 +2,7 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
+use super::tuning::Tuning;
 use std::path::PathBuf;
 
 mod bind {
@@ -9,6 +10,12 @@ mod bind {
     include!(concat!(env!("OUT_DIR"), "/sfizz_bindings.rs"));
 }
 
+// Frames of every sample loaded with the instrument, the rest gets streamed
+pub const DEFAULT_PRELOAD_SIZE: u32 = 65536;
+
+// Less than a buffer streams too late
+pub const MIN_PRELOAD_SIZE: u32 = 1024;
+
 #[derive(Debug)]
 pub struct FailedToLoadFileError {
     pub file_path: PathBuf,
@@ -82,12 +89,26 @@ impl Synth {
         }
     }
 
+    // velocity in 0.0..=1.0
+    pub fn send_hd_note_on(&mut self, note_number: u8, velocity: f32) {
+        unsafe {
+            bind::sfizz_send_hd_note_on(self.c_synth, 0, note_number as i32, velocity);
+        }
+    }
+
     pub fn send_note_off(&mut self, note_number: u8, velocity: u8) {
         unsafe {
             bind::sfizz_send_note_off(self.c_synth, 0, note_number as i32, velocity as i32);
         }
     }
 
+    // velocity in 0.0..=1.0
+    pub fn send_hd_note_off(&mut self, note_number: u8, velocity: f32) {
+        unsafe {
+            bind::sfizz_send_hd_note_off(self.c_synth, 0, note_number as i32, velocity);
+        }
+    }
+
     pub fn send_polyphonic_aftertouch(&mut self, note_number: u8, pressure: u8) {
         unsafe {
             bind::sfizz_send_poly_aftertouch(self.c_synth, 0, note_number as i32, pressure as i32);
@@ -100,6 +121,13 @@ impl Synth {
         }
     }
 
+    // value in 0.0..=1.0
+    pub fn send_hd_cc(&mut self, cmd: u8, value: f32) {
+        unsafe {
+            bind::sfizz_send_hd_cc(self.c_synth, 0, cmd as i32, value);
+        }
+    }
+
     pub fn send_channel_aftertouch(&mut self, pressure: u8) {
         unsafe {
             bind::sfizz_send_channel_aftertouch(self.c_synth, 0, pressure as i32);
@@ -112,6 +140,13 @@ impl Synth {
         }
     }
 
+    // value in -1.0..=1.0
+    pub fn send_hd_pitch_wheel(&mut self, value: f32) {
+        unsafe {
+            bind::sfizz_send_hd_pitch_wheel(self.c_synth, 0, value);
+        }
+    }
+
     pub fn load_file(&mut self, path: &std::path::Path) -> Result<(), FailedToLoadFileError> {
         let path_c = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
         let result = unsafe { bind::sfizz_load_file(self.c_synth, path_c.as_ptr()) };
@@ -124,6 +159,21 @@ impl Synth {
         }
     }
 
+    // Every key gets its own degree, sfizz has no keyboard mappings
+    pub fn set_tuning(&mut self, tuning: &Tuning) -> bool {
+        let Ok(text) = std::ffi::CString::new(tuning.to_scala()) else {
+            return false;
+        };
+        unsafe {
+            if !bind::sfizz_load_scala_string(self.c_synth, text.as_ptr()) {
+                return false;
+            }
+            bind::sfizz_set_scala_root_key(self.c_synth, 0);
+            bind::sfizz_set_tuning_frequency(self.c_synth, tuning.reference_frequency());
+        }
+        true
+    }
+
     pub fn silence(&mut self) {
         unsafe {
             bind::sfizz_all_sound_off(self.c_synth);
@@ -180,7 +230,7 @@ impl Default for Synth {
             num_frames: None,
         };
         synth.set_oversampling_factor(OversamplingFactor::X1);
-        synth.set_preload_size(65536);
+        synth.set_preload_size(DEFAULT_PRELOAD_SIZE);
         synth.set_sample_quality(ProcessingMode::Live, 2);
         synth.set_num_voices(64);
         // sfizz_set_samples_per_block(synth, BUFFER_SIZE);
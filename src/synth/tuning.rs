@@ -0,0 +1,316 @@
+// Scala tunings for music outside of 12-TET: a scale (.scl) of the intervals of its degrees and
+// optionally a keyboard mapping (.kbm) of which key plays which degree and which key is tuned
+// to what frequency. See https://www.huygens-fokker.org/scala/scl_format.html
+
+use crate::{files::FileError, path::VirtualPaths};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const NUM_KEYS: usize = 128;
+
+// Without a keyboard mapping degree 0 is on middle C, and middle C stays where it is in 12-TET
+const DEFAULT_MIDDLE_KEY: i32 = 60;
+const DEFAULT_REFERENCE_FREQUENCY: f64 = 261.625_565_3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Files {
+    // Virtual paths
+    pub scale: PathBuf,
+    pub keyboard_mapping: Option<PathBuf>,
+}
+
+// The pitch of every key as a fractional MIDI note number, 69.0 being 440 Hz
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    pitches: [Option<f32>; NUM_KEYS],
+}
+
+impl Default for Tuning {
+    // 12-TET
+    fn default() -> Self {
+        Self {
+            pitches: std::array::from_fn(|key| Some(key as f32)),
+        }
+    }
+}
+
+impl Tuning {
+    pub fn load(virtual_paths: &VirtualPaths, files: &Files) -> Result<Self, FileError> {
+        let read = |path: &Path| -> Result<String, FileError> {
+            let path = virtual_paths
+                .translate(path)
+                .ok_or(FileError::InvalidPath)?;
+            Ok(String::from_utf8_lossy(&std::fs::read(path)?).into_owned())
+        };
+        let scale = read(&files.scale)?;
+        let mapping = files.keyboard_mapping.as_deref().map(read).transpose()?;
+        Self::parse(&scale, mapping.as_deref())
+    }
+
+    pub fn parse(scale: &str, keyboard_mapping: Option<&str>) -> Result<Self, FileError> {
+        let scale = parse_scale(scale)?;
+        let mapping = match keyboard_mapping {
+            Some(text) => parse_keyboard_mapping(text)?,
+            None => KeyboardMapping::default(),
+        };
+        let cents = |key: i32| mapping.cents(&scale, key);
+        let reference_cents = cents(mapping.reference_key).ok_or(FileError::InvalidFile)?;
+        let reference_pitch = 69.0 + 12.0 * (mapping.reference_frequency / 440.0).log2();
+        let pitches = std::array::from_fn(|key| {
+            let key = key as i32;
+            if !(mapping.first_key..=mapping.last_key).contains(&key) {
+                return Some(key as f32);
+            }
+            let pitch = reference_pitch + (cents(key)? - reference_cents) / 100.0;
+            Some(pitch as f32)
+        });
+        Ok(Self { pitches })
+    }
+
+    // None for a key the keyboard mapping leaves out, it doesn't sound
+    pub fn pitch(&self, key: u8) -> Option<f32> {
+        self.pitches.get(key as usize).copied().flatten()
+    }
+
+    // The tuning as a scale of a degree per key starting on key 0, for synths that read Scala
+    // files but not keyboard mappings. Keys left out stay in 12-TET there.
+    pub fn to_scala(&self) -> String {
+        let pitch = |key: usize| self.pitches[key].unwrap_or(key as f32);
+        let lowest = pitch(0);
+        let cents = |pitch: f32| (pitch - lowest) * 100.0;
+        let mut text = format!("! Made by AMI\nKey by key\n{NUM_KEYS}\n");
+        for key in 1..NUM_KEYS {
+            text.push_str(&format!("{:.5}\n", cents(pitch(key))));
+        }
+        text.push_str(&format!("{:.5}\n", cents(pitch(NUM_KEYS - 1) + 1.0)));
+        text
+    }
+
+    // Of A4, the key the synths tune from
+    pub fn reference_frequency(&self) -> f32 {
+        let pitch = self.pitch(69).unwrap_or(69.0);
+        440.0 * 2f32.powf((pitch - 69.0) / 12.0)
+    }
+}
+
+// Cents of the degrees from 1 up, the last one is the period the scale repeats at
+fn parse_scale(text: &str) -> Result<Vec<f64>, FileError> {
+    let mut lines = text.lines().filter(|line| !line.starts_with('!'));
+    let _description = lines.next();
+    let mut values = lines.filter_map(|line| line.split_whitespace().next());
+    let count: usize = values
+        .next()
+        .and_then(|count| count.parse().ok())
+        .ok_or(FileError::InvalidFile)?;
+    let degrees = values
+        .take(count)
+        .map(parse_pitch)
+        .collect::<Option<Vec<_>>>()
+        .ok_or(FileError::InvalidFile)?;
+    if count == 0 || degrees.len() < count {
+        return Err(FileError::InvalidFile);
+    }
+    Ok(degrees)
+}
+
+// Cents have a period, everything else is a ratio like 3/2 or 2
+fn parse_pitch(value: &str) -> Option<f64> {
+    if value.contains('.') {
+        return value.parse().ok();
+    }
+    let (numerator, denominator) = value.split_once('/').unwrap_or((value, "1"));
+    let numerator: f64 = numerator.parse().ok()?;
+    let denominator: f64 = denominator.parse().ok()?;
+    let ratio = numerator / denominator;
+    (ratio > 0.0 && ratio.is_finite()).then(|| 1200.0 * ratio.log2())
+}
+
+#[derive(Debug)]
+struct KeyboardMapping {
+    // Inclusive, the keys outside play 12-TET
+    first_key: i32,
+    last_key: i32,
+    // Plays degree 0
+    middle_key: i32,
+    reference_key: i32,
+    reference_frequency: f64,
+    // The degree the mapping repeats at, 0 for the period of the scale
+    octave_degree: usize,
+    // The degree of every key of a repetition, none for the ones that don't sound. Empty maps
+    // the keys one by one to the degrees.
+    degrees: Vec<Option<usize>>,
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self {
+            first_key: 0,
+            last_key: NUM_KEYS as i32 - 1,
+            middle_key: DEFAULT_MIDDLE_KEY,
+            reference_key: DEFAULT_MIDDLE_KEY,
+            reference_frequency: DEFAULT_REFERENCE_FREQUENCY,
+            octave_degree: 0,
+            degrees: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardMapping {
+    // From degree 0 on the middle key
+    fn cents(&self, scale: &[f64], key: i32) -> Option<f64> {
+        let offset = key - self.middle_key;
+        if self.degrees.is_empty() {
+            return Some(degree_cents(scale, offset));
+        }
+        let size = self.degrees.len() as i32;
+        let degree = self.degrees[offset.rem_euclid(size) as usize]?;
+        let octave = match self.octave_degree {
+            0 => degree_cents(scale, scale.len() as i32),
+            octave_degree => degree_cents(scale, octave_degree as i32),
+        };
+        Some(offset.div_euclid(size) as f64 * octave + degree_cents(scale, degree as i32))
+    }
+}
+
+fn degree_cents(scale: &[f64], degree: i32) -> f64 {
+    let count = scale.len() as i32;
+    let period = scale[scale.len() - 1];
+    let cents = match degree.rem_euclid(count) {
+        0 => 0.0,
+        index => scale[index as usize - 1],
+    };
+    degree.div_euclid(count) as f64 * period + cents
+}
+
+fn parse_keyboard_mapping(text: &str) -> Result<KeyboardMapping, FileError> {
+    let mut values = text
+        .lines()
+        .filter(|line| !line.starts_with('!'))
+        .filter_map(|line| line.split_whitespace().next());
+    let mut next = || values.next().ok_or(FileError::InvalidFile);
+    let int = |value: &str| value.parse::<i32>().map_err(|_| FileError::InvalidFile);
+    // the keys are MIDI keys, a mapping can't repeat after more of them than there are
+    let key = |value: &str| {
+        let key = int(value)?;
+        if (0..NUM_KEYS as i32).contains(&key) {
+            Ok(key)
+        } else {
+            Err(FileError::InvalidFile)
+        }
+    };
+    let size = int(next()?)?.max(0) as usize;
+    if size > NUM_KEYS {
+        return Err(FileError::InvalidFile);
+    }
+    let first_key = key(next()?)?;
+    let last_key = key(next()?)?;
+    let middle_key = key(next()?)?;
+    let reference_key = key(next()?)?;
+    let reference_frequency: f64 = next()?.parse().map_err(|_| FileError::InvalidFile)?;
+    let octave_degree = int(next()?)?.max(0) as usize;
+    if !(reference_frequency.is_finite() && reference_frequency > 0.0) {
+        return Err(FileError::InvalidFile);
+    }
+    // x leaves a key out, and so does a mapping that ends early
+    let degrees = (0..size)
+        .map(|_| next().ok().and_then(|value| value.parse().ok()))
+        .collect();
+    Ok(KeyboardMapping {
+        first_key,
+        last_key,
+        middle_key,
+        reference_key,
+        reference_frequency,
+        octave_degree,
+        degrees,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tuning;
+    use crate::files::FileError;
+
+    fn assert_pitch(tuning: &Tuning, key: u8, pitch: f32) {
+        let tuned = tuning.pitch(key).unwrap();
+        assert!((tuned - pitch).abs() < 1e-3, "{key}: {tuned} != {pitch}");
+    }
+
+    #[test]
+    fn scales() {
+        let twelve = "! 12-TET\n12-TET\n 12 notes\n!\n100.0\n200.\n300.0\n400.0\n500.0\n600.0\n\
+                      700.0\n800.0\n900.0\n1000.0\n1100.0\n2/1\n";
+        let tuning = Tuning::parse(twelve, None).unwrap();
+        for key in 0..128 {
+            assert_pitch(&tuning, key, key as f32);
+        }
+        assert!((tuning.reference_frequency() - 440.0).abs() < 1e-2);
+
+        // a fifth, middle C stays
+        let just = "Just\n2\n3/2 the fifth\n2\n";
+        let tuning = Tuning::parse(just, None).unwrap();
+        assert_pitch(&tuning, 60, 60.0);
+        assert_pitch(&tuning, 61, 67.01955);
+        assert_pitch(&tuning, 62, 72.0);
+        assert_pitch(&tuning, 59, 55.01955);
+        assert_pitch(&tuning, 58, 48.0);
+
+        assert_eq!(
+            Tuning::parse("Broken\n3\n100.0\n", None),
+            Err(FileError::InvalidFile)
+        );
+        assert_eq!(
+            Tuning::parse("Broken\n1\nfifth\n", None),
+            Err(FileError::InvalidFile)
+        );
+    }
+
+    #[test]
+    fn keyboard_mappings() {
+        let quarter_tones = "24 quarter tones\n24\n".to_owned()
+            + &(1..=24)
+                .map(|step| format!("{}.0\n", step * 50))
+                .collect::<String>();
+        // quarter tones on every other key from C4 on, the last key of four left out by ending
+        // early, and C4 at 256 Hz
+        let mapping = "! mapping\n4\n60\n127\n60\n60\n256.0\n2\n! the degrees\n0\nx\n1\n";
+        let tuning = Tuning::parse(&quarter_tones, Some(mapping)).unwrap();
+        let c4 = 69.0 + 12.0 * (256f32 / 440.0).log2();
+        assert_pitch(&tuning, 60, c4);
+        assert_eq!(tuning.pitch(61), None);
+        assert_pitch(&tuning, 62, c4 + 0.5);
+        assert_eq!(tuning.pitch(63), None);
+        assert_pitch(&tuning, 64, c4 + 1.0);
+        assert_pitch(&tuning, 66, c4 + 1.5);
+        assert_pitch(&tuning, 59, 59.0);
+        // A4 is left out and stays where it is
+        assert!((tuning.reference_frequency() - 440.0).abs() < 1e-2);
+
+        // the scale for sfizz, starting on key 0 with A4 at the reference frequency
+        let scala = tuning.to_scala();
+        assert_eq!(scala.lines().count(), 3 + 128);
+        let frequency = tuning.reference_frequency();
+        let mapping = format!("0\n0\n127\n0\n69\n{frequency}\n0\n");
+        let key_by_key = Tuning::parse(&scala, Some(&mapping)).unwrap();
+        for key in [0, 59, 60, 61, 66, 127] {
+            let pitch = tuning.pitch(key).unwrap_or(key as f32);
+            assert_pitch(&key_by_key, key, pitch);
+        }
+    }
+
+    #[test]
+    fn malformed_mappings() {
+        let scale = "12-TET\n1\n2/1\n";
+        assert!(Tuning::parse(scale, Some("0\n0\n127\n60\n69\n440.0\n0\n")).is_ok());
+        for mapping in [
+            "2147483647\n0\n127\n60\n69\n440.0\n0\n",
+            "0\n0\n127\n-2147483648\n69\n440.0\n0\n",
+            "0\n0\n128\n60\n69\n440.0\n0\n",
+            "0\n0\n127\n60\n69\ninf\n0\n",
+            "0\n0\n127\n60\n69\nNaN\n0\n",
+        ] {
+            let res = Tuning::parse(scale, Some(mapping));
+            assert_eq!(res.err(), Some(FileError::InvalidFile), "{mapping:?}");
+        }
+    }
+}
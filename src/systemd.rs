@@ -0,0 +1,119 @@
+// Running as a systemd service with `Type=notify`: systemd hears when AMI is ready and when it
+// stops, and with `WatchdogSec=` it restarts AMI once the pings stop. They only go out while
+// the loops of the drum machine, the controller, the renderer and the audio output all still
+// go around.
+
+use crate::{app::App, audio};
+use std::{env, time::Duration};
+use tracing::{info, warn};
+
+pub const READY: &str = "READY=1";
+pub const STOPPING: &str = "STOPPING=1";
+const WATCHDOG: &str = "WATCHDOG=1";
+
+// Whether systemd got the state, not run by systemd it goes nowhere
+pub fn notify(state: &str) -> bool {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return false;
+    };
+    match send(&socket, state) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to notify systemd: {e}");
+            false
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let datagram = UnixDatagram::unbound()?;
+    match socket.as_bytes() {
+        // an abstract socket
+        #[cfg(target_os = "linux")]
+        [b'@', name @ ..] => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+// How often systemd wants to hear from AMI, none without a watchdog for this process
+pub fn watchdog_timeout() -> Option<Duration> {
+    let pid = env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid != std::process::id().to_string()) {
+        return None;
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+// Pings twice per timeout, a loop that hasn't gone around for half of it counts as wedged
+pub async fn run_watchdog(app: App, timeout: Duration) {
+    let period = timeout / 2;
+    info!("| systemd watchdog every {} ms", period.as_millis());
+    loop {
+        tokio::time::sleep(period).await;
+        match wedged(&app, period).await {
+            Some(part) => warn!("The {part} is not responding, systemd will restart AMI"),
+            None => {
+                notify(WATCHDOG);
+            }
+        }
+    }
+}
+
+async fn wedged(app: &App, timeout: Duration) -> Option<&'static str> {
+    if app.heartbeats.drum_machine.age() > timeout {
+        return Some("drum machine");
+    }
+    if app.heartbeats.controller.age() > timeout {
+        return Some("controller");
+    }
+    // the audio callback holds it while rendering
+    if tokio::time::timeout(timeout, app.renderer.lock())
+        .await
+        .is_err()
+    {
+        return Some("renderer");
+    }
+    if let Some(audio) = &app.requesters.audio {
+        let req = audio::output::RequestKind::GetDeviceStatus;
+        let res = tokio::time::timeout(timeout, crate::app::send_audio_request(audio, req));
+        if !matches!(res.await, Ok(Some(_))) {
+            return Some("audio output");
+        }
+    }
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn notifies() {
+        let dir = std::env::temp_dir().join(format!("ami-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify");
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        super::send(path.as_os_str(), super::READY).unwrap();
+        let mut buf = [0; 16];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
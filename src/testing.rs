@@ -0,0 +1,620 @@
+// Test support for running the whole stack in process: the app behind a webserver on an
+// ephemeral port, a dummy audio backend pulling blocks from the renderer, virtual MIDI
+// injected straight into the MIDI channel and websocket clients talking JSON to the server
+
+use crate::{
+    app::App,
+    deser::{DeserializationResult, SerializationResult},
+    json::{JsonUpdateKind, JsonUpdater},
+    midi::{self, MidiReader},
+    path::VirtualPaths,
+    render::{
+        command::ResponseCallback,
+        node::{Render, RenderPtr, RequestKind},
+    },
+    webserver::{
+        self, ClientMessage, ClientMessageKind, Encoding, ServerMessage, ServerMessageKind,
+    },
+};
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinHandle,
+};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+pub const SAMPLE_RATE: u32 = 44100;
+pub const BLOCK_SIZE: usize = 64;
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct TestStack {
+    pub app: App,
+    pub addr: SocketAddr,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl TestStack {
+    pub async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    // `configure` sets up the webserver, e.g. its password
+    pub async fn start_with(configure: impl FnOnce(&mut webserver::SharedState)) -> Self {
+        let (midi_tx, _) = midi::create_channel(32);
+        let midi_reader = MidiReader::with_slots(midi_tx.clone(), 16);
+        let app = App::new(midi_tx, midi_reader, VirtualPaths::default());
+        {
+            let mut renderer = app.renderer.lock().await;
+            renderer.register_node_kind("TestTone", || Box::<TestTone>::default());
+            renderer.set_sample_rate(SAMPLE_RATE);
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind test listener");
+        let addr = listener.local_addr().expect("Test listener has no address");
+
+        let app2 = app.clone();
+        let mut state = app.shared_state();
+        configure(&mut state);
+        let server = tokio::spawn(webserver::serve(listener, state, move |addr, req| {
+            let app = app2.clone();
+            async move { app.handle_client_message(addr, req).await }
+        }));
+        let backend = tokio::spawn(run_dummy_backend(app.clone()));
+
+        Self {
+            app,
+            addr,
+            tasks: vec![server, backend],
+        }
+    }
+
+    // Returns once the initial cache arrived, the client receives every broadcast from then on
+    pub async fn connect(&self) -> TestClient {
+        self.connect_with(Encoding::Json).await
+    }
+
+    pub async fn connect_with(&self, encoding: Encoding) -> TestClient {
+        match encoding {
+            Encoding::Json => self.connect_to("", encoding).await,
+            Encoding::MsgPack => self.connect_to("?encoding=msgpack", encoding).await,
+        }
+    }
+
+    pub async fn connect_spectator(&self) -> TestClient {
+        self.connect_to("?spectator=true", Encoding::Json).await
+    }
+
+    async fn connect_to(&self, query: &str, encoding: Encoding) -> TestClient {
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws{query}", self.addr))
+            .await
+            .expect("Failed to connect to the test server");
+        let mut client = TestClient {
+            ws,
+            encoding,
+            next_id: 1,
+            broadcasts: VecDeque::new(),
+            cache: serde_json::Value::Null,
+        };
+        let ServerMessageKind::Cache(cache) = client
+            .wait_broadcast(|msg| matches!(msg, ServerMessageKind::Cache(_)))
+            .await
+        else {
+            unreachable!()
+        };
+        client.cache = cache;
+        client
+    }
+
+    // Virtual MIDI input, as if it came from a connected port
+    pub fn send_midi(&self, message: midi::Message) {
+        self.app
+            .midi_tx
+            .send(message)
+            .expect("No MIDI receivers in the test stack");
+    }
+
+    // The raw response to a plain HTTP GET
+    pub async fn http_get(&self, path: &str) -> String {
+        let request =
+            format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        self.http_request(&request).await
+    }
+
+    pub async fn http_post(&self, path: &str, body: &str) -> String {
+        let request = format!(
+            "POST {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        self.http_request(&request).await
+    }
+
+    async fn http_request(&self, request: &str) -> String {
+        let mut stream = TcpStream::connect(self.addr)
+            .await
+            .expect("Failed to connect to the test server");
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .expect("Failed to send an HTTP request");
+        let mut response = String::new();
+        tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+            .await
+            .expect("Timed out waiting for an HTTP response")
+            .expect("Failed to read an HTTP response");
+        response
+    }
+
+    pub async fn render(&self, frames: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut lbuf = vec![0.0; frames];
+        let mut rbuf = vec![0.0; frames];
+        self.app.renderer.lock().await.render(&mut lbuf, &mut rbuf);
+        (lbuf, rbuf)
+    }
+}
+
+impl Drop for TestStack {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+// A directory of its own for a test, tests running in parallel never share one and it's
+// removed even when the test fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let unique = NEXT.fetch_add(1, Ordering::Relaxed);
+        let file_name = format!("ami-{name}-{}-{unique}", std::process::id());
+        let path = std::env::temp_dir().join(file_name);
+        std::fs::create_dir_all(&path).expect("Failed to create a test directory");
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Stands in for the audio output, the renderer only picks up requests while rendering
+async fn run_dummy_backend(app: App) {
+    let mut lbuf = [0.0; BLOCK_SIZE];
+    let mut rbuf = [0.0; BLOCK_SIZE];
+    loop {
+        app.renderer.lock().await.render(&mut lbuf, &mut rbuf);
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+pub struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    encoding: Encoding,
+    next_id: usize,
+    broadcasts: VecDeque<ServerMessageKind>,
+    pub cache: serde_json::Value,
+}
+
+impl TestClient {
+    pub async fn request(&mut self, payload: ClientMessageKind) -> ServerMessageKind {
+        let id = self.next_id;
+        self.next_id += 1;
+        let msg = ClientMessage {
+            id,
+            request: true,
+            payload,
+        };
+        let msg = match self.encoding {
+            Encoding::Json => Message::Text(
+                serde_json::to_string(&msg).expect("Failed to serialize client message"),
+            ),
+            Encoding::MsgPack => Message::Binary(
+                rmp_serde::to_vec_named(&msg).expect("Failed to serialize client message"),
+            ),
+        };
+        self.ws
+            .send(msg)
+            .await
+            .expect("Failed to send client message");
+
+        loop {
+            let msg = self.receive().await;
+            if msg.response && msg.id == id {
+                return msg.payload;
+            } else if !msg.response {
+                self.broadcasts.push_back(msg.payload);
+            }
+        }
+    }
+
+    // Skips broadcasts until one matches, including those received while waiting for responses
+    pub async fn wait_broadcast<F>(&mut self, mut matches: F) -> ServerMessageKind
+    where
+        F: FnMut(&ServerMessageKind) -> bool,
+    {
+        while let Some(payload) = self.broadcasts.pop_front() {
+            if matches(&payload) {
+                return payload;
+            }
+        }
+        loop {
+            let msg = self.receive().await;
+            if !msg.response && matches(&msg.payload) {
+                return msg.payload;
+            }
+        }
+    }
+
+    async fn receive(&mut self) -> ServerMessage {
+        loop {
+            let msg = tokio::time::timeout(TIMEOUT, self.ws.next())
+                .await
+                .expect("Timed out waiting for a server message")
+                .expect("Server closed the connection")
+                .expect("Websocket error");
+            match msg {
+                Message::Text(msg) => {
+                    return serde_json::from_str(&msg).expect("Invalid server message");
+                }
+                Message::Binary(msg) => {
+                    assert_eq!(
+                        self.encoding,
+                        Encoding::MsgPack,
+                        "Unexpected binary message"
+                    );
+                    return rmp_serde::from_slice(&msg).expect("Invalid server message");
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// FNV-1a over the sample bits, stable across runs and platforms
+pub fn checksum(samples: &[f32]) -> u64 {
+    samples
+        .iter()
+        .flat_map(|sample| sample.to_bits().to_le_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+// Deterministic render node, every held note adds a constant level (note / 128 on the
+// left channel, velocity / 128 on the right one), so the output doesn't depend on how
+// many blocks were rendered before
+#[derive(Default, Clone)]
+pub struct TestTone {
+    notes: Vec<(u8, u8)>,
+}
+
+impl Render for TestTone {
+    fn render_additive(&mut self, lbuf: &mut [f32], rbuf: &mut [f32]) {
+        for (note, velocity) in &self.notes {
+            lbuf.iter_mut().for_each(|s| *s += *note as f32 / 128.0);
+            rbuf.iter_mut().for_each(|s| *s += *velocity as f32 / 128.0);
+        }
+    }
+
+    fn reset_rendering(&mut self) {
+        self.notes.clear();
+    }
+
+    fn set_virtual_paths(&mut self, _vp: VirtualPaths) {}
+
+    fn set_sample_rate(&mut self, _sample_rate: u32) {}
+
+    fn receive_midi_message(&mut self, message: &midi::Message) {
+        match message.kind {
+            midi::MessageKind::NoteOn { note, velocity } => {
+                self.notes.retain(|(n, _)| *n != note);
+                self.notes.push((note, velocity));
+            }
+            midi::MessageKind::NoteOff { note, .. } => self.notes.retain(|(n, _)| *n != note),
+            _ => {}
+        }
+    }
+
+    fn panic(&mut self) {
+        self.notes.clear();
+    }
+
+    fn set_global_transposition(&mut self, _transposition: i8) {}
+
+    fn set_json_updater(&mut self, _updater: JsonUpdater) {}
+
+    fn process_request(&mut self, _kind: RequestKind, cb: ResponseCallback) {
+        cb(JsonUpdateKind::Denied)
+    }
+
+    fn serialize(&self) -> SerializationResult {
+        Ok(json!({ "notes": self.notes.len() }))
+    }
+
+    fn deserialize(&mut self, _source: &serde_json::Value) -> DeserializationResult {
+        Ok(())
+    }
+
+    fn clone_node(&self) -> RenderPtr {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, TempDir, TestStack};
+    use crate::{
+        control::drum_machine,
+        midi::{Message, MessageKind},
+        render::command,
+        sync,
+        webserver::{ClientMessageKind, Encoding, Role, ServerMessageKind},
+    };
+    use serde_json::json;
+
+    fn note_on(note: u8, velocity: u8) -> Message {
+        Message::new(0, MessageKind::NoteOn { note, velocity })
+    }
+
+    #[tokio::test]
+    async fn ping_and_initial_cache() {
+        let stack = TestStack::start().await;
+        let mut client = stack.connect().await;
+
+        assert!(client.cache["nodes"]
+            .as_array()
+            .is_some_and(|n| n.is_empty()));
+        assert!(client.cache["drum_machine"].is_object());
+
+        let res = client.request(ClientMessageKind::Ping).await;
+        assert!(matches!(res, ServerMessageKind::Pong));
+    }
+
+    #[tokio::test]
+    async fn virtual_midi_is_broadcast() {
+        let stack = TestStack::start().await;
+        let mut client = stack.connect().await;
+
+        stack.send_midi(note_on(60, 100));
+        let event = client
+            .wait_broadcast(|msg| matches!(msg, ServerMessageKind::MidiEvent(_)))
+            .await;
+        assert!(matches!(event, ServerMessageKind::MidiEvent(msg) if msg == note_on(60, 100)));
+    }
+
+    #[tokio::test]
+    async fn rendered_audio() {
+        let stack = TestStack::start().await;
+        let mut client = stack.connect().await;
+        let mut other = stack.connect().await;
+
+        let res = client
+            .request(ClientMessageKind::RendererRequest(
+                command::RequestKind::AddNode {
+                    kind: "TestTone".into(),
+                },
+            ))
+            .await;
+        assert!(matches!(res, ServerMessageKind::Ack));
+        other
+            .wait_broadcast(|msg| {
+                matches!(
+                    msg,
+                    ServerMessageKind::RendererResponse(command::ResponseKind::AddNode { .. })
+                )
+            })
+            .await;
+        let (left, right) = stack.render(256).await;
+        assert_eq!(checksum(&left), checksum(&[0.0; 256]));
+        assert_eq!(checksum(&right), checksum(&[0.0; 256]));
+
+        stack.send_midi(note_on(64, 32));
+        let (left, right) = stack.render(256).await;
+        assert_eq!(checksum(&left), checksum(&[0.5; 256]));
+        assert_eq!(checksum(&right), checksum(&[0.25; 256]));
+
+        stack.send_midi(Message::new(
+            0,
+            MessageKind::NoteOff {
+                note: 64,
+                velocity: 0,
+            },
+        ));
+        let (left, _) = stack.render(256).await;
+        assert_eq!(checksum(&left), checksum(&[0.0; 256]));
+    }
+
+    #[tokio::test]
+    async fn password_protection() {
+        let stack = TestStack::start_with(|state| state.password = Some("secret".into())).await;
+        let mut client = stack.connect().await;
+        let change =
+            || ClientMessageKind::DrumMachineRequest(drum_machine::RequestKind::SetSwing(10));
+
+        let res = client.request(ClientMessageKind::Ping).await;
+        assert!(matches!(res, ServerMessageKind::Pong));
+        let res = client.request(change()).await;
+        assert!(matches!(res, ServerMessageKind::Unauthorized));
+
+        let res = client
+            .request(ClientMessageKind::Authenticate("guess".into()))
+            .await;
+        assert!(matches!(res, ServerMessageKind::Nak));
+        let res = client
+            .request(ClientMessageKind::Authenticate("secret".into()))
+            .await;
+        assert!(matches!(res, ServerMessageKind::Ack));
+        let res = client.request(change()).await;
+        assert!(matches!(res, ServerMessageKind::Ack));
+
+        // every connection authenticates on its own
+        let mut other = stack.connect().await;
+        let res = other.request(change()).await;
+        assert!(matches!(res, ServerMessageKind::Unauthorized));
+    }
+
+    #[tokio::test]
+    async fn uploads_stay_in_their_root() {
+        let root = TempDir::new("web-upload");
+        let samples = root.join("samples");
+        std::fs::create_dir_all(&samples).unwrap();
+        let stack = TestStack::start_with(|state| {
+            state
+                .virtual_paths
+                .insert("samples:".into(), samples.clone())
+        })
+        .await;
+
+        let res = stack.http_post("/upload/samples:/kick.wav", "kick").await;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert_eq!(
+            std::fs::read_to_string(samples.join("kick.wav")).unwrap(),
+            "kick"
+        );
+        for path in ["samples:/../escaped.wav", "samples:/a/../../escaped.wav"] {
+            let res = stack.http_post(&format!("/upload/{path}"), "x").await;
+            assert!(res.starts_with("HTTP/1.1 400"));
+        }
+        assert!(!root.join("escaped.wav").exists());
+    }
+
+    #[tokio::test]
+    async fn msgpack_encoding() {
+        let stack = TestStack::start().await;
+        let mut json_client = stack.connect().await;
+        let mut client = stack.connect_with(Encoding::MsgPack).await;
+        assert_eq!(client.cache, json_client.cache);
+
+        let res = client.request(ClientMessageKind::Ping).await;
+        assert!(matches!(res, ServerMessageKind::Pong));
+        let change = ClientMessageKind::DrumMachineRequest(drum_machine::RequestKind::SetSwing(10));
+        let res = client.request(change).await;
+        assert!(matches!(res, ServerMessageKind::Ack));
+
+        // both get the broadcast, each in its own encoding
+        for client in [&mut client, &mut json_client] {
+            client
+                .wait_broadcast(|msg| matches!(msg, ServerMessageKind::DrumMachineUpdate(_)))
+                .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn delta_sync() {
+        let stack = TestStack::start().await;
+        let mut client = stack.connect().await;
+        let ServerMessageKind::Snapshot(seq, mut state) =
+            client.request(ClientMessageKind::Sync(None)).await
+        else {
+            panic!("Expected a snapshot");
+        };
+
+        let change = ClientMessageKind::DrumMachineRequest(drum_machine::RequestKind::SetSwing(10));
+        let res = client.request(change).await;
+        assert!(matches!(res, ServerMessageKind::Ack));
+        let ServerMessageKind::StateDelta(delta) = client
+            .wait_broadcast(|msg| matches!(msg, ServerMessageKind::StateDelta(_)))
+            .await
+        else {
+            unreachable!();
+        };
+        assert_eq!(delta.seq, seq + 1);
+        sync::apply(&mut state, &delta.ops).unwrap();
+        assert_eq!(state["drum_machine"]["swing"], json!(10));
+
+        // a client coming back gets what it missed
+        let mut other = stack.connect().await;
+        let res = other.request(ClientMessageKind::Sync(Some(seq))).await;
+        assert!(matches!(res, ServerMessageKind::Deltas(deltas) if deltas == vec![delta]));
+        let res = other.request(ClientMessageKind::Sync(Some(seq + 5))).await;
+        assert!(matches!(res, ServerMessageKind::Snapshot(s, _) if s == seq + 1));
+    }
+
+    #[tokio::test]
+    async fn spectators() {
+        let stack = TestStack::start().await;
+        let mut editor = stack.connect().await;
+        let mut spectator = stack.connect_spectator().await;
+        let change =
+            || ClientMessageKind::DrumMachineRequest(drum_machine::RequestKind::SetSwing(10));
+
+        let res = spectator.request(change()).await;
+        assert!(matches!(res, ServerMessageKind::Unauthorized));
+        let res = editor.request(change()).await;
+        assert!(matches!(res, ServerMessageKind::Ack));
+        // spectators still get the broadcasts
+        spectator
+            .wait_broadcast(|msg| matches!(msg, ServerMessageKind::DrumMachineUpdate(_)))
+            .await;
+
+        let ServerMessageKind::ClientList(list) =
+            spectator.request(ClientMessageKind::ListClients).await
+        else {
+            panic!("Expected the client list");
+        };
+        let (addr, _) = *list
+            .iter()
+            .find(|(_, role)| *role == Role::Spectator)
+            .unwrap();
+        let res = spectator
+            .request(ClientMessageKind::SetClientRole(addr, Role::Editor))
+            .await;
+        assert!(matches!(res, ServerMessageKind::Unauthorized));
+        let res = editor
+            .request(ClientMessageKind::SetClientRole(addr, Role::Editor))
+            .await;
+        assert!(matches!(res, ServerMessageKind::Ack));
+        let res = spectator.request(change()).await;
+        assert!(matches!(res, ServerMessageKind::Ack));
+    }
+
+    #[tokio::test]
+    async fn static_web_client() {
+        let root = TempDir::new("web");
+        std::fs::write(root.join("index.html"), "<html>index</html>").unwrap();
+        std::fs::write(root.join("app.js"), "run()").unwrap();
+        let web_root = root.to_path_buf();
+        let stack = TestStack::start_with(|state| state.web_root = Some(web_root)).await;
+
+        let script = stack.http_get("/app.js").await;
+        assert!(script.starts_with("HTTP/1.1 200"));
+        assert!(script.contains("content-type: application/javascript"));
+        assert!(script.ends_with("run()"));
+        // routes of the client
+        let route = stack.http_get("/drum-machine/patterns").await;
+        assert!(route.contains("content-type: text/html") && route.ends_with("<html>index</html>"));
+        assert!(stack
+            .http_get("/missing.css")
+            .await
+            .starts_with("HTTP/1.1 404"));
+        assert!(stack
+            .http_get("/../index.html")
+            .await
+            .starts_with("HTTP/1.1 404"));
+    }
+}
@@ -1,23 +1,38 @@
 use crate::{
-    control::drum_machine, json::JsonUpdateKind, midi::{self, MidiReader}, render::command
+    audio, control::{self, drum_machine}, files::{DiskUsage, FileError, FileInfo, SoundfontPreset, Upload}, json::JsonUpdateKind, midi::{self, MidiReader}, pads, path::VirtualPaths, performances, render::{self, command, load::Load, meter::Levels}, setlist, sfz, snapshots, sync::{self, Delta, PatchOp, MAX_DELTA_HISTORY}
 };
 use axum::{
+    body::Body,
     extract::{
         ws::{Message, WebSocket},
-        ConnectInfo, State, WebSocketUpgrade,
+        ConnectInfo, Path, Query, State, WebSocketUpgrade,
     },
-    http::Method,
-    response::IntoResponse,
-    routing::get,
+    http::{header, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json,
 };
 use axum_embed::ServeEmbed;
 use axum_extra::{headers, TypedHeader};
+use axum_server::tls_rustls::RustlsConfig;
 use futures::{stream::SplitSink, Future, SinkExt, StreamExt};
 use rust_embed::Embed;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
-use tokio::sync::{broadcast, Mutex};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc, Mutex},
+};
 use tower_http::cors::CorsLayer;
 use tracing::{error, info, warn};
 
@@ -30,9 +45,68 @@ pub struct SharedState {
     pub clients: Clients,
     pub midi_reader: Arc<Mutex<MidiReader>>,
     pub cache: Arc<Mutex<Cache>>,
+    pub virtual_paths: VirtualPaths,
+    // Without one every client may do everything
+    pub password: Option<Arc<str>>,
+    // The web client is served from here instead of the embedded build
+    pub web_root: Option<PathBuf>,
+}
+
+// Slows down guessing the password
+const FAILED_AUTHENTICATION_DELAY: Duration = Duration::from_millis(500);
+
+// Progress is broadcast every time this many more bytes of an upload arrived
+const UPLOAD_PROGRESS_STEP: u64 = 1024 * 1024;
+
+// PEM files, with them the server speaks https and wss only
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+pub async fn run<F, Fut>(http_port: u16, tls: Option<TlsConfig>, state: SharedState, req_handler: F)
+where
+    F: FnMut(SocketAddr, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    if let Some(tls) = tls {
+        info!("Starting server on https://localhost:{http_port}/");
+
+        let config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+            .await
+            .expect("Failed to load the TLS certificate or key");
+        axum_server::bind_rustls(SocketAddr::from(([0, 0, 0, 0], http_port)), config)
+            .serve(router(state, req_handler).into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+        return;
+    }
+
+    info!("Starting server on http://localhost:{http_port}/");
+
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{http_port}"))
+        .await
+        .unwrap();
+
+    serve(listener, state, req_handler).await;
+}
+
+// Serves on an already bound listener, tests bind to an ephemeral port this way
+pub async fn serve<F, Fut>(listener: TcpListener, state: SharedState, req_handler: F)
+where
+    F: FnMut(SocketAddr, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    axum::serve(
+        listener,
+        router(state, req_handler).into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
-pub async fn run<F, Fut>(http_port: u16, state: SharedState, req_handler: F)
+fn router<F, Fut>(state: SharedState, req_handler: F) -> axum::Router
 where
     F: FnMut(SocketAddr, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
     Fut: Future<Output = ServerMessageKind> + Send + 'static,
@@ -51,35 +125,81 @@ where
         ])
         .allow_origin(tower_http::cors::Any);
 
-    let wc_assets = ServeEmbed::<WebClientAssets>::new();
-
-    let app = axum::Router::new()
-        .fallback_service(wc_assets)
+    let router = axum::Router::new()
         .route("/ws", get(ws_handler))
+        .route("/upload/*path", post(upload_handler));
+    let router = if state.web_root.is_some() {
+        router.fallback(get(static_file_handler))
+    } else {
+        router.fallback_service(ServeEmbed::<WebClientAssets>::new())
+    };
+
+    router
         .layer(cors)
         // .layer(
         //     TraceLayer::new_for_http()
         //         .make_span_with(DefaultMakeSpan::default().include_headers(true)),
         // )
-        .with_state((state, req_handler));
+        .with_state((state, req_handler))
     // .route("/", get(|| async { "Hello, World!" }))
+}
 
-    info!("Starting server on http://localhost:{http_port}/");
+async fn static_file_handler<F, Fut>(
+    State((state, _)): State<(SharedState, F)>,
+    uri: Uri,
+) -> Response
+where
+    F: FnMut(SocketAddr, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    let Some(root) = &state.web_root else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Some(path) = resolve_static_file(root, uri.path()).await else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match tokio::fs::read(&path).await {
+        Ok(content) => {
+            let mime = mime_guess::from_path(&path).first_or_octet_stream();
+            ([(header::CONTENT_TYPE, mime.to_string())], content).into_response()
+        }
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{http_port}"))
+// The file for a request path, paths which aren't files and don't look like one either are
+// routes of the client, they get its index.html
+pub async fn resolve_static_file(root: &std::path::Path, uri_path: &str) -> Option<PathBuf> {
+    let relative = uri_path.trim_start_matches('/');
+    let path = root.join(relative);
+    if !crate::path::is_path_within_base(&path, root) {
+        return None;
+    }
+    if tokio::fs::metadata(&path)
         .await
-        .unwrap();
+        .is_ok_and(|metadata| metadata.is_file())
+    {
+        Some(path)
+    } else if std::path::Path::new(relative).extension().is_some() {
+        None
+    } else {
+        Some(root.join("index.html"))
+    }
+}
 
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+#[derive(Debug, Default, Deserialize)]
+struct WsParams {
+    #[serde(default)]
+    encoding: Encoding,
+    // Stage displays and such which must not change anything, even without a password
+    #[serde(default)]
+    spectator: bool,
 }
 
+// GET /ws?encoding=<json|msgpack>&spectator=<bool>
 async fn ws_handler<F, Fut>(
     ws: WebSocketUpgrade,
+    Query(params): Query<WsParams>,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State((state, req_handler)): State<(SharedState, F)>,
@@ -95,12 +215,105 @@ where
         "New connection from {addr}. (clients connected: {})",
         state.clients.len().await + 1
     );
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, state, req_handler))
+    ws.on_upgrade(move |socket| handle_socket(socket, addr, params, state, req_handler))
+}
+
+// POST /upload/<virtual path>, the body is the file content
+async fn upload_handler<F, Fut>(
+    Path(path): Path<PathBuf>,
+    content_length: Option<TypedHeader<headers::ContentLength>>,
+    bearer: Option<TypedHeader<headers::Authorization<headers::authorization::Bearer>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State((mut state, _)): State<(SharedState, F)>,
+    body: Body,
+) -> impl IntoResponse
+where
+    F: FnMut(SocketAddr, ClientMessageKind) -> Fut + Send + Sync + Clone + 'static,
+    Fut: Future<Output = ServerMessageKind> + Send + 'static,
+{
+    if let Some(password) = &state.password {
+        let given = bearer.as_ref().map_or("", |TypedHeader(auth)| auth.token());
+        if !password_matches(password, given) {
+            warn!("Unauthorized upload of {path:?} from {addr}");
+            tokio::time::sleep(FAILED_AUTHENTICATION_DELAY).await;
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+    let size = content_length.map(|TypedHeader(length)| length.0);
+    let result = receive_upload(&mut state, &path, size, body).await;
+    match &result {
+        Ok(()) => info!("Upload of {path:?} from {addr} finished"),
+        Err(e) => warn!("Upload of {path:?} from {addr} failed: {e:?}"),
+    }
+    let status = match &result {
+        Ok(()) => StatusCode::OK,
+        Err(FileError::InvalidPath) => StatusCode::BAD_REQUEST,
+        Err(FileError::NotFound) => StatusCode::NOT_FOUND,
+        Err(FileError::Exists) => StatusCode::CONFLICT,
+        Err(FileError::TooLarge) => StatusCode::PAYLOAD_TOO_LARGE,
+        Err(FileError::InvalidFile) => StatusCode::UNPROCESSABLE_ENTITY,
+        Err(FileError::Io(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, Json(result)).into_response()
+}
+
+// Takes as long for every wrong password of the same length
+fn password_matches(password: &str, given: &str) -> bool {
+    password.len() == given.len()
+        && password
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Without a password there's nothing to authenticate with, everyone who may edit already does,
+// so a spectator stays one
+pub async fn authenticate(password: Option<&str>, given: &str) -> ServerMessageKind {
+    match password {
+        Some(password) if password_matches(password, given) => ServerMessageKind::Ack,
+        Some(_) => {
+            tokio::time::sleep(FAILED_AUTHENTICATION_DELAY).await;
+            ServerMessageKind::Nak
+        }
+        None => ServerMessageKind::Nak,
+    }
+}
+
+async fn receive_upload(
+    state: &mut SharedState,
+    path: &std::path::Path,
+    size: Option<u64>,
+    body: Body,
+) -> Result<(), FileError> {
+    let mut upload = Upload::create(&state.virtual_paths, path, size).await?;
+    let mut stream = body.into_data_stream();
+    let mut reported = 0;
+    while let Some(chunk) = stream.next().await {
+        let received = match chunk {
+            Ok(chunk) => upload.write(&chunk).await,
+            Err(e) => Err(FileError::Io(e.to_string())),
+        };
+        let received = match received {
+            Ok(received) => received,
+            Err(e) => {
+                upload.abort().await;
+                return Err(e);
+            }
+        };
+        if received - reported >= UPLOAD_PROGRESS_STEP {
+            reported = received;
+            let progress = ServerMessageKind::UploadProgress(path.to_owned(), received, size);
+            state.clients.broadcast(progress);
+        }
+    }
+    upload.finish().await
 }
 
 async fn handle_socket<F, Fut>(
     socket: WebSocket,
     addr: SocketAddr,
+    params: WsParams,
     state: SharedState,
     mut req_handler: F,
 ) where
@@ -108,51 +321,167 @@ async fn handle_socket<F, Fut>(
     Fut: Future<Output = ServerMessageKind> + Send + 'static,
 {
     let (tx, mut rx) = socket.split();
-    let mut brd_rx = state.clients.tx.subscribe();
+    let mut brd_rx = state.clients.subscribe();
     let mut clients = state.clients;
     let midi_reader = state.midi_reader;
-    clients.push(Client { addr }).await;
+    let mut roles = clients.clone();
+    let password = state.password;
+    let encoding = params.encoding;
+    let role = if params.spectator || password.is_some() {
+        Role::Spectator
+    } else {
+        Role::Editor
+    };
+    clients
+        .push(Client {
+            addr,
+            encoding,
+            role,
+        })
+        .await;
     let tx = Arc::new(Mutex::new(tx));
     let tx2 = Arc::clone(&tx);
 
     send_broadcast(
         &mut *tx.lock().await,
+        encoding,
         ServerMessageKind::ConnectedMidiInputs(midi_reader.lock().await.connected_input_names()),
     )
     .await;
 
     send_broadcast(
         &mut *tx.lock().await,
+        encoding,
+        ServerMessageKind::MidiInputSettings(midi_reader.lock().await.input_settings()),
+    )
+    .await;
+
+    send_broadcast(
+        &mut *tx.lock().await,
+        encoding,
+        ServerMessageKind::Role(role),
+    )
+    .await;
+
+    send_broadcast(
+        &mut *tx.lock().await,
+        encoding,
         ServerMessageKind::Cache(state.cache.lock().await.get().clone()),
     )
     .await;
 
+    // the answer to Sync and the deltas following it
+    let (sync_tx, mut sync_rx) = mpsc::channel::<(ServerMessage, broadcast::Receiver<Delta>)>(1);
+    let cache = Arc::clone(&state.cache);
+    let monitor = Arc::new(Mutex::new(midi::monitor::Monitor::default()));
+    let monitor2 = Arc::clone(&monitor);
+
     tokio::select! {
         _ = async move {
-            while let Ok(msg) = brd_rx.recv().await {
-                // tracing::trace!("Sending broadcast message to a client at {addr}: {msg:?}");
-                send_raw_msg(&mut *tx.lock().await, msg).await;
+            let mut delta_rx = None;
+            loop {
+                tokio::select! {
+                    msg = brd_rx.recv() => {
+                        let Ok(msg) = msg else {
+                            break;
+                        };
+                        if let Some(event) = msg.midi_event {
+                            if !monitor.lock().await.passes(event, Instant::now()) {
+                                continue;
+                            }
+                        }
+                        let closing = msg.closing;
+                        if !msg.state || delta_rx.is_none() {
+                            // tracing::trace!("Sending broadcast message to a client at {addr}: {msg:?}");
+                            send_raw_msg(&mut *tx.lock().await, msg.encoded(encoding)).await;
+                        }
+                        if closing {
+                            send_raw_msg(&mut *tx.lock().await, Message::Close(None)).await;
+                            break;
+                        }
+                    }
+                    Some((res, rx)) = sync_rx.recv() => {
+                        send_msg(&mut *tx.lock().await, encoding, res).await;
+                        delta_rx = Some(rx);
+                    }
+                    delta = next_delta(&mut delta_rx) => {
+                        let payload = match delta {
+                            Ok(delta) => ServerMessageKind::StateDelta(delta),
+                            // too far behind, start over from the current state
+                            Err(_) => {
+                                let cache = cache.lock().await;
+                                delta_rx = Some(cache.subscribe_deltas());
+                                ServerMessageKind::Snapshot(cache.seq(), cache.get().clone())
+                            }
+                        };
+                        send_broadcast(&mut *tx.lock().await, encoding, payload).await;
+                    }
+                }
             }
         } => {},
         _ = async move {
             while let Some(Ok(msg)) = rx.next().await {
-                match msg {
-                    Message::Text(msg) => {
-                        if let Ok(msg) = serde_json::from_str::<ClientMessage>(&msg) {
-                            send_msg(&mut *tx2.lock().await, ServerMessage {
-                                id: msg.id,
-                                response: true,
-                                payload: req_handler(addr, msg.payload).await,
-                            }).await;
+                let msg = match msg {
+                    Message::Text(text) => {
+                        serde_json::from_str::<ClientMessage>(&text).map_err(|_| text)
+                    }
+                    Message::Binary(bytes) => rmp_serde::from_slice::<ClientMessage>(&bytes)
+                        .map_err(|_| format!("{} bytes of MessagePack", bytes.len())),
+                    Message::Close(_) => break,
+                    _ => continue,
+                };
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(msg) => {
+                        warn!("Invalid message from {addr}: {msg}");
+                        continue;
+                    }
+                };
+                let payload = match msg.payload {
+                    ClientMessageKind::Sync(seq) => {
+                        let (payload, delta_rx) = {
+                            let cache = state.cache.lock().await;
+                            let payload = match seq.and_then(|seq| cache.deltas_since(seq)) {
+                                Some(deltas) => ServerMessageKind::Deltas(deltas),
+                                None => ServerMessageKind::Snapshot(cache.seq(), cache.get().clone()),
+                            };
+                            (payload, cache.subscribe_deltas())
+                        };
+                        let res = ServerMessage {
+                            id: msg.id,
+                            response: true,
+                            payload,
+                        };
+                        // sent along with the deltas, so none of them comes first
+                        if sync_tx.send((res, delta_rx)).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    ClientMessageKind::SetMidiMonitor(settings) => {
+                        monitor2.lock().await.set_settings(settings);
+                        ServerMessageKind::Ack
+                    }
+                    ClientMessageKind::Authenticate(given) => {
+                        let res = authenticate(password.as_deref(), &given).await;
+                        if matches!(res, ServerMessageKind::Ack) {
+                            roles.set_role(addr, Role::Editor).await;
                         } else {
-                            warn!("Invalid message from {addr}: {msg}");
+                            warn!("Failed authentication from {addr}");
                         }
+                        res
                     }
-                    Message::Close(_) => {
-                        break;
+                    payload if payload.is_read_only() => req_handler(addr, payload).await,
+                    payload if roles.role(addr).await == Some(Role::Editor) => {
+                        req_handler(addr, payload).await
                     }
-                    _ => {}
-                }
+                    _ => ServerMessageKind::Unauthorized,
+                };
+                send_msg(&mut *tx2.lock().await, encoding, ServerMessage {
+                    id: msg.id,
+                    response: true,
+                    payload,
+                }).await;
             }
         } => {},
     };
@@ -164,9 +493,52 @@ async fn handle_socket<F, Fut>(
     );
 }
 
+// Waits forever without a receiver
+async fn next_delta(
+    delta_rx: &mut Option<broadcast::Receiver<Delta>>,
+) -> Result<Delta, broadcast::error::RecvError> {
+    match delta_rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 #[derive(Debug)]
 pub struct Client {
     pub addr: SocketAddr,
+    pub encoding: Encoding,
+    pub role: Role,
+}
+
+// Spectators only get to read, like everyone before authenticating on a password protected
+// server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Spectator,
+    Editor,
+}
+
+// How the messages of a connection are encoded, picked when it connects, MessagePack goes in
+// binary frames and has the same structure as the JSON
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+impl Encoding {
+    pub fn encode(self, msg: &ServerMessage) -> Message {
+        match self {
+            Encoding::Json => Message::Text(
+                serde_json::to_string(msg).expect("Failed to serialize server message"),
+            ),
+            Encoding::MsgPack => Message::Binary(
+                rmp_serde::to_vec_named(msg).expect("Failed to serialize server message"),
+            ),
+        }
+    }
 }
 
 pub async fn send_raw_msg(tx: &mut SplitSink<WebSocket, Message>, msg: Message) {
@@ -175,33 +547,65 @@ pub async fn send_raw_msg(tx: &mut SplitSink<WebSocket, Message>, msg: Message)
         .unwrap_or_else(|e| error!("Send error: {e}"));
 }
 
-pub async fn send_msg(tx: &mut SplitSink<WebSocket, Message>, msg: ServerMessage) {
-    let msg = serde_json::to_string(&msg).expect("Failed to serialize server message");
-    let msg = Message::Text(msg);
-    send_raw_msg(tx, msg).await;
+pub async fn send_msg(
+    tx: &mut SplitSink<WebSocket, Message>,
+    encoding: Encoding,
+    msg: ServerMessage,
+) {
+    send_raw_msg(tx, encoding.encode(&msg)).await;
 }
 
-pub async fn send_broadcast(tx: &mut SplitSink<WebSocket, Message>, msg: ServerMessageKind) {
+pub async fn send_broadcast(
+    tx: &mut SplitSink<WebSocket, Message>,
+    encoding: Encoding,
+    msg: ServerMessageKind,
+) {
     let msg = ServerMessage {
         id: 0,
         response: false,
         payload: msg,
     };
-    send_msg(tx, msg).await;
+    send_msg(tx, encoding, msg).await;
+}
+
+// Encoded once for all clients, `msgpack` only while MessagePack clients are connected and
+// `state` marks the state updates, clients syncing with deltas get those as deltas instead,
+// `midi_event` is for the MIDI monitor of every client to decide on, after a `closing` one the
+// connections get closed. `payload` is the message itself, for those which don't talk JSON.
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub json: Message,
+    pub msgpack: Option<Message>,
+    pub payload: Arc<ServerMessageKind>,
+    pub state: bool,
+    pub midi_event: Option<midi::monitor::Event>,
+    pub closing: bool,
+}
+
+impl Broadcast {
+    // A client which connected after the broadcast was encoded may get JSON anyway
+    pub fn encoded(self, encoding: Encoding) -> Message {
+        match (encoding, self.msgpack) {
+            (Encoding::MsgPack, Some(msgpack)) => msgpack,
+            _ => self.json,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Clients {
     // thread safe struct of Clients, can be cloned
     clients: Arc<Mutex<Vec<Client>>>,
-    tx: broadcast::Sender<Message>,
+    msgpack_clients: Arc<AtomicUsize>,
+    tx: broadcast::Sender<Broadcast>,
 }
 
 impl Clients {
     pub fn new(broadcast_channel_capacity: usize) -> Self {
-        let (tx, _) = broadcast::channel::<Message>(broadcast_channel_capacity);
+        let (tx, _) = broadcast::channel::<Broadcast>(broadcast_channel_capacity);
         Self {
             clients: Default::default(),
+            msgpack_clients: Default::default(),
             tx,
         }
     }
@@ -213,12 +617,44 @@ impl Clients {
 
     pub async fn push(&mut self, client: Client) {
         let mut clients = self.clients.lock().await;
+        if client.encoding == Encoding::MsgPack {
+            self.msgpack_clients.fetch_add(1, Ordering::Relaxed);
+        }
         clients.push(client);
     }
 
     pub async fn remove(&mut self, addr: SocketAddr) {
         let mut clients = self.clients.lock().await;
-        clients.retain(|c| c.addr != addr);
+        clients.retain(|c| {
+            if c.addr == addr && c.encoding == Encoding::MsgPack {
+                self.msgpack_clients.fetch_sub(1, Ordering::Relaxed);
+            }
+            c.addr != addr
+        });
+    }
+
+    pub async fn role(&self, addr: SocketAddr) -> Option<Role> {
+        let clients = self.clients.lock().await;
+        clients.iter().find(|c| c.addr == addr).map(|c| c.role)
+    }
+
+    // `false` if there's no such client
+    pub async fn set_role(&mut self, addr: SocketAddr, role: Role) -> bool {
+        let mut clients = self.clients.lock().await;
+        let Some(client) = clients.iter_mut().find(|c| c.addr == addr) else {
+            return false;
+        };
+        client.role = role;
+        true
+    }
+
+    pub async fn list(&self) -> Vec<(SocketAddr, Role)> {
+        let clients = self.clients.lock().await;
+        clients.iter().map(|c| (c.addr, c.role)).collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Broadcast> {
+        self.tx.subscribe()
     }
 
     pub fn broadcast(&mut self, payload: ServerMessageKind) {
@@ -226,13 +662,26 @@ impl Clients {
             return;
         }
 
+        let state = payload.is_state_update();
+        let closing = matches!(payload, ServerMessageKind::Shutdown);
+        let midi_event = match &payload {
+            ServerMessageKind::MidiEvent(message) => Some(midi::monitor::event_of(message)),
+            _ => None,
+        };
         let msg = ServerMessage {
             id: 0,
             response: false,
             payload,
         };
-        let msg = serde_json::to_string(&msg).expect("Failed to serialize server message");
-        let msg = Message::Text(msg);
+        let msgpack = self.msgpack_clients.load(Ordering::Relaxed) > 0;
+        let msg = Broadcast {
+            json: Encoding::Json.encode(&msg),
+            msgpack: msgpack.then(|| Encoding::MsgPack.encode(&msg)),
+            payload: Arc::new(msg.payload),
+            state,
+            midi_event,
+            closing,
+        };
 
         self.tx.send(msg).unwrap_or_else(|e| {
             error!("Broadcast error: {e}");
@@ -246,52 +695,201 @@ pub enum ServerMessageKind {
     Pong,
     Ack,
     Nak,
+    // The request needs authentication
+    Unauthorized,
+    // The role of the connection, sent when it connects
+    Role(Role),
+    // Every connected client with its role
+    ClientList(Vec<(SocketAddr, Role)>),
     Log(String),
     MidiEvent(midi::Message),
     AvailableMidiInputs(Vec<String>),
     ConnectedMidiInputs(Vec<Option<String>>),
+    // Of every input slot
+    MidiInputSettings(Vec<midi::input::Settings>),
     Cache(serde_json::Value),
     RendererResponse(command::ResponseKind),
     DirInfo(Option<Vec<(bool, PathBuf)>>), // (is_dir, path)
+    DirTree(Result<Vec<FileInfo>, FileError>),
+    DiskUsage(Vec<DiskUsage>),
+    SoundfontPresets(Result<Vec<SoundfontPreset>, FileError>),
+    SfzInspection(Result<sfz::Inspection, FileError>),
     DrumMachineUpdate(JsonUpdateKind),
+    ControllerResponse(control::command::ResponseKind),
+    PadUpdate(JsonUpdateKind),
+    SetlistUpdate(JsonUpdateKind),
+    SnapshotUpdate(JsonUpdateKind),
+    PerformanceUpdate(JsonUpdateKind),
+    AudioResponse(audio::output::ResponseKind),
+    // The output device came or went
+    AudioDevice(audio::output::DeviceStatus),
+    // Recording started or stopped
+    RecorderUpdate(midi::recorder::Status),
+    // (node id, virtual path), the file a node plays changed on disk and wasn't reloaded, a
+    // LoadFile request reloads it
+    InstrumentFileChanged(usize, PathBuf),
+    FileError(FileError),
+    // (path, bytes received, announced size)
+    UploadProgress(PathBuf, u64, Option<u64>),
+    // How much of the audio time rendering takes, per node and for the whole chain
+    RenderLoad(Load),
+    // Peak and RMS levels of the master bus and every node
+    Levels(Levels),
+    // (seq, state), the whole state as of delta `seq`
+    Snapshot(u64, serde_json::Value),
+    Deltas(Vec<Delta>),
+    StateDelta(Delta),
+    // AMI is stopping, the connection gets closed right after it
+    Shutdown,
+}
+
+impl ServerMessageKind {
+    pub fn is_state_update(&self) -> bool {
+        matches!(
+            self,
+            Self::RendererResponse(_)
+                | Self::DrumMachineUpdate(_)
+                | Self::ControllerResponse(_)
+                | Self::PadUpdate(_)
+                | Self::SetlistUpdate(_)
+                | Self::SnapshotUpdate(_)
+                | Self::PerformanceUpdate(_)
+        )
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerMessage {
-    id: usize,
-    response: bool,
-    payload: ServerMessageKind,
+    pub id: usize,
+    pub response: bool,
+    pub payload: ServerMessageKind,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientMessageKind {
     Ping,
+    // With the server's password, answered with Ack or Nak, the connection becomes an editor
+    Authenticate(String),
+    // Answered with ClientList
+    ListClients,
+    // Changes the role of another connection, only editors may do that
+    SetClientRole(SocketAddr, Role),
     Report(String),
     ConnectMidiInput(usize, String),
     DisconnectMidiInput(usize),
+    SetMidiInputSettings(usize, midi::input::Settings),
     RendererRequest(command::RequestKind),
     ReadDir(PathBuf),
+    // (path, recursive), answered with DirTree
+    ReadDirDeep(PathBuf, bool),
+    // Usage of every virtual root
+    DiskUsage,
+    // Banks and presets of the soundfont at the path, answered with SoundfontPresets
+    ListSoundfontPresets(PathBuf),
+    // Key ranges, velocity layers and missing samples of the .sfz file at the path, answered
+    // with SfzInspection
+    InspectSfz(PathBuf),
+    // (from, to), answered with Ack or FileError
+    CopyFile(PathBuf, PathBuf),
     DrumMachineRequest(drum_machine::RequestKind),
+    ControllerRequest(control::command::RequestKind),
+    PadRequest(pads::RequestKind),
+    // Navigation is answered with Ack once the actions of the entry ran, or Nak
+    SetlistRequest(setlist::RequestKind),
+    // Recalling is answered with Ack once the renderer and the controller took the snapshot, or
+    // Nak
+    SnapshotRequest(snapshots::RequestKind),
+    // Switching is answered with Ack once the renderer and the controller took the session of
+    // the slot, or Nak
+    PerformanceRequest(performances::RequestKind),
+    AudioRequest(audio::output::RequestKind),
+    // Start is answered with Ack or FileError, StartSession too or with Nak while recording or
+    // without an audio output, Stop with Ack once the files are written or FileError, GetStatus
+    // with RecorderUpdate
+    RecorderRequest(midi::recorder::RequestKind),
+    // Switches the connection over to state deltas, with the seq of the last delta the client
+    // has it's answered with the ones it missed if possible, otherwise with a Snapshot
+    Sync(Option<u64>),
+    // Which MIDI events the connection gets and how many, answered with Ack
+    SetMidiMonitor(midi::monitor::Settings),
+}
+
+impl ClientMessageKind {
+    // Allowed without authentication
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Self::Ping
+                | Self::ListClients
+                | Self::AudioRequest(audio::output::RequestKind::GetLatency)
+                | Self::AudioRequest(audio::output::RequestKind::GetDeviceStatus)
+                | Self::AudioRequest(audio::output::RequestKind::GetChannels)
+                | Self::RecorderRequest(midi::recorder::RequestKind::GetStatus)
+                | Self::ReadDir(_)
+                | Self::ReadDirDeep(..)
+                | Self::DiskUsage
+                | Self::ListSoundfontPresets(_)
+                | Self::InspectSfz(_)
+                | Self::Sync(_)
+                | Self::SetMidiMonitor(_)
+        )
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ClientMessage {
-    id: usize,
-    request: bool,
-    payload: ClientMessageKind,
+    pub id: usize,
+    pub request: bool,
+    pub payload: ClientMessageKind,
 }
 
 pub struct Cache {
     cache: serde_json::Value,
+    seq: u64,
+    history: VecDeque<Delta>,
+    delta_tx: broadcast::Sender<Delta>,
 }
 
 impl Cache {
-    pub fn new(drum_machine_json: serde_json::Value) -> Self {
+    pub fn new(drum_machine_json: serde_json::Value, controller_json: serde_json::Value) -> Self {
+        let (delta_tx, _) = broadcast::channel(MAX_DELTA_HISTORY);
         Self {
             cache: json!({
                 "nodes": [],
                 "drum_machine": drum_machine_json,
+                "controller": controller_json,
+                "pads": [],
+                "snapshots": [],
+                "zones": [],
+                "layered_instruments": [],
+                "selected_layered_instrument": null,
+                "modulation_routes": [],
+                "expression": null,
+                "bypassed": [],
+                "groups": [],
+                "output_routes": [],
+                "monitor_pairs": [],
+                "pitch_shifts": [],
+                "global_transposition": 0,
+                "transposition_cc": null,
+                "octave_shift": 0,
+                "octave_shift_triggers": { "up": null, "down": null },
+                "setlist": {
+                    "entries": [],
+                    "player": null,
+                    "next_trigger": null,
+                    "previous_trigger": null,
+                    "current": null,
+                },
+                "performances": {
+                    "slots": [],
+                    "current": null,
+                    "program_change_channel": null,
+                },
             }),
+            seq: 0,
+            history: VecDeque::with_capacity(MAX_DELTA_HISTORY),
+            delta_tx,
         }
     }
 
@@ -299,68 +897,492 @@ impl Cache {
         &self.cache
     }
 
-    pub fn cache_renderer_response(&mut self, res: &command::ResponseKind) {
-        match res {
-            command::ResponseKind::InvalidNodeKind => todo!(),
-            command::ResponseKind::InvalidId => {}
-            command::ResponseKind::Denied => {}
-            command::ResponseKind::Failed => {}
-            command::ResponseKind::NodeResponse { id, kind } => self.node_update(*id, kind),
-            command::ResponseKind::AddNode { kind, instance, .. } => self.add_node(kind, instance),
-            command::ResponseKind::RemoveNode { id } => self.remove_node(*id),
-            command::ResponseKind::CloneNode { id } => self.clone_node(*id),
-            command::ResponseKind::MoveNode { id, new_id } => todo!(),
-        }
+    // (node id, virtual path) of every render node with a file loaded
+    pub fn loaded_files(&self) -> Vec<(usize, PathBuf)> {
+        let Some(nodes) = self.cache["nodes"].as_array() else {
+            return vec![];
+        };
+        nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(id, node)| {
+                let path = node["instance"]["loaded_file"].as_str()?;
+                Some((id, PathBuf::from(path)))
+            })
+            .collect()
     }
 
-    pub fn chache_drum_machine_update(&mut self, kind: &JsonUpdateKind) {
-        match kind {
-            JsonUpdateKind::InvalidId => {}
-            JsonUpdateKind::Denied => {}
-            JsonUpdateKind::Failed => {}
-            JsonUpdateKind::Ok => {}
-            JsonUpdateKind::UpdateFields(updates) => {
-                for update in updates {
-                    self.cache["drum_machine"][&update.0] = update.1.clone();
-                }
-            }
-        }
+    // Sequence number of the latest delta, the state of `get` includes it
+    pub fn seq(&self) -> u64 {
+        self.seq
     }
 
-    fn add_node(&mut self, kind: &str, value: &serde_json::Value) {
-        if let Some(nodes) = self.cache["nodes"].as_array_mut() {
-            nodes.push(json!({
-                "kind": kind,
-                "instance": value,
-            }));
+    // Deltas made from now on, together with `get` and `seq` this is a consistent view as long
+    // as the cache stays locked in between
+    pub fn subscribe_deltas(&self) -> broadcast::Receiver<Delta> {
+        self.delta_tx.subscribe()
+    }
+
+    // The deltas after `seq`, `None` when they aren't all in the history anymore
+    pub fn deltas_since(&self, seq: u64) -> Option<Vec<Delta>> {
+        if seq > self.seq {
+            return None;
         }
+        let missed = (self.seq - seq) as usize;
+        (missed <= self.history.len()).then(|| {
+            self.history
+                .range(self.history.len() - missed..)
+                .cloned()
+                .collect()
+        })
     }
 
-    fn remove_node(&mut self, id: usize) {
-        if let Some(nodes) = self.cache["nodes"].as_array_mut() {
-            nodes.remove(id);
+    fn commit(&mut self, ops: Vec<PatchOp>) {
+        if ops.is_empty() {
+            return;
+        }
+        self.seq += 1;
+        let delta = Delta { seq: self.seq, ops };
+        if self.history.len() == MAX_DELTA_HISTORY {
+            self.history.pop_front();
         }
+        self.history.push_back(delta.clone());
+        // nobody syncing is fine
+        let _ = self.delta_tx.send(delta);
     }
 
-    fn clone_node(&mut self, id: usize) {
-        if let Some(nodes) = self.cache["nodes"].as_array_mut() {
-            if id <= nodes.len() {
-                nodes.push(nodes[id].clone());
+    pub fn cache_renderer_response(&mut self, res: &command::ResponseKind) {
+        let nodes = &mut self.cache["nodes"];
+        let ops = match res {
+            command::ResponseKind::InvalidNodeKind => vec![],
+            command::ResponseKind::InvalidId => vec![],
+            command::ResponseKind::Denied => vec![],
+            command::ResponseKind::Failed => vec![],
+            command::ResponseKind::NodeResponse { id, kind } => {
+                node_update(nodes, &["nodes"], *id, kind)
             }
-        }
+            command::ResponseKind::AddNode { kind, instance, .. } => {
+                add_node(nodes, &["nodes"], kind, instance)
+            }
+            command::ResponseKind::RemoveNode { id } => {
+                let mut ops = remove_node(nodes, &["nodes"], *id);
+                let cache = &mut self.cache;
+                ops.extend(change_zones(cache, |zones| zones.remove_node(*id)));
+                ops.extend(change_layers(cache, |layers| layers.remove_node(*id)));
+                ops.extend(change_modulation(cache, |routes| routes.remove_node(*id)));
+                ops.extend(change_expression(cache, |pedal| pedal.remove_node(*id)));
+                ops.extend(change_bypass(cache, |bypass| bypass.remove_node(*id)));
+                ops.extend(change_groups(cache, |groups| groups.remove_node(*id)));
+                ops.extend(change_outputs(cache, |outputs| outputs.remove_node(*id)));
+                ops.extend(change_pitch_shifts(cache, |shifts| shifts.remove_node(*id)));
+                ops
+            }
+            command::ResponseKind::CloneNode { id } => clone_node(nodes, &["nodes"], *id),
+            command::ResponseKind::MoveNode { id, new_id } => {
+                let (from, to) = (*id, *new_id);
+                let mut ops = move_node(nodes, &["nodes"], from, to);
+                let cache = &mut self.cache;
+                ops.extend(change_zones(cache, |zones| zones.move_node(from, to)));
+                ops.extend(change_layers(cache, |layers| layers.move_node(from, to)));
+                ops.extend(change_modulation(cache, |routes| {
+                    routes.move_node(from, to)
+                }));
+                ops.extend(change_expression(cache, |pedal| pedal.move_node(from, to)));
+                ops.extend(change_bypass(cache, |bypass| bypass.move_node(from, to)));
+                ops.extend(change_groups(cache, |groups| groups.move_node(from, to)));
+                ops.extend(change_outputs(cache, |outputs| outputs.move_node(from, to)));
+                ops.extend(change_pitch_shifts(cache, |shifts| {
+                    shifts.move_node(from, to)
+                }));
+                ops
+            }
+            command::ResponseKind::Panic => vec![],
+            command::ResponseKind::GlobalTransposition(transposition) => {
+                vec![set_field(&mut self.cache, &[], "global_transposition", json!(transposition))]
+            }
+            command::ResponseKind::TranspositionCc(kind) => {
+                vec![set_field(&mut self.cache, &[], "transposition_cc", json!(kind))]
+            }
+            command::ResponseKind::OctaveShift(octaves) => {
+                vec![set_field(&mut self.cache, &[], "octave_shift", json!(octaves))]
+            }
+            command::ResponseKind::OctaveShiftTriggers(triggers) => {
+                vec![set_field(&mut self.cache, &[], "octave_shift_triggers", json!(triggers))]
+            }
+            command::ResponseKind::Zones(zones) => {
+                vec![set_field(&mut self.cache, &[], "zones", json!(zones))]
+            }
+            command::ResponseKind::LayeredInstruments {
+                instruments,
+                selected,
+            } => vec![
+                set_field(&mut self.cache, &[], "layered_instruments", json!(instruments)),
+                set_field(&mut self.cache, &[], "selected_layered_instrument", json!(selected)),
+            ],
+            command::ResponseKind::Expression(settings) => {
+                vec![set_field(&mut self.cache, &[], "expression", json!(settings))]
+            }
+            command::ResponseKind::Bypassed(nodes) => {
+                vec![set_field(&mut self.cache, &[], "bypassed", json!(nodes))]
+            }
+            command::ResponseKind::Groups(groups) => {
+                vec![set_field(&mut self.cache, &[], "groups", json!(groups))]
+            }
+            command::ResponseKind::OutputRoutes(routes) => {
+                vec![set_field(&mut self.cache, &[], "output_routes", json!(routes))]
+            }
+            command::ResponseKind::MonitorPairs(pairs) => {
+                vec![set_field(&mut self.cache, &[], "monitor_pairs", json!(pairs))]
+            }
+            command::ResponseKind::PitchShifts(shifts) => {
+                vec![set_field(&mut self.cache, &[], "pitch_shifts", json!(shifts))]
+            }
+            command::ResponseKind::ModulationRoutes(routes) => {
+                vec![set_field(&mut self.cache, &[], "modulation_routes", json!(routes))]
+            }
+            // named like the fields of the state
+            command::ResponseKind::Setup(setup) => match json!(setup) {
+                serde_json::Value::Object(fields) => fields
+                    .into_iter()
+                    .map(|(field, value)| set_field(&mut self.cache, &[], &field, value))
+                    .collect(),
+                _ => vec![],
+            },
+        };
+        self.commit(ops);
     }
 
-    fn node_update(&mut self, node_id: usize, kind: &JsonUpdateKind) {
-        match kind {
-            JsonUpdateKind::InvalidId => {}
-            JsonUpdateKind::Denied => {}
-            JsonUpdateKind::Failed => {}
-            JsonUpdateKind::Ok => {}
-            JsonUpdateKind::UpdateFields(updates) => {
-                for update in updates {
-                    self.cache["nodes"][node_id]["instance"][&update.0] = update.1.clone();
-                }
+    pub fn cache_controller_response(&mut self, res: &control::command::ResponseKind) {
+        use control::command::ResponseKind as RK;
+        const NODES: &[&str] = &["controller", "nodes"];
+        let controller = &mut self.cache["controller"];
+        let ops = match res {
+            RK::InvalidNodeKind => vec![],
+            RK::InvalidId => vec![],
+            RK::Denied => vec![],
+            RK::Failed => vec![],
+            RK::NodeResponse { id, kind } => {
+                node_update(&mut controller["nodes"], NODES, *id, kind)
             }
-        }
+            RK::AddNode { kind, instance, .. } => {
+                add_node(&mut controller["nodes"], NODES, kind, instance)
+            }
+            RK::RemoveNode { id } => remove_node(&mut controller["nodes"], NODES, *id),
+            RK::CloneNode { id } => clone_node(&mut controller["nodes"], NODES, *id),
+            RK::MoveNode { id, new_id } => move_node(&mut controller["nodes"], NODES, *id, *new_id),
+            RK::MoveInstrument(updates) => updates
+                .iter()
+                .flat_map(|(id, kind)| node_update(&mut controller["nodes"], NODES, *id, kind))
+                .collect(),
+            RK::SetRhythm(rhythm) => {
+                vec![set_field(
+                    controller,
+                    &["controller"],
+                    "rhythm",
+                    json!(rhythm),
+                )]
+            }
+            RK::SetTempoBpm(tempo_bpm) => vec![set_field(
+                controller,
+                &["controller"],
+                "tempo_bpm",
+                json!(tempo_bpm),
+            )],
+            RK::Transport(status) => vec![set_field(
+                controller,
+                &["controller"],
+                "transport",
+                json!(status),
+            )],
+            RK::QuantizedSetups(flag) => vec![set_field(
+                controller,
+                &["controller"],
+                "quantized_setups",
+                json!(flag),
+            )],
+            RK::QueuedSetup(flag) => vec![set_field(
+                controller,
+                &["controller"],
+                "queued_setup",
+                json!(flag),
+            )],
+            RK::Setup(setup) => match json!(setup) {
+                serde_json::Value::Object(fields) => fields
+                    .into_iter()
+                    .map(|(field, value)| set_field(controller, &["controller"], &field, value))
+                    .collect(),
+                _ => vec![],
+            },
+        };
+        self.commit(ops);
+    }
+
+    pub fn cache_pads_update(&mut self, kind: &JsonUpdateKind) {
+        let ops = update_fields(&mut self.cache, &[], kind);
+        self.commit(ops);
+    }
+
+    pub fn cache_snapshots_update(&mut self, kind: &JsonUpdateKind) {
+        let ops = update_fields(&mut self.cache, &[], kind);
+        self.commit(ops);
+    }
+
+    pub fn cache_setlist_update(&mut self, kind: &JsonUpdateKind) {
+        let ops = update_fields(&mut self.cache["setlist"], &["setlist"], kind);
+        self.commit(ops);
+    }
+
+    pub fn cache_performances_update(&mut self, kind: &JsonUpdateKind) {
+        let ops = update_fields(&mut self.cache["performances"], &["performances"], kind);
+        self.commit(ops);
+    }
+
+    pub fn chache_drum_machine_update(&mut self, kind: &JsonUpdateKind) {
+        let ops = update_fields(&mut self.cache["drum_machine"], &["drum_machine"], kind);
+        self.commit(ops);
+    }
+}
+
+// The zones of the state after a node went or moved, changed like the renderer changes them
+fn change_zones(
+    cache: &mut serde_json::Value,
+    change: impl FnOnce(&mut render::zones::Zones),
+) -> Option<PatchOp> {
+    let zones = Vec::deserialize(&cache["zones"]).ok()?;
+    let mut render_zones = render::zones::Zones::default();
+    render_zones.set(zones);
+    change(&mut render_zones);
+    let zones = json!(render_zones.get());
+    (cache["zones"] != zones).then(|| set_field(cache, &[], "zones", zones))
+}
+
+// Same for the layered instruments
+fn change_layers(
+    cache: &mut serde_json::Value,
+    change: impl FnOnce(&mut render::layers::Layers),
+) -> Option<PatchOp> {
+    let instruments = Vec::deserialize(&cache["layered_instruments"]).ok()?;
+    let mut layers = render::layers::Layers::default();
+    layers.set(instruments, None);
+    change(&mut layers);
+    let instruments = json!(layers.get());
+    (cache["layered_instruments"] != instruments)
+        .then(|| set_field(cache, &[], "layered_instruments", instruments))
+}
+
+// Same for the modulation routes
+fn change_modulation(
+    cache: &mut serde_json::Value,
+    change: impl FnOnce(&mut render::modulation::Modulation),
+) -> Option<PatchOp> {
+    let routes = Vec::deserialize(&cache["modulation_routes"]).ok()?;
+    let mut modulation = render::modulation::Modulation::default();
+    modulation.set(routes);
+    change(&mut modulation);
+    let routes = json!(modulation.get());
+    (cache["modulation_routes"] != routes)
+        .then(|| set_field(cache, &[], "modulation_routes", routes))
+}
+
+// And the nodes of the expression pedal
+fn change_expression(
+    cache: &mut serde_json::Value,
+    change: impl FnOnce(&mut render::expression::Expression),
+) -> Option<PatchOp> {
+    let settings = Option::deserialize(&cache["expression"]).ok()?;
+    let mut expression = render::expression::Expression::default();
+    expression.set(settings);
+    change(&mut expression);
+    let settings = json!(expression.get());
+    (cache["expression"] != settings).then(|| set_field(cache, &[], "expression", settings))
+}
+
+// And the bypassed nodes
+fn change_bypass(
+    cache: &mut serde_json::Value,
+    change: impl FnOnce(&mut render::bypass::Bypass),
+) -> Option<PatchOp> {
+    let nodes = Vec::deserialize(&cache["bypassed"]).ok()?;
+    let mut bypass = render::bypass::Bypass::default();
+    bypass.set(nodes);
+    change(&mut bypass);
+    let nodes = json!(bypass.get());
+    (cache["bypassed"] != nodes).then(|| set_field(cache, &[], "bypassed", nodes))
+}
+
+// And the groups
+fn change_groups(
+    cache: &mut serde_json::Value,
+    change: impl FnOnce(&mut render::groups::Groups),
+) -> Option<PatchOp> {
+    let groups = Vec::deserialize(&cache["groups"]).ok()?;
+    let mut render_groups = render::groups::Groups::default();
+    render_groups.set(groups);
+    change(&mut render_groups);
+    let groups = json!(render_groups.get());
+    (cache["groups"] != groups).then(|| set_field(cache, &[], "groups", groups))
+}
+
+// And the routes to the output pairs
+fn change_outputs(
+    cache: &mut serde_json::Value,
+    change: impl FnOnce(&mut render::outputs::Outputs),
+) -> Option<PatchOp> {
+    let routes = Vec::deserialize(&cache["output_routes"]).ok()?;
+    let mut outputs = render::outputs::Outputs::default();
+    outputs.set(routes);
+    change(&mut outputs);
+    let routes = json!(outputs.get());
+    (cache["output_routes"] != routes).then(|| set_field(cache, &[], "output_routes", routes))
+}
+
+// And the pitch shifts
+fn change_pitch_shifts(
+    cache: &mut serde_json::Value,
+    change: impl FnOnce(&mut render::pitch_shift::PitchShifts),
+) -> Option<PatchOp> {
+    let shifts = Vec::deserialize(&cache["pitch_shifts"]).ok()?;
+    let mut pitch_shifts = render::pitch_shift::PitchShifts::default();
+    pitch_shifts.set(shifts);
+    change(&mut pitch_shifts);
+    let shifts = json!(pitch_shifts.get());
+    (cache["pitch_shifts"] != shifts).then(|| set_field(cache, &[], "pitch_shifts", shifts))
+}
+
+// Sets a field of an object and returns the op doing the same, `base` is where the object is
+fn set_field(
+    object: &mut serde_json::Value,
+    base: &[&str],
+    field: &str,
+    value: serde_json::Value,
+) -> PatchOp {
+    let path = sync::pointer(base.iter().copied().chain([field]));
+    let exists = object.get(field).is_some();
+    object[field] = value.clone();
+    if exists {
+        PatchOp::Replace { path, value }
+    } else {
+        PatchOp::Add { path, value }
+    }
+}
+
+fn update_fields(
+    object: &mut serde_json::Value,
+    base: &[&str],
+    kind: &JsonUpdateKind,
+) -> Vec<PatchOp> {
+    match kind {
+        JsonUpdateKind::InvalidId => vec![],
+        JsonUpdateKind::Denied => vec![],
+        JsonUpdateKind::Failed => vec![],
+        JsonUpdateKind::Ok => vec![],
+        JsonUpdateKind::InvalidPreset(_) => vec![],
+        JsonUpdateKind::UpdateFields(updates) => updates
+            .iter()
+            .map(|update| set_field(object, base, &update.0, update.1.clone()))
+            .collect(),
+    }
+}
+
+fn add_node(
+    nodes: &mut serde_json::Value,
+    base: &[&str],
+    kind: &str,
+    value: &serde_json::Value,
+) -> Vec<PatchOp> {
+    let Some(nodes) = nodes.as_array_mut() else {
+        return vec![];
+    };
+    let node = json!({
+        "kind": kind,
+        "instance": value,
+    });
+    nodes.push(node.clone());
+    vec![PatchOp::Add {
+        path: sync::pointer(base.iter().copied().chain(["-"])),
+        value: node,
+    }]
+}
+
+fn remove_node(nodes: &mut serde_json::Value, base: &[&str], id: usize) -> Vec<PatchOp> {
+    let Some(nodes) = nodes.as_array_mut() else {
+        return vec![];
+    };
+    nodes.remove(id);
+    vec![PatchOp::Remove {
+        path: sync::pointer(base.iter().map(|t| t.to_string()).chain([id.to_string()])),
+    }]
+}
+
+fn clone_node(nodes: &mut serde_json::Value, base: &[&str], id: usize) -> Vec<PatchOp> {
+    let Some(nodes) = nodes.as_array_mut() else {
+        return vec![];
+    };
+    if id >= nodes.len() {
+        return vec![];
+    }
+    let node = nodes[id].clone();
+    nodes.push(node.clone());
+    vec![PatchOp::Add {
+        path: sync::pointer(base.iter().copied().chain(["-"])),
+        value: node,
+    }]
+}
+
+// A remove and an add at the new place, the nodes in between shift by one
+fn move_node(
+    nodes: &mut serde_json::Value,
+    base: &[&str],
+    id: usize,
+    new_id: usize,
+) -> Vec<PatchOp> {
+    let Some(nodes) = nodes.as_array_mut() else {
+        return vec![];
+    };
+    if id >= nodes.len() || new_id >= nodes.len() {
+        return vec![];
+    }
+    let node = nodes.remove(id);
+    nodes.insert(new_id, node.clone());
+    let path =
+        |id: usize| sync::pointer(base.iter().map(|t| t.to_string()).chain([id.to_string()]));
+    vec![
+        PatchOp::Remove { path: path(id) },
+        PatchOp::Add {
+            path: path(new_id),
+            value: node,
+        },
+    ]
+}
+
+fn node_update(
+    nodes: &mut serde_json::Value,
+    base: &[&str],
+    node_id: usize,
+    kind: &JsonUpdateKind,
+) -> Vec<PatchOp> {
+    let id = node_id.to_string();
+    let base: Vec<&str> = base
+        .iter()
+        .copied()
+        .chain([id.as_str(), "instance"])
+        .collect();
+    update_fields(&mut nodes[node_id]["instance"], &base, kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authenticate, ServerMessageKind};
+
+    #[tokio::test]
+    async fn spectators_stay_without_a_password() {
+        assert!(matches!(
+            authenticate(Some("secret"), "secret").await,
+            ServerMessageKind::Ack
+        ));
+        assert!(matches!(
+            authenticate(None, "anything").await,
+            ServerMessageKind::Nak
+        ));
     }
 }